@@ -1,13 +1,30 @@
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
 use anyhow::Context;
+use drogue_doppelgaenger_core::{error::ErrorInformation, service::DesiredStateUpdate};
+use drogue_doppelgaenger_model::{Thing, ThingState};
+use schemars::{
+    gen::{SchemaGenerator, SchemaSettings},
+    JsonSchema,
+};
 use serde_json::{json, Value};
 use url::Url;
 
 const SPEC: &str = include_str!("../api/openapi.yaml");
+const SWAGGER_UI: &str = include_str!("../api/swagger-ui.html");
+const OAUTH2_REDIRECT: &str = include_str!("../api/oauth2-redirect.html");
 
-#[derive(Clone, Debug)]
+/// The path of the OpenAPI document.
+pub const API_DOCS_PATH: &str = "/api-docs";
+/// The path of the Swagger UI.
+pub const SWAGGER_UI_PATH: &str = "/api-docs/ui";
+/// The path of the OAuth2 redirect page of the Swagger UI.
+pub const OAUTH2_REDIRECT_PATH: &str = "/api-docs/oauth2-redirect.html";
+
+#[derive(Clone, Debug, Default)]
 pub struct OpenApiConfig {
     pub authorization_url: Option<Url>,
+    /// The OAuth client, used by the Swagger UI.
+    pub client_id: Option<String>,
 }
 
 pub async fn api(req: HttpRequest, openapi: web::Data<OpenApiConfig>) -> impl Responder {
@@ -20,11 +37,38 @@ pub async fn api(req: HttpRequest, openapi: web::Data<OpenApiConfig>) -> impl Re
     }
 }
 
+pub async fn swagger_ui(openapi: web::Data<OpenApiConfig>) -> impl Responder {
+    let config = json!({
+        "url": API_DOCS_PATH,
+        "oauth2RedirectPath": OAUTH2_REDIRECT_PATH,
+        "clientId": openapi.client_id,
+    });
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(SWAGGER_UI.replace("{{config}}", &config.to_string()))
+}
+
+pub async fn oauth2_redirect() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(OAUTH2_REDIRECT)
+}
+
 fn spec(req: HttpRequest, openapi: &OpenApiConfig) -> anyhow::Result<Value> {
     // load API spec
 
     let mut api: Value = serde_yaml::from_str(SPEC).context("Failed to parse OpenAPI YAML")?;
 
+    // schemas, generated from the types
+
+    let schemas = api["components"]["schemas"]
+        .as_object_mut()
+        .context("Missing schemas in OpenAPI YAML")?;
+    for (name, schema) in generate_schemas()? {
+        schemas.insert(name, schema);
+    }
+
     // server
 
     let ci = req.connection_info();
@@ -55,3 +99,27 @@ fn spec(req: HttpRequest, openapi: &OpenApiConfig) -> anyhow::Result<Value> {
 
     Ok(api)
 }
+
+/// Generate the schemas of the API types, replacing the ones of the YAML document.
+///
+/// Types referenced by the API types are added using the name of their type.
+fn generate_schemas() -> anyhow::Result<Vec<(String, Value)>> {
+    let mut gen = SchemaGenerator::from(SchemaSettings::openapi3());
+
+    let mut schemas = vec![
+        schema::<Thing>(&mut gen, "Thing")?,
+        schema::<ThingState>(&mut gen, "ThingState")?,
+        schema::<ErrorInformation>(&mut gen, "ErrorInformation")?,
+        schema::<DesiredStateUpdate>(&mut gen, "DesiredStateUpdate")?,
+    ];
+
+    for (name, schema) in gen.take_definitions() {
+        schemas.push((name, serde_json::to_value(schema)?));
+    }
+
+    Ok(schemas)
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator, name: &str) -> anyhow::Result<(String, Value)> {
+    Ok((name.to_string(), serde_json::to_value(T::json_schema(gen))?))
+}
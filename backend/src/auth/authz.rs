@@ -0,0 +1,185 @@
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    HttpMessage,
+};
+use drogue_bazaar::auth::{UserDetails, UserInformation};
+use drogue_client::user::{
+    self,
+    v1::authz::{AuthorizationRequest, Outcome, Permission},
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::{
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+/// Authorizes access to applications, using the user service of Drogue Cloud.
+///
+/// Without a client, all access is allowed, leaving authorization to [`super::LocalAuthN`] and
+/// the `NotAnonymous` authorizer.
+#[derive(Clone, Default)]
+pub struct ApplicationAuthorizer {
+    client: Option<user::v1::Client>,
+}
+
+impl ApplicationAuthorizer {
+    pub fn new(client: Option<user::v1::Client>) -> Self {
+        Self { client }
+    }
+
+    /// Check if the user has the permission on the application.
+    pub async fn authorize(
+        &self,
+        user: &UserInformation,
+        application: &str,
+        permission: Permission,
+    ) -> Result<(), actix_web::Error> {
+        let client = match &self.client {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        let (user_id, roles) = match user {
+            UserInformation::Authenticated(UserDetails { user_id, roles }) => {
+                (Some(user_id.clone()), roles.clone())
+            }
+            UserInformation::Anonymous => (None, vec![]),
+        };
+
+        let response = client
+            .authorize(AuthorizationRequest {
+                application: application.to_string(),
+                permission,
+                user_id,
+                roles,
+            })
+            .await
+            .map_err(|err| {
+                log::warn!("Failed to authorize: {err}");
+                actix_web::error::ErrorInternalServerError("Failed to authorize")
+            })?;
+
+        match response.outcome {
+            Outcome::Allow => Ok(()),
+            Outcome::Deny => Err(actix_web::error::ErrorForbidden(format!(
+                "Access to application '{application}' denied"
+            ))),
+        }
+    }
+
+    /// Check the permission of the user on each of the applications.
+    pub async fn authorize_all<'a>(
+        &self,
+        user: &UserInformation,
+        applications: impl IntoIterator<Item = &'a str>,
+        permission: Permission,
+    ) -> Result<(), actix_web::Error> {
+        let mut applications = applications.into_iter().collect::<Vec<_>>();
+        applications.sort_unstable();
+        applications.dedup();
+
+        for application in applications {
+            self.authorize(user, application, permission).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The permission required by a request: reading for safe methods, writing for all others.
+pub fn permission(method: &Method) -> Permission {
+    match *method {
+        Method::GET | Method::HEAD | Method::OPTIONS => Permission::Read,
+        _ => Permission::Write,
+    }
+}
+
+/// Middleware, authorizing requests for the application of the first path segment, after the
+/// scope it is registered on.
+///
+/// Must be registered after the authentication middleware, so that the user information is
+/// available. Requests without an application in their path pass, the endpoint needs to check
+/// them.
+pub struct ApplicationAuthZ {
+    authorizer: ApplicationAuthorizer,
+}
+
+impl ApplicationAuthZ {
+    pub fn new(authorizer: ApplicationAuthorizer) -> Self {
+        Self { authorizer }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ApplicationAuthZ
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = ApplicationAuthZMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ApplicationAuthZMiddleware {
+            service: Rc::new(service),
+            authorizer: self.authorizer.clone(),
+        }))
+    }
+}
+
+pub struct ApplicationAuthZMiddleware<S> {
+    service: Rc<S>,
+    authorizer: ApplicationAuthorizer,
+}
+
+impl<S, B> Service<ServiceRequest> for ApplicationAuthZMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let authorizer = self.authorizer.clone();
+
+        Box::pin(async move {
+            // the resource isn't matched yet, so take the segment following the scope
+            let application = req
+                .match_info()
+                .unprocessed()
+                .trim_start_matches('/')
+                .split('/')
+                .next()
+                .filter(|application| !application.is_empty())
+                .map(ToString::to_string);
+
+            if let Some(application) = application {
+                let user = req
+                    .extensions()
+                    .get::<UserInformation>()
+                    .cloned()
+                    .unwrap_or(UserInformation::Anonymous);
+
+                if let Err(err) = authorizer
+                    .authorize(&user, &application, permission(req.method()))
+                    .await
+                {
+                    return Ok(req.error_response(err));
+                }
+            }
+
+            Ok(service.call(req).await?.map_into_boxed_body())
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use super::{LocalAuthenticator, LocalCredentials, Outcome};
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    HttpMessage, HttpResponse,
+};
+use drogue_bazaar::actix::auth::authentication::AuthN;
+use futures::future::LocalBoxFuture;
+use std::{
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+/// Middleware, authenticating requests with local credentials, before delegating to [`AuthN`].
+///
+/// Requests which successfully authenticate with local credentials skip the [`AuthN`]
+/// authentication, requests without local credentials are processed by it.
+pub struct LocalAuthN {
+    authn: AuthN,
+    authenticator: Option<LocalAuthenticator>,
+}
+
+impl LocalAuthN {
+    pub fn new(authn: AuthN, authenticator: Option<LocalAuthenticator>) -> Self {
+        Self {
+            authn,
+            authenticator,
+        }
+    }
+}
+
+/// A service, shared between the local and the delegated authentication.
+pub struct Shared<S>(Rc<S>);
+
+impl<S, Req> Service<Req> for Shared<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(ctx)
+    }
+
+    fn call(&self, req: Req) -> Self::Future {
+        self.0.call(req)
+    }
+}
+
+/// Convert a response of any body type into a boxed body response.
+pub trait IntoBoxedResponse {
+    fn into_boxed(self) -> ServiceResponse<BoxBody>;
+}
+
+impl<B: MessageBody + 'static> IntoBoxedResponse for ServiceResponse<B> {
+    fn into_boxed(self) -> ServiceResponse<BoxBody> {
+        self.map_into_boxed_body()
+    }
+}
+
+type Delegate<S> = <AuthN as Transform<Shared<S>, ServiceRequest>>::Transform;
+
+impl<S, B> Transform<S, ServiceRequest> for LocalAuthN
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+    AuthN: Transform<Shared<S>, ServiceRequest, Error = actix_web::Error>,
+    <AuthN as Transform<Shared<S>, ServiceRequest>>::Response: IntoBoxedResponse,
+    <AuthN as Transform<Shared<S>, ServiceRequest>>::Future: 'static,
+    Delegate<S>: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = LocalAuthNMiddleware<S, Delegate<S>>;
+    type InitError = <AuthN as Transform<Shared<S>, ServiceRequest>>::InitError;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let service = Rc::new(service);
+        let delegate = self.authn.new_transform(Shared(service.clone()));
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            Ok(LocalAuthNMiddleware {
+                service,
+                delegate: Rc::new(delegate.await?),
+                authenticator,
+            })
+        })
+    }
+}
+
+pub struct LocalAuthNMiddleware<S, D> {
+    service: Rc<S>,
+    delegate: Rc<D>,
+    authenticator: Option<LocalAuthenticator>,
+}
+
+impl<S, D, B> Service<ServiceRequest> for LocalAuthNMiddleware<S, D>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+    D: Service<ServiceRequest, Error = actix_web::Error> + 'static,
+    D::Response: IntoBoxedResponse,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // the delegate wraps the service, so this covers both
+        self.delegate.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let delegate = self.delegate.clone();
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            let outcome = match &authenticator {
+                Some(authenticator) => authenticator
+                    .authenticate(LocalCredentials::from_request(&req))
+                    .await
+                    .map_err(|err| {
+                        log::warn!("Failed to authenticate: {err}");
+                        actix_web::error::ErrorInternalServerError("Failed to authenticate")
+                    })?,
+                None => Outcome::Delegate,
+            };
+
+            match outcome {
+                Outcome::Authenticated(user) => {
+                    req.extensions_mut().insert(user);
+                    Ok(service.call(req).await?.map_into_boxed_body())
+                }
+                Outcome::Failed => Ok(req.into_response(HttpResponse::Unauthorized().finish())),
+                Outcome::Delegate => Ok(delegate.call(req).await?.into_boxed()),
+            }
+        })
+    }
+}
@@ -0,0 +1,330 @@
+//! Local authentication, using API keys and basic auth.
+//!
+//! The credentials are stored in the database, next to the things. API keys are stored as
+//! SHA-256 hash, passwords as PHC string (e.g. argon2).
+//!
+//! Access to applications is authorized using the user service of Drogue Cloud, if configured.
+//! Clients can be limited further using scopes, granted as roles.
+
+mod authz;
+mod middleware;
+mod scopes;
+
+pub use authz::*;
+pub use middleware::*;
+pub use scopes::*;
+
+use actix_web::{dev::ServiceRequest, http::header};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use deadpool_postgres::{Pool, PoolError};
+use drogue_bazaar::{
+    auth::{UserDetails, UserInformation},
+    db::postgres,
+};
+use drogue_doppelgaenger_core::config::check::{Check, Checker};
+use postgres_types::Type;
+use sha2::{Digest, Sha256};
+
+/// Header carrying the API key.
+pub const HEADER_API_KEY: &str = "x-api-key";
+
+/// The enabled local authentication methods.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct LocalAuthMethods {
+    /// Allow authenticating with an API key, using the `X-API-Key` header.
+    #[serde(default)]
+    pub api_keys: bool,
+    /// Allow authenticating with username and password, using basic auth.
+    #[serde(default)]
+    pub basic: bool,
+}
+
+impl LocalAuthMethods {
+    pub fn is_enabled(&self) -> bool {
+        self.api_keys || self.basic
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct LocalAuthConfig {
+    #[serde(flatten)]
+    pub methods: LocalAuthMethods,
+    pub postgres: postgres::Config,
+}
+
+impl Check for LocalAuthConfig {
+    fn check(&self, checker: &mut Checker) {
+        if self.methods.is_enabled() {
+            checker.field("postgres", &self.postgres);
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("Pool error: {0}")]
+    Pool(#[from] PoolError),
+    #[error("Failed to verify password: {0}")]
+    Verify(#[from] tokio::task::JoinError),
+}
+
+/// Local credentials, presented by a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LocalCredentials {
+    /// The API key, from the `X-API-Key` header.
+    pub api_key: Option<String>,
+    /// Username and password, from a basic auth header.
+    pub basic: Option<(String, String)>,
+}
+
+impl LocalCredentials {
+    /// Extract the credentials, using a lookup of header values by name.
+    pub fn from_headers<'h, F>(lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<&'h str>,
+    {
+        Self {
+            api_key: lookup(HEADER_API_KEY).map(ToString::to_string),
+            basic: lookup(header::AUTHORIZATION.as_str()).and_then(parse_basic),
+        }
+    }
+
+    pub fn from_request(req: &ServiceRequest) -> Self {
+        Self::from_headers(|name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        })
+    }
+}
+
+/// The outcome of a local authentication attempt.
+#[derive(Debug)]
+pub enum Outcome {
+    /// Successfully authenticated.
+    Authenticated(UserInformation),
+    /// Local credentials were presented, but are invalid.
+    Failed,
+    /// No local credentials were presented, delegate to the next authenticator.
+    Delegate,
+}
+
+#[derive(Clone)]
+pub struct LocalAuthenticator {
+    methods: LocalAuthMethods,
+    pool: Pool,
+}
+
+impl LocalAuthenticator {
+    pub fn from_config(config: LocalAuthConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            methods: config.methods,
+            pool: config.postgres.create_pool()?,
+        })
+    }
+
+    pub async fn authenticate(&self, credentials: LocalCredentials) -> Result<Outcome, Error> {
+        if self.methods.api_keys {
+            if let Some(key) = &credentials.api_key {
+                return self.authenticate_api_key(key).await;
+            }
+        }
+
+        if self.methods.basic {
+            if let Some((username, password)) = credentials.basic {
+                return self.authenticate_basic(&username, password).await;
+            }
+        }
+
+        Ok(Outcome::Delegate)
+    }
+
+    async fn authenticate_api_key(&self, key: &str) -> Result<Outcome, Error> {
+        let con = self.pool.get().await?;
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    USER_ID,
+    ROLES
+FROM
+    api_keys
+WHERE
+    HASH = $1
+"#,
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+
+        Ok(match con.query_opt(&stmt, &[&hash]).await? {
+            Some(row) => Outcome::Authenticated(UserInformation::Authenticated(UserDetails {
+                user_id: row.try_get("USER_ID")?,
+                roles: row.try_get("ROLES")?,
+            })),
+            None => Outcome::Failed,
+        })
+    }
+
+    async fn authenticate_basic(&self, username: &str, password: String) -> Result<Outcome, Error> {
+        let con = self.pool.get().await?;
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    PASSWORD_HASH,
+    ROLES
+FROM
+    users
+WHERE
+    USERNAME = $1
+"#,
+                &[Type::VARCHAR],
+            )
+            .await?;
+
+        let row = match con.query_opt(&stmt, &[&username]).await? {
+            Some(row) => row,
+            // not a local user, might be an access token
+            None => return Ok(Outcome::Delegate),
+        };
+
+        let password_hash: String = row.try_get("PASSWORD_HASH")?;
+        let roles: Vec<String> = row.try_get("ROLES")?;
+
+        // verifying is expensive by design, so don't block the executor
+        let valid =
+            tokio::task::spawn_blocking(move || verify_password(&password_hash, &password)).await?;
+
+        Ok(match valid {
+            true => Outcome::Authenticated(UserInformation::Authenticated(UserDetails {
+                user_id: username.to_string(),
+                roles,
+            })),
+            false => Outcome::Failed,
+        })
+    }
+}
+
+fn verify_password(password_hash: &str, password: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(err) => {
+            log::warn!("Invalid password hash: {err}");
+            false
+        }
+    }
+}
+
+/// Extract username and password from a basic auth header.
+#[cfg(test)]
+fn basic_credentials(req: &ServiceRequest) -> Option<(String, String)> {
+    LocalCredentials::from_request(req).basic
+}
+
+/// Parse username and password from the value of a basic auth header.
+pub(crate) fn parse_basic(value: &str) -> Option<(String, String)> {
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use argon2::{password_hash::SaltString, PasswordHasher};
+
+    fn hash(password: &str) -> String {
+        let salt = SaltString::new("c29tZXNhbHQ").unwrap();
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn basic(value: &str) -> Option<(String, String)> {
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, value))
+            .to_srv_request();
+        basic_credentials(&req)
+    }
+
+    #[test]
+    fn test_methods() {
+        let methods: LocalAuthMethods = serde_json::from_str("{}").unwrap();
+        assert!(!methods.is_enabled());
+
+        let methods: LocalAuthMethods = serde_json::from_str(r#"{"basic": true}"#).unwrap();
+        assert!(methods.is_enabled());
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = hash("secret");
+
+        assert!(verify_password(&hash, "secret"));
+        assert!(!verify_password(&hash, "Secret"));
+        assert!(!verify_password(&hash, ""));
+    }
+
+    #[test]
+    fn test_verify_invalid_hash() {
+        assert!(!verify_password("secret", "secret"));
+        assert!(!verify_password("", ""));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        assert_eq!(
+            basic(&format!("Basic {}", base64::encode("foo:bar"))),
+            Some(("foo".to_string(), "bar".to_string()))
+        );
+        // only split on the first colon, passwords may contain colons
+        assert_eq!(
+            basic(&format!("Basic {}", base64::encode("foo:bar:baz"))),
+            Some(("foo".to_string(), "bar:baz".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_local_credentials() {
+        let req = TestRequest::default()
+            .insert_header((HEADER_API_KEY, "key"))
+            .to_srv_request();
+        assert_eq!(
+            LocalCredentials::from_request(&req),
+            LocalCredentials {
+                api_key: Some("key".to_string()),
+                basic: None,
+            }
+        );
+
+        assert_eq!(
+            LocalCredentials::from_headers(|_| None),
+            LocalCredentials::default()
+        );
+    }
+
+    #[test]
+    fn test_basic_credentials_invalid() {
+        assert_eq!(
+            basic(&format!("Bearer {}", base64::encode("foo:bar"))),
+            None
+        );
+        assert_eq!(basic(&format!("Basic {}", base64::encode("foo"))), None);
+        assert_eq!(basic("Basic %%%"), None);
+        assert_eq!(basic("Basic"), None);
+
+        assert_eq!(
+            basic_credentials(&TestRequest::default().to_srv_request()),
+            None
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    HttpMessage, HttpResponse,
+};
+use drogue_bazaar::auth::{UserDetails, UserInformation};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter},
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+/// A scope, limiting the access of a client.
+///
+/// Scopes are granted as roles of the user, e.g. the roles of an API key, or the roles of an
+/// OAuth token. Users without any scope role have full access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Read things.
+    ThingsRead,
+    /// Modify things, includes [`Scope::StateReport`].
+    ThingsWrite,
+    /// Report the state of things, as a device would.
+    StateReport,
+}
+
+impl Scope {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::ThingsRead => "things:read",
+            Self::ThingsWrite => "things:write",
+            Self::StateReport => "state:report",
+        }
+    }
+
+    fn from_role(role: &str) -> Option<Self> {
+        [Self::ThingsRead, Self::ThingsWrite, Self::StateReport]
+            .into_iter()
+            .find(|scope| scope.as_str() == role)
+    }
+
+    /// The scope required by an API request: reporting for updates of the reported state, reading
+    /// for safe methods, and writing for all others.
+    pub fn required(req: &ServiceRequest) -> Self {
+        let reported = || {
+            req.match_info()
+                .unprocessed()
+                .trim_matches('/')
+                .split('/')
+                .nth(3)
+                == Some("reportedStates")
+        };
+
+        match *req.method() {
+            Method::GET | Method::HEAD | Method::OPTIONS => Self::ThingsRead,
+            Method::PUT if reported() => Self::StateReport,
+            _ => Self::ThingsWrite,
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The scopes of a user, or `None` if the user isn't restricted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scopes(Option<BTreeSet<Scope>>);
+
+impl From<&UserInformation> for Scopes {
+    fn from(user: &UserInformation) -> Self {
+        match user {
+            UserInformation::Authenticated(UserDetails { roles, .. }) => {
+                let scopes = roles
+                    .iter()
+                    .filter_map(|role| Scope::from_role(role))
+                    .collect::<BTreeSet<_>>();
+                Self((!scopes.is_empty()).then_some(scopes))
+            }
+            UserInformation::Anonymous => Self(None),
+        }
+    }
+}
+
+impl Scopes {
+    /// Check if the scopes allow access requiring the scope.
+    pub fn allows(&self, scope: Scope) -> bool {
+        match &self.0 {
+            None => true,
+            Some(scopes) => {
+                scopes.contains(&scope)
+                    || (scope == Scope::StateReport && scopes.contains(&Scope::ThingsWrite))
+            }
+        }
+    }
+}
+
+/// Middleware, checking the scopes of the user.
+///
+/// Must be registered after the authentication middleware, so that the user information is
+/// available.
+pub struct ScopeAuthZ {
+    scope: Option<Scope>,
+}
+
+impl ScopeAuthZ {
+    /// Require the scope of each request, see [`Scope::required`].
+    pub fn new() -> Self {
+        Self { scope: None }
+    }
+
+    /// Require a fixed scope, for all requests.
+    pub fn fixed(scope: Scope) -> Self {
+        Self { scope: Some(scope) }
+    }
+}
+
+impl Default for ScopeAuthZ {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for ScopeAuthZ
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = ScopeAuthZMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(ScopeAuthZMiddleware {
+            service: Rc::new(service),
+            scope: self.scope,
+        }))
+    }
+}
+
+pub struct ScopeAuthZMiddleware<S> {
+    service: Rc<S>,
+    scope: Option<Scope>,
+}
+
+impl<S, B> Service<ServiceRequest> for ScopeAuthZMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let scope = self.scope.unwrap_or_else(|| Scope::required(&req));
+
+        Box::pin(async move {
+            let allowed = req
+                .extensions()
+                .get::<UserInformation>()
+                .map_or(true, |user| Scopes::from(user).allows(scope));
+
+            if !allowed {
+                return Ok(req.into_response(
+                    HttpResponse::Forbidden().body(format!("Missing scope: {scope}")),
+                ));
+            }
+
+            Ok(service.call(req).await?.map_into_boxed_body())
+        })
+    }
+}
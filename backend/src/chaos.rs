@@ -0,0 +1,26 @@
+use actix_web::{web, HttpResponse};
+use drogue_doppelgaenger_core::chaos::{self, FaultConfig};
+
+pub async fn components() -> HttpResponse {
+    HttpResponse::Ok().json(chaos::components())
+}
+
+pub async fn faults_get(component: web::Path<String>) -> HttpResponse {
+    match chaos::lookup(&component) {
+        Some(faults) => HttpResponse::Ok().json(faults.get()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+pub async fn faults_set(
+    component: web::Path<String>,
+    payload: web::Json<FaultConfig>,
+) -> HttpResponse {
+    match chaos::lookup(&component) {
+        Some(faults) => {
+            faults.set(payload.into_inner());
+            HttpResponse::NoContent().finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
@@ -0,0 +1,75 @@
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+};
+use drogue_doppelgaenger_core::correlation::{self, HEADER_CORRELATION_ID};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use tracing::Instrument;
+
+/// Middleware, initializing the correlation ID from a request header, or creating a new one.
+///
+/// The correlation ID is also returned as a response header.
+pub struct Correlation;
+
+impl<S, B> Transform<S, ServiceRequest> for Correlation
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = CorrelationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CorrelationMiddleware { service }))
+    }
+}
+
+pub struct CorrelationMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CorrelationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let correlation_id = req
+            .headers()
+            .get(HEADER_CORRELATION_ID)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string)
+            .unwrap_or_else(correlation::new_id);
+
+        let span = tracing::info_span!(
+            "request",
+            correlation_id = %correlation_id,
+            method = %req.method(),
+            path = %req.path(),
+        );
+
+        let fut = self.service.call(req);
+
+        Box::pin(correlation::scope(
+            correlation_id.clone(),
+            async move {
+                let mut res = fut.await?;
+                if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static("x-correlation-id"), value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        ))
+    }
+}
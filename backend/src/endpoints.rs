@@ -1,47 +1,229 @@
 use crate::{
-    notifier::actix::WebSocketHandler,
-    utils::{to_datetime, to_duration},
+    auth::{ApplicationAuthorizer, Scopes},
+    notifier::{actix::WebSocketHandler, Encoding, CBOR_PROTOCOL},
+    utils::{self, to_datetime, to_duration, Preconditions, Representation},
     Instance,
 };
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{
+    http::{
+        header::{self, ETag, EntityTag, HeaderValue, LastModified},
+        StatusCode,
+    },
+    web::{self, Bytes, BytesMut},
+    HttpRequest, HttpResponse, ResponseError,
+};
 use actix_web_actors::ws;
 use chrono::Utc;
 use drogue_bazaar::auth::UserInformation;
+use drogue_client::user::v1::authz::Permission;
 use drogue_doppelgaenger_core::{
-    command::CommandSink,
-    listener::KafkaSource,
+    command::{Command, CommandSink},
+    error::ErrorInformation,
+    listener::Listener,
+    machine::{self, OutboxMessage, Outcome},
+    model::{Internal, InternalThingExt},
     notifier::Notifier,
-    processor::{sink::Sink, SetDesiredValue},
+    processor::{sink::Sink, Event, Message, SetDesiredValue},
     service::{
-        AnnotationsUpdater, DefaultService, DesiredStateUpdate, DesiredStateUpdater,
-        DesiredStateValueUpdater, Id, JsonMergeUpdater, JsonPatchUpdater, Patch,
-        ReportedStateUpdater, Service, StateRemover, StateType, SyntheticStateUpdater, UpdateMode,
-        UpdateOptions,
+        self, AnnotationsUpdater, BatchOperation, DefaultService, DesiredStateUpdate,
+        DesiredStateUpdater, DesiredStateValueUpdater, GuardedUpdater, Id, JsonMergeUpdater,
+        JsonPatchUpdater, ManagedSections, Patch, ReportedStateUpdater, Service, StateRemover,
+        StateType, SyntheticStateUpdater, UpdateMode, UpdateOptions,
     },
-    storage::Storage,
+    storage::{query::Query, ListOptions, Storage},
 };
-use drogue_doppelgaenger_model::{Reconciliation, SyntheticType, Thing};
+use drogue_doppelgaenger_model::{Reconciliation, SyntheticType, Thing, ThingState};
+use futures::StreamExt;
 use serde_json::{json, Value};
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::SystemTime};
 
-const OPTS: UpdateOptions = UpdateOptions {
+pub(crate) const OPTS: UpdateOptions = UpdateOptions {
     ignore_unclean_inbox: true,
+    resource_version: None,
+    unmodified_since: None,
 };
 
+/// The number of things in a page, if not requested otherwise.
+pub(crate) const DEFAULT_PAGE_SIZE: u32 = 100;
+/// The maximum number of things in a page.
+pub(crate) const MAX_PAGE_SIZE: u32 = 1000;
+
 pub async fn things_get<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
     path: web::Path<Id>,
+    representation: Representation,
 ) -> Result<HttpResponse, actix_web::Error> {
     Ok(match service.get(&path.into_inner()).await? {
-        Some(thing) => HttpResponse::Ok().json(thing),
+        Some(thing) => {
+            let mut response = thing_response(thing, representation);
+            response
+                .headers_mut()
+                .insert(header::VARY, HeaderValue::from_static("Accept"));
+            response
+        }
         None => HttpResponse::NotFound().finish(),
     })
 }
 
+/// Get only the values of the features of a thing, see [`ThingState`].
+pub async fn things_get_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<Id>,
+) -> Result<HttpResponse, actix_web::Error> {
+    Ok(match service.get(&path.into_inner()).await? {
+        Some(thing) => thing_response(thing, Representation::State),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+fn thing_response(thing: Thing<Internal>, representation: Representation) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(resource_version) = &thing.metadata.resource_version {
+        response.insert_header(ETag(EntityTag::new_strong(resource_version.clone())));
+    }
+    if let Some(last_modified) = thing.last_modified() {
+        response.insert_header(LastModified(SystemTime::from(last_modified).into()));
+    }
+
+    match representation {
+        Representation::Thing => response.json(thing),
+        Representation::State => response
+            .content_type(utils::THING_STATE_CONTENT_TYPE)
+            .json(ThingState::from(thing)),
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ListQuery {
+    /// The maximum number of things to return.
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// The continuation token, returned with the previous page.
+    #[serde(default, rename = "continue")]
+    pub continue_token: Option<String>,
+    /// Only list things matching the label selector, e.g. `environment=prod,region in (eu,us)`.
+    #[serde(default, rename = "labelSelector")]
+    pub label_selector: String,
+    /// Only list things with matching state, e.g. `reportedState.temperature > 30`.
+    #[serde(default)]
+    pub q: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ThingPage {
+    pub items: Vec<Thing>,
+    /// The token to request the next page, absent on the last page.
+    #[serde(rename = "continue", skip_serializing_if = "Option::is_none")]
+    pub continue_token: Option<String>,
+}
+
+/// Encode the name of the last thing of a page, as continuation token.
+fn encode_continue(name: &str) -> String {
+    base64::encode_config(name, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decode a continuation token, into the name of the last thing of the previous page.
+fn decode_continue(token: &str) -> Result<String, utils::Error> {
+    base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|name| String::from_utf8(name).ok())
+        .ok_or(utils::Error::ContinuationToken)
+}
+
+/// List the things of an application, one page at a time.
+pub async fn things_list<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<String>,
+    query: web::Query<ListQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let application = path.into_inner();
+    let ListQuery {
+        limit,
+        continue_token,
+        label_selector,
+        q,
+    } = query.into_inner();
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let opts = ListOptions {
+        after: continue_token.as_deref().map(decode_continue).transpose()?,
+        // fetch one more, to know if there is a next page
+        limit: Some(limit + 1),
+        selector: label_selector.parse().map_err(utils::Error::from)?,
+    };
+
+    let query: Query = q.parse().map_err(utils::Error::from)?;
+    let mut items = match query.is_empty() {
+        true => service.list_with(&application, &opts).await?,
+        false => service.query(&application, &query, &opts).await?,
+    };
+
+    let continue_token = match items.len() > limit as usize {
+        true => {
+            items.truncate(limit as usize);
+            items
+                .last()
+                .map(|thing| encode_continue(&thing.metadata.name))
+        }
+        false => None,
+    };
+
+    Ok(HttpResponse::Ok().json(ThingPage {
+        items: items
+            .into_iter()
+            .map(|thing| thing.into_external())
+            .collect(),
+        continue_token,
+    }))
+}
+
+/// Get the aggregated statistics of the things of an application.
+pub async fn things_stats<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    Ok(HttpResponse::Ok().json(service.stats(&path.into_inner()).await?))
+}
+
+/// Get the delivery state of the recent outbox events of a thing.
+pub async fn things_deliveries<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<Id>,
+) -> Result<HttpResponse, actix_web::Error> {
+    Ok(match service.deliveries(&path.into_inner()).await? {
+        Some(deliveries) => HttpResponse::Ok().json(deliveries),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
+
+/// Get a previous generation of a thing.
+pub async fn things_revision<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<(String, String, u32)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (application, thing, generation) = path.into_inner();
+
+    Ok(
+        match service
+            .get_revision(&Id::new(application, thing), generation)
+            .await?
+        {
+            Some(thing) => HttpResponse::Ok().json(thing),
+            None => HttpResponse::NotFound().finish(),
+        },
+    )
+}
+
 pub async fn things_create<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    authorizer: web::Data<ApplicationAuthorizer>,
+    user: UserInformation,
     payload: web::Json<Thing>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    authorizer
+        .authorize(&user, &payload.metadata.application, Permission::Write)
+        .await?;
+
     service
         .create(payload.into_inner().strip_internal())
         .await?;
@@ -49,30 +231,381 @@ pub async fn things_create<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     Ok(HttpResponse::Created().json(json!({})))
 }
 
+/// The maximum number of operations of a single batch.
+const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BatchRequest {
+    Create(Thing),
+    Update(Thing),
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct BatchResult {
+    /// The HTTP status code, the operation would have returned as single request.
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorInformation>,
+}
+
+/// Create and replace multiple things, with a single request.
+///
+/// The outcome of each operation is reported individually, in the same order as the operations.
+pub async fn things_batch<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    authorizer: web::Data<ApplicationAuthorizer>,
+    user: UserInformation,
+    payload: web::Json<Vec<BatchRequest>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let payload = payload.into_inner();
+    if payload.len() > MAX_BATCH_SIZE {
+        return Err(utils::Error::BatchSize(payload.len(), MAX_BATCH_SIZE).into());
+    }
+
+    authorizer
+        .authorize_all(
+            &user,
+            payload.iter().map(|request| match request {
+                BatchRequest::Create(thing) | BatchRequest::Update(thing) => {
+                    thing.metadata.application.as_str()
+                }
+            }),
+            Permission::Write,
+        )
+        .await?;
+
+    let (operations, created): (Vec<_>, Vec<_>) = payload
+        .into_iter()
+        .map(|request| match request {
+            BatchRequest::Create(thing) => (BatchOperation::Create(thing.strip_internal()), true),
+            BatchRequest::Update(thing) => (BatchOperation::Update(thing.strip_internal()), false),
+        })
+        .unzip();
+
+    let results = service
+        .apply_batch(operations, &OPTS)
+        .await?
+        .into_iter()
+        .zip(created)
+        .map(|(result, created)| match result {
+            Ok(_) if created => BatchResult {
+                status: StatusCode::CREATED.as_u16(),
+                error: None,
+            },
+            Ok(_) => BatchResult {
+                status: StatusCode::NO_CONTENT.as_u16(),
+                error: None,
+            },
+            Err(err) => BatchResult {
+                status: err.error_response().status().as_u16(),
+                error: Some(ErrorInformation {
+                    error: "OperationFailed".to_string(),
+                    message: Some(err.to_string()),
+                }),
+            },
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DryRunQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// A command, which would have been sent.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct DryRunCommand {
+    pub application: String,
+    pub device: String,
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// The payload, base64 encoded.
+    pub payload: String,
+}
+
+impl From<Command> for DryRunCommand {
+    fn from(command: Command) -> Self {
+        Self {
+            application: command.application,
+            device: command.device,
+            channel: command.channel,
+            route: command.route,
+            payload: base64::encode(command.payload),
+        }
+    }
+}
+
+/// The outcome of a dry run.
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct DryRunResult {
+    /// The thing, as it would have been stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thing: Option<Thing<Internal>>,
+    /// The events, which would have been sent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outbox: Vec<OutboxMessage>,
+    /// The commands, which would have been sent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<DryRunCommand>,
+    /// The reason the thing was rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorInformation>,
+}
+
+/// Run the machine for a thing, without storing the result.
+///
+/// Returns the resulting thing, or the reason it was rejected, using `422` as status code.
+pub async fn things_validate<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    authorizer: web::Data<ApplicationAuthorizer>,
+    user: UserInformation,
+    payload: web::Json<Thing>,
+) -> Result<HttpResponse, actix_web::Error> {
+    authorizer
+        .authorize(&user, &payload.metadata.application, Permission::Read)
+        .await?;
+
+    dry_run(&service, payload.into_inner()).await
+}
+
+/// Dry run a thing, addressed by its path.
+///
+/// Only dry runs are supported, requiring the `dryRun` query parameter.
+pub async fn things_dry_run<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<Id>,
+    query: web::Query<DryRunQuery>,
+    payload: web::Json<Thing>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if !query.dry_run {
+        return Err(utils::Error::DryRunRequired.into());
+    }
+
+    let Id { application, thing } = path.into_inner();
+    let mut payload = payload.into_inner();
+    payload.metadata.application = application;
+    payload.metadata.name = thing;
+
+    dry_run(&service, payload).await
+}
+
+async fn dry_run<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: &DefaultService<S, N, Si, Cmd>,
+    thing: Thing,
+) -> Result<HttpResponse, actix_web::Error> {
+    match service.dry_run(thing.strip_internal()).await {
+        Ok(Outcome {
+            new_thing,
+            outbox,
+            commands,
+        }) => Ok(HttpResponse::Ok().json(DryRunResult {
+            thing: Some(new_thing),
+            outbox,
+            commands: commands.into_iter().map(Into::into).collect(),
+            error: None,
+        })),
+        Err(service::Error::Machine(err)) => {
+            let error = match err {
+                machine::Error::Mutator(_) => "InvalidUpdate",
+                machine::Error::Reconcile(_) => "ReconcileFailed",
+                machine::Error::Validation(_) => "ValidationFailed",
+                machine::Error::Internal(_) => "InternalError",
+            };
+            Ok(HttpResponse::UnprocessableEntity().json(DryRunResult {
+                error: Some(ErrorInformation {
+                    error: error.to_string(),
+                    message: Some(err.to_string()),
+                }),
+                ..Default::default()
+            }))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The maximum size of a single line, when importing things.
+const MAX_IMPORT_LINE: usize = 2 * 1024 * 1024;
+
+/// Export all things of an application, as newline delimited JSON.
+///
+/// The internal state of the things is not part of the export.
+pub async fn things_export<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let things = service.into_inner().export(path.into_inner()).map(
+        |thing| -> Result<Bytes, Box<dyn std::error::Error>> {
+            let mut line = serde_json::to_vec(&thing?.strip_internal::<Value>())?;
+            line.push(b'\n');
+            Ok(line.into())
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(things)
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct ImportResult {
+    /// The number of imported things.
+    pub imported: usize,
+    /// The lines which could not be imported.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<ImportFailure>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ImportFailure {
+    /// The line number, starting with 1.
+    pub line: usize,
+    /// The HTTP status code, creating the thing would have returned.
+    pub status: u16,
+    pub error: ErrorInformation,
+}
+
+/// Import things into an application, from newline delimited JSON.
+///
+/// Things are imported into the application of the request, ignoring the application of the
+/// thing. Existing things are not replaced, but reported as failed.
+pub async fn things_import<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    path: web::Path<String>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let application = path.into_inner();
+
+    let mut result = ImportResult::default();
+    let mut buffer = BytesMut::new();
+    let mut line = 0;
+
+    while let Some(chunk) = payload.next().await {
+        buffer.extend_from_slice(&chunk?);
+
+        while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+            let data = buffer.split_to(pos + 1);
+            line += 1;
+            import_line(&service, &application, line, &data[..pos], &mut result).await;
+        }
+
+        if buffer.len() > MAX_IMPORT_LINE {
+            return Err(utils::Error::LineSize(line + 1, MAX_IMPORT_LINE).into());
+        }
+    }
+
+    // the last line might not be terminated
+    line += 1;
+    import_line(&service, &application, line, &buffer, &mut result).await;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+async fn import_line<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: &DefaultService<S, N, Si, Cmd>,
+    application: &str,
+    line: usize,
+    data: &[u8],
+    result: &mut ImportResult,
+) {
+    if data.iter().all(u8::is_ascii_whitespace) {
+        return;
+    }
+
+    let failure = match serde_json::from_slice::<Thing>(data) {
+        Ok(thing) => {
+            let mut thing = thing.strip_internal();
+            thing.metadata.application = application.to_string();
+            match service.import(thing).await {
+                Ok(_) => {
+                    result.imported += 1;
+                    return;
+                }
+                Err(err) => ImportFailure {
+                    line,
+                    status: err.error_response().status().as_u16(),
+                    error: ErrorInformation {
+                        error: "ImportFailed".to_string(),
+                        message: Some(err.to_string()),
+                    },
+                },
+            }
+        }
+        Err(err) => ImportFailure {
+            line,
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            error: ErrorInformation {
+                error: "InvalidFormat".to_string(),
+                message: Some(err.to_string()),
+            },
+        },
+    };
+
+    result.failed.push(failure);
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct UpdateQuery {
+    /// Preserve the sections of the thing, managed by the system.
+    #[serde(default)]
+    pub guarded: bool,
+    /// A comma separated list of managed sections to take from the payload, when guarded.
+    #[serde(default)]
+    pub include: String,
+}
+
 pub async fn things_update<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    authorizer: web::Data<ApplicationAuthorizer>,
+    user: UserInformation,
+    preconditions: Preconditions,
     payload: web::Json<Thing>,
+    query: web::Query<UpdateQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let application = payload.metadata.application.clone();
-    let thing = payload.metadata.name.clone();
-    let payload = payload.into_inner();
-
-    service
-        .update(&Id { application, thing }, &payload.strip_internal(), &OPTS)
+    authorizer
+        .authorize(&user, &application, Permission::Write)
         .await?;
+    let thing = payload.metadata.name.clone();
+    let payload = payload.into_inner().strip_internal();
+    let id = Id { application, thing };
+    let opts = preconditions.apply(OPTS);
+
+    if query.guarded {
+        let updater = GuardedUpdater {
+            thing: payload,
+            include: query
+                .include
+                .parse::<ManagedSections>()
+                .map_err(utils::Error::from)?,
+        };
+        service.update(&id, &updater, &opts).await?;
+    } else {
+        service.update(&id, &payload, &opts).await?;
+    }
 
     Ok(HttpResponse::NoContent().json(json!({})))
 }
 
 pub async fn things_patch<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
     payload: web::Json<Patch>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let payload = payload.into_inner();
 
     service
-        .update(&path.into_inner(), &JsonPatchUpdater(payload), &OPTS)
+        .update(
+            &path.into_inner(),
+            &JsonPatchUpdater(payload),
+            &preconditions.apply(OPTS),
+        )
         .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
@@ -80,13 +613,18 @@ pub async fn things_patch<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
 
 pub async fn things_merge<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
     payload: web::Json<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let payload = payload.into_inner();
 
     service
-        .update(&path.into_inner(), &JsonMergeUpdater(payload), &OPTS)
+        .update(
+            &path.into_inner(),
+            &JsonMergeUpdater(payload),
+            &preconditions.apply(OPTS),
+        )
         .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
@@ -94,6 +632,7 @@ pub async fn things_merge<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
 
 pub async fn things_update_reported_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
     payload: web::Json<BTreeMap<String, Value>>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -103,7 +642,25 @@ pub async fn things_update_reported_state<S: Storage, N: Notifier, Si: Sink, Cmd
         .update(
             &path.into_inner(),
             &ReportedStateUpdater(payload, UpdateMode::Merge),
-            &OPTS,
+            &preconditions.apply(OPTS),
+        )
+        .await?;
+
+    Ok(HttpResponse::NoContent().json(json!({})))
+}
+
+pub async fn things_delete_reported_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (application, thing, state) = path.into_inner();
+
+    service
+        .update(
+            &Id::new(application, thing),
+            &StateRemover(state, StateType::Reported),
+            &preconditions.apply(OPTS),
         )
         .await?;
 
@@ -112,6 +669,7 @@ pub async fn things_update_reported_state<S: Storage, N: Notifier, Si: Sink, Cmd
 
 pub async fn things_update_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<(String, String, String)>,
     payload: web::Json<SyntheticType>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -122,7 +680,7 @@ pub async fn things_update_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cm
         .update(
             &Id::new(application, thing),
             &SyntheticStateUpdater(state, payload),
-            &OPTS,
+            &preconditions.apply(OPTS),
         )
         .await?;
 
@@ -131,6 +689,7 @@ pub async fn things_update_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cm
 
 pub async fn things_delete_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<(String, String, String)>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let (application, thing, state) = path.into_inner();
@@ -139,7 +698,7 @@ pub async fn things_delete_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cm
         .update(
             &Id::new(application, thing),
             &StateRemover(state, StateType::Synthetic),
-            &OPTS,
+            &preconditions.apply(OPTS),
         )
         .await?;
 
@@ -148,6 +707,7 @@ pub async fn things_delete_synthetic_state<S: Storage, N: Notifier, Si: Sink, Cm
 
 pub async fn things_update_desired_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<(String, String, String)>,
     payload: web::Json<DesiredStateUpdate>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -158,7 +718,25 @@ pub async fn things_update_desired_state<S: Storage, N: Notifier, Si: Sink, Cmd:
         .update(
             &Id::new(application, thing),
             &DesiredStateUpdater(state, payload),
-            &OPTS,
+            &preconditions.apply(OPTS),
+        )
+        .await?;
+
+    Ok(HttpResponse::NoContent().json(json!({})))
+}
+
+pub async fn things_delete_desired_state<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
+    path: web::Path<(String, String, String)>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (application, thing, state) = path.into_inner();
+
+    service
+        .update(
+            &Id::new(application, thing),
+            &StateRemover(state, StateType::Desired),
+            &preconditions.apply(OPTS),
         )
         .await?;
 
@@ -173,6 +751,7 @@ pub async fn things_update_desired_state_value<
 >(
     request: HttpRequest,
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<(String, String, String)>,
     payload: web::Json<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
@@ -199,34 +778,88 @@ pub async fn things_update_desired_state_value<
         .update(
             &Id::new(application, thing),
             &DesiredStateValueUpdater(values),
-            &OPTS,
+            &preconditions.apply(OPTS),
         )
         .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
 }
 
+/// Set desired values of many things, by thing name and feature name.
+///
+/// Instead of updating the things directly, this publishes a `SetDesiredValue` event for each
+/// thing, so that the updates are processed the same way as events from devices.
+pub async fn things_update_desired_states<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    instance: web::Data<Instance>,
+    path: web::Path<String>,
+    payload: web::Json<BTreeMap<String, BTreeMap<String, Value>>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let application = path.into_inner();
+    if !instance.is_allowed(&application) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let payload = payload.into_inner();
+    if payload.len() > MAX_BATCH_SIZE {
+        return Err(utils::Error::BatchSize(payload.len(), MAX_BATCH_SIZE).into());
+    }
+
+    let total = payload.len();
+    let events = payload
+        .into_iter()
+        .map(|(thing, values)| {
+            let values = values
+                .into_iter()
+                .map(|(feature, value)| (feature, SetDesiredValue::Value(value)))
+                .collect();
+            Event::new(&application, thing, Message::SetDesiredValue { values })
+        })
+        .collect::<Vec<_>>();
+
+    match service.sink().publish_iter(events).await {
+        Ok(()) => Ok(HttpResponse::Accepted().json(json!({ "events": total }))),
+        Err((published, err)) => {
+            log::warn!("Failed to publish desired values: {err}");
+            Ok(HttpResponse::ServiceUnavailable().json(ErrorInformation {
+                error: "PublishFailed".to_string(),
+                message: Some(format!(
+                    "Published {published} of {total} events, before failing: {err}"
+                )),
+            }))
+        }
+    }
+}
+
 pub async fn things_update_reconciliation<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
     payload: web::Json<Reconciliation>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let payload = payload.into_inner();
 
-    service.update(&path.into_inner(), &payload, &OPTS).await?;
+    service
+        .update(&path.into_inner(), &payload, &preconditions.apply(OPTS))
+        .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
 }
 
 pub async fn things_update_annotations<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
     payload: web::Json<BTreeMap<String, Option<String>>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let payload = payload.into_inner();
 
     service
-        .update(&path.into_inner(), &AnnotationsUpdater(payload), &OPTS)
+        .update(
+            &path.into_inner(),
+            &AnnotationsUpdater(payload),
+            &preconditions.apply(OPTS),
+        )
         .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
@@ -234,10 +867,12 @@ pub async fn things_update_annotations<S: Storage, N: Notifier, Si: Sink, Cmd: C
 
 pub async fn things_delete<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    preconditions: Preconditions,
     path: web::Path<Id>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    // FIXME: allow adding preconditions
-    service.delete(&path.into_inner(), None).await?;
+    service
+        .delete(&path.into_inner(), Some(&preconditions.preconditions()))
+        .await?;
 
     Ok(HttpResponse::NoContent().json(json!({})))
 }
@@ -246,45 +881,61 @@ pub async fn things_notifications<S: Storage, N: Notifier, Si: Sink, Cmd: Comman
     req: HttpRequest,
     path: web::Path<String>,
     stream: web::Payload,
-    source: web::Data<KafkaSource>,
+    source: web::Data<Listener>,
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
     instance: web::Data<Instance>,
+    authorizer: web::Data<ApplicationAuthorizer>,
+    user: UserInformation,
 ) -> Result<HttpResponse, actix_web::Error> {
     let application = path.into_inner();
-    if let Some(expected_application) = &instance.application {
-        if expected_application != &application {
-            return Ok(HttpResponse::NotFound().finish());
-        }
+    if !instance.is_allowed(&application) {
+        return Ok(HttpResponse::NotFound().finish());
     }
 
+    // reading got authorized when connecting, writes are checked for each request
+    let write_access = authorizer
+        .authorize(&user, &application, Permission::Write)
+        .await
+        .is_ok();
+
     let handler =
-        WebSocketHandler::new(service.into_inner(), source.into_inner(), application, None);
-    ws::start(handler, &req, stream)
+        WebSocketHandler::new(service.into_inner(), source.into_inner(), application, None)
+            .with_encoding(Encoding::negotiate(&req))
+            .with_scopes(Scopes::from(&user))
+            .with_write_access(write_access);
+    ws::start_with_protocols(handler, &[CBOR_PROTOCOL], &req, stream)
 }
 
 pub async fn things_notifications_single<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
     req: HttpRequest,
     path: web::Path<(String, String)>,
     stream: web::Payload,
-    source: web::Data<KafkaSource>,
+    source: web::Data<Listener>,
     service: web::Data<DefaultService<S, N, Si, Cmd>>,
     instance: web::Data<Instance>,
+    authorizer: web::Data<ApplicationAuthorizer>,
     user: UserInformation,
 ) -> Result<HttpResponse, actix_web::Error> {
     log::info!("Start single notification: {user:?}");
 
     let (application, thing) = path.into_inner();
-    if let Some(expected_application) = &instance.application {
-        if expected_application != &application {
-            return Ok(HttpResponse::NotFound().finish());
-        }
+    if !instance.is_allowed(&application) {
+        return Ok(HttpResponse::NotFound().finish());
     }
 
+    let write_access = authorizer
+        .authorize(&user, &application, Permission::Write)
+        .await
+        .is_ok();
+
     let handler = WebSocketHandler::new(
         service.into_inner(),
         source.into_inner(),
         application,
         Some(thing),
-    );
-    ws::start(handler, &req, stream)
+    )
+    .with_encoding(Encoding::negotiate(&req))
+    .with_scopes(Scopes::from(&user))
+    .with_write_access(write_access);
+    ws::start_with_protocols(handler, &[CBOR_PROTOCOL], &req, stream)
 }
@@ -0,0 +1,311 @@
+//! The GraphQL API, allowing clients to fetch exactly the fields of things they need.
+//!
+//! Queries and mutations use the same service as the REST API, subscriptions are fed by the
+//! listener of change events. Access to applications is authorized by each resolver, using the
+//! [`ApplicationAuthorizer`].
+
+use crate::{
+    auth::ApplicationAuthorizer,
+    endpoints::{DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, OPTS},
+    Instance,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::{Context, Data, Json, Object, Schema, Subscription};
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use drogue_bazaar::auth::UserInformation;
+use drogue_client::user::v1::authz::Permission;
+use drogue_doppelgaenger_core::{
+    command::CommandSink,
+    listener::{Listener, Message},
+    model::Internal,
+    notifier::Notifier,
+    processor::{sink::Sink, SetDesiredValue},
+    service::{
+        DefaultService, DesiredStateValueUpdater, Id, ReportedStateUpdater, Service, UpdateMode,
+    },
+    storage::{ListOptions, Storage},
+};
+use drogue_doppelgaenger_model::{Thing, ThingState};
+use futures::{future::ready, Stream, StreamExt};
+use serde_json::Value;
+use std::{collections::BTreeMap, fmt::Display, marker::PhantomData, sync::Arc};
+
+pub type ThingsSchema<S, N, Si, Cmd> =
+    Schema<QueryRoot<S, N, Si, Cmd>, MutationRoot<S, N, Si, Cmd>, SubscriptionRoot<S, N, Si, Cmd>>;
+
+/// Create the schema, using the service and listener of the backend.
+pub fn schema<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    source: web::Data<Listener>,
+    instance: web::Data<Instance>,
+    authorizer: ApplicationAuthorizer,
+) -> ThingsSchema<S, N, Si, Cmd> {
+    Schema::build(
+        QueryRoot(PhantomData),
+        MutationRoot(PhantomData),
+        SubscriptionRoot(PhantomData),
+    )
+    .data(service)
+    .data(source)
+    .data(instance)
+    .data(authorizer)
+    .finish()
+}
+
+pub async fn graphql<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    schema: web::Data<ThingsSchema<S, N, Si, Cmd>>,
+    request: GraphQLRequest,
+    user: UserInformation,
+) -> GraphQLResponse {
+    schema.execute(request.into_inner().data(user)).await.into()
+}
+
+pub async fn graphql_ws<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    schema: web::Data<ThingsSchema<S, N, Si, Cmd>>,
+    request: HttpRequest,
+    payload: web::Payload,
+    user: UserInformation,
+) -> actix_web::Result<HttpResponse> {
+    let mut data = Data::default();
+    data.insert(user);
+    GraphQLSubscription::new(schema.get_ref().clone())
+        .with_data(data)
+        .start(&request, payload)
+}
+
+/// A thing, as exposed by the GraphQL API.
+pub struct ThingObject(Arc<Thing>);
+
+#[Object(name = "Thing")]
+impl ThingObject {
+    async fn application(&self) -> &str {
+        &self.0.metadata.application
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.metadata.name
+    }
+
+    async fn uid(&self) -> Option<&str> {
+        self.0.metadata.uid.as_deref()
+    }
+
+    async fn generation(&self) -> Option<u32> {
+        self.0.metadata.generation
+    }
+
+    async fn resource_version(&self) -> Option<&str> {
+        self.0.metadata.resource_version.as_deref()
+    }
+
+    async fn labels(&self) -> Json<BTreeMap<String, String>> {
+        Json(self.0.metadata.labels.clone())
+    }
+
+    async fn annotations(&self) -> Json<BTreeMap<String, String>> {
+        Json(self.0.metadata.annotations.clone())
+    }
+
+    /// The values of the reported state.
+    async fn reported_state(&self) -> Json<BTreeMap<String, Value>> {
+        Json(ThingState::from(self.0.as_ref()).reported_state)
+    }
+
+    /// The values of the desired state.
+    async fn desired_state(&self) -> Json<BTreeMap<String, Value>> {
+        Json(ThingState::from(self.0.as_ref()).desired_state)
+    }
+
+    /// The values of the synthetic state.
+    async fn synthetic_state(&self) -> Json<BTreeMap<String, Value>> {
+        Json(ThingState::from(self.0.as_ref()).synthetic_state)
+    }
+
+    /// The full thing, the same as returned by the REST API.
+    async fn thing(&self) -> Json<Thing> {
+        Json(self.0.as_ref().clone())
+    }
+}
+
+impl From<Thing<Internal>> for ThingObject {
+    fn from(thing: Thing<Internal>) -> Self {
+        Self(Arc::new(thing.into_external()))
+    }
+}
+
+pub struct QueryRoot<S, N, Si, Cmd>(PhantomData<fn() -> (S, N, Si, Cmd)>);
+
+#[Object(name = "Query")]
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> QueryRoot<S, N, Si, Cmd> {
+    /// Get a single thing.
+    async fn thing(
+        &self,
+        ctx: &Context<'_>,
+        application: String,
+        name: String,
+    ) -> async_graphql::Result<Option<ThingObject>> {
+        if !is_allowed(ctx, &application)? {
+            return Ok(None);
+        }
+        authorize(ctx, &application, Permission::Read).await?;
+
+        Ok(service::<S, N, Si, Cmd>(ctx)?
+            .get(&Id::new(application, name))
+            .await
+            .map_err(error)?
+            .map(ThingObject::from))
+    }
+
+    /// List things of an application, ordered by name.
+    async fn things(
+        &self,
+        ctx: &Context<'_>,
+        application: String,
+        #[graphql(desc = "A label selector, e.g. `environment=prod,region in (eu,us)`")]
+        label_selector: Option<String>,
+        #[graphql(desc = "The maximum number of things to return")] limit: Option<u32>,
+        #[graphql(desc = "Only return things with a name after this one")] after: Option<String>,
+    ) -> async_graphql::Result<Vec<ThingObject>> {
+        if !is_allowed(ctx, &application)? {
+            return Ok(vec![]);
+        }
+        authorize(ctx, &application, Permission::Read).await?;
+
+        let opts = ListOptions {
+            after,
+            limit: Some(limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)),
+            selector: label_selector.unwrap_or_default().parse().map_err(error)?,
+        };
+
+        Ok(service::<S, N, Si, Cmd>(ctx)?
+            .list_with(&application, &opts)
+            .await
+            .map_err(error)?
+            .into_iter()
+            .map(ThingObject::from)
+            .collect())
+    }
+}
+
+pub struct MutationRoot<S, N, Si, Cmd>(PhantomData<fn() -> (S, N, Si, Cmd)>);
+
+#[Object(name = "Mutation")]
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> MutationRoot<S, N, Si, Cmd> {
+    /// Merge values into the reported state of a thing.
+    async fn update_reported_state(
+        &self,
+        ctx: &Context<'_>,
+        application: String,
+        name: String,
+        state: Json<BTreeMap<String, Value>>,
+    ) -> async_graphql::Result<ThingObject> {
+        if !is_allowed(ctx, &application)? {
+            return Err(error("Thing not found"));
+        }
+        authorize(ctx, &application, Permission::Write).await?;
+
+        Ok(service::<S, N, Si, Cmd>(ctx)?
+            .update(
+                &Id::new(application, name),
+                &ReportedStateUpdater(state.0, UpdateMode::Merge),
+                &OPTS,
+            )
+            .await
+            .map_err(error)?
+            .into())
+    }
+
+    /// Set the values of desired state features of a thing.
+    async fn set_desired_values(
+        &self,
+        ctx: &Context<'_>,
+        application: String,
+        name: String,
+        values: Json<BTreeMap<String, Value>>,
+    ) -> async_graphql::Result<ThingObject> {
+        if !is_allowed(ctx, &application)? {
+            return Err(error("Thing not found"));
+        }
+        authorize(ctx, &application, Permission::Write).await?;
+
+        let values = values
+            .0
+            .into_iter()
+            .map(|(feature, value)| (feature, SetDesiredValue::Value(value)))
+            .collect();
+
+        Ok(service::<S, N, Si, Cmd>(ctx)?
+            .update(
+                &Id::new(application, name),
+                &DesiredStateValueUpdater(values),
+                &OPTS,
+            )
+            .await
+            .map_err(error)?
+            .into())
+    }
+}
+
+pub struct SubscriptionRoot<S, N, Si, Cmd>(PhantomData<fn() -> (S, N, Si, Cmd)>);
+
+#[Subscription(name = "Subscription")]
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> SubscriptionRoot<S, N, Si, Cmd> {
+    /// Changes of the things of an application, or of a single thing.
+    ///
+    /// Only full changes are sent, changes sent as patch are skipped, as well as missed changes.
+    async fn thing_changes(
+        &self,
+        ctx: &Context<'_>,
+        application: String,
+        name: Option<String>,
+    ) -> async_graphql::Result<impl Stream<Item = ThingObject>> {
+        if !is_allowed(ctx, &application)? {
+            return Err(error("Application not found"));
+        }
+        authorize(ctx, &application, Permission::Read).await?;
+
+        let source = ctx.data::<web::Data<Listener>>()?;
+        let source = match name {
+            Some(name) => source.subscribe(Id::new(application, name)),
+            None => source.subscribe_application(application),
+        };
+
+        Ok(source.filter_map(|msg| {
+            ready(match msg {
+                Ok(Message::Change(thing)) => Some(ThingObject(thing)),
+                Ok(Message::Patch(_)) | Err(_) => None,
+            })
+        }))
+    }
+}
+
+fn service<'c, S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    ctx: &Context<'c>,
+) -> async_graphql::Result<&'c web::Data<DefaultService<S, N, Si, Cmd>>> {
+    ctx.data::<web::Data<DefaultService<S, N, Si, Cmd>>>()
+}
+
+fn is_allowed(ctx: &Context<'_>, application: &str) -> async_graphql::Result<bool> {
+    Ok(ctx.data::<web::Data<Instance>>()?.is_allowed(application))
+}
+
+/// Authorize access to the application, for the user of the request.
+async fn authorize(
+    ctx: &Context<'_>,
+    application: &str,
+    permission: Permission,
+) -> async_graphql::Result<()> {
+    let user = ctx
+        .data_opt::<UserInformation>()
+        .cloned()
+        .unwrap_or(UserInformation::Anonymous);
+
+    ctx.data::<ApplicationAuthorizer>()?
+        .authorize(&user, application, permission)
+        .await
+        .map_err(error)
+}
+
+fn error(err: impl Display) -> async_graphql::Error {
+    async_graphql::Error::new(err.to_string())
+}
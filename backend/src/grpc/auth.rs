@@ -0,0 +1,164 @@
+//! Authentication of gRPC requests, using the same credentials as the HTTP API.
+
+use crate::{
+    auth::{parse_basic, LocalAuthenticator, LocalCredentials, Outcome},
+    Authentication,
+};
+use drogue_bazaar::{
+    actix::auth::authentication::{AuthN, Credentials, UsernameAndToken},
+    auth::UserInformation,
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Context, Poll, Service},
+    Status,
+};
+use tower::Layer;
+
+/// Authenticates requests with local credentials, OAuth tokens, or access tokens.
+#[derive(Clone)]
+pub struct Authenticator {
+    delegate: AuthN,
+    local: Option<LocalAuthenticator>,
+    /// If authentication is required, otherwise anonymous requests are allowed.
+    required: bool,
+}
+
+impl Authenticator {
+    pub fn new(auth: &Authentication) -> Self {
+        Self {
+            delegate: auth.delegate(),
+            local: auth.local_auth.clone(),
+            required: auth.is_enabled(),
+        }
+    }
+
+    /// Authenticate the request, the same way as [`crate::auth::LocalAuthN`] does.
+    pub async fn authenticate(&self, headers: &http::HeaderMap) -> Result<UserInformation, Status> {
+        let lookup = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(local) = &self.local {
+            let outcome = local
+                .authenticate(LocalCredentials::from_headers(lookup))
+                .await
+                .map_err(|err| {
+                    log::warn!("Failed to authenticate: {err}");
+                    Status::internal("Failed to authenticate")
+                })?;
+
+            match outcome {
+                Outcome::Authenticated(user) => return Ok(user),
+                Outcome::Failed => return Err(Status::unauthenticated("Invalid credentials")),
+                Outcome::Delegate => {}
+            }
+        }
+
+        let user = self
+            .delegate
+            .authenticate(credentials(lookup(http::header::AUTHORIZATION.as_str())))
+            .await
+            .map_err(|err| {
+                log::debug!("Failed to authenticate: {err:?}");
+                Status::unauthenticated("Invalid credentials")
+            })?;
+
+        match user {
+            UserInformation::Anonymous if self.required => {
+                Err(Status::unauthenticated("Missing credentials"))
+            }
+            user => Ok(user),
+        }
+    }
+}
+
+/// The credentials of the authorization header, for the delegated authentication.
+fn credentials(authorization: Option<&str>) -> Credentials {
+    match authorization {
+        Some(value) => match value.strip_prefix("Bearer ") {
+            Some(token) => Credentials::OpenIDToken(token.trim().to_string()),
+            None => match parse_basic(value) {
+                Some((username, access_token)) => Credentials::AccessToken(UsernameAndToken {
+                    username,
+                    access_token: Some(access_token),
+                }),
+                None => Credentials::Anonymous,
+            },
+        },
+        None => Credentials::Anonymous,
+    }
+}
+
+/// Layer, authenticating all requests before they reach the services.
+///
+/// The user information of authenticated requests gets added to the extensions of the request.
+#[derive(Clone)]
+pub struct AuthenticationLayer(pub Authenticator);
+
+impl<S> Layer<S> for AuthenticationLayer {
+    type Service = Authenticated<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Authenticated {
+            inner,
+            authenticator: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Authenticated<S> {
+    inner: S,
+    authenticator: Authenticator,
+}
+
+impl<S, B> Service<http::Request<B>> for Authenticated<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<B>) -> Self::Future {
+        // use the service which is ready, and keep a clone for the next request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = self.authenticator.clone();
+
+        Box::pin(async move {
+            match authenticator.authenticate(req.headers()).await {
+                Ok(user) => {
+                    req.extensions_mut().insert(user);
+                    inner.call(req).await
+                }
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_credentials() {
+        assert!(matches!(credentials(None), Credentials::Anonymous));
+        assert!(matches!(
+            credentials(Some("Bearer token")),
+            Credentials::OpenIDToken(token) if token == "token"
+        ));
+        assert!(matches!(
+            credentials(Some(&format!("Basic {}", base64::encode("foo:bar")))),
+            Credentials::AccessToken(UsernameAndToken { username, access_token })
+                if username == "foo" && access_token.as_deref() == Some("bar")
+        ));
+        assert!(matches!(credentials(Some("Foo")), Credentials::Anonymous));
+    }
+}
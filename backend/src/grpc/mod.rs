@@ -0,0 +1,72 @@
+//! A gRPC API, for machine-to-machine clients.
+//!
+//! The gRPC server runs alongside the HTTP server, sharing the same service. Clients are
+//! authenticated and authorized the same way as for the HTTP API.
+
+#[cfg(feature = "grpc")]
+mod auth;
+#[cfg(feature = "grpc")]
+mod server;
+
+use crate::{Authentication, Instance};
+use actix_web::web;
+use drogue_bazaar::app::Startup;
+use drogue_doppelgaenger_core::{
+    command::CommandSink, listener::Listener, notifier::Notifier, processor::sink::Sink,
+    service::DefaultService, storage::Storage,
+};
+use std::net::SocketAddr;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The address the gRPC server binds to.
+    #[serde(default = "default::bind_addr")]
+    pub bind_addr: SocketAddr,
+}
+
+mod default {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    /// Only bind to the loopback interface by default, exposing the API is explicit.
+    pub const fn bind_addr() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8082)
+    }
+}
+
+/// Spawn the gRPC server.
+#[cfg(feature = "grpc")]
+pub fn spawn<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    startup: &mut dyn Startup,
+    config: Config,
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    source: web::Data<Listener>,
+    instance: web::Data<Instance>,
+    auth: &Authentication,
+) -> anyhow::Result<()> {
+    log::info!("Starting gRPC server: {}", config.bind_addr);
+    let server = server::server(
+        config.bind_addr,
+        service,
+        source,
+        instance,
+        auth::Authenticator::new(auth),
+        auth.authorizer(),
+    );
+    startup.spawn(async move { Ok(server.await?) });
+    Ok(())
+}
+
+/// Spawn the gRPC server, which fails without the `grpc` feature.
+#[cfg(not(feature = "grpc"))]
+pub fn spawn<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    _: &mut dyn Startup,
+    _: Config,
+    _: web::Data<DefaultService<S, N, Si, Cmd>>,
+    _: web::Data<Listener>,
+    _: web::Data<Instance>,
+    _: &Authentication,
+) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "The gRPC API is configured, but the backend was built without the 'grpc' feature"
+    )
+}
@@ -0,0 +1,365 @@
+use super::auth::{AuthenticationLayer, Authenticator};
+use crate::{
+    auth::{ApplicationAuthorizer, Scope, Scopes},
+    endpoints::OPTS,
+    Instance,
+};
+use actix_web::{web, ResponseError};
+use drogue_bazaar::auth::UserInformation;
+use drogue_client::user::v1::authz::Permission;
+use drogue_doppelgaenger_core::{
+    command::CommandSink,
+    listener::{Listener, Message},
+    model::Internal,
+    notifier::Notifier,
+    processor::{sink::Sink, SetDesiredValue},
+    service::{DefaultService, DesiredStateValueUpdater, Id, JsonMergeUpdater, Service},
+    storage::Storage,
+};
+use drogue_doppelgaenger_model::{Thing, ThingState};
+use futures::{future::ready, Future, Stream, StreamExt};
+use prost_types::{value::Kind, ListValue, Struct};
+use std::{collections::BTreeMap, net::SocketAddr, pin::Pin};
+use tonic::{Request, Response, Status};
+
+mod api {
+    tonic::include_proto!("drogue.doppelgaenger.v1alpha1");
+}
+
+use api::things_server::{Things, ThingsServer};
+
+/// Create the gRPC server future, serving the things service.
+pub fn server<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    addr: SocketAddr,
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    source: web::Data<Listener>,
+    instance: web::Data<Instance>,
+    authenticator: Authenticator,
+    authorizer: ApplicationAuthorizer,
+) -> impl Future<Output = Result<(), tonic::transport::Error>> {
+    tonic::transport::Server::builder()
+        .layer(AuthenticationLayer(authenticator))
+        .add_service(ThingsServer::new(ThingsService {
+            service,
+            source,
+            instance,
+            authorizer,
+        }))
+        .serve(addr)
+}
+
+struct ThingsService<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> {
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    source: web::Data<Listener>,
+    instance: web::Data<Instance>,
+    authorizer: ApplicationAuthorizer,
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> ThingsService<S, N, Si, Cmd> {
+    /// Ensure the instance serves the application, as the REST API, reject it as not found.
+    fn ensure_allowed(&self, application: &str) -> Result<(), Status> {
+        match self.instance.is_allowed(application) {
+            true => Ok(()),
+            false => Err(Status::not_found("Application not found")),
+        }
+    }
+
+    /// Authorize access to the application, the same way as the HTTP API does.
+    ///
+    /// The user is added to the request by the [`AuthenticationLayer`].
+    async fn authorize(
+        &self,
+        user: &UserInformation,
+        application: &str,
+        scope: Scope,
+    ) -> Result<(), Status> {
+        self.ensure_allowed(application)?;
+
+        if !Scopes::from(user).allows(scope) {
+            return Err(Status::permission_denied(format!("Missing scope: {scope}")));
+        }
+
+        let permission = match scope {
+            Scope::ThingsRead => Permission::Read,
+            Scope::ThingsWrite | Scope::StateReport => Permission::Write,
+        };
+
+        self.authorizer
+            .authorize(user, application, permission)
+            .await
+            .map_err(|err| {
+                let message = err.to_string();
+                match err.as_response_error().status_code().as_u16() {
+                    403 => Status::permission_denied(message),
+                    _ => Status::internal(message),
+                }
+            })
+    }
+
+    async fn id(
+        &self,
+        user: &UserInformation,
+        id: Option<api::ThingId>,
+        scope: Scope,
+    ) -> Result<Id, Status> {
+        let id = id.ok_or_else(|| Status::invalid_argument("Missing thing ID"))?;
+        self.authorize(user, &id.application, scope).await?;
+        Ok(Id::new(id.application, id.name))
+    }
+
+    async fn thing(
+        &self,
+        user: &UserInformation,
+        thing: Option<Struct>,
+    ) -> Result<Thing<Internal>, Status> {
+        let thing: Thing = serde_json::from_value(from_struct(thing.unwrap_or_default()))
+            .map_err(|err| Status::invalid_argument(format!("Invalid thing: {err}")))?;
+        self.authorize(user, &thing.metadata.application, Scope::ThingsWrite)
+            .await?;
+        Ok(thing.strip_internal())
+    }
+}
+
+/// The user of the request, as authenticated by the [`AuthenticationLayer`].
+fn user<T>(request: &Request<T>) -> UserInformation {
+    request
+        .extensions()
+        .get::<UserInformation>()
+        .cloned()
+        .unwrap_or(UserInformation::Anonymous)
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<api::Thing, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Things for ThingsService<S, N, Si, Cmd> {
+    async fn get(&self, request: Request<api::ThingId>) -> Result<Response<api::Thing>, Status> {
+        let user = user(&request);
+        let id = self
+            .id(&user, Some(request.into_inner()), Scope::ThingsRead)
+            .await?;
+
+        match self.service.get(&id).await.map_err(status)? {
+            Some(thing) => Ok(Response::new(thing.into())),
+            None => Err(Status::not_found("Thing not found")),
+        }
+    }
+
+    async fn create(
+        &self,
+        request: Request<api::CreateRequest>,
+    ) -> Result<Response<api::Thing>, Status> {
+        let user = user(&request);
+        let thing = self.thing(&user, request.into_inner().thing).await?;
+
+        let thing = self.service.create(thing).await.map_err(status)?;
+
+        Ok(Response::new(thing.into()))
+    }
+
+    async fn update(
+        &self,
+        request: Request<api::UpdateRequest>,
+    ) -> Result<Response<api::Thing>, Status> {
+        let user = user(&request);
+        let thing = self.thing(&user, request.into_inner().thing).await?;
+        let id = Id::new(&thing.metadata.application, &thing.metadata.name);
+
+        let thing = self
+            .service
+            .update(&id, &thing, &OPTS)
+            .await
+            .map_err(status)?;
+
+        Ok(Response::new(thing.into()))
+    }
+
+    async fn patch(
+        &self,
+        request: Request<api::PatchRequest>,
+    ) -> Result<Response<api::Thing>, Status> {
+        let user = user(&request);
+        let request = request.into_inner();
+        let id = self.id(&user, request.id, Scope::ThingsWrite).await?;
+        let patch = from_struct(request.patch.unwrap_or_default());
+
+        let thing = self
+            .service
+            .update(&id, &JsonMergeUpdater(patch), &OPTS)
+            .await
+            .map_err(status)?;
+
+        Ok(Response::new(thing.into()))
+    }
+
+    async fn set_desired(
+        &self,
+        request: Request<api::SetDesiredRequest>,
+    ) -> Result<Response<api::Thing>, Status> {
+        let user = user(&request);
+        let request = request.into_inner();
+        let id = self.id(&user, request.id, Scope::ThingsWrite).await?;
+        let values = request
+            .values
+            .into_iter()
+            .map(|(feature, value)| (feature, SetDesiredValue::Value(from_value(value))))
+            .collect();
+
+        let thing = self
+            .service
+            .update(&id, &DesiredStateValueUpdater(values), &OPTS)
+            .await
+            .map_err(status)?;
+
+        Ok(Response::new(thing.into()))
+    }
+
+    type WatchStream = WatchStream;
+
+    /// Watch changes, skipping changes sent as patch.
+    ///
+    /// In case the client missed changes, the stream is ended with `DATA_LOSS`, so that the client
+    /// can re-fetch the current state.
+    async fn watch(
+        &self,
+        request: Request<api::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let user = user(&request);
+        let request = request.into_inner();
+        self.authorize(&user, &request.application, Scope::ThingsRead)
+            .await?;
+
+        let source = match request.name {
+            Some(name) => self.source.subscribe(Id::new(request.application, name)),
+            None => self.source.subscribe_application(request.application),
+        };
+
+        let stream = source
+            .filter_map(|msg| {
+                ready(match msg {
+                    Ok(Message::Change(thing)) => Some(Ok(api::Thing::from(thing.as_ref()))),
+                    Ok(Message::Patch(_)) => None,
+                    Err(err) => Some(Err(Status::data_loss(err.to_string()))),
+                })
+            })
+            .scan(false, |failed, item| {
+                // end the stream after the first error
+                ready(match *failed {
+                    true => None,
+                    false => {
+                        *failed = item.is_err();
+                        Some(item)
+                    }
+                })
+            });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Map service errors to the gRPC status, based on their HTTP status code.
+fn status<E: ResponseError>(err: E) -> Status {
+    let message = err.to_string();
+    match err.error_response().status().as_u16() {
+        400 | 422 => Status::invalid_argument(message),
+        404 => Status::not_found(message),
+        409 => Status::already_exists(message),
+        412 => Status::failed_precondition(message),
+        503 => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+impl From<Thing<Internal>> for api::Thing {
+    fn from(thing: Thing<Internal>) -> Self {
+        Self::from(&thing.into_external())
+    }
+}
+
+impl From<&Thing> for api::Thing {
+    fn from(thing: &Thing) -> Self {
+        let state = ThingState::from(thing);
+        let metadata = state.metadata;
+
+        Self {
+            metadata: Some(api::Metadata {
+                deleting: metadata.deletion_timestamp.is_some(),
+                application: metadata.application,
+                name: metadata.name,
+                uid: metadata.uid,
+                generation: metadata.generation,
+                resource_version: metadata.resource_version,
+                labels: metadata.labels.into_iter().collect(),
+                annotations: metadata.annotations.into_iter().collect(),
+            }),
+            reported_state: to_values(state.reported_state),
+            desired_state: to_values(state.desired_state),
+            synthetic_state: to_values(state.synthetic_state),
+        }
+    }
+}
+
+fn to_values(
+    values: BTreeMap<String, serde_json::Value>,
+) -> std::collections::HashMap<String, prost_types::Value> {
+    values
+        .into_iter()
+        .map(|(name, value)| (name, to_value(value)))
+        .collect()
+}
+
+fn to_value(value: serde_json::Value) -> prost_types::Value {
+    use serde_json::Value;
+
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(value) => Kind::BoolValue(value),
+        Value::Number(value) => Kind::NumberValue(value.as_f64().unwrap_or_default()),
+        Value::String(value) => Kind::StringValue(value),
+        Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.into_iter().map(to_value).collect(),
+        }),
+        Value::Object(fields) => Kind::StructValue(Struct {
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name, to_value(value)))
+                .collect(),
+        }),
+    };
+
+    prost_types::Value { kind: Some(kind) }
+}
+
+fn from_struct(value: Struct) -> serde_json::Value {
+    serde_json::Value::Object(
+        value
+            .fields
+            .into_iter()
+            .map(|(name, value)| (name, from_value(value)))
+            .collect(),
+    )
+}
+
+/// Convert a protobuf value to JSON.
+///
+/// Protobuf only knows floating point numbers, numbers without a fraction are converted back to
+/// integers, so that they can be deserialized into integer fields, like the generation.
+fn from_value(value: prost_types::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match value.kind {
+        None | Some(Kind::NullValue(_)) => Value::Null,
+        Some(Kind::BoolValue(value)) => Value::Bool(value),
+        Some(Kind::NumberValue(value)) if value.fract() == 0.0 && value.abs() < i64::MAX as f64 => {
+            Value::from(value as i64)
+        }
+        Some(Kind::NumberValue(value)) => serde_json::Number::from_f64(value)
+            .map(Value::Number)
+            .unwrap_or_default(),
+        Some(Kind::StringValue(value)) => Value::String(value),
+        Some(Kind::ListValue(values)) => {
+            Value::Array(values.values.into_iter().map(from_value).collect())
+        }
+        Some(Kind::StructValue(value)) => from_struct(value),
+    }
+}
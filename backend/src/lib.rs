@@ -1,9 +1,30 @@
 mod api;
-mod endpoints;
+pub mod auth;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod correlation;
+pub mod endpoints;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+pub mod grpc;
+mod limits;
 mod notifier;
 mod utils;
+pub mod v1alpha2;
 
-use crate::api::{api, OpenApiConfig};
+pub use auth::{
+    ApplicationAuthZ, ApplicationAuthorizer, LocalAuthConfig, LocalAuthMethods, Scope, ScopeAuthZ,
+};
+pub use limits::PayloadLimits;
+
+use crate::{
+    api::{
+        api, oauth2_redirect, swagger_ui, OpenApiConfig, API_DOCS_PATH, OAUTH2_REDIRECT_PATH,
+        SWAGGER_UI_PATH,
+    },
+    auth::{LocalAuthN, LocalAuthenticator},
+    correlation::Correlation,
+};
 use ::openid::Configurable;
 use actix_web::{
     guard,
@@ -26,8 +47,10 @@ use drogue_bazaar::{
 };
 use drogue_client::user;
 use drogue_doppelgaenger_core::{
-    command::{mqtt, CommandSink},
-    listener::KafkaSource,
+    applications::Applications,
+    command::{self, CommandSink},
+    config::check::{self, Check, Checker},
+    listener::{self, Listener},
     notifier::{kafka, Notifier},
     processor::sink::{self, Sink},
     service::{self, DefaultService},
@@ -35,28 +58,61 @@ use drogue_doppelgaenger_core::{
     PROJECT,
 };
 use serde_json::json;
+use url::Url;
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Config<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> {
-    pub application: Option<String>,
+    /// Limit the backend to these applications, see [`Applications`].
+    #[serde(default, alias = "application")]
+    pub applications: Applications,
     // serde(bound) required as S isn't serializable: https://github.com/serde-rs/serde/issues/1296
     #[serde(bound = "")]
     pub service: service::Config<S, N, Si, Cmd>,
 
-    pub listener: kafka::Config,
+    pub listener: listener::Config,
 
     #[serde(default)]
     pub user_auth: Option<ClientConfig>,
 
     pub oauth: openid::AuthenticatorConfig,
 
+    /// Local authentication, using credentials stored in the database.
+    #[serde(default)]
+    pub local_auth: Option<LocalAuthConfig>,
+
     #[serde(default)]
     pub openapi_oauth_client: Option<String>,
+
+    #[serde(default)]
+    pub payload_limits: PayloadLimits,
+
+    /// Serve the gRPC API, requires the `grpc` feature.
+    #[serde(default)]
+    pub grpc: Option<grpc::Config>,
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Check for Config<S, N, Si, Cmd>
+where
+    service::Config<S, N, Si, Cmd>: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("service", &self.service)
+            .field("listener", &self.listener)
+            .field("local_auth", &self.local_auth);
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Instance {
-    pub application: Option<String>,
+    pub applications: Applications,
+}
+
+impl Instance {
+    /// Check if the instance serves the application.
+    pub fn is_allowed(&self, application: &str) -> bool {
+        self.applications.is_allowed(application)
+    }
 }
 
 async fn index() -> impl Responder {
@@ -67,157 +123,526 @@ async fn index() -> impl Responder {
     }))
 }
 
-pub async fn configure<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
-    startup: &mut dyn Startup,
-    config: Config<S, N, Si, Cmd>,
-) -> anyhow::Result<impl Fn(&mut web::ServiceConfig) + Send + Sync + Clone> {
-    let service = DefaultService::from_config(startup, config.service)?;
-    let service = web::Data::new(service);
-
-    let source = KafkaSource::new(startup, config.listener)?;
-    let source = web::Data::new(source);
-
-    let instance = web::Data::new(Instance {
-        application: config.application,
-    });
-
-    let authenticator = config.oauth.into_client().await?;
-    let user_auth = if let Some(user_auth) = config.user_auth {
-        Some(user_auth.into_client::<user::v1::Client>().await?)
-    } else {
-        None
-    };
-
-    let authorization_url = match config.openapi_oauth_client {
-        Some(client) => {
-            let auth = authenticator.as_ref().ok_or_else(|| {
-                anyhow!("OpenAPI OAuth is configured, but no OAuth configuration is present")
-            })?;
-            let client = auth.client_by_name(&client).ok_or_else(|| {
-                anyhow!(
-                    "OpenAPI OAuth is configured, but client '{}' could not be found",
-                    client
-                )
-            })?;
-            Some(client.provider.config().authorization_endpoint.clone())
+/// Authentication of API requests.
+#[derive(Clone)]
+pub struct Authentication {
+    authenticator: Option<openid::Authenticator>,
+    user_auth: Option<user::v1::Client>,
+    local_auth: Option<LocalAuthenticator>,
+}
+
+impl Authentication {
+    pub async fn from_config(
+        oauth: openid::AuthenticatorConfig,
+        user_auth: Option<ClientConfig>,
+        local_auth: Option<LocalAuthConfig>,
+    ) -> anyhow::Result<Self> {
+        let authenticator = oauth.into_client().await?;
+        let user_auth = if let Some(user_auth) = user_auth {
+            Some(user_auth.into_client::<user::v1::Client>().await?)
+        } else {
+            None
+        };
+        let local_auth = local_auth
+            .map(LocalAuthenticator::from_config)
+            .transpose()?;
+
+        Ok(Self {
+            authenticator,
+            user_auth,
+            local_auth,
+        })
+    }
+
+    /// Create the authentication middleware.
+    pub fn authn(&self) -> LocalAuthN {
+        LocalAuthN::new(self.delegate(), self.local_auth.clone())
+    }
+
+    /// The authentication of OAuth and access tokens, following the local authentication.
+    fn delegate(&self) -> AuthN {
+        AuthN::from((
+            self.authenticator.clone(),
+            self.user_auth.clone().map(pat::Authenticator::new),
+        ))
+    }
+
+    /// Check if requests must be authenticated, otherwise anonymous requests are allowed.
+    pub fn is_enabled(&self) -> bool {
+        self.authenticator.is_some() || self.local_auth.is_some()
+    }
+
+    /// Create the authorizer of application access, using the user service, if configured.
+    pub fn authorizer(&self) -> ApplicationAuthorizer {
+        ApplicationAuthorizer::new(self.user_auth.clone())
+    }
+
+    /// Get the authorization URL and the client ID of an OAuth client, for the OpenAPI spec.
+    fn openapi_client(&self, client: &str) -> anyhow::Result<(Url, String)> {
+        let auth = self.authenticator.as_ref().ok_or_else(|| {
+            anyhow!("OpenAPI OAuth is configured, but no OAuth configuration is present")
+        })?;
+        let client = auth.client_by_name(client).ok_or_else(|| {
+            anyhow!(
+                "OpenAPI OAuth is configured, but client '{}' could not be found",
+                client
+            )
+        })?;
+        Ok((
+            client.provider.config().authorization_endpoint.clone(),
+            client.client_id.clone(),
+        ))
+    }
+}
+
+/// The backend, which can be mounted into an existing application.
+///
+/// Use [`Backend::configure`] to get the default layout, or combine [`Backend::app_data`],
+/// [`Backend::things`], [`Authentication::authn`], and [`ApplicationAuthZ`] with
+/// [`Authentication::authorizer`] for mounting the things API under a custom scope.
+pub struct Backend<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> {
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    source: web::Data<Listener>,
+    instance: web::Data<Instance>,
+    openapi: web::Data<OpenApiConfig>,
+    auth: Authentication,
+    limits: PayloadLimits,
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Clone for Backend<S, N, Si, Cmd> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            source: self.source.clone(),
+            instance: self.instance.clone(),
+            openapi: self.openapi.clone(),
+            auth: self.auth.clone(),
+            limits: self.limits.clone(),
         }
-        None => None,
-    };
-
-    if log::log_enabled!(log::Level::Info) {
-        log::info!(
-            "Authentication: {:?}",
-            AuthN::from((
-                authenticator.clone(),
-                user_auth.clone().map(pat::Authenticator::new),
-            ))
+    }
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Backend<S, N, Si, Cmd> {
+    /// Create a new backend from an existing service, e.g. using custom implementations of
+    /// storage and notifier.
+    pub fn new(
+        service: DefaultService<S, N, Si, Cmd>,
+        source: Listener,
+        auth: Authentication,
+    ) -> Self {
+        Self {
+            service: web::Data::new(service),
+            source: web::Data::new(source),
+            instance: web::Data::new(Instance {
+                applications: Default::default(),
+            }),
+            openapi: web::Data::new(OpenApiConfig::default()),
+            auth,
+            limits: Default::default(),
+        }
+    }
+
+    pub async fn from_config(
+        startup: &mut dyn Startup,
+        config: Config<S, N, Si, Cmd>,
+    ) -> anyhow::Result<Self> {
+        let service = DefaultService::from_config(startup, config.service)?;
+        let source =
+            Listener::new(startup, config.listener)?.with_applications(config.applications.clone());
+        let auth =
+            Authentication::from_config(config.oauth, config.user_auth, config.local_auth).await?;
+
+        let (authorization_url, client_id) = match config.openapi_oauth_client {
+            Some(client) => {
+                let (authorization_url, client_id) = auth.openapi_client(&client)?;
+                (Some(authorization_url), Some(client_id))
+            }
+            None => (None, None),
+        };
+
+        if log::log_enabled!(log::Level::Info) {
+            log::info!("Authentication: {:?}", auth.delegate());
+            log::info!("Local authentication: {:?}", auth.local_auth.is_some());
+        }
+
+        let backend = Self::new(service, source, auth)
+            .with_applications(config.applications)
+            .with_authorization_url(authorization_url)
+            .with_openapi_client(client_id)
+            .with_payload_limits(config.payload_limits);
+
+        if let Some(grpc) = config.grpc {
+            grpc::spawn(
+                startup,
+                grpc,
+                backend.service.clone(),
+                backend.source.clone(),
+                backend.instance.clone(),
+                &backend.auth,
+            )?;
+        }
+
+        Ok(backend)
+    }
+
+    /// Limit the backend to a set of applications.
+    pub fn with_applications(mut self, applications: Applications) -> Self {
+        self.instance = web::Data::new(Instance { applications });
+        self
+    }
+
+    pub fn with_authorization_url(mut self, authorization_url: Option<Url>) -> Self {
+        self.openapi = web::Data::new(OpenApiConfig {
+            authorization_url,
+            ..self.openapi.get_ref().clone()
+        });
+        self
+    }
+
+    /// Set the OAuth client, used by the Swagger UI.
+    pub fn with_openapi_client(mut self, client_id: Option<String>) -> Self {
+        self.openapi = web::Data::new(OpenApiConfig {
+            client_id,
+            ..self.openapi.get_ref().clone()
+        });
+        self
+    }
+
+    pub fn with_payload_limits(mut self, limits: PayloadLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn service(&self) -> &web::Data<DefaultService<S, N, Si, Cmd>> {
+        &self.service
+    }
+
+    pub fn auth(&self) -> &Authentication {
+        &self.auth
+    }
+
+    /// Register the application data, required by the endpoints.
+    pub fn app_data(&self, ctx: &mut web::ServiceConfig) {
+        ctx.app_data(self.service.clone());
+        ctx.app_data(self.instance.clone());
+        ctx.app_data(self.source.clone());
+        ctx.app_data(self.openapi.clone());
+        ctx.app_data(web::Data::new(self.auth.authorizer()));
+    }
+
+    /// Register the things API resources, relative to the current scope.
+    ///
+    /// This doesn't apply authentication or authorization, which is the responsibility of the
+    /// surrounding scope.
+    pub fn things(&self, ctx: &mut web::ServiceConfig) {
+        let limits = &self.limits;
+
+        ctx.app_data(limits.json(limits.sections))
+            .service(
+                web::resource("")
+                    .app_data(limits.json(limits.things))
+                    .route(web::post().to(endpoints::things_create::<S, N, Si, Cmd>))
+                    .route(web::put().to(endpoints::things_update::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things")
+                    .route(web::get().to(endpoints::things_list::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}")
+                    .app_data(limits.json(limits.patch))
+                    .route(web::get().to(endpoints::things_get::<S, N, Si, Cmd>))
+                    .route(web::post().to(endpoints::things_dry_run::<S, N, Si, Cmd>))
+                    .route(web::delete().to(endpoints::things_delete::<S, N, Si, Cmd>))
+                    .route(
+                        web::patch()
+                            .guard(guard::Header("content-type", "application/json-patch+json"))
+                            .to(endpoints::things_patch::<S, N, Si, Cmd>),
+                    )
+                    .route(
+                        web::patch()
+                            .guard(guard::Header(
+                                "content-type",
+                                "application/merge-patch+json",
+                            ))
+                            .to(endpoints::things_merge::<S, N, Si, Cmd>),
+                    ),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/state")
+                    .route(web::get().to(endpoints::things_get_state::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/reportedStates")
+                    .route(web::put().to(endpoints::things_update_reported_state::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/reportedStates/{name}").route(
+                    web::delete().to(endpoints::things_delete_reported_state::<S, N, Si, Cmd>),
+                ),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/syntheticStates/{name}")
+                    .route(web::put().to(endpoints::things_update_synthetic_state::<S, N, Si, Cmd>))
+                    .route(web::delete().to(endpoints::things_delete_synthetic_state::<
+                        S,
+                        N,
+                        Si,
+                        Cmd,
+                    >)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/desiredStates/{name}")
+                    .route(web::put().to(endpoints::things_update_desired_state::<S, N, Si, Cmd>))
+                    .route(
+                        web::delete().to(endpoints::things_delete_desired_state::<S, N, Si, Cmd>),
+                    ),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/desiredStates/{name}/value").route(
+                    web::put().to(endpoints::things_update_desired_state_value::<S, N, Si, Cmd>),
+                ),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/reconciliations")
+                    .route(web::put().to(endpoints::things_update_reconciliation::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/annotations")
+                    .route(web::put().to(endpoints::things_update_annotations::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/deliveries")
+                    .route(web::get().to(endpoints::things_deliveries::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/revisions/{generation}")
+                    .route(web::get().to(endpoints::things_revision::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/desiredStates")
+                    .route(web::put().to(endpoints::things_update_desired_states::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/stats")
+                    .route(web::get().to(endpoints::things_stats::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/export")
+                    .route(web::get().to(endpoints::things_export::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/import")
+                    .route(web::post().to(endpoints::things_import::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/notifications")
+                    .route(web::get().to(endpoints::things_notifications::<S, N, Si, Cmd>)),
+            )
+            .service(
+                web::resource("/{application}/things/{thing}/notifications")
+                    .route(web::get().to(endpoints::things_notifications_single::<S, N, Si, Cmd>)),
+            );
+    }
+
+    /// Register the batch resource, relative to the current scope.
+    pub fn batch(&self, ctx: &mut web::ServiceConfig) {
+        ctx.service(
+            web::resource("")
+                .app_data(self.limits.json(self.limits.batch))
+                .route(web::post().to(endpoints::things_batch::<S, N, Si, Cmd>)),
+        );
+    }
+
+    /// Register the validation resource, relative to the current scope.
+    pub fn validate(&self, ctx: &mut web::ServiceConfig) {
+        ctx.service(
+            web::resource("")
+                .app_data(self.limits.json(self.limits.things))
+                .route(web::post().to(endpoints::things_validate::<S, N, Si, Cmd>)),
         );
     }
 
-    let openapi = web::Data::new(OpenApiConfig { authorization_url });
+    /// Register the GraphQL API, relative to the current scope.
+    ///
+    /// Subscriptions use the same resource, upgraded to a WebSocket.
+    #[cfg(feature = "graphql")]
+    pub fn graphql(&self, ctx: &mut web::ServiceConfig) {
+        let schema = graphql::schema(
+            self.service.clone(),
+            self.source.clone(),
+            self.instance.clone(),
+            self.auth.authorizer(),
+        );
+
+        ctx.app_data(web::Data::new(schema)).service(
+            web::resource("")
+                .route(
+                    web::get()
+                        .guard(guard::Header("upgrade", "websocket"))
+                        .to(graphql::graphql_ws::<S, N, Si, Cmd>),
+                )
+                .route(web::post().to(graphql::graphql::<S, N, Si, Cmd>)),
+        );
+    }
 
-    Ok(move |ctx: &mut web::ServiceConfig| {
-        let auth = AuthN::from((
-            authenticator.clone(),
-            user_auth.clone().map(pat::Authenticator::new),
-        ));
+    /// Register the `v1alpha2` things API resources, relative to the current scope.
+    ///
+    /// This extends the resources of [`Backend::things`] with listing and watching things, and
+    /// aggregate summaries.
+    pub fn things_v1alpha2(&self, ctx: &mut web::ServiceConfig) {
+        ctx.service(
+            web::resource("/{application}/things").route(web::get().to(v1alpha2::things_list::<
+                S,
+                N,
+                Si,
+                Cmd,
+            >)),
+        );
+        ctx.service(
+            web::resource("/{application}/summary")
+                .route(web::get().to(v1alpha2::things_summary::<S, N, Si, Cmd>)),
+        );
+        self.things(ctx);
+    }
 
-        ctx.app_data(service.clone());
-        ctx.app_data(instance.clone());
-        ctx.app_data(source.clone());
-        ctx.app_data(openapi.clone());
+    /// Register the backend, using the default layout.
+    pub fn configure(&self, ctx: &mut web::ServiceConfig) {
+        self.app_data(ctx);
 
         ctx.route("/", web::get().to(index));
         ctx.route("/api", web::get().to(api));
+        ctx.route(API_DOCS_PATH, web::get().to(api));
+        ctx.route(SWAGGER_UI_PATH, web::get().to(swagger_ui));
+        ctx.route(OAUTH2_REDIRECT_PATH, web::get().to(oauth2_redirect));
 
+        #[cfg(feature = "chaos")]
         ctx.service(
-            web::scope("/api/v1alpha1/things")
+            web::scope("/api/chaos/v1alpha1")
                 .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
-                .wrap(auth)
-                .service(
-                    web::resource("")
-                        .route(web::post().to(endpoints::things_create::<S, N, Si, Cmd>))
-                        .route(web::put().to(endpoints::things_update::<S, N, Si, Cmd>)),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}")
-                        .route(web::get().to(endpoints::things_get::<S, N, Si, Cmd>))
-                        .route(web::delete().to(endpoints::things_delete::<S, N, Si, Cmd>))
-                        .route(
-                            web::patch()
-                                .guard(guard::Header("content-type", "application/json-patch+json"))
-                                .to(endpoints::things_patch::<S, N, Si, Cmd>),
-                        )
-                        .route(
-                            web::patch()
-                                .guard(guard::Header(
-                                    "content-type",
-                                    "application/merge-patch+json",
-                                ))
-                                .to(endpoints::things_merge::<S, N, Si, Cmd>),
-                        ),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/reportedStates").route(
-                        web::put().to(endpoints::things_update_reported_state::<S, N, Si, Cmd>),
-                    ),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/syntheticStates/{name}")
-                        .route(web::put().to(endpoints::things_update_synthetic_state::<
-                            S,
-                            N,
-                            Si,
-                            Cmd,
-                        >))
-                        .route(web::delete().to(endpoints::things_delete_synthetic_state::<
-                            S,
-                            N,
-                            Si,
-                            Cmd,
-                        >)),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/desiredStates/{name}").route(
-                        web::put().to(endpoints::things_update_desired_state::<S, N, Si, Cmd>),
-                    ),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/desiredStates/{name}/value")
-                        .route(
-                            web::put().to(endpoints::things_update_desired_state_value::<
-                                S,
-                                N,
-                                Si,
-                                Cmd,
-                            >),
-                        ),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/reconciliations").route(
-                        web::put().to(endpoints::things_update_reconciliation::<S, N, Si, Cmd>),
-                    ),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/annotations").route(
-                        web::put().to(endpoints::things_update_annotations::<S, N, Si, Cmd>),
-                    ),
-                )
+                .wrap(self.auth.authn())
+                .route("", web::get().to(chaos::components))
                 .service(
-                    web::resource("/{application}/notifications")
-                        .route(web::get().to(endpoints::things_notifications::<S, N, Si, Cmd>)),
-                )
-                .service(
-                    web::resource("/{application}/things/{thing}/notifications").route(
-                        web::get().to(endpoints::things_notifications_single::<S, N, Si, Cmd>),
-                    ),
+                    web::resource("/{component}")
+                        .route(web::get().to(chaos::faults_get))
+                        .route(web::put().to(chaos::faults_set)),
                 ),
         );
-    })
+
+        ctx.service(
+            web::scope("/api/v1alpha1/things:batch")
+                .wrap(ScopeAuthZ::fixed(Scope::ThingsWrite))
+                .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
+                .wrap(self.auth.authn())
+                .wrap(Correlation)
+                .configure(|ctx| self.batch(ctx)),
+        );
+
+        ctx.service(
+            web::scope("/api/v1alpha1/things:validate")
+                .wrap(ScopeAuthZ::fixed(Scope::ThingsRead))
+                .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
+                .wrap(self.auth.authn())
+                .wrap(Correlation)
+                .configure(|ctx| self.validate(ctx)),
+        );
+
+        ctx.service(
+            web::scope("/api/v1alpha1/things")
+                .wrap(ScopeAuthZ::new())
+                .wrap(ApplicationAuthZ::new(self.auth.authorizer()))
+                .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
+                .wrap(self.auth.authn())
+                .wrap(Correlation)
+                .configure(|ctx| self.things(ctx)),
+        );
+
+        #[cfg(feature = "graphql")]
+        ctx.service(
+            web::scope("/api/v1alpha1/graphql")
+                .wrap(ScopeAuthZ::fixed(Scope::ThingsWrite))
+                .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
+                .wrap(self.auth.authn())
+                .wrap(Correlation)
+                .configure(|ctx| self.graphql(ctx)),
+        );
+
+        ctx.service(
+            web::scope("/api/v1alpha2/things")
+                .wrap(ScopeAuthZ::new())
+                .wrap(ApplicationAuthZ::new(self.auth.authorizer()))
+                .wrap(AuthZ::new(NotAnonymous.or_else_allow()))
+                .wrap(self.auth.authn())
+                .wrap(Correlation)
+                .configure(|ctx| self.things_v1alpha2(ctx)),
+        );
+    }
+
+    /// Convert into a function, registering the backend using the default layout.
+    pub fn into_configurator(self) -> impl Fn(&mut web::ServiceConfig) + Send + Sync + Clone {
+        move |ctx: &mut web::ServiceConfig| self.configure(ctx)
+    }
+}
+
+pub async fn configure<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    startup: &mut dyn Startup,
+    config: Config<S, N, Si, Cmd>,
+) -> anyhow::Result<impl Fn(&mut web::ServiceConfig) + Send + Sync + Clone> {
+    Ok(Backend::from_config(startup, config)
+        .await?
+        .into_configurator())
+}
+
+#[cfg(not(any(feature = "nats", feature = "amqp", feature = "pubsub")))]
+type EventSink = sink::kafka::Sink;
+
+#[cfg(feature = "nats")]
+type EventSink = sink::nats::Sink;
+
+#[cfg(all(feature = "amqp", not(feature = "nats")))]
+type EventSink = sink::amqp::Sink;
+
+#[cfg(all(feature = "pubsub", not(any(feature = "nats", feature = "amqp"))))]
+type EventSink = sink::pubsub::Sink;
+
+#[cfg(not(any(
+    feature = "routed-commands",
+    feature = "kafka-commands",
+    feature = "http-commands"
+)))]
+type Commands = command::mqtt::CommandSink;
+
+#[cfg(feature = "routed-commands")]
+type Commands = command::router::CommandSink;
+
+#[cfg(all(feature = "kafka-commands", not(feature = "routed-commands")))]
+type Commands = command::kafka::CommandSink;
+
+#[cfg(all(
+    feature = "http-commands",
+    not(any(feature = "routed-commands", feature = "kafka-commands"))
+))]
+type Commands = command::http::CommandSink;
+
+#[cfg(not(feature = "chaos"))]
+mod types {
+    use super::*;
+    pub type Storage = postgres::Storage;
+    pub type Notifier = kafka::Notifier;
+    pub type Sink = super::EventSink;
+}
+
+#[cfg(feature = "chaos")]
+mod types {
+    use super::*;
+    use drogue_doppelgaenger_core::chaos::Chaos;
+    pub type Storage = Chaos<postgres::Storage>;
+    pub type Notifier = Chaos<kafka::Notifier>;
+    pub type Sink = Chaos<super::EventSink>;
 }
 
 pub async fn run(
-    config: Config<postgres::Storage, kafka::Notifier, sink::kafka::Sink, mqtt::CommandSink>,
+    config: Config<types::Storage, types::Notifier, types::Sink, Commands>,
     startup: &mut dyn Startup,
 ) -> anyhow::Result<()> {
     let configurator = configure::<_, _, _, _>(startup, config).await?;
@@ -231,3 +656,8 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Check the configuration, without running the backend.
+pub async fn check(mode: check::Mode) -> anyhow::Result<()> {
+    check::run::<Config<types::Storage, types::Notifier, types::Sink, Commands>>(mode).await
+}
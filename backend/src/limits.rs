@@ -0,0 +1,125 @@
+use actix_web::web;
+
+/// Maximum payload sizes of the API endpoints, in bytes.
+///
+/// The limits apply to the decoded payload, so they also apply to compressed requests.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct PayloadLimits {
+    /// The limit for all endpoints which don't have a specific limit.
+    #[serde(default = "default::payload")]
+    pub default: usize,
+    /// Creating and replacing things.
+    #[serde(default)]
+    pub things: Option<usize>,
+    /// Patching things.
+    #[serde(default)]
+    pub patch: Option<usize>,
+    /// Updating a section of a thing (states, reconciliations, annotations).
+    #[serde(default)]
+    pub sections: Option<usize>,
+    /// Creating and replacing things in batches.
+    #[serde(default)]
+    pub batch: Option<usize>,
+}
+
+mod default {
+    pub const fn payload() -> usize {
+        2 * 1024 * 1024
+    }
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        Self {
+            default: default::payload(),
+            things: None,
+            patch: None,
+            sections: None,
+            batch: None,
+        }
+    }
+}
+
+impl PayloadLimits {
+    /// Create a JSON extractor configuration, using the specific limit, or the default.
+    pub fn json(&self, limit: Option<usize>) -> web::JsonConfig {
+        web::JsonConfig::default().limit(limit.unwrap_or(self.default))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::{json, Value};
+    use std::io::Write;
+
+    async fn post(limit: usize, body: Vec<u8>, gzip: bool) -> StatusCode {
+        let limits = PayloadLimits {
+            things: Some(limit),
+            ..Default::default()
+        };
+
+        let app = init_service(
+            App::new()
+                .app_data(limits.json(limits.things))
+                .route("/", web::post().to(|_: web::Json<Value>| async { "" })),
+        )
+        .await;
+
+        let (body, encoding) = match gzip {
+            true => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(&body).unwrap();
+                (encoder.finish().unwrap(), "gzip")
+            }
+            false => (body, "identity"),
+        };
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header(("content-type", "application/json"))
+            .insert_header(("content-encoding", encoding))
+            .set_payload(body)
+            .to_request();
+
+        call_service(&app, req).await.status()
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        serde_json::to_vec(&json!({ "value": "x".repeat(len) })).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let limits: PayloadLimits = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(limits, PayloadLimits::default());
+        assert_eq!(limits.default, 2 * 1024 * 1024);
+    }
+
+    #[actix_web::test]
+    async fn test_limit() {
+        assert_eq!(post(1024, payload(100), false).await, StatusCode::OK);
+        assert_eq!(
+            post(1024, payload(2048), false).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_gzip() {
+        assert_eq!(post(1024, payload(100), true).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_gzip_limit_applies_decoded() {
+        // compresses well below the limit, but exceeds it when decoded
+        let body = payload(64 * 1024);
+        assert_eq!(post(1024, body, true).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,5 +1,8 @@
 use super::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
-use crate::notifier::{Request, Response, SetDesiredValue};
+use crate::{
+    auth::{Scope, Scopes},
+    notifier::{Encoding, Projection, Request, Response, SetDesiredValue},
+};
 use actix::{
     Actor, ActorContext, AsyncContext, Handler, ResponseFuture, SpawnHandle, StreamHandler,
     WrapFuture,
@@ -7,35 +10,52 @@ use actix::{
 use actix_web_actors::ws::{self, CloseCode, CloseReason};
 use drogue_doppelgaenger_core::{
     command::CommandSink,
-    listener::{KafkaSource, Message},
+    listener::{Listener, Message},
     notifier::Notifier,
     processor::{self, sink::Sink, Event},
     service::{DefaultService, Id, Service},
-    storage::Storage,
+    storage::{selector::LabelSelector, ListOptions, Storage},
 };
+use drogue_doppelgaenger_model::Thing;
 use futures::StreamExt;
 use std::{collections::BTreeMap, collections::HashMap, fmt::Display, sync::Arc, time::Instant};
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 
 mod message {
-    use crate::notifier::Response;
+    use crate::notifier::{Projection, Response};
     use actix::Message;
     use actix_web_actors::ws::CloseReason;
-    use drogue_doppelgaenger_core::processor::SetDesiredValue;
-    use std::collections::BTreeMap;
+    use drogue_doppelgaenger_core::{processor, storage::selector::LabelSelector};
+    use std::sync::Arc;
 
     #[derive(Message)]
     #[rtype(result = "()")]
-    pub struct Subscribe(pub String);
+    pub struct Subscribe(pub String, pub Option<Arc<Projection>>);
     #[derive(Message)]
     #[rtype(result = "()")]
     pub struct Unsubscribe(pub String);
+    /// Send the current state of a subscribed thing again.
     #[derive(Message)]
-    #[rtype(result = "Result<(), serde_json::Error>")]
-    pub struct Event(pub Response);
+    #[rtype(result = "()")]
+    pub struct Resync(pub String);
+    /// Subscribe to all things matching the label selector, and the name prefix.
     #[derive(Message)]
     #[rtype(result = "()")]
-    pub struct SetDesiredValues(pub String, pub BTreeMap<String, SetDesiredValue>);
+    pub struct SubscribeSelector(
+        pub LabelSelector,
+        pub Option<String>,
+        pub Option<Arc<Projection>>,
+    );
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct UnsubscribeSelector;
+    #[derive(Message)]
+    #[rtype(result = "anyhow::Result<()>")]
+    pub struct Event(pub Response, pub Option<Arc<Projection>>);
+    /// Publish a message to a thing, using the sink.
+    #[derive(Message)]
+    #[rtype(result = "()")]
+    pub struct Publish(pub String, pub processor::Message);
     #[derive(Message)]
     #[rtype(result = "()")]
     pub struct Close(pub Option<CloseReason>);
@@ -43,31 +63,62 @@ mod message {
 
 pub struct WebSocketHandler<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> {
     heartbeat: Instant,
-    listeners: HashMap<Id, SpawnHandle>,
+    /// The listeners of subscribed things, along with their projection.
+    listeners: HashMap<Id, (SpawnHandle, Option<Arc<Projection>>)>,
+    /// The listener of a selector subscription.
+    selector: Option<SpawnHandle>,
     service: Arc<DefaultService<S, N, Si, Cmd>>,
-    source: Arc<KafkaSource>,
+    source: Arc<Listener>,
     application: String,
     /// Whether or not to just subscribe for a single thing
     thing: Option<String>,
+    encoding: Encoding,
+    /// The scopes of the user, limiting the requests.
+    scopes: Scopes,
+    /// Whether the user has the permission to modify things of the application.
+    write_access: bool,
 }
 
 impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> WebSocketHandler<S, N, Si, Cmd> {
     pub fn new(
         service: Arc<DefaultService<S, N, Si, Cmd>>,
-        source: Arc<KafkaSource>,
+        source: Arc<Listener>,
         application: String,
         thing: Option<String>,
     ) -> Self {
         Self {
             heartbeat: Instant::now(),
             listeners: Default::default(),
+            selector: None,
             service,
             source,
             application,
             thing,
+            encoding: Default::default(),
+            scopes: Default::default(),
+            write_access: true,
         }
     }
 
+    /// Limit the requests to the scopes of the user.
+    pub fn with_scopes(mut self, scopes: Scopes) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    /// Set whether the user has the permission to modify things of the application, see
+    /// [`crate::auth::ApplicationAuthorizer`].
+    pub fn with_write_access(mut self, write_access: bool) -> Self {
+        self.write_access = write_access;
+        self
+    }
+
+    /// Set the encoding of the frames.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
     fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
@@ -84,16 +135,72 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> WebSocketHandler<S, N,
     fn handle_protocol_message(
         &self,
         ctx: &mut ws::WebsocketContext<Self>,
-        result: Result<Request, serde_json::Error>,
+        result: anyhow::Result<Request>,
     ) {
+        if let Ok(request) = &result {
+            let scope = match request {
+                Request::SetDesiredValues { .. } | Request::Patch { .. } => Scope::ThingsWrite,
+                Request::ReportState { .. } => Scope::StateReport,
+                _ => Scope::ThingsRead,
+            };
+            if !self.scopes.allows(scope) {
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(format!("Missing scope: {scope}")),
+                }));
+                ctx.stop();
+                return;
+            }
+            if scope != Scope::ThingsRead && !self.write_access {
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Policy,
+                    description: Some(format!(
+                        "Access to application '{}' denied",
+                        self.application
+                    )),
+                }));
+                ctx.stop();
+                return;
+            }
+        }
+
         match result {
-            Ok(Request::Subscribe { thing }) if self.thing.is_none() => {
-                ctx.address().do_send(message::Subscribe(thing));
+            Ok(Request::Subscribe { thing, projection }) if self.thing.is_none() => {
+                let projection = Projection::new(projection).map(Arc::new);
+                ctx.address().do_send(message::Subscribe(thing, projection));
             }
             Ok(Request::Unsubscribe { thing }) if self.thing.is_none() => {
                 ctx.address().do_send(message::Unsubscribe(thing));
             }
-            Ok(Request::Subscribe { .. } | Request::Unsubscribe { .. }) => {
+            Ok(Request::Resync { thing }) => {
+                ctx.address().do_send(message::Resync(thing));
+            }
+            Ok(Request::SubscribeSelector {
+                selector,
+                name_prefix,
+                projection,
+            }) if self.thing.is_none() => match selector.parse::<LabelSelector>() {
+                Ok(selector) => {
+                    let projection = Projection::new(projection).map(Arc::new);
+                    ctx.address().do_send(message::SubscribeSelector(
+                        selector,
+                        name_prefix,
+                        projection,
+                    ));
+                }
+                Err(err) => {
+                    Self::close_err(ctx, err);
+                }
+            },
+            Ok(Request::UnsubscribeSelector) if self.thing.is_none() => {
+                ctx.address().do_send(message::UnsubscribeSelector);
+            }
+            Ok(
+                Request::Subscribe { .. }
+                | Request::Unsubscribe { .. }
+                | Request::SubscribeSelector { .. }
+                | Request::UnsubscribeSelector,
+            ) => {
                 ctx.close(Some(CloseReason {
                     code: CloseCode::Unsupported,
                     description: Some(
@@ -104,13 +211,29 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> WebSocketHandler<S, N,
             }
             Ok(Request::SetDesiredValues { thing, values }) => match Self::convert_set(values) {
                 Ok(values) => {
-                    ctx.address()
-                        .do_send(message::SetDesiredValues(thing, values));
+                    ctx.address().do_send(message::Publish(
+                        thing,
+                        processor::Message::SetDesiredValue { values },
+                    ));
                 }
                 Err(err) => {
                     Self::close_err(ctx, err);
                 }
             },
+            Ok(Request::ReportState {
+                thing,
+                state,
+                partial,
+            }) => {
+                ctx.address().do_send(message::Publish(
+                    thing,
+                    processor::Message::ReportState { state, partial },
+                ));
+            }
+            Ok(Request::Patch { thing, patch }) => {
+                ctx.address()
+                    .do_send(message::Publish(thing, processor::Message::Patch(patch)));
+            }
             Err(err) => {
                 Self::close_err(ctx, err);
             }
@@ -149,7 +272,10 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Actor
         self.start_heartbeat(ctx);
         if let Some(thing) = &self.thing {
             log::info!("Starting in single-thing mode: {thing}");
-            if let Err(err) = ctx.address().try_send(message::Subscribe(thing.clone())) {
+            if let Err(err) = ctx
+                .address()
+                .try_send(message::Subscribe(thing.clone(), None))
+            {
                 log::warn!("Failed to initialize single-thing listener: {err}");
                 ctx.close(Some(CloseReason {
                     code: CloseCode::Abnormal,
@@ -175,11 +301,11 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>
                 self.heartbeat = Instant::now();
             }
             Ok(ws::Message::Binary(data)) => {
-                self.handle_protocol_message(ctx, serde_json::from_slice(&data));
+                self.handle_protocol_message(ctx, self.encoding.decode(&data));
             }
             Ok(ws::Message::Text(data)) => {
                 log::debug!("Message: {data}");
-                self.handle_protocol_message(ctx, serde_json::from_str(&data));
+                self.handle_protocol_message(ctx, serde_json::from_str(&data).map_err(Into::into));
             }
             Ok(ws::Message::Close(reason)) => {
                 log::debug!("Client disconnected - reason: {:?}", reason);
@@ -204,9 +330,10 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Subsc
     type Result = ();
 
     fn handle(&mut self, msg: message::Subscribe, ctx: &mut Self::Context) -> Self::Result {
+        let message::Subscribe(thing, projection) = msg;
         let id = Id {
             application: self.application.clone(),
-            thing: msg.0,
+            thing,
         };
         if self.listeners.contains_key(&id) {
             return;
@@ -219,6 +346,7 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Subsc
 
         let addr = ctx.address();
         let i = id.clone();
+        let p = projection.clone();
         let task = ctx.spawn(
             async move {
                 // now read the initial state
@@ -226,9 +354,12 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Subsc
                     Ok(Some(thing)) => {
                         let initial_generation = thing.metadata.generation;
                         // send initial
-                        addr.do_send(message::Event(Response::Initial {
-                            thing: Arc::new(thing.into_external()),
-                        }));
+                        addr.do_send(message::Event(
+                            Response::Initial {
+                                thing: Arc::new(thing.into_external()),
+                            },
+                            projection.clone(),
+                        ));
                         initial_generation
                     }
                     Ok(None) => Some(0),
@@ -245,18 +376,38 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Subsc
                 };
 
                 // and run the loop
+                let mut generation = initial_generation;
                 while let Some(msg) = source.next().await {
                     match msg {
                         Ok(Message::Change(thing)) => {
-                            if thing.metadata.generation > initial_generation {
+                            if thing.metadata.generation > generation {
                                 // prevent initial duplicates
-                                addr.do_send(message::Event(Response::Change { thing }))
+                                generation = thing.metadata.generation;
+                                addr.do_send(message::Event(
+                                    Response::Change { thing },
+                                    projection.clone(),
+                                ))
                             } else {
                                 log::info!("Suppressing duplicate generation change");
                             }
                         }
+                        Ok(Message::Patch(patch)) => {
+                            if generation == Some(patch.base_generation) {
+                                generation = Some(patch.generation);
+                                addr.do_send(message::Event(
+                                    Response::Patch { patch },
+                                    projection.clone(),
+                                ))
+                            } else {
+                                // the client can't apply it, wait for the next full state
+                                log::debug!(
+                                    "Skipping patch of generation {} (current: {generation:?})",
+                                    patch.base_generation
+                                );
+                            }
+                        }
                         Err(BroadcastStreamRecvError::Lagged(lag)) => {
-                            addr.do_send(message::Event(Response::Lag { lag }))
+                            addr.do_send(message::Event(Response::Lag { lag }, None))
                         }
                     }
                 }
@@ -271,7 +422,7 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Subsc
             .into_actor(self),
         );
 
-        self.listeners.insert(i, task);
+        self.listeners.insert(i, (task, p));
     }
 }
 
@@ -285,26 +436,208 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Unsub
             application: self.application.clone(),
             thing: msg.0,
         };
-        if let Some(task) = self.listeners.remove(&id) {
+        if let Some((task, _)) = self.listeners.remove(&id) {
+            ctx.cancel_future(task);
+        }
+    }
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Resync>
+    for WebSocketHandler<S, N, Si, Cmd>
+{
+    type Result = ();
+
+    /// Re-create the subscription, which sends the current state, and resets the generation.
+    fn handle(&mut self, msg: message::Resync, ctx: &mut Self::Context) -> Self::Result {
+        let id = Id {
+            application: self.application.clone(),
+            thing: msg.0,
+        };
+        match self.listeners.remove(&id) {
+            Some((task, projection)) => {
+                ctx.cancel_future(task);
+                Handler::<message::Subscribe>::handle(
+                    self,
+                    message::Subscribe(id.thing, projection),
+                    ctx,
+                );
+            }
+            None => {
+                log::debug!("Ignoring resync of unsubscribed thing: {}", id.thing);
+            }
+        }
+    }
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::SubscribeSelector>
+    for WebSocketHandler<S, N, Si, Cmd>
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: message::SubscribeSelector, ctx: &mut Self::Context) -> Self::Result {
+        let message::SubscribeSelector(selector, name_prefix, projection) = msg;
+
+        if let Some(task) = self.selector.take() {
+            ctx.cancel_future(task);
+        }
+
+        let service = self.service.clone();
+        let application = self.application.clone();
+        let name_prefix = name_prefix.unwrap_or_default();
+
+        // subscribe first
+        let mut source = self.source.subscribe_application(application.clone());
+
+        let addr = ctx.address();
+        let task = ctx.spawn(
+            async move {
+                // the generations of the matching things, which got sent to the client
+                let mut generations = HashMap::<String, Option<u32>>::new();
+
+                // now read the initial state
+                let opts = ListOptions::default().with_selector(selector.clone());
+                match service.list_with(&application, &opts).await {
+                    Ok(things) => {
+                        for thing in things {
+                            if !thing.metadata.name.starts_with(&name_prefix) {
+                                continue;
+                            }
+                            generations
+                                .insert(thing.metadata.name.clone(), thing.metadata.generation);
+                            addr.do_send(message::Event(
+                                Response::Initial {
+                                    thing: Arc::new(thing.into_external()),
+                                },
+                                projection.clone(),
+                            ));
+                        }
+                    }
+                    Err(err) => {
+                        addr.do_send(message::Close(Some(CloseReason {
+                            code: CloseCode::Abnormal,
+                            description: Some("Failed to read initial state".to_string()),
+                        })));
+
+                        log::warn!("Failed to read initial state: {err}");
+                        return;
+                    }
+                }
+
+                // and run the loop
+                while let Some(msg) = source.next().await {
+                    match msg {
+                        Ok(Message::Change(thing)) => {
+                            if thing.metadata.name.starts_with(&name_prefix) {
+                                if let Some(response) =
+                                    select_change(&selector, &mut generations, thing)
+                                {
+                                    addr.do_send(message::Event(response, projection.clone()));
+                                }
+                            }
+                        }
+                        Ok(Message::Patch(patch)) if patch.thing.starts_with(&name_prefix) => {
+                            if patch.modifies("/metadata/labels") {
+                                // the selector must be evaluated on the full thing
+                                let id = Id::new(&application, &patch.thing);
+                                match service.get(&id).await {
+                                    Ok(Some(thing)) => {
+                                        let thing = Arc::new(thing.into_external());
+                                        if let Some(response) =
+                                            select_change(&selector, &mut generations, thing)
+                                        {
+                                            addr.do_send(message::Event(
+                                                response,
+                                                projection.clone(),
+                                            ));
+                                        }
+                                    }
+                                    Ok(None) => {}
+                                    Err(err) => {
+                                        log::warn!("Failed to read patched thing: {err}");
+                                    }
+                                }
+                            } else if let Some(generation) = generations.get_mut(&patch.thing) {
+                                if *generation == Some(patch.base_generation) {
+                                    *generation = Some(patch.generation);
+                                    addr.do_send(message::Event(
+                                        Response::Patch { patch },
+                                        projection.clone(),
+                                    ));
+                                }
+                            }
+                        }
+                        Ok(Message::Patch(_)) => {}
+                        Err(BroadcastStreamRecvError::Lagged(lag)) => {
+                            addr.do_send(message::Event(Response::Lag { lag }, None))
+                        }
+                    }
+                }
+                log::warn!("Selector listener loop exited");
+
+                addr.do_send(message::Close(Some(CloseReason {
+                    code: CloseCode::Error,
+                    description: Some("Notifier stream closed".to_string()),
+                })));
+            }
+            .into_actor(self),
+        );
+
+        self.selector = Some(task);
+    }
+}
+
+/// Evaluate the change of a thing for a selector subscription.
+///
+/// Sends changes of matching things, unless the client already has that generation. A change
+/// removing a thing from the selection is sent too, so that the client can drop the thing.
+fn select_change(
+    selector: &LabelSelector,
+    generations: &mut HashMap<String, Option<u32>>,
+    thing: Arc<Thing>,
+) -> Option<Response> {
+    let name = &thing.metadata.name;
+
+    if selector.matches(&thing.metadata.labels) {
+        let generation = generations.entry(name.clone()).or_default();
+        if thing.metadata.generation > *generation {
+            *generation = thing.metadata.generation;
+            return Some(Response::Change { thing });
+        }
+        None
+    } else if generations.remove(name).is_some() {
+        Some(Response::Change { thing })
+    } else {
+        None
+    }
+}
+
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::UnsubscribeSelector>
+    for WebSocketHandler<S, N, Si, Cmd>
+{
+    type Result = ();
+
+    fn handle(&mut self, _: message::UnsubscribeSelector, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(task) = self.selector.take() {
             ctx.cancel_future(task);
         }
     }
 }
 
-impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::SetDesiredValues>
+impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Publish>
     for WebSocketHandler<S, N, Si, Cmd>
 {
     type Result = ResponseFuture<()>;
 
-    fn handle(&mut self, msg: message::SetDesiredValues, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: message::Publish, _ctx: &mut Self::Context) -> Self::Result {
         let application = self.application.clone();
         let sink = self.service.sink().clone();
 
         Box::pin(async move {
-            let thing = msg.0;
-            let message = processor::Message::SetDesiredValue { values: msg.1 };
+            let message::Publish(thing, message) = msg;
 
-            let _ = sink.publish(Event::new(application, thing, message)).await;
+            if let Err(err) = sink.publish(Event::new(application, thing, message)).await {
+                log::warn!("Failed to publish message: {err}");
+            }
         })
     }
 }
@@ -312,10 +645,25 @@ impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::SetDe
 impl<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink> Handler<message::Event>
     for WebSocketHandler<S, N, Si, Cmd>
 {
-    type Result = Result<(), serde_json::Error>;
+    type Result = anyhow::Result<()>;
 
     fn handle(&mut self, msg: message::Event, ctx: &mut Self::Context) -> Self::Result {
-        ctx.text(serde_json::to_string(&msg.0)?);
+        let message::Event(response, projection) = msg;
+
+        let value = match projection {
+            Some(projection) => projection.apply(&response)?,
+            None => serde_json::to_value(&response)?,
+        };
+
+        match self.encoding {
+            Encoding::Json => ctx.text(serde_json::to_string(&value)?),
+            Encoding::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::ser::into_writer(&value, &mut payload)?;
+                ctx.binary(payload);
+            }
+        }
+
         Ok(())
     }
 }
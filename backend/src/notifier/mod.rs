@@ -1,16 +1,59 @@
 pub mod actix;
+mod projection;
 
+use actix_web::{http::header, HttpRequest};
 use chrono::{DateTime, Utc};
-use drogue_doppelgaenger_core::processor;
+use drogue_doppelgaenger_core::{notifier::ThingPatch, processor, service::Patch};
 use drogue_doppelgaenger_model::Thing;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use std::time::Duration;
 
+pub use projection::Projection;
+
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// The WebSocket subprotocol, for exchanging CBOR encoded frames.
+pub const CBOR_PROTOCOL: &str = "doppelgaenger.cbor.v1";
+
+/// The encoding of the frames of a WebSocket connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON, sent as text frames.
+    #[default]
+    Json,
+    /// CBOR, sent as binary frames.
+    Cbor,
+}
+
+impl Encoding {
+    /// Negotiate the encoding, using the subprotocols requested by the client.
+    pub fn negotiate(request: &HttpRequest) -> Self {
+        let cbor = request
+            .headers()
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == CBOR_PROTOCOL);
+
+        match cbor {
+            true => Self::Cbor,
+            false => Self::Json,
+        }
+    }
+
+    /// Decode a binary frame.
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(data)?,
+            Self::Cbor => ciborium::de::from_reader(data)?,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(untagged)]
 pub enum SetDesiredValue {
@@ -71,21 +114,158 @@ impl TryFrom<SetDesiredValue> for processor::SetDesiredValue {
 pub enum Request {
     Subscribe {
         thing: String,
+        /// Only send these fields of the thing, e.g. `metadata` and `reportedState.temperature`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        projection: Vec<String>,
     },
     Unsubscribe {
         thing: String,
     },
+    /// Send the current state of a subscribed thing again, e.g. after missing changes.
+    Resync {
+        thing: String,
+    },
+    /// Subscribe to all things of the application matching the label selector, and the name
+    /// prefix. Replaces a previous selector subscription.
+    #[serde(rename_all = "camelCase")]
+    SubscribeSelector {
+        /// The label selector, e.g. `environment=prod,region in (eu,us)`.
+        #[serde(default)]
+        selector: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name_prefix: Option<String>,
+        /// Only send these fields of the things.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        projection: Vec<String>,
+    },
+    UnsubscribeSelector,
     SetDesiredValues {
         thing: String,
         values: BTreeMap<String, SetDesiredValue>,
     },
+    /// Report state values, as the device would.
+    ReportState {
+        thing: String,
+        state: BTreeMap<String, Value>,
+        /// Only update the provided values, instead of replacing the full reported state
+        #[serde(default)]
+        partial: bool,
+    },
+    /// Apply a JSON patch to the thing.
+    Patch {
+        thing: String,
+        patch: Patch,
+    },
 }
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 #[serde(tag = "type")]
 pub enum Response {
-    Initial { thing: Arc<Thing> },
-    Change { thing: Arc<Thing> },
-    Lag { lag: u64 },
+    Initial {
+        thing: Arc<Thing>,
+    },
+    Change {
+        thing: Arc<Thing>,
+    },
+    /// A change, as JSON patch to the previously sent state.
+    Patch {
+        patch: Arc<ThingPatch>,
+    },
+    Lag {
+        lag: u64,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use json_patch::PatchOperation;
+    use serde_json::json;
+
+    fn request(value: Value) -> Request {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_report_state() {
+        match request(json!({
+            "type": "reportState",
+            "thing": "foo",
+            "state": { "temperature": 42 },
+        })) {
+            Request::ReportState {
+                thing,
+                state,
+                partial,
+            } => {
+                assert_eq!(thing, "foo");
+                assert_eq!(state, BTreeMap::from([("temperature".into(), json!(42))]));
+                assert!(!partial);
+            }
+            request => panic!("Unexpected request: {request:?}"),
+        }
+    }
+
+    #[test]
+    fn test_report_state_partial() {
+        assert!(matches!(
+            request(json!({
+                "type": "reportState",
+                "thing": "foo",
+                "state": {},
+                "partial": true,
+            })),
+            Request::ReportState { partial: true, .. }
+        ));
+    }
+
+    #[test]
+    fn test_patch() {
+        match request(json!({
+            "type": "patch",
+            "thing": "foo",
+            "patch": [
+                { "op": "replace", "path": "/metadata/labels/foo", "value": "bar" },
+            ],
+        })) {
+            Request::Patch { thing, patch } => {
+                assert_eq!(thing, "foo");
+                assert_eq!(patch.0.len(), 1);
+                assert!(
+                    matches!(&patch.0[0], PatchOperation::Replace(op) if op.path == "/metadata/labels/foo")
+                );
+            }
+            request => panic!("Unexpected request: {request:?}"),
+        }
+    }
+
+    #[test]
+    fn test_patch_invalid() {
+        assert!(serde_json::from_value::<Request>(json!({
+            "type": "patch",
+            "thing": "foo",
+            "patch": { "metadata": {} },
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn test_decode_cbor() {
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(
+            &json!({
+                "type": "reportState",
+                "thing": "foo",
+                "state": { "temperature": 42 },
+            }),
+            &mut data,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            Encoding::Cbor.decode::<Request>(&data).unwrap(),
+            Request::ReportState { thing, .. } if thing == "foo"
+        ));
+    }
 }
@@ -0,0 +1,165 @@
+use super::Response;
+use json_patch::{Patch, PatchOperation};
+use serde_json::{Map, Value};
+
+/// A projection of a thing, selecting only some of its fields.
+///
+/// Fields are selected by their path in the JSON model of the thing, using dots as separator,
+/// e.g. `reportedState.temperature`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Projection(Vec<Vec<String>>);
+
+impl<S: AsRef<str>> FromIterator<S> for Projection {
+    fn from_iter<T: IntoIterator<Item = S>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|path| {
+                    path.as_ref()
+                        .split('.')
+                        .filter(|segment| !segment.is_empty())
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                })
+                .filter(|path| !path.is_empty())
+                .collect(),
+        )
+    }
+}
+
+impl Projection {
+    /// Create a projection, or `None` if there are no fields, and so the full thing should be sent.
+    pub fn new(fields: Vec<String>) -> Option<Self> {
+        let projection: Self = fields.into_iter().collect();
+        (!projection.0.is_empty()).then_some(projection)
+    }
+
+    /// Apply the projection to a response.
+    pub fn apply(&self, response: &Response) -> Result<Value, serde_json::Error> {
+        let mut value = serde_json::to_value(response)?;
+
+        match response {
+            Response::Initial { .. } | Response::Change { .. } => {
+                if let Some(thing) = value.get_mut("thing") {
+                    *thing = self.select(thing);
+                }
+            }
+            Response::Patch { patch } => {
+                let ops = serde_json::to_value(self.filter(&patch.patch))?;
+                if let Some(patch) = value.pointer_mut("/patch/patch") {
+                    *patch = ops;
+                }
+            }
+            Response::Lag { .. } => {}
+        }
+
+        Ok(value)
+    }
+
+    /// Select the fields of the projection from the value.
+    fn select(&self, value: &Value) -> Value {
+        let mut result = Map::new();
+        for path in &self.0 {
+            select(&mut result, value, path);
+        }
+        Value::Object(result)
+    }
+
+    /// Filter the operations of a patch, keeping only those affecting the projection.
+    ///
+    /// Operations on a parent of a selected field are kept, with their value projected.
+    fn filter(&self, patch: &Patch) -> Patch {
+        let paths = self.0.iter().map(|path| pointer(path)).collect::<Vec<_>>();
+        let selected = |pointer: &str| {
+            paths
+                .iter()
+                .any(|path| is_below(pointer, path) || is_below(path, pointer))
+        };
+
+        Patch(
+            patch
+                .0
+                .iter()
+                .filter_map(|op| {
+                    let mut op = op.clone();
+                    match &mut op {
+                        PatchOperation::Add(op) => {
+                            op.value = self.select_below(&op.path, &op.value)?;
+                        }
+                        PatchOperation::Replace(op) => {
+                            op.value = self.select_below(&op.path, &op.value)?;
+                        }
+                        PatchOperation::Test(op) => {
+                            op.value = self.select_below(&op.path, &op.value)?;
+                        }
+                        PatchOperation::Remove(op) if !selected(&op.path) => return None,
+                        PatchOperation::Move(op) if !(selected(&op.path) && selected(&op.from)) => {
+                            return None
+                        }
+                        PatchOperation::Copy(op) if !(selected(&op.path) && selected(&op.from)) => {
+                            return None
+                        }
+                        _ => {}
+                    }
+                    Some(op)
+                })
+                .collect(),
+        )
+    }
+
+    /// Select the fields of the projection from a value located at the pointer, or `None` if the
+    /// value is not part of the projection.
+    fn select_below(&self, pointer: &str, value: &Value) -> Option<Value> {
+        let mut result = Map::new();
+        let mut full = false;
+
+        for path in &self.0 {
+            let path_pointer = self::pointer(path);
+            if is_below(pointer, &path_pointer) {
+                // the value is part of a selected field
+                full = true;
+            } else if is_below(&path_pointer, pointer) {
+                // the value is a parent of a selected field
+                let depth = pointer.split('/').count() - 1;
+                select(&mut result, value, &path[depth..]);
+            }
+        }
+
+        match full {
+            true => Some(value.clone()),
+            false if !result.is_empty() => Some(Value::Object(result)),
+            false => None,
+        }
+    }
+}
+
+fn select(target: &mut Map<String, Value>, source: &Value, path: &[String]) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    if let Some(value) = source.get(first) {
+        if rest.is_empty() {
+            target.insert(first.clone(), value.clone());
+        } else if let Value::Object(target) = target
+            .entry(first.clone())
+            .or_insert_with(|| Value::Object(Default::default()))
+        {
+            select(target, value, rest);
+        }
+    }
+}
+
+/// Encode a path as JSON pointer.
+fn pointer(path: &[String]) -> String {
+    path.iter()
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// Check if the pointer is equal to, or below, the parent pointer.
+fn is_below(pointer: &str, parent: &str) -> bool {
+    pointer
+        .strip_prefix(parent)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
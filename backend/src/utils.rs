@@ -1,9 +1,18 @@
 use actix_web::body::BoxBody;
-use actix_web::http::header::{HeaderValue, ToStrError};
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::dev::Payload;
+use actix_web::http::header::{
+    self, Accept, Header, HeaderValue, IfMatch, IfUnmodifiedSince, ToStrError,
+};
+use actix_web::{mime, FromRequest, HttpRequest, HttpResponse, ResponseError};
 use chrono::{DateTime, Duration, ParseError, Utc};
-use drogue_doppelgaenger_core::error::ErrorInformation;
+use drogue_doppelgaenger_core::{
+    error::ErrorInformation,
+    service::{UnknownSection, UpdateOptions},
+    storage::{query::QueryError, selector::SelectorError},
+};
+use futures::future::{ready, Ready};
 use humantime::DurationError;
+use std::time::SystemTime;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -15,6 +24,22 @@ pub enum Error {
     OutOfRange(#[from] time::OutOfRangeError),
     #[error("Duration: {0}")]
     Duration(#[from] DurationError),
+    #[error(transparent)]
+    Section(#[from] UnknownSection),
+    #[error("Invalid continuation token")]
+    ContinuationToken,
+    #[error("Label selector: {0}")]
+    Selector(#[from] SelectorError),
+    #[error("Query: {0}")]
+    Query(#[from] QueryError),
+    #[error("Too many operations in batch: {0} (max: {1})")]
+    BatchSize(usize, usize),
+    #[error("Line {0} exceeds the maximum size of {1} bytes")]
+    LineSize(usize, usize),
+    #[error("Precondition: {0}")]
+    Precondition(String),
+    #[error("Only dry runs are supported, use 'dryRun=true'")]
+    DryRunRequired,
 }
 
 impl ResponseError for Error {
@@ -35,3 +60,119 @@ pub fn to_duration(value: &HeaderValue) -> Result<Duration, Error> {
 pub fn to_datetime(value: &HeaderValue) -> Result<DateTime<Utc>, Error> {
     Ok(DateTime::parse_from_rfc3339(value.to_str()?)?.into())
 }
+
+/// Preconditions of a request, from the `If-Match` and `If-Unmodified-Since` headers.
+///
+/// The entity tag of a thing is its resource version. Only a single, strong, entity tag, or `*`,
+/// is supported.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Preconditions {
+    pub resource_version: Option<String>,
+    pub unmodified_since: Option<DateTime<Utc>>,
+}
+
+impl Preconditions {
+    fn parse(request: &HttpRequest) -> Result<Self, Error> {
+        let mut result = Self::default();
+
+        if request.headers().contains_key(header::IF_MATCH) {
+            match IfMatch::parse(request).map_err(|err| Error::Precondition(err.to_string()))? {
+                IfMatch::Any => {}
+                IfMatch::Items(tags) => match tags.as_slice() {
+                    [tag] if !tag.weak => result.resource_version = Some(tag.tag().to_string()),
+                    _ => {
+                        return Err(Error::Precondition(
+                            "only a single, strong, entity tag is supported".to_string(),
+                        ))
+                    }
+                },
+            }
+        }
+
+        if request.headers().contains_key(header::IF_UNMODIFIED_SINCE) {
+            let IfUnmodifiedSince(date) = IfUnmodifiedSince::parse(request)
+                .map_err(|err| Error::Precondition(err.to_string()))?;
+            result.unmodified_since = Some(SystemTime::from(date).into());
+        }
+
+        Ok(result)
+    }
+
+    /// Apply the preconditions to update options.
+    pub fn apply(self, opts: UpdateOptions) -> UpdateOptions {
+        UpdateOptions {
+            resource_version: self.resource_version,
+            unmodified_since: self.unmodified_since,
+            ..opts
+        }
+    }
+
+    /// The preconditions, as used by the service.
+    pub fn preconditions(&self) -> drogue_doppelgaenger_core::Preconditions<'_> {
+        drogue_doppelgaenger_core::Preconditions {
+            resource_version: self.resource_version.as_deref(),
+            uid: None,
+            unmodified_since: self.unmodified_since,
+        }
+    }
+}
+
+impl FromRequest for Preconditions {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::parse(req))
+    }
+}
+
+/// The media type of the [`ThingState`](drogue_doppelgaenger_model::ThingState) representation.
+pub const THING_STATE_CONTENT_TYPE: &str = "application/vnd.drogue.thing-state+json";
+
+/// The representation of a thing, negotiated using the `Accept` header.
+///
+/// Falls back to the full thing, if the header is missing, or doesn't accept any supported
+/// media type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Representation {
+    /// The full thing.
+    #[default]
+    Thing,
+    /// Only the values of the features.
+    State,
+}
+
+impl Representation {
+    fn negotiate(request: &HttpRequest) -> Self {
+        let Ok(accept) = Accept::parse(request) else {
+            return Self::default();
+        };
+
+        accept
+            .ranked()
+            .into_iter()
+            .find_map(|media_type| {
+                if media_type.essence_str() == THING_STATE_CONTENT_TYPE {
+                    Some(Self::State)
+                } else if media_type.type_() == mime::STAR
+                    || (media_type.type_() == mime::APPLICATION
+                        && (media_type.subtype() == mime::JSON
+                            || media_type.subtype() == mime::STAR))
+                {
+                    Some(Self::Thing)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl FromRequest for Representation {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(Self::negotiate(req)))
+    }
+}
@@ -0,0 +1,489 @@
+//! Endpoints, specific to the `v1alpha2` API.
+
+use crate::Instance;
+use actix_web::{
+    web::{self, Bytes},
+    HttpResponse,
+};
+use drogue_doppelgaenger_core::{
+    command::CommandSink,
+    listener::{Listener, Message},
+    notifier::Notifier,
+    processor::sink::Sink,
+    service::{DefaultService, Service},
+    storage::Storage,
+};
+use drogue_doppelgaenger_model::Thing;
+use futures::{future::ready, stream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, IntervalStream};
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct ListOptions {
+    /// Watch for changes, after sending the initial list.
+    #[serde(default)]
+    pub watch: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SummaryOptions {
+    /// The interval in which summaries get sent, if they changed.
+    #[serde(default = "default::interval", with = "humantime_serde")]
+    pub interval: Duration,
+    /// A comma separated list of labels to break down the counts by.
+    #[serde(default)]
+    pub labels: String,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn interval() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+/// The shortest interval a client may request.
+const MIN_SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ThingList {
+    pub items: Vec<Thing>,
+}
+
+/// An event of a watch stream.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "type")]
+pub enum WatchEvent {
+    Added {
+        thing: Arc<Thing>,
+    },
+    Modified {
+        thing: Arc<Thing>,
+    },
+    Deleted {
+        thing: Arc<Thing>,
+    },
+    /// All things of the initial list have been sent, the following events are changes.
+    Bookmark,
+    /// Events have been missed, the client should re-start the watch.
+    Lag {
+        lag: u64,
+    },
+}
+
+/// Tracks the known generations of things, converting changes into watch events.
+#[derive(Default)]
+struct Tracker {
+    generations: HashMap<String, u32>,
+}
+
+impl Tracker {
+    fn initial(&mut self, thing: Thing) -> WatchEvent {
+        self.generations.insert(
+            thing.metadata.name.clone(),
+            thing.metadata.generation.unwrap_or_default(),
+        );
+        WatchEvent::Added {
+            thing: Arc::new(thing),
+        }
+    }
+
+    fn change(&mut self, thing: Arc<Thing>) -> Option<WatchEvent> {
+        if thing.metadata.deletion_timestamp.is_some() {
+            return self
+                .generations
+                .remove(&thing.metadata.name)
+                .map(|_| WatchEvent::Deleted { thing });
+        }
+
+        let generation = thing.metadata.generation.unwrap_or_default();
+        match self.generations.get_mut(&thing.metadata.name) {
+            // we already know this, or a newer, generation
+            Some(known) if *known >= generation => None,
+            Some(known) => {
+                *known = generation;
+                Some(WatchEvent::Modified { thing })
+            }
+            None => {
+                self.generations
+                    .insert(thing.metadata.name.clone(), generation);
+                Some(WatchEvent::Added { thing })
+            }
+        }
+    }
+}
+
+/// List things of an application, and optionally watch them for changes.
+///
+/// When watching, the response is a stream of newline delimited [`WatchEvent`]s. Starting with
+/// an `added` event for each existing thing, followed by a `bookmark` event, and the changes
+/// after that.
+pub async fn things_list<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    path: web::Path<String>,
+    options: web::Query<ListOptions>,
+    source: web::Data<Listener>,
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    instance: web::Data<Instance>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let application = path.into_inner();
+    if !instance.is_allowed(&application) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    if !options.watch {
+        let items = service
+            .list(&application)
+            .await?
+            .into_iter()
+            .map(|thing| thing.into_external())
+            .collect();
+        return Ok(HttpResponse::Ok().json(ThingList { items }));
+    }
+
+    // subscribe first, so that we don't miss changes between listing and watching
+    let source = source.subscribe_application(application.clone());
+
+    let mut tracker = Tracker::default();
+    let initial: Vec<_> = service
+        .list(&application)
+        .await?
+        .into_iter()
+        .map(|thing| tracker.initial(thing.into_external()))
+        .chain(std::iter::once(WatchEvent::Bookmark))
+        .collect();
+
+    let changes = source.filter_map(move |msg| {
+        ready(match msg {
+            Ok(Message::Change(thing)) => tracker.change(thing),
+            // watching requires the full thing, patched things get resynced by the next snapshot
+            Ok(Message::Patch(_)) => None,
+            Err(BroadcastStreamRecvError::Lagged(lag)) => Some(WatchEvent::Lag { lag }),
+        })
+    });
+
+    let events = stream::iter(initial).chain(changes).map(|event| {
+        let mut data = serde_json::to_vec(&event)?;
+        data.push(b'\n');
+        Ok::<_, serde_json::Error>(Bytes::from(data))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(events))
+}
+
+/// Number of things.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Counts {
+    pub total: usize,
+    /// Things without any conditions.
+    pub healthy: usize,
+}
+
+impl Counts {
+    fn add(&mut self, healthy: bool) {
+        self.total += 1;
+        if healthy {
+            self.healthy += 1;
+        }
+    }
+}
+
+/// An aggregate summary of all things of an application.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    #[serde(flatten)]
+    pub counts: Counts,
+    /// Number of things, by condition type.
+    pub conditions: BTreeMap<String, usize>,
+    /// Counts, by label name and label value.
+    pub labels: BTreeMap<String, BTreeMap<String, Counts>>,
+}
+
+/// An event of a summary stream.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "type")]
+pub enum SummaryEvent {
+    Summary(Summary),
+    /// Events have been missed, the client should re-start the subscription.
+    Lag {
+        lag: u64,
+    },
+}
+
+/// The aggregated state of a single thing.
+struct Entry {
+    generation: u32,
+    labels: Vec<(String, String)>,
+    conditions: Vec<String>,
+}
+
+/// Aggregates the state of things, from the initial list and the following changes.
+struct Aggregator {
+    labels: Vec<String>,
+    things: HashMap<String, Entry>,
+    changed: bool,
+}
+
+impl Aggregator {
+    fn new(labels: Vec<String>) -> Self {
+        Self {
+            labels,
+            things: Default::default(),
+            changed: true,
+        }
+    }
+
+    fn entry(&self, thing: &Thing) -> Entry {
+        Entry {
+            generation: thing.metadata.generation.unwrap_or_default(),
+            labels: self
+                .labels
+                .iter()
+                .filter_map(|label| {
+                    thing
+                        .metadata
+                        .labels
+                        .get(label)
+                        .map(|value| (label.clone(), value.clone()))
+                })
+                .collect(),
+            conditions: thing.conditions.keys().cloned().collect(),
+        }
+    }
+
+    fn change(&mut self, thing: &Thing) {
+        if thing.metadata.deletion_timestamp.is_some() {
+            if self.things.remove(&thing.metadata.name).is_some() {
+                self.changed = true;
+            }
+            return;
+        }
+
+        let entry = self.entry(thing);
+        match self.things.get(&thing.metadata.name) {
+            // we already know this, or a newer, generation
+            Some(known) if known.generation >= entry.generation => {}
+            _ => {
+                self.things.insert(thing.metadata.name.clone(), entry);
+                self.changed = true;
+            }
+        }
+    }
+
+    /// Get the current summary, if it changed since the last call.
+    fn take_summary(&mut self) -> Option<Summary> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+
+        let mut summary = Summary::default();
+        for entry in self.things.values() {
+            let healthy = entry.conditions.is_empty();
+            summary.counts.add(healthy);
+            for condition in &entry.conditions {
+                *summary.conditions.entry(condition.clone()).or_default() += 1;
+            }
+            for (label, value) in &entry.labels {
+                summary
+                    .labels
+                    .entry(label.clone())
+                    .or_default()
+                    .entry(value.clone())
+                    .or_default()
+                    .add(healthy);
+            }
+        }
+
+        Some(summary)
+    }
+}
+
+enum Input {
+    Change(Result<Message, BroadcastStreamRecvError>),
+    Tick,
+}
+
+/// Subscribe to aggregate summaries of the things of an application.
+///
+/// The response is a stream of newline delimited [`SummaryEvent`]s. Starting with the summary of
+/// the existing things, followed by an updated summary each interval, if it changed.
+pub async fn things_summary<S: Storage, N: Notifier, Si: Sink, Cmd: CommandSink>(
+    path: web::Path<String>,
+    options: web::Query<SummaryOptions>,
+    source: web::Data<Listener>,
+    service: web::Data<DefaultService<S, N, Si, Cmd>>,
+    instance: web::Data<Instance>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let application = path.into_inner();
+    if !instance.is_allowed(&application) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let options = options.into_inner();
+    let labels = options
+        .labels
+        .split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(ToString::to_string)
+        .collect();
+
+    // subscribe first, so that we don't miss changes between listing and watching
+    let source = source.subscribe_application(application.clone());
+
+    let mut aggregator = Aggregator::new(labels);
+    for thing in service.list(&application).await? {
+        aggregator.change(&thing.into_external());
+    }
+
+    let mut interval = tokio::time::interval(options.interval.max(MIN_SUMMARY_INTERVAL));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let ticks = IntervalStream::new(interval).map(|_| Input::Tick);
+
+    let events = stream::select(source.map(Input::Change), ticks)
+        .filter_map(move |input| {
+            ready(match input {
+                Input::Change(Ok(Message::Change(thing))) => {
+                    aggregator.change(&thing);
+                    None
+                }
+                Input::Change(Ok(Message::Patch(_))) => None,
+                Input::Change(Err(BroadcastStreamRecvError::Lagged(lag))) => {
+                    Some(SummaryEvent::Lag { lag })
+                }
+                Input::Tick => aggregator.take_summary().map(SummaryEvent::Summary),
+            })
+        })
+        .map(|event| {
+            let mut data = serde_json::to_vec(&event)?;
+            data.push(b'\n');
+            Ok::<_, serde_json::Error>(Bytes::from(data))
+        });
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(events))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use drogue_doppelgaenger_model::Condition;
+
+    fn thing(name: &str, generation: u32, region: &str, conditions: &[&str]) -> Thing {
+        let mut thing = Thing::new("app", name);
+        thing.metadata.generation = Some(generation);
+        thing
+            .metadata
+            .labels
+            .insert("region".to_string(), region.to_string());
+        for condition in conditions {
+            thing.conditions.insert(
+                condition.to_string(),
+                Condition {
+                    last_transition_time: Utc::now(),
+                    reason: None,
+                    message: None,
+                },
+            );
+        }
+        thing
+    }
+
+    fn counts(total: usize, healthy: usize) -> Counts {
+        Counts { total, healthy }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut aggregator = Aggregator::new(vec!["region".to_string()]);
+        aggregator.change(&thing("a", 1, "eu", &[]));
+        aggregator.change(&thing("b", 1, "eu", &["Failed"]));
+        aggregator.change(&thing("c", 1, "us", &[]));
+
+        assert_eq!(
+            aggregator.take_summary(),
+            Some(Summary {
+                counts: counts(3, 2),
+                conditions: BTreeMap::from([("Failed".to_string(), 1)]),
+                labels: BTreeMap::from([(
+                    "region".to_string(),
+                    BTreeMap::from([
+                        ("eu".to_string(), counts(2, 1)),
+                        ("us".to_string(), counts(1, 1)),
+                    ])
+                )]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_unchanged() {
+        let mut aggregator = Aggregator::new(vec![]);
+
+        // the initial summary is always sent, even without things
+        assert_eq!(aggregator.take_summary(), Some(Summary::default()));
+        assert_eq!(aggregator.take_summary(), None);
+
+        aggregator.change(&thing("a", 1, "eu", &[]));
+        assert!(aggregator.take_summary().is_some());
+        assert_eq!(aggregator.take_summary(), None);
+    }
+
+    #[test]
+    fn test_ignore_outdated() {
+        let mut aggregator = Aggregator::new(vec![]);
+        aggregator.change(&thing("a", 2, "eu", &[]));
+        aggregator.take_summary();
+
+        // an older generation, arriving late, must not override the newer one
+        aggregator.change(&thing("a", 1, "eu", &["Failed"]));
+        assert_eq!(aggregator.take_summary(), None);
+
+        aggregator.change(&thing("a", 3, "eu", &["Failed"]));
+        assert_eq!(
+            aggregator.take_summary().map(|summary| summary.counts),
+            Some(counts(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_deleted() {
+        let mut aggregator = Aggregator::new(vec!["region".to_string()]);
+        aggregator.change(&thing("a", 1, "eu", &[]));
+        aggregator.change(&thing("b", 1, "us", &[]));
+        aggregator.take_summary();
+
+        let mut deleted = thing("a", 2, "eu", &[]);
+        deleted.metadata.deletion_timestamp = Some(Utc::now());
+        aggregator.change(&deleted);
+
+        let summary = aggregator.take_summary().unwrap();
+        assert_eq!(summary.counts, counts(1, 1));
+        assert!(!summary.labels["region"].contains_key("eu"));
+
+        // deleting an unknown thing doesn't change the summary
+        aggregator.change(&deleted);
+        assert_eq!(aggregator.take_summary(), None);
+    }
+
+    #[test]
+    fn test_unlisted_labels() {
+        let mut aggregator = Aggregator::new(vec!["zone".to_string()]);
+        aggregator.change(&thing("a", 1, "eu", &[]));
+
+        assert!(aggregator.take_summary().unwrap().labels.is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+//! Recompute the waker column of things, from their internal state.
+//!
+//! The `WAKER` column indexes the waker stored in the internal state of a thing. After restoring
+//! a database backup, or fixing a corrupted column, it may be out of sync with the data, so that
+//! things don't get woken up anymore. Things with outbox events, but without a waker, additionally
+//! get a waker scheduled, so that their outbox gets processed.
+//!
+//! Things are processed in batches, ordered by their application and name.
+
+use crate::{
+    model::{Internal, WakerExt, WakerReason},
+    service::Id,
+    storage::postgres,
+};
+use chrono::{DateTime, Utc};
+use postgres_types::{Json, Type};
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    pub storage: postgres::Config,
+    /// The number of things to process in a single batch.
+    #[serde(default = "default::batch_size")]
+    pub batch_size: u32,
+}
+
+mod default {
+    pub const fn batch_size() -> u32 {
+        1000
+    }
+}
+
+/// The outcome of a backfill.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Things which have been scanned.
+    pub scanned: usize,
+    /// Things with a waker which was out of sync, and got updated.
+    pub updated: usize,
+    /// Things with a waker which was out of sync, but got skipped, as they changed concurrently.
+    pub skipped: usize,
+    /// Things which failed to process.
+    pub failed: usize,
+}
+
+/// Compute the waker of a thing, scheduling a waker for pending outbox events if necessary.
+///
+/// Returns the time the thing should be woken up, and if the internal state was changed.
+pub fn expected_waker(
+    internal: &mut Option<Internal>,
+    now: DateTime<Utc>,
+) -> (Option<DateTime<Utc>>, bool) {
+    match internal {
+        Some(internal) if internal.waker.when.is_none() && !internal.outbox.is_empty() => {
+            internal.wakeup_at(now, WakerReason::Outbox);
+            (internal.waker.when, true)
+        }
+        Some(internal) => (internal.waker.when, false),
+        None => (None, false),
+    }
+}
+
+/// Check if two timestamps are the same, ignoring the precision lost when storing them.
+fn same_instant(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).num_milliseconds() == 0,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl Config {
+    pub async fn run(self, dry_run: bool) -> anyhow::Result<Report> {
+        let pool = self.storage.postgres.create_pool()?;
+        let con = pool.get().await?;
+
+        let mut types = vec![Type::VARCHAR, Type::VARCHAR, Type::INT8];
+        let applications = &self.storage.applications;
+        let and_application = match applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    applications.sql_condition("APPLICATION", 4)
+                )
+            }
+            true => String::new(),
+        };
+        let (allow, deny) = applications.sql_params();
+
+        let select = con
+            .prepare_typed(
+                &format!(
+                    r#"
+SELECT
+    APPLICATION,
+    NAME,
+    RESOURCE_VERSION,
+    WAKER,
+    DATA
+FROM
+    things
+WHERE
+    (APPLICATION, NAME) > ($1, $2)
+{and_application}
+ORDER BY
+    APPLICATION, NAME
+LIMIT $3
+"#
+                ),
+                &types,
+            )
+            .await?;
+
+        let update = con
+            .prepare_typed(
+                r#"
+UPDATE
+    things
+SET
+    WAKER = $1,
+    DATA = $2
+WHERE
+        APPLICATION = $3
+    AND
+        NAME = $4
+    AND
+        RESOURCE_VERSION = $5
+"#,
+                &[
+                    Type::TIMESTAMPTZ,
+                    Type::JSON,
+                    Type::VARCHAR,
+                    Type::VARCHAR,
+                    Type::UUID,
+                ],
+            )
+            .await?;
+
+        let mut report = Report::default();
+        let mut last = Id::new("", "");
+        let limit = self.batch_size.max(1) as i64;
+
+        loop {
+            let rows = match applications.is_empty() {
+                true => {
+                    con.query(&select, &[&last.application, &last.thing, &limit])
+                        .await?
+                }
+                false => {
+                    con.query(
+                        &select,
+                        &[&last.application, &last.thing, &limit, &allow, &deny],
+                    )
+                    .await?
+                }
+            };
+
+            let Some(row) = rows.last() else {
+                break;
+            };
+            last = Id::new(
+                row.try_get::<_, String>("APPLICATION")?,
+                row.try_get::<_, String>("NAME")?,
+            );
+
+            let now = Utc::now();
+            for row in rows {
+                report.scanned += 1;
+
+                let id = Id::new(
+                    row.try_get::<_, String>("APPLICATION")?,
+                    row.try_get::<_, String>("NAME")?,
+                );
+                let resource_version: Uuid = row.try_get("RESOURCE_VERSION")?;
+                let waker: Option<DateTime<Utc>> = row.try_get("WAKER")?;
+                let mut data = row.try_get::<_, Json<Value>>("DATA")?.0;
+
+                let mut internal = match data
+                    .get("internal")
+                    .filter(|internal| !internal.is_null())
+                    .cloned()
+                    .map(serde_json::from_value::<Internal>)
+                    .transpose()
+                {
+                    Ok(internal) => internal,
+                    Err(err) => {
+                        log::warn!("Failed to parse internal state of {id}: {err}");
+                        report.failed += 1;
+                        continue;
+                    }
+                };
+
+                let (expected, changed) = expected_waker(&mut internal, now);
+                if !changed && same_instant(waker, expected) {
+                    continue;
+                }
+
+                log::info!(
+                    "Waker of {id} out of sync - current: {waker:?}, expected: {expected:?}"
+                );
+
+                if dry_run {
+                    report.updated += 1;
+                    continue;
+                }
+
+                if changed {
+                    data["internal"] = serde_json::to_value(&internal)?;
+                }
+
+                // the oplock prevents overriding concurrent changes, which set the waker anyway
+                let result = con
+                    .execute(
+                        &update,
+                        &[
+                            &expected,
+                            &Json(&data),
+                            &id.application,
+                            &id.thing,
+                            &resource_version,
+                        ],
+                    )
+                    .await;
+
+                match result {
+                    Ok(0) => {
+                        log::info!("Skipping {id}, as it changed concurrently");
+                        report.skipped += 1;
+                    }
+                    Ok(_) => report.updated += 1,
+                    Err(err) => {
+                        log::warn!("Failed to update waker of {id}: {err}");
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::{Event, Message};
+
+    #[test]
+    fn test_expected_waker() {
+        let now = Utc::now();
+
+        assert_eq!(expected_waker(&mut None, now), (None, false));
+
+        // waker is kept
+        let mut internal = Internal::default();
+        internal.wakeup_at(now, WakerReason::Reconcile);
+        assert_eq!(expected_waker(&mut Some(internal), now), (Some(now), false));
+
+        // pending outbox events schedule a waker
+        let mut internal = Some(Internal {
+            outbox: vec![Event::new("default", "other", Message::report_state(true))],
+            ..Default::default()
+        });
+        assert_eq!(expected_waker(&mut internal, now), (Some(now), true));
+        assert!(internal
+            .map(|internal| internal.waker.why.contains(&WakerReason::Outbox))
+            .unwrap_or_default());
+    }
+
+    #[test]
+    fn test_same_instant() {
+        let now = Utc::now();
+        assert!(same_instant(None, None));
+        assert!(same_instant(Some(now), Some(now)));
+        assert!(!same_instant(Some(now), None));
+        assert!(!same_instant(
+            Some(now),
+            Some(now + chrono::Duration::seconds(1))
+        ));
+    }
+}
@@ -0,0 +1,556 @@
+//! Check the storage for inconsistent things, and repair them.
+//!
+//! A thing is considered inconsistent if it has:
+//!
+//! * an outbox, which wasn't processed for some time
+//! * events in the transactional outbox, which weren't relayed for some time
+//! * a waker, which is overdue for some time
+//! * a deletion timestamp, but still exists after some time
+//!
+//! Things get repaired by sending a wakeup event, so that the processor picks them up again.
+//! Deleted things which don't have any pending events get removed from the storage. Events in the
+//! transactional outbox (see [`postgres::Config::outbox`]) are only reported, as they get
+//! published by the relay, and not by the processor.
+
+use crate::{
+    model::{Internal, WakerReason},
+    processor::{
+        sink::{self, Sink},
+        Event, Message,
+    },
+    service::Id,
+    storage::{self, postgres, Storage},
+    Preconditions,
+};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use postgres_types::{Json, Type};
+use serde_json::Value;
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    pub storage: postgres::Config,
+    /// The event topic to send wakeups to.
+    pub sink: sink::kafka::Config,
+
+    #[serde(default)]
+    pub thresholds: Thresholds,
+}
+
+/// The time after which a thing is considered inconsistent.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Thresholds {
+    /// Age of the oldest outbox event.
+    #[serde(with = "humantime_serde", default = "default::threshold")]
+    pub outbox: Duration,
+    /// Time since the waker was due.
+    #[serde(with = "humantime_serde", default = "default::threshold")]
+    pub waker: Duration,
+    /// Time since the thing was marked deleted.
+    #[serde(with = "humantime_serde", default = "default::threshold")]
+    pub deletion: Duration,
+}
+
+mod default {
+    use super::*;
+
+    pub const fn threshold() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            outbox: default::threshold(),
+            waker: default::threshold(),
+            deletion: default::threshold(),
+        }
+    }
+}
+
+/// A thing, as found in the storage.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub id: Id,
+    pub uid: Uuid,
+    pub resource_version: Uuid,
+    pub deletion_timestamp: Option<DateTime<Utc>>,
+    pub internal: Option<Internal>,
+    /// Events of the thing, waiting in the transactional outbox.
+    pub relay: Option<RelayPending>,
+}
+
+/// Events of a thing, waiting in the transactional outbox to be relayed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayPending {
+    pub events: usize,
+    pub oldest: DateTime<Utc>,
+}
+
+impl TryFrom<Row> for Entry {
+    type Error = anyhow::Error;
+
+    fn try_from(row: Row) -> Result<Self, Self::Error> {
+        let data = row.try_get::<_, Option<Json<Value>>>("DATA")?;
+        let internal = data
+            .and_then(|data| data.0.get("internal").cloned())
+            .filter(|internal| !internal.is_null())
+            .map(serde_json::from_value)
+            .transpose()?;
+
+        let relay = match (
+            row.try_get::<_, Option<i64>>("RELAY_EVENTS")?,
+            row.try_get::<_, Option<DateTime<Utc>>>("RELAY_OLDEST")?,
+        ) {
+            (Some(events), Some(oldest)) if events > 0 => Some(RelayPending {
+                events: events as usize,
+                oldest,
+            }),
+            _ => None,
+        };
+
+        Ok(Self {
+            id: Id {
+                application: row.try_get("APPLICATION")?,
+                thing: row.try_get("NAME")?,
+            },
+            uid: row.try_get("UID")?,
+            resource_version: row.try_get("RESOURCE_VERSION")?,
+            deletion_timestamp: row.try_get("DELETION_TIMESTAMP")?,
+            internal,
+            relay,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The outbox has events, which weren't sent.
+    StaleOutbox {
+        events: usize,
+        oldest: DateTime<Utc>,
+    },
+    /// The transactional outbox has events, which weren't relayed.
+    StaleRelay {
+        events: usize,
+        oldest: DateTime<Utc>,
+    },
+    /// The waker is overdue.
+    OverdueWaker { due: DateTime<Utc> },
+    /// The thing is marked deleted, but wasn't removed.
+    StuckDeletion { since: DateTime<Utc> },
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::StaleOutbox { events, oldest } => {
+                write!(f, "stale outbox ({events} events, oldest: {oldest})")
+            }
+            Self::StaleRelay { events, oldest } => {
+                write!(f, "stale outbox relay ({events} events, oldest: {oldest})")
+            }
+            Self::OverdueWaker { due } => write!(f, "overdue waker (due: {due})"),
+            Self::StuckDeletion { since } => write!(f, "stuck deletion (since: {since})"),
+        }
+    }
+}
+
+/// The outcome of a check.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub issues: Vec<(Id, Vec<Issue>)>,
+    pub repaired: usize,
+    pub failed: usize,
+}
+
+impl Thresholds {
+    /// Find the issues of an entry.
+    pub fn check(&self, entry: &Entry, now: DateTime<Utc>) -> Vec<Issue> {
+        let mut issues = vec![];
+
+        let before = |threshold: Duration| {
+            now - ChronoDuration::from_std(threshold).unwrap_or_else(|_| ChronoDuration::zero())
+        };
+
+        if let Some(internal) = &entry.internal {
+            if let Some(oldest) = internal.outbox.iter().map(|event| event.timestamp).min() {
+                if oldest < before(self.outbox) {
+                    issues.push(Issue::StaleOutbox {
+                        events: internal.outbox.len(),
+                        oldest,
+                    });
+                }
+            }
+
+            if let Some(due) = internal.waker.when {
+                if due < before(self.waker) {
+                    issues.push(Issue::OverdueWaker { due });
+                }
+            }
+        }
+
+        if let Some(relay) = &entry.relay {
+            if relay.oldest < before(self.outbox) {
+                issues.push(Issue::StaleRelay {
+                    events: relay.events,
+                    oldest: relay.oldest,
+                });
+            }
+        }
+
+        if let Some(since) = entry.deletion_timestamp {
+            if since < before(self.deletion) {
+                issues.push(Issue::StuckDeletion { since });
+            }
+        }
+
+        issues
+    }
+}
+
+impl Entry {
+    /// Check if the issues of the entry can be repaired.
+    ///
+    /// A stale relay can't be repaired by waking up the thing, and a deleted thing must not be
+    /// removed while its events are still waiting to be relayed. So those are only reported.
+    pub fn repairable(&self, issues: &[Issue]) -> bool {
+        issues.iter().any(|issue| match issue {
+            Issue::StaleOutbox { .. } | Issue::OverdueWaker { .. } => true,
+            Issue::StuckDeletion { .. } => self.relay.is_none(),
+            Issue::StaleRelay { .. } => false,
+        })
+    }
+}
+
+impl Config {
+    pub async fn run(self, repair: bool) -> anyhow::Result<Report> {
+        let pool = self.storage.postgres.create_pool()?;
+        let storage = postgres::Storage::from_config(&self.storage)?;
+        let sink = sink::kafka::Sink::from_config(self.sink.clone())?;
+
+        let con = pool.get().await?;
+
+        let mut types = vec![Type::TIMESTAMPTZ];
+        let applications = &self.storage.applications;
+        let and_application = match applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    applications.sql_condition("T.APPLICATION", 2)
+                )
+            }
+            true => String::new(),
+        };
+        let (allow, deny) = applications.sql_params();
+
+        // we pre-select candidates using the smallest threshold, and check the details later
+        let stmt = con
+            .prepare_typed(
+                &format!(
+                    r#"
+SELECT
+    T.APPLICATION,
+    T.NAME,
+    T.UID,
+    T.RESOURCE_VERSION,
+    T.DELETION_TIMESTAMP,
+    T.DATA,
+    O.EVENTS AS RELAY_EVENTS,
+    O.OLDEST AS RELAY_OLDEST
+FROM
+    things T
+LEFT JOIN (
+    SELECT
+        APPLICATION,
+        NAME,
+        COUNT(*) AS EVENTS,
+        MIN(CREATED) AS OLDEST
+    FROM
+        outbox
+    GROUP BY
+        APPLICATION, NAME
+) O ON O.APPLICATION = T.APPLICATION AND O.NAME = T.NAME
+WHERE
+    (
+            T.DELETION_TIMESTAMP < $1
+        OR
+            T.WAKER < $1
+        OR
+            json_array_length(T.DATA -> 'internal' -> 'outbox') > 0
+        OR
+            O.OLDEST < $1
+    )
+{and_application}
+"#
+                ),
+                &types,
+            )
+            .await?;
+
+        let now = Utc::now();
+        let min = [
+            self.thresholds.outbox,
+            self.thresholds.waker,
+            self.thresholds.deletion,
+        ]
+        .into_iter()
+        .min()
+        .unwrap_or_default();
+        let cutoff = now - ChronoDuration::from_std(min)?;
+
+        let rows = match applications.is_empty() {
+            true => con.query(&stmt, &[&cutoff]).await?,
+            false => con.query(&stmt, &[&cutoff, &allow, &deny]).await?,
+        };
+
+        let mut report = Report::default();
+
+        for row in rows {
+            let entry = match Entry::try_from(row) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("Failed to parse entry: {err}");
+                    report.failed += 1;
+                    continue;
+                }
+            };
+
+            let issues = self.thresholds.check(&entry, now);
+            if issues.is_empty() {
+                continue;
+            }
+
+            log::info!("Inconsistent thing: {} - {issues:?}", entry.id);
+
+            if repair && !entry.repairable(&issues) {
+                log::info!(
+                    "Not repairing {}, waiting for the outbox relay: {:?}",
+                    entry.id,
+                    entry.relay
+                );
+            } else if repair {
+                match Self::repair(&storage, &sink, &entry).await {
+                    Ok(()) => report.repaired += 1,
+                    Err(err) => {
+                        log::warn!("Failed to repair {}: {err}", entry.id);
+                        report.failed += 1;
+                    }
+                }
+            }
+
+            report.issues.push((entry.id, issues));
+        }
+
+        Ok(report)
+    }
+
+    async fn repair(
+        storage: &postgres::Storage,
+        sink: &sink::kafka::Sink,
+        entry: &Entry,
+    ) -> anyhow::Result<()> {
+        let outbox_empty = entry
+            .internal
+            .as_ref()
+            .map(|internal| internal.outbox.is_empty())
+            .unwrap_or(true);
+
+        if entry.deletion_timestamp.is_some() && outbox_empty && entry.relay.is_none() {
+            // nothing left to do for the deleted thing, so remove it
+            let uid = entry.uid.to_string();
+            let resource_version = entry.resource_version.to_string();
+            match storage
+                .delete_with(
+                    &entry.id.application,
+                    &entry.id.thing,
+                    Preconditions {
+                        uid: Some(&uid),
+                        resource_version: Some(&resource_version),
+                        unmodified_since: None,
+                    },
+                )
+                .await
+            {
+                Ok(true) => {
+                    sink.tombstone(&entry.id.application, &entry.id.thing)
+                        .await?;
+                    return Ok(());
+                }
+                Ok(false) | Err(storage::Error::NotFound) => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // re-queue the thing, processing the wakeup will process the outbox as well
+
+        let mut reasons = entry
+            .internal
+            .as_ref()
+            .map(|internal| internal.waker.why.clone())
+            .unwrap_or_default();
+        if !outbox_empty {
+            reasons.insert(WakerReason::Outbox);
+        }
+        if entry.deletion_timestamp.is_some() {
+            reasons.insert(WakerReason::Deletion);
+        }
+
+        sink.publish(Event::new(
+            entry.id.application.clone(),
+            entry.id.thing.clone(),
+            Message::Wakeup {
+                reasons: reasons.into_iter().collect(),
+            },
+        ))
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::Waker;
+
+    fn entry(internal: Option<Internal>, deletion_timestamp: Option<DateTime<Utc>>) -> Entry {
+        Entry {
+            id: Id::new("default", "thing"),
+            uid: Uuid::new_v4(),
+            resource_version: Uuid::new_v4(),
+            deletion_timestamp,
+            internal,
+            relay: None,
+        }
+    }
+
+    #[test]
+    fn test_consistent() {
+        let now = Utc::now();
+        let thresholds = Thresholds::default();
+
+        assert_eq!(thresholds.check(&entry(None, None), now), vec![]);
+
+        let internal = Internal {
+            waker: Waker {
+                when: Some(now - ChronoDuration::seconds(10)),
+                why: Default::default(),
+            },
+            outbox: vec![Event::new("default", "other", Message::report_state(true))],
+            ..Default::default()
+        };
+        assert_eq!(
+            thresholds.check(
+                &entry(Some(internal), Some(now - ChronoDuration::seconds(10))),
+                now
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_inconsistent() {
+        let now = Utc::now();
+        let past = now - ChronoDuration::hours(1);
+        let thresholds = Thresholds::default();
+
+        let mut event = Event::new("default", "other", Message::report_state(true));
+        event.timestamp = past;
+
+        let internal = Internal {
+            waker: Waker {
+                when: Some(past),
+                why: Default::default(),
+            },
+            outbox: vec![event],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            thresholds.check(&entry(Some(internal), Some(past)), now),
+            vec![
+                Issue::StaleOutbox {
+                    events: 1,
+                    oldest: past
+                },
+                Issue::OverdueWaker { due: past },
+                Issue::StuckDeletion { since: past },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stale_relay() {
+        let now = Utc::now();
+        let past = now - ChronoDuration::hours(1);
+        let thresholds = Thresholds::default();
+
+        let mut entry = entry(None, None);
+        entry.relay = Some(RelayPending {
+            events: 2,
+            oldest: now - ChronoDuration::seconds(10),
+        });
+        assert_eq!(thresholds.check(&entry, now), vec![]);
+
+        entry.relay = Some(RelayPending {
+            events: 2,
+            oldest: past,
+        });
+        let issues = thresholds.check(&entry, now);
+        assert_eq!(
+            issues,
+            vec![Issue::StaleRelay {
+                events: 2,
+                oldest: past
+            }]
+        );
+        assert!(!entry.repairable(&issues));
+    }
+
+    #[test]
+    fn test_repairable() {
+        let now = Utc::now();
+        let past = now - ChronoDuration::hours(1);
+        let thresholds = Thresholds::default();
+
+        let mut entry = entry(None, Some(past));
+        let issues = thresholds.check(&entry, now);
+        assert_eq!(issues, vec![Issue::StuckDeletion { since: past }]);
+        assert!(entry.repairable(&issues));
+
+        // must not be removed before its events got relayed
+        entry.relay = Some(RelayPending {
+            events: 1,
+            oldest: now,
+        });
+        assert!(!entry.repairable(&issues));
+
+        entry.internal = Some(Internal {
+            waker: Waker {
+                when: Some(past),
+                why: Default::default(),
+            },
+            ..Default::default()
+        });
+        let issues = thresholds.check(&entry, now);
+        assert_eq!(
+            issues,
+            vec![
+                Issue::OverdueWaker { due: past },
+                Issue::StuckDeletion { since: past }
+            ]
+        );
+        assert!(entry.repairable(&issues));
+    }
+}
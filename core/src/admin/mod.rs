@@ -0,0 +1,7 @@
+//! Administrative tasks, which are not part of the regular operation.
+
+pub mod backfill;
+pub mod check;
+pub mod offload;
+pub mod replay;
+pub mod transfer;
@@ -0,0 +1,256 @@
+//! Offload idle things to the cold storage.
+//!
+//! A thing is considered idle if neither its reported, desired, nor synthetic state changed for
+//! some time. Things with a waker, pending outbox events, or a deletion timestamp are never
+//! offloaded, as they still require processing.
+//!
+//! Offloaded things get rehydrated transparently when being accessed, see
+//! [`crate::storage::postgres::cold`].
+
+use crate::{
+    model::{Internal, Thing},
+    service::Id,
+    storage::{self, postgres, Storage},
+};
+use anyhow::bail;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use postgres_types::Type;
+use std::time::Duration;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    pub storage: postgres::Config,
+    /// The time a thing must be idle, before it gets offloaded.
+    #[serde(with = "humantime_serde", default = "default::idle")]
+    pub idle: Duration,
+    /// The number of things to process in a single batch.
+    #[serde(default = "default::batch_size")]
+    pub batch_size: u32,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn idle() -> Duration {
+        Duration::from_secs(30 * 24 * 60 * 60)
+    }
+
+    pub const fn batch_size() -> u32 {
+        1000
+    }
+}
+
+/// The outcome of offloading.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Things which have been scanned.
+    pub scanned: usize,
+    /// Things which were idle, and got offloaded.
+    pub offloaded: usize,
+    /// Things which failed to offload.
+    pub failed: usize,
+}
+
+/// The last time the state of a thing changed.
+pub fn last_activity(thing: &Thing<Internal>) -> Option<DateTime<Utc>> {
+    thing
+        .reported_state
+        .values()
+        .map(|feature| feature.last_update)
+        .chain(
+            thing
+                .desired_state
+                .values()
+                .map(|feature| feature.last_update),
+        )
+        .chain(
+            thing
+                .synthetic_state
+                .values()
+                .map(|feature| feature.last_update),
+        )
+        .chain(thing.metadata.creation_timestamp)
+        .max()
+}
+
+/// Check if a thing is idle, and can be offloaded.
+pub fn is_idle(thing: &Thing<Internal>, idle: ChronoDuration, now: DateTime<Utc>) -> bool {
+    if thing.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+
+    if let Some(internal) = &thing.internal {
+        if internal.waker.when.is_some() || !internal.outbox.is_empty() {
+            return false;
+        }
+    }
+
+    last_activity(thing).map_or(false, |last| now - last >= idle)
+}
+
+impl Config {
+    pub async fn run(self, dry_run: bool) -> anyhow::Result<Report> {
+        let storage = postgres::Storage::from_config(&self.storage)?;
+        if !storage.is_tiered() {
+            bail!("No cold storage configured");
+        }
+
+        let idle = ChronoDuration::from_std(self.idle)?;
+
+        let pool = self.storage.postgres.create_pool()?;
+        let con = pool.get().await?;
+
+        let mut types = vec![Type::VARCHAR, Type::VARCHAR, Type::INT8];
+        let applications = &self.storage.applications;
+        let and_application = match applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    applications.sql_condition("APPLICATION", 4)
+                )
+            }
+            true => String::new(),
+        };
+        let (allow, deny) = applications.sql_params();
+
+        // only select candidates, the details are checked on the full thing
+        let select = con
+            .prepare_typed(
+                &format!(
+                    r#"
+SELECT
+    APPLICATION,
+    NAME
+FROM
+    things
+WHERE
+        (APPLICATION, NAME) > ($1, $2)
+    AND
+        WAKER IS NULL
+    AND
+        DELETION_TIMESTAMP IS NULL
+{and_application}
+ORDER BY
+    APPLICATION, NAME
+LIMIT $3
+"#
+                ),
+                &types,
+            )
+            .await?;
+
+        let mut report = Report::default();
+        let mut last = Id::new("", "");
+        let limit = self.batch_size.max(1) as i64;
+
+        loop {
+            let rows = match applications.is_empty() {
+                true => {
+                    con.query(&select, &[&last.application, &last.thing, &limit])
+                        .await?
+                }
+                false => {
+                    con.query(
+                        &select,
+                        &[&last.application, &last.thing, &limit, &allow, &deny],
+                    )
+                    .await?
+                }
+            };
+
+            if rows.is_empty() {
+                break;
+            }
+
+            let now = Utc::now();
+            for row in rows {
+                report.scanned += 1;
+
+                last = Id::new(
+                    row.try_get::<_, String>("APPLICATION")?,
+                    row.try_get::<_, String>("NAME")?,
+                );
+
+                let thing = match storage.get(&last.application, &last.thing).await {
+                    Ok(Some(thing)) => thing,
+                    // got deleted in the meantime
+                    Ok(None) | Err(storage::Error::NotFound) => continue,
+                    Err(err) => {
+                        log::warn!("Failed to read {last}: {err}");
+                        report.failed += 1;
+                        continue;
+                    }
+                };
+
+                if !is_idle(&thing, idle, now) {
+                    continue;
+                }
+
+                log::info!("Offloading {last}");
+
+                if dry_run {
+                    report.offloaded += 1;
+                    continue;
+                }
+
+                match storage.offload(&thing).await {
+                    Ok(true) => report.offloaded += 1,
+                    Ok(false) => log::info!("Skipping {last}, as it changed concurrently"),
+                    Err(err) => {
+                        log::warn!("Failed to offload {last}: {err}");
+                        report.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        model::{ReportedFeature, WakerExt, WakerReason},
+        processor::{Event, Message},
+    };
+    use serde_json::json;
+
+    #[test]
+    fn test_is_idle() {
+        let now = Utc::now();
+        let idle = ChronoDuration::days(30);
+
+        let mut thing = Thing::new("default", "thing");
+        thing.metadata.creation_timestamp = Some(now - ChronoDuration::days(60));
+        assert!(is_idle(&thing, idle, now));
+
+        // recent reports keep it active
+        let mut active = thing.clone();
+        active
+            .reported_state
+            .insert("value".into(), ReportedFeature::now(json!(1)));
+        assert!(!is_idle(&active, idle, now));
+
+        // as does pending work
+        let mut waking = thing.clone();
+        waking.wakeup_at(now + ChronoDuration::days(1), WakerReason::Reconcile);
+        assert!(!is_idle(&waking, idle, now));
+
+        let mut sending = thing.clone();
+        sending.internal = Some(Internal {
+            outbox: vec![Event::new("default", "other", Message::report_state(true))],
+            ..Default::default()
+        });
+        assert!(!is_idle(&sending, idle, now));
+
+        let mut deleting = thing;
+        deleting.metadata.deletion_timestamp = Some(now);
+        assert!(!is_idle(&deleting, idle, now));
+    }
+}
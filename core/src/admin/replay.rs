@@ -0,0 +1,367 @@
+//! Replay the internal event topic.
+//!
+//! Reads the events of the internal event topic, starting from a given offset or point in time,
+//! and publishes them into another event topic, optionally remapping the application and thing
+//! names.
+//! The replay stops once it caught up with the state of the topic at the time the replay was
+//! started.
+
+use crate::{
+    config::kafka::KafkaProperties,
+    processor::{
+        sink::{self, Sink},
+        source::{
+            self,
+            kafka::{from_msg, is_tombstone},
+        },
+        Event, Message,
+    },
+};
+use chrono::{DateTime, Utc};
+use rdkafka::{
+    config::FromClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    error::KafkaError,
+    Message, Offset, TopicPartitionList,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+use uuid::Uuid;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The event topic to read from.
+    pub source: source::kafka::Config,
+    /// The event topic to replay into.
+    pub target: sink::kafka::Config,
+
+    /// Where to start replaying.
+    #[serde(default)]
+    pub start: Start,
+
+    #[serde(default)]
+    pub mapping: Mapping,
+}
+
+/// The position to start replaying from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Start {
+    /// The earliest available event.
+    #[default]
+    Beginning,
+    /// An offset, applied to every partition.
+    Offset(i64),
+    /// The first event at, or after, a point in time.
+    Timestamp(DateTime<Utc>),
+}
+
+/// Remapping the IDs of replayed events.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Mapping {
+    /// Map of source to target application names.
+    #[serde(default)]
+    pub applications: HashMap<String, String>,
+    /// Only replay events of applications which have a mapping.
+    #[serde(default)]
+    pub only_mapped: bool,
+    /// Map of source to target thing names.
+    #[serde(default)]
+    pub things: HashMap<String, String>,
+    /// Map of source to target thing name prefixes, used when there is no exact thing mapping.
+    ///
+    /// If multiple prefixes match, the longest one is used.
+    #[serde(default)]
+    pub thing_prefixes: BTreeMap<String, String>,
+}
+
+impl Mapping {
+    /// Map an event, returns `None` if the event should be skipped.
+    pub fn map(&self, mut event: Event) -> Option<Event> {
+        match self.applications.get(&event.application) {
+            Some(application) => {
+                event.application = application.clone();
+            }
+            None if self.only_mapped => return None,
+            None => {}
+        }
+
+        event.thing = self.map_thing(event.thing);
+
+        // references to children are thing names too
+        match &mut event.message {
+            Message::RegisterChild { r#ref, .. } | Message::UnregisterChild { r#ref } => {
+                *r#ref = self.map_thing(std::mem::take(r#ref));
+            }
+            _ => {}
+        }
+
+        Some(event)
+    }
+
+    /// Map a thing name, returns the name unchanged if there is no mapping.
+    pub fn map_thing(&self, thing: String) -> String {
+        if let Some(target) = self.things.get(&thing) {
+            return target.clone();
+        }
+
+        // iterate in reverse order, so that longer prefixes come before their own prefixes
+        match self
+            .thing_prefixes
+            .iter()
+            .rev()
+            .find(|(prefix, _)| thing.starts_with(prefix.as_str()))
+        {
+            Some((prefix, target)) => format!("{target}{}", &thing[prefix.len()..]),
+            None => thing,
+        }
+    }
+}
+
+/// The outcome of a replay.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub replayed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Config {
+    pub async fn run(self) -> anyhow::Result<Stats> {
+        let topic = self.source.topic.clone();
+        let consumer = self.consumer()?;
+        let sink = sink::kafka::Sink::from_config(self.target.clone())?;
+
+        // evaluate the range to replay, for each partition
+
+        let watermarks = self.watermarks(&consumer, &topic)?;
+        let start = self.start_offsets(&consumer, &topic, &watermarks)?;
+        let mut end: BTreeMap<_, _> = watermarks
+            .into_iter()
+            .map(|(partition, (_, high))| (partition, high))
+            .collect();
+
+        let mut assignment = TopicPartitionList::new();
+        for (partition, offset) in start {
+            match offset {
+                Offset::Offset(offset) if end.get(&partition).map_or(true, |e| offset >= *e) => {
+                    // nothing to replay
+                    end.remove(&partition);
+                }
+                Offset::End => {
+                    end.remove(&partition);
+                }
+                offset => {
+                    assignment.add_partition_offset(&topic, partition, offset)?;
+                }
+            }
+        }
+
+        log::info!("Replaying partitions: {assignment:?}, until: {end:?}");
+
+        consumer.assign(&assignment)?;
+
+        // replay
+
+        let mut stats = Stats::default();
+
+        while !end.is_empty() {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(KafkaError::PartitionEOF(partition)) => {
+                    // the last offset might not be a record we receive, e.g. due to compaction,
+                    // or being a transaction marker, so the end of the partition ends it too
+                    if end.remove(&partition).is_some() {
+                        log::info!("Partition {partition} caught up");
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+
+            if let Some(last) = end.get(&msg.partition()) {
+                if msg.offset() >= *last - 1 {
+                    log::info!("Partition {} caught up", msg.partition());
+                    end.remove(&msg.partition());
+                }
+                if msg.offset() >= *last {
+                    continue;
+                }
+            } else {
+                continue;
+            }
+
+            if is_tombstone(&msg) {
+                // deleted things don't need to be replayed
+                stats.skipped += 1;
+                continue;
+            }
+
+            let event = match from_msg(&msg) {
+                Ok(event) => event,
+                Err(err) => {
+                    log::info!("Unable to parse message, skipping! Reason: {err}");
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+
+            match self.mapping.map(event) {
+                Some(event) => {
+                    log::debug!("Replaying event: {event:?}");
+                    sink.publish(event).await?;
+                    stats.replayed += 1;
+                }
+                None => {
+                    stats.skipped += 1;
+                }
+            }
+        }
+
+        log::info!("Replay complete: {stats:?}");
+
+        Ok(stats)
+    }
+
+    fn consumer(&self) -> anyhow::Result<StreamConsumer> {
+        let mut config: rdkafka::ClientConfig =
+            KafkaProperties(self.source.properties.clone()).into();
+
+        // we use manual assignments, and must not interfere with the processor's group
+        config.set(
+            "group.id",
+            format!("doppelgaenger-replay-{}", Uuid::new_v4()),
+        );
+        config.set("enable.auto.commit", "false");
+        // required to detect the end of partitions, which don't have a record at the last offset
+        config.set("enable.partition.eof", "true");
+        config.set("auto.offset.reset", "earliest");
+
+        Ok(StreamConsumer::from_config(&config)?)
+    }
+
+    /// Get the low and high watermarks for all partitions of the topic.
+    fn watermarks(
+        &self,
+        consumer: &StreamConsumer,
+        topic: &str,
+    ) -> anyhow::Result<BTreeMap<i32, (i64, i64)>> {
+        let metadata = consumer.fetch_metadata(Some(topic), TIMEOUT)?;
+
+        let mut result = BTreeMap::new();
+        for partition in metadata
+            .topics()
+            .iter()
+            .filter(|t| t.name() == topic)
+            .flat_map(|t| t.partitions())
+        {
+            let watermarks = consumer.fetch_watermarks(topic, partition.id(), TIMEOUT)?;
+            result.insert(partition.id(), watermarks);
+        }
+
+        Ok(result)
+    }
+
+    fn start_offsets(
+        &self,
+        consumer: &StreamConsumer,
+        topic: &str,
+        partitions: &BTreeMap<i32, (i64, i64)>,
+    ) -> anyhow::Result<Vec<(i32, Offset)>> {
+        Ok(match &self.start {
+            Start::Beginning => partitions
+                .iter()
+                .map(|(partition, (low, _))| (*partition, Offset::Offset(*low)))
+                .collect(),
+            Start::Offset(offset) => partitions
+                .keys()
+                .map(|partition| (*partition, Offset::Offset(*offset)))
+                .collect(),
+            Start::Timestamp(timestamp) => {
+                let mut tpl = TopicPartitionList::new();
+                for partition in partitions.keys() {
+                    tpl.add_partition_offset(
+                        topic,
+                        *partition,
+                        Offset::Offset(timestamp.timestamp_millis()),
+                    )?;
+                }
+                consumer
+                    .offsets_for_times(tpl, TIMEOUT)?
+                    .elements()
+                    .iter()
+                    .map(|e| (e.partition(), e.offset()))
+                    .collect()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        let mut mapping = Mapping::default();
+        mapping
+            .applications
+            .insert("foo".to_string(), "bar".to_string());
+
+        let event = mapping
+            .map(Event::new("foo", "thing", Message::report_state(true)))
+            .unwrap();
+        assert_eq!(event.application, "bar");
+        assert_eq!(event.thing, "thing");
+
+        let event = mapping
+            .map(Event::new("baz", "thing", Message::report_state(true)))
+            .unwrap();
+        assert_eq!(event.application, "baz");
+
+        mapping.only_mapped = true;
+        assert!(mapping
+            .map(Event::new("baz", "thing", Message::report_state(true)))
+            .is_none());
+    }
+
+    #[test]
+    fn test_mapping_things() {
+        let mut mapping = Mapping::default();
+        mapping
+            .things
+            .insert("device-1".to_string(), "sensor-1".to_string());
+        mapping
+            .thing_prefixes
+            .insert("device-".to_string(), "dev-".to_string());
+        mapping
+            .thing_prefixes
+            .insert("device-x-".to_string(), "x-".to_string());
+
+        assert_eq!(mapping.map_thing("device-1".to_string()), "sensor-1");
+        assert_eq!(mapping.map_thing("device-2".to_string()), "dev-2");
+        assert_eq!(mapping.map_thing("device-x-1".to_string()), "x-1");
+        assert_eq!(mapping.map_thing("other".to_string()), "other");
+
+        let event = mapping
+            .map(Event::new(
+                "foo",
+                "device-2",
+                Message::RegisterChild {
+                    r#ref: "device-1".to_string(),
+                    template: Default::default(),
+                },
+            ))
+            .unwrap();
+        assert_eq!(event.application, "foo");
+        assert_eq!(event.thing, "dev-2");
+        assert!(matches!(
+            event.message,
+            Message::RegisterChild { r#ref, .. } if r#ref == "sensor-1"
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+//! Transfer all things from one storage to another.
+//!
+//! Things get imported into the target storage (see [`Storage::import`]), preserving the metadata
+//! managed by the storage, if the target supports it. Afterwards, the things of both storages
+//! are verified by comparing their count, and a digest of their content.
+
+use crate::{
+    model::{Internal, Thing},
+    storage::{self, Storage},
+};
+use anyhow::{anyhow, bail};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct Config<S: Storage, T: Storage> {
+    /// The storage to read from.
+    pub source: S::Config,
+    /// The storage to import into.
+    pub target: T::Config,
+    /// The applications to transfer.
+    #[serde(default)]
+    pub applications: Vec<String>,
+}
+
+/// The outcome of transferring an application.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub application: String,
+    /// Things found in the source storage.
+    pub source: usize,
+    /// Things imported into the target storage.
+    pub imported: usize,
+    /// Things which already existed in the target storage, and were skipped.
+    pub existing: usize,
+    /// Things which failed to import.
+    pub failed: usize,
+    /// Things found in the target storage, after the transfer.
+    pub target: usize,
+    /// Things of the target storage, which kept the metadata of the source storage.
+    pub preserved: usize,
+    /// Digest of the things in the source storage.
+    pub source_digest: String,
+    /// Digest of the things in the target storage, after the transfer.
+    pub target_digest: String,
+}
+
+impl Report {
+    /// Check if the target storage has the same content as the source storage.
+    pub fn is_verified(&self) -> bool {
+        self.failed == 0 && self.source == self.target && self.source_digest == self.target_digest
+    }
+}
+
+impl<S: Storage, T: Storage> Config<S, T> {
+    pub async fn run(self) -> anyhow::Result<Vec<Report>> {
+        if self.applications.is_empty() {
+            bail!("No applications to transfer");
+        }
+
+        let source = S::from_config(&self.source)?;
+        let target = T::from_config(&self.target)?;
+
+        let mut result = Vec::with_capacity(self.applications.len());
+        for application in self.applications {
+            result.push(transfer(&source, &target, application).await?);
+        }
+
+        Ok(result)
+    }
+}
+
+async fn transfer<S: Storage, T: Storage>(
+    source: &S,
+    target: &T,
+    application: String,
+) -> anyhow::Result<Report> {
+    log::info!("Transferring application: {application}");
+
+    let things = source
+        .list(&application)
+        .await
+        .map_err(|err| anyhow!("Failed to list source things: {err}"))?;
+
+    let mut report = Report {
+        source: things.len(),
+        source_digest: digest(&things),
+        ..Default::default()
+    };
+
+    for thing in &things {
+        match target.import(thing.clone()).await {
+            Ok(_) => report.imported += 1,
+            Err(storage::Error::AlreadyExists) => {
+                log::info!("Skipping existing thing: {}", thing.metadata.name);
+                report.existing += 1;
+            }
+            Err(err) => {
+                log::warn!("Failed to import thing {}: {err}", thing.metadata.name);
+                report.failed += 1;
+            }
+        }
+    }
+
+    let imported = target
+        .list(&application)
+        .await
+        .map_err(|err| anyhow!("Failed to list target things: {err}"))?;
+
+    let sources: HashMap<_, _> = things
+        .iter()
+        .map(|thing| (&thing.metadata.name, &thing.metadata))
+        .collect();
+
+    report.target = imported.len();
+    report.target_digest = digest(&imported);
+    report.preserved = imported
+        .iter()
+        .filter(|thing| {
+            sources.get(&thing.metadata.name).map_or(false, |source| {
+                source.uid == thing.metadata.uid
+                    && source.generation == thing.metadata.generation
+                    && source.resource_version == thing.metadata.resource_version
+            })
+        })
+        .count();
+    report.application = application;
+
+    Ok(report)
+}
+
+/// A digest over the content of things, ignoring the metadata managed by the storage.
+fn digest(things: &[Thing<Internal>]) -> String {
+    let mut things: Vec<_> = things.iter().collect();
+    things.sort_unstable_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+
+    let mut hasher = Sha256::new();
+    for thing in things {
+        let mut thing = thing.clone();
+        thing.metadata.uid = None;
+        thing.metadata.resource_version = None;
+        thing.metadata.generation = None;
+        thing.metadata.creation_timestamp = None;
+
+        // serializing a thing can't fail, as all keys are strings
+        hasher.update(serde_json::to_vec(&thing).unwrap_or_default());
+        hasher.update(b"\n");
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::ReportedFeature;
+    use serde_json::json;
+
+    fn thing(name: &str, value: i64) -> Thing<Internal> {
+        let mut thing = Thing::new("default", name);
+        thing
+            .reported_state
+            .insert("value".into(), ReportedFeature::now(json!(value)));
+        thing
+    }
+
+    #[test]
+    fn test_digest() {
+        let a = thing("a", 1);
+        let b = thing("b", 2);
+
+        let mut c = b.clone();
+        c.metadata.uid = Some("uid".to_string());
+        c.metadata.generation = Some(42);
+
+        // order and storage metadata don't matter
+        assert_eq!(digest(&[a.clone(), b.clone()]), digest(&[c, a.clone()]));
+
+        // content does
+        assert_ne!(digest(&[a.clone(), b]), digest(&[a.clone(), thing("b", 3)]));
+        assert_ne!(digest(&[a.clone()]), digest(&[a.clone(), a]));
+    }
+}
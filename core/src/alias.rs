@@ -0,0 +1,324 @@
+//! Mirror the state of things into their aliases.
+//!
+//! An alias is a thing, which mirrors the state of another thing (see [`crate::model::AliasOf`]),
+//! possibly of a different application. Changes of the mirrored thing are picked up from the
+//! notifier stream.
+//! For each alias, a report state event is sent to the event topic, so that the processor applies
+//! the change to the alias, just like to any other thing.
+//!
+//! Reported and synthetic values of the mirrored thing are reported for the alias. If the mirrored
+//! thing gets deleted, the state of the alias is cleared.
+
+use crate::{
+    applications::Applications,
+    config::{
+        check::{Check, Checker},
+        kafka::KafkaProperties,
+    },
+    model::Thing,
+    notifier,
+    processor::{sink::Sink, Event, Message},
+    storage::postgres,
+};
+use lazy_static::lazy_static;
+use postgres_types::{Json, Type};
+use prometheus::{register_int_counter, IntCounter};
+use rdkafka::{
+    config::FromClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    Message as _,
+};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tracing::instrument;
+
+lazy_static! {
+    static ref ALIAS_UPDATES: IntCounter =
+        register_int_counter!("alias_updates", "Number of updates sent to aliases").unwrap();
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// The storage, to look up the aliases of a thing.
+    pub storage: postgres::Config,
+
+    /// Kafka properties of the change events (notifier) topic.
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// The change events (notifier) topic.
+    pub topic: String,
+    /// The consumer group. Instances sharing the same group split the work among them.
+    pub group_id: String,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if !self.disabled {
+            checker
+                .field("storage", &self.storage)
+                .kafka("properties", &self.properties)
+                .not_empty("topic", &self.topic)
+                .not_empty("group_id", &self.group_id);
+        }
+    }
+}
+
+/// An alias, as found in the storage.
+#[derive(Clone, Debug)]
+struct Alias {
+    application: String,
+    thing: String,
+    /// The reported values of the alias.
+    reported: BTreeMap<String, Value>,
+}
+
+impl Config {
+    pub async fn run<Si: Sink>(self, sink: Si) -> anyhow::Result<()> {
+        let pool = self.storage.postgres.create_pool()?;
+
+        let mut config: rdkafka::ClientConfig = KafkaProperties(self.properties).into();
+
+        config.set("group.id", &self.group_id);
+        config.set("enable.partition.eof", "false");
+
+        // configure for QoS 1
+
+        config.set("enable.auto.commit", "true");
+        config.set("auto.commit.interval.ms", "5000");
+        config.set("enable.auto.offset.store", "false");
+
+        log::info!("Alias sync - source: {config:?}");
+
+        let consumer = StreamConsumer::from_config(&config)?;
+        consumer.subscribe(&[&self.topic])?;
+
+        let syncer = Syncer {
+            pool,
+            applications: self.storage.applications,
+            sink,
+        };
+
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::warn!("Failed to receive from Kafka: {err}");
+                    break;
+                }
+            };
+
+            match msg.payload().map(notifier::decode) {
+                Some(Ok(thing)) => {
+                    if let Err(err) = syncer.sync(&thing).await {
+                        log::warn!("Failed to sync aliases: {err}");
+                        break;
+                    }
+                }
+                Some(Err(err)) => {
+                    log::info!("Unable to parse change event, skipping! Reason: {err}");
+                }
+                None => {}
+            }
+
+            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                log::warn!("Failed to store offset: {err}");
+                break;
+            }
+        }
+
+        log::warn!("Exiting alias sync loop");
+
+        Ok(())
+    }
+}
+
+struct Syncer<Si: Sink> {
+    pool: deadpool_postgres::Pool,
+    applications: Applications,
+    sink: Si,
+}
+
+impl<Si: Sink> Syncer<Si> {
+    #[instrument(skip_all, fields(
+        application = thing.metadata.application,
+        thing = thing.metadata.name,
+    ), err)]
+    async fn sync(&self, thing: &Thing) -> anyhow::Result<()> {
+        let aliases = self
+            .find_aliases(&thing.metadata.application, &thing.metadata.name)
+            .await?;
+
+        if aliases.is_empty() {
+            return Ok(());
+        }
+
+        let state = mirror(thing);
+
+        for alias in aliases {
+            if alias.reported == state {
+                // nothing changed, which also breaks cycles of aliases
+                continue;
+            }
+
+            log::debug!(
+                "Updating alias: {}/{} -> {}/{}",
+                thing.metadata.application,
+                thing.metadata.name,
+                alias.application,
+                alias.thing
+            );
+
+            self.sink
+                .publish(Event::new(
+                    alias.application,
+                    alias.thing,
+                    Message::ReportState {
+                        state: state.clone(),
+                        partial: false,
+                    },
+                ))
+                .await?;
+            ALIAS_UPDATES.inc();
+        }
+
+        Ok(())
+    }
+
+    /// Find all (non-deleted) aliases of a thing.
+    async fn find_aliases(&self, application: &str, thing: &str) -> anyhow::Result<Vec<Alias>> {
+        let con = self.pool.get().await?;
+
+        let mut types = vec![Type::VARCHAR, Type::VARCHAR];
+        let and_application = match self.applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    self.applications.sql_condition("APPLICATION", 3)
+                )
+            }
+            true => String::new(),
+        };
+
+        let stmt = con
+            .prepare_typed_cached(
+                &format!(
+                    r#"
+SELECT
+    APPLICATION,
+    NAME,
+    DATA -> 'reported_state' AS REPORTED
+FROM
+    things
+WHERE
+        DATA -> 'alias_of' ->> 'thing' = $1
+    AND
+        COALESCE(DATA -> 'alias_of' ->> 'application', APPLICATION) = $2
+    AND
+        DELETION_TIMESTAMP IS NULL
+{and_application}
+"#
+                ),
+                &types,
+            )
+            .await?;
+
+        let rows = match self.applications.is_empty() {
+            true => con.query(&stmt, &[&thing, &application]).await?,
+            false => {
+                let (allow, deny) = self.applications.sql_params();
+                con.query(&stmt, &[&thing, &application, &allow, &deny])
+                    .await?
+            }
+        };
+
+        rows.into_iter()
+            .map(|row| {
+                let reported = row
+                    .try_get::<_, Option<Json<BTreeMap<String, Value>>>>("REPORTED")?
+                    .map(|reported| reported.0)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(name, feature)| {
+                        let value = feature.get("value").cloned().unwrap_or_default();
+                        (name, value)
+                    })
+                    .collect();
+
+                Ok(Alias {
+                    application: row.try_get("APPLICATION")?,
+                    thing: row.try_get("NAME")?,
+                    reported,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The state to report for an alias of the thing.
+fn mirror(thing: &Thing) -> BTreeMap<String, Value> {
+    if thing.metadata.deletion_timestamp.is_some() {
+        return Default::default();
+    }
+
+    let mut state: BTreeMap<_, _> = thing
+        .reported_state
+        .iter()
+        .map(|(name, feature)| (name.clone(), feature.value.clone()))
+        .collect();
+
+    // synthetic values take precedence, the same as when evaluating the state
+    state.extend(
+        thing
+            .synthetic_state
+            .iter()
+            .map(|(name, feature)| (name.clone(), feature.value.clone())),
+    );
+
+    state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{ReportedFeature, SyntheticFeature, SyntheticType};
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn test_mirror() {
+        let mut thing = Thing::new("default", "thing");
+        thing
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(42)));
+        thing
+            .reported_state
+            .insert("bat".into(), ReportedFeature::now(json!(1.5)));
+        thing.synthetic_state.insert(
+            "bat".into(),
+            SyntheticFeature {
+                r#type: SyntheticType::Alias("bat".into()),
+                last_update: Utc::now(),
+                value: json!(2.5),
+            },
+        );
+
+        let state = mirror(&thing);
+        assert_eq!(
+            Value::Object(state.into_iter().collect()),
+            json!({
+                "temperature": 42,
+                "bat": 2.5,
+            })
+        );
+
+        thing.metadata.deletion_timestamp = Some(Utc::now());
+        assert!(mirror(&thing).is_empty());
+    }
+}
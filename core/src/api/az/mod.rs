@@ -1,14 +1,14 @@
 mod types;
 
 use anyhow::anyhow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str::from_utf8;
 pub use types::*;
 
 use crate::{
     command::{Command, CommandSink},
     events::DataExt,
-    injector::{mqtt::Target, SourceConfig},
+    injector::{metadata::Context, mqtt::Target, SourceConfig},
     processor::{sink::Sink, Event, Message},
     service::{Id, Service},
 };
@@ -43,8 +43,23 @@ pub struct Config {
 
 #[derive(Clone, Debug, Default, serde::Deserialize)]
 pub struct TwinConfig {
+    /// The twin application to use, if no mapping for the source application exists.
     #[serde(default)]
     pub application: Option<String>,
+    /// Map of source to twin application names.
+    #[serde(default)]
+    pub applications: HashMap<String, String>,
+}
+
+impl TwinConfig {
+    /// Get the twin application for a source application.
+    pub fn application(&self, source_application: &str) -> String {
+        self.applications
+            .get(source_application)
+            .or(self.application.as_ref())
+            .cloned()
+            .unwrap_or_else(|| source_application.to_string())
+    }
 }
 
 impl Config {
@@ -52,7 +67,7 @@ impl Config {
     where
         S: Sink,
         C: CommandSink,
-        Svc: Service + Sync + Send,
+        Svc: Service + Sync + Send + 'static,
         Svc::Error: Sync + Send + 'static,
     {
         Twin::new(self, sink, command, service)?.run().await
@@ -78,7 +93,7 @@ impl<S, C, Svc> Twin<S, C, Svc>
 where
     S: Sink,
     C: CommandSink,
-    Svc: Service + Sync + Send,
+    Svc: Service + Sync + Send + 'static,
     Svc::Error: Sync + Send + 'static,
 {
     pub fn new(config: Config, sink: S, command: C, service: Svc) -> anyhow::Result<Self> {
@@ -108,7 +123,7 @@ impl<S, C, Svc> Target for TwinTarget<S, C, Svc>
 where
     S: Sink,
     C: CommandSink,
-    Svc: Service + Sync + Send,
+    Svc: Service + Sync + Send + 'static,
     Svc::Error: Sync + Send + 'static,
 {
     #[instrument(
@@ -117,7 +132,7 @@ where
             time=event.time().map(|s|s.timestamp_millis()),
             subject=event.subject()),
         ret, err)]
-    async fn event(&self, mut event: cloudevents::Event) -> anyhow::Result<()> {
+    async fn event(&self, mut event: cloudevents::Event, _: Context) -> anyhow::Result<()> {
         let timestamp = match event.time() {
             Some(time) => time,
             _ => return Ok(()),
@@ -129,11 +144,7 @@ where
             _ => return Ok(()),
         };
 
-        let application = self
-            .config
-            .application
-            .clone()
-            .unwrap_or_else(|| source_application.clone());
+        let application = self.config.application(&source_application);
 
         let device = match event.extension("device") {
             Some(ExtensionValue::String(device)) => device.to_string(),
@@ -250,6 +261,7 @@ where
                 device: ctx.device.to_string(),
                 channel: format!("$iothub/twin/res/{}/?$rid={}", code, ctx.rid),
                 payload,
+                route: None,
             })
             .await
     }
@@ -290,6 +302,8 @@ where
                     state: properties,
                     partial: true,
                 },
+                correlation_id: None,
+                provenance: None,
             })
             .await?;
 
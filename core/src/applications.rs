@@ -0,0 +1,196 @@
+//! Restricting an instance to a set of applications.
+
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// The applications an instance is limited to.
+///
+/// The applications are configured using a comma separated list of patterns, e.g.
+/// `site-*,weather,!site-test`. A pattern prefixed with `!` denies matching applications, and a
+/// `*` matches any number of characters. An application is allowed, if it matches any of the
+/// allowing patterns, and none of the denying patterns. Without allowing patterns, all
+/// applications, which are not denied, are allowed. So a single application name limits the
+/// instance to exactly that application.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Applications {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid application pattern '{0}'")]
+pub struct ApplicationsError(String);
+
+impl FromStr for Applications {
+    type Err = ApplicationsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Self::default();
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (list, pattern) = match entry.strip_prefix('!') {
+                Some(pattern) => (&mut result.deny, pattern.trim()),
+                None => (&mut result.allow, entry),
+            };
+            if pattern.is_empty() || pattern.contains('/') {
+                return Err(ApplicationsError(entry.to_string()));
+            }
+            list.push(pattern.to_string());
+        }
+
+        Ok(result)
+    }
+}
+
+impl TryFrom<String> for Applications {
+    type Error = ApplicationsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Applications {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self
+            .allow
+            .iter()
+            .cloned()
+            .chain(self.deny.iter().map(|pattern| format!("!{pattern}")))
+            .collect::<Vec<_>>();
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+impl Applications {
+    /// Check if there are no restrictions, allowing all applications.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check if the application is allowed.
+    pub fn is_allowed(&self, application: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| matches(p, application)))
+            && !self.deny.iter().any(|p| matches(p, application))
+    }
+
+    /// An SQL condition on the application column, using the parameters `$index` (allowed) and
+    /// `$index + 1` (denied), see [`Self::sql_params`].
+    pub fn sql_condition(&self, column: &str, index: usize) -> String {
+        let deny = index + 1;
+        format!(
+            r#"(CARDINALITY(${index}::VARCHAR[]) = 0 OR {column} LIKE ANY(${index}))
+    AND
+        NOT {column} LIKE ANY(${deny})"#
+        )
+    }
+
+    /// The parameters of [`Self::sql_condition`], the allowed and denied patterns, in the syntax
+    /// of `LIKE`.
+    pub fn sql_params(&self) -> (Vec<String>, Vec<String>) {
+        let like = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    pattern
+                        .replace('\\', "\\\\")
+                        .replace('%', "\\%")
+                        .replace('_', "\\_")
+                        .replace('*', "%")
+                })
+                .collect()
+        };
+        (like(&self.allow), like(&self.deny))
+    }
+}
+
+/// Match an application against a pattern, where `*` matches any number of characters.
+pub(crate) fn matches(pattern: &str, application: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // there always is a first part, possibly empty
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = application.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            // the last part must match the end
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    // no wildcard, must match exactly
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let applications: Applications = "site-*, weather,!site-test".parse().unwrap();
+        assert_eq!(
+            applications,
+            Applications {
+                allow: vec!["site-*".into(), "weather".into()],
+                deny: vec!["site-test".into()],
+            }
+        );
+        assert_eq!(applications.to_string(), "site-*,weather,!site-test");
+
+        assert_eq!("".parse(), Ok(Applications::default()));
+        assert_eq!(
+            "foo,!".parse::<Applications>(),
+            Err(ApplicationsError("!".into()))
+        );
+    }
+
+    #[test]
+    fn test_allowed() {
+        let applications: Applications = "site-*,weather,!site-test".parse().unwrap();
+        assert!(applications.is_allowed("site-a"));
+        assert!(applications.is_allowed("site-"));
+        assert!(applications.is_allowed("weather"));
+        assert!(!applications.is_allowed("site-test"));
+        assert!(!applications.is_allowed("weather-2"));
+        assert!(!applications.is_allowed("other"));
+
+        let applications: Applications = "!*-test".parse().unwrap();
+        assert!(applications.is_allowed("site-a"));
+        assert!(!applications.is_allowed("site-test"));
+
+        let applications: Applications = "default".parse().unwrap();
+        assert!(applications.is_allowed("default"));
+        assert!(!applications.is_allowed("default2"));
+
+        let applications: Applications = "a*b*c".parse().unwrap();
+        assert!(applications.is_allowed("abc"));
+        assert!(applications.is_allowed("a-b-b-c"));
+        assert!(!applications.is_allowed("a-c-b"));
+
+        assert!(Applications::default().is_allowed("any"));
+    }
+
+    #[test]
+    fn test_sql_params() {
+        let applications: Applications = "site_*,!100%".parse().unwrap();
+        assert_eq!(
+            applications.sql_params(),
+            (vec!["site\\_%".to_string()], vec!["100\\%".to_string()])
+        );
+    }
+}
@@ -0,0 +1,420 @@
+//! Fault injection for storage, sink and notifier implementations.
+//!
+//! Wrapping an implementation with [`Chaos`] allows injecting errors and latency into its
+//! operations. The initial settings come from the configuration, but can be changed at runtime
+//! using the [`Faults`] handle, which is registered by component name (see [`lookup`]).
+
+use crate::{
+    config::check::{Check, Checker},
+    model::{Internal, Thing},
+    notifier,
+    processor::{sink, Event},
+    storage, Preconditions,
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rand::Rng;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<String, Faults>> = Default::default();
+}
+
+/// Settings for injecting faults.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FaultConfig {
+    /// Probability (from `0.0` to `1.0`) of an operation to fail.
+    #[serde(default)]
+    pub error_rate: f64,
+    /// Fixed latency, added to every operation.
+    #[serde(default, with = "humantime_serde")]
+    pub latency: Option<Duration>,
+    /// Random latency, between zero and this value, added to every operation.
+    #[serde(default, with = "humantime_serde")]
+    pub jitter: Option<Duration>,
+}
+
+impl FaultConfig {
+    fn delay(&self) -> Option<Duration> {
+        let jitter = self
+            .jitter
+            .filter(|jitter| !jitter.is_zero())
+            .map(|jitter| rand::thread_rng().gen_range(Duration::ZERO..=jitter));
+
+        match (self.latency, jitter) {
+            (None, None) => None,
+            (Some(latency), None) => Some(latency),
+            (None, Some(jitter)) => Some(jitter),
+            (Some(latency), Some(jitter)) => Some(latency + jitter),
+        }
+    }
+
+    fn fail(&self) -> bool {
+        self.error_rate > 0.0 && rand::thread_rng().gen_bool(self.error_rate.min(1.0))
+    }
+}
+
+/// Configuration of a wrapped implementation.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    #[serde(default)]
+    pub chaos: FaultConfig,
+}
+
+impl<C: Check> Check for Config<C> {
+    fn check(&self, checker: &mut Checker) {
+        self.inner.check(checker);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Injected fault")]
+pub struct InjectedFault;
+
+/// A shared handle to the fault settings of a component.
+#[derive(Clone, Debug, Default)]
+pub struct Faults(Arc<RwLock<FaultConfig>>);
+
+impl Faults {
+    /// Register the faults of a component.
+    ///
+    /// If the component is already registered, the existing handle is returned, so that all
+    /// instances of a component share the same settings.
+    pub fn register(component: &str, config: FaultConfig) -> Self {
+        REGISTRY
+            .lock()
+            .unwrap()
+            .entry(component.to_string())
+            .or_insert_with(|| Self(Arc::new(RwLock::new(config))))
+            .clone()
+    }
+
+    pub fn get(&self) -> FaultConfig {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: FaultConfig) {
+        log::warn!("Changing injected faults: {config:?}");
+        *self.0.write().unwrap() = config;
+    }
+
+    /// Apply the configured faults to the current operation.
+    pub async fn inject(&self) -> Result<(), InjectedFault> {
+        let (delay, fail) = {
+            let config = self.0.read().unwrap();
+            (config.delay(), config.fail())
+        };
+
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        match fail {
+            true => Err(InjectedFault),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Lookup the faults handle of a registered component.
+pub fn lookup(component: &str) -> Option<Faults> {
+    REGISTRY.lock().unwrap().get(component).cloned()
+}
+
+/// List all registered components.
+pub fn components() -> Vec<String> {
+    REGISTRY.lock().unwrap().keys().cloned().collect()
+}
+
+/// Wraps an implementation, injecting faults.
+#[derive(Clone, Debug)]
+pub struct Chaos<T> {
+    inner: T,
+    faults: Faults,
+}
+
+impl<T> Chaos<T> {
+    pub fn new(component: &str, inner: T, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            faults: Faults::register(component, config),
+        }
+    }
+
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+}
+
+#[async_trait]
+impl<S: storage::Storage> storage::Storage for Chaos<S> {
+    type Config = Config<S::Config>;
+    type Error = S::Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            "storage",
+            S::from_config(&config.inner)?,
+            config.chaos.clone(),
+        ))
+    }
+
+    async fn get(
+        &self,
+        application: &str,
+        name: &str,
+    ) -> Result<Option<Thing<Internal>>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.get(application, name).await
+    }
+
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &storage::ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.list_with(application, opts).await
+    }
+
+    async fn query(
+        &self,
+        application: &str,
+        query: &storage::query::Query,
+        opts: &storage::ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.query(application, query, opts).await
+    }
+
+    async fn stats(
+        &self,
+        application: &str,
+    ) -> Result<storage::Stats, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.stats(application).await
+    }
+
+    async fn pending_events(
+        &self,
+        application: &str,
+        name: &str,
+    ) -> Result<Vec<Event>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.pending_events(application, name).await
+    }
+
+    async fn create(
+        &self,
+        thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.create(thing).await
+    }
+
+    async fn import(
+        &self,
+        thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.import(thing).await
+    }
+
+    async fn update(
+        &self,
+        thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.update(thing).await
+    }
+
+    async fn update_from(
+        &self,
+        current: &Thing<Internal>,
+        thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.update_from(current, thing).await
+    }
+
+    async fn create_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<
+        Vec<Result<Thing<Internal>, storage::Error<Self::Error>>>,
+        storage::Error<Self::Error>,
+    > {
+        self.faults.inject().await?;
+        self.inner.create_many(things).await
+    }
+
+    async fn update_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<
+        Vec<Result<Thing<Internal>, storage::Error<Self::Error>>>,
+        storage::Error<Self::Error>,
+    > {
+        self.faults.inject().await?;
+        self.inner.update_many(things).await
+    }
+
+    async fn delete_with(
+        &self,
+        application: &str,
+        name: &str,
+        opts: Preconditions<'_>,
+    ) -> Result<bool, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.delete_with(application, name, opts).await
+    }
+
+    async fn get_revision(
+        &self,
+        application: &str,
+        name: &str,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>, storage::Error<Self::Error>> {
+        self.faults.inject().await?;
+        self.inner.get_revision(application, name, generation).await
+    }
+}
+
+impl<E> From<InjectedFault> for storage::Error<E>
+where
+    E: Send + Sync + std::error::Error,
+{
+    fn from(err: InjectedFault) -> Self {
+        Self::Generic(err.to_string())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error<E: std::error::Error + 'static> {
+    #[error("Injected fault")]
+    Injected,
+    #[error(transparent)]
+    Inner(E),
+}
+
+#[async_trait]
+impl<N: notifier::Notifier> notifier::Notifier for Chaos<N> {
+    type Config = Config<N::Config>;
+    type Error = Error<N::Error>;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            "notifier",
+            N::from_config(&config.inner)?,
+            config.chaos.clone(),
+        ))
+    }
+
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.faults
+            .inject()
+            .await
+            .map_err(|_| notifier::Error::Sender(Error::Injected))?;
+
+        self.inner
+            .notify(thing)
+            .await
+            .map_err(|notifier::Error::Sender(err)| notifier::Error::Sender(Error::Inner(err)))
+    }
+
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.faults
+            .inject()
+            .await
+            .map_err(|_| notifier::Error::Sender(Error::Injected))?;
+
+        self.inner
+            .notify_change(current, thing)
+            .await
+            .map_err(|notifier::Error::Sender(err)| notifier::Error::Sender(Error::Inner(err)))
+    }
+}
+
+#[async_trait]
+impl<S: sink::Sink> sink::Sink for Chaos<S> {
+    type Config = Config<S::Config>;
+
+    fn from_config(config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            "sink",
+            S::from_config(config.inner)?,
+            config.chaos,
+        ))
+    }
+
+    async fn publish(&self, event: Event) -> anyhow::Result<()> {
+        self.faults.inject().await.map_err(|err| anyhow!(err))?;
+        self.inner.publish(event).await
+    }
+
+    async fn publish_iter<I>(&self, i: I) -> Result<(), (usize, anyhow::Error)>
+    where
+        I: IntoIterator<Item = Event> + Send + Sync,
+        <I as IntoIterator>::IntoIter: Send + Sync,
+    {
+        self.faults
+            .inject()
+            .await
+            .map_err(|err| (0, anyhow!(err)))?;
+        self.inner.publish_iter(i).await
+    }
+
+    async fn tombstone(&self, application: &str, thing: &str) -> anyhow::Result<()> {
+        self.faults.inject().await.map_err(|err| anyhow!(err))?;
+        self.inner.tombstone(application, thing).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_no_faults() {
+        let faults = Faults::default();
+        for _ in 0..100 {
+            assert!(faults.inject().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_always_fail() {
+        let faults = Faults::default();
+        faults.set(FaultConfig {
+            error_rate: 1.0,
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            assert!(faults.inject().await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_delay() {
+        let config = FaultConfig {
+            latency: Some(Duration::from_millis(100)),
+            jitter: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = config.delay().unwrap();
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! The clock, providing the current time for processing changes.
+//!
+//! By default, the system clock is used. A different clock can be injected for the scope of a
+//! task, the same way as the correlation ID (see [`crate::correlation`]). This allows processing
+//! recorded events with their original timestamps, making the outcome reproducible.
+//!
+//! Deadlines of scripts are not affected, as they limit the actual time spent running the script.
+
+use chrono::{DateTime, Utc};
+use std::{fmt::Debug, future::Future, sync::Arc};
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock, which is stopped at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+tokio::task_local! {
+    static CLOCK: Arc<dyn Clock>;
+}
+
+/// Run a future in the scope of a clock.
+pub async fn scope<F>(clock: Arc<dyn Clock>, f: F) -> F::Output
+where
+    F: Future,
+{
+    CLOCK.scope(clock, f).await
+}
+
+/// Get the current time, of the clock of the current scope, or the system clock.
+pub fn now() -> DateTime<Utc> {
+    CLOCK
+        .try_with(|clock| clock.now())
+        .unwrap_or_else(|_| Utc::now())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[tokio::test]
+    async fn test_scope() {
+        let time = Utc.ymd(2022, 1, 1).and_hms(12, 0, 0);
+
+        assert_ne!(now(), time);
+
+        let scoped = scope(Arc::new(FixedClock(time)), async {
+            tokio::task::yield_now().await;
+            now()
+        })
+        .await;
+        assert_eq!(scoped, time);
+
+        assert_ne!(now(), time);
+    }
+}
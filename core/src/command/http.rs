@@ -0,0 +1,208 @@
+//! Sending commands using the command API of Drogue Cloud.
+//!
+//! Commands are posted to the command endpoint, authenticating using an access token, which is
+//! requested from the token endpoint using OAuth client credentials. This doesn't require any
+//! MQTT access to Drogue Cloud.
+
+use crate::{
+    command::Command,
+    config::check::{Check, Checker},
+};
+use async_trait::async_trait;
+use drogue_bazaar::app::Startup;
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::instrument;
+use url::Url;
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Config {
+    /// The URL of the Drogue Cloud API, e.g. `https://api.sandbox.drogue.cloud`.
+    pub url: String,
+    /// The OAuth token endpoint, issuing the access tokens.
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+    /// Override the application of all commands.
+    #[serde(default)]
+    pub application: Option<String>,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        for (name, url) in [("url", &self.url), ("token_url", &self.token_url)] {
+            if let Err(err) = Url::parse(url) {
+                checker.issue(name, format!("invalid URL: {err}"));
+            }
+        }
+        checker
+            .not_empty("client_id", &self.client_id)
+            .not_empty("client_secret", &self.client_secret);
+    }
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+/// Time before the expiration of an access token, at which it gets refreshed.
+const TOKEN_REFRESH: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Token endpoint responded with: {0}")]
+    Token(StatusCode),
+    #[error("Command endpoint responded with: {0}")]
+    Response(StatusCode),
+    #[error("Invalid URL: {0}")]
+    Url(String),
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+pub struct CommandSink {
+    client: reqwest::Client,
+    config: Config,
+    url: Url,
+    /// The current access token, and when it expires.
+    token: Mutex<Option<(String, Option<Instant>)>>,
+}
+
+/// The URL of the command endpoint of a device.
+pub fn command_url(
+    url: &Url,
+    application: &str,
+    device: &str,
+    command: &str,
+) -> Result<Url, Error> {
+    let mut url = url.clone();
+    url.path_segments_mut()
+        .map_err(|()| Error::Url("must be a base URL".to_string()))?
+        .pop_if_empty()
+        .extend([
+            "api",
+            "command",
+            "v1alpha1",
+            "apps",
+            application,
+            "devices",
+            device,
+        ]);
+    url.query_pairs_mut().append_pair("command", command);
+    Ok(url)
+}
+
+impl CommandSink {
+    /// Get an access token, requesting a new one if there is none, or it is about to expire.
+    async fn token(&self) -> Result<String, Error> {
+        let mut token = self.token.lock().await;
+
+        if let Some((token, expires)) = &*token {
+            if expires.map_or(true, |expires| Instant::now() + TOKEN_REFRESH < expires) {
+                return Ok(token.clone());
+            }
+        }
+
+        let response = self
+            .client
+            .post(&self.config.token_url)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(&[("grant_type", "client_credentials")])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(Error::Token(response.status()));
+        }
+
+        let TokenResponse {
+            access_token,
+            expires_in,
+        } = response.json().await?;
+        let expires = expires_in.map(|expires_in| Instant::now() + Duration::from_secs(expires_in));
+
+        *token = Some((access_token.clone(), expires));
+
+        Ok(access_token)
+    }
+}
+
+#[async_trait]
+impl super::CommandSink for CommandSink {
+    type Error = Error;
+    type Config = Config;
+
+    fn from_config(_startup: &mut dyn Startup, config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(config.timeout).build()?,
+            url: Url::parse(&config.url)?,
+            config,
+            token: Default::default(),
+        })
+    }
+
+    #[instrument(skip_all, fields(
+        application=command.application,
+        device=command.device,
+        channel=command.channel,
+    ), err)]
+    async fn send_command(&self, command: Command) -> Result<(), Self::Error> {
+        let application = self
+            .config
+            .application
+            .as_ref()
+            .unwrap_or(&command.application);
+        let url = command_url(&self.url, application, &command.device, &command.channel)?;
+
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(self.token().await?)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(command.payload)
+            .send()
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED => {
+                // request a new token with the next command
+                self.token.lock().await.take();
+                Err(Error::Response(StatusCode::UNAUTHORIZED))
+            }
+            status => Err(Error::Response(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_url() {
+        let url = Url::parse("https://api.sandbox.drogue.cloud/").unwrap();
+
+        assert_eq!(
+            command_url(&url, "app", "device/1", "set-state")
+                .unwrap()
+                .as_str(),
+            "https://api.sandbox.drogue.cloud/api/command/v1alpha1/apps/app/devices/device%2F1?command=set-state"
+        );
+    }
+}
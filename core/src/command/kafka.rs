@@ -0,0 +1,123 @@
+//! Sending commands to a Kafka topic.
+//!
+//! Commands are published as binary mode cloud events, using the structure of Drogue Cloud
+//! commands: the application, device, and command (channel) are carried in the headers, the
+//! payload of the command as record payload.
+
+use crate::{
+    command::Command,
+    config::{
+        check::{Check, Checker},
+        kafka::KafkaProperties,
+    },
+    kafka::AddHeader,
+};
+use async_trait::async_trait;
+use drogue_bazaar::app::Startup;
+use rdkafka::{
+    config::FromClientConfig,
+    error::KafkaError,
+    message::OwnedHeaders,
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+};
+use std::{collections::HashMap, time::Duration};
+use tracing::instrument;
+use uuid::Uuid;
+
+/// The cloud events type of commands.
+pub const TYPE_COMMAND: &str = "io.drogue.command.v1";
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    pub topic: String,
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+    /// Override the application of all commands.
+    #[serde(default)]
+    pub application: Option<String>,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .kafka("properties", &self.properties)
+            .not_empty("topic", &self.topic);
+    }
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(2)
+    }
+}
+
+pub struct CommandSink {
+    producer: FutureProducer,
+    topic: String,
+    timeout: Timeout,
+    application: Option<String>,
+}
+
+impl CommandSink {
+    /// The headers of a command, following the structure of Drogue Cloud.
+    fn headers(application: &str, command: &Command) -> OwnedHeaders {
+        OwnedHeaders::new()
+            .add("ce_specversion", "1.0")
+            .add("ce_id", &Uuid::new_v4().to_string())
+            .add(
+                "ce_source",
+                &format!("drogue://{application}/{}", command.device),
+            )
+            .add("ce_type", TYPE_COMMAND)
+            .add("ce_time", &crate::clock::now().to_rfc3339())
+            .add("ce_subject", &command.channel)
+            .add("ce_application", application)
+            .add("ce_device", &command.device)
+            .add("content-type", "application/octet-stream")
+    }
+}
+
+#[async_trait]
+impl super::CommandSink for CommandSink {
+    type Error = KafkaError;
+    type Config = Config;
+
+    fn from_config(_startup: &mut dyn Startup, config: Self::Config) -> anyhow::Result<Self> {
+        let properties: rdkafka::ClientConfig = KafkaProperties(config.properties).into();
+        let producer = FutureProducer::from_config(&properties)?;
+
+        Ok(Self {
+            producer,
+            topic: config.topic,
+            timeout: Timeout::After(config.timeout),
+            application: config.application,
+        })
+    }
+
+    #[instrument(skip_all, fields(
+        application=command.application,
+        device=command.device,
+        channel=command.channel,
+    ), err)]
+    async fn send_command(&self, command: Command) -> Result<(), Self::Error> {
+        let application = self.application.as_ref().unwrap_or(&command.application);
+
+        let key = format!("{application}/{}", command.device);
+        let headers = Self::headers(application, &command);
+
+        let record = FutureRecord::to(&self.topic)
+            .key(&key)
+            .headers(headers)
+            .payload(&command.payload);
+
+        match self.producer.send(record, self.timeout).await {
+            Ok(_) => Ok(()),
+            Err((err, _)) => Err(err),
+        }
+    }
+}
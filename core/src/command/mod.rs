@@ -1,4 +1,7 @@
+pub mod http;
+pub mod kafka;
 pub mod mqtt;
+pub mod router;
 
 use async_trait::async_trait;
 use drogue_bazaar::app::Startup;
@@ -12,6 +15,8 @@ pub struct Command {
     pub device: String,
     pub channel: String,
     pub payload: Vec<u8>,
+    /// The route of the command, see [`router`].
+    pub route: Option<String>,
 }
 
 #[async_trait]
@@ -1,4 +1,8 @@
-use crate::{command::Command, mqtt::MqttClient};
+use crate::{
+    command::Command,
+    config::check::{Check, Checker},
+    mqtt::MqttClient,
+};
 use async_trait::async_trait;
 use drogue_bazaar::app::{Startup, StartupExt};
 use rumqttc::{AsyncClient, ClientError, Event, EventLoop, Incoming, Outgoing, QoS};
@@ -17,6 +21,12 @@ pub struct Config {
     pub initial_reconnect_delay: Duration,
 }
 
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker.mqtt(&self.client);
+    }
+}
+
 mod default {
     use std::time::Duration;
 
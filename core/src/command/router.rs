@@ -0,0 +1,218 @@
+//! Routing commands to different command sinks.
+//!
+//! The sink of a command is selected by the route of the thing (the annotation
+//! [`ANNOTATION_COMMAND_ROUTE`]), the application of the command, or falls back to the default
+//! sink.
+
+use crate::{
+    command::{http, kafka, mqtt, Command, CommandSink as _},
+    config::check::{Check, Checker},
+};
+use async_trait::async_trait;
+use drogue_bazaar::app::Startup;
+use std::collections::HashMap;
+use tracing::instrument;
+
+/// Annotation selecting the route of the commands of a thing.
+pub const ANNOTATION_COMMAND_ROUTE: &str = "drogue.io/command-route";
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Config {
+    /// The named command sinks.
+    pub sinks: HashMap<String, SinkConfig>,
+    /// Mapping routes (the value of the route annotation) to sinks.
+    #[serde(default)]
+    pub routes: HashMap<String, String>,
+    /// Mapping applications to sinks.
+    #[serde(default)]
+    pub applications: HashMap<String, String>,
+    /// The sink of commands without a matching route or application.
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "type")]
+pub enum SinkConfig {
+    Mqtt(mqtt::Config),
+    Kafka(kafka::Config),
+    Http(http::Config),
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if self.sinks.is_empty() {
+            checker.issue("sinks", "must not be empty");
+        }
+
+        for (name, sink) in &self.sinks {
+            let name = format!("sinks.{name}");
+            match sink {
+                SinkConfig::Mqtt(config) => checker.field(&name, config),
+                SinkConfig::Kafka(config) => checker.field(&name, config),
+                SinkConfig::Http(config) => checker.field(&name, config),
+            };
+        }
+
+        let targets = self
+            .routes
+            .iter()
+            .map(|(key, sink)| (format!("routes.{key}"), sink))
+            .chain(
+                self.applications
+                    .iter()
+                    .map(|(key, sink)| (format!("applications.{key}"), sink)),
+            )
+            .chain(
+                self.default
+                    .iter()
+                    .map(|sink| ("default".to_string(), sink)),
+            );
+
+        for (name, sink) in targets {
+            if !self.sinks.contains_key(sink) {
+                checker.issue(&name, format!("unknown sink: {sink}"));
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("MQTT: {0}")]
+    Mqtt(#[from] rumqttc::ClientError),
+    #[error("Kafka: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[error("HTTP: {0}")]
+    Http(#[from] http::Error),
+    #[error("No command sink for application: {0}")]
+    NoRoute(String),
+}
+
+enum Sink {
+    Mqtt(mqtt::CommandSink),
+    Kafka(kafka::CommandSink),
+    Http(http::CommandSink),
+}
+
+impl Sink {
+    fn from_config(startup: &mut dyn Startup, config: SinkConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            SinkConfig::Mqtt(config) => {
+                Self::Mqtt(mqtt::CommandSink::from_config(startup, config)?)
+            }
+            SinkConfig::Kafka(config) => {
+                Self::Kafka(kafka::CommandSink::from_config(startup, config)?)
+            }
+            SinkConfig::Http(config) => {
+                Self::Http(http::CommandSink::from_config(startup, config)?)
+            }
+        })
+    }
+
+    async fn send_command(&self, command: Command) -> Result<(), Error> {
+        match self {
+            Self::Mqtt(sink) => sink.send_command(command).await?,
+            Self::Kafka(sink) => sink.send_command(command).await?,
+            Self::Http(sink) => sink.send_command(command).await?,
+        }
+        Ok(())
+    }
+}
+
+pub struct CommandSink {
+    sinks: HashMap<String, Sink>,
+    routes: HashMap<String, String>,
+    applications: HashMap<String, String>,
+    default: Option<String>,
+}
+
+impl CommandSink {
+    /// Select the name of the sink of a command.
+    fn select(&self, command: &Command) -> Option<&str> {
+        command
+            .route
+            .as_ref()
+            .and_then(|route| self.routes.get(route))
+            .or_else(|| self.applications.get(&command.application))
+            .or(self.default.as_ref())
+            .map(|sink| sink.as_str())
+    }
+}
+
+#[async_trait]
+impl super::CommandSink for CommandSink {
+    type Error = Error;
+    type Config = Config;
+
+    fn from_config(startup: &mut dyn Startup, config: Self::Config) -> anyhow::Result<Self> {
+        let mut sinks = HashMap::with_capacity(config.sinks.len());
+        for (name, sink) in config.sinks {
+            sinks.insert(name, Sink::from_config(startup, sink)?);
+        }
+
+        Ok(Self {
+            sinks,
+            routes: config.routes,
+            applications: config.applications,
+            default: config.default,
+        })
+    }
+
+    #[instrument(skip_all, fields(
+        application=command.application,
+        device=command.device,
+        channel=command.channel,
+        route=command.route,
+    ), err)]
+    async fn send_command(&self, command: Command) -> Result<(), Self::Error> {
+        match self.select(&command).and_then(|name| self.sinks.get(name)) {
+            Some(sink) => sink.send_command(command).await,
+            None => Err(Error::NoRoute(command.application)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command(application: &str, route: Option<&str>) -> Command {
+        Command {
+            application: application.to_string(),
+            device: "device".to_string(),
+            channel: "channel".to_string(),
+            payload: vec![],
+            route: route.map(ToString::to_string),
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let router = CommandSink {
+            sinks: Default::default(),
+            routes: [("legacy".to_string(), "mqtt".to_string())].into(),
+            applications: [("fleet".to_string(), "kafka".to_string())].into(),
+            default: Some("http".to_string()),
+        };
+
+        assert_eq!(router.select(&command("fleet", None)), Some("kafka"));
+        // the route of the thing takes precedence
+        assert_eq!(
+            router.select(&command("fleet", Some("legacy"))),
+            Some("mqtt")
+        );
+        assert_eq!(
+            router.select(&command("fleet", Some("unknown"))),
+            Some("kafka")
+        );
+        assert_eq!(router.select(&command("other", None)), Some("http"));
+
+        let router = CommandSink {
+            default: None,
+            ..router
+        };
+        assert_eq!(router.select(&command("other", None)), None);
+    }
+}
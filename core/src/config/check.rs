@@ -0,0 +1,435 @@
+//! Checking the configuration, before starting up.
+//!
+//! A check parses the full configuration from the environment, and validates it. Instead of
+//! failing on the first problem, all issues are collected and reported with the environment
+//! variable they originate from. Optionally, the connectivity to the configured services is
+//! probed too.
+
+use crate::{config::kafka::KafkaProperties, mqtt::MqttClient};
+use drogue_bazaar::{core::config::ConfigFromEnv, db::postgres};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    future::Future,
+    time::Duration,
+};
+
+/// The timeout when probing the connectivity to a service.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The mode of the configuration check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Mode {
+    /// Probe the connectivity to the configured services.
+    pub connect: bool,
+}
+
+impl Mode {
+    /// Evaluate the command line arguments, returning a mode if a check was requested.
+    ///
+    /// `--check-config` requests a check, `--check-connectivity` requests a check, which also
+    /// probes the connectivity.
+    pub fn from_args() -> Option<Self> {
+        let mut check = false;
+        let mut connect = false;
+
+        for arg in std::env::args().skip(1) {
+            match arg.as_str() {
+                "--check-config" => check = true,
+                "--check-connectivity" => {
+                    check = true;
+                    connect = true;
+                }
+                _ => {}
+            }
+        }
+
+        check.then_some(Self { connect })
+    }
+}
+
+/// An issue with the configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    /// The environment variable the issue originates from, may be empty if unknown.
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// A configuration which can be checked.
+pub trait Check {
+    fn check(&self, checker: &mut Checker);
+}
+
+impl<C: Check> Check for Option<C> {
+    fn check(&self, checker: &mut Checker) {
+        if let Some(config) = self {
+            config.check(checker);
+        }
+    }
+}
+
+impl Check for postgres::Config {
+    fn check(&self, checker: &mut Checker) {
+        let path = checker.path("");
+        checker.probes.push((path, Probe::Postgres(self.clone())));
+    }
+}
+
+/// A service to probe the connectivity to.
+enum Probe {
+    Kafka(HashMap<String, String>),
+    Postgres(postgres::Config),
+    Redis(String),
+    Nats(String),
+    Mqtt(MqttClient),
+}
+
+/// Collects the issues of a configuration.
+#[derive(Default)]
+pub struct Checker {
+    path: Vec<String>,
+    issues: Vec<Issue>,
+    probes: Vec<(String, Probe)>,
+}
+
+impl Checker {
+    /// Check a nested configuration.
+    pub fn field<C: Check + ?Sized>(&mut self, name: &str, config: &C) -> &mut Self {
+        self.path.push(name.to_string());
+        config.check(self);
+        self.path.pop();
+        self
+    }
+
+    /// Record an issue of a field, relative to the current configuration.
+    pub fn issue(&mut self, name: &str, message: impl Display) -> &mut Self {
+        self.issues.push(Issue {
+            path: self.path(name),
+            message: message.to_string(),
+        });
+        self
+    }
+
+    /// Require a field to be non-empty.
+    pub fn not_empty(&mut self, name: &str, value: &str) -> &mut Self {
+        if value.trim().is_empty() {
+            self.issue(name, "must not be empty");
+        }
+        self
+    }
+
+    /// Check the Kafka properties of a field.
+    pub fn kafka(&mut self, name: &str, properties: &HashMap<String, String>) -> &mut Self {
+        if !properties.contains_key("bootstrap_servers")
+            && !properties.contains_key("bootstrap.servers")
+        {
+            self.issue(&format!("{name}.bootstrap_servers"), "missing");
+        } else {
+            let path = self.path(name);
+            self.probes.push((path, Probe::Kafka(properties.clone())));
+        }
+        self
+    }
+
+    /// Check the Redis URL of a field.
+    pub fn redis(&mut self, name: &str, url: &str) -> &mut Self {
+        match ::redis::Client::open(url) {
+            Ok(_) => {
+                let path = self.path(name);
+                self.probes.push((path, Probe::Redis(url.into())));
+            }
+            Err(err) => {
+                self.issue(name, err);
+            }
+        }
+        self
+    }
+
+    /// Check the NATS servers of a field.
+    pub fn nats(&mut self, name: &str, servers: &str) -> &mut Self {
+        if servers.trim().is_empty() {
+            self.issue(name, "must not be empty");
+        } else {
+            let path = self.path(name);
+            self.probes.push((path, Probe::Nats(servers.into())));
+        }
+        self
+    }
+
+    /// Check an MQTT client configuration, located at the current path.
+    pub(crate) fn mqtt(&mut self, client: &MqttClient) -> &mut Self {
+        self.not_empty("host", &client.host);
+        if client.port == 0 {
+            self.issue("port", "must not be zero");
+        }
+        match (&client.username, &client.password) {
+            (Some(_), None) => {
+                self.issue("password", "missing, but username is set");
+            }
+            (None, Some(_)) => {
+                self.issue("username", "missing, but password is set");
+            }
+            _ => {
+                let path = self.path("");
+                self.probes.push((path, Probe::Mqtt(client.clone())));
+            }
+        }
+        self
+    }
+
+    /// The environment variable of a field, relative to the current configuration.
+    fn path(&self, name: &str) -> String {
+        env_var(
+            self.path
+                .iter()
+                .map(String::as_str)
+                .chain(name.split('.'))
+                .filter(|segment| !segment.is_empty()),
+        )
+    }
+
+    /// Probe the connectivity to all services of the configuration.
+    pub async fn probe(&mut self) {
+        for (path, probe) in std::mem::take(&mut self.probes) {
+            log::info!("Probing: {path}");
+            let result = match probe {
+                Probe::Kafka(properties) => probe_kafka(properties).await,
+                Probe::Postgres(config) => {
+                    with_timeout(async {
+                        config.create_pool()?.get().await?;
+                        Ok(())
+                    })
+                    .await
+                }
+                Probe::Redis(url) => {
+                    with_timeout(async {
+                        ::redis::Client::open(url)?.get_async_connection().await?;
+                        Ok(())
+                    })
+                    .await
+                }
+                Probe::Nats(servers) => {
+                    with_timeout(async {
+                        async_nats::connect(servers).await?;
+                        Ok(())
+                    })
+                    .await
+                }
+                Probe::Mqtt(client) => probe_mqtt(client).await,
+            };
+            if let Err(err) = result {
+                self.issues.push(Issue {
+                    path,
+                    message: format!("unable to connect: {err}"),
+                });
+            }
+        }
+    }
+
+    pub fn into_issues(self) -> Vec<Issue> {
+        self.issues
+    }
+}
+
+async fn with_timeout<F>(f: F) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    tokio::time::timeout(CONNECT_TIMEOUT, f)
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")))
+}
+
+async fn probe_kafka(properties: HashMap<String, String>) -> anyhow::Result<()> {
+    let config: rdkafka::ClientConfig = KafkaProperties(properties).into();
+    tokio::task::spawn_blocking(move || {
+        let consumer: BaseConsumer = config.create()?;
+        consumer.fetch_metadata(None, CONNECT_TIMEOUT)?;
+        Ok(())
+    })
+    .await?
+}
+
+async fn probe_mqtt(client: MqttClient) -> anyhow::Result<()> {
+    use rumqttc::{Event, Packet};
+
+    let (_client, mut eventloop) = rumqttc::AsyncClient::new(client.try_into()?, 1);
+    with_timeout(async move {
+        loop {
+            if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await? {
+                return Ok(());
+            }
+        }
+    })
+    .await
+}
+
+/// Convert a path of configuration keys into the name of the environment variable.
+fn env_var<'a>(segments: impl IntoIterator<Item = &'a str>) -> String {
+    segments
+        .into_iter()
+        .map(str::to_uppercase)
+        .collect::<Vec<_>>()
+        .join("__")
+}
+
+/// Convert an error parsing the configuration into an issue, locating the key if possible.
+fn parse_error(err: impl Display) -> Issue {
+    let message = err.to_string();
+
+    if let Some((message, key)) = message.rsplit_once(" for key `") {
+        if let Some(key) = key.strip_suffix('`') {
+            return Issue {
+                path: env_var(key.split('.')),
+                message: message.to_string(),
+            };
+        }
+    }
+
+    Issue {
+        path: String::new(),
+        message,
+    }
+}
+
+/// Parse and check the configuration, returning all issues.
+pub async fn check<C>(mode: Mode) -> Vec<Issue>
+where
+    C: DeserializeOwned + Check,
+{
+    let config = match C::from_env() {
+        Ok(config) => config,
+        Err(err) => return vec![parse_error(err)],
+    };
+
+    let mut checker = Checker::default();
+    config.check(&mut checker);
+
+    // only probe if the configuration itself is valid
+    if mode.connect && checker.issues.is_empty() {
+        checker.probe().await;
+    }
+
+    checker.into_issues()
+}
+
+/// Run the configuration check, printing the issues.
+///
+/// Fails if there are any issues.
+pub async fn run<C>(mode: Mode) -> anyhow::Result<()>
+where
+    C: DeserializeOwned + Check,
+{
+    let issues = check::<C>(mode).await;
+
+    if issues.is_empty() {
+        println!("Configuration is valid");
+        return Ok(());
+    }
+
+    for issue in &issues {
+        println!("{issue}");
+    }
+
+    anyhow::bail!("Configuration has {} issue(s)", issues.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Kafka {
+        properties: HashMap<String, String>,
+        topic: String,
+    }
+
+    impl Check for Kafka {
+        fn check(&self, checker: &mut Checker) {
+            checker
+                .kafka("properties", &self.properties)
+                .not_empty("topic", &self.topic);
+        }
+    }
+
+    struct Root {
+        sink: Kafka,
+        source: Option<Kafka>,
+    }
+
+    impl Check for Root {
+        fn check(&self, checker: &mut Checker) {
+            checker
+                .field("event_sink", &self.sink)
+                .field("event_source", &self.source);
+        }
+    }
+
+    #[test]
+    fn test_issues() {
+        let config = Root {
+            sink: Kafka {
+                properties: Default::default(),
+                topic: "".to_string(),
+            },
+            source: Some(Kafka {
+                properties: [(
+                    "bootstrap_servers".to_string(),
+                    "localhost:9092".to_string(),
+                )]
+                .into_iter()
+                .collect(),
+                topic: "events".to_string(),
+            }),
+        };
+
+        let mut checker = Checker::default();
+        config.check(&mut checker);
+
+        assert_eq!(checker.probes.len(), 1);
+        assert_eq!(
+            checker.into_issues(),
+            vec![
+                Issue {
+                    path: "EVENT_SINK__PROPERTIES__BOOTSTRAP_SERVERS".to_string(),
+                    message: "missing".to_string(),
+                },
+                Issue {
+                    path: "EVENT_SINK__TOPIC".to_string(),
+                    message: "must not be empty".to_string(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert_eq!(
+            parse_error(
+                "invalid type: string \"foo\", expected a boolean for key `sink.tombstones`"
+            ),
+            Issue {
+                path: "SINK__TOMBSTONES".to_string(),
+                message: "invalid type: string \"foo\", expected a boolean".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_error("missing field `topic`"),
+            Issue {
+                path: "".to_string(),
+                message: "missing field `topic`".to_string(),
+            }
+        );
+    }
+}
@@ -0,0 +1,32 @@
+//! Correlation of log events across components.
+//!
+//! A correlation ID is tracked by a task local value. It gets initialized from an incoming
+//! HTTP request or event, and is forwarded with every event created in that scope.
+
+use std::future::Future;
+use uuid::Uuid;
+
+/// The HTTP header carrying the correlation ID.
+pub const HEADER_CORRELATION_ID: &str = "X-Correlation-ID";
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+/// Run a future in the scope of a correlation ID.
+pub async fn scope<F>(correlation_id: String, f: F) -> F::Output
+where
+    F: Future,
+{
+    CORRELATION_ID.scope(correlation_id, f).await
+}
+
+/// Get the correlation ID of the current scope, if there is any.
+pub fn current() -> Option<String> {
+    CORRELATION_ID.try_with(Clone::clone).ok()
+}
+
+/// Create a new, random, correlation ID.
+pub fn new_id() -> String {
+    Uuid::new_v4().to_string()
+}
@@ -1,5 +1,88 @@
-use cloudevents::Data;
-use serde::de::DeserializeOwned;
+use chrono::{DateTime, Utc};
+use cloudevents::{Data, EventBuilder, EventBuilderV10};
+use serde::{de::DeserializeOwned, de::Error as _, Serialize};
+use serde_json::Value;
+
+/// The source of all cloud events, created by the twin.
+pub const SOURCE: &str = "drogue-doppelgaenger";
+/// The type of events, consumed by the processor.
+pub const TYPE_EVENT: &str = "io.drogue.doppelgeanger.event.v1";
+/// The type of change notifications.
+pub const TYPE_CHANGE: &str = "io.drogue.doppelgaenger.change.v1";
+
+/// The content type of structured cloud events.
+pub const CONTENT_TYPE_STRUCTURED: &str = "application/cloudevents+json";
+
+/// The format of the payload of events and notifications.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Format {
+    /// Plain JSON.
+    ///
+    /// Where the transport supports it, the cloud events attributes are still sent as
+    /// headers (binary mode).
+    #[default]
+    Json,
+    /// A structured cloud event, containing the JSON payload as data.
+    CloudEvents,
+}
+
+impl Format {
+    /// The content type of the payload.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::CloudEvents => CONTENT_TYPE_STRUCTURED,
+        }
+    }
+}
+
+/// Create a builder for a cloud event about a thing.
+///
+/// The subject is `<application>/<thing>`, the application and thing are also set as extensions.
+pub fn builder(
+    id: impl Into<String>,
+    r#type: &str,
+    application: &str,
+    thing: &str,
+    time: DateTime<Utc>,
+) -> EventBuilderV10 {
+    EventBuilderV10::new()
+        .id(id)
+        .source(SOURCE)
+        .ty(r#type)
+        .subject(format!("{application}/{thing}"))
+        .time(time)
+        .extension("application", application)
+        .extension("thing", thing)
+}
+
+/// Build the event with JSON data, and encode it as structured cloud event.
+pub fn to_structured<T: Serialize>(
+    builder: EventBuilderV10,
+    data: &T,
+) -> Result<Vec<u8>, serde_json::Error> {
+    let event = builder
+        .data("application/json", serde_json::to_value(data)?)
+        .build()
+        .map_err(serde_json::Error::custom)?;
+    serde_json::to_vec(&event)
+}
+
+/// Check if the JSON value is a structured cloud event.
+pub fn is_structured(value: &Value) -> bool {
+    value.get("specversion").is_some()
+}
+
+/// Unwrap the data of a structured cloud event, or return the value as is.
+pub fn unwrap_structured(value: &Value) -> Result<Option<Value>, serde_json::Error> {
+    if !is_structured(value) {
+        return Ok(Some(value.clone()));
+    }
+
+    let mut event: cloudevents::Event = serde_json::from_value(value.clone())?;
+    event.take_as_json()
+}
 
 /// Work with the data section of cloud events.
 pub trait DataExt {
@@ -36,3 +119,33 @@ impl DataExt for cloudevents::Event {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::AttributesReader;
+    use serde_json::json;
+
+    #[test]
+    fn test_structured() {
+        let builder = builder("1", TYPE_CHANGE, "default", "thing1", Utc::now());
+        let payload = to_structured(builder, &json!({"foo": "bar"})).unwrap();
+
+        let value: Value = serde_json::from_slice(&payload).unwrap();
+        assert!(is_structured(&value));
+
+        let event: cloudevents::Event = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(event.ty(), TYPE_CHANGE);
+        assert_eq!(event.subject(), Some("default/thing1"));
+
+        assert_eq!(
+            unwrap_structured(&value).unwrap(),
+            Some(json!({"foo": "bar"}))
+        );
+        // plain JSON is passed through
+        assert_eq!(
+            unwrap_structured(&json!({"foo": "bar"})).unwrap(),
+            Some(json!({"foo": "bar"}))
+        );
+    }
+}
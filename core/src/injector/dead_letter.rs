@@ -0,0 +1,235 @@
+//! Storing events which could not be injected.
+//!
+//! Each dead letter contains the original cloud event, along with the error, so that broken
+//! payloads can be diagnosed later on.
+
+use crate::config::{
+    check::{Check, Checker},
+    kafka::KafkaProperties,
+};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use cloudevents::AttributesReader;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use rdkafka::{
+    config::FromClientConfig,
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+lazy_static! {
+    static ref DEAD_LETTERS: IntCounterVec = register_int_counter_vec!(
+        "injector_dead_letters",
+        "Number of events stored as dead letter",
+        &["source", "result"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[serde(tag = "type")]
+pub enum Config {
+    /// Send dead letters to a Kafka topic.
+    Kafka(KafkaConfig),
+    /// Append dead letters to a file, one JSON object per line.
+    File(FileConfig),
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct KafkaConfig {
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    pub topic: String,
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct FileConfig {
+    pub path: PathBuf,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(2)
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        match self {
+            Self::Kafka(config) => {
+                checker
+                    .kafka("properties", &config.properties)
+                    .not_empty("topic", &config.topic);
+            }
+            Self::File(config) => {
+                if config.path.as_os_str().is_empty() {
+                    checker.issue("path", "must not be empty");
+                }
+            }
+        }
+    }
+}
+
+/// A dead letter, as it gets stored.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    /// The name of the injector source.
+    pub source: String,
+    /// The time the event was rejected.
+    pub timestamp: DateTime<Utc>,
+    /// The reason the event was rejected.
+    pub error: String,
+    /// The original event.
+    pub event: cloudevents::Event,
+}
+
+/// Stores events which could not be injected.
+pub enum DeadLetterSink {
+    Kafka {
+        producer: FutureProducer,
+        topic: String,
+        timeout: Timeout,
+    },
+    File(Arc<Mutex<File>>),
+}
+
+impl DeadLetterSink {
+    pub fn from_config(config: Config) -> anyhow::Result<Self> {
+        Ok(match config {
+            Config::Kafka(config) => {
+                let properties: rdkafka::ClientConfig = KafkaProperties(config.properties).into();
+                Self::Kafka {
+                    producer: FutureProducer::from_config(&properties)?,
+                    topic: config.topic,
+                    timeout: Timeout::After(config.timeout),
+                }
+            }
+            Config::File(config) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)
+                    .map_err(|err| {
+                        anyhow!(
+                            "Failed to open dead letter file '{}': {err}",
+                            config.path.display()
+                        )
+                    })?;
+                Self::File(Arc::new(Mutex::new(file)))
+            }
+        })
+    }
+
+    /// Store the event as dead letter.
+    ///
+    /// Failing to store the dead letter is logged, but not reported, as the event is lost
+    /// either way.
+    pub async fn store(&self, source: &str, event: cloudevents::Event, error: &anyhow::Error) {
+        let letter = DeadLetter {
+            source: source.to_string(),
+            timestamp: Utc::now(),
+            error: error.to_string(),
+            event,
+        };
+
+        match self.send(letter).await {
+            Ok(()) => {
+                DEAD_LETTERS.with_label_values(&[source, "ok"]).inc();
+            }
+            Err(err) => {
+                DEAD_LETTERS.with_label_values(&[source, "failed"]).inc();
+                log::warn!("Failed to store dead letter: {err}");
+            }
+        }
+    }
+
+    async fn send(&self, letter: DeadLetter) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(&letter)?;
+
+        match self {
+            Self::Kafka {
+                producer,
+                topic,
+                timeout,
+            } => {
+                let key = letter.event.source().to_string();
+                let record = FutureRecord::to(topic).key(&key).payload(&payload);
+                producer
+                    .send(record, *timeout)
+                    .await
+                    .map_err(|(err, _)| err)?;
+            }
+            Self::File(file) => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut file = file
+                        .lock()
+                        .map_err(|_| anyhow!("Dead letter file lock poisoned"))?;
+                    file.write_all(&payload)?;
+                    file.write_all(b"\n")?;
+                    file.flush()?;
+                    Ok::<_, anyhow::Error>(())
+                })
+                .await??;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{EventBuilder, EventBuilderV10};
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_file() {
+        let path = std::env::temp_dir().join(format!("dead-letters-{}.json", Uuid::new_v4()));
+        let sink =
+            DeadLetterSink::from_config(Config::File(FileConfig { path: path.clone() })).unwrap();
+
+        let event = EventBuilderV10::new()
+            .id("1")
+            .source("drogue://app/device")
+            .ty("io.drogue.event.v1")
+            .data("application/octet-stream", vec![0xFFu8])
+            .build()
+            .unwrap();
+
+        sink.store("default", event.clone(), &anyhow!("Invalid payload"))
+            .await;
+        sink.store("default", event, &anyhow!("Invalid payload"))
+            .await;
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let letters = content
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0]["source"], json!("default"));
+        assert_eq!(letters[0]["error"], json!("Invalid payload"));
+        assert_eq!(letters[0]["event"]["id"], json!("1"));
+        assert_eq!(letters[0]["event"]["data_base64"], json!("/w=="));
+    }
+}
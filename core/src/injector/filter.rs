@@ -0,0 +1,263 @@
+//! Filtering events, before their payload gets mapped.
+//!
+//! The filter runs after the metadata mapper, so that it can match on the application, device,
+//! and channel of an event. Events can be dropped, or routed to a different application.
+
+use crate::{
+    applications,
+    config::check::{Check, Checker},
+    injector::metadata::Meta,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// A comma separated list of patterns, e.g. `sensor-*,!sensor-test`.
+///
+/// Uses the same syntax as [`applications::Applications`]: a pattern prefixed with `!` denies
+/// matching values, and a `*` matches any number of characters. A value matches, if it matches
+/// any of the allowing patterns, and none of the denying patterns. Without allowing patterns,
+/// all values which are not denied match.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Patterns {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid pattern '{0}'")]
+pub struct PatternsError(String);
+
+impl FromStr for Patterns {
+    type Err = PatternsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Self::default();
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (list, pattern) = match entry.strip_prefix('!') {
+                Some(pattern) => (&mut result.deny, pattern.trim()),
+                None => (&mut result.allow, entry),
+            };
+            if pattern.is_empty() {
+                return Err(PatternsError(entry.to_string()));
+            }
+            list.push(pattern.to_string());
+        }
+
+        Ok(result)
+    }
+}
+
+impl TryFrom<String> for Patterns {
+    type Error = PatternsError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Patterns {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self
+            .allow
+            .iter()
+            .cloned()
+            .chain(self.deny.iter().map(|pattern| format!("!{pattern}")))
+            .collect::<Vec<_>>();
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+impl Patterns {
+    /// Check if the value matches.
+    pub fn matches(&self, value: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| applications::matches(p, value)))
+            && !self.deny.iter().any(|p| applications::matches(p, value))
+    }
+}
+
+/// Patterns an event must match.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Matcher {
+    #[serde(default)]
+    pub applications: Patterns,
+    #[serde(default)]
+    pub devices: Patterns,
+    #[serde(default)]
+    pub channels: Patterns,
+    /// The content type of the event, without any parameters. Events without a content type
+    /// match as an empty value.
+    #[serde(default)]
+    pub content_types: Patterns,
+}
+
+impl Matcher {
+    /// Match an event, returning the name of the first mismatching attribute.
+    fn mismatch(&self, meta: &Meta, content_type: Option<&str>) -> Option<&'static str> {
+        let content_type = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(str::trim)
+            .unwrap_or_default();
+
+        if !self.applications.matches(&meta.application) {
+            Some("application")
+        } else if !self.devices.matches(&meta.device) {
+            Some("device")
+        } else if !self.channels.matches(&meta.channel) {
+            Some("channel")
+        } else if !self.content_types.matches(content_type) {
+            Some("content_type")
+        } else {
+            None
+        }
+    }
+}
+
+/// Route matching events to a different application.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Route {
+    #[serde(flatten)]
+    pub matcher: Matcher,
+    /// The application to route the events to.
+    pub application: String,
+}
+
+/// Filter events by their metadata.
+///
+/// Events not matching the filter are dropped. Accepted events are checked against the routes,
+/// in the order of their names, and routed by the first matching route.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Filter {
+    #[serde(flatten)]
+    pub accept: Matcher,
+    #[serde(default)]
+    pub routes: BTreeMap<String, Route>,
+}
+
+/// The outcome of filtering an event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome<'f> {
+    /// Accept the event.
+    Accept,
+    /// Accept the event, by the named route.
+    Route { name: &'f str, application: &'f str },
+    /// Drop the event, because of the mismatching attribute.
+    Drop(&'static str),
+}
+
+impl Check for Filter {
+    fn check(&self, checker: &mut Checker) {
+        for (name, route) in &self.routes {
+            checker.not_empty(&format!("routes.{name}.application"), &route.application);
+        }
+    }
+}
+
+impl Filter {
+    pub fn filter(&self, meta: &Meta, content_type: Option<&str>) -> Outcome {
+        if let Some(attribute) = self.accept.mismatch(meta, content_type) {
+            return Outcome::Drop(attribute);
+        }
+
+        self.routes
+            .iter()
+            .find(|(_, route)| route.matcher.mismatch(meta, content_type).is_none())
+            .map_or(Outcome::Accept, |(name, route)| Outcome::Route {
+                name,
+                application: &route.application,
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn meta(application: &str, device: &str, channel: &str) -> Meta {
+        Meta {
+            id: "1".to_string(),
+            timestamp: Utc::now(),
+            application: application.to_string(),
+            device: device.to_string(),
+            channel: channel.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_patterns() {
+        let patterns: Patterns = "application/*,!application/octet-stream".parse().unwrap();
+        assert!(patterns.matches("application/json"));
+        assert!(!patterns.matches("application/octet-stream"));
+        assert!(!patterns.matches("text/plain"));
+        assert_eq!(
+            patterns.to_string(),
+            "application/*,!application/octet-stream"
+        );
+
+        assert!(Patterns::default().matches(""));
+        assert!("foo,!".parse::<Patterns>().is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter {
+            accept: Matcher {
+                applications: "site-*".parse().unwrap(),
+                devices: "!test-*".parse().unwrap(),
+                channels: Patterns::default(),
+                content_types: "application/json".parse().unwrap(),
+            },
+            routes: [(
+                "legacy".to_string(),
+                Route {
+                    matcher: Matcher {
+                        channels: "legacy".parse().unwrap(),
+                        ..Default::default()
+                    },
+                    application: "site-legacy".to_string(),
+                },
+            )]
+            .into(),
+        };
+
+        let json = Some("application/json; charset=utf-8");
+
+        assert_eq!(
+            filter.filter(&meta("site-a", "device", "state"), json),
+            Outcome::Accept
+        );
+        assert_eq!(
+            filter.filter(&meta("site-a", "device", "legacy"), json),
+            Outcome::Route {
+                name: "legacy",
+                application: "site-legacy"
+            }
+        );
+        assert_eq!(
+            filter.filter(&meta("other", "device", "state"), json),
+            Outcome::Drop("application")
+        );
+        assert_eq!(
+            filter.filter(&meta("site-a", "test-1", "state"), json),
+            Outcome::Drop("device")
+        );
+        assert_eq!(
+            filter.filter(&meta("site-a", "device", "state"), None),
+            Outcome::Drop("content_type")
+        );
+
+        assert_eq!(
+            Filter::default().filter(&meta("any", "device", "state"), None),
+            Outcome::Accept
+        );
+    }
+}
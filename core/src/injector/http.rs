@@ -0,0 +1,264 @@
+//! Receive cloud events using HTTP.
+//!
+//! Events are posted to the endpoint, in the binary or structured content mode of the HTTP
+//! protocol binding. The request completes once the event got injected, so that clients can
+//! retry failed requests.
+
+use crate::{
+    config::check::{Check, Checker},
+    events::CONTENT_TYPE_STRUCTURED,
+    injector::{metadata::Context, mqtt::Target},
+};
+use actix_web::{
+    http::header::{self, HeaderMap},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use cloudevents::{AttributesReader, EventBuilder, EventBuilderV10};
+use std::net::SocketAddr;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The address to listen on.
+    #[serde(default = "default::bind_addr")]
+    pub bind_addr: String,
+    /// A token, which clients must present as bearer token.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The maximum size of an event, in bytes.
+    #[serde(default = "default::max_size")]
+    pub max_size: usize,
+}
+
+mod default {
+    pub fn bind_addr() -> String {
+        "[::]:8081".to_string()
+    }
+
+    pub const fn max_size() -> usize {
+        256 * 1024
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if let Err(err) = self.bind_addr.parse::<SocketAddr>() {
+            checker.issue("bind_addr", format!("invalid address: {err}"));
+        }
+        if let Some(token) = &self.token {
+            checker.not_empty("token", token);
+        }
+    }
+}
+
+impl Config {
+    pub async fn run<T>(self, target: T) -> anyhow::Result<()>
+    where
+        T: Target + Send + Sync + 'static,
+    {
+        log::info!("HTTP injector - listening on: {}", self.bind_addr);
+
+        let target = web::Data::new(target);
+        let token = web::Data::new(self.token);
+        let max_size = self.max_size;
+
+        HttpServer::new(move || {
+            App::new()
+                .app_data(target.clone())
+                .app_data(token.clone())
+                .app_data(web::PayloadConfig::new(max_size))
+                .route("/{path:.*}", web::post().to(inject::<T>))
+        })
+        .bind(&self.bind_addr)?
+        .run()
+        .await?;
+
+        log::warn!("Exiting HTTP injector");
+
+        Ok(())
+    }
+}
+
+async fn inject<T>(
+    target: web::Data<T>,
+    token: web::Data<Option<String>>,
+    request: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse
+where
+    T: Target + Send + Sync + 'static,
+{
+    if let Some(token) = token.as_ref() {
+        let expected = format!("Bearer {token}");
+        let authorization = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if authorization != Some(expected.as_str()) {
+            return HttpResponse::Unauthorized().finish();
+        }
+    }
+
+    let (event, context) = match to_event(request.path(), request.headers(), &body) {
+        Ok(result) => result,
+        Err(err) => {
+            log::info!("Unable to parse request: {err}");
+            return HttpResponse::BadRequest().body(err.to_string());
+        }
+    };
+
+    match handle_event(target.as_ref(), event, context).await {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(err) => {
+            log::warn!("Failed to handle event: {err}");
+            HttpResponse::ServiceUnavailable().finish()
+        }
+    }
+}
+
+#[instrument(skip_all, fields(id = event.id()))]
+async fn handle_event<T: Target>(
+    target: &T,
+    event: cloudevents::Event,
+    context: Context,
+) -> anyhow::Result<()> {
+    target.event(event, context).await
+}
+
+/// Convert an HTTP request, in binary or structured content mode, into a cloud event.
+///
+/// Headers which are not cloud events attributes are returned as part of the context, the path
+/// of the request as topic.
+fn to_event(
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> anyhow::Result<(cloudevents::Event, Context)> {
+    let mut builder = EventBuilderV10::new();
+    let mut content_type = None;
+    let mut context = Context {
+        topic: path.to_string(),
+        ..Default::default()
+    };
+
+    for (name, value) in headers {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            _ => continue,
+        };
+
+        match name.as_str() {
+            "content-type" => content_type = Some(value),
+            "authorization" | "ce-specversion" | "ce-datacontenttype" => {}
+            "ce-id" => builder = builder.id(value),
+            "ce-source" => builder = builder.source(value),
+            "ce-type" => builder = builder.ty(value),
+            "ce-subject" => builder = builder.subject(value),
+            "ce-time" => builder = builder.time(value.parse::<DateTime<Utc>>()?),
+            key => match key.strip_prefix("ce-") {
+                Some(name) => builder = builder.extension(name, value),
+                None => {
+                    context.headers.insert(key.to_string(), value.to_string());
+                }
+            },
+        }
+    }
+
+    if content_type.map_or(false, |content_type| {
+        content_type.starts_with(CONTENT_TYPE_STRUCTURED)
+    }) {
+        return Ok((serde_json::from_slice(body)?, context));
+    }
+
+    if !body.is_empty() {
+        builder = builder.data(
+            content_type.unwrap_or("application/octet-stream"),
+            body.to_vec(),
+        );
+    }
+
+    let event = builder
+        .build()
+        .map_err(|err| anyhow!("Invalid cloud event: {err}"))?;
+
+    Ok((event, context))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use serde_json::json;
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for &(name, value) in headers {
+            map.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    #[test]
+    fn test_binary() {
+        let (event, context) = to_event(
+            "/telemetry",
+            &headers(&[
+                ("ce-specversion", "1.0"),
+                ("ce-id", "1"),
+                ("ce-source", "drogue://app/device"),
+                ("ce-type", "io.drogue.event.v1"),
+                ("ce-subject", "state"),
+                ("ce-application", "app"),
+                ("ce-device", "device"),
+                ("content-type", "application/json"),
+                ("x-request-id", "42"),
+            ]),
+            br#"{"temperature": 21}"#,
+        )
+        .unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.subject(), Some("state"));
+        assert_eq!(
+            event.extension("device").map(ToString::to_string),
+            Some("device".to_string())
+        );
+        assert_eq!(context.topic, "/telemetry");
+        assert_eq!(context.headers["x-request-id"], "42");
+    }
+
+    #[test]
+    fn test_structured() {
+        let body = json!({
+            "specversion": "1.0",
+            "id": "1",
+            "source": "drogue://app/device",
+            "type": "io.drogue.event.v1",
+            "subject": "state",
+            "application": "app",
+            "device": "device",
+            "datacontenttype": "application/json",
+            "data": {"temperature": 21},
+        });
+
+        let (event, _) = to_event(
+            "/",
+            &headers(&[("content-type", CONTENT_TYPE_STRUCTURED)]),
+            &serde_json::to_vec(&body).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.subject(), Some("state"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(to_event("/", &headers(&[("ce-id", "1")]), b"").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Receive cloud events from a Kafka topic.
+//!
+//! Events are expected in the binary, or structured content mode of the Kafka protocol binding,
+//! like the events topics of Drogue Cloud. Running
+//! multiple instances with the same consumer group distributes the partitions among the
+//! instances. As events of a device share the same partition, the order of events per device
+//! is retained.
+
+use crate::{
+    config::{
+        check::{Check, Checker},
+        kafka::KafkaProperties,
+    },
+    events::CONTENT_TYPE_STRUCTURED,
+    injector::{metadata::Context, mqtt::Target},
+};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use cloudevents::{EventBuilder, EventBuilderV10};
+use rdkafka::{
+    config::FromClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    message::{BorrowedMessage, Headers},
+    Message,
+};
+use std::{collections::HashMap, str::from_utf8};
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+
+    pub topic: String,
+
+    /// The consumer group. Instances sharing the same group split the work among them.
+    pub group_id: String,
+
+    /// Where to start consuming, if the consumer group has no committed offset yet.
+    #[serde(default)]
+    pub offset_reset: OffsetReset,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OffsetReset {
+    /// Only consume events, which arrive after the consumer group was created.
+    #[default]
+    Latest,
+    /// Consume all events retained in the topic.
+    Earliest,
+}
+
+impl OffsetReset {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Latest => "latest",
+            Self::Earliest => "earliest",
+        }
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .kafka("properties", &self.properties)
+            .not_empty("topic", &self.topic)
+            .not_empty("group_id", &self.group_id);
+    }
+}
+
+impl Config {
+    pub async fn run<T: Target>(self, target: T) -> anyhow::Result<()> {
+        let mut config: rdkafka::ClientConfig = KafkaProperties(self.properties).into();
+
+        config.set("group.id", &self.group_id);
+        config.set("enable.partition.eof", "false");
+        config.set("auto.offset.reset", self.offset_reset.as_str());
+
+        // configure for QoS 1
+
+        config.set("enable.auto.commit", "true");
+        config.set("auto.commit.interval.ms", "5000");
+        config.set("enable.auto.offset.store", "false");
+
+        log::info!("Kafka injector - source: {config:?}");
+
+        let consumer = StreamConsumer::from_config(&config)?;
+        consumer.subscribe(&[&self.topic])?;
+
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::warn!("Failed to receive from Kafka: {err}");
+                    bail!("Failed to receive from Kafka: {err}");
+                }
+            };
+
+            match to_event(&msg) {
+                Ok((event, context)) => {
+                    if let Err(err) = handle_event(&target, event, context).await {
+                        log::warn!("Failed to handle event: {err}");
+                        bail!("Failed to handle event: {err}");
+                    }
+                }
+                Err(err) => {
+                    log::info!("Unable to parse message, skipping! Reason: {err}");
+                }
+            }
+
+            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                log::warn!("Failed to store offset: {err}");
+                bail!("Failed to store offset: {err}");
+            }
+        }
+    }
+}
+
+#[instrument(skip_all, fields(id = event.id()))]
+async fn handle_event<T: Target>(
+    target: &T,
+    event: cloudevents::Event,
+    context: Context,
+) -> anyhow::Result<()> {
+    target.event(event, context).await
+}
+
+/// Convert a Kafka message, in binary or structured content mode, into a cloud event.
+///
+/// Headers which are not cloud events attributes are returned as part of the context.
+fn to_event(msg: &BorrowedMessage) -> anyhow::Result<(cloudevents::Event, Context)> {
+    let structured = msg
+        .headers()
+        .iter()
+        .flat_map(|headers| headers.iter())
+        .any(|h| {
+            h.key == "content-type"
+                && h.value
+                    .and_then(|value| from_utf8(value).ok())
+                    .map_or(false, |value| value.starts_with(CONTENT_TYPE_STRUCTURED))
+        });
+
+    if structured {
+        let payload = msg.payload().ok_or_else(|| anyhow!("Missing payload"))?;
+        let context = Context {
+            topic: msg.topic().to_string(),
+            headers: msg
+                .headers()
+                .iter()
+                .flat_map(|headers| headers.iter())
+                .filter(|h| h.key != "content-type")
+                .filter_map(|h| {
+                    let value = from_utf8(h.value?).ok()?;
+                    Some((h.key.to_lowercase(), value.to_string()))
+                })
+                .collect(),
+        };
+        return Ok((serde_json::from_slice(payload)?, context));
+    }
+
+    let mut builder = EventBuilderV10::new();
+    let mut content_type = None;
+    let mut context = Context {
+        topic: msg.topic().to_string(),
+        ..Default::default()
+    };
+
+    for h in msg.headers().iter().flat_map(|headers| headers.iter()) {
+        let value = match h.value.map(from_utf8) {
+            Some(Ok(value)) => value,
+            _ => continue,
+        };
+
+        match h.key {
+            "content-type" => content_type = Some(value),
+            "ce_specversion" | "ce_datacontenttype" => {}
+            "ce_id" => builder = builder.id(value),
+            "ce_source" => builder = builder.source(value),
+            "ce_type" => builder = builder.ty(value),
+            "ce_subject" => builder = builder.subject(value),
+            "ce_time" => builder = builder.time(value.parse::<DateTime<Utc>>()?),
+            key => match key.strip_prefix("ce_") {
+                Some(name) => builder = builder.extension(name, value),
+                None => {
+                    context
+                        .headers
+                        .insert(key.to_lowercase(), value.to_string());
+                }
+            },
+        }
+    }
+
+    if let Some(payload) = msg.payload() {
+        builder = builder.data(
+            content_type.unwrap_or("application/octet-stream"),
+            payload.to_vec(),
+        );
+    }
+
+    Ok((builder.build()?, context))
+}
@@ -1,6 +1,8 @@
-use anyhow::anyhow;
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, TimeZone, Utc};
 use cloudevents::AttributesReader;
+use regex::Regex;
+use std::{collections::HashMap, str::FromStr};
 
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -12,6 +14,8 @@ pub enum MetadataMapper {
         #[serde(default)]
         override_application: Option<String>,
     },
+    /// Extracts the metadata using expressions
+    Expression(ExpressionMapper),
 }
 
 impl Default for MetadataMapper {
@@ -30,12 +34,26 @@ pub struct Meta {
     pub channel: String,
 }
 
+/// Information about a received event, which is not part of the cloud event itself.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// The topic the event was received from.
+    pub topic: String,
+    /// Message headers, which are not cloud events attributes. Names are lowercase.
+    pub headers: HashMap<String, String>,
+}
+
 impl MetadataMapper {
-    pub fn map(&self, event: &cloudevents::Event) -> anyhow::Result<Option<Meta>> {
+    pub fn map(
+        &self,
+        event: &cloudevents::Event,
+        context: &Context,
+    ) -> anyhow::Result<Option<Meta>> {
         match self {
             Self::Raw {
                 override_application,
             } => Self::map_raw(event, override_application.as_deref()),
+            Self::Expression(mapper) => mapper.map(event, context),
         }
     }
 
@@ -77,3 +95,488 @@ impl MetadataMapper {
         }))
     }
 }
+
+/// Extracts the metadata using an [`Expression`] for each value.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct ExpressionMapper {
+    /// Only accept events of this type, accepts all events if not set.
+    #[serde(default)]
+    pub event_type: Option<String>,
+    pub application: Expression,
+    pub device: Expression,
+    pub channel: Expression,
+    /// The ID of the event, defaults to the cloud event ID.
+    #[serde(default)]
+    pub id: Option<Expression>,
+    /// The timestamp of the event, as RFC 3339 or milliseconds since the epoch. Defaults to the
+    /// cloud event time, or the current time.
+    #[serde(default)]
+    pub timestamp: Option<Expression>,
+    /// Match a value of the event against a pattern, skipping events which don't match. The
+    /// captured values are available as `${capture:<name>}`.
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+}
+
+impl ExpressionMapper {
+    pub fn map(
+        &self,
+        event: &cloudevents::Event,
+        context: &Context,
+    ) -> anyhow::Result<Option<Meta>> {
+        if let Some(event_type) = &self.event_type {
+            if event.ty() != event_type {
+                return Ok(None);
+            }
+        }
+
+        let captures = match &self.pattern {
+            Some(pattern) => match pattern.captures(event, context) {
+                Some(captures) => captures,
+                None => return Ok(None),
+            },
+            None => HashMap::new(),
+        };
+
+        let eval = |name: &str, expression: &Expression| {
+            expression
+                .eval_with(event, context, &captures)
+                .ok_or_else(|| anyhow!("Missing value for '{name}'"))
+        };
+
+        let id = match &self.id {
+            Some(id) => eval("id", id)?,
+            None => event.id().to_string(),
+        };
+        let timestamp = match &self.timestamp {
+            Some(timestamp) => parse_timestamp(&eval("timestamp", timestamp)?)?,
+            None => event.time().copied().unwrap_or_else(Utc::now),
+        };
+
+        Ok(Some(Meta {
+            id,
+            timestamp,
+            application: eval("application", &self.application)?,
+            device: eval("device", &self.device)?,
+            channel: eval("channel", &self.channel)?,
+        }))
+    }
+}
+
+fn parse_timestamp(value: &str) -> anyhow::Result<DateTime<Utc>> {
+    match value.parse::<i64>() {
+        Ok(millis) => Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .ok_or_else(|| anyhow!("Invalid timestamp: {value}")),
+        Err(_) => Ok(DateTime::parse_from_rfc3339(value)?.with_timezone(&Utc)),
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct PatternConfig {
+    /// The value to match, defaults to the topic.
+    #[serde(default)]
+    pub input: Option<Expression>,
+    /// A regular expression, with named capture groups.
+    #[serde(default)]
+    pub regex: Option<String>,
+    /// A template, like `things/{thing}/data/{channel}`, capturing a segment per placeholder.
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+/// A pattern, capturing values from an event.
+///
+/// The pattern is either a regular expression, using named capture groups, or a template, in
+/// which each `{name}` placeholder captures a single segment (up to the next `/`). A template
+/// must match the whole input.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(try_from = "PatternConfig")]
+pub struct Pattern {
+    input: Expression,
+    regex: Regex,
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.input == other.input && self.regex.as_str() == other.regex.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl TryFrom<PatternConfig> for Pattern {
+    type Error = anyhow::Error;
+
+    fn try_from(config: PatternConfig) -> Result<Self, Self::Error> {
+        let regex = match (config.regex, config.template) {
+            (Some(regex), None) => Regex::new(&regex)?,
+            (None, Some(template)) => template_to_regex(&template)?,
+            (Some(_), Some(_)) => bail!("Only one of 'regex' and 'template' may be set"),
+            (None, None) => bail!("One of 'regex' or 'template' must be set"),
+        };
+
+        Ok(Self {
+            input: config
+                .input
+                .unwrap_or_else(|| Expression(vec![Part::Placeholder(vec![Selector::Topic])])),
+            regex,
+        })
+    }
+}
+
+impl Pattern {
+    /// Match the input of the event, returns `None` if it is missing or doesn't match.
+    pub fn captures(
+        &self,
+        event: &cloudevents::Event,
+        context: &Context,
+    ) -> Option<HashMap<String, String>> {
+        let input = self.input.eval(event, context)?;
+        let captures = self.regex.captures(&input)?;
+
+        Some(
+            self.regex
+                .capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    captures
+                        .name(name)
+                        .map(|value| (name.to_string(), value.as_str().to_string()))
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Convert a template into an anchored regular expression.
+fn template_to_regex(template: &str) -> anyhow::Result<Regex> {
+    let mut pattern = String::from("^");
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        pattern.push_str(&regex::escape(&rest[..start]));
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unterminated placeholder: {}", &rest[start..]))?;
+        let name = &rest[start + 1..start + end];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid placeholder name: '{name}'");
+        }
+        pattern.push_str(&format!("(?P<{name}>[^/]+)"));
+        rest = &rest[start + end + 1..];
+    }
+
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+
+    Ok(Regex::new(&pattern)?)
+}
+
+/// An expression, extracting a value from an event.
+///
+/// An expression is a template, which may contain placeholders like `${extension:device}`.
+/// A placeholder can have multiple alternatives, separated by `|`, using the first one which
+/// is present (e.g. `${header:device|extension:device|'unknown'}`). The alternatives are:
+///
+/// * `id`, `source`, `type`, `subject`, `time`: attributes of the cloud event
+/// * `extension:<name>`: an extension of the cloud event
+/// * `header:<name>`: a header of the message (e.g. a Kafka header)
+/// * `topic`, `topic:<n>`: the topic, or the n-th (zero based) segment of the topic
+/// * `capture:<name>`: a value captured by the [`Pattern`] of the mapper
+/// * `'<value>'`: a literal value
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression(Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Vec<Selector>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Selector {
+    Id,
+    Source,
+    Type,
+    Subject,
+    Time,
+    Topic,
+    TopicSegment(usize),
+    Extension(String),
+    Header(String),
+    Capture(String),
+    Literal(String),
+}
+
+impl Expression {
+    /// Evaluate the expression, returns `None` if a placeholder has no value.
+    pub fn eval(&self, event: &cloudevents::Event, context: &Context) -> Option<String> {
+        self.eval_with(event, context, &HashMap::new())
+    }
+
+    /// Evaluate the expression, using values captured by a [`Pattern`].
+    pub fn eval_with(
+        &self,
+        event: &cloudevents::Event,
+        context: &Context,
+        captures: &HashMap<String, String>,
+    ) -> Option<String> {
+        let mut result = String::new();
+        for part in &self.0 {
+            match part {
+                Part::Literal(value) => result.push_str(value),
+                Part::Placeholder(selectors) => result.push_str(
+                    &selectors
+                        .iter()
+                        .find_map(|selector| selector.eval(event, context, captures))?,
+                ),
+            }
+        }
+        Some(result)
+    }
+}
+
+impl Selector {
+    fn eval(
+        &self,
+        event: &cloudevents::Event,
+        context: &Context,
+        captures: &HashMap<String, String>,
+    ) -> Option<String> {
+        match self {
+            Self::Id => Some(event.id().to_string()),
+            Self::Source => Some(event.source().to_string()),
+            Self::Type => Some(event.ty().to_string()),
+            Self::Subject => event.subject().map(ToString::to_string),
+            Self::Time => event.time().map(|time| time.to_rfc3339()),
+            Self::Topic => Some(context.topic.clone()).filter(|topic| !topic.is_empty()),
+            Self::TopicSegment(n) => context
+                .topic
+                .split('/')
+                .nth(*n)
+                .filter(|segment| !segment.is_empty())
+                .map(ToString::to_string),
+            Self::Extension(name) => event.extension(name).map(ToString::to_string),
+            Self::Header(name) => context.headers.get(name).cloned(),
+            Self::Capture(name) => captures.get(name).cloned(),
+            Self::Literal(value) => Some(value.clone()),
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(value) = s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+            return Ok(Self::Literal(value.to_string()));
+        }
+
+        Ok(match s.split_once(':') {
+            None => match s {
+                "id" => Self::Id,
+                "source" => Self::Source,
+                "type" => Self::Type,
+                "subject" => Self::Subject,
+                "time" => Self::Time,
+                "topic" => Self::Topic,
+                _ => bail!("Unknown selector: {s}"),
+            },
+            Some(("extension", name)) => Self::Extension(name.to_string()),
+            Some(("header", name)) => Self::Header(name.to_lowercase()),
+            Some(("capture", name)) => Self::Capture(name.to_string()),
+            Some(("topic", n)) => Self::TopicSegment(
+                n.parse()
+                    .map_err(|err| anyhow!("Invalid topic segment '{n}': {err}"))?,
+            ),
+            Some(_) => bail!("Unknown selector: {s}"),
+        })
+    }
+}
+
+impl FromStr for Expression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated placeholder: {}", &rest[start..]))?;
+            let selectors = rest[start + 2..start + end]
+                .split('|')
+                .map(Selector::from_str)
+                .collect::<Result<_, _>>()?;
+            parts.push(Part::Placeholder(selectors));
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self(parts))
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{EventBuilder, EventBuilderV10};
+
+    fn event() -> cloudevents::Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("https://example.com")
+            .ty("com.example.telemetry")
+            .extension("device", "device1")
+            .build()
+            .unwrap()
+    }
+
+    fn context() -> Context {
+        Context {
+            topic: "tenants/app1/devices".to_string(),
+            headers: [("x-channel".to_string(), "state".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn eval(expression: &str) -> Option<String> {
+        expression
+            .parse::<Expression>()
+            .unwrap()
+            .eval(&event(), &context())
+    }
+
+    #[test]
+    fn test_expression() {
+        assert_eq!(eval("static").as_deref(), Some("static"));
+        assert_eq!(eval("${extension:device}").as_deref(), Some("device1"));
+        assert_eq!(
+            eval("${topic:1}/${extension:device}").as_deref(),
+            Some("app1/device1")
+        );
+        assert_eq!(
+            eval("${extension:channel|header:X-Channel}").as_deref(),
+            Some("state")
+        );
+        assert_eq!(eval("${subject|'default'}").as_deref(), Some("default"));
+        assert_eq!(eval("${subject}"), None);
+    }
+
+    #[test]
+    fn test_invalid_expression() {
+        assert!("${foo}".parse::<Expression>().is_err());
+        assert!("${topic:a}".parse::<Expression>().is_err());
+        assert!("${id".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn test_expression_mapper() {
+        let mapper = ExpressionMapper {
+            event_type: Some("com.example.telemetry".to_string()),
+            application: "${topic:1}".parse().unwrap(),
+            device: "${extension:device}".parse().unwrap(),
+            channel: "${header:x-channel}".parse().unwrap(),
+            id: None,
+            timestamp: Some("1672531200000".parse().unwrap()),
+            pattern: None,
+        };
+
+        let meta = mapper.map(&event(), &context()).unwrap().unwrap();
+        assert_eq!(meta.id, "1");
+        assert_eq!(meta.application, "app1");
+        assert_eq!(meta.device, "device1");
+        assert_eq!(meta.channel, "state");
+        assert_eq!(
+            meta.timestamp,
+            Utc.timestamp_millis_opt(1672531200000).unwrap()
+        );
+
+        let other = ExpressionMapper {
+            event_type: Some("com.example.other".to_string()),
+            ..mapper
+        };
+        assert!(other.map(&event(), &context()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_template() {
+        let pattern = Pattern::try_from(PatternConfig {
+            input: None,
+            regex: None,
+            template: Some("tenants/{application}/{thing}".to_string()),
+        })
+        .unwrap();
+
+        let captures = pattern.captures(&event(), &context()).unwrap();
+        assert_eq!(captures["application"], "app1");
+        assert_eq!(captures["thing"], "devices");
+
+        let other = Context {
+            topic: "tenants/app1/devices/more".to_string(),
+            ..context()
+        };
+        assert!(pattern.captures(&event(), &other).is_none());
+
+        assert!(template_to_regex("things/{thing").is_err());
+        assert!(template_to_regex("things/{}").is_err());
+    }
+
+    #[test]
+    fn test_pattern_mapper() {
+        let mapper = ExpressionMapper {
+            event_type: None,
+            application: "${capture:application}".parse().unwrap(),
+            device: "${capture:device}".parse().unwrap(),
+            channel: "${capture:channel|'state'}".parse().unwrap(),
+            id: None,
+            timestamp: None,
+            pattern: Some(
+                Pattern::try_from(PatternConfig {
+                    input: Some("${source}".parse().unwrap()),
+                    regex: Some(
+                        r"^https://(?P<application>[^.]+)\.com/(?P<device>\w+)$".to_string(),
+                    ),
+                    template: None,
+                })
+                .unwrap(),
+            ),
+        };
+
+        let matching = EventBuilderV10::new()
+            .id("1")
+            .source("https://example.com/device1")
+            .ty("com.example.telemetry")
+            .build()
+            .unwrap();
+
+        let meta = mapper.map(&matching, &context()).unwrap().unwrap();
+        assert_eq!(meta.application, "example");
+        assert_eq!(meta.device, "device1");
+        assert_eq!(meta.channel, "state");
+
+        // not matching the pattern
+        assert!(mapper.map(&event(), &context()).unwrap().is_none());
+    }
+}
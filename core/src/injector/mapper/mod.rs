@@ -1,2 +1,4 @@
 pub mod metadata;
 pub mod payload;
+pub mod protobuf;
+pub mod script;
@@ -1,9 +1,9 @@
-use crate::injector::metadata::Meta;
+use crate::injector::{metadata::Meta, protobuf::Protobuf, script::Script};
 use crate::processor::Message;
 use anyhow::{anyhow, bail};
 use cloudevents::Data;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::collections::BTreeMap;
 use url::Url;
 
@@ -26,6 +26,10 @@ pub enum PayloadMapper {
     },
     #[serde(alias = "simpleState")]
     SimpleState,
+    /// Decodes protobuf payloads, taking the fields of the message as reported state properties.
+    Protobuf(Protobuf),
+    /// Runs JavaScript code, mapping the event to a message.
+    Script(Script),
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -36,6 +40,211 @@ pub struct SimpleState {
     pub state: BTreeMap<String, Value>,
 }
 
+/// Store payloads, which can't be mapped, as an opaque reported feature.
+///
+/// This keeps the data of undecodable (e.g. encrypted) payloads, until a matching mapper is
+/// available.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct RawPayload {
+    /// The name of the reported feature to store the payload in.
+    #[serde(default = "default::feature")]
+    pub feature: String,
+    /// The maximum size of a payload, larger payloads get dropped.
+    #[serde(default = "default::max_size")]
+    pub max_size: usize,
+}
+
+mod default {
+    pub fn feature() -> String {
+        "$raw".to_string()
+    }
+
+    pub const fn max_size() -> usize {
+        16 * 1024
+    }
+}
+
+impl Default for RawPayload {
+    fn default() -> Self {
+        Self {
+            feature: default::feature(),
+            max_size: default::max_size(),
+        }
+    }
+}
+
+impl RawPayload {
+    /// Map the payload into a partial update of the raw payload feature.
+    ///
+    /// The value contains the base64 encoded payload, its content type, and size.
+    pub fn map(
+        &self,
+        (content_type, schema, data): (Option<String>, Option<Url>, Option<Data>),
+    ) -> anyhow::Result<Message> {
+        let data = match data {
+            Some(Data::Binary(blob)) => blob,
+            Some(Data::String(string)) => string.into_bytes(),
+            Some(Data::Json(value)) => serde_json::to_vec(&value)?,
+            None => bail!("Missing payload"),
+        };
+
+        if data.len() > self.max_size {
+            bail!(
+                "Payload exceeds maximum raw payload size: {} > {}",
+                data.len(),
+                self.max_size
+            );
+        }
+
+        let mut value = json!({
+            "data": base64::encode(&data),
+            "size": data.len(),
+        });
+        if let Some(content_type) = content_type {
+            value["contentType"] = content_type.into();
+        }
+        if let Some(schema) = schema {
+            value["schema"] = schema.to_string().into();
+        }
+
+        Ok(Message::report_state(true)
+            .state(&self.feature, value)
+            .into())
+    }
+}
+
+/// Decode payloads, before they are passed on to the [`PayloadMapper`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PayloadDecoder {
+    /// Pass on payloads as they are.
+    #[default]
+    None,
+    /// Decode CBOR payloads (`application/cbor`) into JSON, and SenML CBOR payloads
+    /// (`application/senml+cbor`) into a JSON object, mapping the name of each record to its value.
+    Cbor,
+}
+
+impl PayloadDecoder {
+    pub fn decode(
+        &self,
+        (content_type, schema, data): (Option<String>, Option<Url>, Option<Data>),
+    ) -> anyhow::Result<(Option<String>, Option<Url>, Option<Data>)> {
+        let (Self::Cbor, Some(Data::Binary(blob))) = (self, &data) else {
+            return Ok((content_type, schema, data));
+        };
+
+        let value = match content_type.as_deref() {
+            Some("application/cbor") => cbor_to_json(ciborium::de::from_reader(blob.as_slice())?)?,
+            Some("application/senml+cbor") => {
+                senml_to_state(cbor_to_json(ciborium::de::from_reader(blob.as_slice())?)?)?
+            }
+            _ => return Ok((content_type, schema, data)),
+        };
+
+        Ok((
+            Some("application/json".to_string()),
+            schema,
+            Some(Data::Json(value)),
+        ))
+    }
+}
+
+/// Convert a CBOR value into JSON.
+///
+/// Byte strings are encoded using base64, integer keys converted to strings.
+fn cbor_to_json(value: ciborium::value::Value) -> anyhow::Result<Value> {
+    use ciborium::value::Value as Cbor;
+
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(value) => Value::Bool(value),
+        Cbor::Integer(value) => {
+            let value = i128::from(value);
+            match (i64::try_from(value), u64::try_from(value)) {
+                (Ok(value), _) => value.into(),
+                (_, Ok(value)) => value.into(),
+                _ => bail!("Integer out of range: {value}"),
+            }
+        }
+        Cbor::Float(value) => serde_json::Number::from_f64(value)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        Cbor::Text(value) => Value::String(value),
+        Cbor::Bytes(value) => Value::String(base64::encode(value)),
+        Cbor::Tag(_, value) => cbor_to_json(*value)?,
+        Cbor::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(cbor_to_json)
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        Cbor::Map(entries) => Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Cbor::Text(key) => key,
+                        Cbor::Integer(key) => i128::from(key).to_string(),
+                        key => bail!("Unsupported map key: {key:?}"),
+                    };
+                    Ok((key, cbor_to_json(value)?))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        value => bail!("Unsupported CBOR value: {value:?}"),
+    })
+}
+
+/// Resolve SenML records into a JSON object, mapping the full name of each record to its value.
+///
+/// Accepts the integer labels of the CBOR representation, as well as the JSON labels.
+fn senml_to_state(value: Value) -> anyhow::Result<Value> {
+    let Value::Array(records) = value else {
+        bail!("Wrong root level value for SenML, expected: Array");
+    };
+
+    let label = |record: &serde_json::Map<String, Value>, name: &str, key: i8| {
+        record
+            .get(name)
+            .or_else(|| record.get(&key.to_string()))
+            .cloned()
+    };
+
+    let mut base_name = String::new();
+    let mut state = serde_json::Map::new();
+
+    for record in records {
+        let Value::Object(record) = record else {
+            bail!("Wrong SenML record, expected: Object");
+        };
+
+        if let Some(Value::String(name)) = label(&record, "bn", -2) {
+            base_name = name;
+        }
+
+        let name = match label(&record, "n", 0) {
+            Some(Value::String(name)) => format!("{base_name}{name}"),
+            _ => base_name.clone(),
+        };
+
+        let value = label(&record, "v", 2)
+            .or_else(|| label(&record, "vs", 3))
+            .or_else(|| label(&record, "vb", 4))
+            .or_else(|| label(&record, "vd", 8))
+            .or_else(|| label(&record, "s", 5));
+
+        if let Some(value) = value {
+            if name.is_empty() {
+                bail!("SenML record without a name");
+            }
+            state.insert(name, value);
+        }
+    }
+
+    Ok(Value::Object(state))
+}
+
 impl Default for PayloadMapper {
     fn default() -> Self {
         Self::Raw
@@ -43,7 +252,7 @@ impl Default for PayloadMapper {
 }
 
 impl PayloadMapper {
-    pub fn map(
+    pub async fn map(
         &self,
         meta: &Meta,
         data: (Option<String>, Option<Url>, Option<Data>),
@@ -55,6 +264,8 @@ impl PayloadMapper {
                 add_timestamp,
             } => self.map_simple(&meta, data, *partial, *add_timestamp),
             Self::SimpleState => self.map_simple_state(data),
+            Self::Protobuf(protobuf) => self.map_protobuf(meta, data, protobuf),
+            Self::Script(script) => script.map(meta, data).await,
         }
     }
 
@@ -110,6 +321,21 @@ impl PayloadMapper {
         }
     }
 
+    fn map_protobuf(
+        &self,
+        meta: &Meta,
+        (content_type, schema, data): (Option<String>, Option<Url>, Option<Data>),
+        protobuf: &Protobuf,
+    ) -> anyhow::Result<Message> {
+        match (content_type.as_deref(), schema, data) {
+            (_, _, Some(Data::Binary(blob))) => Ok(Message::ReportState {
+                state: protobuf.decode(meta, &blob)?,
+                partial: protobuf.partial,
+            }),
+            (content_type, schema, data) => self.otherwise(content_type, schema, data),
+        }
+    }
+
     fn otherwise(
         &self,
         content_type: Option<&str>,
@@ -136,3 +362,125 @@ where
         Data::Binary(blob) => serde_json::from_slice(&blob),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raw_payload() {
+        let raw = RawPayload::default();
+
+        let message = raw
+            .map((
+                Some("application/octet-stream".to_string()),
+                None,
+                Some(Data::Binary(vec![1, 2, 3])),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            message,
+            Message::from(Message::report_state(true).state(
+                "$raw",
+                json!({
+                    "contentType": "application/octet-stream",
+                    "data": "AQID",
+                    "size": 3,
+                })
+            ))
+        );
+    }
+
+    fn cbor(value: ciborium::value::Value) -> Vec<u8> {
+        let mut data = vec![];
+        ciborium::ser::into_writer(&value, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_decode_cbor() {
+        use ciborium::value::Value as Cbor;
+
+        let data = cbor(Cbor::Map(vec![
+            (Cbor::Text("temperature".into()), Cbor::Float(21.5)),
+            (Cbor::Text("on".into()), Cbor::Bool(true)),
+            (Cbor::Integer(1.into()), Cbor::Bytes(vec![1, 2, 3])),
+        ]));
+
+        let (content_type, _, data) = PayloadDecoder::Cbor
+            .decode((
+                Some("application/cbor".to_string()),
+                None,
+                Some(Data::Binary(data)),
+            ))
+            .unwrap();
+
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            data,
+            Some(Data::Json(json!({
+                "temperature": 21.5,
+                "on": true,
+                "1": "AQID",
+            })))
+        );
+    }
+
+    #[test]
+    fn test_decode_senml() {
+        use ciborium::value::Value as Cbor;
+
+        let data = cbor(Cbor::Array(vec![
+            Cbor::Map(vec![
+                (Cbor::Integer((-2).into()), Cbor::Text("sensor/".into())),
+                (Cbor::Integer(0.into()), Cbor::Text("temperature".into())),
+                (Cbor::Integer(2.into()), Cbor::Float(21.5)),
+            ]),
+            Cbor::Map(vec![
+                (Cbor::Integer(0.into()), Cbor::Text("door".into())),
+                (Cbor::Integer(4.into()), Cbor::Bool(false)),
+            ]),
+        ]));
+
+        let (_, _, data) = PayloadDecoder::Cbor
+            .decode((
+                Some("application/senml+cbor".to_string()),
+                None,
+                Some(Data::Binary(data)),
+            ))
+            .unwrap();
+
+        assert_eq!(
+            data,
+            Some(Data::Json(json!({
+                "sensor/temperature": 21.5,
+                "sensor/door": false,
+            })))
+        );
+    }
+
+    #[test]
+    fn test_decode_passthrough() {
+        let data = (
+            Some("application/json".to_string()),
+            None,
+            Some(Data::Json(json!({"temperature": 21}))),
+        );
+
+        assert_eq!(PayloadDecoder::Cbor.decode(data.clone()).unwrap(), data);
+        assert_eq!(PayloadDecoder::None.decode(data.clone()).unwrap(), data);
+    }
+
+    #[test]
+    fn test_raw_payload_too_large() {
+        let raw = RawPayload {
+            max_size: 2,
+            ..Default::default()
+        };
+
+        assert!(raw
+            .map((None, None, Some(Data::Binary(vec![1, 2, 3]))))
+            .is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Decoding protobuf payloads, using message descriptors loaded at runtime.
+
+use crate::injector::metadata::Meta;
+use anyhow::{anyhow, bail};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ProtobufConfig {
+    /// The file descriptor set, containing the message types (e.g. created using
+    /// `protoc --include_imports --descriptor_set_out`).
+    pub descriptor_set: PathBuf,
+    /// The full name of the message type, by channel.
+    #[serde(default)]
+    pub channels: HashMap<String, String>,
+    /// The full name of the message type of channels which are not mapped.
+    #[serde(default)]
+    pub message_type: Option<String>,
+    /// If the data is a partial update.
+    #[serde(default)]
+    pub partial: bool,
+}
+
+/// Decodes protobuf payloads into the reported state, taking the fields of the message as
+/// reported state properties, using the JSON names of the fields.
+///
+/// The descriptor set gets loaded, and the message types resolved, when the configuration is
+/// loaded.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(try_from = "ProtobufConfig")]
+pub struct Protobuf {
+    channels: HashMap<String, MessageDescriptor>,
+    message_type: Option<MessageDescriptor>,
+    pub partial: bool,
+}
+
+impl TryFrom<ProtobufConfig> for Protobuf {
+    type Error = anyhow::Error;
+
+    fn try_from(config: ProtobufConfig) -> Result<Self, Self::Error> {
+        let descriptor_set = std::fs::read(&config.descriptor_set).map_err(|err| {
+            anyhow!(
+                "Failed to read descriptor set '{}': {err}",
+                config.descriptor_set.display()
+            )
+        })?;
+        let pool = DescriptorPool::decode(descriptor_set.as_slice())?;
+
+        Self::new(
+            &pool,
+            config.channels,
+            config.message_type.as_deref(),
+            config.partial,
+        )
+    }
+}
+
+impl Protobuf {
+    pub fn new(
+        pool: &DescriptorPool,
+        channels: HashMap<String, String>,
+        message_type: Option<&str>,
+        partial: bool,
+    ) -> anyhow::Result<Self> {
+        let resolve = |name: &str| {
+            pool.get_message_by_name(name)
+                .ok_or_else(|| anyhow!("Unknown message type: {name}"))
+        };
+
+        Ok(Self {
+            channels: channels
+                .into_iter()
+                .map(|(channel, name)| Ok((channel, resolve(&name)?)))
+                .collect::<anyhow::Result<_>>()?,
+            message_type: message_type.map(resolve).transpose()?,
+            partial,
+        })
+    }
+
+    /// Decode a payload into the reported state.
+    pub fn decode(&self, meta: &Meta, payload: &[u8]) -> anyhow::Result<BTreeMap<String, Value>> {
+        let descriptor = self
+            .channels
+            .get(&meta.channel)
+            .or(self.message_type.as_ref())
+            .ok_or_else(|| anyhow!("No message type for channel: {}", meta.channel))?;
+
+        let message = DynamicMessage::decode(descriptor.clone(), payload)?;
+
+        // report fields with default values too, as they are part of the state
+        let options = SerializeOptions::new().skip_default_fields(false);
+
+        match message.serialize_with_options(serde_json::value::Serializer, &options)? {
+            Value::Object(props) => Ok(props.into_iter().collect()),
+            _ => bail!("Wrong root level value of message, expected: Object"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use prost::Message;
+    use prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+    use serde_json::json;
+
+    fn pool() -> DescriptorPool {
+        let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        };
+
+        DescriptorPool::from_file_descriptor_set(FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("sensor.proto".to_string()),
+                package: Some("example".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Sensor".to_string()),
+                    field: vec![
+                        field("temperature", 1, Type::Double),
+                        field("door", 2, Type::Bool),
+                    ],
+                    ..Default::default()
+                }],
+                syntax: Some("proto3".to_string()),
+                ..Default::default()
+            }],
+        })
+        .unwrap()
+    }
+
+    fn meta(channel: &str) -> Meta {
+        Meta {
+            id: "1".to_string(),
+            timestamp: Utc::now(),
+            application: "app".to_string(),
+            device: "device".to_string(),
+            channel: channel.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let pool = pool();
+        let descriptor = pool.get_message_by_name("example.Sensor").unwrap();
+
+        let mut message = DynamicMessage::new(descriptor);
+        message.set_field_by_name("temperature", prost_reflect::Value::F64(21.5));
+        message.set_field_by_name("door", prost_reflect::Value::Bool(true));
+        let payload = message.encode_to_vec();
+
+        let protobuf = Protobuf::new(
+            &pool,
+            [("sensor".to_string(), "example.Sensor".to_string())].into(),
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            protobuf.decode(&meta("sensor"), &payload).unwrap(),
+            [
+                ("temperature".to_string(), json!(21.5)),
+                ("door".to_string(), json!(true)),
+            ]
+            .into()
+        );
+        // channel without a message type
+        assert!(protobuf.decode(&meta("other"), &payload).is_err());
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        assert!(
+            Protobuf::new(&pool(), Default::default(), Some("example.Unknown"), false).is_err()
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Mapping payloads using JavaScript code.
+
+use crate::{
+    injector::metadata::Meta,
+    machine::deno::{DenoOptions, Execution, Json},
+    processor::Message,
+};
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use cloudevents::Data;
+use serde_json::Value;
+use std::time::Duration;
+use url::Url;
+
+/// Maps payloads by running JavaScript code.
+///
+/// The code receives the event as `context.event`, and sets the message to publish as
+/// `context.message`.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Script {
+    /// The JavaScript code.
+    pub code: String,
+    /// The maximum time the code may run.
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Input {
+    event: Event,
+}
+
+/// The event, as seen by the script.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Event {
+    id: String,
+    timestamp: DateTime<Utc>,
+    application: String,
+    device: String,
+    channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_schema: Option<String>,
+    /// The data, JSON if possible, a string for text data, or an array of bytes.
+    data: Value,
+}
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct Output {
+    #[serde(default)]
+    message: Option<Message>,
+}
+
+impl Script {
+    pub async fn map(
+        &self,
+        meta: &Meta,
+        (content_type, schema, data): (Option<String>, Option<Url>, Option<Data>),
+    ) -> anyhow::Result<Message> {
+        let data = match data {
+            Some(Data::Json(value)) => value,
+            Some(Data::String(string)) => Value::String(string),
+            Some(Data::Binary(blob)) if is_json(content_type.as_deref()) => {
+                serde_json::from_slice(&blob)?
+            }
+            Some(Data::Binary(blob)) => blob.into(),
+            None => Value::Null,
+        };
+
+        let input = Input {
+            event: Event {
+                id: meta.id.clone(),
+                timestamp: meta.timestamp,
+                application: meta.application.clone(),
+                device: meta.device.clone(),
+                channel: meta.channel.clone(),
+                content_type,
+                data_schema: schema.map(|schema| schema.to_string()),
+                data,
+            },
+        };
+
+        let exec = Execution::new(
+            "payload-mapper",
+            &self.code,
+            DenoOptions {
+                deadline: tokio::time::Instant::now() + self.timeout,
+            },
+        );
+
+        let result = exec.run::<_, Json<Output>, ()>(Json(input)).await?;
+
+        result
+            .output
+            .0
+            .message
+            .ok_or_else(|| anyhow!("Script did not set a message"))
+    }
+}
+
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type.map_or(false, |content_type| {
+        matches!(content_type, "application/json" | "text/json") || content_type.ends_with("+json")
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_script() {
+        let script = Script {
+            code: r#"
+const [temperature, humidity] = context.event.data.split(";").map(Number);
+context.message = {
+    reportState: {
+        state: { temperature, humidity, channel: context.event.channel },
+        partial: true,
+    }
+};
+"#
+            .to_string(),
+            timeout: Duration::from_secs(1),
+        };
+
+        let meta = Meta {
+            id: "1".to_string(),
+            timestamp: Utc::now(),
+            application: "app".to_string(),
+            device: "device".to_string(),
+            channel: "climate".to_string(),
+        };
+
+        let message = script
+            .map(
+                &meta,
+                (
+                    Some("text/plain".to_string()),
+                    None,
+                    Some(Data::String("21.5;40.5".to_string())),
+                ),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            message,
+            Message::from(
+                Message::report_state(true)
+                    .state("temperature", json!(21.5))
+                    .state("humidity", json!(40.5))
+                    .state("channel", json!("climate"))
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_script_without_message() {
+        let script = Script {
+            code: "// nothing".to_string(),
+            timeout: Duration::from_secs(1),
+        };
+
+        let meta = Meta {
+            id: "1".to_string(),
+            timestamp: Utc::now(),
+            application: "app".to_string(),
+            device: "device".to_string(),
+            channel: "climate".to_string(),
+        };
+
+        assert!(script.map(&meta, (None, None, None)).await.is_err());
+    }
+}
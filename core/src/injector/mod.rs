@@ -1,18 +1,28 @@
 //! Injectors allow to inject events from an external system into the internal Kafka topic
 
+pub mod dead_letter;
+pub mod filter;
+pub mod http;
+pub mod kafka;
 mod mapper;
 pub mod mqtt;
+pub mod ws;
 
 pub use mapper::*;
 
 use crate::{
+    config::check::{Check, Checker},
     injector::{
+        dead_letter::DeadLetterSink,
+        filter::Filter,
         metadata::MetadataMapper,
         mqtt::{SinkTarget, Target},
-        payload::PayloadMapper,
+        payload::{PayloadDecoder, PayloadMapper, RawPayload},
     },
     processor::sink::Sink,
 };
+use anyhow::bail;
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
@@ -22,19 +32,133 @@ pub struct Config {
     pub disabled: bool,
     #[serde(default)]
     pub metadata_mapper: MetadataMapper,
+    /// Filter events, before their payload is mapped.
+    #[serde(default)]
+    pub filter: Filter,
+    /// Decode payloads, before they are passed to the payload mapper.
+    #[serde(default)]
+    pub payload_decoder: PayloadDecoder,
+    #[serde(default)]
+    pub payload_mapper: PayloadMapper,
+    /// Store payloads the payload mapper fails on as opaque reported feature, instead of
+    /// dropping them.
+    #[serde(default)]
+    pub raw_payload: Option<RawPayload>,
+    /// Store events which could not be injected, instead of only logging them.
+    #[serde(default)]
+    pub dead_letter: Option<dead_letter::Config>,
+    /// The name of this injector instance, recorded in the provenance of injected events.
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// The source, using the mappers of this configuration.
+    #[serde(default)]
+    pub source: Option<SourceConfig>,
+    /// Additional sources by name, each with its own mappers.
+    #[serde(default)]
+    pub sources: BTreeMap<String, Source>,
+}
+
+/// The name of the source configured by [`Config::source`], used for metrics.
+pub const DEFAULT_SOURCE: &str = "default";
+
+/// A named source of the injector.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub struct Source {
+    #[serde(default)]
+    pub metadata_mapper: MetadataMapper,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Decode payloads, before they are passed to the payload mapper.
+    #[serde(default)]
+    pub payload_decoder: PayloadDecoder,
     #[serde(default)]
     pub payload_mapper: PayloadMapper,
+    #[serde(default)]
+    pub raw_payload: Option<RawPayload>,
+    #[serde(default)]
+    pub dead_letter: Option<dead_letter::Config>,
     pub source: SourceConfig,
 }
 
+impl Check for Source {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("filter", &self.filter)
+            .field("source", &self.source);
+        if let Some(dead_letter) = &self.dead_letter {
+            checker.field("dead_letter", dead_letter);
+        }
+    }
+}
+
 impl Config {
+    /// Run all sources concurrently, until the first one exits.
     pub async fn run<S: Sink>(self, sink: S) -> anyhow::Result<()> {
-        let target = SinkTarget {
-            sink,
-            metadata_mapper: self.metadata_mapper,
-            payload_mapper: self.payload_mapper,
-        };
-        self.source.run(target).await
+        let default = self.source.map(|source| {
+            (
+                DEFAULT_SOURCE.to_string(),
+                Source {
+                    metadata_mapper: self.metadata_mapper,
+                    filter: self.filter,
+                    payload_decoder: self.payload_decoder,
+                    payload_mapper: self.payload_mapper,
+                    raw_payload: self.raw_payload,
+                    dead_letter: self.dead_letter,
+                    source,
+                },
+            )
+        });
+
+        let mut sources = vec![];
+        for (name, source) in default.into_iter().chain(self.sources) {
+            log::info!("Running injector source: {name}");
+            let target = SinkTarget {
+                sink: sink.clone(),
+                metadata_mapper: source.metadata_mapper,
+                filter: source.filter,
+                payload_decoder: source.payload_decoder,
+                payload_mapper: source.payload_mapper,
+                raw_payload: source.raw_payload,
+                dead_letter: source
+                    .dead_letter
+                    .map(DeadLetterSink::from_config)
+                    .transpose()?,
+                instance: self.instance.clone(),
+                source: name,
+            };
+            sources.push(Box::pin(source.source.run(target)));
+        }
+
+        if sources.is_empty() {
+            bail!("No injector source configured");
+        }
+
+        let (result, _, _) = futures::future::select_all(sources).await;
+        result
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if self.disabled {
+            return;
+        }
+
+        if let Some(source) = &self.source {
+            checker
+                .field("filter", &self.filter)
+                .field("source", source);
+            if let Some(dead_letter) = &self.dead_letter {
+                checker.field("dead_letter", dead_letter);
+            }
+        } else if self.sources.is_empty() {
+            checker.issue("source", "either 'source' or 'sources' must be configured");
+        }
+
+        for (name, source) in &self.sources {
+            checker.field(&format!("sources.{name}"), source);
+        }
     }
 }
 
@@ -42,12 +166,32 @@ impl Config {
 #[serde(rename_all = "lowercase")]
 pub enum SourceConfig {
     Mqtt(mqtt::Config),
+    Kafka(kafka::Config),
+    Http(http::Config),
+    Ws(ws::Config),
+}
+
+impl Check for SourceConfig {
+    fn check(&self, checker: &mut Checker) {
+        match self {
+            Self::Mqtt(mqtt) => checker.field("mqtt", mqtt),
+            Self::Kafka(kafka) => checker.field("kafka", kafka),
+            Self::Http(http) => checker.field("http", http),
+            Self::Ws(ws) => checker.field("ws", ws),
+        };
+    }
 }
 
 impl SourceConfig {
-    pub async fn run<T: Target>(self, target: T) -> anyhow::Result<()> {
+    pub async fn run<T>(self, target: T) -> anyhow::Result<()>
+    where
+        T: Target + Send + Sync + 'static,
+    {
         match self {
             Self::Mqtt(mqtt) => mqtt.run(target).await,
+            Self::Kafka(kafka) => kafka.run(target).await,
+            Self::Http(http) => http.run(target).await,
+            Self::Ws(ws) => ws.run(target).await,
         }
     }
 }
@@ -1,16 +1,20 @@
 use crate::{
+    config::check::{Check, Checker},
     injector::{
-        metadata::{Meta, MetadataMapper},
-        payload::PayloadMapper,
+        dead_letter::DeadLetterSink,
+        filter::{Filter, Outcome},
+        metadata::{Context, Meta, MetadataMapper},
+        payload::{PayloadDecoder, PayloadMapper, RawPayload},
     },
     mqtt::MqttClient,
-    processor::{sink::Sink, Event},
+    processor::{sink::Sink, Event, Provenance},
 };
 use anyhow::bail;
 use async_trait::async_trait;
 use chrono::Utc;
+use cloudevents::AttributesReader;
 use lazy_static::lazy_static;
-use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
 use rumqttc::{AsyncClient, EventLoop, Incoming, Publish, QoS, SubscribeReasonCode};
 use std::time::Duration;
 use tracing::instrument;
@@ -19,11 +23,23 @@ lazy_static! {
     static ref EVENTS: IntCounterVec = register_int_counter_vec!(
         "injector_events",
         "Number of events processed by injector",
-        &["result"]
+        &["source", "result"]
+    )
+    .unwrap();
+    static ref LAG: HistogramVec =
+        register_histogram_vec!("injector_lag", "Lag in ms for the injector", &["source"]).unwrap();
+    static ref FILTERED: IntCounterVec = register_int_counter_vec!(
+        "injector_filtered_events",
+        "Number of events dropped or routed by the filter of the injector",
+        &["source", "result", "reason"]
+    )
+    .unwrap();
+    static ref RAW_PAYLOADS: IntCounterVec = register_int_counter_vec!(
+        "injector_raw_payloads",
+        "Number of payloads stored as raw payload",
+        &["source"]
     )
     .unwrap();
-    static ref LAG: Histogram =
-        register_histogram!("injector_lag", "Lag in ms for the injector").unwrap();
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -45,6 +61,12 @@ mod default {
     }
 }
 
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker.mqtt(&self.client).not_empty("topic", &self.topic);
+    }
+}
+
 impl Config {
     pub async fn run<T: Target>(self, target: T) -> anyhow::Result<()> {
         Injector::new(self, target)?.run().await
@@ -54,7 +76,7 @@ impl Config {
 #[async_trait]
 pub trait Target {
     /// An event was received from the injector and needs to be processed.
-    async fn event(&self, event: cloudevents::Event) -> anyhow::Result<()>;
+    async fn event(&self, event: cloudevents::Event, context: Context) -> anyhow::Result<()>;
 }
 
 /// An injector target based on a [`Sink`].
@@ -62,21 +84,74 @@ pub struct SinkTarget<S: Sink> {
     pub sink: S,
 
     pub metadata_mapper: MetadataMapper,
+    pub filter: Filter,
+    pub payload_decoder: PayloadDecoder,
     pub payload_mapper: PayloadMapper,
+    /// Store payloads the mapper fails on as raw payload, `None` if disabled.
+    pub raw_payload: Option<RawPayload>,
+    /// Store events which could not be injected, `None` if disabled.
+    pub dead_letter: Option<DeadLetterSink>,
+    /// The name of the injector instance, recorded in the provenance of events.
+    pub instance: Option<String>,
+    /// The name of the source, used for metrics.
+    pub source: String,
 }
 
 impl<S: Sink> SinkTarget<S> {
-    fn build_event(&self, mut event: cloudevents::Event) -> anyhow::Result<Option<Event>> {
-        let meta = match self.metadata_mapper.map(&event)? {
+    async fn build_event(
+        &self,
+        mut event: cloudevents::Event,
+        context: Context,
+    ) -> anyhow::Result<Option<Event>> {
+        let mut meta = match self.metadata_mapper.map(&event, &context)? {
             Some(meta) => meta,
             None => {
                 return Ok(None);
             }
         };
 
-        LAG.observe((Utc::now() - meta.timestamp).num_milliseconds() as f64);
+        match self.filter.filter(&meta, event.datacontenttype()) {
+            Outcome::Accept => {}
+            Outcome::Route { name, application } => {
+                FILTERED
+                    .with_label_values(&[&self.source, "routed", name])
+                    .inc();
+                meta.application = application.to_string();
+            }
+            Outcome::Drop(attribute) => {
+                FILTERED
+                    .with_label_values(&[&self.source, "dropped", attribute])
+                    .inc();
+                return Ok(None);
+            }
+        }
 
-        let message = self.payload_mapper.map(&meta, event.take_data())?;
+        let received = Utc::now();
+        LAG.with_label_values(&[&self.source])
+            .observe((received - meta.timestamp).num_milliseconds() as f64);
+
+        let provenance = Provenance {
+            source: Some(event.source().to_string()),
+            message_id: Some(event.id().to_string()),
+            received: Some(received),
+            injector: self.instance.clone(),
+        };
+
+        let data = event.take_data();
+        let message = match self.payload_decoder.decode(data.clone()) {
+            Ok(decoded) => self.payload_mapper.map(&meta, decoded).await,
+            Err(err) => Err(err),
+        };
+        let message = match (message, &self.raw_payload) {
+            (Ok(message), _) => message,
+            (Err(err), Some(raw_payload)) => {
+                log::debug!("Unable to map payload: {err}, storing as raw payload");
+                let message = raw_payload.map(data)?;
+                RAW_PAYLOADS.with_label_values(&[&self.source]).inc();
+                message
+            }
+            (Err(err), None) => return Err(err),
+        };
 
         let Meta {
             id,
@@ -94,29 +169,37 @@ impl<S: Sink> SinkTarget<S> {
             application,
             thing,
             message,
+            correlation_id: None,
+            provenance: Some(provenance),
         }))
     }
 }
 
 #[async_trait]
 impl<S: Sink> Target for SinkTarget<S> {
-    async fn event(&self, event: cloudevents::Event) -> anyhow::Result<()> {
-        match self.build_event(event) {
+    async fn event(&self, event: cloudevents::Event, context: Context) -> anyhow::Result<()> {
+        // keep the original event, only if we need it
+        let original = self.dead_letter.as_ref().map(|_| event.clone());
+
+        match self.build_event(event, context).await {
             Ok(Some(event)) => {
                 log::debug!("Injecting event: {event:?}");
                 if let Err(err) = self.sink.publish(event).await {
                     log::error!("Failed to inject event: {err}, Exiting loop");
                     bail!("Failed to inject event: {err}")
                 }
-                EVENTS.with_label_values(&["ok"]).inc();
+                EVENTS.with_label_values(&[&self.source, "ok"]).inc();
             }
             Ok(None) => {
                 // got skipped
-                EVENTS.with_label_values(&["skipped"]).inc();
+                EVENTS.with_label_values(&[&self.source, "skipped"]).inc();
             }
             Err(err) => {
-                EVENTS.with_label_values(&["invalid"]).inc();
+                EVENTS.with_label_values(&[&self.source, "invalid"]).inc();
                 log::info!("Unable to parse event: {err}, skipping...");
+                if let (Some(dead_letter), Some(original)) = (&self.dead_letter, original) {
+                    dead_letter.store(&self.source, original, &err).await;
+                }
             }
         }
 
@@ -226,6 +309,11 @@ impl<T: Target> Injector<T> {
         let event: cloudevents::Event = serde_json::from_slice(&publish.payload)?;
         log::debug!("Cloud Event: {event:?}");
 
-        self.target.event(event).await
+        let context = Context {
+            topic: publish.topic.clone(),
+            ..Default::default()
+        };
+
+        self.target.event(event, context).await
     }
 }
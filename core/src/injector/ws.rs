@@ -0,0 +1,190 @@
+//! Receive cloud events from the WebSocket integration of Drogue Cloud.
+//!
+//! The integration sends the events of an application as structured cloud events. The
+//! connection is re-established after it got closed, backing off in case of failures.
+
+use crate::{
+    config::check::{Check, Checker},
+    injector::{metadata::Context, mqtt::Target},
+};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{header::AUTHORIZATION, HeaderValue},
+    Message,
+};
+use tracing::instrument;
+use url::Url;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The URL of the events of the application, e.g.
+    /// `wss://ws-integration.sandbox.drogue.cloud/my-app`.
+    pub url: String,
+    /// The access token, or API key.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The consumer group. Instances sharing the same group split the events among them.
+    #[serde(default)]
+    pub group_id: Option<String>,
+
+    #[serde(with = "humantime_serde", default = "default::initial_reconnect_delay")]
+    pub initial_reconnect_delay: Duration,
+    #[serde(with = "humantime_serde", default = "default::max_reconnect_delay")]
+    pub max_reconnect_delay: Duration,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn initial_reconnect_delay() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub const fn max_reconnect_delay() -> Duration {
+        Duration::from_secs(60)
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        match Url::parse(&self.url) {
+            Ok(url) if !matches!(url.scheme(), "ws" | "wss") => {
+                checker.issue("url", "must be a 'ws' or 'wss' URL");
+            }
+            Ok(_) => {}
+            Err(err) => {
+                checker.issue("url", format!("invalid URL: {err}"));
+            }
+        }
+        if self.max_reconnect_delay < self.initial_reconnect_delay {
+            checker.issue(
+                "max_reconnect_delay",
+                "must not be less than the initial reconnect delay",
+            );
+        }
+    }
+}
+
+impl Config {
+    pub async fn run<T: Target>(self, target: T) -> anyhow::Result<()> {
+        let mut url = Url::parse(&self.url)?;
+        if let Some(group_id) = &self.group_id {
+            url.query_pairs_mut().append_pair("group_id", group_id);
+        }
+
+        let mut delay = self.initial_reconnect_delay;
+
+        loop {
+            let mut request = url.as_str().into_client_request()?;
+            if let Some(token) = &self.token {
+                request.headers_mut().insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("Bearer {token}"))?,
+                );
+            }
+
+            match tokio_tungstenite::connect_async(request).await {
+                Ok((mut stream, _)) => {
+                    log::info!("Connection open: {}", self.url);
+                    delay = self.initial_reconnect_delay;
+
+                    while let Some(msg) = stream.next().await {
+                        let msg = match msg {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                log::warn!("WebSocket error: {err}");
+                                break;
+                            }
+                        };
+
+                        match msg {
+                            Message::Text(_) | Message::Binary(_) => {
+                                if let Err(err) = handle_message(&target, url.path(), msg).await {
+                                    log::warn!("Failed to handle event: {err}");
+                                    let _ = stream.close(None).await;
+                                    log::warn!("Exiting WebSocket injector loop");
+                                    return Ok(());
+                                }
+                            }
+                            Message::Close(frame) => {
+                                log::info!("Connection closed: {frame:?}");
+                            }
+                            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
+                                // pings are answered by the stream
+                            }
+                        }
+                    }
+
+                    // flush any pending messages, like a pong, before re-connecting
+                    let _ = stream.flush().await;
+                }
+                Err(err) => {
+                    log::warn!("Failed to connect: {err}");
+                }
+            }
+
+            log::info!("Re-connecting in {}", humantime::format_duration(delay));
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.max_reconnect_delay);
+        }
+    }
+}
+
+#[instrument(skip_all, err)]
+async fn handle_message<T: Target>(target: &T, topic: &str, msg: Message) -> anyhow::Result<()> {
+    let event = match to_event(msg) {
+        Ok(Some(event)) => event,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            log::info!("Unable to parse message, skipping! Reason: {err}");
+            return Ok(());
+        }
+    };
+
+    let context = Context {
+        topic: topic.to_string(),
+        ..Default::default()
+    };
+
+    target.event(event, context).await
+}
+
+/// Parse a WebSocket message into a cloud event.
+fn to_event(msg: Message) -> anyhow::Result<Option<cloudevents::Event>> {
+    Ok(match msg {
+        Message::Text(text) => Some(serde_json::from_str(&text)?),
+        Message::Binary(data) => Some(serde_json::from_slice(&data)?),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::AttributesReader;
+    use serde_json::json;
+
+    #[test]
+    fn test_to_event() {
+        let event = json!({
+            "specversion": "1.0",
+            "id": "1",
+            "source": "drogue://app/device",
+            "type": "io.drogue.event.v1",
+            "subject": "state",
+            "application": "app",
+            "device": "device",
+            "datacontenttype": "application/json",
+            "data": {"temperature": 21},
+        });
+
+        let event = to_event(Message::Text(event.to_string())).unwrap().unwrap();
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.subject(), Some("state"));
+
+        assert!(to_event(Message::Ping(vec![])).unwrap().is_none());
+        assert!(to_event(Message::Text("{}".to_string())).is_err());
+    }
+}
@@ -1,6 +1,13 @@
+pub mod admin;
+pub mod alias;
 pub mod api;
+pub mod applications;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod clock;
 pub mod command;
 pub mod config;
+pub mod correlation;
 pub mod error;
 pub mod events;
 pub mod injector;
@@ -10,17 +17,21 @@ pub mod machine;
 pub mod model;
 mod mqtt;
 pub mod notifier;
+pub mod outbox;
 pub mod processor;
+pub mod reference;
+pub mod registry;
 pub mod service;
 pub mod storage;
 pub mod waker;
 
+use chrono::{DateTime, Utc};
 pub use drogue_bazaar::core::default::is_default;
 use drogue_doppelgaenger_model::InternalState;
 
 drogue_bazaar::project!(PROJECT: "Drogue IoT Doppelgänger");
 
-use crate::model::Thing;
+use crate::model::{Internal, InternalThingExt, Thing};
 
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Preconditions<'o> {
@@ -28,6 +39,8 @@ pub struct Preconditions<'o> {
     pub resource_version: Option<&'o str>,
     /// Required resource UID.
     pub uid: Option<&'o str>,
+    /// Required to not be modified since, with a precision of seconds.
+    pub unmodified_since: Option<DateTime<Utc>>,
 }
 
 impl<'o, I: InternalState> From<&'o Thing<I>> for Preconditions<'o> {
@@ -35,12 +48,13 @@ impl<'o, I: InternalState> From<&'o Thing<I>> for Preconditions<'o> {
         Self {
             resource_version: thing.metadata.resource_version.as_deref(),
             uid: thing.metadata.uid.as_deref(),
+            unmodified_since: None,
         }
     }
 }
 
 impl Preconditions<'_> {
-    pub fn matches<I: InternalState>(&self, thing: &Thing<I>) -> bool {
+    pub fn matches(&self, thing: &Thing<Internal>) -> bool {
         if let Some(resource_version) = self.resource_version {
             if Some(resource_version) != thing.metadata.resource_version.as_deref() {
                 return false;
@@ -53,6 +67,14 @@ impl Preconditions<'_> {
             }
         }
 
+        if let (Some(unmodified_since), Some(last_modified)) =
+            (self.unmodified_since, thing.last_modified())
+        {
+            if last_modified.timestamp() > unmodified_since.timestamp() {
+                return false;
+            }
+        }
+
         return true;
     }
 }
@@ -0,0 +1,61 @@
+use super::Inner;
+use crate::{config::kafka::KafkaProperties, notifier::kafka};
+use anyhow::Context;
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    message::BorrowedMessage,
+    Message as _,
+};
+use std::sync::{Arc, RwLock};
+
+pub struct Runner {
+    consumer: StreamConsumer,
+    inner: Arc<RwLock<Inner>>,
+}
+
+fn find_id<'m>(msg: &'m BorrowedMessage) -> Option<&'m str> {
+    msg.key_view().transpose().ok().flatten()
+}
+
+impl Runner {
+    pub fn new(config: kafka::Config, inner: Arc<RwLock<Inner>>) -> anyhow::Result<Self> {
+        log::info!("Starting Kafka event source: {config:?}");
+
+        let topic = config.topic;
+
+        let mut config: rdkafka::ClientConfig = KafkaProperties(config.properties).into();
+
+        config.set("enable.partition.eof", "false");
+
+        let consumer: StreamConsumer = config.create().context("Creating consumer")?;
+
+        consumer.subscribe(&[&topic]).context("Start subscribe")?;
+
+        Ok(Self { consumer, inner })
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        log::info!("Running Kafka listener ...");
+
+        loop {
+            let msg = self.consumer.recv().await;
+            match msg {
+                Err(err) => {
+                    log::error!("Failed to read from Kafka: {err}");
+                    break;
+                }
+                Ok(msg) => {
+                    let id = find_id(&msg);
+                    log::debug!("Thing id: {id:?}");
+                    if let Some(id) = id {
+                        self.inner.read().unwrap().dispatch(id, msg.payload());
+                    }
+                }
+            }
+        }
+
+        log::warn!("Exiting Kafka loop!");
+
+        Ok(())
+    }
+}
@@ -1,44 +1,110 @@
-//! This needs restructuring
+//! Listen to change events of things, as sent by a [`crate::notifier::Notifier`].
 
-use crate::config::kafka::KafkaProperties;
-use crate::{model::Thing, notifier::kafka, service::Id};
-use anyhow::Context;
+mod kafka;
+mod nats;
+mod postgres;
+mod redis;
+
+use crate::{
+    applications::Applications,
+    config::check::{Check, Checker},
+    model::Thing,
+    notifier,
+    service::Id,
+};
 use drogue_bazaar::app::Startup;
 use drogue_bazaar::core::SpawnerExt;
-use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::message::BorrowedMessage;
-use rdkafka::Message as _;
 use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
 use tokio::sync::broadcast::{channel, Sender};
 use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tokio_stream::wrappers::BroadcastStream;
 
-/// A notifier source using Kafka.
-pub struct KafkaSource {
+/// The source of change events, matching the notifier in use.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Config {
+    Kafka(notifier::kafka::Config),
+    Redis(notifier::redis::Config),
+    Postgres(notifier::postgres::Config),
+    Nats(notifier::nats::Config),
+}
+
+impl From<notifier::kafka::Config> for Config {
+    fn from(config: notifier::kafka::Config) -> Self {
+        Self::Kafka(config)
+    }
+}
+
+impl From<notifier::redis::Config> for Config {
+    fn from(config: notifier::redis::Config) -> Self {
+        Self::Redis(config)
+    }
+}
+
+impl From<notifier::postgres::Config> for Config {
+    fn from(config: notifier::postgres::Config) -> Self {
+        Self::Postgres(config)
+    }
+}
+
+impl From<notifier::nats::Config> for Config {
+    fn from(config: notifier::nats::Config) -> Self {
+        Self::Nats(config)
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        match self {
+            Self::Kafka(config) => config.check(checker),
+            Self::Redis(config) => config.check(checker),
+            Self::Postgres(config) => config.check(checker),
+            Self::Nats(config) => config.check(checker),
+        }
+    }
+}
+
+/// A listener for change events, distributing them to subscribers.
+pub struct Listener {
     inner: Arc<RwLock<Inner>>,
 }
 
 struct Inner {
-    listeners: BTreeMap<String, (usize, Sender<Message>)>,
+    listeners: BTreeMap<Key, (usize, Sender<Message>)>,
+    /// Only dispatch changes of these applications.
+    applications: Applications,
+}
+
+/// The key of a listener.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    /// A single thing, by its ID.
+    Thing(String),
+    /// All things of an application.
+    Application(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Change(Arc<Thing>),
+    /// A change as JSON patch, see [`notifier::Payload::Patch`].
+    Patch(Arc<notifier::ThingPatch>),
 }
 
 pub struct Source {
-    id: String,
-    rx: Pin<Box<dyn futures::Stream<Item = Result<Message, BroadcastStreamRecvError>>>>,
+    key: Key,
+    rx: Pin<Box<dyn futures::Stream<Item = Result<Message, BroadcastStreamRecvError>> + Send>>,
     inner: Arc<RwLock<Inner>>,
 }
 
 impl Deref for Source {
-    type Target = Pin<Box<dyn futures::Stream<Item = Result<Message, BroadcastStreamRecvError>>>>;
+    type Target =
+        Pin<Box<dyn futures::Stream<Item = Result<Message, BroadcastStreamRecvError>> + Send>>;
 
     fn deref(&self) -> &Self::Target {
         &self.rx
@@ -51,58 +117,135 @@ impl DerefMut for Source {
     }
 }
 
+impl futures::Stream for Source {
+    type Item = Result<Message, BroadcastStreamRecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.as_mut().poll_next(cx)
+    }
+}
+
 impl Drop for Source {
     fn drop(&mut self) {
         let mut lock = self.inner.write().unwrap();
 
-        let remove = if let Some(entry) = lock.listeners.get_mut(&self.id) {
+        let remove = if let Some(entry) = lock.listeners.get_mut(&self.key) {
             entry.0 -= 1;
             entry.0 == 0
         } else {
             false
         };
         if remove {
-            lock.listeners.remove(&self.id);
+            lock.listeners.remove(&self.key);
         }
     }
 }
 
-fn find_id<'m>(msg: &'m BorrowedMessage) -> Option<&'m str> {
-    msg.key_view().transpose().ok().flatten()
-}
-
-impl KafkaSource {
-    pub fn new(startup: &mut dyn Startup, config: kafka::Config) -> anyhow::Result<Self> {
-        log::info!("Starting Kafka event source: {config:?}");
+impl Inner {
+    /// The listeners of the thing, either of the thing itself or of its application.
+    ///
+    /// The `id` is expected in the format of `<application>/<thing>`. Things of applications,
+    /// which are not allowed, don't have any listeners.
+    fn listeners(&self, id: &str) -> impl Iterator<Item = &Sender<Message>> {
+        let application = id.split_once('/').map(|(application, _)| application);
+        let allowed = application.map_or(false, |application| {
+            self.applications.is_allowed(application)
+        });
 
-        let topic = config.topic;
+        [
+            self.listeners.get(&Key::Thing(id.to_string())),
+            application.and_then(|application| {
+                self.listeners
+                    .get(&Key::Application(application.to_string()))
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(move |_| allowed)
+        .map(|(_, sender)| sender)
+    }
 
-        let mut config: rdkafka::ClientConfig = KafkaProperties(config.properties).into();
+    /// Check if there is any listener for the thing.
+    fn has_listeners(&self, id: &str) -> bool {
+        self.listeners(id).next().is_some()
+    }
 
-        config.set("enable.partition.eof", "false");
+    /// Dispatch a change event to the listeners of the thing.
+    ///
+    /// The `id` is expected in the format of `<application>/<thing>`. The payload only gets parsed
+    /// if there is a listener.
+    fn dispatch(&self, id: &str, payload: Option<&[u8]>) {
+        if !self.has_listeners(id) {
+            return;
+        }
 
-        let consumer: StreamConsumer = config.create().context("Creating consumer")?;
+        if let Some(Ok(change)) = payload.map(notifier::decode_change) {
+            self.broadcast(id, change);
+        }
+    }
 
-        consumer.subscribe(&[&topic]).context("Start subscribe")?;
+    /// Broadcast an already parsed change event to the listeners of the thing.
+    fn broadcast(&self, id: &str, change: notifier::Change) {
+        let message = match change {
+            notifier::Change::Thing(thing) => Message::Change(Arc::new(thing)),
+            notifier::Change::Patch(patch) => Message::Patch(Arc::new(patch)),
+        };
+        for listener in self.listeners(id) {
+            if let Err(err) = listener.send(message.clone()) {
+                log::info!("Failed to broadcast change: {err:?}");
+            }
+        }
+    }
+}
 
+impl Listener {
+    pub fn new(startup: &mut dyn Startup, config: impl Into<Config>) -> anyhow::Result<Self> {
         let inner = Inner {
             listeners: Default::default(),
+            applications: Default::default(),
         };
         let inner = Arc::new(RwLock::new(inner));
 
-        let runner = KafkaSourceRunner {
-            consumer,
-            inner: inner.clone(),
-        };
-
-        startup.spawn(async move { runner.run().await });
+        match config.into() {
+            Config::Kafka(config) => {
+                let runner = kafka::Runner::new(config, inner.clone())?;
+                startup.spawn(async move { runner.run().await });
+            }
+            Config::Redis(config) => {
+                let runner = redis::Runner::new(config, inner.clone())?;
+                startup.spawn(async move { runner.run().await });
+            }
+            Config::Postgres(config) => {
+                let runner = postgres::Runner::new(config, inner.clone())?;
+                startup.spawn(async move { runner.run().await });
+            }
+            Config::Nats(config) => {
+                let runner = nats::Runner::new(config, inner.clone())?;
+                startup.spawn(async move { runner.run().await });
+            }
+        }
 
         Ok(Self { inner })
     }
 
+    /// Limit the listener to changes of things of these applications.
+    pub fn with_applications(self, applications: Applications) -> Self {
+        self.inner.write().unwrap().applications = applications;
+        self
+    }
+
     pub fn subscribe(&self, id: Id) -> Source {
+        self.subscribe_key(Key::Thing(id.to_string()))
+    }
+
+    /// Subscribe to changes of all things of an application.
+    pub fn subscribe_application(&self, application: impl Into<String>) -> Source {
+        self.subscribe_key(Key::Application(application.into()))
+    }
+
+    fn subscribe_key(&self, key: Key) -> Source {
         let mut lock = self.inner.write().unwrap();
-        let rx = match lock.listeners.entry(id.to_string()) {
+        let rx = match lock.listeners.entry(key.clone()) {
             Entry::Vacant(entry) => {
                 let (tx, rx) = channel(10);
                 entry.insert((1, tx));
@@ -116,51 +259,9 @@ impl KafkaSource {
         };
 
         Source {
-            id: id.to_string(),
+            key,
             rx: Box::pin(BroadcastStream::new(rx)),
             inner: self.inner.clone(),
         }
     }
 }
-
-pub struct KafkaSourceRunner {
-    consumer: StreamConsumer,
-    inner: Arc<RwLock<Inner>>,
-}
-
-impl KafkaSourceRunner {
-    pub async fn run(self) -> anyhow::Result<()> {
-        log::info!("Running Kafka listener ...");
-
-        loop {
-            let msg = self.consumer.recv().await;
-            match msg {
-                Err(err) => {
-                    log::error!("Failed to read from Kafka: {err}");
-                    break;
-                }
-                Ok(msg) => {
-                    let id = find_id(&msg);
-                    log::debug!("Thing id: {id:?}");
-                    if let Some(id) = id {
-                        let lock = self.inner.read().unwrap();
-                        if let Some(listener) = lock.listeners.get(id) {
-                            if let Some(Ok(thing)) =
-                                msg.payload().map(serde_json::from_slice::<Thing>)
-                            {
-                                if let Err(err) = listener.1.send(Message::Change(Arc::new(thing)))
-                                {
-                                    log::info!("Failed to broadcast change: {err:?}");
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        log::warn!("Exiting Kafka loop!");
-
-        Ok(())
-    }
-}
@@ -0,0 +1,49 @@
+use super::Inner;
+use crate::notifier::nats;
+use futures::StreamExt;
+use std::sync::{Arc, RwLock};
+
+pub struct Runner {
+    servers: String,
+    prefix: String,
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl Runner {
+    pub fn new(config: nats::Config, inner: Arc<RwLock<Inner>>) -> anyhow::Result<Self> {
+        log::info!("Starting NATS event source: {config:?}");
+
+        Ok(Self {
+            servers: config.servers,
+            prefix: format!("{}.", config.subject),
+            inner,
+        })
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        log::info!("Running NATS listener ...");
+
+        let client = async_nats::connect(self.servers.as_str()).await?;
+        let mut subscriber = client
+            .subscribe(format!("{}>", self.prefix))
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to subscribe: {err}"))?;
+
+        while let Some(msg) = subscriber.next().await {
+            // the application name can't contain a dot, but the thing name may
+            let id = msg
+                .subject
+                .strip_prefix(&self.prefix)
+                .and_then(|id| id.split_once('.'))
+                .map(|(application, thing)| format!("{application}/{thing}"));
+            log::debug!("Thing id: {id:?}");
+            if let Some(id) = id {
+                self.inner.read().unwrap().dispatch(&id, Some(&msg.payload));
+            }
+        }
+
+        log::warn!("Exiting NATS loop!");
+
+        Ok(())
+    }
+}
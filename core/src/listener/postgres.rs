@@ -0,0 +1,116 @@
+use super::Inner;
+use crate::{
+    notifier::{
+        self,
+        postgres::{Config, Notification},
+    },
+    storage::{self, postgres::Storage, Storage as _},
+};
+use futures::StreamExt;
+use postgres_native_tls::MakeTlsConnector;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use tokio_postgres::AsyncMessage;
+
+pub struct Runner {
+    config: tokio_postgres::Config,
+    channel: String,
+    storage: Storage,
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl Runner {
+    pub fn new(config: Config, inner: Arc<RwLock<Inner>>) -> anyhow::Result<Self> {
+        log::info!("Starting Postgres event source: {config:?}");
+
+        let storage = Storage::from_config(&storage::postgres::Config {
+            applications: Default::default(),
+            postgres: config.postgres.clone(),
+            cold: None,
+            replica: None,
+            outbox: false,
+        })?;
+
+        Ok(Self {
+            config: config.postgres.db.get_pg_config()?,
+            channel: config.channel,
+            storage,
+            inner,
+        })
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        log::info!("Running Postgres listener ...");
+
+        let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+        let (client, mut connection) = self.config.connect(tls).await?;
+
+        // the connection must be polled for the client to make progress
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        let connection = tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                match message? {
+                    AsyncMessage::Notification(notification) => {
+                        if tx.send(notification).is_err() {
+                            break;
+                        }
+                    }
+                    message => log::debug!("Postgres message: {message:?}"),
+                }
+            }
+            Ok::<_, tokio_postgres::Error>(())
+        });
+
+        client
+            .batch_execute(&format!("LISTEN \"{}\"", self.channel.replace('"', "\"\"")))
+            .await?;
+
+        while let Some(notification) = rx.recv().await {
+            match serde_json::from_str::<Notification<Value>>(notification.payload()) {
+                Ok(notification) => self.dispatch(notification).await,
+                Err(err) => log::info!("Failed to decode notification: {err}"),
+            }
+        }
+
+        log::warn!("Exiting Postgres loop!");
+
+        connection.await??;
+
+        Ok(())
+    }
+
+    async fn dispatch(&self, notification: Notification<Value>) {
+        let id = format!("{}/{}", notification.application, notification.thing);
+        log::debug!("Thing id: {id}");
+
+        if !self.inner.read().unwrap().has_listeners(&id) {
+            return;
+        }
+
+        let change = match notification.state {
+            Some(state) => match notifier::decode_change_value(&state) {
+                Ok(change) => change,
+                Err(err) => {
+                    log::info!("Failed to decode changed thing: {err}");
+                    return;
+                }
+            },
+            // too big for the notification, fetch the current state instead
+            None => match self
+                .storage
+                .get(&notification.application, &notification.thing)
+                .await
+            {
+                Ok(Some(thing)) => notifier::Change::Thing(thing.into_external()),
+                Ok(None) | Err(storage::Error::NotFound) => return,
+                Err(err) => {
+                    log::info!("Failed to fetch changed thing: {err}");
+                    return;
+                }
+            },
+        };
+
+        self.inner.read().unwrap().broadcast(&id, change);
+    }
+}
@@ -0,0 +1,45 @@
+use super::Inner;
+use crate::notifier::redis;
+use futures::StreamExt;
+use std::sync::{Arc, RwLock};
+
+pub struct Runner {
+    client: ::redis::Client,
+    prefix: String,
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl Runner {
+    pub fn new(config: redis::Config, inner: Arc<RwLock<Inner>>) -> anyhow::Result<Self> {
+        log::info!("Starting Redis event source: {config:?}");
+
+        Ok(Self {
+            client: ::redis::Client::open(config.url.as_str())?,
+            prefix: format!("{}/", config.channel),
+            inner,
+        })
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        log::info!("Running Redis listener ...");
+
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.psubscribe(format!("{}*", self.prefix)).await?;
+
+        let mut messages = pubsub.on_message();
+        while let Some(msg) = messages.next().await {
+            let id = msg.get_channel_name().strip_prefix(&self.prefix);
+            log::debug!("Thing id: {id:?}");
+            if let Some(id) = id {
+                self.inner
+                    .read()
+                    .unwrap()
+                    .dispatch(id, Some(msg.get_payload_bytes()));
+            }
+        }
+
+        log::warn!("Exiting Redis loop!");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+//! Coercing reported values to the types declared by the schema of a thing.
+//!
+//! Only types of values which can be converted without ambiguity are coerced. Values which
+//! cannot be converted are left untouched, and will fail the validation later on.
+
+use crate::model::{Internal, JsonSchema, Schema, Thing};
+use chrono::{TimeZone, Utc};
+use serde_json::{Number, Value};
+
+/// Coerce the reported values of a thing to the types declared by its schema.
+pub fn coerce(thing: &mut Thing<Internal>) {
+    let schema = match &thing.schema {
+        Some(Schema::Json(JsonSchema::Draft7(schema))) => schema,
+        None => return,
+    };
+
+    let properties = match schema
+        .pointer("/properties/reportedState/properties")
+        .and_then(Value::as_object)
+    {
+        Some(properties) => properties,
+        None => return,
+    };
+
+    for (name, feature) in &mut thing.reported_state {
+        if let Some(schema) = properties.get(name) {
+            coerce_value(&mut feature.value, schema);
+        }
+    }
+}
+
+/// Coerce a value to the type declared by the schema.
+fn coerce_value(value: &mut Value, schema: &Value) {
+    match value {
+        Value::Object(map) => {
+            if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+                for (name, value) in map {
+                    if let Some(schema) = properties.get(name) {
+                        coerce_value(value, schema);
+                    }
+                }
+            }
+            return;
+        }
+        Value::Array(items) => {
+            if let Some(schema) = schema.get("items").filter(|items| items.is_object()) {
+                for value in items {
+                    coerce_value(value, schema);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let types = declared_types(schema);
+    if types.is_empty() || types.iter().any(|t| matches_type(value, t)) {
+        // no type declared, or the value already matches
+        return;
+    }
+
+    let format = schema.get("format").and_then(Value::as_str);
+
+    if let Some(coerced) = types.iter().find_map(|t| convert(value, t, format)) {
+        *value = coerced;
+    }
+}
+
+fn declared_types(schema: &Value) -> Vec<&str> {
+    match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    }
+}
+
+fn matches_type(value: &Value, t: &str) -> bool {
+    match (t, value) {
+        ("null", Value::Null)
+        | ("boolean", Value::Bool(_))
+        | ("number", Value::Number(_))
+        | ("string", Value::String(_))
+        | ("array", Value::Array(_))
+        | ("object", Value::Object(_)) => true,
+        ("integer", Value::Number(n)) => n.is_i64() || n.is_u64(),
+        _ => false,
+    }
+}
+
+fn convert(value: &Value, t: &str, format: Option<&str>) -> Option<Value> {
+    match (t, value) {
+        ("number", Value::String(s)) => s
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map(Value::Number),
+        ("integer", Value::String(s)) => s.trim().parse::<i64>().ok().map(Value::from),
+        ("integer", Value::Number(n)) => n
+            .as_f64()
+            .filter(|f| f.fract() == 0.0 && *f >= i64::MIN as f64 && *f <= i64::MAX as f64)
+            .map(|f| Value::from(f as i64)),
+        ("boolean", Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        ("string", Value::Number(n)) if format == Some("date-time") => {
+            // epoch seconds
+            let millis = (n.as_f64()? * 1000.0).round();
+            if !millis.is_finite() || millis.abs() > i64::MAX as f64 {
+                return None;
+            }
+            match Utc.timestamp_millis_opt(millis as i64) {
+                chrono::LocalResult::Single(timestamp) => {
+                    Some(Value::String(timestamp.to_rfc3339()))
+                }
+                _ => None,
+            }
+        }
+        ("string", Value::Number(n)) => Some(Value::String(n.to_string())),
+        ("string", Value::Bool(b)) => Some(Value::String(b.to_string())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::ReportedFeature;
+    use serde_json::json;
+
+    fn thing(schema: Value, reported: Value) -> Thing<Internal> {
+        let mut thing = Thing::new("default", "thing");
+        thing.schema = Some(Schema::Json(JsonSchema::Draft7(json!({
+            "type": "object",
+            "properties": {
+                "reportedState": {
+                    "type": "object",
+                    "properties": schema,
+                }
+            }
+        }))));
+        if let Value::Object(reported) = reported {
+            for (name, value) in reported {
+                thing
+                    .reported_state
+                    .insert(name, ReportedFeature::now(value));
+            }
+        }
+        thing
+    }
+
+    fn reported(thing: &Thing<Internal>) -> Value {
+        Value::Object(
+            thing
+                .reported_state
+                .iter()
+                .map(|(k, v)| (k.clone(), v.value.clone()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_coerce() {
+        let mut thing = thing(
+            json!({
+                "temperature": { "type": "number" },
+                "count": { "type": "integer" },
+                "on": { "type": "boolean" },
+                "label": { "type": "string" },
+                "timestamp": { "type": "string", "format": "date-time" },
+                "nested": {
+                    "type": "object",
+                    "properties": {
+                        "level": { "type": "number" }
+                    }
+                },
+                "list": { "type": "array", "items": { "type": "integer" } },
+                "unknown": {},
+            }),
+            json!({
+                "temperature": "42.5",
+                "count": 3.0,
+                "on": "TRUE",
+                "label": 42,
+                "timestamp": 1640995200,
+                "nested": { "level": "1.5", "other": "1" },
+                "list": ["1", 2],
+                "unknown": "42",
+                "other": "42",
+            }),
+        );
+
+        coerce(&mut thing);
+
+        assert_eq!(
+            reported(&thing),
+            json!({
+                "temperature": 42.5,
+                "count": 3,
+                "on": true,
+                "label": "42",
+                "timestamp": "2022-01-01T00:00:00+00:00",
+                "nested": { "level": 1.5, "other": "1" },
+                "list": [1, 2],
+                "unknown": "42",
+                "other": "42",
+            })
+        );
+    }
+
+    #[test]
+    fn test_no_coercion() {
+        let mut thing = thing(
+            json!({
+                "temperature": { "type": "number" },
+                "count": { "type": "integer" },
+                "either": { "type": ["string", "number"] },
+            }),
+            json!({
+                "temperature": "hot",
+                "count": 1.5,
+                "either": "42",
+            }),
+        );
+
+        coerce(&mut thing);
+
+        assert_eq!(
+            reported(&thing),
+            json!({
+                "temperature": "hot",
+                "count": 1.5,
+                "either": "42",
+            })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Reconciling things using external controllers.
+//!
+//! Things annotated with [`ANNOTATION_CONTROLLER`] get their changes posted to the URL of the
+//! annotation. The controller responds with a JSON patch, which will be applied to the new state
+//! of the thing.
+
+use crate::model::{Internal, Thing};
+use anyhow::{anyhow, bail};
+use json_patch::Patch;
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tracing::instrument;
+use url::Url;
+
+/// Annotation containing the URL of the external controller.
+pub const ANNOTATION_CONTROLLER: &str = "drogue.io/controller";
+
+/// Condition raised when the external controller failed.
+pub const CONDITION_CONTROLLER_FAILED: &str = "ControllerFailed";
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Config {
+    /// Enable calling external controllers.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// URLs of controllers which are allowed to be called.
+    ///
+    /// A controller URL is allowed if it has the same scheme, host, and port as one of the
+    /// allowed URLs, and its path starts with the path segments of the allowed URL. If empty, no
+    /// controller is allowed.
+    #[serde(default)]
+    pub allowed_urls: Vec<String>,
+
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+}
+
+mod default {
+    use super::*;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Request<'r> {
+    current_state: &'r Thing<Value>,
+    new_state: &'r Thing<Value>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Controller {
+    client: reqwest::Client,
+    allowed_urls: Arc<Vec<Url>>,
+}
+
+impl Controller {
+    /// Create a new controller client, returns `None` if the controllers are disabled.
+    pub fn from_config(config: Config) -> anyhow::Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let allowed_urls = config
+            .allowed_urls
+            .iter()
+            .map(|url| Url::parse(url).map_err(|err| anyhow!("Invalid allowed URL '{url}': {err}")))
+            .collect::<Result<_, _>>()?;
+
+        let client = reqwest::Client::builder().timeout(config.timeout).build()?;
+
+        Ok(Some(Self {
+            client,
+            allowed_urls: Arc::new(allowed_urls),
+        }))
+    }
+
+    fn is_allowed(&self, url: &str) -> bool {
+        let url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+
+        self.allowed_urls.iter().any(|allowed| {
+            allowed.scheme() == url.scheme()
+                && allowed.host() == url.host()
+                && allowed.port_or_known_default() == url.port_or_known_default()
+                && is_path_prefix(allowed, &url)
+        })
+    }
+
+    /// Run the external controller for the new state, if the thing requests it.
+    ///
+    /// Returns `true` if the controller was called.
+    #[instrument(skip_all, fields(url), err)]
+    pub async fn reconcile(
+        &self,
+        current_thing: &Thing<Internal>,
+        new_thing: &mut Thing<Internal>,
+    ) -> anyhow::Result<bool> {
+        let url = match new_thing.metadata.annotations.get(ANNOTATION_CONTROLLER) {
+            Some(url) => url.clone(),
+            None => return Ok(false),
+        };
+        tracing::Span::current().record("url", url.as_str());
+
+        if !self.is_allowed(&url) {
+            bail!("Controller URL is not allowed: {url}");
+        }
+
+        let current_state = current_thing.clone().strip_internal();
+        let new_state = new_thing.clone().strip_internal();
+
+        let response = self
+            .client
+            .post(&url)
+            .json(&Request {
+                current_state: &current_state,
+                new_state: &new_state,
+            })
+            .send()
+            .await?;
+
+        let patch: Patch = match response.status() {
+            StatusCode::NO_CONTENT => return Ok(true),
+            status if status.is_success() => response.json().await?,
+            status => bail!("Controller responded with: {status}"),
+        };
+
+        let mut json = serde_json::to_value(new_state)?;
+        json_patch::patch(&mut json, &patch)
+            .map_err(|err| anyhow!("Failed to apply patch: {err}"))?;
+        let mut patched: Thing<Internal> = serde_json::from_value(json)?;
+
+        // the internal state is not visible to the controller, and conditions are managed by
+        // the system
+        patched.internal = new_thing.internal.take();
+        patched.conditions = std::mem::take(&mut new_thing.conditions);
+        *new_thing = patched;
+
+        Ok(true)
+    }
+}
+
+/// Check if the path segments of the URL start with the path segments of the allowed URL.
+fn is_path_prefix(allowed: &Url, url: &Url) -> bool {
+    let segments = |url: &Url| {
+        url.path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+    };
+
+    match (segments(allowed), segments(url)) {
+        (Some(allowed), Some(url)) => url.starts_with(&allowed),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn controller(allowed_urls: &[&str]) -> Controller {
+        Controller::from_config(Config {
+            enabled: true,
+            allowed_urls: allowed_urls.iter().map(ToString::to_string).collect(),
+            timeout: default::timeout(),
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_allowed() {
+        let controller = controller(&["https://api.example.com/controllers/"]);
+
+        assert!(controller.is_allowed("https://api.example.com/controllers/foo"));
+        assert!(controller.is_allowed("https://api.example.com:443/controllers/foo/bar"));
+        assert!(controller.is_allowed("https://API.example.com/controllers"));
+    }
+
+    #[test]
+    fn test_denied() {
+        let controller = controller(&["https://api.example.com/controllers"]);
+
+        for url in [
+            "https://api.example.com.evil.net/controllers/foo",
+            "https://api.example.com@evil.net/controllers/foo",
+            "https://api.example.com:8443/controllers/foo",
+            "http://api.example.com/controllers/foo",
+            "https://api.example.com/controllersfoo",
+            "https://api.example.com/controllers/../admin",
+            "https://api.example.com/",
+            "https://evil.net/controllers/foo",
+            "not a url",
+        ] {
+            assert!(!controller.is_allowed(url), "{url} must not be allowed");
+        }
+    }
+
+    #[test]
+    fn test_deny_by_default() {
+        let controller = controller(&[]);
+
+        assert!(!controller.is_allowed("https://api.example.com/controllers/foo"));
+    }
+
+    #[test]
+    fn test_invalid_allowed_url() {
+        assert!(Controller::from_config(Config {
+            enabled: true,
+            allowed_urls: vec!["api.example.com".to_string()],
+            timeout: default::timeout(),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Values derived from numeric reported features, without the need for scripting.
+//!
+//! The previous value of the synthetic feature acts as state, so that updating a value only
+//! requires the new sample.
+
+use serde_json::{Number, Value};
+
+/// Update an exponential moving average with a new sample.
+pub fn ema(previous: &Value, sample: Option<f64>, alpha: f64) -> Value {
+    let alpha = alpha.clamp(0.0, 1.0);
+    match (
+        previous.as_f64(),
+        sample.filter(|sample| sample.is_finite()),
+    ) {
+        (Some(previous), Some(sample)) => number(alpha * sample + (1.0 - alpha) * previous),
+        (None, Some(sample)) => number(sample),
+        (_, None) => previous.clone(),
+    }
+}
+
+/// Update the difference of a new sample to the last value of the feature.
+pub fn delta(previous: &Value, last: Option<f64>, sample: Option<f64>) -> Value {
+    match (last, sample) {
+        (Some(last), Some(sample)) => number(sample - last),
+        (None, Some(_)) => Value::Null,
+        (_, None) => previous.clone(),
+    }
+}
+
+/// Evaluate a threshold, with hysteresis.
+///
+/// Values between the lower and upper bound keep the previous state.
+pub fn threshold(previous: &Value, value: Option<f64>, upper: f64, lower: f64) -> Value {
+    let lower = lower.min(upper);
+    let breached = match value {
+        Some(value) if value > upper => true,
+        Some(value) if value < lower => false,
+        _ => previous.as_bool().unwrap_or_default(),
+    };
+    Value::Bool(breached)
+}
+
+fn number(value: f64) -> Value {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ema() {
+        let value = ema(&Value::Null, Some(10.0), 0.5);
+        assert_eq!(value, json!(10.0));
+        let value = ema(&value, Some(20.0), 0.5);
+        assert_eq!(value, json!(15.0));
+        // no new sample
+        assert_eq!(ema(&value, None, 0.5), json!(15.0));
+    }
+
+    #[test]
+    fn test_delta() {
+        assert_eq!(delta(&Value::Null, None, Some(5.0)), Value::Null);
+        assert_eq!(delta(&Value::Null, Some(5.0), Some(7.5)), json!(2.5));
+        assert_eq!(delta(&json!(2.5), Some(7.5), None), json!(2.5));
+    }
+
+    #[test]
+    fn test_threshold() {
+        let value = threshold(&Value::Null, Some(50.0), 80.0, 70.0);
+        assert_eq!(value, json!(false));
+        let value = threshold(&value, Some(85.0), 80.0, 70.0);
+        assert_eq!(value, json!(true));
+        // within the hysteresis
+        let value = threshold(&value, Some(75.0), 80.0, 70.0);
+        assert_eq!(value, json!(true));
+        let value = threshold(&value, Some(65.0), 80.0, 70.0);
+        assert_eq!(value, json!(false));
+        let value = threshold(&value, Some(75.0), 80.0, 70.0);
+        assert_eq!(value, json!(false));
+    }
+}
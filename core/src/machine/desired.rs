@@ -1,6 +1,6 @@
 use super::deno;
 use crate::{
-    command,
+    clock, command,
     machine::{
         deno::{DenoOptions, Execution, Json},
         recon::ScriptAction,
@@ -28,6 +28,7 @@ impl CommandBuilder {
     pub fn into_commands(
         mut self,
         application: &str,
+        route: Option<&str>,
     ) -> Result<Vec<command::Command>, serde_json::Error> {
         for (device, channels) in self.channels {
             for (channel, values) in channels {
@@ -36,10 +37,17 @@ impl CommandBuilder {
                     device: device.clone(),
                     channel,
                     payload: serde_json::to_vec(&values)?,
+                    route: None,
                 })
             }
         }
 
+        if let Some(route) = route {
+            for command in &mut self.commands {
+                command.route = Some(route.to_string());
+            }
+        }
+
         Ok(self.commands)
     }
 
@@ -57,6 +65,43 @@ impl CommandBuilder {
     }
 }
 
+/// The encoding of the commands of a feature, defaulting to the channel annotation of the thing.
+pub fn encoding(
+    command: &model::Command,
+    annotations: &BTreeMap<String, String>,
+) -> CommandEncoding {
+    command.encoding.clone().unwrap_or_else(|| {
+        if let Some(channel) = annotations.get("drogue.io/channel") {
+            CommandEncoding::Channel(channel.to_string())
+        } else {
+            CommandEncoding::Raw
+        }
+    })
+}
+
+/// The name of the command (channel), sent for a feature.
+pub fn command_name<'a>(encoding: &'a CommandEncoding, feature: &'a str) -> &'a str {
+    match encoding {
+        CommandEncoding::Remap { channel, .. } | CommandEncoding::Channel(channel) => channel,
+        CommandEncoding::Template {
+            channel: Some(channel),
+            ..
+        } => channel,
+        CommandEncoding::Raw | CommandEncoding::Cbor | CommandEncoding::Template { .. } => feature,
+    }
+}
+
+/// Render a command payload template, replacing the placeholders `${value}` and `${feature}`.
+pub fn render_template(
+    template: &str,
+    feature: &str,
+    value: &Value,
+) -> Result<String, serde_json::Error> {
+    Ok(template
+        .replace("${value}", &serde_json::to_string(value)?)
+        .replace("${feature}", feature))
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Command {
     #[serde(default)]
@@ -173,7 +218,7 @@ impl DesiredReconciler for Code {
 
                 if !commands.is_empty() {
                     // tick "last attempt" only when we make an attempt (by sending commands)
-                    *feature.last_attempt = Some(Utc::now());
+                    *feature.last_attempt = Some(clock::now());
                     // FIXME: find a way to support "channel" aggregation too
                     for command in commands {
                         context.commands.push_command(command::Command {
@@ -183,6 +228,7 @@ impl DesiredReconciler for Code {
                                 .unwrap_or_else(|| context.new_thing.metadata.name.clone()),
                             channel: command.channel,
                             payload: vec![],
+                            route: None,
                         });
                     }
                 }
@@ -211,7 +257,7 @@ impl DesiredReconciler for model::Command {
             Some(last_attempt) => {
                 // check due time
                 let due = *last_attempt + period;
-                if due > Utc::now() {
+                if due > clock::now() {
                     if matches!(self.mode, CommandMode::Active) {
                         context.waker.wakeup_at(due, WakerReason::Reconcile);
                     }
@@ -224,23 +270,13 @@ impl DesiredReconciler for model::Command {
         }
 
         // last_attempt = now
-        *input.last_attempt = Some(Utc::now());
+        *input.last_attempt = Some(clock::now());
         if matches!(self.mode, CommandMode::Active) {
             context.waker.wakeup(period, WakerReason::Reconcile);
         }
 
-        let encoding = self.encoding.clone().unwrap_or_else(|| {
-            if let Some(channel) = context
-                .new_thing
-                .metadata
-                .annotations
-                .get("drogue.io/channel")
-            {
-                CommandEncoding::Channel(channel.to_string())
-            } else {
-                CommandEncoding::Raw
-            }
-        });
+        let encoding = encoding(self, &context.new_thing.metadata.annotations);
+        let channel = command_name(&encoding, input.name).to_string();
 
         let device = context
             .new_thing
@@ -265,8 +301,29 @@ impl DesiredReconciler for model::Command {
                 context.commands.push_command(command::Command {
                     application: context.new_thing.metadata.application.clone(),
                     device,
-                    channel: input.name.to_string(),
+                    channel,
                     payload: serde_json::to_vec(&input.value)?,
+                    route: None,
+                });
+            }
+            CommandEncoding::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::ser::into_writer(&input.value, &mut payload)?;
+                context.commands.push_command(command::Command {
+                    application: context.new_thing.metadata.application.clone(),
+                    device,
+                    channel,
+                    payload,
+                    route: None,
+                });
+            }
+            CommandEncoding::Template { template, .. } => {
+                context.commands.push_command(command::Command {
+                    application: context.new_thing.metadata.application.clone(),
+                    device,
+                    channel,
+                    payload: render_template(&template, input.name, &input.value)?.into_bytes(),
+                    route: None,
                 });
             }
         }
@@ -274,3 +331,51 @@ impl DesiredReconciler for model::Command {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name(&CommandEncoding::Raw, "light"), "light");
+        assert_eq!(command_name(&CommandEncoding::Cbor, "light"), "light");
+        assert_eq!(
+            command_name(&CommandEncoding::Channel("state".to_string()), "light"),
+            "state"
+        );
+        assert_eq!(
+            command_name(
+                &CommandEncoding::Template {
+                    channel: None,
+                    template: "${value}".to_string()
+                },
+                "light"
+            ),
+            "light"
+        );
+        assert_eq!(
+            command_name(
+                &CommandEncoding::Template {
+                    channel: Some("set".to_string()),
+                    template: "${value}".to_string()
+                },
+                "light"
+            ),
+            "set"
+        );
+    }
+
+    #[test]
+    fn test_render_template() {
+        assert_eq!(
+            render_template(r#"{"set": ${value}}"#, "light", &json!(true)).unwrap(),
+            r#"{"set": true}"#
+        );
+        assert_eq!(
+            render_template("${feature}=${value}", "mode", &json!("eco")).unwrap(),
+            r#"mode="eco""#
+        );
+    }
+}
@@ -1,10 +1,15 @@
-mod deno;
-mod desired;
+mod coerce;
+pub mod controller;
+pub(crate) mod deno;
+mod derived;
+pub(crate) mod desired;
 mod recon;
+mod statistics;
 
 use crate::{
     command::Command,
     machine::{
+        controller::Controller,
         deno::{DenoOptions, Json},
         recon::{Reconciler, ScriptAction},
     },
@@ -15,14 +20,27 @@ use anyhow::anyhow;
 use deno_core::url::Url;
 use jsonschema::{Draft, JSONSchema, SchemaResolver, SchemaResolverError};
 use lazy_static::lazy_static;
-use prometheus::{register_histogram, Histogram};
+use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
 use serde_json::Value;
-use std::{convert::Infallible, fmt::Debug, future::Future, sync::Arc};
+use std::{convert::Infallible, fmt::Debug, future::Future, sync::Arc, time::Duration};
 use tracing::instrument;
 
 lazy_static! {
     static ref TIMER_DELAY: Histogram =
         register_histogram!("timer_delay", "Amount of time by which timers are delayed").unwrap();
+    static ref WAKER_DELAY: Histogram =
+        register_histogram!("waker_delay", "Amount of time by which wakers are delayed").unwrap();
+    static ref OUTBOX_AGE: Histogram = register_histogram!(
+        "outbox_age",
+        "Age of the oldest undelivered outbox event, in ms"
+    )
+    .unwrap();
+    static ref DELAY_CONDITIONS: IntCounterVec = register_int_counter_vec!(
+        "delay_conditions",
+        "Number of raised delay conditions",
+        &["condition"]
+    )
+    .unwrap();
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
@@ -43,9 +61,106 @@ pub enum Error {
     Internal(#[source] anyhow::Error),
 }
 
+/// Condition raised when a run produced more outbox messages than allowed.
+pub const CONDITION_OUTBOX_LIMIT_EXCEEDED: &str = "OutboxLimitExceeded";
+/// Condition raised when a run produced more commands than allowed.
+pub const CONDITION_COMMAND_LIMIT_EXCEEDED: &str = "CommandLimitExceeded";
+/// Condition raised when a timer ran later than the alert threshold.
+pub const CONDITION_TIMER_DELAYED: &str = "TimerDelayed";
+/// Condition raised when an outbox event wasn't delivered within the alert threshold.
+pub const CONDITION_OUTBOX_DELAYED: &str = "OutboxDelayed";
+/// Condition raised when a thing was woken up later than the alert threshold.
+pub const CONDITION_WAKER_OVERDUE: &str = "WakerOverdue";
+
+/// Limits applied to a single run of the machine.
+///
+/// Exceeding a limit will not fail the run, but truncate the outcome and raise a condition on
+/// the thing.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Limits {
+    /// Maximum number of outbox messages
+    #[serde(default = "default::max_outbox")]
+    pub max_outbox: usize,
+    /// Maximum number of commands
+    #[serde(default = "default::max_commands")]
+    pub max_commands: usize,
+}
+
+/// Thresholds of delays, which raise a condition on the thing.
+///
+/// The conditions reflect the state of the most recent run, and get cleared once a run stays
+/// within the threshold again.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Alerts {
+    /// Maximum delay of a timer
+    #[serde(default = "default::timer_delay", with = "humantime_serde")]
+    pub timer_delay: Duration,
+    /// Maximum age of an undelivered outbox event
+    #[serde(default = "default::outbox_age", with = "humantime_serde")]
+    pub outbox_age: Duration,
+    /// Maximum delay of a waker
+    #[serde(default = "default::waker_delay", with = "humantime_serde")]
+    pub waker_delay: Duration,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            timer_delay: default::timer_delay(),
+            outbox_age: default::outbox_age(),
+            waker_delay: default::waker_delay(),
+        }
+    }
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timer_delay() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub const fn outbox_age() -> Duration {
+        Duration::from_secs(5 * 60)
+    }
+
+    pub const fn waker_delay() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub const fn max_outbox() -> usize {
+        100
+    }
+
+    pub const fn max_commands() -> usize {
+        100
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_outbox: default::max_outbox(),
+            max_commands: default::max_commands(),
+        }
+    }
+}
+
+/// Options for running the machine.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub limits: Limits,
+    pub alerts: Alerts,
+    /// The client for external controllers, `None` if disabled.
+    pub controller: Option<Controller>,
+    /// Coerce reported values to the types declared by the schema, before validating.
+    pub coerce: bool,
+}
+
 /// The state machine runner. Good for a single run.
 pub struct Machine {
     thing: Thing<Internal>,
+    options: Options,
 }
 
 pub struct Outcome {
@@ -61,18 +176,35 @@ pub struct DeletionOutcome {
 
 impl Machine {
     pub fn new(thing: Thing<Internal>) -> Self {
-        Self { thing }
+        Self {
+            thing,
+            options: Default::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
     }
 
     /// Run actions for creating a new thing.
-    #[instrument(skip_all, err)]
     pub async fn create(new_thing: Thing<Internal>) -> Result<Outcome, Error> {
+        Self::create_with(new_thing, Default::default()).await
+    }
+
+    /// Run actions for creating a new thing, using the provided options.
+    #[instrument(skip_all, err)]
+    pub async fn create_with(
+        new_thing: Thing<Internal>,
+        options: Options,
+    ) -> Result<Outcome, Error> {
         // Creating means that we start with an empty thing, and then set the initial state.
         // This allows to run through the reconciliation initially.
         let outcome = Self::new(Thing::new(
             &new_thing.metadata.application,
             &new_thing.metadata.name,
         ))
+        .with_options(options)
         .update(|_| async { Ok::<_, Infallible>(new_thing) })
         .await?;
 
@@ -81,7 +213,11 @@ impl Machine {
     }
 
     /// Run an update.
-    #[instrument(skip_all, err)]
+    #[instrument(skip_all, fields(
+        application = %self.thing.metadata.application,
+        thing = %self.thing.metadata.name,
+        generation = ?self.thing.metadata.generation,
+    ), err)]
     pub async fn update<F, Fut, E>(self, f: F) -> Result<Outcome, Error>
     where
         F: FnOnce(Thing<Internal>) -> Fut,
@@ -104,15 +240,19 @@ impl Machine {
         // start with original state
 
         let original_thing = Arc::new(self.thing);
-        log::debug!("Original state: {original_thing:?}");
+        tracing::debug!(?original_thing, "Original state");
 
         // apply the update
 
-        let new_thing = f((*original_thing).clone())
+        let mut new_thing = f((*original_thing).clone())
             .await
             .map_err(|err| Error::Mutator(Box::new(err)))?;
 
-        log::debug!("New state (post-update: {new_thing:?}");
+        if self.options.coerce {
+            coerce::coerce(&mut new_thing);
+        }
+
+        tracing::debug!(?new_thing, "New state (post-update)");
 
         // reconcile the result
 
@@ -120,14 +260,16 @@ impl Machine {
             new_thing,
             outbox,
             commands,
-        } = Reconciler::new(original_thing, new_thing).run().await?;
+        } = Reconciler::new(original_thing, new_thing, self.options)
+            .run()
+            .await?;
 
-        log::debug!("New state (post-reconcile: {new_thing:?}");
+        tracing::debug!(?new_thing, "New state (post-reconcile)");
 
         // validate the outcome
         Self::validate(&new_thing)?;
 
-        log::debug!("New state (post-validate: {new_thing:?}");
+        tracing::debug!(?new_thing, "New state (post-validate)");
 
         // reapply the captured metadata
 
@@ -264,8 +406,11 @@ impl SchemaResolver for RejectResolver {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::model::{Metadata, ReportedFeature};
+    use crate::model::{
+        Delta, Ema, Metadata, ReportedFeature, Statistics, SyntheticFeature, SyntheticType,
+    };
     use chrono::{DateTime, TimeZone, Utc};
+    use serde_json::json;
     use std::collections::BTreeMap;
 
     #[tokio::test]
@@ -291,10 +436,12 @@ mod test {
             Thing {
                 metadata,
                 schema: None,
+                alias_of: None,
                 reported_state: Default::default(),
                 desired_state: Default::default(),
                 synthetic_state: Default::default(),
                 reconciliation: Default::default(),
+                conditions: Default::default(),
                 internal: None,
             },
             new_thing
@@ -330,6 +477,7 @@ mod test {
             Thing {
                 metadata: test_metadata(),
                 schema: None,
+                alias_of: None,
                 reported_state: {
                     let mut r = BTreeMap::new();
                     r.insert(
@@ -344,6 +492,7 @@ mod test {
                 desired_state: Default::default(),
                 synthetic_state: Default::default(),
                 reconciliation: Default::default(),
+                conditions: Default::default(),
                 internal: None
             },
             new_thing
@@ -353,12 +502,190 @@ mod test {
         assert_eq!(commands, vec![]);
     }
 
+    #[tokio::test]
+    async fn test_suspended() {
+        let mut thing = test_thing();
+        thing.metadata.annotations.insert(
+            crate::model::ANNOTATION_SUSPEND.to_string(),
+            "true".to_string(),
+        );
+        thing.reconciliation.changed.insert(
+            "test".to_string(),
+            Code::JavaScript(
+                r#"context.newState.reportedState["changed"] = { lastUpdate: new Date().toISOString(), value: true };"#
+                    .to_string(),
+            )
+            .into(),
+        );
+
+        let Outcome {
+            new_thing,
+            outbox,
+            commands,
+        } = Machine::new(thing)
+            .update(|mut thing| async {
+                thing
+                    .reported_state
+                    .insert("temperature".to_string(), ReportedFeature::now(42.into()));
+                Ok::<_, Infallible>(thing)
+            })
+            .await
+            .unwrap();
+
+        // reported state must be accepted, but the code must not run
+        assert!(new_thing.reported_state.contains_key("temperature"));
+        assert!(!new_thing.reported_state.contains_key("changed"));
+        assert_eq!(outbox, vec![]);
+        assert_eq!(commands, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_limit() {
+        let mut thing = test_thing();
+        thing.reconciliation.changed.insert(
+            "test".to_string(),
+            Code::JavaScript(
+                r#"
+for (let i = 0; i < 10; i++) {
+    context.outbox.push({thing: "other", message: { merge: {"counter": i}}});
+}
+"#
+                .to_string(),
+            )
+            .into(),
+        );
+
+        let options = Options {
+            limits: Limits {
+                max_outbox: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let Outcome {
+            new_thing, outbox, ..
+        } = Machine::new(thing)
+            .with_options(options.clone())
+            .update(|thing| async { Ok::<_, Infallible>(thing) })
+            .await
+            .unwrap();
+
+        assert_eq!(outbox.len(), 5);
+        assert!(new_thing
+            .conditions
+            .contains_key(CONDITION_OUTBOX_LIMIT_EXCEEDED));
+
+        // drop the code, the condition must be cleared
+
+        let Outcome {
+            new_thing, outbox, ..
+        } = Machine::new(new_thing)
+            .with_options(options)
+            .update(|mut thing| async {
+                thing.reconciliation.changed.clear();
+                Ok::<_, Infallible>(thing)
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(outbox.len(), 0);
+        assert!(new_thing.conditions.is_empty());
+    }
+
     const UID: &str = "3952a802-01e8-11ed-a9c0-d45d6455d2cc";
 
     fn creation_timestamp() -> DateTime<Utc> {
         Utc.ymd(2022, 1, 1).and_hms(12, 42, 0)
     }
 
+    /// Report the state, the same way as the processor does.
+    async fn report(thing: Thing<Internal>, state: Value) -> Thing<Internal> {
+        use crate::service::{InfallibleUpdater, ReportedStateUpdater, UpdateMode};
+
+        let state = serde_json::from_value(state).unwrap();
+        Machine::new(thing)
+            .update(|thing| async move {
+                Ok::<_, Infallible>(InfallibleUpdater::update(
+                    &ReportedStateUpdater(state, UpdateMode::Merge),
+                    thing,
+                ))
+            })
+            .await
+            .unwrap()
+            .new_thing
+    }
+
+    #[tokio::test]
+    async fn test_statistics_equal_reports() {
+        let mut thing = test_thing();
+        thing.synthetic_state.insert(
+            "stats".to_string(),
+            SyntheticFeature {
+                r#type: SyntheticType::Statistics(Statistics {
+                    feature: "temperature".to_string(),
+                    window: Duration::from_secs(60 * 60),
+                }),
+                last_update: Utc::now(),
+                value: Value::Null,
+            },
+        );
+
+        let thing = report(thing, json!({"temperature": 20})).await;
+        let thing = report(thing, json!({"temperature": 20})).await;
+
+        // reporting an equal value is a sample too
+        let state = statistics::State::from_value(&thing.synthetic_state["stats"].value);
+        let current = state.current.unwrap();
+        assert_eq!(current.count, 2);
+        assert_eq!(current.mean, 20.0);
+
+        // the reported features are only tracked while processing the change
+        assert!(thing
+            .internal
+            .as_ref()
+            .map_or(true, |internal| internal.reported.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_derived_equal_reports() {
+        let mut thing = test_thing();
+        for (name, r#type) in [
+            (
+                "ema",
+                SyntheticType::Ema(Ema {
+                    feature: "temperature".to_string(),
+                    alpha: serde_json::Number::from_f64(0.5).unwrap(),
+                }),
+            ),
+            (
+                "delta",
+                SyntheticType::Delta(Delta {
+                    feature: "temperature".to_string(),
+                }),
+            ),
+        ] {
+            thing.synthetic_state.insert(
+                name.to_string(),
+                SyntheticFeature {
+                    r#type,
+                    last_update: Utc::now(),
+                    value: Value::Null,
+                },
+            );
+        }
+
+        let thing = report(thing, json!({"temperature": 10})).await;
+        let thing = report(thing, json!({"temperature": 20})).await;
+        assert_eq!(thing.synthetic_state["ema"].value, json!(15.0));
+        assert_eq!(thing.synthetic_state["delta"].value, json!(10.0));
+
+        // reporting an equal value is a sample too
+        let thing = report(thing, json!({"temperature": 20})).await;
+        assert_eq!(thing.synthetic_state["ema"].value, json!(17.5));
+        assert_eq!(thing.synthetic_state["delta"].value, json!(0.0));
+    }
+
     fn test_metadata() -> Metadata {
         Metadata {
             name: "default".to_string(),
@@ -377,10 +704,12 @@ mod test {
         Thing {
             metadata: test_metadata(),
             schema: None,
+            alias_of: None,
             reported_state: Default::default(),
             desired_state: Default::default(),
             synthetic_state: Default::default(),
             reconciliation: Default::default(),
+            conditions: Default::default(),
             internal: Default::default(),
         }
     }
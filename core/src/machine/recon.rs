@@ -1,20 +1,27 @@
 use crate::{
-    command::Command,
+    clock,
+    command::{router::ANNOTATION_COMMAND_ROUTE, Command},
     machine::{
+        controller::CONDITION_CONTROLLER_FAILED,
         deno::{self, DenoOptions, Json},
+        derived,
         desired::{CommandBuilder, Context, DesiredReconciler, FeatureContext},
-        Error, ExecutionResult, OutboxMessage, Outcome, TIMER_DELAY,
+        statistics, Error, ExecutionResult, Options, OutboxMessage, Outcome,
+        CONDITION_COMMAND_LIMIT_EXCEEDED, CONDITION_OUTBOX_DELAYED,
+        CONDITION_OUTBOX_LIMIT_EXCEEDED, CONDITION_TIMER_DELAYED, CONDITION_WAKER_OVERDUE,
+        DELAY_CONDITIONS, OUTBOX_AGE, TIMER_DELAY, WAKER_DELAY,
     },
     model::{
-        Changed, Code, DesiredFeatureMethod, DesiredFeatureReconciliation, DesiredMode, Internal,
-        InternalThingExt, Reconciliation, SyntheticType, Thing, Timer, WakerExt, WakerReason,
+        Changed, Code, CommandResult, ConditionsExt, DesiredFeature, DesiredFeatureMethod,
+        DesiredFeatureReconciliation, DesiredMode, ExpiryExt, Internal, InternalThingExt,
+        Reconciliation, SyntheticType, Thing, Timer, WakerExt, WakerReason,
     },
 };
 use anyhow::anyhow;
 use chrono::{DateTime, Duration, Utc};
 use indexmap::IndexMap;
 use serde_json::Value;
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 use tracing::instrument;
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq, serde::Serialize)]
@@ -31,20 +38,39 @@ pub struct Reconciler {
     deadline: tokio::time::Instant,
     current_thing: Arc<Thing<Internal>>,
     new_thing: Thing<Internal>,
+    /// The features reported by the change, see [`Internal::reported`].
+    reported: BTreeSet<String>,
     outbox: Vec<OutboxMessage>,
     commands: Vec<Command>,
+    options: Options,
 }
 
 impl Reconciler {
-    pub fn new(current_thing: Arc<Thing<Internal>>, new_thing: Thing<Internal>) -> Self {
+    pub fn new(
+        current_thing: Arc<Thing<Internal>>,
+        mut new_thing: Thing<Internal>,
+        options: Options,
+    ) -> Self {
         let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(1);
 
+        let reported = new_thing
+            .internal
+            .as_mut()
+            .map(|internal| std::mem::take(&mut internal.reported))
+            .unwrap_or_default();
+        // don't turn a missing internal state into an empty one, which would be a change
+        if current_thing.internal.is_none() && new_thing.internal == Some(Default::default()) {
+            new_thing.internal = None;
+        }
+
         Self {
             current_thing,
             new_thing,
+            reported,
             deadline,
             outbox: Default::default(),
             commands: Default::default(),
+            options,
         }
     }
 
@@ -56,22 +82,38 @@ impl Reconciler {
         // synthetics
         self.generate_synthetics().await?;
 
-        // run code
-        let Reconciliation {
-            changed,
-            timers,
-            deleting: _,
-        } = self.new_thing.reconciliation.clone();
-        // reconcile changed and timers, but not deleting, as we don't delete
-        self.reconcile_changed(changed).await?;
-        self.reconcile_timers(timers).await?;
-
-        // reconcile desired state
-        self.reconcile_desired_state().await?;
+        if self.new_thing.metadata.is_suspended() {
+            tracing::debug!("Reconciliation suspended");
+        } else {
+            // run code
+            let Reconciliation {
+                changed,
+                timers,
+                deleting: _,
+            } = self.new_thing.reconciliation.clone();
+            // reconcile changed and timers, but not deleting, as we don't delete
+            self.reconcile_changed(changed).await?;
+            self.reconcile_timers(timers).await?;
+
+            // run the external controller
+            self.reconcile_controller().await;
+
+            // reconcile desired state
+            self.reconcile_desired_state().await?;
+        }
 
         // detect reported state changes
         self.sync_reported_state();
 
+        // truncate the outcome, if necessary
+        self.enforce_limits();
+
+        // raise conditions for systemic delays
+        self.detect_delays();
+
+        // wake up when the thing might expire
+        self.schedule_expiry();
+
         Ok(Outcome {
             new_thing: self.new_thing,
             outbox: self.outbox,
@@ -83,6 +125,9 @@ impl Reconciler {
         // clear reconcile waker
         self.new_thing.clear_wakeup(WakerReason::Reconcile);
 
+        // conditions are managed by the system, don't accept changes from the update
+        self.new_thing.conditions = self.current_thing.conditions.clone();
+
         // clear old logs first, otherwise logging of state will continuously grow
         // FIXME: remove when we only send a view of the state to the reconcile code
         for (_, v) in &mut self.new_thing.reconciliation.changed {
@@ -90,12 +135,176 @@ impl Reconciler {
         }
     }
 
+    /// Schedule the expiry of the thing, or clear it if the thing doesn't expire (anymore).
+    fn schedule_expiry(&mut self) {
+        self.new_thing.clear_wakeup(WakerReason::Expiry);
+        if let Some(when) = self.new_thing.expires_at() {
+            self.new_thing.wakeup_at(when, WakerReason::Expiry);
+        }
+    }
+
+    /// Run the external controller, if enabled and requested by the thing.
+    ///
+    /// A failing controller does not fail the reconciliation, but raises a condition.
+    async fn reconcile_controller(&mut self) {
+        let controller = match &self.options.controller {
+            Some(controller) => controller,
+            None => return,
+        };
+
+        match controller
+            .reconcile(&self.current_thing, &mut self.new_thing)
+            .await
+        {
+            Ok(true) => {
+                self.new_thing
+                    .conditions
+                    .clear_condition(CONDITION_CONTROLLER_FAILED);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                self.new_thing.conditions.set_condition_at(
+                    CONDITION_CONTROLLER_FAILED,
+                    "Failed".to_string(),
+                    err.to_string(),
+                    clock::now(),
+                );
+            }
+        }
+    }
+
+    /// Enforce the limits of a single run.
+    ///
+    /// Exceeding a limit truncates the outcome and raises a condition, which gets cleared again
+    /// once a run stays within the limit.
+    fn enforce_limits(&mut self) {
+        Self::enforce_limit(
+            &mut self.new_thing,
+            &mut self.outbox,
+            self.options.limits.max_outbox,
+            CONDITION_OUTBOX_LIMIT_EXCEEDED,
+            "outbox messages",
+        );
+        Self::enforce_limit(
+            &mut self.new_thing,
+            &mut self.commands,
+            self.options.limits.max_commands,
+            CONDITION_COMMAND_LIMIT_EXCEEDED,
+            "commands",
+        );
+    }
+
+    fn enforce_limit<T>(
+        thing: &mut Thing<Internal>,
+        items: &mut Vec<T>,
+        max: usize,
+        condition: &str,
+        what: &str,
+    ) {
+        if items.len() > max {
+            let message = format!("Produced {} {what}, truncated to {max}", items.len());
+            tracing::warn!(
+                application = %thing.metadata.application,
+                thing = %thing.metadata.name,
+                "{message}"
+            );
+            items.truncate(max);
+            thing.conditions.set_condition_at(
+                condition,
+                "Truncated".to_string(),
+                message,
+                clock::now(),
+            );
+        } else {
+            thing.conditions.clear_condition(condition);
+        }
+    }
+
+    /// Detect delays of the waker and the outbox, raising or clearing their conditions.
+    fn detect_delays(&mut self) {
+        let now = clock::now();
+        let internal = self.current_thing.internal.as_ref();
+
+        let waker_delay = internal
+            .and_then(|internal| internal.waker.when)
+            .map(|when| now - when)
+            .filter(|delay| *delay > Duration::zero());
+        if let Some(delay) = waker_delay {
+            WAKER_DELAY.observe(delay.num_milliseconds() as f64);
+        }
+        Self::update_delay_condition(
+            &mut self.new_thing,
+            CONDITION_WAKER_OVERDUE,
+            waker_delay,
+            self.options.alerts.waker_delay,
+            |delay| format!("Woken up late by {delay}"),
+        );
+
+        let outbox_age = internal
+            .into_iter()
+            .flat_map(|internal| &internal.deliveries)
+            .filter(|delivery| !delivery.is_sent())
+            .map(|delivery| delivery.created)
+            .min()
+            .map(|created| now - created);
+        if let Some(age) = outbox_age {
+            OUTBOX_AGE.observe(age.num_milliseconds() as f64);
+        }
+        Self::update_delay_condition(
+            &mut self.new_thing,
+            CONDITION_OUTBOX_DELAYED,
+            outbox_age,
+            self.options.alerts.outbox_age,
+            |age| format!("Oldest undelivered outbox event is {age} old"),
+        );
+    }
+
+    /// Raise a condition if the delay exceeds the threshold, or clear it otherwise.
+    fn update_delay_condition<F>(
+        thing: &mut Thing<Internal>,
+        condition: &str,
+        delay: Option<Duration>,
+        threshold: std::time::Duration,
+        message: F,
+    ) where
+        F: FnOnce(humantime::FormattedDuration) -> String,
+    {
+        let delay = delay
+            .and_then(|delay| delay.to_std().ok())
+            .filter(|delay| *delay > threshold);
+
+        match delay {
+            Some(delay) => {
+                // round to seconds, to prevent changing the message on every run
+                let delay = std::time::Duration::from_secs(delay.as_secs());
+                let message = message(humantime::format_duration(delay));
+                tracing::warn!(
+                    application = %thing.metadata.application,
+                    thing = %thing.metadata.name,
+                    "{message}"
+                );
+                if !thing.conditions.contains_key(condition) {
+                    DELAY_CONDITIONS.with_label_values(&[condition]).inc();
+                }
+                thing.conditions.set_condition_at(
+                    condition,
+                    "Delayed".to_string(),
+                    message,
+                    clock::now(),
+                );
+            }
+            None => {
+                thing.conditions.clear_condition(condition);
+            }
+        }
+    }
+
     /// Synchronize the reported state changes with the previous state
     ///
     /// In case a value changed, the timestamp will be set to "now", otherwise the timestamp
     /// will be copied from the previous value.
     fn sync_reported_state(&mut self) {
-        let now = Utc::now();
+        let now = clock::now();
 
         for (k, next) in &mut self.new_thing.reported_state {
             if let Some(previous) = self.current_thing.reported_state.get(k) {
@@ -112,22 +321,113 @@ impl Reconciler {
 
     #[instrument(skip_all, err)]
     async fn generate_synthetics(&mut self) -> Result<(), Error> {
-        let now = Utc::now();
+        let now = clock::now();
 
         let new_state = Arc::new(self.new_thing.clone());
 
+        let mut wakeup = None;
+
         for (name, mut syn) in &mut self.new_thing.synthetic_state {
-            let value =
-                Self::run_synthetic(name, &syn.r#type, new_state.clone(), self.deadline).await?;
+            let value = match &syn.r#type {
+                SyntheticType::Statistics(statistics) => {
+                    let sample = Self::sample(
+                        &self.current_thing,
+                        &new_state,
+                        &self.reported,
+                        &statistics.feature,
+                    );
+
+                    let mut state = statistics::State::from_value(&syn.value);
+                    let end = state.update(statistics.window, sample, now);
+                    // wake up when the window ends, to roll over
+                    if state.current.is_some() || state.previous.is_some() {
+                        wakeup = Some(wakeup.map_or(end, |w: DateTime<Utc>| w.min(end)));
+                    }
+                    state.to_value()
+                }
+                SyntheticType::Ema(ema) => derived::ema(
+                    &syn.value,
+                    Self::sample(
+                        &self.current_thing,
+                        &new_state,
+                        &self.reported,
+                        &ema.feature,
+                    ),
+                    ema.alpha.as_f64().unwrap_or_default(),
+                ),
+                SyntheticType::Delta(delta) => {
+                    let sample = Self::sample(
+                        &self.current_thing,
+                        &new_state,
+                        &self.reported,
+                        &delta.feature,
+                    );
+                    let last = self
+                        .current_thing
+                        .reported_state
+                        .get(&delta.feature)
+                        .and_then(|reported| reported.value.as_f64());
+                    derived::delta(&syn.value, last, sample)
+                }
+                SyntheticType::Threshold(threshold) => {
+                    let upper = threshold.upper.as_f64().unwrap_or_default();
+                    derived::threshold(
+                        &syn.value,
+                        new_state
+                            .reported_state
+                            .get(&threshold.feature)
+                            .and_then(|reported| reported.value.as_f64()),
+                        upper,
+                        threshold
+                            .lower
+                            .as_ref()
+                            .and_then(|lower| lower.as_f64())
+                            .unwrap_or(upper),
+                    )
+                }
+                // resolved outside the machine, see [`crate::reference`]
+                SyntheticType::Reference(_) => syn.value.clone(),
+                r#type => {
+                    Self::run_synthetic(name, r#type, new_state.clone(), self.deadline).await?
+                }
+            };
             if syn.value != value {
                 syn.value = value;
                 syn.last_update = now;
             }
         }
 
+        if let Some(wakeup) = wakeup {
+            self.new_thing.wakeup_at(wakeup, WakerReason::Reconcile);
+        }
+
         Ok(())
     }
 
+    /// Get the numeric value of a reported feature, if it got reported or updated with this
+    /// change.
+    ///
+    /// Reporting an unchanged value keeps the last update of the feature, but still is a sample.
+    fn sample(
+        current_thing: &Thing<Internal>,
+        new_thing: &Thing<Internal>,
+        reported: &BTreeSet<String>,
+        feature: &str,
+    ) -> Option<f64> {
+        new_thing
+            .reported_state
+            .get(feature)
+            .filter(|new| {
+                reported.contains(feature)
+                    || current_thing
+                        .reported_state
+                        .get(feature)
+                        .map(|current| current.last_update != new.last_update)
+                        .unwrap_or(true)
+            })
+            .and_then(|new| new.value.as_f64())
+    }
+
     /// sync the state with the reported and expected state
     fn sync_desired_state(&mut self) -> Result<(), Error> {
         let mut waker = self.new_thing.waker();
@@ -156,10 +456,13 @@ impl Reconciler {
                     // desired value changed, start reconciling again
                     desired.reconciliation =
                         DesiredFeatureReconciliation::Reconciling { last_attempt: None };
-                    desired.last_update = Utc::now();
+                    desired.last_update = clock::now();
+                    desired.last_result = None;
                 }
             }
 
+            let acknowledged = acknowledged(desired);
+
             if matches!(desired.method, DesiredFeatureMethod::Manual) {
                 continue;
             }
@@ -174,21 +477,25 @@ impl Reconciler {
                 (_, DesiredMode::Disabled) => {
                     // ... mark disabled
                     desired.reconciliation =
-                        DesiredFeatureReconciliation::Disabled { when: Utc::now() };
+                        DesiredFeatureReconciliation::Disabled { when: clock::now() };
                 }
 
                 // Mode is not disabled, but we are are
                 (DesiredFeatureReconciliation::Disabled { .. }, _) => {
                     if reported_value != desired_value {
                         // not the same
-                        if desired.valid_until.map(|u| u > Utc::now()).unwrap_or(true) {
+                        if desired
+                            .valid_until
+                            .map(|u| u > clock::now())
+                            .unwrap_or(true)
+                        {
                             // the value is still valid, back to reconciling
                             desired.reconciliation =
                                 DesiredFeatureReconciliation::Reconciling { last_attempt: None };
                         } else {
                             // the value is no longer valid
                             desired.reconciliation = DesiredFeatureReconciliation::Failed {
-                                when: Utc::now(),
+                                when: clock::now(),
                                 reason: Some(
                                     "Activated reconciliation with expired value".to_string(),
                                 ),
@@ -197,7 +504,7 @@ impl Reconciler {
                     } else {
                         // equals => means success
                         desired.reconciliation =
-                            DesiredFeatureReconciliation::Succeeded { when: Utc::now() }
+                            DesiredFeatureReconciliation::Succeeded { when: clock::now() }
                     }
                 }
 
@@ -205,7 +512,10 @@ impl Reconciler {
                 (DesiredFeatureReconciliation::Succeeded { .. }, DesiredMode::Sync) => {
                     // if we should keep it in sync, check values and if the value is still valid
                     if reported_value != desired_value
-                        && desired.valid_until.map(|u| u > Utc::now()).unwrap_or(true)
+                        && desired
+                            .valid_until
+                            .map(|u| u > clock::now())
+                            .unwrap_or(true)
                     {
                         // if not, back to reconciling
                         desired.reconciliation =
@@ -225,16 +535,30 @@ impl Reconciler {
                 }
 
                 // we are reconciling
-                (DesiredFeatureReconciliation::Reconciling { .. }, _) => {
+                (DesiredFeatureReconciliation::Reconciling { .. }, mode) => {
                     if reported_value == desired_value {
                         // value changed to expected value -> success
                         desired.reconciliation =
-                            DesiredFeatureReconciliation::Succeeded { when: Utc::now() };
+                            DesiredFeatureReconciliation::Succeeded { when: clock::now() };
+                    } else if let Some(result) = acknowledged.filter(|result| {
+                        // when keeping in sync, we still wait for the reported state
+                        !result.success || matches!(mode, DesiredMode::Once)
+                    }) {
+                        // the device acknowledged the command
+                        desired.reconciliation = match result.success {
+                            true => DesiredFeatureReconciliation::Succeeded { when: result.when },
+                            false => DesiredFeatureReconciliation::Failed {
+                                when: result.when,
+                                reason: result
+                                    .reason
+                                    .or_else(|| Some("Command failed".to_string())),
+                            },
+                        };
                     } else if let Some(valid_until) = desired.valid_until {
                         // value did not change to expected value, and expired -> failure
-                        if valid_until < Utc::now() {
+                        if valid_until < clock::now() {
                             desired.reconciliation = DesiredFeatureReconciliation::Failed {
-                                when: Utc::now(),
+                                when: clock::now(),
                                 reason: None,
                             };
                         } else {
@@ -320,7 +644,14 @@ impl Reconciler {
 
         self.commands.extend(
             commands
-                .into_commands(&self.new_thing.metadata.application)
+                .into_commands(
+                    &self.new_thing.metadata.application,
+                    self.new_thing
+                        .metadata
+                        .annotations
+                        .get(ANNOTATION_COMMAND_ROUTE)
+                        .map(String::as_str),
+                )
                 .map_err(|err| Error::Reconcile(anyhow!(err)))?,
         );
 
@@ -334,6 +665,12 @@ impl Reconciler {
     #[instrument(skip_all, err)]
     async fn reconcile_changed(&mut self, changed: IndexMap<String, Changed>) -> Result<(), Error> {
         for (name, mut changed) in changed {
+            if !features_changed(&self.current_thing, &self.new_thing, &changed.features) {
+                // none of the features of interest changed, keep it as it is
+                self.new_thing.reconciliation.changed.insert(name, changed);
+                continue;
+            }
+
             let ExecutionResult { logs } = self
                 .run_code(
                     format!("changed-{}", name),
@@ -351,6 +688,15 @@ impl Reconciler {
 
     #[instrument(skip_all, err)]
     async fn reconcile_timers(&mut self, timers: IndexMap<String, Timer>) -> Result<(), Error> {
+        if timers.is_empty() {
+            self.new_thing
+                .conditions
+                .clear_condition(CONDITION_TIMER_DELAYED);
+        }
+
+        // the most delayed timer of this run, `None` if no timer ran
+        let mut delayed: Option<(String, Duration)> = None;
+
         for (name, mut timer) in timers {
             let due = match timer.stopped {
                 true => {
@@ -361,7 +707,7 @@ impl Reconciler {
                 false => {
                     let last_started = match timer.last_started {
                         None => {
-                            let now = Utc::now();
+                            let now = clock::now();
                             timer.last_started = Some(now);
                             now
                         }
@@ -380,7 +726,7 @@ impl Reconciler {
                         }
                         (None, None) => {
                             // timer never ran, and there is no delay, run now
-                            Some(Utc::now())
+                            Some(clock::now())
                         }
                         (None, Some(initial_delay)) => {
                             // timer never ran, check it the first run is due
@@ -395,13 +741,16 @@ impl Reconciler {
             };
 
             if let Some(due) = due {
-                let diff = Utc::now() - due;
+                let diff = clock::now() - due;
 
                 let next_run = if diff >= Duration::zero() {
-                    log::debug!("Late by: {diff}");
+                    tracing::debug!(late_by = %diff, "Running timer");
                     TIMER_DELAY.observe(diff.num_milliseconds() as f64);
+                    if delayed.as_ref().map_or(true, |(_, max)| diff > *max) {
+                        delayed = Some((name.clone(), diff));
+                    }
 
-                    let now = Utc::now();
+                    let now = clock::now();
 
                     self.run_code(format!("timer-{}", name), ScriptAction::Timer, &timer.code)
                         .await?;
@@ -409,7 +758,7 @@ impl Reconciler {
                     let next_run =
                         Self::find_next_run(timer.last_started.unwrap_or(now), timer.period);
 
-                    log::info!("Next run: {next_run}");
+                    tracing::debug!(%next_run, "Scheduled next run");
 
                     timer.last_run = Some(now);
 
@@ -424,10 +773,20 @@ impl Reconciler {
             self.new_thing.reconciliation.timers.insert(name, timer);
         }
 
+        if let Some((name, delay)) = delayed {
+            Self::update_delay_condition(
+                &mut self.new_thing,
+                CONDITION_TIMER_DELAYED,
+                Some(delay),
+                self.options.alerts.timer_delay,
+                |delay| format!("Timer '{name}' ran late by {delay}"),
+            );
+        }
+
         Ok(())
     }
 
-    #[instrument(skip(self, code), err)]
+    #[instrument(skip(self, name, code), fields(script = %name), err)]
     async fn run_code(
         &mut self,
         name: String,
@@ -502,7 +861,7 @@ impl Reconciler {
         }
     }
 
-    #[instrument(skip(r#type, new_state), ret, err)]
+    #[instrument(skip(name, r#type, new_state), fields(script = %name), ret, err)]
     async fn run_synthetic(
         name: &str,
         r#type: &SyntheticType,
@@ -534,6 +893,12 @@ impl Reconciler {
                 Some(value) => Ok(value.value.clone()),
                 None => Ok(Value::Null),
             },
+            // types which need the previous state, or other things, are handled by the caller
+            SyntheticType::Statistics(_)
+            | SyntheticType::Ema(_)
+            | SyntheticType::Delta(_)
+            | SyntheticType::Threshold(_)
+            | SyntheticType::Reference(_) => Ok(Value::Null),
         }
     }
 
@@ -546,7 +911,7 @@ impl Reconciler {
         let diff = (now - last_started).num_milliseconds();
 
         if diff < 0 {
-            return Utc::now();
+            return clock::now();
         }
 
         let diff = diff.clamp(0, u32::MAX as i64) as u32;
@@ -556,10 +921,50 @@ impl Reconciler {
     }
 
     fn find_next_run(last_started: DateTime<Utc>, period: std::time::Duration) -> DateTime<Utc> {
-        Self::find_next_run_from(last_started, period, Utc::now())
+        Self::find_next_run_from(last_started, period, clock::now())
     }
 }
 
+/// The result of the last command, if the device acknowledged the current reconciliation attempt.
+fn acknowledged(desired: &DesiredFeature) -> Option<CommandResult> {
+    let acknowledge = match &desired.method {
+        DesiredFeatureMethod::Command(command) => command.acknowledge,
+        _ => false,
+    };
+
+    match (&desired.reconciliation, &desired.last_result) {
+        (
+            DesiredFeatureReconciliation::Reconciling {
+                last_attempt: Some(last_attempt),
+            },
+            Some(result),
+        ) if acknowledge && result.when >= *last_attempt => Some(result.clone()),
+        _ => None,
+    }
+}
+
+/// The effective value of a feature, synthetic values taking precedence over reported ones.
+fn feature_value<'t>(thing: &'t Thing<Internal>, name: &str) -> Option<&'t Value> {
+    thing
+        .synthetic_state
+        .get(name)
+        .map(|feature| &feature.value)
+        .or_else(|| thing.reported_state.get(name).map(|feature| &feature.value))
+}
+
+/// Check if the value of one of the features changed, which is always the case if there are no
+/// features of interest.
+fn features_changed(
+    current_thing: &Thing<Internal>,
+    new_thing: &Thing<Internal>,
+    features: &[String],
+) -> bool {
+    features.is_empty()
+        || features
+            .iter()
+            .any(|name| feature_value(current_thing, name) != feature_value(new_thing, name))
+}
+
 #[cfg(test)]
 mod test {
 
@@ -591,4 +996,68 @@ mod test {
             day.and_hms(expected.0, expected.1, expected.2)
         );
     }
+
+    #[test]
+    fn test_delay_condition() {
+        let mut thing = Thing::new("default", "thing");
+        let threshold = Duration::from_secs(60);
+
+        Reconciler::update_delay_condition(
+            &mut thing,
+            CONDITION_WAKER_OVERDUE,
+            Some(chrono::Duration::milliseconds(90_500)),
+            threshold,
+            |delay| format!("Late by {delay}"),
+        );
+        let condition = thing.conditions.get(CONDITION_WAKER_OVERDUE).unwrap();
+        assert_eq!(condition.message.as_deref(), Some("Late by 1m 30s"));
+
+        // within the threshold
+        Reconciler::update_delay_condition(
+            &mut thing,
+            CONDITION_WAKER_OVERDUE,
+            Some(chrono::Duration::seconds(30)),
+            threshold,
+            |delay| format!("Late by {delay}"),
+        );
+        assert!(thing.conditions.is_empty());
+    }
+
+    #[test]
+    fn test_features_changed() {
+        use crate::model::{ReportedFeature, SyntheticFeature};
+        use serde_json::json;
+
+        let mut current = Thing::new("default", "thing");
+        current
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(42)));
+        current
+            .reported_state
+            .insert("humidity".into(), ReportedFeature::now(json!(50)));
+
+        let mut new = current.clone();
+        new.reported_state
+            .insert("humidity".into(), ReportedFeature::now(json!(55)));
+
+        assert!(features_changed(&current, &new, &[]));
+        assert!(!features_changed(&current, &new, &["temperature".into()]));
+        assert!(features_changed(
+            &current,
+            &new,
+            &["temperature".into(), "humidity".into()]
+        ));
+        // missing on both sides
+        assert!(!features_changed(&current, &new, &["warning".into()]));
+        new.synthetic_state.insert(
+            "warning".into(),
+            SyntheticFeature {
+                r#type: SyntheticType::Alias("temperature".into()),
+                last_update: Utc::now(),
+                value: json!(true),
+            },
+        );
+        // new features count as changed
+        assert!(features_changed(&current, &new, &["warning".into()]));
+    }
 }
@@ -0,0 +1,196 @@
+//! Rolling statistics of reported features.
+//!
+//! Statistics are maintained over tumbling windows, aligned to the epoch. Samples are added
+//! incrementally, so that the raw values don't need to be kept.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::Value;
+
+/// The state of a statistics synthetic feature.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    /// The currently active window.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<Window>,
+    /// The last completed window, if it directly precedes the current one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Window>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl Window {
+    fn new(start: DateTime<Utc>, value: f64) -> Self {
+        Self {
+            start,
+            count: 1,
+            min: value,
+            max: value,
+            mean: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.mean += (value - self.mean) / self.count as f64;
+    }
+}
+
+impl State {
+    /// Parse the state from the value of the synthetic feature, starting fresh if it is invalid.
+    pub fn from_value(value: &Value) -> Self {
+        serde_json::from_value(value.clone()).unwrap_or_default()
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Update the state, returning the end of the current window.
+    pub fn update(
+        &mut self,
+        window: std::time::Duration,
+        sample: Option<f64>,
+        now: DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        let window = Duration::from_std(window)
+            .ok()
+            .filter(|window| window.num_milliseconds() > 0)
+            .unwrap_or_else(|| Duration::milliseconds(1));
+        let start = window_start(window, now);
+
+        self.roll(window, start);
+
+        if let Some(value) = sample.filter(|value| value.is_finite()) {
+            match &mut self.current {
+                Some(current) => current.add(value),
+                None => self.current = Some(Window::new(start, value)),
+            }
+        }
+
+        start + window
+    }
+
+    /// Move windows which ended, dropping those which are outdated.
+    fn roll(&mut self, window: Duration, start: DateTime<Utc>) {
+        let preceding = start - window;
+
+        for w in [self.previous.take(), self.current.take()]
+            .into_iter()
+            .flatten()
+        {
+            if w.start == start {
+                self.current = Some(w);
+            } else if w.start == preceding {
+                self.previous = Some(w);
+            }
+        }
+    }
+}
+
+/// The start of the window containing `now`.
+fn window_start(window: Duration, now: DateTime<Utc>) -> DateTime<Utc> {
+    let window = window.num_milliseconds();
+    let now = now.timestamp_millis();
+    Utc.timestamp_millis(now - now.rem_euclid(window))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Utc.ymd(2022, 1, 1).and_hms(h, m, s)
+    }
+
+    const MINUTE: std::time::Duration = std::time::Duration::from_secs(60);
+
+    #[test]
+    fn test_window_start() {
+        assert_eq!(
+            window_start(Duration::minutes(1), at(1, 2, 30)),
+            at(1, 2, 0)
+        );
+        assert_eq!(window_start(Duration::hours(1), at(1, 2, 30)), at(1, 0, 0));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let mut state = State::default();
+
+        assert_eq!(state.update(MINUTE, Some(1.0), at(0, 0, 10)), at(0, 1, 0));
+        assert_eq!(state.update(MINUTE, Some(5.0), at(0, 0, 20)), at(0, 1, 0));
+        assert_eq!(state.update(MINUTE, None, at(0, 0, 30)), at(0, 1, 0));
+        assert_eq!(state.update(MINUTE, Some(3.0), at(0, 0, 40)), at(0, 1, 0));
+
+        assert_eq!(
+            state,
+            State {
+                current: Some(Window {
+                    start: at(0, 0, 0),
+                    count: 3,
+                    min: 1.0,
+                    max: 5.0,
+                    mean: 3.0,
+                }),
+                previous: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_roll() {
+        let mut state = State::default();
+
+        state.update(MINUTE, Some(1.0), at(0, 0, 10));
+        state.update(MINUTE, Some(2.0), at(0, 1, 10));
+
+        assert_eq!(
+            state,
+            State {
+                current: Some(Window::new(at(0, 1, 0), 2.0)),
+                previous: Some(Window::new(at(0, 0, 0), 1.0)),
+            }
+        );
+
+        // no sample, but time moved on
+        state.update(MINUTE, None, at(0, 2, 10));
+        assert_eq!(
+            state,
+            State {
+                current: None,
+                previous: Some(Window::new(at(0, 1, 0), 2.0)),
+            }
+        );
+
+        // a gap drops everything
+        state.update(MINUTE, Some(3.0), at(0, 10, 0));
+        assert_eq!(
+            state,
+            State {
+                current: Some(Window::new(at(0, 10, 0), 3.0)),
+                previous: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        assert_eq!(State::from_value(&Value::Null), State::default());
+        assert_eq!(
+            State::from_value(&serde_json::json!({"foo": "bar"})),
+            State::default()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{clock, processor::Event};
+use chrono::{DateTime, Utc};
+
+/// Number of sent events kept in the delivery history.
+pub const DELIVERY_HISTORY: usize = 10;
+
+/// The delivery state of an outbox event.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    /// The ID of the event.
+    pub id: String,
+    /// The thing the event is sent to.
+    pub thing: String,
+    /// When the event was created.
+    pub created: DateTime<Utc>,
+    /// The number of attempts to send the event.
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(flatten)]
+    pub status: DeliveryStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(tag = "status")]
+pub enum DeliveryStatus {
+    /// Waiting to be sent.
+    Pending,
+    /// Successfully sent.
+    Sent { timestamp: DateTime<Utc> },
+    /// The last attempt failed, the event will be retried.
+    Failed {
+        timestamp: DateTime<Utc>,
+        error: String,
+    },
+}
+
+impl Delivery {
+    pub fn new(event: &Event) -> Self {
+        Self {
+            id: event.id.clone(),
+            thing: event.thing.clone(),
+            created: event.timestamp,
+            attempts: 0,
+            status: DeliveryStatus::Pending,
+        }
+    }
+
+    pub fn is_sent(&self) -> bool {
+        matches!(self.status, DeliveryStatus::Sent { .. })
+    }
+}
+
+pub trait DeliveryExt {
+    /// Record that the events were sent.
+    fn delivered<'a, I>(&mut self, events: I)
+    where
+        I: IntoIterator<Item = &'a Event>;
+
+    /// Record that sending an event failed.
+    fn failed(&mut self, event: &Event, error: String);
+}
+
+impl DeliveryExt for Vec<Delivery> {
+    fn delivered<'a, I>(&mut self, events: I)
+    where
+        I: IntoIterator<Item = &'a Event>,
+    {
+        let timestamp = clock::now();
+        for event in events {
+            let delivery = find_or_insert(self, event);
+            delivery.attempts += 1;
+            delivery.status = DeliveryStatus::Sent { timestamp };
+        }
+
+        // only keep the most recent sent events, pending and failed ones stay
+
+        let mut sent = self.iter().filter(|delivery| delivery.is_sent()).count();
+        self.retain(|delivery| {
+            if sent > DELIVERY_HISTORY && delivery.is_sent() {
+                sent -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn failed(&mut self, event: &Event, error: String) {
+        let delivery = find_or_insert(self, event);
+        delivery.attempts += 1;
+        delivery.status = DeliveryStatus::Failed {
+            timestamp: clock::now(),
+            error,
+        };
+    }
+}
+
+fn find_or_insert<'a>(deliveries: &'a mut Vec<Delivery>, event: &Event) -> &'a mut Delivery {
+    match deliveries
+        .iter()
+        .position(|delivery| delivery.id == event.id)
+    {
+        Some(n) => &mut deliveries[n],
+        None => {
+            deliveries.push(Delivery::new(event));
+            // unwrap is safe, as we just pushed an element
+            deliveries.last_mut().unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::Message;
+
+    fn event(n: usize) -> Event {
+        let mut event = Event::new("default", "thing", Message::report_state(true));
+        event.id = format!("event-{n}");
+        event
+    }
+
+    #[test]
+    fn test_status() {
+        let events: Vec<_> = (0..3).map(event).collect();
+        let mut deliveries: Vec<_> = events.iter().map(Delivery::new).collect();
+
+        deliveries.delivered(&events[0..1]);
+        deliveries.failed(&events[1], "Failed".to_string());
+
+        assert!(deliveries[0].is_sent());
+        assert_eq!(deliveries[0].attempts, 1);
+        assert!(matches!(
+            &deliveries[1].status,
+            DeliveryStatus::Failed { error, .. } if error == "Failed"
+        ));
+        assert_eq!(deliveries[1].attempts, 1);
+        assert_eq!(deliveries[2].status, DeliveryStatus::Pending);
+        assert_eq!(deliveries[2].attempts, 0);
+
+        deliveries.delivered(&events[1..]);
+        assert!(deliveries.iter().all(Delivery::is_sent));
+        assert_eq!(deliveries[1].attempts, 2);
+    }
+
+    #[test]
+    fn test_history() {
+        let events: Vec<_> = (0..DELIVERY_HISTORY + 5).map(event).collect();
+        let mut deliveries = vec![Delivery::new(&event(100))];
+
+        deliveries.delivered(&events);
+
+        assert_eq!(deliveries.len(), DELIVERY_HISTORY + 1);
+        // pending ones are kept
+        assert_eq!(deliveries[0].id, "event-100");
+        // the oldest sent ones are dropped
+        assert_eq!(deliveries[1].id, "event-5");
+    }
+}
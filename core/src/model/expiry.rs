@@ -0,0 +1,92 @@
+use super::*;
+use crate::clock;
+use chrono::{DateTime, Duration, Utc};
+
+/// Expiry of things, see [`ANNOTATION_EXPIRES_AFTER`].
+pub trait ExpiryExt {
+    /// The time the thing expires, if it has an expiry.
+    ///
+    /// This is measured from the last report of the state, even if the values didn't change, the
+    /// creation of the thing, or now if the thing isn't created yet.
+    fn expires_at(&self) -> Option<DateTime<Utc>>;
+
+    /// Check if the thing has expired.
+    fn is_expired(&self) -> bool {
+        self.expires_at()
+            .map(|when| when <= clock::now())
+            .unwrap_or_default()
+    }
+}
+
+impl ExpiryExt for Thing<Internal> {
+    fn expires_at(&self) -> Option<DateTime<Utc>> {
+        let expires_after = Duration::from_std(self.metadata.expires_after()?).ok()?;
+
+        // unchanged values keep their last update, so check for the last report too
+        let last_activity = self
+            .reported_state
+            .values()
+            .map(|feature| feature.last_update)
+            .chain(
+                self.internal
+                    .as_ref()
+                    .and_then(|internal| internal.last_report),
+            )
+            .max()
+            .or(self.metadata.creation_timestamp)
+            .unwrap_or_else(clock::now);
+
+        last_activity.checked_add_signed(expires_after)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_expires_at() {
+        let mut thing = Thing::<Internal>::new("default", "thing1");
+        thing.metadata.creation_timestamp = Some(Utc.ymd(2022, 1, 1).and_hms(0, 0, 0));
+        assert_eq!(thing.expires_at(), None);
+
+        thing
+            .metadata
+            .annotations
+            .insert(ANNOTATION_EXPIRES_AFTER.into(), "1d".into());
+        assert_eq!(
+            thing.expires_at(),
+            Some(Utc.ymd(2022, 1, 2).and_hms(0, 0, 0))
+        );
+        assert!(thing.is_expired());
+
+        // reported state extends the lifetime
+        thing.reported_state.insert(
+            "temperature".into(),
+            ReportedFeature {
+                last_update: Utc.ymd(2022, 1, 5).and_hms(12, 0, 0),
+                value: 42.into(),
+            },
+        );
+        assert_eq!(
+            thing.expires_at(),
+            Some(Utc.ymd(2022, 1, 6).and_hms(12, 0, 0))
+        );
+
+        // so does reporting an unchanged value
+        thing.set_last_report(Utc.ymd(2022, 1, 7).and_hms(0, 0, 0));
+        assert_eq!(
+            thing.expires_at(),
+            Some(Utc.ymd(2022, 1, 8).and_hms(0, 0, 0))
+        );
+
+        // invalid values are ignored
+        thing
+            .metadata
+            .annotations
+            .insert(ANNOTATION_EXPIRES_AFTER.into(), "soon".into());
+        assert_eq!(thing.expires_at(), None);
+        assert!(!thing.is_expired());
+    }
+}
@@ -1,9 +1,19 @@
+mod delivery;
+mod expiry;
 mod waker;
 
+pub use delivery::*;
 pub use drogue_doppelgaenger_model::*;
+pub use expiry::*;
 pub use waker::*;
 
 use crate::processor::Event;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Number of processed event IDs kept, for detecting redelivered events.
+pub const PROCESSED_HISTORY: usize = 32;
 
 #[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,6 +22,24 @@ pub struct Internal {
     pub waker: Waker,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub outbox: Vec<Event>,
+    /// The delivery state of recent outbox events.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deliveries: Vec<Delivery>,
+    /// The IDs of the most recent events, which are not idempotent, applied to the thing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub processed: Vec<String>,
+    /// The time the thing was last modified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    /// The time the state of the thing was last reported, even if it didn't change.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_report: Option<DateTime<Utc>>,
+    /// The features reported by the change being processed, even if their values didn't change.
+    ///
+    /// Set by the [`crate::service::ReportedStateUpdater`], and taken by the reconciliation, so it
+    /// never gets persisted.
+    #[serde(skip)]
+    pub reported: BTreeSet<String>,
 }
 
 impl Internal {
@@ -19,9 +47,27 @@ impl Internal {
         internal.as_ref().map(Internal::is_empty).unwrap_or(true)
     }
 
+    /// Check if there is no pending work, a waker or outbox events.
+    ///
+    /// The history (deliveries, processed events, timestamps) doesn't count, it is persisted
+    /// using [`InternalThingExt::to_value`].
     pub fn is_empty(&self) -> bool {
         self.waker.is_empty() & self.outbox.is_empty()
     }
+
+    /// Check if the event was already applied to the thing.
+    pub fn is_processed(&self, event_id: &str) -> bool {
+        self.processed.iter().any(|id| id == event_id)
+    }
+
+    /// Record that the event was applied, only keeping the most recent events.
+    pub fn processed(&mut self, event_id: &str) {
+        self.processed.push(event_id.to_string());
+        if self.processed.len() > PROCESSED_HISTORY {
+            self.processed
+                .drain(..self.processed.len() - PROCESSED_HISTORY);
+        }
+    }
 }
 
 impl InternalState for Internal {
@@ -36,6 +82,16 @@ pub trait InternalThingExt {
     /// Set the waker state, creating an internal if necessary
     fn set_waker(&mut self, waker: Waker);
     fn outbox(&self) -> &[Event];
+    /// The time the thing was last modified, falling back to its creation.
+    fn last_modified(&self) -> Option<DateTime<Utc>>;
+    /// Set the modification time, creating an internal if necessary.
+    fn set_modified(&mut self, modified: Option<DateTime<Utc>>);
+    /// Record that the state was reported, creating an internal if necessary.
+    fn set_last_report(&mut self, when: DateTime<Utc>);
+    /// Serialize the thing, including the full internal state.
+    ///
+    /// Serializing the thing itself only includes the internal state if there is pending work.
+    fn to_value(&self) -> Result<Value, serde_json::Error>;
 }
 
 impl InternalThingExt for Thing<Internal> {
@@ -72,4 +128,38 @@ impl InternalThingExt for Thing<Internal> {
             .map(|internal| internal.outbox.as_slice())
             .unwrap_or(&EMPTY)
     }
+
+    fn last_modified(&self) -> Option<DateTime<Utc>> {
+        self.internal
+            .as_ref()
+            .and_then(|internal| internal.modified)
+            .or(self.metadata.creation_timestamp)
+    }
+
+    fn set_modified(&mut self, modified: Option<DateTime<Utc>>) {
+        match &mut self.internal {
+            Some(internal) => internal.modified = modified,
+            None if modified.is_some() => {
+                self.internal = Some(Internal {
+                    modified,
+                    ..Default::default()
+                })
+            }
+            None => {}
+        }
+    }
+
+    fn set_last_report(&mut self, when: DateTime<Utc>) {
+        self.internal
+            .get_or_insert_with(Default::default)
+            .last_report = Some(when);
+    }
+
+    fn to_value(&self) -> Result<Value, serde_json::Error> {
+        let mut value = serde_json::to_value(self)?;
+        if let (Some(internal), Value::Object(map)) = (&self.internal, &mut value) {
+            map.insert("internal".to_string(), serde_json::to_value(internal)?);
+        }
+        Ok(value)
+    }
 }
@@ -1,4 +1,5 @@
 use super::*;
+use crate::clock;
 use chrono::{DateTime, Duration, Utc};
 use std::collections::BTreeSet;
 
@@ -6,7 +7,7 @@ pub trait WakerExt {
     fn wakeup_at(&mut self, when: DateTime<Utc>, reason: WakerReason);
 
     fn wakeup(&mut self, delay: Duration, reason: WakerReason) {
-        self.wakeup_at(Utc::now() + delay, reason);
+        self.wakeup_at(clock::now() + delay, reason);
     }
 
     fn clear_wakeup(&mut self, reason: WakerReason);
@@ -85,4 +86,8 @@ pub enum WakerReason {
     Reconcile,
     Outbox,
     Deletion,
+    /// The thing might have expired, see [`ANNOTATION_EXPIRES_AFTER`].
+    Expiry,
+    /// A state report, held back by the rate limit, needs to be applied.
+    RateLimit,
 }
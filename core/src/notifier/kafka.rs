@@ -1,5 +1,8 @@
 use super::*;
-use crate::config::kafka::KafkaProperties;
+use crate::config::{
+    check::{Check, Checker},
+    kafka::KafkaProperties,
+};
 use crate::kafka::AddHeader;
 use crate::model::Metadata;
 use crate::notifier;
@@ -19,6 +22,20 @@ pub struct Config {
     pub topic: String,
     #[serde(with = "humantime_serde", default = "default::timeout")]
     pub timeout: Duration,
+    /// The payload of the notifications.
+    #[serde(default)]
+    pub payload: Payload,
+    /// The format of the notifications.
+    #[serde(default)]
+    pub format: Format,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .kafka("properties", &self.properties)
+            .not_empty("topic", &self.topic);
+    }
 }
 
 mod default {
@@ -37,6 +54,8 @@ pub struct Notifier {
     producer: FutureProducer,
     topic: String,
     timeout: Timeout,
+    payload: Payload,
+    format: Format,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -60,6 +79,8 @@ impl super::Notifier for Notifier {
 
     fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
         let topic = config.topic.clone();
+        let payload = config.payload;
+        let format = config.format;
         let timeout = Timeout::After(config.timeout);
         let config: rdkafka::ClientConfig = KafkaProperties(config.properties.clone()).into();
         let producer = FutureProducer::from_config(&config)?;
@@ -68,11 +89,33 @@ impl super::Notifier for Notifier {
             producer,
             topic,
             timeout,
+            payload,
+            format,
         })
     }
 
     #[instrument(skip_all, err)]
     async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project(thing)).await
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project_change(current, thing))
+            .await
+    }
+}
+
+impl Notifier {
+    async fn send(
+        &self,
+        thing: &Thing<Internal>,
+        payload: Projection<'_>,
+    ) -> Result<(), notifier::Error<Error>> {
         let Metadata {
             application, name, ..
         } = &thing.metadata;
@@ -81,12 +124,13 @@ impl super::Notifier for Notifier {
 
         let headers = OwnedHeaders::new()
             .add("application", application)
-            .add("thing", name);
+            .add("thing", name)
+            .add("content-type", self.format.content_type());
 
         let key = format!("{application}/{name}");
-        let payload = serde_json::to_string(&thing).map_err(Error::Serializer)?;
+        let payload = encode_payload(thing, &payload, self.format).map_err(Error::Serializer)?;
 
-        let msg = FutureRecord::<String, String>::to(&self.topic)
+        let msg = FutureRecord::<String, Vec<u8>>::to(&self.topic)
             .key(&key)
             .headers(headers)
             .payload(&payload);
@@ -1,10 +1,22 @@
 pub mod kafka;
+pub mod multi;
+pub mod nats;
+pub mod postgres;
+pub mod redis;
+pub mod webhook;
 
-use crate::model::{Internal, Thing};
+use crate::events::{self, Format};
+use crate::model::{DesiredFeature, Internal, ReportedFeature, Thing, ThingState};
 use crate::service::Id;
 use async_trait::async_trait;
+use chrono::Utc;
+use serde::{de::Error as _, Deserialize};
+use serde_json::Value;
 use std::fmt::Debug;
 
+/// With [`Payload::Patch`], every n-th generation is sent as full thing, instead of a patch.
+pub const PATCH_SNAPSHOT_INTERVAL: u32 = 10;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error<E> {
     #[error("Sender: {0}")]
@@ -19,4 +31,347 @@ pub trait Notifier: Sized + Send + Sync + 'static {
     fn from_config(config: &Self::Config) -> anyhow::Result<Self>;
 
     async fn notify(&self, thing: &Thing<Internal>) -> Result<(), Error<Self::Error>>;
+
+    /// Notify about a change of a thing, from its previous state.
+    ///
+    /// By default, this is the same as [`Self::notify`]. Notifiers supporting [`Payload::Patch`]
+    /// send the difference to the previous state instead.
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), Error<Self::Error>> {
+        let _ = current;
+        self.notify(thing).await
+    }
+}
+
+/// The payload of a change notification.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Payload {
+    /// The full thing, including its internal state.
+    #[default]
+    Full,
+    /// The thing, without its internal state and reconciliation code.
+    Thing,
+    /// Only the values of the features, see [`ThingState`].
+    State,
+    /// A JSON patch, from the previous to the new state of the thing (see [`ThingPatch`]), both
+    /// without the internal state and reconciliation code.
+    ///
+    /// Things which got created or deleted, and every [`PATCH_SNAPSHOT_INTERVAL`]-th generation,
+    /// are sent as full thing, so that receivers can resync.
+    Patch,
+}
+
+/// A change of a thing, as a JSON patch.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThingPatch {
+    pub application: String,
+    pub thing: String,
+    /// The generation of the thing, the patch applies to.
+    pub base_generation: u32,
+    /// The generation of the thing, after applying the patch.
+    pub generation: u32,
+    pub patch: json_patch::Patch,
+}
+
+impl ThingPatch {
+    /// Check if the patch modifies the value at the JSON pointer, or any value below it.
+    pub fn modifies(&self, pointer: &str) -> bool {
+        use json_patch::PatchOperation;
+
+        let matches = |path: &str| {
+            path.strip_prefix(pointer)
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+        };
+
+        self.patch.0.iter().any(|op| match op {
+            PatchOperation::Add(op) => matches(&op.path),
+            PatchOperation::Remove(op) => matches(&op.path),
+            PatchOperation::Replace(op) => matches(&op.path),
+            PatchOperation::Move(op) => matches(&op.path) || matches(&op.from),
+            PatchOperation::Copy(op) => matches(&op.path),
+            PatchOperation::Test(_) => false,
+        })
+    }
+}
+
+/// A decoded change notification.
+#[derive(Clone, Debug)]
+pub enum Change {
+    Thing(Thing),
+    Patch(ThingPatch),
+}
+
+/// A thing, as projected by a [`Payload`].
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(untagged)]
+pub enum Projection<'t> {
+    Full(&'t Thing<Internal>),
+    Thing(Box<Thing>),
+    State(ThingState),
+    Patch(ThingPatch),
+}
+
+impl Payload {
+    /// Project the thing into the payload of a notification.
+    pub fn project<'t>(&self, thing: &'t Thing<Internal>) -> Projection<'t> {
+        match self {
+            Self::Full => Projection::Full(thing),
+            Self::Thing | Self::Patch => Projection::Thing(Box::new(reduce(thing))),
+            Self::State => Projection::State(thing.into()),
+        }
+    }
+
+    /// Project the change of a thing into the payload of a notification.
+    pub fn project_change<'t>(
+        &self,
+        current: &Thing<Internal>,
+        thing: &'t Thing<Internal>,
+    ) -> Projection<'t> {
+        match self {
+            Self::Patch => match patch(current, thing) {
+                Some(patch) => Projection::Patch(patch),
+                None => self.project(thing),
+            },
+            _ => self.project(thing),
+        }
+    }
+}
+
+/// The thing, without its internal state and reconciliation code.
+fn reduce(thing: &Thing<Internal>) -> Thing {
+    let mut thing: Thing = thing.clone().strip_internal();
+    thing.reconciliation = Default::default();
+    thing
+}
+
+/// Create a patch between the two states, or `None` if a full thing must be sent.
+fn patch(current: &Thing<Internal>, thing: &Thing<Internal>) -> Option<ThingPatch> {
+    let base_generation = current.metadata.generation?;
+    let generation = thing.metadata.generation?;
+    if thing.metadata.deletion_timestamp.is_some()
+        || current.metadata.uid != thing.metadata.uid
+        || generation % PATCH_SNAPSHOT_INTERVAL == 0
+    {
+        return None;
+    }
+
+    let current = serde_json::to_value(reduce(current)).ok()?;
+    let new = serde_json::to_value(reduce(thing)).ok()?;
+
+    Some(ThingPatch {
+        application: thing.metadata.application.clone(),
+        thing: thing.metadata.name.clone(),
+        base_generation,
+        generation,
+        patch: json_patch::diff(&current, &new),
+    })
+}
+
+/// Encode the payload of a notification about the thing, in the requested format.
+pub fn encode_payload(
+    thing: &Thing<Internal>,
+    payload: &Projection<'_>,
+    format: Format,
+) -> Result<Vec<u8>, serde_json::Error> {
+    match format {
+        Format::Json => serde_json::to_vec(payload),
+        Format::CloudEvents => events::to_structured(
+            events::builder(
+                uuid::Uuid::new_v4().to_string(),
+                events::TYPE_CHANGE,
+                &thing.metadata.application,
+                &thing.metadata.name,
+                Utc::now(),
+            ),
+            payload,
+        ),
+    }
+}
+
+/// Decode the payload of a notification, requiring a thing.
+///
+/// Accepts all variants of [`Payload`], but fails for patches, see [`decode_change`].
+pub fn decode(payload: &[u8]) -> Result<Thing, serde_json::Error> {
+    match decode_change(payload)? {
+        Change::Thing(thing) => Ok(thing),
+        Change::Patch(_) => Err(serde_json::Error::custom(
+            "Received a patch, but require the full thing",
+        )),
+    }
+}
+
+/// Decode the payload of a notification, accepting all variants of [`Payload`], either as plain
+/// JSON or as structured cloud event.
+///
+/// A [`ThingState`] doesn't carry the timestamps of the features, nor its synthetic features.
+/// So a thing decoded from it uses the current time, and only contains the reported and desired
+/// values.
+pub fn decode_change(payload: &[u8]) -> Result<Change, serde_json::Error> {
+    decode_change_value(&serde_json::from_slice(payload)?)
+}
+
+/// Decode the payload of a notification, already parsed as JSON, see [`decode_change`].
+pub fn decode_change_value(payload: &Value) -> Result<Change, serde_json::Error> {
+    if events::is_structured(payload) {
+        return match events::unwrap_structured(payload)? {
+            Some(data) => decode_change_value(&data),
+            None => Err(serde_json::Error::custom("Missing data in cloud event")),
+        };
+    }
+
+    // a thing doesn't have a patch field
+    if payload.get("patch").is_some() {
+        return ThingPatch::deserialize(payload).map(Change::Patch);
+    }
+
+    Thing::deserialize(payload)
+        .map(Change::Thing)
+        .or_else(|err| {
+            ThingState::deserialize(payload)
+                .map(|state| Change::Thing(from_state(state)))
+                // report why it's not a thing
+                .map_err(|_| err)
+        })
+}
+
+fn from_state(state: ThingState) -> Thing {
+    let now = Utc::now();
+
+    let mut thing = Thing::new(&state.metadata.application, &state.metadata.name);
+    thing.metadata = state.metadata;
+    thing.reported_state = state
+        .reported_state
+        .into_iter()
+        .map(|(name, value)| (name, ReportedFeature::new(value, now)))
+        .collect();
+    thing.desired_state = state
+        .desired_state
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                name,
+                DesiredFeature {
+                    value,
+                    mode: Default::default(),
+                    last_update: now,
+                    valid_until: None,
+                    reconciliation: Default::default(),
+                    method: Default::default(),
+                    last_result: None,
+                },
+            )
+        })
+        .collect();
+
+    thing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{Code, SyntheticFeature, SyntheticType};
+    use serde_json::json;
+
+    fn thing() -> Thing<Internal> {
+        let mut thing = Thing::new("default", "thing1");
+        thing
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(42)));
+        thing.synthetic_state.insert(
+            "alarm".into(),
+            SyntheticFeature {
+                r#type: SyntheticType::JavaScript("true".into()),
+                last_update: Utc::now(),
+                value: json!(true),
+            },
+        );
+        thing
+            .reconciliation
+            .changed
+            .insert("foo".into(), Code::JavaScript("true".into()).into());
+        let mut internal = Internal::default();
+        internal.waker.when = Some(Utc::now());
+        thing.internal = Some(internal);
+        thing
+    }
+
+    fn roundtrip(payload: Payload, thing: &Thing<Internal>) -> Thing {
+        decode(&serde_json::to_vec(&payload.project(thing)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_payload() {
+        let thing = thing();
+
+        let full = roundtrip(Payload::Full, &thing);
+        assert_eq!(full.reconciliation, thing.reconciliation);
+        assert_eq!(full.synthetic_state, thing.synthetic_state);
+
+        let reduced = roundtrip(Payload::Thing, &thing);
+        assert_eq!(reduced.metadata, thing.metadata);
+        assert_eq!(reduced.reported_state, thing.reported_state);
+        assert_eq!(reduced.synthetic_state, thing.synthetic_state);
+        assert!(reduced.reconciliation.is_empty());
+        assert!(reduced.internal.is_none());
+
+        let state = roundtrip(Payload::State, &thing);
+        assert_eq!(state.metadata, thing.metadata);
+        assert_eq!(state.reported_state["temperature"].value, json!(42));
+        assert!(state.synthetic_state.is_empty());
+        assert!(state.reconciliation.is_empty());
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut current = thing();
+        current.metadata.generation = Some(1);
+        let mut thing = current.clone();
+        thing.metadata.generation = Some(2);
+        thing
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(43)));
+
+        let payload = serde_json::to_vec(&Payload::Patch.project_change(&current, &thing)).unwrap();
+        let patch = match decode_change(&payload).unwrap() {
+            Change::Patch(patch) => patch,
+            change => panic!("Expected a patch, got: {change:?}"),
+        };
+        assert_eq!(patch.base_generation, 1);
+        assert_eq!(patch.generation, 2);
+        assert!(decode(&payload).is_err());
+
+        // applying the patch to the previous state results in the new state
+        let mut value = serde_json::to_value(reduce(&current)).unwrap();
+        json_patch::patch(&mut value, &patch.patch).unwrap();
+        assert_eq!(value, serde_json::to_value(reduce(&thing)).unwrap());
+
+        assert!(patch.modifies("/reportedState"));
+        assert!(!patch.modifies("/reported"));
+        assert!(!patch.modifies("/metadata/labels"));
+
+        // snapshot
+        thing.metadata.generation = Some(PATCH_SNAPSHOT_INTERVAL);
+        let payload = serde_json::to_vec(&Payload::Patch.project_change(&current, &thing)).unwrap();
+        assert!(matches!(decode_change(&payload).unwrap(), Change::Thing(_)));
+    }
+
+    #[test]
+    fn test_cloud_events() {
+        let thing = thing();
+
+        let payload =
+            encode_payload(&thing, &Payload::Thing.project(&thing), Format::CloudEvents).unwrap();
+        let value: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(value["type"], json!(events::TYPE_CHANGE));
+        assert_eq!(value["subject"], json!("default/thing1"));
+
+        let decoded = decode(&payload).unwrap();
+        assert_eq!(decoded.metadata, thing.metadata);
+        assert_eq!(decoded.reported_state, thing.reported_state);
+    }
 }
@@ -0,0 +1,226 @@
+//! Send change notifications to multiple notifiers at once.
+//!
+//! Each change is sent to all targets, even if some of them fail. Failures are counted per target,
+//! and the notification fails if any of the targets failed.
+
+use super::{Notifier as _, *};
+use crate::config::check::{Check, Checker};
+use crate::notifier;
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tracing::instrument;
+
+lazy_static! {
+    static ref NOTIFIER_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "notifier_errors",
+        "Number of failed notifications, by target",
+        &["target"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    pub targets: Vec<TargetConfig>,
+}
+
+/// A target of the notifications.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct TargetConfig {
+    /// The name of the target, used in metrics and errors. Defaults to the type of the notifier.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub notifier: NotifierConfig,
+}
+
+/// The configuration of a target, by type of the notifier.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NotifierConfig {
+    Kafka(kafka::Config),
+    Redis(redis::Config),
+    Nats(nats::Config),
+    Postgres(postgres::Config),
+    Webhook(webhook::Config),
+}
+
+impl NotifierConfig {
+    fn r#type(&self) -> &'static str {
+        match self {
+            Self::Kafka(_) => "kafka",
+            Self::Redis(_) => "redis",
+            Self::Nats(_) => "nats",
+            Self::Postgres(_) => "postgres",
+            Self::Webhook(_) => "webhook",
+        }
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if self.targets.is_empty() {
+            checker.issue("targets", "must not be empty");
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            let name = format!("targets.{i}.{}", target.notifier.r#type());
+            match &target.notifier {
+                NotifierConfig::Kafka(config) => checker.field(&name, config),
+                NotifierConfig::Redis(config) => checker.field(&name, config),
+                NotifierConfig::Nats(config) => checker.field(&name, config),
+                NotifierConfig::Postgres(config) => checker.field(&name, config),
+                NotifierConfig::Webhook(config) => checker.field(&name, config),
+            };
+        }
+    }
+}
+
+enum Target {
+    Kafka(kafka::Notifier),
+    Redis(redis::Notifier),
+    Nats(nats::Notifier),
+    Postgres(postgres::Notifier),
+    Webhook(webhook::Notifier),
+}
+
+/// Run the same operation on a notifier, of whatever type the target is.
+macro_rules! dispatch {
+    ($target:expr, $notifier:ident => $f:expr) => {
+        match $target {
+            Target::Kafka($notifier) => $f.await.map_err(|err| err.to_string()),
+            Target::Redis($notifier) => $f.await.map_err(|err| err.to_string()),
+            Target::Nats($notifier) => $f.await.map_err(|err| err.to_string()),
+            Target::Postgres($notifier) => $f.await.map_err(|err| err.to_string()),
+            Target::Webhook($notifier) => $f.await.map_err(|err| err.to_string()),
+        }
+    };
+}
+
+pub struct Notifier {
+    targets: Vec<(String, Target)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to notify target '{target}': {message}")]
+    Target { target: String, message: String },
+}
+
+impl From<Error> for notifier::Error<Error> {
+    fn from(err: Error) -> Self {
+        Self::Sender(err)
+    }
+}
+
+impl Notifier {
+    /// Evaluate the results of all targets, failing with the first failed target.
+    fn evaluate(&self, results: Vec<Result<(), String>>) -> Result<(), notifier::Error<Error>> {
+        let mut error = None;
+
+        for ((name, _), result) in self.targets.iter().zip(results) {
+            if let Err(message) = result {
+                log::info!("Failed to notify target '{name}': {message}");
+                NOTIFIER_ERRORS.with_label_values(&[name]).inc();
+                error.get_or_insert(Error::Target {
+                    target: name.clone(),
+                    message,
+                });
+            }
+        }
+
+        match error {
+            Some(err) => Err(err.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl super::Notifier for Notifier {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        let targets = config
+            .targets
+            .iter()
+            .map(|target| {
+                let name = target
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| target.notifier.r#type().to_string());
+                let notifier = match &target.notifier {
+                    NotifierConfig::Kafka(config) => {
+                        Target::Kafka(kafka::Notifier::from_config(config)?)
+                    }
+                    NotifierConfig::Redis(config) => {
+                        Target::Redis(redis::Notifier::from_config(config)?)
+                    }
+                    NotifierConfig::Nats(config) => {
+                        Target::Nats(nats::Notifier::from_config(config)?)
+                    }
+                    NotifierConfig::Postgres(config) => {
+                        Target::Postgres(postgres::Notifier::from_config(config)?)
+                    }
+                    NotifierConfig::Webhook(config) => {
+                        Target::Webhook(webhook::Notifier::from_config(config)?)
+                    }
+                };
+                Ok((name, notifier))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { targets })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        let results = join_all(self.targets.iter().map(|(_, target)| async move {
+            dispatch!(target, notifier => notifier.notify(thing))
+        }))
+        .await;
+
+        self.evaluate(results)
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        let results = join_all(self.targets.iter().map(|(_, target)| async move {
+            dispatch!(target, notifier => notifier.notify_change(current, thing))
+        }))
+        .await;
+
+        self.evaluate(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_config() {
+        let config: Config = serde_json::from_value(json!({
+            "targets": [
+                {"kafka": {"properties": {"bootstrap_servers": "localhost:9092"}, "topic": "changes"}},
+                {"name": "integration", "webhook": {"urls": ["https://example.com/changes"]}},
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(config.targets[0].name, None);
+        assert_eq!(config.targets[0].notifier.r#type(), "kafka");
+        assert_eq!(config.targets[1].name.as_deref(), Some("integration"));
+        assert!(matches!(
+            &config.targets[1].notifier,
+            NotifierConfig::Webhook(config) if config.payload == Payload::Thing
+        ));
+    }
+}
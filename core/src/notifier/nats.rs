@@ -0,0 +1,130 @@
+use super::*;
+use crate::config::check::{Check, Checker};
+use crate::model::Metadata;
+use crate::notifier;
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The NATS servers, as comma separated list of URLs, e.g. `nats://localhost:4222`.
+    pub servers: String,
+    /// The prefix of the subjects, the changes get published to.
+    ///
+    /// Changes of a thing are published to `<subject>.<application>.<thing>`.
+    #[serde(default = "default::subject")]
+    pub subject: String,
+    /// The payload of the notifications.
+    #[serde(default)]
+    pub payload: Payload,
+    /// The format of the notifications.
+    #[serde(default)]
+    pub format: Format,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .nats("servers", &self.servers)
+            .not_empty("subject", &self.subject);
+    }
+}
+
+mod default {
+    pub fn subject() -> String {
+        "doppelgaenger".to_string()
+    }
+}
+
+pub struct Notifier {
+    servers: String,
+    client: OnceCell<async_nats::Client>,
+    subject: String,
+    payload: Payload,
+    format: Format,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Connect: {0}")]
+    Connect(#[from] std::io::Error),
+    #[error("NATS: {0}")]
+    Nats(async_nats::Error),
+    #[error("Serializer: {0}")]
+    Serializer(#[from] serde_json::Error),
+}
+
+impl From<Error> for notifier::Error<Error> {
+    fn from(err: Error) -> Self {
+        Self::Sender(err)
+    }
+}
+
+impl Notifier {
+    async fn client(&self) -> Result<&async_nats::Client, Error> {
+        // the client takes care of reconnecting, so we only need to create it once
+        Ok(self
+            .client
+            .get_or_try_init(|| async_nats::connect(self.servers.as_str()))
+            .await?)
+    }
+}
+
+#[async_trait]
+impl super::Notifier for Notifier {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            servers: config.servers.clone(),
+            client: OnceCell::new(),
+            subject: config.subject.clone(),
+            payload: config.payload,
+            format: config.format,
+        })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project(thing)).await
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project_change(current, thing))
+            .await
+    }
+}
+
+impl Notifier {
+    async fn send(
+        &self,
+        thing: &Thing<Internal>,
+        payload: Projection<'_>,
+    ) -> Result<(), notifier::Error<Error>> {
+        let Metadata {
+            application, name, ..
+        } = &thing.metadata;
+
+        log::debug!("Notify change - {application} / {name}");
+
+        let subject = format!("{}.{application}.{name}", self.subject);
+        let payload = encode_payload(thing, &payload, self.format).map_err(Error::Serializer)?;
+
+        self.client()
+            .await?
+            .publish(subject, payload.into())
+            .await
+            .map_err(|err| notifier::Error::Sender(Error::Nats(err)))?;
+
+        log::debug!("Notification sent");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+//! Sending change notifications using Postgres `NOTIFY`.
+//!
+//! The payload of a notification is limited by Postgres to 8000 bytes. Things exceeding this limit
+//! are sent without their state, and the listener needs to fetch them from the database instead.
+
+use super::*;
+use crate::config::check::{Check, Checker};
+use crate::model::Metadata;
+use crate::notifier;
+use deadpool_postgres::PoolError;
+use drogue_bazaar::db::postgres;
+use postgres_types::Type;
+use tracing::instrument;
+
+/// The maximum size of a notification payload, as accepted by Postgres.
+const MAX_PAYLOAD: usize = 7999;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub postgres: postgres::Config,
+    /// The channel, the changes get published to.
+    #[serde(default = "default::channel")]
+    pub channel: String,
+    /// The payload of the notifications.
+    #[serde(default)]
+    pub payload: Payload,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        self.postgres.check(checker);
+        checker.not_empty("channel", &self.channel);
+    }
+}
+
+mod default {
+    pub fn channel() -> String {
+        "doppelgaenger".to_string()
+    }
+}
+
+/// The payload of a notification.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Notification<T> {
+    pub application: String,
+    pub thing: String,
+    /// The changed thing, missing if it exceeded the maximum payload size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<T>,
+}
+
+pub struct Notifier {
+    pool: deadpool_postgres::Pool,
+    channel: String,
+    payload: Payload,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Postgres: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("Pool: {0}")]
+    Pool(#[from] PoolError),
+    #[error("Serializer: {0}")]
+    Serializer(#[from] serde_json::Error),
+}
+
+impl From<Error> for notifier::Error<Error> {
+    fn from(err: Error) -> Self {
+        Self::Sender(err)
+    }
+}
+
+/// Encode the notification of a change, omitting the state if it is too big.
+pub fn encode(
+    thing: &Thing<Internal>,
+    payload: Projection<'_>,
+) -> Result<String, serde_json::Error> {
+    let Metadata {
+        application, name, ..
+    } = &thing.metadata;
+
+    let mut notification = Notification {
+        application: application.clone(),
+        thing: name.clone(),
+        state: Some(payload),
+    };
+
+    let payload = serde_json::to_string(&notification)?;
+    if payload.len() <= MAX_PAYLOAD {
+        return Ok(payload);
+    }
+
+    notification.state = None;
+    serde_json::to_string(&notification)
+}
+
+#[async_trait]
+impl super::Notifier for Notifier {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            pool: config.postgres.create_pool()?,
+            channel: config.channel.clone(),
+            payload: config.payload,
+        })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project(thing)).await
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project_change(current, thing))
+            .await
+    }
+}
+
+impl Notifier {
+    async fn send(
+        &self,
+        thing: &Thing<Internal>,
+        payload: Projection<'_>,
+    ) -> Result<(), notifier::Error<Error>> {
+        log::debug!(
+            "Notify change - {} / {}",
+            thing.metadata.application,
+            thing.metadata.name
+        );
+
+        let payload = encode(thing, payload).map_err(Error::Serializer)?;
+
+        let con = self.pool.get().await.map_err(Error::Pool)?;
+        let stmt = con
+            .prepare_typed_cached("SELECT pg_notify($1, $2)", &[Type::TEXT, Type::TEXT])
+            .await
+            .map_err(Error::Postgres)?;
+        con.execute(&stmt, &[&self.channel, &payload])
+            .await
+            .map_err(Error::Postgres)?;
+
+        log::debug!("Notification sent");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut thing = Thing::new("default", "thing1");
+
+        let notification: Notification<Thing<Internal>> =
+            serde_json::from_str(&encode(&thing, Payload::Full.project(&thing)).unwrap()).unwrap();
+        assert_eq!(notification.application, "default");
+        assert_eq!(notification.thing, "thing1");
+        assert_eq!(notification.state, Some(thing.clone()));
+
+        thing
+            .metadata
+            .annotations
+            .insert("large".into(), "x".repeat(MAX_PAYLOAD));
+
+        let payload = encode(&thing, Payload::Full.project(&thing)).unwrap();
+        assert!(payload.len() <= MAX_PAYLOAD);
+        let notification: Notification<Thing<Internal>> = serde_json::from_str(&payload).unwrap();
+        assert_eq!(notification.thing, "thing1");
+        assert_eq!(notification.state, None);
+    }
+}
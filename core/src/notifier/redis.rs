@@ -0,0 +1,130 @@
+use super::*;
+use crate::config::check::{Check, Checker};
+use crate::model::Metadata;
+use crate::notifier;
+use ::redis::{aio::ConnectionManager, AsyncCommands, RedisError};
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The Redis URL, e.g. `redis://localhost:6379`.
+    pub url: String,
+    /// The prefix of the channels, the changes get published to.
+    ///
+    /// Changes of a thing are published to `<channel>/<application>/<thing>`.
+    #[serde(default = "default::channel")]
+    pub channel: String,
+    /// The payload of the notifications.
+    #[serde(default)]
+    pub payload: Payload,
+    /// The format of the notifications.
+    #[serde(default)]
+    pub format: Format,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .redis("url", &self.url)
+            .not_empty("channel", &self.channel);
+    }
+}
+
+mod default {
+    pub fn channel() -> String {
+        "doppelgaenger".to_string()
+    }
+}
+
+pub struct Notifier {
+    client: ::redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    channel: String,
+    payload: Payload,
+    format: Format,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Redis: {0}")]
+    Redis(#[from] RedisError),
+    #[error("Serializer: {0}")]
+    Serializer(#[from] serde_json::Error),
+}
+
+impl From<Error> for notifier::Error<Error> {
+    fn from(err: Error) -> Self {
+        Self::Sender(err)
+    }
+}
+
+impl Notifier {
+    async fn connection(&self) -> Result<ConnectionManager, Error> {
+        // the connection manager takes care of reconnecting, so we only need to create it once
+        Ok(self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await?
+            .clone())
+    }
+}
+
+#[async_trait]
+impl super::Notifier for Notifier {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: ::redis::Client::open(config.url.as_str())?,
+            connection: OnceCell::new(),
+            channel: config.channel.clone(),
+            payload: config.payload,
+            format: config.format,
+        })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project(thing)).await
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.payload.project_change(current, thing))
+            .await
+    }
+}
+
+impl Notifier {
+    async fn send(
+        &self,
+        thing: &Thing<Internal>,
+        payload: Projection<'_>,
+    ) -> Result<(), notifier::Error<Error>> {
+        let Metadata {
+            application, name, ..
+        } = &thing.metadata;
+
+        log::debug!("Notify change - {application} / {name}");
+
+        let channel = format!("{}/{application}/{name}", self.channel);
+        let payload = encode_payload(thing, &payload, self.format).map_err(Error::Serializer)?;
+
+        let mut connection = self.connection().await?;
+        connection
+            .publish::<_, _, ()>(channel, payload)
+            .await
+            .map_err(|err| notifier::Error::Sender(Error::Redis(err)))?;
+
+        log::debug!("Notification sent");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Notify changes by posting them to HTTP endpoints.
+//!
+//! Each change gets posted as JSON to all configured endpoints. Failed requests are retried, and
+//! if a secret is configured, the body gets signed using HMAC-SHA256. The signature is sent in
+//! the [`HEADER_SIGNATURE`] header, as `sha256=<hex encoded signature>`.
+
+use super::*;
+use crate::config::check::{Check, Checker};
+use crate::model::Metadata;
+use crate::notifier;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use tracing::instrument;
+
+/// The header containing the signature of the body.
+pub const HEADER_SIGNATURE: &str = "X-Doppelgaenger-Signature";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The URLs of the endpoints, the changes get posted to.
+    pub urls: Vec<String>,
+    /// The secret, used for signing the body.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// The payload, sent to the endpoints. Defaults to the thing, without its internal state.
+    #[serde(default = "default::payload")]
+    pub payload: Payload,
+    /// The format of the notifications.
+    #[serde(default)]
+    pub format: Format,
+
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+    /// The number of retries, after the first attempt failed.
+    #[serde(default = "default::retries")]
+    pub retries: u32,
+    /// The delay between two attempts.
+    #[serde(with = "humantime_serde", default = "default::retry_delay")]
+    pub retry_delay: Duration,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if self.urls.is_empty() {
+            checker.issue("urls", "must not be empty");
+        }
+        for url in &self.urls {
+            if let Err(err) = url::Url::parse(url) {
+                checker.issue("urls", format!("invalid URL '{url}': {err}"));
+            }
+        }
+    }
+}
+
+mod default {
+    use super::*;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    pub const fn retries() -> u32 {
+        3
+    }
+
+    pub const fn retry_delay() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    pub const fn payload() -> Payload {
+        Payload::Thing
+    }
+}
+
+pub struct Notifier {
+    client: reqwest::Client,
+    config: Config,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Endpoint {0} responded with: {1}")]
+    Response(String, reqwest::StatusCode),
+    #[error("Serializer: {0}")]
+    Serializer(#[from] serde_json::Error),
+}
+
+impl From<Error> for notifier::Error<Error> {
+    fn from(err: Error) -> Self {
+        Self::Sender(err)
+    }
+}
+
+impl Notifier {
+    /// Post the body to an endpoint, retrying failed attempts.
+    async fn post(&self, url: &str, body: &[u8], signature: Option<&str>) -> Result<(), Error> {
+        let mut attempt = 0;
+        loop {
+            match self.post_once(url, body, signature).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < self.config.retries => {
+                    attempt += 1;
+                    log::info!("Failed to notify {url} (attempt {attempt}), retrying: {err}");
+                    tokio::time::sleep(self.config.retry_delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn post_once(
+        &self,
+        url: &str,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut request = self
+            .client
+            .post(url)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                self.config.format.content_type(),
+            )
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            request = request.header(HEADER_SIGNATURE, signature);
+        }
+
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(Error::Response(url.to_string(), status)),
+        }
+    }
+}
+
+#[async_trait]
+impl super::Notifier for Notifier {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder().timeout(config.timeout).build()?,
+            config: config.clone(),
+        })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify(&self, thing: &Thing<Internal>) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.config.payload.project(thing)).await
+    }
+
+    #[instrument(skip_all, err)]
+    async fn notify_change(
+        &self,
+        current: &Thing<Internal>,
+        thing: &Thing<Internal>,
+    ) -> Result<(), notifier::Error<Self::Error>> {
+        self.send(thing, self.config.payload.project_change(current, thing))
+            .await
+    }
+}
+
+impl Notifier {
+    async fn send(
+        &self,
+        thing: &Thing<Internal>,
+        payload: Projection<'_>,
+    ) -> Result<(), notifier::Error<Error>> {
+        let Metadata {
+            application, name, ..
+        } = &thing.metadata;
+
+        log::debug!("Notify change - {application} / {name}");
+
+        let body =
+            encode_payload(thing, &payload, self.config.format).map_err(Error::Serializer)?;
+        let signature = self
+            .config
+            .secret
+            .as_deref()
+            .map(|secret| sign(secret, &body));
+
+        for url in &self.config.urls {
+            self.post(url, &body, signature.as_deref()).await?;
+        }
+
+        log::debug!("Notification sent");
+
+        Ok(())
+    }
+}
+
+/// Sign the body, returning the value of the [`HEADER_SIGNATURE`] header.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(body);
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
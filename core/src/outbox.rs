@@ -0,0 +1,164 @@
+//! Relay events from the transactional outbox.
+//!
+//! When the storage is configured to use the transactional outbox (see
+//! [`crate::storage::postgres::Config::outbox`]), events of things are written to the `outbox`
+//! table, in the same statement as the thing itself. The relay picks them up from there, in the
+//! order they were written, and publishes them to the event sink.
+//!
+//! Events are deleted once they got published. Publishing might fail after some events were sent,
+//! in which case the remaining events are retried. So events are delivered at least once.
+//!
+//! Multiple relays may run at the same time, as rows get locked while being published. However,
+//! only a single relay preserves the order of events.
+
+use crate::{
+    config::check::{Check, Checker},
+    processor::{sink::Sink, Event},
+};
+use lazy_static::lazy_static;
+use postgres_types::{Json, Type};
+use prometheus::{register_int_counter, IntCounter};
+use std::time::Duration;
+
+lazy_static! {
+    static ref OUTBOX_RELAYED: IntCounter = register_int_counter!(
+        "outbox_relayed",
+        "Number of events relayed from the transactional outbox"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub disabled: bool,
+
+    pub postgres: drogue_bazaar::db::postgres::Config,
+
+    /// The maximum number of events published in one batch.
+    #[serde(default = "default::batch_size")]
+    pub batch_size: u32,
+
+    /// The period of checking for new events, when the outbox is empty.
+    #[serde(with = "humantime_serde", default = "default::check_period")]
+    pub check_period: Duration,
+}
+
+pub mod default {
+    use std::time::Duration;
+
+    pub const fn batch_size() -> u32 {
+        100
+    }
+
+    pub const fn check_period() -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if !self.disabled {
+            checker.field("postgres", &self.postgres);
+            if self.batch_size == 0 {
+                checker.issue("batch_size", "must be greater than zero");
+            }
+        }
+    }
+}
+
+impl Config {
+    pub async fn run<Si: Sink>(self, sink: Si) -> anyhow::Result<()> {
+        let pool = self.postgres.create_pool()?;
+
+        log::info!(
+            "Running outbox relay - batch size: {}, check period: {:?}",
+            self.batch_size,
+            self.check_period
+        );
+
+        let relay = Relay {
+            pool,
+            batch_size: self.batch_size,
+            sink,
+        };
+
+        let mut interval = tokio::time::interval(self.check_period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            // drain the outbox, before waiting for the next check
+            loop {
+                match relay.relay().await {
+                    Ok(n) if n < relay.batch_size as usize => break,
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::warn!("Failed to relay outbox events: {err}");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Relay<Si: Sink> {
+    pool: deadpool_postgres::Pool,
+    batch_size: u32,
+    sink: Si,
+}
+
+impl<Si: Sink> Relay<Si> {
+    /// Relay a single batch of events, returning the number of events fetched.
+    async fn relay(&self) -> anyhow::Result<usize> {
+        let mut con = self.pool.get().await?;
+        let tx = con.transaction().await?;
+
+        let stmt = tx
+            .prepare_typed_cached(
+                r#"
+SELECT
+    ID, EVENT
+FROM
+    outbox
+ORDER BY
+    ID
+LIMIT $1
+FOR UPDATE SKIP LOCKED
+"#,
+                &[Type::INT8],
+            )
+            .await?;
+
+        let rows = tx.query(&stmt, &[&(self.batch_size as i64)]).await?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let mut ids = Vec::with_capacity(rows.len());
+        let mut events = Vec::with_capacity(rows.len());
+        for row in &rows {
+            ids.push(row.try_get::<_, i64>("ID")?);
+            events.push(row.try_get::<_, Json<Event>>("EVENT")?.0);
+        }
+
+        // only delete what got published
+        let (published, result) = match self.sink.publish_iter(events).await {
+            Ok(()) => (ids.len(), Ok(())),
+            Err((n, err)) => (n, Err(err)),
+        };
+
+        if published > 0 {
+            let stmt = tx
+                .prepare_typed_cached("DELETE FROM outbox WHERE ID = ANY($1)", &[Type::INT8_ARRAY])
+                .await?;
+            tx.execute(&stmt, &[&&ids[..published]]).await?;
+            tx.commit().await?;
+            OUTBOX_RELAYED.inc_by(published as u64);
+        }
+
+        result.map(|()| rows.len())
+    }
+}
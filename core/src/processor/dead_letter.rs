@@ -0,0 +1,80 @@
+//! Publishing events which permanently failed processing to a dead letter topic.
+//!
+//! Dead letters are published the same way as events, along with headers describing the failure.
+//! So they can be inspected with the usual Kafka tooling, and replayed into the event topic once
+//! the cause got fixed, see [`crate::admin::replay`].
+
+use crate::processor::{
+    sink::{self, Sink as _},
+    Event,
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use tracing::instrument;
+
+lazy_static! {
+    static ref DEAD_LETTERS: IntCounterVec = register_int_counter_vec!(
+        "dead_letters",
+        "Number of events published to the dead letter topic, by reason",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+/// The header containing the reason of the failure, e.g. `machine`.
+pub const HEADER_REASON: &str = "ce_deadletterreason";
+/// The header containing the error message.
+pub const HEADER_ERROR: &str = "ce_deadlettererror";
+/// The header containing the time the event failed.
+pub const HEADER_FAILED: &str = "ce_deadletterfailed";
+
+/// An error, which will fail the same way when processing the event again.
+#[derive(Debug, thiserror::Error)]
+#[error("{reason}: {message}")]
+pub struct PermanentError {
+    /// The reason of the failure, used as label of the metric.
+    pub reason: &'static str,
+    pub message: String,
+}
+
+impl PermanentError {
+    pub fn new(reason: &'static str, err: impl std::fmt::Display) -> Self {
+        Self {
+            reason,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// The dead letter topic.
+pub struct DeadLetters {
+    sink: sink::kafka::Sink,
+}
+
+impl DeadLetters {
+    pub fn from_config(config: sink::kafka::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            sink: sink::kafka::Sink::from_config(config)?,
+        })
+    }
+
+    /// Publish the event, along with the error it permanently failed with.
+    #[instrument(skip_all, fields(id = event.id, reason = error.reason), err)]
+    pub async fn publish(&self, event: &Event, error: &PermanentError) -> anyhow::Result<()> {
+        self.sink
+            .publish_with_headers(
+                event,
+                &[
+                    (HEADER_REASON, error.reason),
+                    (HEADER_ERROR, &error.message),
+                    (HEADER_FAILED, &Utc::now().to_rfc3339()),
+                ],
+            )
+            .await?;
+
+        DEAD_LETTERS.with_label_values(&[error.reason]).inc();
+
+        Ok(())
+    }
+}
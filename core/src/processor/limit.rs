@@ -0,0 +1,476 @@
+//! Rate limiting of state updates, per thing.
+
+use crate::{clock, service::Id};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+lazy_static! {
+    static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "rate_limited",
+        "Number of state reports exceeding the rate limit",
+        &["action"]
+    )
+    .unwrap();
+}
+
+/// Interval in which expired entries get removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Config {
+    /// The limit for applications without a specific limit, unlimited if not present.
+    #[serde(default)]
+    pub default: Option<Limit>,
+    /// Limits by application.
+    #[serde(default)]
+    pub applications: HashMap<String, Limit>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Limit {
+    /// Number of state reports accepted per period.
+    pub updates: u32,
+    #[serde(with = "humantime_serde")]
+    pub period: Duration,
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+/// How to handle state reports exceeding the limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Mode {
+    /// Keep the reported state, and apply it with the next accepted report, or at the end of the
+    /// window, whatever comes first.
+    #[default]
+    Coalesce,
+    /// Drop the reported state.
+    Reject,
+}
+
+/// A state report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub state: BTreeMap<String, Value>,
+    pub partial: bool,
+}
+
+impl Report {
+    /// Merge a newer report into this one.
+    fn merge(&mut self, newer: Report) {
+        if newer.partial {
+            self.state.extend(newer.state);
+        } else {
+            *self = newer;
+        }
+    }
+}
+
+struct Window {
+    start: DateTime<Utc>,
+    count: u32,
+    pending: Option<Report>,
+    /// A flush of the pending report is scheduled.
+    flush_scheduled: bool,
+}
+
+struct State {
+    windows: HashMap<Id, Window>,
+    last_cleanup: DateTime<Utc>,
+}
+
+pub struct RateLimiter {
+    config: Config,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State {
+                windows: Default::default(),
+                last_cleanup: clock::now(),
+            }),
+        }
+    }
+
+    fn limit(&self, application: &str) -> Option<&Limit> {
+        self.config
+            .applications
+            .get(application)
+            .or(self.config.default.as_ref())
+    }
+
+    /// Check a state report against the limit.
+    ///
+    /// Returns the report to apply, which may include previously coalesced reports, or `None`
+    /// if the report exceeded the limit.
+    pub fn check(&self, id: &Id, report: Report) -> Option<Report> {
+        self.check_at(clock::now(), id, report)
+    }
+
+    fn check_at(&self, now: DateTime<Utc>, id: &Id, report: Report) -> Option<Report> {
+        let limit = match self.limit(&id.application) {
+            Some(limit) => limit,
+            None => return Some(report),
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        if elapsed(state.last_cleanup, now) > CLEANUP_INTERVAL {
+            // each window expires according to the limit of its own application
+            state.windows.retain(|id, window| {
+                window.pending.is_some()
+                    || self
+                        .limit(&id.application)
+                        .map_or(false, |limit| elapsed(window.start, now) < limit.period)
+            });
+            state.last_cleanup = now;
+        }
+
+        let window = state.windows.entry(id.clone()).or_insert_with(|| Window {
+            start: now,
+            count: 0,
+            pending: None,
+            flush_scheduled: false,
+        });
+
+        if elapsed(window.start, now) >= limit.period {
+            window.start = now;
+            window.count = 0;
+        }
+
+        if window.count < limit.updates {
+            window.count += 1;
+            Some(match window.pending.take() {
+                Some(mut pending) => {
+                    pending.merge(report);
+                    pending
+                }
+                None => report,
+            })
+        } else {
+            match limit.mode {
+                Mode::Coalesce => {
+                    RATE_LIMITED.with_label_values(&["coalesced"]).inc();
+                    match &mut window.pending {
+                        Some(pending) => pending.merge(report),
+                        None => window.pending = Some(report),
+                    }
+                }
+                Mode::Reject => {
+                    RATE_LIMITED.with_label_values(&["rejected"]).inc();
+                }
+            }
+            None
+        }
+    }
+
+    /// Schedule the flush of a pending report.
+    ///
+    /// Returns the end of the window, at which the pending report should be flushed using
+    /// [`Self::flush`], or `None` if there is nothing pending, or a flush is already scheduled.
+    pub fn schedule_flush(&self, id: &Id) -> Option<DateTime<Utc>> {
+        let period = chrono::Duration::from_std(self.limit(&id.application)?.period).ok()?;
+
+        let mut state = self.state.lock().unwrap();
+        let window = state.windows.get_mut(id)?;
+
+        if window.pending.is_none() || window.flush_scheduled {
+            return None;
+        }
+
+        window.flush_scheduled = true;
+        Some(window.start + period)
+    }
+
+    /// Take the pending report, counting it as an update of the current window.
+    ///
+    /// Returns `None` if the pending report was already applied with a later report, or if the
+    /// window is still exhausted. In the latter case, the flush needs to be scheduled again.
+    pub fn flush(&self, id: &Id) -> Option<Report> {
+        self.flush_at(clock::now(), id)
+    }
+
+    fn flush_at(&self, now: DateTime<Utc>, id: &Id) -> Option<Report> {
+        let limit = self.limit(&id.application);
+
+        let mut state = self.state.lock().unwrap();
+        let window = state.windows.get_mut(id)?;
+        window.flush_scheduled = false;
+
+        if let Some(limit) = limit {
+            if elapsed(window.start, now) >= limit.period {
+                window.start = now;
+                window.count = 0;
+            }
+            if window.count >= limit.updates {
+                return None;
+            }
+        }
+
+        let pending = window.pending.take()?;
+        window.count += 1;
+
+        Some(pending)
+    }
+}
+
+/// The time elapsed since a point in time, zero if the clock went backwards.
+fn elapsed(since: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    (now - since).to_std().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn report(partial: bool, state: &[(&str, Value)]) -> Report {
+        Report {
+            state: state
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+            partial,
+        }
+    }
+
+    fn limiter(mode: Mode) -> RateLimiter {
+        RateLimiter::new(Config {
+            default: Some(Limit {
+                updates: 1,
+                period: Duration::from_secs(1),
+                mode,
+            }),
+            applications: Default::default(),
+        })
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = RateLimiter::new(Default::default());
+        let id = Id::new("default", "thing");
+        for _ in 0..10 {
+            assert!(limiter.check(&id, report(true, &[])).is_some());
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let limiter = limiter(Mode::Coalesce);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("a", json!(1))])),
+            Some(report(true, &[("a", json!(1))]))
+        );
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("a", json!(2))])),
+            None
+        );
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("b", json!(3))])),
+            None
+        );
+
+        // next window, we must get the coalesced state
+
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("c", json!(4))])),
+            Some(report(
+                true,
+                &[("a", json!(2)), ("b", json!(3)), ("c", json!(4))]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_coalesce_full() {
+        let limiter = limiter(Mode::Coalesce);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+
+        // a full report replaces the pending state
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.check_at(now, &id, report(false, &[("b", json!(3))])),
+            Some(report(false, &[("b", json!(3))]))
+        );
+    }
+
+    #[test]
+    fn test_reject() {
+        let limiter = limiter(Mode::Reject);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("b", json!(3))])),
+            Some(report(true, &[("b", json!(3))]))
+        );
+    }
+
+    #[test]
+    fn test_flush() {
+        let limiter = limiter(Mode::Coalesce);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        // nothing pending, nothing to flush
+        assert_eq!(limiter.schedule_flush(&id), None);
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("b", json!(3))]))
+            .is_none());
+
+        // schedule once, at the end of the window
+        assert_eq!(
+            limiter.schedule_flush(&id),
+            Some(now + chrono::Duration::seconds(1))
+        );
+        assert_eq!(limiter.schedule_flush(&id), None);
+
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.flush_at(now, &id),
+            Some(report(true, &[("a", json!(2)), ("b", json!(3))]))
+        );
+
+        // the flush counts as update of the new window
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("c", json!(4))]))
+            .is_none());
+        assert!(limiter.schedule_flush(&id).is_some());
+    }
+
+    #[test]
+    fn test_flush_after_report() {
+        let limiter = limiter(Mode::Coalesce);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+        assert!(limiter.schedule_flush(&id).is_some());
+
+        // a report of the next window, arriving before the flush, takes the pending report
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.check_at(now, &id, report(true, &[("b", json!(3))])),
+            Some(report(true, &[("a", json!(2)), ("b", json!(3))]))
+        );
+        assert_eq!(limiter.flush_at(now, &id), None);
+    }
+
+    #[test]
+    fn test_flush_early() {
+        let limiter = limiter(Mode::Coalesce);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+        assert!(limiter.schedule_flush(&id).is_some());
+
+        // the window is still exhausted, keep the report and schedule again
+        assert_eq!(limiter.flush_at(now, &id), None);
+        assert!(limiter.schedule_flush(&id).is_some());
+
+        let now = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            limiter.flush_at(now, &id),
+            Some(report(true, &[("a", json!(2))]))
+        );
+    }
+
+    #[test]
+    fn test_cleanup_per_application() {
+        let limiter = RateLimiter::new(Config {
+            default: Some(Limit {
+                updates: 1,
+                period: Duration::from_secs(1),
+                mode: Mode::Reject,
+            }),
+            applications: [(
+                "slow".to_string(),
+                Limit {
+                    updates: 1,
+                    period: Duration::from_secs(600),
+                    mode: Mode::Reject,
+                },
+            )]
+            .into(),
+        });
+        let slow = Id::new("slow", "thing");
+        let fast = Id::new("fast", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &slow, report(true, &[("a", json!(1))]))
+            .is_some());
+
+        // the cleanup, triggered by the other application, must keep the window
+        let now = now + chrono::Duration::minutes(2);
+        assert!(limiter
+            .check_at(now, &fast, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &slow, report(true, &[("a", json!(2))]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_reject_no_flush() {
+        let limiter = limiter(Mode::Reject);
+        let id = Id::new("default", "thing");
+        let now = Utc::now();
+
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(1))]))
+            .is_some());
+        assert!(limiter
+            .check_at(now, &id, report(true, &[("a", json!(2))]))
+            .is_none());
+        assert_eq!(limiter.schedule_flush(&id), None);
+    }
+}
@@ -1,20 +1,33 @@
+pub mod dead_letter;
+pub mod limit;
 pub mod sink;
 pub mod source;
 
 use crate::{
+    clock::{self, FixedClock},
     command::CommandSink,
+    config::check::{Check, Checker},
+    correlation,
+    events::{self, DataExt},
     model::{Internal, Reconciliation, Thing, WakerReason},
     notifier::Notifier,
-    processor::{sink::Sink, source::Source},
+    processor::{
+        dead_letter::{DeadLetters, PermanentError},
+        limit::{RateLimiter, Report},
+        sink::Sink,
+        source::Source,
+    },
     service::{
-        self, Cleanup, DefaultService, DesiredStateValueUpdater, Id, InfallibleUpdater,
-        JsonMergeUpdater, JsonPatchUpdater, MapValueInserter, MapValueRemover,
-        ReportedStateUpdater, Service, UpdateMode, UpdateOptions, Updater, UpdaterExt,
+        self, Cleanup, CommandResultUpdater, DefaultService, DesiredStateValueUpdater, Expire, Id,
+        InfallibleUpdater, JsonMergeUpdater, JsonPatchUpdater, MapValueInserter, MapValueRemover,
+        ReferenceUpdater, ReportedStateUpdater, Service, UpdateMode, UpdateOptions, Updater,
+        UpdaterExt,
     },
     storage::{self, Storage},
 };
 use anyhow::anyhow;
 use chrono::{DateTime, Utc};
+use cloudevents::{AttributesReader, EventBuilder};
 use drogue_bazaar::app::Startup;
 use json_patch::Patch;
 use lazy_static::lazy_static;
@@ -23,8 +36,8 @@ use prometheus::{
     IntCounterVec,
 };
 use serde_json::Value;
-use std::collections::BTreeMap;
-use tracing::instrument;
+use std::{collections::BTreeMap, sync::Arc};
+use tracing::{instrument, Instrument};
 use uuid::Uuid;
 
 lazy_static! {
@@ -34,6 +47,8 @@ lazy_static! {
         register_int_counter_vec!("updates", "Event updates", &["result"]).unwrap();
     static ref PROCESSING_TIME: Histogram =
         register_histogram!("processing_time", "Time required to process events").unwrap();
+    static ref DUPLICATES: IntCounter =
+        register_int_counter!("duplicate_events", "Number of skipped, redelivered events").unwrap();
 }
 
 #[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -43,6 +58,61 @@ pub struct Event {
     pub application: String,
     pub thing: String,
     pub message: Message,
+    /// The ID correlating this event with the request or event which caused it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// The upstream message this event originates from, if injected from an external system.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+/// The annotation recording the source system of the last injected event.
+pub const ANNOTATION_PROVENANCE_SOURCE: &str = "drogue.io/provenance-source";
+/// The annotation recording the upstream message ID of the last injected event.
+pub const ANNOTATION_PROVENANCE_MESSAGE_ID: &str = "drogue.io/provenance-message-id";
+/// The annotation recording when the last injected event was received.
+pub const ANNOTATION_PROVENANCE_RECEIVED: &str = "drogue.io/provenance-received";
+/// The annotation recording the injector instance of the last injected event.
+pub const ANNOTATION_PROVENANCE_INJECTOR: &str = "drogue.io/provenance-injector";
+
+/// The origin of an event, injected from an external system.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provenance {
+    /// The system the upstream message originates from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// The ID of the upstream message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// The time the upstream message was received.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received: Option<DateTime<Utc>>,
+    /// The instance of the injector which received the upstream message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub injector: Option<String>,
+}
+
+impl InfallibleUpdater for Provenance {
+    /// Record the provenance as annotations, replacing the provenance of a previous event.
+    fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        let annotations = &mut thing.metadata.annotations;
+        for (key, value) in [
+            (ANNOTATION_PROVENANCE_SOURCE, self.source.clone()),
+            (ANNOTATION_PROVENANCE_MESSAGE_ID, self.message_id.clone()),
+            (
+                ANNOTATION_PROVENANCE_RECEIVED,
+                self.received.map(|received| received.to_rfc3339()),
+            ),
+            (ANNOTATION_PROVENANCE_INJECTOR, self.injector.clone()),
+        ] {
+            match value {
+                Some(value) => annotations.insert(key.to_string(), value),
+                None => annotations.remove(key),
+            };
+        }
+        thing
+    }
 }
 
 impl Event {
@@ -53,12 +123,99 @@ impl Event {
     ) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
-            timestamp: Utc::now(),
+            timestamp: clock::now(),
             application: application.into(),
             thing: thing.into(),
             message: message.into(),
+            correlation_id: correlation::current(),
+            provenance: None,
         }
     }
+
+    /// The cloud events extension attributes of the event, besides its application and thing.
+    pub fn extensions(&self) -> Vec<(&'static str, String)> {
+        let mut extensions = Vec::new();
+
+        if let Some(correlation_id) = &self.correlation_id {
+            extensions.push(("correlationid", correlation_id.clone()));
+        }
+
+        if let Some(Provenance {
+            source,
+            message_id,
+            received,
+            injector,
+        }) = &self.provenance
+        {
+            if let Some(source) = source {
+                extensions.push(("provenancesource", source.clone()));
+            }
+            if let Some(message_id) = message_id {
+                extensions.push(("provenanceid", message_id.clone()));
+            }
+            if let Some(received) = received {
+                extensions.push(("provenancereceived", received.to_rfc3339()));
+            }
+            if let Some(injector) = injector {
+                extensions.push(("provenanceinjector", injector.clone()));
+            }
+        }
+
+        extensions
+    }
+
+    /// Encode the event as structured cloud event.
+    pub fn to_structured(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut builder = events::builder(
+            &self.id,
+            events::TYPE_EVENT,
+            &self.application,
+            &self.thing,
+            self.timestamp,
+        );
+        for (name, value) in self.extensions() {
+            builder = builder.extension(name, value);
+        }
+        events::to_structured(builder, &self.message)
+    }
+
+    /// Decode an event from a structured cloud event.
+    pub fn from_structured(payload: &[u8]) -> anyhow::Result<Self> {
+        let mut event: cloudevents::Event = serde_json::from_slice(payload)?;
+
+        let extension = |name: &str| event.extension(name).map(ToString::to_string);
+
+        let application =
+            extension("application").ok_or_else(|| anyhow!("Missing 'application' extension"))?;
+        let thing = extension("thing").ok_or_else(|| anyhow!("Missing 'thing' extension"))?;
+        let correlation_id = extension("correlationid");
+        let provenance = Provenance {
+            source: extension("provenancesource"),
+            message_id: extension("provenanceid"),
+            received: extension("provenancereceived")
+                .map(|received| received.parse())
+                .transpose()?,
+            injector: extension("provenanceinjector"),
+        };
+
+        let id = event.id().to_string();
+        let timestamp = *event
+            .time()
+            .ok_or_else(|| anyhow!("Missing 'time' attribute"))?;
+        let message = event
+            .take_as_json()?
+            .ok_or_else(|| anyhow!("Missing data"))?;
+
+        Ok(Self {
+            id,
+            timestamp,
+            application,
+            thing,
+            message,
+            correlation_id,
+            provenance: (provenance != Provenance::default()).then_some(provenance),
+        })
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
@@ -102,6 +259,19 @@ pub enum Message {
         #[serde(rename = "$ref")]
         r#ref: String,
     },
+    /// Update the values of reference synthetic features, see [`crate::reference`].
+    UpdateReferences {
+        #[serde(default)]
+        values: BTreeMap<String, Value>,
+    },
+    /// The result of a command, acknowledged by the device, see [`service::CommandResultUpdater`].
+    CommandResult {
+        /// The name of the command (channel).
+        command: String,
+        success: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
 }
 
 #[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
@@ -191,11 +361,76 @@ impl InfallibleUpdater for ReportStateBuilder {
     }
 }
 
+/// Apply an updater only once for an event, skipping events which got redelivered.
+///
+/// This is required for events which are not idempotent, like JSON patches. The IDs of such
+/// events are recorded in the internal state of the thing, see [`Internal::processed`].
+pub struct Deduplicate<U: Updater> {
+    pub event_id: String,
+    pub updater: U,
+}
+
+impl<U: Updater> Updater for Deduplicate<U> {
+    type Error = U::Error;
+
+    fn update(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Self::Error> {
+        if let Some(internal) = &thing.internal {
+            if internal.is_processed(&self.event_id) {
+                tracing::info!(event_id = %self.event_id, "Skipping redelivered event");
+                DUPLICATES.inc();
+                return Ok(thing);
+            }
+        }
+
+        let mut thing = self.updater.update(thing)?;
+        thing
+            .internal
+            .get_or_insert_with(Default::default)
+            .processed(&self.event_id);
+
+        Ok(thing)
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Config<St: Storage, No: Notifier, Si: Sink, So: Source, Cmd: CommandSink> {
     #[serde(bound = "")]
     pub service: service::Config<St, No, Si, Cmd>,
     pub source: So::Config,
+    /// Rate limiting of state reports
+    #[serde(default)]
+    pub rate_limit: limit::Config,
+    /// Process events using their original timestamp as the current time, e.g. for replaying
+    #[serde(default)]
+    pub event_time: bool,
+    /// Publish events which permanently failed processing to a dead letter topic, see
+    /// [`dead_letter`]
+    #[serde(default)]
+    pub dead_letter: Option<sink::kafka::Config>,
+    /// The maximum number of events processed at the same time, events of the same thing are
+    /// still processed in order
+    #[serde(default = "default::concurrency")]
+    pub concurrency: usize,
+}
+
+pub mod default {
+    pub const fn concurrency() -> usize {
+        1
+    }
+}
+
+impl<St: Storage, No: Notifier, Si: Sink, So: Source, Cmd: CommandSink> Check
+    for Config<St, No, Si, So, Cmd>
+where
+    service::Config<St, No, Si, Cmd>: Check,
+    So::Config: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("service", &self.service)
+            .field("source", &self.source)
+            .field("dead_letter", &self.dead_letter);
+    }
 }
 
 pub struct Processor<St, No, Si, So, Cmd>
@@ -208,6 +443,10 @@ where
 {
     service: DefaultService<St, No, Si, Cmd>,
     source: So,
+    limiter: RateLimiter,
+    event_time: bool,
+    dead_letters: Option<DeadLetters>,
+    concurrency: usize,
 }
 
 impl<St, No, Si, So, Cmd> Processor<St, No, Si, So, Cmd>
@@ -224,12 +463,59 @@ where
     ) -> anyhow::Result<Self> {
         let service = DefaultService::from_config(startup, config.service)?;
         let source = So::from_config(config.source)?;
+        let dead_letters = config
+            .dead_letter
+            .map(DeadLetters::from_config)
+            .transpose()?;
 
-        Ok(Self::new(service, source))
+        Ok(Self::new(service, source)
+            .with_rate_limit(config.rate_limit)
+            .with_event_time(config.event_time)
+            .with_dead_letters(dead_letters)
+            .with_concurrency(config.concurrency))
     }
 
     pub fn new(service: DefaultService<St, No, Si, Cmd>, source: So) -> Self {
-        Self { service, source }
+        Self {
+            service,
+            source,
+            limiter: RateLimiter::new(Default::default()),
+            event_time: false,
+            dead_letters: None,
+            concurrency: default::concurrency(),
+        }
+    }
+
+    /// Set the rate limit for state reports.
+    pub fn with_rate_limit(mut self, config: limit::Config) -> Self {
+        self.limiter = RateLimiter::new(config);
+        self
+    }
+
+    /// Process events using their original timestamp as the current time.
+    ///
+    /// Processing a recorded stream of events this way reproduces the timestamps of the original
+    /// run, see [`crate::clock`].
+    pub fn with_event_time(mut self, event_time: bool) -> Self {
+        self.event_time = event_time;
+        self
+    }
+
+    /// Set the dead letter topic, for events which permanently failed processing.
+    ///
+    /// Without a dead letter topic, such events get dropped.
+    pub fn with_dead_letters(mut self, dead_letters: Option<DeadLetters>) -> Self {
+        self.dead_letters = dead_letters;
+        self
+    }
+
+    /// Set the maximum number of events processed at the same time.
+    ///
+    /// Events of the same thing are still processed one after the other, in the order they were
+    /// received. Sources not supporting this process one event at a time.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
     }
 
     /// Cleanup a thing, ignore if missing.
@@ -247,6 +533,8 @@ where
     {
         let opts = UpdateOptions {
             ignore_unclean_inbox: false,
+            resource_version: None,
+            unmodified_since: None,
         };
 
         loop {
@@ -254,11 +542,13 @@ where
             match thing {
                 Some(thing) => {
                     if thing.metadata.deletion_timestamp.is_some() {
-                        log::debug!("Thing is already being deleted");
+                        tracing::debug!("Thing is already being deleted");
                         // cleaned up
                         break;
                     }
-                    let thing = updater.update(thing)?;
+                    let thing = updater
+                        .update(thing)
+                        .map_err(|err| PermanentError::new("updater", err))?;
 
                     let result = if thing.metadata.deletion_timestamp.is_some() {
                         // perform delete
@@ -282,6 +572,9 @@ where
                             // ok, we clean up anyway
                             break;
                         }
+                        Err(service::Error::Machine(err)) => {
+                            return Err(PermanentError::new("machine", err).into());
+                        }
                         Err(err) => {
                             return Err(anyhow!(err));
                         }
@@ -309,6 +602,8 @@ where
     {
         let opts = UpdateOptions {
             ignore_unclean_inbox: false,
+            resource_version: None,
+            unmodified_since: None,
         };
 
         // FIXME: consider taking this into the service
@@ -317,7 +612,9 @@ where
             let thing = service.get(&id).await?;
             match thing {
                 Some(thing) => {
-                    let thing = updater.update(thing)?;
+                    let thing = updater
+                        .update(thing)
+                        .map_err(|err| PermanentError::new("updater", err))?;
                     match service.update(&id, &thing, &opts).await {
                         Ok(_) => {
                             break;
@@ -328,6 +625,9 @@ where
                             // retry
                             continue;
                         }
+                        Err(service::Error::Machine(err)) => {
+                            return Err(PermanentError::new("machine", err).into());
+                        }
                         Err(err) => {
                             return Err(anyhow!(err));
                         }
@@ -335,7 +635,9 @@ where
                 }
                 None => {
                     let thing = Thing::new(&id.application, &id.thing);
-                    let thing = updater.update(thing)?;
+                    let thing = updater
+                        .update(thing)
+                        .map_err(|err| PermanentError::new("updater", err))?;
 
                     match service.create(thing).await {
                         Ok(_) => {
@@ -345,6 +647,9 @@ where
                             // retry
                             continue;
                         }
+                        Err(service::Error::Machine(err)) => {
+                            return Err(PermanentError::new("machine", err).into());
+                        }
                         Err(err) => {
                             return Err(anyhow!(err));
                         }
@@ -367,12 +672,14 @@ where
     {
         let opts = UpdateOptions {
             ignore_unclean_inbox: false,
+            resource_version: None,
+            unmodified_since: None,
         };
 
         loop {
             match service.update(id, &updater, &opts).await {
                 Ok(_) => {
-                    log::debug!("Processing complete ... ok!");
+                    tracing::debug!("Processing complete ... ok!");
                     UPDATES.with_label_values(&["ok"]).inc();
                     break;
                 }
@@ -383,7 +690,7 @@ where
                 }
                 Err(service::Error::Storage(storage::Error::NotFound)) => {
                     UPDATES.with_label_values(&["not-found"]).inc();
-                    log::info!("Thing not found: {id}");
+                    tracing::info!("Thing not found");
                     // the thing does not exists, skip
                     break;
                 }
@@ -394,22 +701,20 @@ where
                 }
                 Err(service::Error::Notifier(err)) => {
                     UPDATES.with_label_values(&["notifier"]).inc();
-                    log::warn!("Failed to notify: {err}");
-                    // not much we can do
-                    // FIXME: consider using a circuit breaker
+                    tracing::warn!(%err, "Failed to notify");
+                    // not much we can do, the service already retried
                     break;
                 }
                 Err(service::Error::Machine(err)) => {
                     UPDATES.with_label_values(&["machine"]).inc();
-                    log::info!("Failed to process state machine: {err}");
-                    // the state machine turned the state into some error (e.g. validation)
-                    // ignore and continue
-                    // FIXME: consider adding a "status" field with the error
-                    break;
+                    tracing::info!(%err, "Failed to process state machine");
+                    // the state machine turned the state into some error (e.g. validation),
+                    // processing the event again would fail the same way
+                    return Err(PermanentError::new("machine", err).into());
                 }
                 Err(err) => {
                     UPDATES.with_label_values(&["other"]).inc();
-                    log::warn!("Failed to process: {err}");
+                    tracing::warn!(%err, "Failed to process");
                     return Err(anyhow!("Failed to process: {err}"));
                 }
             }
@@ -420,70 +725,59 @@ where
 
     pub async fn run(self) -> anyhow::Result<()> {
         self.source
-            .run(|event| async {
-                log::debug!("Event: {event:?}");
+            .run_concurrent(self.concurrency, |event| async {
+                tracing::debug!(?event, "Processing event");
                 EVENTS.inc();
 
                 let _timer = PROCESSING_TIME.start_timer();
 
+                // only keep a copy of the event when it may become a dead letter
+                let dead_letter = self.dead_letters.is_some().then(|| event.clone());
+
                 let Event {
-                    id: _,
-                    timestamp: _,
+                    id: event_id,
+                    timestamp,
                     application,
                     thing,
                     message,
+                    correlation_id,
+                    provenance,
                 } = event;
-                let id = Id { application, thing };
-
-                match message {
-                    Message::RegisterChild { r#ref, template } => {
-                        Self::run_upsert(
-                            &self.service,
-                            &id,
-                            MapValueInserter("$children".to_string(), r#ref).and_then(template),
-                        )
-                        .await?;
-                    }
-                    Message::UnregisterChild { r#ref } => {
-                        Self::run_cleanup(
-                            &self.service,
-                            &id,
-                            MapValueRemover("$children".to_string(), r#ref)
-                                .and_then(Cleanup("$children".to_string())),
-                        )
-                        .await?;
-                    }
-                    Message::ReportState { state, partial } => {
-                        Self::run_update(
-                            &self.service,
-                            &id,
-                            ReportedStateUpdater(
-                                state,
-                                match partial {
-                                    true => UpdateMode::Merge,
-                                    false => UpdateMode::Replace,
-                                },
-                            ),
-                        )
-                        .await?
-                    }
-                    Message::Merge(merge) => {
-                        Self::run_update(&self.service, &id, JsonMergeUpdater(merge)).await?
-                    }
-                    Message::Patch(patch) => {
-                        Self::run_update(&self.service, &id, JsonPatchUpdater(patch)).await?
-                    }
-                    Message::Wakeup { reasons: _ } => {
-                        // don't do any real change, this will just reconcile and process what is necessary
-                        Self::run_update(&self.service, &id, ()).await?
-                    }
-                    Message::SetDesiredValue { values } => {
-                        Self::run_update(&self.service, &id, DesiredStateValueUpdater(values))
-                            .await?
-                    }
-                }
 
-                Ok(())
+                let correlation_id = correlation_id.unwrap_or_else(|| event_id.clone());
+                let span = tracing::info_span!(
+                    "event",
+                    event_id = %event_id,
+                    application = %application,
+                    thing = %thing,
+                    correlation_id = %correlation_id,
+                    provenance_source = provenance.as_ref().and_then(|p| p.source.as_deref()),
+                    provenance_id = provenance.as_ref().and_then(|p| p.message_id.as_deref()),
+                );
+
+                let process = correlation::scope(
+                    correlation_id,
+                    Self::process(
+                        &self.service,
+                        &self.limiter,
+                        event_id,
+                        Id { application, thing },
+                        message,
+                        provenance,
+                    )
+                    .instrument(span),
+                );
+
+                let result = match self.event_time {
+                    true => clock::scope(Arc::new(FixedClock(timestamp)), process).await,
+                    false => process.await,
+                };
+
+                match result.map_err(|err| err.downcast::<PermanentError>()) {
+                    Ok(()) => Ok(()),
+                    Err(Ok(err)) => Self::dead_letter(&self.dead_letters, dead_letter, err).await,
+                    Err(Err(err)) => Err(err),
+                }
             })
             .await?;
 
@@ -491,4 +785,266 @@ where
 
         Ok(())
     }
+
+    /// Schedule the flush of a coalesced state report, at the end of the rate limit window.
+    ///
+    /// The flush is sent as wakeup event, so that it gets processed in order with the other events
+    /// of the thing.
+    fn schedule_flush(service: &DefaultService<St, No, Si, Cmd>, limiter: &RateLimiter, id: &Id) {
+        let at = match limiter.schedule_flush(id) {
+            Some(at) => at,
+            None => return,
+        };
+
+        let mut event = Event::new(
+            &id.application,
+            &id.thing,
+            Message::Wakeup {
+                reasons: vec![WakerReason::RateLimit],
+            },
+        );
+        event.timestamp = at;
+
+        let delay = (at - clock::now()).to_std().unwrap_or_default();
+        let sink = service.sink().clone();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(err) = sink.publish(event).await {
+                tracing::warn!(%err, "Failed to schedule flush of rate limited state report");
+            }
+        });
+    }
+
+    /// Handle an event which permanently failed processing.
+    async fn dead_letter(
+        dead_letters: &Option<DeadLetters>,
+        event: Option<Event>,
+        err: PermanentError,
+    ) -> anyhow::Result<()> {
+        match (dead_letters, event) {
+            (Some(dead_letters), Some(event)) => {
+                tracing::info!(%err, "Publishing event to dead letter topic");
+                // if that fails too, fail processing, so that the event gets retried
+                dead_letters.publish(&event, &err).await
+            }
+            _ => {
+                tracing::info!(%err, "Dropping event");
+                Ok(())
+            }
+        }
+    }
+
+    async fn process(
+        service: &DefaultService<St, No, Si, Cmd>,
+        limiter: &RateLimiter,
+        event_id: String,
+        id: Id,
+        message: Message,
+        provenance: Option<Provenance>,
+    ) -> anyhow::Result<()> {
+        match message {
+            Message::RegisterChild { r#ref, template } => {
+                Self::run_upsert(
+                    service,
+                    &id,
+                    MapValueInserter("$children".to_string(), r#ref).and_then(template),
+                )
+                .await?;
+            }
+            Message::UnregisterChild { r#ref } => {
+                Self::run_cleanup(
+                    service,
+                    &id,
+                    MapValueRemover("$children".to_string(), r#ref)
+                        .and_then(Cleanup("$children".to_string())),
+                )
+                .await?;
+            }
+            Message::ReportState { state, partial } => {
+                let Report { state, partial } = match limiter.check(&id, Report { state, partial })
+                {
+                    Some(report) => report,
+                    None => {
+                        tracing::debug!("State report exceeded rate limit");
+                        Self::schedule_flush(service, limiter, &id);
+                        return Ok(());
+                    }
+                };
+
+                Self::run_update(
+                    service,
+                    &id,
+                    ReportedStateUpdater(
+                        state,
+                        match partial {
+                            true => UpdateMode::Merge,
+                            false => UpdateMode::Replace,
+                        },
+                    )
+                    .and_then(provenance),
+                )
+                .await?
+            }
+            Message::Merge(merge) => {
+                Self::run_update(service, &id, JsonMergeUpdater(merge)).await?
+            }
+            Message::Patch(patch) => {
+                // applying a patch twice might not have the same result
+                let updater = Deduplicate {
+                    event_id,
+                    updater: JsonPatchUpdater(patch),
+                };
+                Self::run_update(service, &id, updater).await?
+            }
+            Message::Wakeup { reasons } if reasons.contains(&WakerReason::RateLimit) => {
+                if let Some(Report { state, partial }) = limiter.flush(&id) {
+                    Self::run_update(
+                        service,
+                        &id,
+                        ReportedStateUpdater(
+                            state,
+                            match partial {
+                                true => UpdateMode::Merge,
+                                false => UpdateMode::Replace,
+                            },
+                        )
+                        .and_then(provenance),
+                    )
+                    .await?
+                }
+                // the window might still be exhausted
+                Self::schedule_flush(service, limiter, &id);
+            }
+            Message::Wakeup { reasons } if reasons.contains(&WakerReason::Expiry) => {
+                // delete the thing if it expired, otherwise this reconciles like any other wakeup
+                Self::run_cleanup(service, &id, Expire).await?
+            }
+            Message::Wakeup { reasons: _ } => {
+                // don't do any real change, this will just reconcile and process what is necessary
+                Self::run_update(service, &id, ()).await?
+            }
+            Message::SetDesiredValue { values } => {
+                Self::run_update(service, &id, DesiredStateValueUpdater(values)).await?
+            }
+            Message::UpdateReferences { values } => {
+                Self::run_update(service, &id, ReferenceUpdater(values)).await?
+            }
+            Message::CommandResult {
+                command,
+                success,
+                reason,
+            } => {
+                Self::run_update(
+                    service,
+                    &id,
+                    CommandResultUpdater {
+                        command,
+                        success,
+                        reason,
+                    },
+                )
+                .await?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::InternalThingExt;
+
+    #[test]
+    fn test_provenance_annotations() {
+        let received = Utc::now();
+        let provenance = Provenance {
+            source: Some("drogue://default/device".to_string()),
+            message_id: Some("4711".to_string()),
+            received: Some(received),
+            injector: None,
+        };
+
+        let mut thing = Thing::new("default", "thing");
+        thing.metadata.annotations.insert(
+            ANNOTATION_PROVENANCE_INJECTOR.to_string(),
+            "old".to_string(),
+        );
+
+        let thing = InfallibleUpdater::update(&provenance, thing);
+
+        assert_eq!(
+            thing.metadata.annotations,
+            [
+                (
+                    ANNOTATION_PROVENANCE_SOURCE,
+                    "drogue://default/device".to_string()
+                ),
+                (ANNOTATION_PROVENANCE_MESSAGE_ID, "4711".to_string()),
+                (ANNOTATION_PROVENANCE_RECEIVED, received.to_rfc3339()),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_structured() {
+        let mut event = Event::new(
+            "default",
+            "thing",
+            Message::Merge(serde_json::json!({"reportedState": {"temperature": 42}})),
+        );
+        event.correlation_id = Some("4711".to_string());
+        event.provenance = Some(Provenance {
+            source: Some("drogue://default/device".to_string()),
+            received: Some(Utc::now()),
+            ..Default::default()
+        });
+
+        let payload = event.to_structured().unwrap();
+        assert_eq!(Event::from_structured(&payload).unwrap(), event);
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let updater = Deduplicate {
+            event_id: "event-1".to_string(),
+            updater: JsonPatchUpdater(
+                serde_json::from_value(serde_json::json!([
+                    {"op": "add", "path": "/metadata/labels", "value": {"count": "1"}},
+                ]))
+                .unwrap(),
+            ),
+        };
+
+        let thing = Updater::update(&updater, Thing::new("default", "thing")).unwrap();
+        assert_eq!(thing.metadata.labels["count"], "1");
+        assert_eq!(thing.internal.as_ref().unwrap().processed, vec!["event-1"]);
+
+        // applying it again doesn't change anything
+        let mut changed = thing.clone();
+        changed.metadata.labels.clear();
+        let changed = Updater::update(&updater, changed).unwrap();
+        assert!(changed.metadata.labels.is_empty());
+        assert_eq!(changed.internal, thing.internal);
+
+        // the processed events are persisted, even without pending work
+        let stored: Thing<Internal> = serde_json::from_value(thing.to_value().unwrap()).unwrap();
+        assert_eq!(stored.internal, thing.internal);
+
+        // and kept by the next event
+        let next = Deduplicate {
+            event_id: "event-2".to_string(),
+            updater: updater.updater,
+        };
+        let thing = Updater::update(&next, stored).unwrap();
+        assert_eq!(
+            thing.internal.as_ref().unwrap().processed,
+            vec!["event-1", "event-2"]
+        );
+    }
 }
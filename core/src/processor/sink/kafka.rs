@@ -1,13 +1,19 @@
-use crate::config::kafka::KafkaProperties;
+use crate::config::{
+    check::{Check, Checker},
+    kafka::KafkaProperties,
+};
+use crate::events::{self, Format};
 use crate::kafka::{AddHeader, KafkaHeaders};
 use crate::processor::Event;
 use anyhow::anyhow;
 use async_trait::async_trait;
+use futures::future::join_all;
 use opentelemetry::global::get_text_map_propagator;
 use rdkafka::config::FromClientConfig;
 use rdkafka::message::OwnedHeaders;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use std::{collections::HashMap, time::Duration};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
 use tracing::instrument;
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -18,6 +24,90 @@ pub struct Config {
     pub topic: String,
     #[serde(with = "humantime_serde", default = "default::timeout")]
     pub timeout: Duration,
+
+    /// Publish tombstones (records without a payload) for deleted things.
+    ///
+    /// As all events are keyed by application and thing, this allows compacting the topic.
+    #[serde(default)]
+    pub tombstones: bool,
+
+    /// The format of the events.
+    ///
+    /// Plain JSON events carry the cloud events attributes as headers (binary mode), structured
+    /// cloud events carry them in the payload.
+    #[serde(default)]
+    pub format: Format,
+
+    /// Publish events in Kafka transactions, using this transactional ID.
+    ///
+    /// This makes publishing multiple events at once atomic, either all or none of them get
+    /// published. The ID must be unique for each instance of the producer. Consumers must use the
+    /// `read_committed` isolation level to not see events of aborted transactions.
+    #[serde(default)]
+    pub transactional_id: Option<String>,
+
+    /// The key of the records, which selects the partition of the events.
+    ///
+    /// Events with the same key are kept in order. Keying by thing spreads the events over all
+    /// partitions, keying by application keeps all events of an application in order.
+    #[serde(default)]
+    pub partitioning: Partitioning,
+}
+
+/// The key of the event records.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Partitioning {
+    /// Key by application and thing.
+    #[default]
+    Thing,
+    /// Key by application.
+    Application,
+    /// Key by a template, replacing `{application}` and `{thing}`.
+    Template(String),
+}
+
+impl Partitioning {
+    /// The key of the records of a thing.
+    pub fn key(&self, application: &str, thing: &str) -> String {
+        match self {
+            Self::Thing => key(application, thing),
+            Self::Application => application.to_string(),
+            Self::Template(template) => template
+                .replace("{application}", application)
+                .replace("{thing}", thing),
+        }
+    }
+
+    /// Check if each thing gets its own key.
+    fn is_per_thing(&self) -> bool {
+        match self {
+            Self::Thing => true,
+            Self::Application => false,
+            Self::Template(template) => {
+                template.contains("{application}") && template.contains("{thing}")
+            }
+        }
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .kafka("properties", &self.properties)
+            .not_empty("topic", &self.topic);
+
+        if let Partitioning::Template(template) = &self.partitioning {
+            checker.not_empty("partitioning.template", template);
+        }
+        // compacting the topic would remove the events of other things
+        if self.tombstones && !self.partitioning.is_per_thing() {
+            checker.issue(
+                "tombstones",
+                "requires the partitioning to have a key for each thing",
+            );
+        }
+    }
 }
 
 mod default {
@@ -33,6 +123,170 @@ pub struct Sink {
     producer: FutureProducer,
     topic: String,
     timeout: Duration,
+    tombstones: bool,
+    format: Format,
+    partitioning: Partitioning,
+    /// Only one transaction can be active on a producer at a time.
+    transactions: Option<Arc<Mutex<()>>>,
+}
+
+/// The key of all records of a thing.
+pub fn key(application: &str, thing: &str) -> String {
+    format!("{application}/{thing}")
+}
+
+impl Sink {
+    async fn send(&self, record: FutureRecord<'_, String, Vec<u8>>) -> anyhow::Result<()> {
+        if let Err((err, _)) = self.producer.send(record, self.timeout).await {
+            Err(anyhow!(err))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run the future in a transaction, if transactions are enabled.
+    ///
+    /// The transaction gets committed if the future succeeds, and aborted otherwise.
+    async fn transactional<T>(
+        &self,
+        f: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let Some(transactions) = &self.transactions else {
+            return f.await;
+        };
+
+        let _lock = transactions.lock().await;
+
+        self.producer.begin_transaction()?;
+
+        // committing and aborting blocks until the broker responded
+        let producer = self.producer.clone();
+        let timeout = self.timeout;
+
+        match f.await {
+            Ok(result) => {
+                tokio::task::spawn_blocking(move || producer.commit_transaction(timeout)).await??;
+                Ok(result)
+            }
+            Err(err) => {
+                if let Err(abort) =
+                    tokio::task::spawn_blocking(move || producer.abort_transaction(timeout)).await?
+                {
+                    log::warn!("Failed to abort transaction: {abort}");
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Publish all events at once, before waiting for them to be delivered.
+    ///
+    /// This allows the producer to batch the records, instead of waiting for each record to be
+    /// delivered, before sending the next one. In case of an error, the index of the first
+    /// failed event is returned. Later events might still have been delivered.
+    async fn publish_batch(&self, events: &[Event]) -> Result<(), (usize, anyhow::Error)> {
+        let (records, headers): (Vec<_>, Vec<_>) = events
+            .iter()
+            .map(|event| self.encode(event, &[]))
+            .collect::<anyhow::Result<Vec<_>>>()
+            // nothing got sent so far
+            .map_err(|err| (0, err))?
+            .into_iter()
+            .map(|(key, payload, headers)| ((key, payload), headers))
+            .unzip();
+
+        let results = join_all(
+            records
+                .iter()
+                .zip(headers)
+                .map(|((key, payload), headers)| {
+                    self.send(
+                        FutureRecord::to(&self.topic)
+                            .key(key)
+                            .payload(payload)
+                            .headers(headers),
+                    )
+                }),
+        )
+        .await;
+
+        for (n, result) in results.into_iter().enumerate() {
+            if let Err(err) = result {
+                return Err((n, err));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish an event, adding the additional headers to the record.
+    pub async fn publish_with_headers(
+        &self,
+        event: &Event,
+        additional: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        let (key, payload, headers) = self.encode(event, additional)?;
+
+        let record = FutureRecord::to(&self.topic)
+            .key(&key)
+            .payload(&payload)
+            .headers(headers);
+
+        self.transactional(self.send(record)).await
+    }
+
+    /// Encode the event into the key, payload and headers of its record.
+    fn encode(
+        &self,
+        event: &Event,
+        additional: &[(&str, &str)],
+    ) -> anyhow::Result<(String, Vec<u8>, OwnedHeaders)> {
+        let key = self.partitioning.key(&event.application, &event.thing);
+
+        let (payload, mut headers) = match self.format {
+            Format::Json => Self::encode_binary(event)?,
+            Format::CloudEvents => Self::encode_structured(event)?,
+        };
+        for (name, value) in additional {
+            headers = headers.add(name, *value);
+        }
+
+        let mut headers = KafkaHeaders::from(headers);
+        get_text_map_propagator(|prop| {
+            prop.inject(&mut headers);
+        });
+
+        Ok((key, payload, headers.into()))
+    }
+
+    /// Encode the event as plain JSON, with binary mode cloud events headers.
+    fn encode_binary(event: &Event) -> anyhow::Result<(Vec<u8>, OwnedHeaders)> {
+        let payload = serde_json::to_vec(&event.message)?;
+
+        let mut headers = OwnedHeaders::new()
+            .add("ce_specversion", "1.0")
+            .add("ce_id", &event.id)
+            .add("ce_source", events::SOURCE)
+            .add("ce_type", events::TYPE_EVENT)
+            .add("ce_timestamp", &event.timestamp.to_rfc3339())
+            .add("content-type", Format::Json.content_type())
+            .add("ce_application", &event.application)
+            .add("ce_thing", &event.thing);
+        for (name, value) in event.extensions() {
+            headers = headers.add(format!("ce_{name}"), &value);
+        }
+
+        Ok((payload, headers))
+    }
+
+    /// Encode the event as structured cloud event.
+    fn encode_structured(event: &Event) -> anyhow::Result<(Vec<u8>, OwnedHeaders)> {
+        let payload = event.to_structured()?;
+
+        let headers = OwnedHeaders::new().add("content-type", Format::CloudEvents.content_type());
+
+        Ok((payload, headers))
+    }
 }
 
 #[async_trait]
@@ -44,15 +298,41 @@ impl super::Sink for Sink {
             properties,
             topic,
             timeout,
+            tombstones,
+            format,
+            transactional_id,
+            partitioning,
         }: Self::Config,
     ) -> anyhow::Result<Self> {
-        let config: rdkafka::ClientConfig = KafkaProperties(properties).into();
+        // a tombstone would compact away the events of all things sharing the key
+        if tombstones && !partitioning.is_per_thing() {
+            anyhow::bail!(
+                "Tombstones require the partitioning to have a key for each thing: {partitioning:?}"
+            );
+        }
+
+        let mut config: rdkafka::ClientConfig = KafkaProperties(properties).into();
+        if let Some(transactional_id) = &transactional_id {
+            config.set("transactional.id", transactional_id);
+        }
         let producer = FutureProducer::from_config(&config)?;
 
+        let transactions = match transactional_id {
+            Some(_) => {
+                producer.init_transactions(timeout)?;
+                Some(Default::default())
+            }
+            None => None,
+        };
+
         Ok(Self {
             producer,
             topic,
             timeout,
+            tombstones,
+            format,
+            partitioning,
+            transactions,
         })
     }
 
@@ -63,35 +343,69 @@ impl super::Sink for Sink {
         thing = event.thing
     ), err)]
     async fn publish(&self, event: Event) -> anyhow::Result<()> {
-        let key = format!("{}/{}", event.application, event.thing);
+        self.publish_with_headers(&event, &[]).await
+    }
 
-        let payload = serde_json::to_vec(&event.message)?;
+    async fn publish_iter<I>(&self, i: I) -> Result<(), (usize, anyhow::Error)>
+    where
+        I: IntoIterator<Item = Event> + Send + Sync,
+        <I as IntoIterator>::IntoIter: Send + Sync,
+    {
+        let events = i.into_iter().collect::<Vec<_>>();
 
-        let headers = OwnedHeaders::new()
-            .add("ce_specversion", "1.0")
-            .add("ce_id", &event.id)
-            .add("ce_source", "drogue-doppelgaenger")
-            .add("ce_type", "io.drogue.doppelgeanger.event.v1")
-            .add("ce_timestamp", &event.timestamp.to_rfc3339())
-            .add("content-type", "application/json")
-            .add("ce_application", &event.application)
-            .add("ce_thing", &event.thing);
+        match self.transactions {
+            None => self.publish_batch(&events).await,
+            // in a transaction, either all or none of the events get published
+            Some(_) => self
+                .transactional(async { self.publish_batch(&events).await.map_err(|(_, err)| err) })
+                .await
+                .map_err(|err| (0, err)),
+        }
+    }
 
-        let mut headers = KafkaHeaders::from(headers);
-        get_text_map_propagator(|prop| {
-            prop.inject(&mut headers);
-        });
-        let headers = headers.into();
+    #[instrument(skip(self), err)]
+    async fn tombstone(&self, application: &str, thing: &str) -> anyhow::Result<()> {
+        if !self.tombstones {
+            return Ok(());
+        }
 
-        let record = FutureRecord::to(&self.topic)
-            .key(&key)
-            .payload(&payload)
-            .headers(headers);
+        let key = self.partitioning.key(application, thing);
+        let record = FutureRecord::to(&self.topic).key(&key);
 
-        if let Err((err, _)) = self.producer.send(record, self.timeout).await {
-            Err(anyhow!(err))
-        } else {
-            Ok(())
-        }
+        self.transactional(self.send(record)).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partitioning() {
+        assert_eq!(Partitioning::Thing.key("app", "thing"), "app/thing");
+        assert_eq!(Partitioning::Application.key("app", "thing"), "app");
+        assert_eq!(
+            Partitioning::Template("{application}:{thing}".into()).key("app", "thing"),
+            "app:thing"
+        );
+
+        assert!(Partitioning::Thing.is_per_thing());
+        assert!(!Partitioning::Application.is_per_thing());
+        assert!(!Partitioning::Template("{application}".into()).is_per_thing());
+    }
+
+    #[test]
+    fn test_tombstones_require_key_per_thing() {
+        let config = Config {
+            properties: Default::default(),
+            topic: "events".into(),
+            timeout: default::timeout(),
+            tombstones: true,
+            format: Default::default(),
+            transactional_id: None,
+            partitioning: Partitioning::Application,
+        };
+
+        assert!(<Sink as super::super::Sink>::from_config(config).is_err());
     }
 }
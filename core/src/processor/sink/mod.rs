@@ -1,4 +1,9 @@
+#[cfg(feature = "amqp")]
+pub mod amqp;
 pub mod kafka;
+pub mod nats;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
 
 use crate::processor::Event;
 use async_trait::async_trait;
@@ -13,6 +18,13 @@ pub trait Sink: Sized + Send + Sync + Clone + 'static {
 
     async fn publish(&self, event: Event) -> anyhow::Result<()>;
 
+    /// Publish a tombstone for a deleted thing.
+    ///
+    /// Sinks not supporting tombstones, or having them disabled, ignore this.
+    async fn tombstone(&self, _application: &str, _thing: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     async fn publish_iter<I>(&self, i: I) -> Result<(), (usize, anyhow::Error)>
     where
         I: IntoIterator<Item = Event> + Send + Sync,
@@ -0,0 +1,159 @@
+//! Publishing events to a NATS JetStream stream.
+//!
+//! Events are published as structured cloud events, to the subject
+//! `<subject>.<partition>.<application>.<thing>`. The partition is derived from the application
+//! and thing, so all events of a thing end up in the same partition, keeping their order.
+
+use crate::config::check::{Check, Checker};
+use crate::processor::{sink::kafka::key, Event};
+use anyhow::anyhow;
+use async_nats::jetstream::{self, stream};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The NATS servers, as comma separated list of URLs, e.g. `nats://localhost:4222`.
+    pub servers: String,
+    /// The JetStream stream, created if it doesn't exist.
+    #[serde(default = "default::stream")]
+    pub stream: String,
+    /// The prefix of the subjects, the events get published to.
+    #[serde(default = "default::subject")]
+    pub subject: String,
+    /// The number of partitions, the events get distributed to.
+    #[serde(default = "default::partitions")]
+    pub partitions: u32,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .nats("servers", &self.servers)
+            .not_empty("stream", &self.stream)
+            .not_empty("subject", &self.subject);
+        if self.partitions == 0 {
+            checker.issue("partitions", "must be greater than zero");
+        }
+    }
+}
+
+pub(crate) mod default {
+    pub fn stream() -> String {
+        "doppelgaenger-events".to_string()
+    }
+
+    pub fn subject() -> String {
+        "doppelgaenger.events".to_string()
+    }
+
+    pub const fn partitions() -> u32 {
+        1
+    }
+}
+
+/// The partition of a thing, the same for all events of the thing.
+pub fn partition(application: &str, thing: &str, partitions: u32) -> u32 {
+    let hash = Sha256::digest(key(application, thing).as_bytes());
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % partitions.max(1)
+}
+
+/// Connect to JetStream, creating the stream if it doesn't exist yet.
+pub(crate) async fn connect(
+    servers: &str,
+    stream: &str,
+    subject: &str,
+) -> anyhow::Result<jetstream::Context> {
+    let client = async_nats::connect(servers).await?;
+    let context = jetstream::new(client);
+
+    context
+        .get_or_create_stream(stream::Config {
+            name: stream.to_string(),
+            subjects: vec![format!("{subject}.>")],
+            ..Default::default()
+        })
+        .await
+        .map_err(|err| anyhow!("Failed to create stream '{stream}': {err}"))?;
+
+    Ok(context)
+}
+
+#[derive(Clone)]
+pub struct Sink {
+    config: Arc<Config>,
+    context: Arc<OnceCell<jetstream::Context>>,
+}
+
+impl Sink {
+    async fn context(&self) -> anyhow::Result<&jetstream::Context> {
+        // the client takes care of reconnecting, so we only need to create it once
+        self.context
+            .get_or_try_init(|| {
+                connect(
+                    &self.config.servers,
+                    &self.config.stream,
+                    &self.config.subject,
+                )
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    type Config = Config;
+
+    fn from_config(config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: Arc::new(config),
+            context: Default::default(),
+        })
+    }
+
+    #[instrument(skip_all, fields(
+        id = event.id,
+        timestamp = %event.timestamp,
+        application = event.application,
+        thing = event.thing
+    ), err)]
+    async fn publish(&self, event: Event) -> anyhow::Result<()> {
+        let partition = partition(&event.application, &event.thing, self.config.partitions);
+        let subject = format!(
+            "{}.{partition}.{}.{}",
+            self.config.subject, event.application, event.thing
+        );
+        let payload = event.to_structured()?;
+
+        // wait for the stream to acknowledge the event
+        self.context()
+            .await?
+            .publish(subject, payload.into())
+            .await
+            .map_err(|err| anyhow!("Failed to publish event: {err}"))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_partition() {
+        assert_eq!(partition("default", "thing1", 1), 0);
+
+        let partitions = (0..100)
+            .map(|i| partition("default", &format!("thing{i}"), 4))
+            .collect::<Vec<_>>();
+        assert!(partitions.iter().all(|p| *p < 4));
+        // stable for the same thing
+        assert_eq!(partition("default", "thing1", 4), partitions[1]);
+        // distributes things
+        assert!((0..4).all(|p| partitions.contains(&p)));
+    }
+}
@@ -0,0 +1,108 @@
+//! Publishing events to a Google Cloud Pub/Sub topic.
+//!
+//! Events are published as structured cloud events. The ordering key of each message is the
+//! application and thing, so that subscriptions with message ordering enabled receive the events
+//! of a thing in order.
+
+use crate::config::check::{Check, Checker};
+use crate::events::CONTENT_TYPE_STRUCTURED;
+use crate::processor::{sink::kafka::key, Event};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use google_cloud_pubsub::{
+    client::{Client, ClientConfig},
+    publisher::Publisher,
+};
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The GCP project, defaults to the project of the credentials.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// The topic, the events get published to.
+    pub topic: String,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker.not_empty("topic", &self.topic);
+    }
+}
+
+/// Create a Pub/Sub client.
+///
+/// The credentials are taken from the environment (e.g. `GOOGLE_APPLICATION_CREDENTIALS`), or
+/// the emulator is used, if `PUBSUB_EMULATOR_HOST` is set.
+pub(crate) async fn client(project_id: Option<String>) -> anyhow::Result<Client> {
+    let config = ClientConfig {
+        project_id,
+        ..Default::default()
+    };
+
+    Client::new(config)
+        .await
+        .map_err(|err| anyhow!("Failed to create Pub/Sub client: {err}"))
+}
+
+#[derive(Clone)]
+pub struct Sink {
+    config: Arc<Config>,
+    publisher: Arc<OnceCell<Publisher>>,
+}
+
+impl Sink {
+    async fn publisher(&self) -> anyhow::Result<&Publisher> {
+        self.publisher
+            .get_or_try_init(|| async {
+                let client = client(self.config.project_id.clone()).await?;
+                Ok(client.topic(&self.config.topic).new_publisher(None))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl super::Sink for Sink {
+    type Config = Config;
+
+    fn from_config(config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: Arc::new(config),
+            publisher: Default::default(),
+        })
+    }
+
+    #[instrument(skip_all, fields(
+        id = event.id,
+        timestamp = %event.timestamp,
+        application = event.application,
+        thing = event.thing
+    ), err)]
+    async fn publish(&self, event: Event) -> anyhow::Result<()> {
+        let message = PubsubMessage {
+            data: event.to_structured()?,
+            attributes: [(
+                "content-type".to_string(),
+                CONTENT_TYPE_STRUCTURED.to_string(),
+            )]
+            .into(),
+            ordering_key: key(&event.application, &event.thing),
+            ..Default::default()
+        };
+
+        // wait for the topic to acknowledge the event
+        self.publisher()
+            .await?
+            .publish(message)
+            .await
+            .get(None)
+            .await
+            .map_err(|err| anyhow!("Failed to publish event: {err}"))?;
+
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
-use crate::config::kafka::KafkaProperties;
-use crate::processor::Event;
+use crate::config::{
+    check::{Check, Checker},
+    kafka::KafkaProperties,
+};
+use crate::events::CONTENT_TYPE_STRUCTURED;
+use crate::processor::{Event, Provenance};
+use crate::service::Id;
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
 use rdkafka::{
     config::FromClientConfig,
     consumer::{Consumer, StreamConsumer},
@@ -9,7 +15,11 @@ use rdkafka::{
     Message,
 };
 use std::str::from_utf8;
-use std::{collections::HashMap, future::Future};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+};
+use tokio::sync::oneshot;
 use tracing::instrument;
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -20,10 +30,19 @@ pub struct Config {
     pub topic: String,
 }
 
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .kafka("properties", &self.properties)
+            .not_empty("topic", &self.topic);
+    }
+}
+
 pub struct EventStream {}
 
 pub struct Source {
     consumer: StreamConsumer,
+    topic: String,
 }
 
 impl Source {
@@ -65,7 +84,7 @@ impl super::Source for Source {
         let consumer = StreamConsumer::from_config(&config)?;
         consumer.subscribe(&[&topic])?;
 
-        Ok(Self { consumer })
+        Ok(Self { consumer, topic })
     }
 
     async fn run<F, Fut>(self, f: F) -> anyhow::Result<()>
@@ -81,6 +100,14 @@ impl super::Source for Source {
             let msg = consumer.recv().await;
 
             match msg {
+                Ok(msg) if is_tombstone(&msg) => {
+                    // deleted things have no events to process
+                    log::debug!("Skipping tombstone");
+                    if let Err(err) = consumer.store_offset_from_message(&msg) {
+                        log::warn!("Failed to store offset: {err}");
+                        break;
+                    }
+                }
                 Ok(msg) => {
                     match from_msg(&msg) {
                         Ok(event) => {
@@ -112,6 +139,169 @@ impl super::Source for Source {
 
         Ok(())
     }
+
+    async fn run_concurrent<F, Fut>(self, concurrency: usize, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        if concurrency <= 1 {
+            return self.run(f).await;
+        }
+
+        log::info!("Running event source loop, processing up to {concurrency} events at a time...");
+
+        let Self { consumer, topic } = self;
+        let f = &f;
+
+        let mut tasks = FuturesUnordered::new();
+        let mut sequencer = Sequencer::default();
+        let mut offsets = Offsets::default();
+
+        loop {
+            // the partition and offset of the message which got processed, if any
+            let completed = tokio::select! {
+                msg = consumer.recv(), if tasks.len() < concurrency => {
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("Failed to receive from Kafka: {err}");
+                            break;
+                        }
+                    };
+
+                    let (partition, offset) = (msg.partition(), msg.offset());
+                    offsets.started(partition, offset);
+
+                    let event = if is_tombstone(&msg) {
+                        // deleted things have no events to process
+                        log::debug!("Skipping tombstone");
+                        None
+                    } else {
+                        match from_msg(&msg) {
+                            Ok(event) => Some(event),
+                            Err(err) => {
+                                log::info!("Unable to parse message, skipping! Reason: {err}");
+                                None
+                            }
+                        }
+                    };
+
+                    match event {
+                        Some(event) => {
+                            log::debug!("Processing event: {event:?}");
+
+                            let id = Id::new(&event.application, &event.thing);
+                            let (seq, previous, done) = sequencer.schedule(id.clone());
+
+                            tasks.push(async move {
+                                if let Some(previous) = previous {
+                                    // if the previous event failed, we exit anyway
+                                    let _ = previous.await;
+                                }
+                                let result = Self::process(f, event).await;
+                                let _ = done.send(());
+                                (id, seq, partition, offset, result)
+                            });
+
+                            None
+                        }
+                        // we still store the offset, as we are skipping the message
+                        None => Some((partition, offset)),
+                    }
+                }
+                Some((id, seq, partition, offset, result)) = tasks.next() => {
+                    sequencer.completed(&id, seq);
+                    if let Err(err) = result {
+                        log::error!("Handler failed: {err}");
+                        break;
+                    }
+                    Some((partition, offset))
+                }
+            };
+
+            if let Some((partition, offset)) =
+                completed.and_then(|(partition, offset)| offsets.completed(partition, offset))
+            {
+                if let Err(err) = consumer.store_offset(&topic, partition, offset) {
+                    log::warn!("Failed to store offset: {err}");
+                    break;
+                }
+            }
+        }
+
+        log::warn!("Exiting consumer loop");
+
+        Ok(())
+    }
+}
+
+/// Keeps the events of a thing in order, while events of different things run concurrently.
+#[derive(Default)]
+struct Sequencer {
+    next: u64,
+    /// The last scheduled event of a thing, and its completion.
+    last: HashMap<Id, (u64, oneshot::Receiver<()>)>,
+}
+
+impl Sequencer {
+    /// Schedule an event of a thing.
+    ///
+    /// Returns the sequence number of the event, the completion of the previous event of the
+    /// same thing to wait for, and the sender to signal its own completion.
+    fn schedule(&mut self, id: Id) -> (u64, Option<oneshot::Receiver<()>>, oneshot::Sender<()>) {
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+
+        let (tx, rx) = oneshot::channel();
+        let previous = self.last.insert(id, (seq, rx)).map(|(_, rx)| rx);
+
+        (seq, previous, tx)
+    }
+
+    /// Forget about a thing, once its last scheduled event completed.
+    fn completed(&mut self, id: &Id, seq: u64) {
+        if matches!(self.last.get(id), Some((last, _)) if *last == seq) {
+            self.last.remove(id);
+        }
+    }
+}
+
+/// Tracks the offsets of messages being processed.
+///
+/// An offset may only be stored once all previous messages of the partition were processed.
+#[derive(Default)]
+struct Offsets {
+    partitions: HashMap<i32, VecDeque<(i64, bool)>>,
+}
+
+impl Offsets {
+    fn started(&mut self, partition: i32, offset: i64) {
+        self.partitions
+            .entry(partition)
+            .or_default()
+            .push_back((offset, false));
+    }
+
+    /// Mark a message as processed, returning the offset to store, if any.
+    ///
+    /// Like [`Consumer::store_offset_from_message`], the stored offset is the one of the next
+    /// message to read, so that the processed message doesn't get redelivered.
+    fn completed(&mut self, partition: i32, offset: i64) -> Option<(i32, i64)> {
+        let pending = self.partitions.get_mut(&partition)?;
+
+        if let Some(entry) = pending.iter_mut().find(|(o, _)| *o == offset) {
+            entry.1 = true;
+        }
+
+        let mut result = None;
+        while let Some((offset, true)) = pending.front().copied() {
+            pending.pop_front();
+            result = Some((partition, offset + 1));
+        }
+
+        result
+    }
 }
 
 /// Extract the ID (application, device) from the message.
@@ -161,9 +351,39 @@ fn extract_meta(msg: &BorrowedMessage) -> anyhow::Result<(String, String, String
     ))
 }
 
+/// Extract an optional header value.
+fn extract_header<'m>(msg: &'m BorrowedMessage, key: &str) -> Option<&'m str> {
+    msg.headers()?
+        .iter()
+        .find(|h| h.key == key)
+        .and_then(|h| h.value)
+        .and_then(|s| from_utf8(s).ok())
+}
+
+/// Check if the message is a tombstone of a deleted thing.
+pub(crate) fn is_tombstone(msg: &BorrowedMessage) -> bool {
+    msg.payload().is_none()
+}
+
+/// Check if the message is a structured cloud event.
+fn is_structured(msg: &BorrowedMessage) -> bool {
+    extract_header(msg, "content-type")
+        .map(|content_type| content_type.starts_with(CONTENT_TYPE_STRUCTURED))
+        .unwrap_or_default()
+}
+
 /// Parse a Kafka message into an [`Event`].
-fn from_msg(msg: &BorrowedMessage) -> anyhow::Result<Event> {
+///
+/// Accepts both plain JSON payloads, with binary mode cloud events headers, as well as structured
+/// cloud events.
+pub(crate) fn from_msg(msg: &BorrowedMessage) -> anyhow::Result<Event> {
+    if is_structured(msg) {
+        return Event::from_structured(msg.payload().ok_or_else(|| anyhow!("Missing payload"))?);
+    }
+
     let (id, timestamp, application, thing) = extract_meta(msg)?;
+    let correlation_id = extract_header(msg, "ce_correlationid").map(ToString::to_string);
+    let provenance = extract_provenance(msg)?;
 
     let message = serde_json::from_slice(msg.payload().ok_or_else(|| anyhow!("Missing payload"))?)?;
 
@@ -173,5 +393,77 @@ fn from_msg(msg: &BorrowedMessage) -> anyhow::Result<Event> {
         application,
         thing,
         message,
+        correlation_id,
+        provenance,
     })
 }
+
+/// Extract the provenance of an event, if any of its headers is present.
+fn extract_provenance(msg: &BorrowedMessage) -> anyhow::Result<Option<Provenance>> {
+    let provenance = Provenance {
+        source: extract_header(msg, "ce_provenancesource").map(ToString::to_string),
+        message_id: extract_header(msg, "ce_provenanceid").map(ToString::to_string),
+        received: extract_header(msg, "ce_provenancereceived")
+            .map(str::parse)
+            .transpose()?,
+        injector: extract_header(msg, "ce_provenanceinjector").map(ToString::to_string),
+    };
+
+    Ok((provenance != Provenance::default()).then_some(provenance))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_offsets() {
+        let mut offsets = Offsets::default();
+
+        offsets.started(0, 1);
+        offsets.started(0, 2);
+        offsets.started(1, 5);
+        offsets.started(0, 3);
+
+        // waiting for offset 1, the offset to store is the next one to read
+        assert_eq!(offsets.completed(0, 2), None);
+        assert_eq!(offsets.completed(1, 5), Some((1, 6)));
+        assert_eq!(offsets.completed(0, 1), Some((0, 3)));
+        assert_eq!(offsets.completed(0, 3), Some((0, 4)));
+        assert_eq!(offsets.completed(2, 0), None);
+    }
+
+    #[test]
+    fn test_offsets_next() {
+        let mut offsets = Offsets::default();
+
+        // the same offset as storing the offset from the message: the next one to read
+        offsets.started(0, 10);
+        assert_eq!(offsets.completed(0, 10), Some((0, 11)));
+        // nothing pending anymore
+        assert_eq!(offsets.completed(0, 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_sequencer() {
+        let mut sequencer = Sequencer::default();
+
+        let (seq1, previous, done1) = sequencer.schedule(Id::new("app", "thing1"));
+        assert!(previous.is_none());
+        let (_, previous, _) = sequencer.schedule(Id::new("app", "thing2"));
+        assert!(previous.is_none());
+
+        // waits for the first event of the same thing
+        let (seq2, previous, _) = sequencer.schedule(Id::new("app", "thing1"));
+        let mut previous = previous.unwrap();
+        assert!(previous.try_recv().is_err());
+        done1.send(()).unwrap();
+        assert!(previous.await.is_ok());
+
+        // only the last event removes the thing
+        sequencer.completed(&Id::new("app", "thing1"), seq1);
+        assert!(sequencer.last.contains_key(&Id::new("app", "thing1")));
+        sequencer.completed(&Id::new("app", "thing1"), seq2);
+        assert!(!sequencer.last.contains_key(&Id::new("app", "thing1")));
+    }
+}
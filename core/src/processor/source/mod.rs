@@ -1,4 +1,9 @@
+#[cfg(feature = "amqp")]
+pub mod amqp;
 pub mod kafka;
+pub mod nats;
+#[cfg(feature = "pubsub")]
+pub mod pubsub;
 
 use crate::processor::Event;
 use async_trait::async_trait;
@@ -16,4 +21,21 @@ pub trait Source: Sized + Send + Sync {
     where
         F: Fn(Event) -> Fut + Send + Sync,
         Fut: Future<Output = anyhow::Result<()>> + Send;
+
+    /// Run the source, processing up to `concurrency` events at the same time.
+    ///
+    /// Events of the same thing must still be processed in the order they were received. Sources
+    /// not supporting this process one event at a time.
+    async fn run_concurrent<F, Fut>(self, concurrency: usize, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        if concurrency > 1 {
+            log::info!(
+                "Source doesn't support concurrent processing, processing one event at a time"
+            );
+        }
+        self.run(f).await
+    }
 }
@@ -0,0 +1,168 @@
+//! Consuming events from a NATS JetStream stream, see [`crate::processor::sink::nats`].
+//!
+//! Events are consumed using a durable pull consumer, which only has a single unacknowledged
+//! event at a time. Events are acknowledged once they are processed, so that the events of a
+//! thing get processed in order, even when being redelivered.
+
+use crate::config::check::{Check, Checker};
+use crate::processor::{sink::nats::connect, Event};
+use anyhow::anyhow;
+use async_nats::jetstream::consumer::{pull, AckPolicy, PullConsumer};
+use async_trait::async_trait;
+use futures::StreamExt;
+use std::future::Future;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The NATS servers, as comma separated list of URLs, e.g. `nats://localhost:4222`.
+    pub servers: String,
+    /// The JetStream stream, created if it doesn't exist.
+    #[serde(default = "default::stream")]
+    pub stream: String,
+    /// The prefix of the subjects, must match the one of the sink.
+    #[serde(default = "default::subject")]
+    pub subject: String,
+    /// The name of the durable consumer.
+    #[serde(default = "default::consumer")]
+    pub consumer: String,
+    /// The partition to consume, all partitions if not set.
+    ///
+    /// The partition is appended to the name of the consumer.
+    #[serde(default)]
+    pub partition: Option<u32>,
+}
+
+mod default {
+    pub use crate::processor::sink::nats::default::{stream, subject};
+
+    pub fn consumer() -> String {
+        "doppelgaenger-processor".to_string()
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .nats("servers", &self.servers)
+            .not_empty("stream", &self.stream)
+            .not_empty("subject", &self.subject)
+            .not_empty("consumer", &self.consumer);
+    }
+}
+
+pub struct Source {
+    config: Config,
+}
+
+impl Source {
+    #[instrument(skip_all, fields(
+        id = event.id,
+        application = event.application,
+        thing = event.thing,
+    ))]
+    async fn process<F, Fut>(f: &F, event: Event) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        f(event).await
+    }
+
+    async fn consumer(&self) -> anyhow::Result<PullConsumer> {
+        let Config {
+            servers,
+            stream,
+            subject,
+            consumer,
+            partition,
+        } = &self.config;
+
+        let (name, filter_subject) = match partition {
+            Some(partition) => (
+                format!("{consumer}-{partition}"),
+                format!("{subject}.{partition}.>"),
+            ),
+            None => (consumer.clone(), format!("{subject}.>")),
+        };
+
+        let context = connect(servers, stream, subject).await?;
+        let stream = context
+            .get_stream(stream)
+            .await
+            .map_err(|err| anyhow!("Failed to get stream '{stream}': {err}"))?;
+
+        stream
+            .get_or_create_consumer(
+                &name,
+                pull::Config {
+                    durable_name: Some(name.clone()),
+                    filter_subject,
+                    ack_policy: AckPolicy::Explicit,
+                    // keep the order of events, even when they get redelivered
+                    max_ack_pending: 1,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|err| anyhow!("Failed to create consumer '{name}': {err}"))
+    }
+}
+
+#[async_trait]
+impl super::Source for Source {
+    type Config = Config;
+
+    fn from_config(config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self { config })
+    }
+
+    async fn run<F, Fut>(self, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        log::info!("Running event source loop...");
+
+        let mut messages = self
+            .consumer()
+            .await?
+            .messages()
+            .await
+            .map_err(|err| anyhow!("Failed to consume events: {err}"))?;
+
+        while let Some(msg) = messages.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::warn!("Failed to receive from NATS: {err}");
+                    break;
+                }
+            };
+
+            match Event::from_structured(&msg.payload) {
+                Ok(event) => {
+                    log::debug!("Processing event: {event:?}");
+
+                    if let Err(err) = Self::process(&f, event).await {
+                        log::error!("Handler failed: {err}");
+                        break;
+                    }
+                }
+                Err(err) => {
+                    log::info!("Unable to parse message, skipping! Reason: {err}");
+                    // we still acknowledge the message, as we are skipping it.
+                }
+            }
+
+            if let Err(err) = msg.ack().await {
+                log::warn!("Failed to acknowledge message: {err}");
+                break;
+            }
+        }
+
+        log::warn!("Exiting consumer loop");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Receiving events from a Google Cloud Pub/Sub subscription.
+//!
+//! Accepts structured cloud events, as well as binary mode cloud events, with the attributes as
+//! `ce-` prefixed message attributes and the JSON message as data. To process the events of a
+//! thing in order, the subscription must have message ordering enabled.
+
+use crate::config::check::{Check, Checker};
+use crate::events::CONTENT_TYPE_STRUCTURED;
+use crate::processor::{sink::pubsub::client, Event, Provenance};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use google_cloud_googleapis::pubsub::v1::PubsubMessage;
+use std::future::Future;
+use tracing::instrument;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The GCP project, defaults to the project of the credentials.
+    #[serde(default)]
+    pub project_id: Option<String>,
+    /// The subscription, the events get received from.
+    pub subscription: String,
+    /// The maximum number of messages pulled at once.
+    #[serde(default = "default::max_messages")]
+    pub max_messages: i32,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker.not_empty("subscription", &self.subscription);
+        if self.max_messages <= 0 {
+            checker.issue("max_messages", "must be greater than zero");
+        }
+    }
+}
+
+mod default {
+    pub const fn max_messages() -> i32 {
+        10
+    }
+}
+
+pub struct Source {
+    config: Config,
+}
+
+impl Source {
+    #[instrument(skip_all, fields(
+        id = event.id,
+        application = event.application,
+        thing = event.thing,
+    ))]
+    async fn process<F, Fut>(f: &F, event: Event) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        f(event).await
+    }
+}
+
+#[async_trait]
+impl super::Source for Source {
+    type Config = Config;
+
+    fn from_config(config: Self::Config) -> anyhow::Result<Self> {
+        Ok(Self { config })
+    }
+
+    async fn run<F, Fut>(self, f: F) -> anyhow::Result<()>
+    where
+        F: Fn(Event) -> Fut + Send + Sync,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        log::info!("Running event source loop...");
+
+        let client = client(self.config.project_id).await?;
+        let subscription = client.subscription(&self.config.subscription);
+
+        'outer: loop {
+            let messages = match subscription.pull(self.config.max_messages, None).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    log::warn!("Failed to pull from Pub/Sub: {err}");
+                    break;
+                }
+            };
+
+            for message in messages {
+                match from_message(&message.message) {
+                    Ok(event) => {
+                        log::debug!("Processing event: {event:?}");
+
+                        // messages which are not acknowledged get redelivered
+                        if let Err(err) = Self::process(&f, event).await {
+                            log::error!("Handler failed: {err}");
+                            break 'outer;
+                        }
+                    }
+                    Err(err) => {
+                        log::info!("Unable to parse message, skipping! Reason: {err}");
+                        // we still acknowledge the message, as we are skipping it.
+                    }
+                }
+
+                if let Err(err) = message.ack().await {
+                    log::warn!("Failed to acknowledge message: {err}");
+                    break 'outer;
+                }
+            }
+        }
+
+        log::warn!("Exiting consumer loop");
+
+        Ok(())
+    }
+}
+
+/// Parse a Pub/Sub message into an [`Event`].
+fn from_message(message: &PubsubMessage) -> anyhow::Result<Event> {
+    let attribute = |name: &str| message.attributes.get(name).cloned();
+
+    let structured = attribute("content-type")
+        .map(|content_type| content_type.starts_with(CONTENT_TYPE_STRUCTURED))
+        .unwrap_or_default();
+
+    if structured {
+        return Event::from_structured(&message.data);
+    }
+
+    let required = |name: &str| {
+        attribute(&format!("ce-{name}")).ok_or_else(|| anyhow!("Missing 'ce-{name}' attribute"))
+    };
+    let optional = |name: &str| attribute(&format!("ce-{name}"));
+
+    let provenance = Provenance {
+        source: optional("provenancesource"),
+        message_id: optional("provenanceid"),
+        received: optional("provenancereceived")
+            .map(|received| received.parse())
+            .transpose()?,
+        injector: optional("provenanceinjector"),
+    };
+
+    Ok(Event {
+        id: required("id")?,
+        timestamp: required("time")?.parse()?,
+        application: required("application")?,
+        thing: required("thing")?,
+        message: serde_json::from_slice(&message.data)?,
+        correlation_id: optional("correlationid"),
+        provenance: (provenance != Provenance::default()).then_some(provenance),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::Message;
+
+    #[test]
+    fn test_from_message() {
+        let event = Event::new(
+            "default",
+            "thing",
+            Message::Merge(serde_json::json!({"reportedState": {"temperature": 42}})),
+        );
+
+        let structured = PubsubMessage {
+            data: event.to_structured().unwrap(),
+            attributes: [(
+                "content-type".to_string(),
+                CONTENT_TYPE_STRUCTURED.to_string(),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(from_message(&structured).unwrap(), event);
+
+        let binary = PubsubMessage {
+            data: serde_json::to_vec(&event.message).unwrap(),
+            attributes: [
+                ("ce-id", event.id.clone()),
+                ("ce-time", event.timestamp.to_rfc3339()),
+                ("ce-application", event.application.clone()),
+                ("ce-thing", event.thing.clone()),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            from_message(&binary).unwrap(),
+            Event {
+                correlation_id: None,
+                ..event
+            }
+        );
+    }
+}
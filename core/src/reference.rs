@@ -0,0 +1,539 @@
+//! Resolve synthetic features, which reference features of other things.
+//!
+//! A reference (see [`crate::model::Reference`]) reads the synthetic, or reported, value of a
+//! feature of another thing, possibly of a different application. Changes of the referenced
+//! thing are picked up from the notifier stream, and sent to the referencing things as an
+//! [`Message::UpdateReferences`] event.
+//!
+//! References to things of other applications must be allowed by the [`Policy`]. Denied
+//! references are not resolved, and keep their previous value.
+
+use crate::{
+    applications::Applications,
+    config::{
+        check::{Check, Checker},
+        kafka::KafkaProperties,
+    },
+    model::{InternalState, Reference, SyntheticType, Thing},
+    notifier,
+    processor::{sink::Sink, Event, Message},
+    storage::{self, postgres, Storage},
+};
+use lazy_static::lazy_static;
+use postgres_types::{Json, Type};
+use prometheus::{register_int_counter, IntCounter};
+use rdkafka::{
+    config::FromClientConfig,
+    consumer::{Consumer, StreamConsumer},
+    Message as _,
+};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+use tracing::instrument;
+
+lazy_static! {
+    static ref REFERENCE_UPDATES: IntCounter = register_int_counter!(
+        "reference_updates",
+        "Number of updates sent to referencing things"
+    )
+    .unwrap();
+    static ref REFERENCE_DENIED: IntCounter = register_int_counter!(
+        "reference_denied",
+        "Number of references denied by the policy"
+    )
+    .unwrap();
+}
+
+/// The wildcard, matching all applications.
+const ANY: &str = "*";
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// The storage, to look up referenced and referencing things.
+    pub storage: postgres::Config,
+
+    /// Kafka properties of the change events (notifier) topic.
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// The change events (notifier) topic.
+    pub topic: String,
+    /// The consumer group. Instances sharing the same group split the work among them.
+    pub group_id: String,
+
+    /// The references allowed between applications.
+    #[serde(default)]
+    pub allow: Policy,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        if !self.disabled {
+            checker
+                .field("storage", &self.storage)
+                .kafka("properties", &self.properties)
+                .not_empty("topic", &self.topic)
+                .not_empty("group_id", &self.group_id);
+        }
+    }
+}
+
+/// The references allowed between applications.
+///
+/// References within the same application are always allowed. References to other applications
+/// must be allowed explicitly, using a comma separated list of `<application>=<referenced>`
+/// entries, e.g. `site-a=weather,*=shared`. A `*` allows all applications.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Policy {
+    /// The referenced applications, by referencing application.
+    allowed: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid policy entry '{0}', expected '<application>=<referenced application>'")]
+pub struct PolicyError(String);
+
+impl FromStr for Policy {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut allowed = BTreeMap::<String, BTreeSet<String>>::new();
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            match entry.split_once('=') {
+                Some((application, referenced))
+                    if !application.trim().is_empty() && !referenced.trim().is_empty() =>
+                {
+                    allowed
+                        .entry(application.trim().to_string())
+                        .or_default()
+                        .insert(referenced.trim().to_string());
+                }
+                _ => return Err(PolicyError(entry.to_string())),
+            }
+        }
+
+        Ok(Self { allowed })
+    }
+}
+
+impl TryFrom<String> for Policy {
+    type Error = PolicyError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries = self
+            .allowed
+            .iter()
+            .flat_map(|(application, referenced)| {
+                referenced
+                    .iter()
+                    .map(move |referenced| format!("{application}={referenced}"))
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+impl Policy {
+    /// Check if things of an application may reference things of another application.
+    pub fn is_allowed(&self, application: &str, referenced: &str) -> bool {
+        if application == referenced {
+            return true;
+        }
+
+        [application, ANY].into_iter().any(|application| {
+            self.allowed.get(application).map_or(false, |allowed| {
+                allowed.contains(referenced) || allowed.contains(ANY)
+            })
+        })
+    }
+}
+
+/// A thing, referencing the changed thing.
+#[derive(Clone, Debug)]
+struct Referencing {
+    application: String,
+    thing: String,
+    /// The referenced features, and the current values, by synthetic feature.
+    features: BTreeMap<String, (String, Value)>,
+}
+
+impl Config {
+    pub async fn run<Si: Sink>(self, sink: Si) -> anyhow::Result<()> {
+        let pool = self.storage.postgres.create_pool()?;
+        let storage = postgres::Storage::from_config(&self.storage)?;
+
+        let mut config: rdkafka::ClientConfig = KafkaProperties(self.properties).into();
+
+        config.set("group.id", &self.group_id);
+        config.set("enable.partition.eof", "false");
+
+        // configure for QoS 1
+
+        config.set("enable.auto.commit", "true");
+        config.set("auto.commit.interval.ms", "5000");
+        config.set("enable.auto.offset.store", "false");
+
+        log::info!("Reference sync - source: {config:?}, allow: {}", self.allow);
+
+        let consumer = StreamConsumer::from_config(&config)?;
+        consumer.subscribe(&[&self.topic])?;
+
+        let syncer = Syncer {
+            pool,
+            storage,
+            applications: self.storage.applications,
+            policy: self.allow,
+            sink,
+        };
+
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::warn!("Failed to receive from Kafka: {err}");
+                    break;
+                }
+            };
+
+            match msg.payload().map(notifier::decode) {
+                Some(Ok(thing)) => {
+                    if let Err(err) = syncer.sync(&thing).await {
+                        log::warn!("Failed to sync references: {err}");
+                        break;
+                    }
+                }
+                Some(Err(err)) => {
+                    log::info!("Unable to parse change event, skipping! Reason: {err}");
+                }
+                None => {}
+            }
+
+            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                log::warn!("Failed to store offset: {err}");
+                break;
+            }
+        }
+
+        log::warn!("Exiting reference sync loop");
+
+        Ok(())
+    }
+}
+
+struct Syncer<Si: Sink> {
+    pool: deadpool_postgres::Pool,
+    storage: postgres::Storage,
+    applications: Applications,
+    policy: Policy,
+    sink: Si,
+}
+
+impl<Si: Sink> Syncer<Si> {
+    #[instrument(skip_all, fields(
+        application = thing.metadata.application,
+        thing = thing.metadata.name,
+    ), err)]
+    async fn sync(&self, thing: &Thing) -> anyhow::Result<()> {
+        self.sync_referencing(thing).await?;
+        self.sync_own(thing).await?;
+        Ok(())
+    }
+
+    /// Update all things, referencing the changed thing.
+    async fn sync_referencing(&self, thing: &Thing) -> anyhow::Result<()> {
+        let referencing = self
+            .find_referencing(&thing.metadata.application, &thing.metadata.name)
+            .await?;
+
+        for referencing in referencing {
+            if !self
+                .policy
+                .is_allowed(&referencing.application, &thing.metadata.application)
+            {
+                log::debug!(
+                    "Denied reference: {}/{} -> {}/{}",
+                    referencing.application,
+                    referencing.thing,
+                    thing.metadata.application,
+                    thing.metadata.name
+                );
+                REFERENCE_DENIED.inc();
+                continue;
+            }
+
+            let values = referencing
+                .features
+                .into_iter()
+                .filter_map(|(name, (feature, current))| {
+                    let value = resolve(thing, &feature);
+                    // nothing changed, which also breaks cycles of references
+                    (value != current).then_some((name, value))
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            self.publish(referencing.application, referencing.thing, values)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the references of the changed thing itself, e.g. when the thing got created.
+    async fn sync_own(&self, thing: &Thing) -> anyhow::Result<()> {
+        if thing.metadata.deletion_timestamp.is_some() {
+            return Ok(());
+        }
+
+        let mut values = BTreeMap::new();
+
+        for (name, feature) in &thing.synthetic_state {
+            let reference = match &feature.r#type {
+                SyntheticType::Reference(reference) => reference,
+                _ => continue,
+            };
+
+            let application = reference
+                .application
+                .as_deref()
+                .unwrap_or(&thing.metadata.application);
+
+            if !self
+                .policy
+                .is_allowed(&thing.metadata.application, application)
+            {
+                REFERENCE_DENIED.inc();
+                continue;
+            }
+
+            let value = match self.storage.get(application, &reference.thing).await {
+                Ok(Some(referenced)) => resolve(&referenced, &reference.feature),
+                Ok(None) | Err(storage::Error::NotFound) => Value::Null,
+                Err(err) => return Err(err.into()),
+            };
+
+            if value != feature.value {
+                values.insert(name.clone(), value);
+            }
+        }
+
+        self.publish(
+            thing.metadata.application.clone(),
+            thing.metadata.name.clone(),
+            values,
+        )
+        .await
+    }
+
+    async fn publish(
+        &self,
+        application: String,
+        thing: String,
+        values: BTreeMap<String, Value>,
+    ) -> anyhow::Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        log::debug!("Updating references: {application}/{thing} -> {values:?}");
+
+        self.sink
+            .publish(Event::new(
+                application,
+                thing,
+                Message::UpdateReferences { values },
+            ))
+            .await?;
+        REFERENCE_UPDATES.inc();
+
+        Ok(())
+    }
+
+    /// Find all (non-deleted) things, referencing a thing.
+    async fn find_referencing(
+        &self,
+        application: &str,
+        thing: &str,
+    ) -> anyhow::Result<Vec<Referencing>> {
+        let con = self.pool.get().await?;
+
+        let mut types = vec![Type::VARCHAR, Type::VARCHAR];
+        let and_application = match self.applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    self.applications.sql_condition("APPLICATION", 3)
+                )
+            }
+            true => String::new(),
+        };
+
+        let stmt = con
+            .prepare_typed_cached(
+                &format!(
+                    r#"
+SELECT
+    APPLICATION,
+    NAME,
+    DATA -> 'synthetic_state' AS SYNTHETIC
+FROM
+    things
+WHERE
+        EXISTS (
+            SELECT 1 FROM JSONB_EACH(DATA -> 'synthetic_state') AS S
+            WHERE
+                    S.VALUE -> 'reference' ->> 'thing' = $1
+                AND
+                    COALESCE(S.VALUE -> 'reference' ->> 'application', APPLICATION) = $2
+        )
+    AND
+        DELETION_TIMESTAMP IS NULL
+{and_application}
+"#
+                ),
+                &types,
+            )
+            .await?;
+
+        let rows = match self.applications.is_empty() {
+            true => con.query(&stmt, &[&thing, &application]).await?,
+            false => {
+                let (allow, deny) = self.applications.sql_params();
+                con.query(&stmt, &[&thing, &application, &allow, &deny])
+                    .await?
+            }
+        };
+
+        rows.into_iter()
+            .map(|row| {
+                let referencing: String = row.try_get("APPLICATION")?;
+
+                let features = row
+                    .try_get::<_, Option<Json<BTreeMap<String, Value>>>>("SYNTHETIC")?
+                    .map(|synthetic| synthetic.0)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|(name, feature)| {
+                        let value = feature.get("value").cloned().unwrap_or_default();
+                        let reference: Reference =
+                            serde_json::from_value(feature.get("reference")?.clone()).ok()?;
+                        (reference.thing == thing
+                            && reference.application.as_deref().unwrap_or(&referencing)
+                                == application)
+                            .then_some((name, (reference.feature, value)))
+                    })
+                    .collect();
+
+                Ok(Referencing {
+                    application: referencing,
+                    thing: row.try_get("NAME")?,
+                    features,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The value of a referenced feature.
+///
+/// Synthetic values take precedence over reported values, the same as when evaluating the state.
+/// Missing features, and deleted things, resolve to `null`.
+pub fn resolve<I: InternalState>(thing: &Thing<I>, feature: &str) -> Value {
+    if thing.metadata.deletion_timestamp.is_some() {
+        return Value::Null;
+    }
+
+    thing
+        .synthetic_state
+        .get(feature)
+        .map(|feature| feature.value.clone())
+        .or_else(|| {
+            thing
+                .reported_state
+                .get(feature)
+                .map(|feature| feature.value.clone())
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{ReportedFeature, SyntheticFeature};
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn test_policy() {
+        let policy: Policy = " site-a=weather, site-b=weather,*=shared ".parse().unwrap();
+
+        assert!(policy.is_allowed("site-a", "site-a"));
+        assert!(policy.is_allowed("site-a", "weather"));
+        assert!(policy.is_allowed("site-b", "weather"));
+        assert!(policy.is_allowed("site-c", "shared"));
+        assert!(!policy.is_allowed("site-c", "weather"));
+        assert!(!policy.is_allowed("weather", "site-a"));
+
+        assert_eq!(policy.to_string(), "*=shared,site-a=weather,site-b=weather");
+
+        assert_eq!(Policy::from_str(""), Ok(Policy::default()));
+        assert!(!Policy::default().is_allowed("site-a", "weather"));
+
+        assert_eq!(
+            Policy::from_str("site-a"),
+            Err(PolicyError("site-a".to_string()))
+        );
+        assert_eq!(
+            Policy::from_str("site-a=weather,=weather"),
+            Err(PolicyError("=weather".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut thing = Thing::new("weather", "site-1");
+        thing
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(21.5)));
+        thing
+            .reported_state
+            .insert("humidity".into(), ReportedFeature::now(json!(40)));
+        thing.synthetic_state.insert(
+            "humidity".into(),
+            SyntheticFeature {
+                r#type: SyntheticType::Alias("humidity".into()),
+                last_update: Utc::now(),
+                value: json!(0.4),
+            },
+        );
+
+        assert_eq!(resolve(&thing, "temperature"), json!(21.5));
+        assert_eq!(resolve(&thing, "humidity"), json!(0.4));
+        assert_eq!(resolve(&thing, "pressure"), Value::Null);
+
+        thing.metadata.deletion_timestamp = Some(Utc::now());
+        assert_eq!(resolve(&thing, "temperature"), Value::Null);
+    }
+}
@@ -0,0 +1,317 @@
+//! Synchronize things with the devices of the Drogue Cloud device registry.
+
+use crate::{
+    model::{Internal, Thing},
+    service::{AnnotationsUpdater, Id, LabelsUpdater, Service, UpdateOptions, UpdaterExt},
+    storage::ListOptions,
+};
+use anyhow::bail;
+use drogue_bazaar::client::ClientConfig;
+use drogue_client::registry;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+use tokio::time::MissedTickBehavior;
+use tracing::instrument;
+
+/// Annotation marking a thing as managed by the registry sync. The value is the name of the
+/// Drogue Cloud application the device belongs to.
+pub const ANNOTATION_REGISTRY: &str = "drogue.io/registry";
+
+lazy_static! {
+    static ref SYNC: IntCounterVec = register_int_counter_vec!(
+        "registry_sync",
+        "Number of things synchronized with the device registry",
+        &["action"]
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub disabled: bool,
+
+    /// The Drogue Cloud application to read the devices from.
+    pub application: String,
+
+    /// The application to create the things in, defaults to the name of the Drogue Cloud
+    /// application.
+    #[serde(default)]
+    pub target_application: Option<String>,
+
+    /// The client for accessing the registry API.
+    pub client: ClientConfig,
+
+    #[serde(with = "humantime_serde", default = "default::period")]
+    pub period: Duration,
+
+    /// Delete things of devices which got removed from the registry.
+    #[serde(default = "default::delete")]
+    pub delete: bool,
+}
+
+mod default {
+    use super::*;
+
+    pub const fn period() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    pub const fn delete() -> bool {
+        true
+    }
+}
+
+const OPTS: UpdateOptions = UpdateOptions {
+    ignore_unclean_inbox: true,
+    resource_version: None,
+    unmodified_since: None,
+};
+
+/// The page size when looking for things of removed devices.
+const PAGE_SIZE: u32 = 100;
+
+impl Config {
+    pub async fn run<Svc>(self, service: Svc) -> anyhow::Result<()>
+    where
+        Svc: Service + Sync + Send,
+        Svc::Error: Sync + Send + 'static,
+    {
+        let client = self
+            .client
+            .clone()
+            .into_client::<registry::v1::Client>()
+            .await?;
+
+        let target_application = self
+            .target_application
+            .clone()
+            .unwrap_or_else(|| self.application.clone());
+        let synchronizer = Synchronizer::new(&self.application, target_application, service)
+            .with_delete(self.delete);
+
+        let mut interval = tokio::time::interval(self.period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let devices = match client.list_devices(&self.application, None).await {
+                Ok(devices) => devices,
+                Err(err) => {
+                    log::warn!("Failed to list devices of the registry: {err}");
+                    continue;
+                }
+            };
+
+            if let Err(err) = synchronizer
+                .sync(devices.map(|devices| devices.into_iter().map(Device::from).collect()))
+                .await
+            {
+                log::warn!("Failed to sync with device registry: {err}");
+            }
+        }
+    }
+}
+
+/// A device of the registry, as far as it is relevant for the sync.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl From<registry::v1::Device> for Device {
+    fn from(device: registry::v1::Device) -> Self {
+        Self {
+            name: device.metadata.name,
+            labels: device.metadata.labels.into_iter().collect(),
+        }
+    }
+}
+
+/// The outcome of synchronizing a single device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Outcome {
+    Created,
+    Updated,
+    Unchanged,
+    /// A thing with the name of the device exists, but isn't managed by the sync.
+    Conflict,
+}
+
+/// Synchronizes the things of an application with the devices of the registry.
+pub struct Synchronizer<Svc: Service> {
+    application: String,
+    target_application: String,
+    delete: bool,
+    service: Svc,
+}
+
+impl<Svc> Synchronizer<Svc>
+where
+    Svc: Service + Sync + Send,
+    Svc::Error: Sync + Send + 'static,
+{
+    /// Create a new synchronizer, for the devices of the Drogue Cloud application.
+    pub fn new(
+        application: impl Into<String>,
+        target_application: impl Into<String>,
+        service: Svc,
+    ) -> Self {
+        Self {
+            application: application.into(),
+            target_application: target_application.into(),
+            delete: default::delete(),
+            service,
+        }
+    }
+
+    /// Delete things of devices which got removed from the registry.
+    pub fn with_delete(mut self, delete: bool) -> Self {
+        self.delete = delete;
+        self
+    }
+
+    pub fn service(&self) -> &Svc {
+        &self.service
+    }
+
+    /// Synchronize the things with the devices of the registry.
+    ///
+    /// The devices are `None` if the registry didn't return the application. This is treated as
+    /// an error, instead of deleting all things managed by the sync.
+    #[instrument(skip_all, fields(application=self.application), err)]
+    pub async fn sync(&self, devices: Option<Vec<Device>>) -> anyhow::Result<()> {
+        let devices = match devices {
+            Some(devices) => devices,
+            None => {
+                SYNC.with_label_values(&["failed"]).inc();
+                bail!(
+                    "Application '{}' not found in the registry, skipping sync",
+                    self.application
+                );
+            }
+        };
+
+        let mut current = BTreeSet::new();
+
+        for Device { name, labels } in devices {
+            match self.sync_device(&name, labels).await {
+                Ok(Outcome::Created) => SYNC.with_label_values(&["created"]).inc(),
+                Ok(Outcome::Updated) => SYNC.with_label_values(&["updated"]).inc(),
+                Ok(Outcome::Unchanged) => {}
+                Ok(Outcome::Conflict) => {
+                    log::warn!(
+                        "Thing '{name}' exists, but is not managed by the registry sync, skipping"
+                    );
+                    SYNC.with_label_values(&["conflict"]).inc();
+                }
+                Err(err) => {
+                    // don't let a single device block the others
+                    log::warn!("Failed to sync device '{name}': {err}");
+                    SYNC.with_label_values(&["failed"]).inc();
+                }
+            }
+            current.insert(name);
+        }
+
+        if self.delete {
+            self.delete_removed(&current).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check if the thing is managed by the sync.
+    fn is_managed(&self, thing: &Thing<Internal>) -> bool {
+        thing.metadata.annotations.get(ANNOTATION_REGISTRY) == Some(&self.application)
+    }
+
+    /// Delete the things managed by the sync, which don't have a device anymore.
+    ///
+    /// The things are looked up in the storage, using the registry annotation, so that devices
+    /// removed while the sync wasn't running get cleaned up too.
+    async fn delete_removed(&self, current: &BTreeSet<String>) -> anyhow::Result<()> {
+        let mut after = None;
+
+        loop {
+            let opts = ListOptions {
+                after: after.take(),
+                limit: Some(PAGE_SIZE),
+                ..Default::default()
+            };
+            let page = self
+                .service
+                .list_with(&self.target_application, &opts)
+                .await?;
+
+            for thing in &page {
+                if !self.is_managed(thing) || current.contains(&thing.metadata.name) {
+                    continue;
+                }
+
+                log::info!(
+                    "Device removed from registry, deleting thing: {}",
+                    thing.metadata.name
+                );
+                let id = Id::new(&self.target_application, &thing.metadata.name);
+                match self.service.delete(&id, None).await {
+                    Ok(_) => SYNC.with_label_values(&["deleted"]).inc(),
+                    Err(err) => {
+                        log::warn!("Failed to delete thing '{id}': {err}");
+                        SYNC.with_label_values(&["failed"]).inc();
+                    }
+                }
+            }
+
+            if page.len() < PAGE_SIZE as usize {
+                return Ok(());
+            }
+            after = page.last().map(|thing| thing.metadata.name.clone());
+        }
+    }
+
+    async fn sync_device(
+        &self,
+        name: &str,
+        labels: BTreeMap<String, String>,
+    ) -> anyhow::Result<Outcome> {
+        let id = Id::new(&self.target_application, name);
+
+        Ok(match self.service.get(&id).await? {
+            None => {
+                log::debug!("Creating thing for device: {id}");
+                let mut thing = Thing::new(&id.application, &id.thing);
+                thing.metadata.labels = labels;
+                thing
+                    .metadata
+                    .annotations
+                    .insert(ANNOTATION_REGISTRY.into(), self.application.clone());
+                self.service.create(thing).await?;
+                Outcome::Created
+            }
+            // don't take over things created by someone else
+            Some(thing) if !self.is_managed(&thing) => Outcome::Conflict,
+            Some(thing) if thing.metadata.labels != labels => {
+                log::debug!("Updating labels of thing: {id}");
+                self.service
+                    .update(
+                        &id,
+                        &LabelsUpdater(labels).and_then(AnnotationsUpdater::new(
+                            ANNOTATION_REGISTRY,
+                            &self.application,
+                        )),
+                        &OPTS,
+                    )
+                    .await?;
+                Outcome::Updated
+            }
+            Some(_) => Outcome::Unchanged,
+        })
+    }
+}
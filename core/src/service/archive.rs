@@ -0,0 +1,92 @@
+//! Archiving the last state of deleted things.
+//!
+//! Archived things are stored as JSON objects in an object store, using the same stores as the
+//! cold storage, see [`crate::storage::postgres::cold`]. Each deletion creates a new object, so
+//! that re-creating and deleting a thing with the same name keeps all previous objects.
+
+use crate::{
+    model::{Internal, Thing},
+    storage::postgres::cold::{self, ColdStore},
+};
+
+pub use cold::{Config, Error};
+
+/// An archive for deleted things.
+#[derive(Clone, Debug)]
+pub struct Archive {
+    store: ColdStore,
+}
+
+/// The key of the object archiving a thing.
+///
+/// The key is based on the UID and generation of the thing, which makes it unique for each
+/// deletion. Application and name are encoded the same way as for the cold storage.
+pub fn key(thing: &Thing<Internal>) -> String {
+    let encode = |value: &str| -> String {
+        url::form_urlencoded::byte_serialize(value.as_bytes())
+            .collect::<String>()
+            .replace('.', "%2E")
+    };
+
+    let metadata = &thing.metadata;
+    format!(
+        "{}/{}/{}-{}.json",
+        encode(&metadata.application),
+        encode(&metadata.name),
+        encode(metadata.uid.as_deref().unwrap_or_default()),
+        metadata.generation.unwrap_or_default()
+    )
+}
+
+impl Archive {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            store: ColdStore::from_config(config)?,
+        })
+    }
+
+    /// Archive the last state of a thing.
+    pub async fn archive(&self, thing: &Thing<Internal>) -> Result<(), Error> {
+        self.store.put(&key(thing), thing).await
+    }
+
+    /// Get an archived thing, `None` if it wasn't archived.
+    pub async fn get(&self, key: &str) -> Result<Option<Thing<Internal>>, Error> {
+        self.store.get(key).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_key() {
+        let mut thing = Thing::new("default", "device/channel");
+        thing.metadata.uid = Some("6ea0f0c1-3fa6-4e1b-8c6f-f2b2f2a5a4a1".into());
+        thing.metadata.generation = Some(3);
+        assert_eq!(
+            key(&thing),
+            "default/device%2Fchannel/6ea0f0c1-3fa6-4e1b-8c6f-f2b2f2a5a4a1-3.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let archive = Archive::from_config(&Config::Filesystem(cold::FilesystemConfig {
+            path: path.clone(),
+        }))
+        .unwrap();
+
+        let mut thing = Thing::new("default", "thing1");
+        thing.metadata.uid = Some(Uuid::new_v4().to_string());
+        thing.metadata.generation = Some(1);
+
+        archive.archive(&thing).await.unwrap();
+        assert_eq!(archive.get(&key(&thing)).await.unwrap(), Some(thing));
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
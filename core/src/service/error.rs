@@ -3,6 +3,7 @@ use crate::{
     error::ErrorInformation,
     machine, notifier,
     notifier::Notifier,
+    service::archive,
     storage::{self, Storage},
 };
 use actix_web::{body::BoxBody, HttpResponse, ResponseError};
@@ -18,6 +19,8 @@ pub enum Error<S: Storage, N: Notifier, Cmd: CommandSink> {
     Machine(#[from] machine::Error),
     #[error("Command sink: {0}")]
     Command(#[source] Cmd::Error),
+    #[error("Archive: {0}")]
+    Archive(#[source] archive::Error),
     #[error("Unclean Outbox")]
     UncleanOutbox,
 }
@@ -29,6 +32,7 @@ impl<S: Storage, N: Notifier, Cmd: CommandSink> Debug for Error<S, N, Cmd> {
             Self::Notifier(err) => f.debug_tuple("Notifier").field(err).finish(),
             Self::Machine(err) => f.debug_tuple("Machine").field(err).finish(),
             Self::Command(err) => f.debug_tuple("Command").field(err).finish(),
+            Self::Archive(err) => f.debug_tuple("Archive").field(err).finish(),
             Self::UncleanOutbox => f.debug_tuple("UncleanOutbox").finish(),
         }
     }
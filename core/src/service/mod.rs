@@ -1,5 +1,7 @@
+pub mod archive;
 mod error;
 mod id;
+pub mod notifications;
 mod updater;
 
 use async_trait::async_trait;
@@ -8,24 +10,42 @@ pub use id::Id;
 pub use updater::*;
 
 use crate::{
-    command::CommandSink,
-    machine::{DeletionOutcome, Machine, OutboxMessage, Outcome},
-    model::{Internal, InternalThingExt, Thing, WakerExt, WakerReason},
+    clock,
+    command::{Command, CommandSink},
+    config::check::{Check, Checker},
+    correlation,
+    machine::{
+        self,
+        controller::{self, Controller},
+        Alerts, DeletionOutcome, Limits, Machine, OutboxMessage, Outcome,
+    },
+    model::{Delivery, DeliveryExt, Internal, InternalThingExt, Thing, WakerExt, WakerReason},
     notifier::Notifier,
     processor::{sink::Sink, Event},
-    storage::{self, Storage},
+    storage::{self, query::Query, ListOptions, Stats, Storage},
     Preconditions,
 };
-use chrono::{Duration, Utc};
+use archive::Archive;
+use chrono::{DateTime, Duration, Utc};
 use drogue_bazaar::app::Startup;
+use futures::{stream, Stream, TryStreamExt};
 use lazy_static::lazy_static;
+use notifications::Notifications;
 use prometheus::{register_int_counter, IntCounter};
+use std::sync::Arc;
 use tracing::instrument;
 use uuid::Uuid;
 
 lazy_static! {
     static ref OUTBOX_EVENTS: IntCounter =
         register_int_counter!("outbox", "Number of generated outbox events").unwrap();
+    static ref OUTBOX_SENT: IntCounter =
+        register_int_counter!("outbox_sent", "Number of sent outbox events").unwrap();
+    static ref OUTBOX_FAILED: IntCounter = register_int_counter!(
+        "outbox_failed",
+        "Number of failed attempts to send outbox events"
+    )
+    .unwrap();
     static ref COMMANDS: IntCounter =
         register_int_counter!("commands", "Number of generated commands").unwrap();
     static ref NOT_CHANGED: IntCounter =
@@ -39,11 +59,82 @@ pub struct Config<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> {
     pub notifier: No::Config,
     pub sink: Si::Config,
     pub command_sink: Cmd::Config,
+    /// Limits of a single reconciliation run
+    #[serde(default)]
+    pub limits: Limits,
+    /// Thresholds of delays, raising conditions
+    #[serde(default)]
+    pub alerts: Alerts,
+    /// Calling external controllers
+    #[serde(default)]
+    pub controller: controller::Config,
+    /// Coerce reported values to the types declared by the schema
+    #[serde(default)]
+    pub coerce: bool,
+    /// Archive the last state of deleted things, see [`archive`]
+    #[serde(default)]
+    pub archive: Option<archive::Config>,
+    /// Retrying failed change notifications, see [`notifications`]
+    #[serde(default)]
+    pub notifications: notifications::Config,
+}
+
+impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Check for Config<St, No, Si, Cmd>
+where
+    St::Config: Check,
+    No::Config: Check,
+    Si::Config: Check,
+    Cmd::Config: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("storage", &self.storage)
+            .field("notifier", &self.notifier)
+            .field("sink", &self.sink)
+            .field("command_sink", &self.command_sink)
+            .field("archive", &self.archive);
+    }
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct UpdateOptions {
     pub ignore_unclean_inbox: bool,
+    /// Only update the thing if it has this resource version.
+    pub resource_version: Option<String>,
+    /// Only update the thing if it wasn't modified since.
+    pub unmodified_since: Option<DateTime<Utc>>,
+}
+
+impl UpdateOptions {
+    /// The preconditions the current thing must match.
+    pub fn preconditions(&self) -> Preconditions<'_> {
+        Preconditions {
+            resource_version: self.resource_version.as_deref(),
+            uid: None,
+            unmodified_since: self.unmodified_since,
+        }
+    }
+}
+
+/// An operation of a batch, see [`Service::apply_batch`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOperation {
+    /// Create a new thing.
+    Create(Thing<Internal>),
+    /// Replace an existing thing, the same way [`Service::update`] does, using the thing as
+    /// updater.
+    Update(Thing<Internal>),
+}
+
+/// An update of a batch, ready to be stored.
+enum PreparedUpdate {
+    /// Nothing left to store.
+    Done(Thing<Internal>),
+    /// Store the thing, and send the commands afterwards.
+    Store {
+        new_thing: Thing<Internal>,
+        commands: Vec<Command>,
+    },
 }
 
 impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Clone for Config<St, No, Si, Cmd> {
@@ -53,18 +144,54 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Clone for Config<St,
             notifier: self.notifier.clone(),
             sink: self.sink.clone(),
             command_sink: self.command_sink.clone(),
+            limits: self.limits.clone(),
+            alerts: self.alerts.clone(),
+            controller: self.controller.clone(),
+            coerce: self.coerce,
+            archive: self.archive.clone(),
+            notifications: self.notifications.clone(),
         }
     }
 }
 
 pub const POSTPONE_DURATION: std::time::Duration = std::time::Duration::from_secs(5);
 
+/// The number of things fetched at once, when exporting things.
+const EXPORT_PAGE_SIZE: u32 = 100;
+
 #[async_trait]
 pub trait Service {
     type Error: std::error::Error;
 
     async fn create(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Self::Error>;
     async fn get(&self, id: &Id) -> Result<Option<Thing<Internal>>, Self::Error>;
+    /// Get a specific generation of a thing, if it is still known.
+    async fn get_revision(
+        &self,
+        id: &Id,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>, Self::Error>;
+    async fn list(&self, application: &str) -> Result<Vec<Thing<Internal>>, Self::Error>;
+    /// List things of an application, ordered by name, see [`ListOptions`].
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Self::Error>;
+    /// Query things of an application by their state, ordered by name, see [`Query`].
+    async fn query(
+        &self,
+        application: &str,
+        query: &Query,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Self::Error>;
+    /// Aggregate the statistics of the things of an application.
+    async fn stats(&self, application: &str) -> Result<Stats, Self::Error>;
+    /// Get the delivery state of the recent outbox events of a thing, or `None` if the thing
+    /// doesn't exist.
+    ///
+    /// This includes the events still waiting in an outbox managed by the storage, as pending.
+    async fn deliveries(&self, id: &Id) -> Result<Option<Vec<Delivery>>, Self::Error>;
     async fn delete(&self, id: &Id, opts: Option<&Preconditions<'_>>) -> Result<bool, Self::Error>;
     async fn update<U>(
         &self,
@@ -74,6 +201,25 @@ pub trait Service {
     ) -> Result<Thing<Internal>, Self::Error>
     where
         U: Updater + Sync;
+    /// Apply a batch of operations, returning the outcome for each operation, in the same order.
+    ///
+    /// The outer result fails, if the batch could not be processed at all.
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        opts: &UpdateOptions,
+    ) -> Result<Vec<Result<Thing<Internal>, Self::Error>>, Self::Error>;
+    /// Import a thing, e.g. when restoring a backup.
+    ///
+    /// The thing is stored as it is, preserving its metadata as far as possible, without running
+    /// the reconciliation.
+    async fn import(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Self::Error>;
+    /// Run the machine for a thing, without storing the result, or sending any events or
+    /// commands.
+    ///
+    /// If the thing exists, the run replaces the current thing, like an update. Otherwise, the
+    /// run creates the thing.
+    async fn dry_run(&self, thing: Thing<Internal>) -> Result<Outcome, Self::Error>;
 }
 
 pub struct DefaultService<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> {
@@ -82,6 +228,9 @@ pub struct DefaultService<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink>
     sink: Si,
     command_sink: Cmd,
     postpone: Duration,
+    options: machine::Options,
+    archive: Option<Archive>,
+    notifications: Notifications,
 }
 
 #[derive(Debug)]
@@ -104,12 +253,26 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
             notifier,
             sink,
             command_sink,
+            limits,
+            alerts,
+            controller,
+            coerce,
+            archive,
+            notifications,
         } = config;
         let storage = St::from_config(&storage)?;
         let notifier = No::from_config(&notifier)?;
         let sink = Si::from_config(sink)?;
         let command_sink = Cmd::from_config(startup, command_sink)?;
-        Ok(Self::new(storage, notifier, sink, command_sink))
+        let controller = Controller::from_config(controller)?;
+        let archive = archive.as_ref().map(Archive::from_config).transpose()?;
+        Ok(Self::new(storage, notifier, sink, command_sink)
+            .with_limits(limits)
+            .with_alerts(alerts)
+            .with_controller(controller)
+            .with_coercion(coerce)
+            .with_archive(archive)
+            .with_notifications(notifications))
     }
 
     pub fn new(storage: St, notifier: No, sink: Si, command_sink: Cmd) -> Self {
@@ -119,13 +282,106 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
             sink,
             command_sink,
             postpone: Duration::seconds(POSTPONE_DURATION.as_secs() as i64),
+            options: Default::default(),
+            archive: None,
+            notifications: Default::default(),
         }
     }
 
+    /// Set the limits of a single reconciliation run.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Set the thresholds of delays, which raise conditions.
+    pub fn with_alerts(mut self, alerts: Alerts) -> Self {
+        self.options.alerts = alerts;
+        self
+    }
+
+    /// Set the client for calling external controllers.
+    pub fn with_controller(mut self, controller: Option<Controller>) -> Self {
+        self.options.controller = controller;
+        self
+    }
+
+    /// Set if reported values should be coerced to the types declared by the schema.
+    pub fn with_coercion(mut self, coerce: bool) -> Self {
+        self.options.coerce = coerce;
+        self
+    }
+
+    /// Set the archive for the last state of deleted things.
+    pub fn with_archive(mut self, archive: Option<Archive>) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    /// Set how failed change notifications are retried.
+    pub fn with_notifications(mut self, config: notifications::Config) -> Self {
+        self.notifications = Notifications::new(config);
+        self
+    }
+
     pub fn sink(&self) -> &Si {
         &self.sink
     }
 
+    /// Stream all things of an application, ordered by name.
+    ///
+    /// The things are fetched page by page, so this doesn't require holding all things in memory.
+    pub fn export(
+        self: Arc<Self>,
+        application: String,
+    ) -> impl Stream<Item = Result<Thing<Internal>, Error<St, No, Cmd>>> {
+        // the state is the name of the last exported thing, or `None` when done
+        stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+            let service = self.clone();
+            let application = application.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok(None);
+                };
+
+                let opts = ListOptions {
+                    after,
+                    limit: Some(EXPORT_PAGE_SIZE),
+                    ..Default::default()
+                };
+                let page = service.list_with(&application, &opts).await?;
+
+                let next = match page.len() < EXPORT_PAGE_SIZE as usize {
+                    true => None,
+                    false => page.last().map(|thing| Some(thing.metadata.name.clone())),
+                };
+
+                Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Check if the thing changed, recording the time of the modification if it did.
+    ///
+    /// The modification time is managed by the service, so any value of the new thing is
+    /// replaced.
+    fn modified(current_thing: &Thing<Internal>, new_thing: &mut Thing<Internal>) -> bool {
+        new_thing.set_modified(
+            current_thing
+                .internal
+                .as_ref()
+                .and_then(|internal| internal.modified),
+        );
+
+        if current_thing == new_thing {
+            return false;
+        }
+
+        new_thing.set_modified(Some(clock::now()));
+        true
+    }
+
     /// Add new, scheduled, messages to the outbox, and return the entries to send out.
     fn add_outbox(thing: &mut Thing<Internal>, outbox: Vec<OutboxMessage>) {
         // get internal section
@@ -155,15 +411,18 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
             .into_iter()
             .map(|message| Event {
                 id: Uuid::new_v4().to_string(),
-                timestamp: Utc::now(),
+                timestamp: clock::now(),
                 application: thing.metadata.application.clone(),
                 thing: message.thing,
                 message: message.message,
+                correlation_id: correlation::current(),
+                provenance: None,
             })
             .collect();
 
-        // append events to the stored outbox
+        // append events to the stored outbox, and track their delivery
 
+        internal.deliveries.extend(add.iter().map(Delivery::new));
         internal.outbox.extend(add);
     }
 
@@ -183,7 +442,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
             return Ok(new_thing);
         };
 
-        log::debug!("New outbox: {outbox:?}");
+        tracing::debug!(?outbox, "New outbox");
 
         if outbox.is_empty() {
             // early return
@@ -194,10 +453,12 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
 
         match self.sink.publish_iter(outbox).await {
             Ok(()) => {
-                log::debug!("All outbox events sent");
+                tracing::debug!("All outbox events sent");
 
                 // ack outbox events
                 if let Some(internal) = &mut new_thing.internal {
+                    OUTBOX_SENT.inc_by(internal.outbox.len() as u64);
+                    internal.deliveries.delivered(&internal.outbox);
                     internal.outbox.clear();
 
                     // we can clear the waker, as we are sure that the outbox was clear initially
@@ -211,17 +472,32 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
                 }
             }
             Err((0, err)) => {
-                log::info!("Failed to send any outbox event: {err:?}");
+                tracing::info!(?err, "Failed to send any outbox event");
+                OUTBOX_FAILED.inc();
                 // Special case, none had been successful. Might actually be to most common case.
-                // And we don't need to do anything.
+                // We only need to record the failure. The waker is already set, and the outbox
+                // unchanged, so we don't store the thing just for that. The failure gets
+                // persisted with the next write of the thing.
+                if let Some(internal) = &mut new_thing.internal {
+                    if let Some(event) = internal.outbox.first() {
+                        internal.deliveries.failed(event, err.to_string());
+                    }
+                }
             }
             Err((done, err)) => {
-                log::info!("Failed to send some outbox events: {err:?}, done: {done}");
+                tracing::info!(?err, done, "Failed to send some outbox events");
+                OUTBOX_SENT.inc_by(done as u64);
+                OUTBOX_FAILED.inc();
 
                 // ack done events
                 if let Some(internal) = &mut new_thing.internal {
                     // remove the first, done elements
-                    internal.outbox = internal.outbox.split_off(done);
+                    let outbox = internal.outbox.split_off(done);
+                    internal.deliveries.delivered(&internal.outbox);
+                    if let Some(event) = outbox.first() {
+                        internal.deliveries.failed(event, err.to_string());
+                    }
+                    internal.outbox = outbox;
 
                     // waker is already set, so just store
                     new_thing = self
@@ -238,6 +514,79 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
         Ok(new_thing)
     }
 
+    /// Send the outbox events, the commands, and the notification of a stored thing.
+    ///
+    /// The thing must not have had any pending outbox events before storing it.
+    async fn complete(
+        &self,
+        new_thing: Thing<Internal>,
+        commands: Vec<Command>,
+    ) -> Result<Thing<Internal>, Error<St, No, Cmd>> {
+        let new_thing = self.send_and_ack(new_thing).await?;
+
+        self.command_sink
+            .send_commands(commands)
+            .await
+            .map_err(Error::Command)?;
+
+        self.notifications
+            .notify(|| self.notifier.notify(&new_thing))
+            .await;
+
+        Ok(new_thing)
+    }
+
+    /// Run the update of a batch, without storing the result.
+    ///
+    /// Things which are deleted, or have pending outbox events, are updated right away, the same
+    /// way [`Service::update`] does.
+    async fn prepare_update(
+        &self,
+        thing: Thing<Internal>,
+        opts: &UpdateOptions,
+    ) -> Result<PreparedUpdate, Error<St, No, Cmd>> {
+        let id = Id::new(&thing.metadata.application, &thing.metadata.name);
+
+        let current_thing = self
+            .storage
+            .get(&id.application, &id.thing)
+            .await
+            .and_then(|r| r.ok_or(storage::Error::NotFound))
+            .map_err(Error::Storage)?;
+
+        if !opts.preconditions().matches(&current_thing) {
+            return Err(Error::Storage(storage::Error::PreconditionFailed));
+        }
+
+        if current_thing.metadata.deletion_timestamp.is_some() || !current_thing.outbox().is_empty()
+        {
+            return Ok(PreparedUpdate::Done(self.update(&id, &thing, opts).await?));
+        }
+
+        let Outcome {
+            mut new_thing,
+            outbox,
+            commands,
+        } = Machine::new(current_thing.clone())
+            .with_options(self.options.clone())
+            .update(|current| async { thing.update(current) })
+            .await?;
+
+        OUTBOX_EVENTS.inc_by(outbox.len() as u64);
+        COMMANDS.inc_by(commands.len() as u64);
+        Self::add_outbox(&mut new_thing, outbox);
+
+        if !Self::modified(&current_thing, &mut new_thing) {
+            NOT_CHANGED.inc();
+            return Ok(PreparedUpdate::Done(current_thing));
+        }
+
+        Ok(PreparedUpdate::Store {
+            new_thing,
+            commands,
+        })
+    }
+
     /// If there are unprocessed events, process them now.
     ///
     /// Return a new "current thing" refreshed from the storage.
@@ -257,7 +606,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
         loop {
             let (thing, state) = self.prepare_outbox(current_thing).await?;
 
-            log::debug!("Outbox state: {state:?}");
+            tracing::debug!(?state, "Outbox state");
 
             match state {
                 OutboxState::Clean => break Ok(thing),
@@ -265,7 +614,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
                 OutboxState::Unclean => break Err(Error::UncleanOutbox),
                 OutboxState::Retry => {
                     current_thing = self.send_and_ack(thing).await?;
-                    log::debug!("Thing after trying: {current_thing:?}");
+                    tracing::debug!(?current_thing, "Thing after trying");
                 }
             }
         }
@@ -290,7 +639,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> DefaultService<St, N
         };
 
         // same cutoff timestamp for all
-        let now = Utc::now();
+        let now = clock::now();
 
         // check if we can retry the outbox
         for event in &mut internal.outbox {
@@ -323,16 +672,20 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
 {
     type Error = Error<St, No, Cmd>;
 
-    #[instrument(skip_all, err)]
+    #[instrument(skip_all, fields(
+        application = %thing.metadata.application,
+        thing = %thing.metadata.name,
+    ), err)]
     async fn create(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Error<St, No, Cmd>> {
         let Outcome {
             mut new_thing,
             outbox,
             commands,
-        } = Machine::create(thing).await?;
+        } = Machine::create_with(thing, self.options.clone()).await?;
 
         OUTBOX_EVENTS.inc_by(outbox.len() as u64);
         Self::add_outbox(&mut new_thing, outbox);
+        new_thing.set_modified(Some(clock::now()));
 
         let new_thing = self
             .storage
@@ -353,21 +706,18 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
 
         // notify
 
-        self.notifier
-            .notify(&new_thing)
-            .await
-            .map_err(Error::Notifier)?;
-
-        // FIXME: handle error
+        self.notifications
+            .notify(|| self.notifier.notify(&new_thing))
+            .await;
 
-        log::debug!("New thing created: {new_thing:?}");
+        tracing::debug!(?new_thing, "New thing created");
 
         // done
 
         Ok(new_thing)
     }
 
-    #[instrument(skip(self), err)]
+    #[instrument(skip_all, fields(application = %id.application, thing = %id.thing), err)]
     async fn get(&self, id: &Id) -> Result<Option<Thing<Internal>>, Error<St, No, Cmd>> {
         self.storage
             .get(&id.application, &id.thing)
@@ -379,7 +729,81 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
             .map_err(Error::Storage)
     }
 
-    #[instrument(skip(self), ret, err)]
+    #[instrument(skip(self), err)]
+    async fn get_revision(
+        &self,
+        id: &Id,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>, Error<St, No, Cmd>> {
+        self.storage
+            .get_revision(&id.application, &id.thing, generation)
+            .await
+            .map_err(Error::Storage)
+    }
+
+    #[instrument(skip(self), err)]
+    async fn list(&self, application: &str) -> Result<Vec<Thing<Internal>>, Error<St, No, Cmd>> {
+        self.storage.list(application).await.map_err(Error::Storage)
+    }
+
+    #[instrument(skip(self), err)]
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Error<St, No, Cmd>> {
+        self.storage
+            .list_with(application, opts)
+            .await
+            .map_err(Error::Storage)
+    }
+
+    #[instrument(skip(self), err)]
+    async fn query(
+        &self,
+        application: &str,
+        query: &Query,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Error<St, No, Cmd>> {
+        self.storage
+            .query(application, query, opts)
+            .await
+            .map_err(Error::Storage)
+    }
+
+    #[instrument(skip(self), err)]
+    async fn stats(&self, application: &str) -> Result<Stats, Error<St, No, Cmd>> {
+        self.storage
+            .stats(application)
+            .await
+            .map_err(Error::Storage)
+    }
+
+    #[instrument(skip_all, fields(application = %id.application, thing = %id.thing), err)]
+    async fn deliveries(&self, id: &Id) -> Result<Option<Vec<Delivery>>, Error<St, No, Cmd>> {
+        let mut deliveries = match self.get(id).await? {
+            Some(thing) => thing
+                .internal
+                .map(|internal| internal.deliveries)
+                .unwrap_or_default(),
+            None => return Ok(None),
+        };
+
+        for event in self
+            .storage
+            .pending_events(&id.application, &id.thing)
+            .await
+            .map_err(Error::Storage)?
+        {
+            if !deliveries.iter().any(|delivery| delivery.id == event.id) {
+                deliveries.push(Delivery::new(&event));
+            }
+        }
+
+        Ok(Some(deliveries))
+    }
+
+    #[instrument(skip(self, id), fields(application = %id.application, thing = %id.thing), ret, err)]
     async fn delete(
         &self,
         id: &Id,
@@ -387,7 +811,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
     ) -> Result<bool, Error<St, No, Cmd>> {
         // get the current thing
 
-        log::debug!("Deleting thing: {id}");
+        tracing::debug!("Deleting thing");
 
         let mut thing = match self.storage.get(&id.application, &id.thing).await {
             Ok(Some(thing)) => thing,
@@ -414,7 +838,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
             .unwrap_or(true);
 
         // mark deleted
-        thing.metadata.deletion_timestamp = Some(Utc::now());
+        thing.metadata.deletion_timestamp = Some(clock::now());
 
         // run machine for deletion
         let DeletionOutcome { mut thing, outbox } = Machine::delete(thing).await?;
@@ -433,14 +857,21 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
         }
 
         if thing.outbox().is_empty() {
+            // archive before deleting, so that a failure doesn't lose the last state
+            if let Some(archive) = &self.archive {
+                archive.archive(&thing).await.map_err(Error::Archive)?;
+            }
+
             // if the outbox is empty, delete
-            self.storage
+            let deleted = self
+                .storage
                 .delete_with(
                     &id.application,
                     &id.thing,
                     Preconditions {
                         resource_version: thing.metadata.resource_version.as_deref(),
                         uid: thing.metadata.uid.as_deref(),
+                        unmodified_since: None,
                     },
                 )
                 .await
@@ -449,19 +880,25 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
                     storage::Error::NotFound => Ok(false),
                     err => Err(Error::Storage(err)),
                 })?;
+
+            if deleted {
+                // the thing is gone already, so a missing tombstone is not a reason to fail
+                if let Err(err) = self.sink.tombstone(&id.application, &id.thing).await {
+                    tracing::warn!(?err, "Failed to publish tombstone");
+                }
+            }
         }
 
         // notify
-        self.notifier
-            .notify(&thing)
-            .await
-            .map_err(Error::Notifier)?;
+        self.notifications
+            .notify(|| self.notifier.notify(&thing))
+            .await;
 
         // done
         Ok(true)
     }
 
-    #[instrument(skip(self, updater), err)]
+    #[instrument(skip(self, id, updater), fields(application = %id.application, thing = %id.thing), err)]
     async fn update<U>(
         &self,
         id: &Id,
@@ -471,7 +908,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
     where
         U: Updater + Sync,
     {
-        log::debug!("Updating thing: {id}");
+        tracing::debug!("Updating thing");
 
         let current_thing = self
             .storage
@@ -498,6 +935,10 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
             return Err(Error::Storage(storage::Error::NotFound));
         }
 
+        if !opts.preconditions().matches(&current_thing) {
+            return Err(Error::Storage(storage::Error::PreconditionFailed));
+        }
+
         // check for unprocessed events
         let current_thing = self
             .check_unprocessed_events(current_thing, opts.ignore_unclean_inbox)
@@ -508,6 +949,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
             outbox,
             commands,
         } = Machine::new(current_thing.clone())
+            .with_options(self.options.clone())
             .update(|thing| async { updater.update(thing) })
             .await?;
 
@@ -517,8 +959,8 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
 
         // check diff after adding outbox events
         // TODO: maybe reconsider? if there is no state change? do we send out events? is an event a state change?
-        if current_thing == new_thing {
-            log::debug!("Thing state not changed. Return early!");
+        if !Self::modified(&current_thing, &mut new_thing) {
+            tracing::debug!("Thing state not changed. Return early!");
             NOT_CHANGED.inc();
             // no change, nothing to do
             return Ok(current_thing);
@@ -528,7 +970,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
 
         let mut new_thing = self
             .storage
-            .update(new_thing)
+            .update_from(&current_thing, new_thing)
             .await
             .map_err(Error::Storage)?;
 
@@ -539,7 +981,7 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
             .map(|i| i.outbox.len())
             .unwrap_or(0);
 
-        log::debug!("Current outbox size: {}", current_outbox);
+        tracing::debug!(current_outbox, "Current outbox size");
 
         if current_outbox == 0 {
             // only send when we had no previous events, otherwise we already queued
@@ -555,15 +997,120 @@ impl<St: Storage, No: Notifier, Si: Sink, Cmd: CommandSink> Service
 
         // notify
 
-        self.notifier
-            .notify(&new_thing)
+        self.notifications
+            .notify(|| self.notifier.notify_change(&current_thing, &new_thing))
+            .await;
+
+        // done
+
+        Ok(new_thing)
+    }
+
+    #[instrument(skip_all, fields(operations = operations.len()), err)]
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        opts: &UpdateOptions,
+    ) -> Result<Vec<Result<Thing<Internal>, Error<St, No, Cmd>>>, Error<St, No, Cmd>> {
+        let mut results: Vec<Option<Result<Thing<Internal>, Error<St, No, Cmd>>>> =
+            operations.iter().map(|_| None).collect();
+
+        // prepare
+
+        let mut creates = (Vec::new(), Vec::new());
+        let mut updates = (Vec::new(), Vec::new());
+
+        for (i, operation) in operations.into_iter().enumerate() {
+            match operation {
+                BatchOperation::Create(thing) => {
+                    match Machine::create_with(thing, self.options.clone()).await {
+                        Ok(Outcome {
+                            mut new_thing,
+                            outbox,
+                            commands,
+                        }) => {
+                            OUTBOX_EVENTS.inc_by(outbox.len() as u64);
+                            Self::add_outbox(&mut new_thing, outbox);
+                            new_thing.set_modified(Some(clock::now()));
+                            creates.0.push((i, commands));
+                            creates.1.push(new_thing);
+                        }
+                        Err(err) => results[i] = Some(Err(err.into())),
+                    }
+                }
+                BatchOperation::Update(thing) => match self.prepare_update(thing, opts).await {
+                    Ok(PreparedUpdate::Done(thing)) => results[i] = Some(Ok(thing)),
+                    Ok(PreparedUpdate::Store {
+                        new_thing,
+                        commands,
+                    }) => {
+                        updates.0.push((i, commands));
+                        updates.1.push(new_thing);
+                    }
+                    Err(err) => results[i] = Some(Err(err)),
+                },
+            }
+        }
+
+        // store, and complete
+
+        let created = self
+            .storage
+            .create_many(creates.1)
+            .await
+            .map_err(Error::Storage)?;
+        let updated = self
+            .storage
+            .update_many(updates.1)
             .await
-            .map_err(Error::Notifier)?;
+            .map_err(Error::Storage)?;
 
-        // FIXME: handle failure
+        for ((i, commands), result) in creates
+            .0
+            .into_iter()
+            .zip(created)
+            .chain(updates.0.into_iter().zip(updated))
+        {
+            results[i] = Some(match result {
+                Ok(new_thing) => self.complete(new_thing, commands).await,
+                Err(err) => Err(Error::Storage(err)),
+            });
+        }
 
-        // done
+        // every operation has an outcome by now
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    #[instrument(skip_all, fields(
+        application = thing.metadata.application,
+        name = thing.metadata.name
+    ), err)]
+    async fn import(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Error<St, No, Cmd>> {
+        let new_thing = self.storage.import(thing).await.map_err(Error::Storage)?;
+
+        self.notifications
+            .notify(|| self.notifier.notify(&new_thing))
+            .await;
 
         Ok(new_thing)
     }
+
+    #[instrument(skip_all, fields(
+        application = %thing.metadata.application,
+        thing = %thing.metadata.name,
+    ), err)]
+    async fn dry_run(&self, thing: Thing<Internal>) -> Result<Outcome, Error<St, No, Cmd>> {
+        let id = Id::new(&thing.metadata.application, &thing.metadata.name);
+
+        match self.get(&id).await? {
+            Some(current_thing) if current_thing.metadata.deletion_timestamp.is_some() => {
+                Err(Error::Storage(storage::Error::NotFound))
+            }
+            Some(current_thing) => Ok(Machine::new(current_thing)
+                .with_options(self.options.clone())
+                .update(|current| async { thing.update(current) })
+                .await?),
+            None => Ok(Machine::create_with(thing, self.options.clone()).await?),
+        }
+    }
 }
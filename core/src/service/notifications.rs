@@ -0,0 +1,259 @@
+//! Retrying change notifications, and breaking the circuit when the notifier keeps failing.
+//!
+//! Things are already stored when they get notified, so a failed notification can't fail the
+//! operation anymore. Instead, it gets retried with an exponential backoff. Once the retries are
+//! exhausted, the notification is dropped and counted. After a number of consecutive dropped
+//! notifications, the circuit opens, and notifications are dropped right away, until the reset
+//! timeout passed and the next notification is attempted again.
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref NOTIFICATIONS_RETRIED: IntCounter = register_int_counter!(
+        "notifications_retried",
+        "Number of retried change notifications"
+    )
+    .unwrap();
+    static ref NOTIFICATIONS_DROPPED: IntCounter = register_int_counter!(
+        "notifications_dropped",
+        "Number of change notifications, dropped after failing"
+    )
+    .unwrap();
+    static ref CIRCUIT_OPENED: IntCounter = register_int_counter!(
+        "notifications_circuit_opened",
+        "Number of times the circuit of the notifier was opened"
+    )
+    .unwrap();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct Config {
+    /// The number of retries, after the first attempt failed.
+    #[serde(default = "default::retries")]
+    pub retries: u32,
+    /// The delay before the first retry, doubled for each further retry.
+    #[serde(default = "default::initial_backoff", with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    /// The maximum delay between two retries.
+    #[serde(default = "default::max_backoff", with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// The number of consecutive dropped notifications, opening the circuit.
+    #[serde(default = "default::failure_threshold")]
+    pub failure_threshold: u32,
+    /// The time the circuit stays open, before attempting notifications again.
+    #[serde(default = "default::reset_timeout", with = "humantime_serde")]
+    pub reset_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            retries: default::retries(),
+            initial_backoff: default::initial_backoff(),
+            max_backoff: default::max_backoff(),
+            failure_threshold: default::failure_threshold(),
+            reset_timeout: default::reset_timeout(),
+        }
+    }
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn retries() -> u32 {
+        3
+    }
+
+    pub const fn initial_backoff() -> Duration {
+        Duration::from_millis(100)
+    }
+
+    pub const fn max_backoff() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    pub const fn failure_threshold() -> u32 {
+        5
+    }
+
+    pub const fn reset_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Consecutive dropped notifications.
+    failures: u32,
+    /// The time until the circuit is open.
+    open_until: Option<Instant>,
+}
+
+/// Send notifications, retrying and circuit breaking failures.
+#[derive(Debug)]
+pub struct Notifications {
+    config: Config,
+    state: Mutex<State>,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl Notifications {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+        }
+    }
+
+    /// Check if the circuit is open, and notifications must be dropped right away.
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        matches!(state.open_until, Some(open_until) if Instant::now() < open_until)
+    }
+
+    /// The delay before the n-th retry, starting with zero.
+    fn backoff(&self, retry: u32) -> Duration {
+        self.config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.config.max_backoff)
+    }
+
+    /// Run the notification, returning `true` if it was sent, and `false` if it was dropped.
+    pub async fn notify<F, Fut, E>(&self, f: F) -> bool
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        if self.is_open() {
+            log::debug!("Circuit is open, dropping notification");
+            NOTIFICATIONS_DROPPED.inc();
+            return false;
+        }
+
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Ok(()) => {
+                    self.succeeded();
+                    return true;
+                }
+                Err(err) if retry < self.config.retries => {
+                    log::info!("Failed to notify (retry {retry}), retrying: {err}");
+                    NOTIFICATIONS_RETRIED.inc();
+                    tokio::time::sleep(self.backoff(retry)).await;
+                    retry += 1;
+                }
+                Err(err) => {
+                    log::warn!("Failed to notify, dropping notification: {err}");
+                    NOTIFICATIONS_DROPPED.inc();
+                    self.failed();
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.config.failure_threshold {
+            log::warn!(
+                "Notifier failed {} times in a row, opening circuit for {:?}",
+                state.failures,
+                self.config.reset_timeout
+            );
+            CIRCUIT_OPENED.inc();
+            state.open_until = Some(Instant::now() + self.config.reset_timeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn config() -> Config {
+        Config {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            failure_threshold: 2,
+            reset_timeout: Duration::from_secs(60),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let notifications = Notifications::new(config());
+        let attempts = &AtomicU32::new(0);
+
+        // fails twice, succeeds with the last retry
+        let sent = notifications
+            .notify(|| async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err("failed"),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+
+        assert!(sent);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(!notifications.is_open());
+    }
+
+    #[tokio::test]
+    async fn test_circuit() {
+        let notifications = Notifications::new(config());
+        let attempts = &AtomicU32::new(0);
+        let fail = || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>("failed")
+        };
+
+        assert!(!notifications.notify(fail).await);
+        assert!(!notifications.is_open());
+        assert!(!notifications.notify(fail).await);
+        assert!(notifications.is_open());
+        assert_eq!(attempts.load(Ordering::SeqCst), 6);
+
+        // dropped right away
+        assert!(!notifications.notify(fail).await);
+        assert_eq!(attempts.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let notifications = Notifications::new(Config {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            ..config()
+        });
+
+        assert_eq!(notifications.backoff(0), Duration::from_millis(100));
+        assert_eq!(notifications.backoff(1), Duration::from_millis(200));
+        assert_eq!(notifications.backoff(2), Duration::from_millis(300));
+        assert_eq!(notifications.backoff(40), Duration::from_millis(300));
+    }
+}
@@ -1,7 +1,10 @@
 use crate::{
+    clock,
+    machine::desired,
     model::{
-        Deleting, DesiredFeature, DesiredFeatureMethod, DesiredFeatureReconciliation, DesiredMode,
-        Reconciliation, ReportedFeature, SyntheticFeature, SyntheticType, Thing,
+        CommandResult, Deleting, DesiredFeature, DesiredFeatureMethod,
+        DesiredFeatureReconciliation, DesiredMode, ExpiryExt, Reconciliation, ReportedFeature,
+        SyntheticFeature, SyntheticType, Thing,
     },
     processor::SetDesiredValue,
 };
@@ -12,10 +15,11 @@ use std::{
     collections::{btree_map::Entry, BTreeMap},
     convert::Infallible,
     fmt::Debug,
+    str::FromStr,
     time::Duration,
 };
 
-use crate::model::Internal;
+use crate::model::{Internal, InternalThingExt};
 pub use json_patch::Patch;
 
 pub trait Updater {
@@ -88,6 +92,15 @@ impl InfallibleUpdater for () {
     }
 }
 
+impl<U: InfallibleUpdater> InfallibleUpdater for Option<U> {
+    fn update(&self, thing: Thing<Internal>) -> Thing<Internal> {
+        match self {
+            Some(updater) => InfallibleUpdater::update(updater, thing),
+            None => thing,
+        }
+    }
+}
+
 pub enum UpdateMode {
     Merge,
     Replace,
@@ -114,12 +127,15 @@ impl InfallibleUpdater for MapValueInserter {
                         fields.insert(self.1.clone(), Value::Null);
                     }
                     _ => {
-                        *e = ReportedFeature::now(json!({ self.1.clone(): null }));
+                        *e = ReportedFeature::new(json!({ self.1.clone(): null }), clock::now());
                     }
                 }
             }
             Entry::Vacant(entry) => {
-                entry.insert(ReportedFeature::now(json!({ self.1.clone(): null })));
+                entry.insert(ReportedFeature::new(
+                    json!({ self.1.clone(): null }),
+                    clock::now(),
+                ));
             }
         }
 
@@ -152,7 +168,29 @@ impl InfallibleUpdater for Cleanup {
                 thing.metadata.name
             );
             // mark deleted
-            thing.metadata.deletion_timestamp = Some(Utc::now());
+            thing.metadata.deletion_timestamp = Some(clock::now());
+        }
+
+        thing
+    }
+}
+
+/// Delete the thing, in case it expired.
+///
+/// NOTE: Like [`Cleanup`], this only works for calls which respect a change on the
+/// `deletion_timestamp` field.
+pub struct Expire;
+
+impl InfallibleUpdater for Expire {
+    fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        if thing.is_expired() {
+            log::debug!(
+                "Thing expired, scheduling deletion of {}/{}",
+                thing.metadata.application,
+                thing.metadata.name
+            );
+            // mark deleted
+            thing.metadata.deletion_timestamp = Some(clock::now());
         }
 
         thing
@@ -192,6 +230,14 @@ pub struct ReportedStateUpdater(pub BTreeMap<String, Value>, pub UpdateMode);
 
 impl InfallibleUpdater for ReportedStateUpdater {
     fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        // the thing is alive, even if none of the values changed, see [`ExpiryExt`]
+        if thing.metadata.expires_after().is_some() {
+            thing.set_last_report(clock::now());
+        }
+        // let the reconciliation sample the features, even if their values didn't change
+        thing.internal.get_or_insert_with(Default::default).reported =
+            self.0.keys().cloned().collect();
+
         match self.1 {
             // merge new data into current, update timestamps when the value has indeed changed
             UpdateMode::Merge => {
@@ -206,7 +252,7 @@ impl InfallibleUpdater for ReportedStateUpdater {
                             }
                         }
                         Entry::Vacant(e) => {
-                            e.insert(ReportedFeature::now(value));
+                            e.insert(ReportedFeature::new(value, clock::now()));
                         }
                     }
                 }
@@ -221,11 +267,11 @@ impl InfallibleUpdater for ReportedStateUpdater {
                             if feature.value == value {
                                 new_state.insert(key, feature);
                             } else {
-                                new_state.insert(key, ReportedFeature::now(value));
+                                new_state.insert(key, ReportedFeature::new(value, clock::now()));
                             }
                         }
                         None => {
-                            new_state.insert(key, ReportedFeature::now(value));
+                            new_state.insert(key, ReportedFeature::new(value, clock::now()));
                         }
                     }
                 }
@@ -237,6 +283,25 @@ impl InfallibleUpdater for ReportedStateUpdater {
     }
 }
 
+/// process an update of referenced values, only applied to reference synthetic features
+pub struct ReferenceUpdater(pub BTreeMap<String, Value>);
+
+impl InfallibleUpdater for ReferenceUpdater {
+    fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        for (name, value) in &self.0 {
+            if let Some(feature) = thing.synthetic_state.get_mut(name) {
+                if matches!(feature.r#type, SyntheticType::Reference(_)) && &feature.value != value
+                {
+                    feature.value = value.clone();
+                    feature.last_update = clock::now();
+                }
+            }
+        }
+
+        thing
+    }
+}
+
 impl InfallibleUpdater for Reconciliation {
     fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
         thing.reconciliation = self.clone();
@@ -250,6 +315,83 @@ impl InfallibleUpdater for Thing<Internal> {
     }
 }
 
+/// Sections of a thing, which are managed by the system.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ManagedSections {
+    /// The status of reconciliations: the last runs and logs of timers and change hooks.
+    pub reconciliation: bool,
+    /// The reconciliation state of desired features.
+    pub desired: bool,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown section: {0}")]
+pub struct UnknownSection(pub String);
+
+impl FromStr for ManagedSections {
+    type Err = UnknownSection;
+
+    /// Parse a comma separated list of sections.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Self::default();
+        for section in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match section {
+                "reconciliation" => result.reconciliation = true,
+                "desired" => result.desired = true,
+                _ => return Err(UnknownSection(section.to_string())),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Replace a thing, preserving the sections managed by the system.
+///
+/// The internal section is always preserved. The managed sections are preserved, unless
+/// included, in which case they are taken from the new thing.
+pub struct GuardedUpdater {
+    pub thing: Thing<Internal>,
+    pub include: ManagedSections,
+}
+
+impl InfallibleUpdater for GuardedUpdater {
+    fn update(&self, current: Thing<Internal>) -> Thing<Internal> {
+        let mut thing = self.thing.clone();
+        thing.internal = current.internal;
+
+        if !self.include.reconciliation {
+            for (name, changed) in &mut thing.reconciliation.changed {
+                if let Some(current) = current.reconciliation.changed.get(name) {
+                    changed.last_log = current.last_log.clone();
+                }
+            }
+            for (name, timer) in &mut thing.reconciliation.timers {
+                if let Some(current) = current.reconciliation.timers.get(name) {
+                    timer.last_started = current.last_started;
+                    timer.last_run = current.last_run;
+                    timer.last_log = current.last_log.clone();
+                }
+            }
+        }
+
+        if !self.include.desired {
+            for (name, desired) in &mut thing.desired_state {
+                match current.desired_state.get(name) {
+                    // only keep the state if the requested value is the same
+                    Some(current)
+                        if current.value == desired.value && current.mode == desired.mode =>
+                    {
+                        desired.reconciliation = current.reconciliation.clone();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        thing
+    }
+}
+
 /// Updater for JSON patch
 pub struct JsonPatchUpdater(pub Patch);
 
@@ -265,7 +407,7 @@ impl Updater for JsonPatchUpdater {
     type Error = PatchError;
 
     fn update(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Self::Error> {
-        let mut json = serde_json::to_value(thing)?;
+        let mut json = thing.to_value()?;
         json_patch::patch(&mut json, &self.0)?;
         Ok(serde_json::from_value(json)?)
     }
@@ -282,7 +424,7 @@ impl Updater for JsonMergeUpdater {
     type Error = MergeError;
 
     fn update(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Self::Error> {
-        let mut json = serde_json::to_value(thing)?;
+        let mut json = thing.to_value()?;
         json_patch::merge(&mut json, &self.0);
         Ok(serde_json::from_value(json)?)
     }
@@ -299,7 +441,7 @@ impl InfallibleUpdater for SyntheticStateUpdater {
             Entry::Vacant(entry) => {
                 entry.insert(SyntheticFeature {
                     r#type: self.1.clone(),
-                    last_update: Utc::now(),
+                    last_update: clock::now(),
                     value: Default::default(),
                 });
             }
@@ -358,7 +500,7 @@ impl Updater for DesiredStateUpdater {
         let valid_until = valid_until.or(valid_for
             .map(chrono::Duration::from_std)
             .transpose()?
-            .map(|d| Utc::now() + d));
+            .map(|d| clock::now() + d));
 
         match thing.desired_state.entry(self.0.clone()) {
             Entry::Occupied(mut entry) => {
@@ -382,11 +524,12 @@ impl Updater for DesiredStateUpdater {
                 // we create some reasonable defaults
                 entry.insert(DesiredFeature {
                     value: value.unwrap_or_default(),
-                    last_update: Utc::now(),
+                    last_update: clock::now(),
                     valid_until,
                     reconciliation: reconciliation.unwrap_or_default(),
                     method: method.unwrap_or_default(),
                     mode: mode.unwrap_or_default(),
+                    last_result: None,
                 });
             }
         }
@@ -434,6 +577,48 @@ impl Updater for DesiredStateValueUpdater {
     }
 }
 
+/// Record the result of a command, acknowledged by the device.
+///
+/// The result is recorded for all desired features, which are reconciled by sending the command,
+/// and are currently waiting for the result of an attempt.
+pub struct CommandResultUpdater {
+    pub command: String,
+    pub success: bool,
+    pub reason: Option<String>,
+}
+
+impl InfallibleUpdater for CommandResultUpdater {
+    fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        let now = clock::now();
+
+        for (name, desired) in &mut thing.desired_state {
+            let DesiredFeatureMethod::Command(command) = &desired.method else {
+                continue;
+            };
+            if !matches!(
+                desired.reconciliation,
+                DesiredFeatureReconciliation::Reconciling {
+                    last_attempt: Some(_)
+                }
+            ) {
+                // no command sent, which could be acknowledged
+                continue;
+            }
+
+            let encoding = desired::encoding(command, &thing.metadata.annotations);
+            if desired::command_name(&encoding, name) == self.command {
+                desired.last_result = Some(CommandResult {
+                    when: now,
+                    success: self.success,
+                    reason: self.reason.clone(),
+                });
+            }
+        }
+
+        thing
+    }
+}
+
 pub struct AnnotationsUpdater(pub BTreeMap<String, Option<String>>);
 
 impl AnnotationsUpdater {
@@ -463,6 +648,16 @@ impl InfallibleUpdater for AnnotationsUpdater {
     }
 }
 
+/// Replace the labels of a thing.
+pub struct LabelsUpdater(pub BTreeMap<String, String>);
+
+impl InfallibleUpdater for LabelsUpdater {
+    fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
+        thing.metadata.labels = self.0.clone();
+        thing
+    }
+}
+
 impl InfallibleUpdater for IndexMap<String, Deleting> {
     fn update(&self, mut thing: Thing<Internal>) -> Thing<Internal> {
         for (k, v) in self {
@@ -504,12 +699,77 @@ mod test {
 
     use super::InfallibleUpdater;
     use super::*;
+    use crate::model::{Code, CommandEncoding, Delivery, Reference, Timer, Waker};
+    use crate::processor::{Event, Message};
     use serde_json::Value;
 
     fn new_thing() -> Thing<Internal> {
         Thing::new("default", "test")
     }
 
+    #[test]
+    fn test_guarded_update() {
+        let mut current = new_thing();
+        current.reconciliation.timers.insert(
+            "timer".to_string(),
+            Timer {
+                last_run: Some(Utc::now()),
+                last_log: vec!["ran".to_string()],
+                ..Timer::new(Duration::from_secs(1), None, Code::JavaScript("".into()))
+            },
+        );
+        current.internal = Some(Internal {
+            waker: Waker {
+                when: Some(Utc::now()),
+                why: Default::default(),
+            },
+            ..Default::default()
+        });
+
+        let mut update = new_thing();
+        update.reconciliation.timers.insert(
+            "timer".to_string(),
+            Timer::new(Duration::from_secs(5), None, Code::JavaScript("".into())),
+        );
+
+        let guarded = GuardedUpdater {
+            thing: update.clone(),
+            include: Default::default(),
+        };
+        let thing = InfallibleUpdater::update(&guarded, current.clone());
+        assert_eq!(thing.internal, current.internal);
+        let timer = &thing.reconciliation.timers["timer"];
+        assert_eq!(timer.period, Duration::from_secs(5));
+        assert_eq!(
+            timer.last_run,
+            current.reconciliation.timers["timer"].last_run
+        );
+        assert_eq!(timer.last_log, vec!["ran".to_string()]);
+
+        let guarded = GuardedUpdater {
+            thing: update,
+            include: "reconciliation".parse().unwrap(),
+        };
+        let thing = InfallibleUpdater::update(&guarded, current.clone());
+        assert_eq!(thing.internal, current.internal);
+        assert_eq!(thing.reconciliation.timers["timer"].last_run, None);
+    }
+
+    #[test]
+    fn test_managed_sections() {
+        assert_eq!("".parse::<ManagedSections>().unwrap(), Default::default());
+        assert_eq!(
+            "reconciliation, desired"
+                .parse::<ManagedSections>()
+                .unwrap(),
+            ManagedSections {
+                reconciliation: true,
+                desired: true
+            }
+        );
+        assert!("internal".parse::<ManagedSections>().is_err());
+    }
+
     #[test]
     fn test_repstate_merge_empty() {
         let thing = new_thing();
@@ -540,6 +800,64 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_repstate_unchanged_keeps_alive() {
+        let past = Utc::now() - chrono::Duration::hours(2);
+
+        let mut thing = new_thing();
+        thing.metadata.creation_timestamp = Some(past);
+        thing
+            .metadata
+            .annotations
+            .insert(crate::model::ANNOTATION_EXPIRES_AFTER.into(), "1h".into());
+        thing
+            .reported_state
+            .insert("foo".into(), ReportedFeature::new("bar".into(), past));
+        assert!(thing.is_expired());
+
+        // report the same value again
+        let mut data = BTreeMap::<String, Value>::new();
+        data.insert("foo".into(), "bar".into());
+        let thing =
+            InfallibleUpdater::update(&ReportedStateUpdater(data, UpdateMode::Merge), thing);
+
+        assert_eq!(thing.reported_state["foo"].last_update, past);
+        assert!(!thing.is_expired());
+
+        let thing = InfallibleUpdater::update(&Expire, thing);
+        assert_eq!(thing.metadata.deletion_timestamp, None);
+    }
+
+    #[test]
+    fn test_merge_keeps_history() {
+        let mut thing = new_thing();
+        thing.internal = Some(Internal {
+            deliveries: vec![Delivery::new(&Event::new(
+                "default",
+                "other",
+                Message::report_state(true),
+            ))],
+            modified: Some(Utc::now()),
+            ..Default::default()
+        });
+
+        let merged = Updater::update(
+            &JsonMergeUpdater(json!({"metadata": {"labels": {"foo": "bar"}}})),
+            thing.clone(),
+        )
+        .unwrap();
+        assert_eq!(merged.metadata.labels["foo"], "bar");
+        assert_eq!(merged.internal, thing.internal);
+        assert_eq!(merged.last_modified(), thing.last_modified());
+
+        let patched = Updater::update(
+            &JsonPatchUpdater(serde_json::from_value(json!([])).unwrap()),
+            thing.clone(),
+        )
+        .unwrap();
+        assert_eq!(patched.internal, thing.internal);
+    }
+
     #[test]
     fn test_map_value() {
         let thing = new_thing();
@@ -581,4 +899,91 @@ mod test {
         );
         assert_eq!(thing.reported_state["$children"].value, json!({}));
     }
+
+    #[test]
+    fn test_reference_update() {
+        let mut thing = new_thing();
+        thing.synthetic_state.insert(
+            "weather".to_string(),
+            SyntheticFeature {
+                r#type: SyntheticType::Reference(Reference {
+                    application: Some("shared".to_string()),
+                    thing: "weather".to_string(),
+                    feature: "temperature".to_string(),
+                }),
+                last_update: Utc::now(),
+                value: Value::Null,
+            },
+        );
+        thing.synthetic_state.insert(
+            "alias".to_string(),
+            SyntheticFeature {
+                r#type: SyntheticType::Alias("temperature".to_string()),
+                last_update: Utc::now(),
+                value: Value::Null,
+            },
+        );
+
+        let thing = InfallibleUpdater::update(
+            &ReferenceUpdater(
+                [
+                    ("weather".to_string(), json!(21.5)),
+                    ("alias".to_string(), json!(42)),
+                    ("unknown".to_string(), json!(1)),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            thing,
+        );
+
+        assert_eq!(thing.synthetic_state["weather"].value, json!(21.5));
+        // only references get updated
+        assert_eq!(thing.synthetic_state["alias"].value, Value::Null);
+        assert!(!thing.synthetic_state.contains_key("unknown"));
+    }
+
+    #[test]
+    fn test_command_result() {
+        let mut thing = new_thing();
+        let desired = |channel: Option<&str>, last_attempt| DesiredFeature {
+            value: json!(true),
+            mode: DesiredMode::Once,
+            last_update: Utc::now(),
+            valid_until: None,
+            reconciliation: DesiredFeatureReconciliation::Reconciling { last_attempt },
+            method: DesiredFeatureMethod::Command(crate::model::Command {
+                encoding: channel.map(|channel| CommandEncoding::Channel(channel.to_string())),
+                acknowledge: true,
+                ..Default::default()
+            }),
+            last_result: None,
+        };
+        thing
+            .desired_state
+            .insert("light".to_string(), desired(None, Some(Utc::now())));
+        thing
+            .desired_state
+            .insert("pending".to_string(), desired(None, None));
+        thing
+            .desired_state
+            .insert("fan".to_string(), desired(Some("light"), Some(Utc::now())));
+
+        let thing = InfallibleUpdater::update(
+            &CommandResultUpdater {
+                command: "light".to_string(),
+                success: false,
+                reason: Some("Busy".to_string()),
+            },
+            thing,
+        );
+
+        let result = thing.desired_state["light"].last_result.as_ref().unwrap();
+        assert!(!result.success);
+        assert_eq!(result.reason.as_deref(), Some("Busy"));
+        // no command was sent yet
+        assert_eq!(thing.desired_state["pending"].last_result, None);
+        // sent using the same channel
+        assert!(thing.desired_state["fan"].last_result.is_some());
+    }
 }
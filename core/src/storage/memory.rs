@@ -0,0 +1,329 @@
+//! Storing things in memory, for development and testing.
+//!
+//! Things are lost when the process terminates. Storages created from a configuration with the
+//! same name share their things, so that all components of a single process (e.g. the all-in-one
+//! server) see the same state.
+
+use crate::{
+    applications::Applications,
+    clock,
+    model::{Internal, Thing},
+    storage::{self, ListOptions},
+    Preconditions,
+};
+use async_trait::async_trait;
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct Config {
+    /// The name of the storage, storages with the same name share their things.
+    #[serde(default)]
+    pub name: String,
+    /// Limit access to things of these applications, see [`Applications`].
+    #[serde(default, alias = "application")]
+    pub applications: Applications,
+}
+
+/// Things, by application and name.
+type Things = BTreeMap<(String, String), Thing<Internal>>;
+
+lazy_static::lazy_static! {
+    static ref STORAGES: Mutex<HashMap<String, Arc<RwLock<Things>>>> = Default::default();
+}
+
+type Result<T> = std::result::Result<T, storage::Error<Infallible>>;
+
+#[derive(Clone, Debug, Default)]
+pub struct Storage {
+    applications: Applications,
+    things: Arc<RwLock<Things>>,
+}
+
+impl Storage {
+    /// Create a new storage, not sharing its things with any other storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn ensure_app(&self, application: &str) -> bool {
+        self.applications.is_allowed(application)
+    }
+
+    fn key(application: &str, name: &str) -> (String, String) {
+        (application.to_string(), name.to_string())
+    }
+}
+
+#[async_trait]
+impl super::Storage for Storage {
+    type Config = Config;
+    type Error = Infallible;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        let things = STORAGES
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Poisoned lock"))?
+            .entry(config.name.clone())
+            .or_default()
+            .clone();
+
+        Ok(Self {
+            applications: config.applications.clone(),
+            things,
+        })
+    }
+
+    async fn get(&self, application: &str, name: &str) -> Result<Option<Thing<Internal>>> {
+        if !self.ensure_app(application) {
+            return Ok(None);
+        }
+
+        Ok(self
+            .things
+            .read()
+            .await
+            .get(&Self::key(application, name))
+            .cloned())
+    }
+
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>> {
+        if !self.ensure_app(application) {
+            return Ok(vec![]);
+        }
+
+        let things = self.things.read().await;
+        let start = Self::key(application, opts.after.as_deref().unwrap_or_default());
+
+        Ok(things
+            .range(start..)
+            .take_while(|((app, _), _)| app == application)
+            .filter(|((_, name), _)| opts.after.as_ref().map_or(true, |after| name > after))
+            .map(|(_, thing)| thing)
+            .filter(|thing| opts.selector.matches(&thing.metadata.labels))
+            .take(opts.limit.map_or(usize::MAX, |limit| limit as usize))
+            .cloned()
+            .collect())
+    }
+
+    async fn create(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotAllowed);
+        }
+
+        thing.metadata.uid = Some(Uuid::new_v4().to_string());
+        thing.metadata.creation_timestamp = Some(clock::now());
+        thing.metadata.generation = Some(1);
+        thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+
+        let key = Self::key(&thing.metadata.application, &thing.metadata.name);
+        match self.things.write().await.entry(key) {
+            Entry::Occupied(_) => Err(storage::Error::AlreadyExists),
+            Entry::Vacant(entry) => Ok(entry.insert(thing).clone()),
+        }
+    }
+
+    async fn update(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotFound);
+        }
+
+        let key = Self::key(&thing.metadata.application, &thing.metadata.name);
+        match self.things.write().await.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let current = entry.get();
+                if !Preconditions::from(&thing).matches(current) {
+                    return Err(storage::Error::PreconditionFailed);
+                }
+
+                // managed by the storage
+                thing.metadata.uid = current.metadata.uid.clone();
+                thing.metadata.creation_timestamp = current.metadata.creation_timestamp;
+                thing.metadata.generation =
+                    Some(current.metadata.generation.unwrap_or_default() + 1);
+                thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+
+                entry.insert(thing.clone());
+                Ok(thing)
+            }
+            Entry::Vacant(_) => Err(storage::Error::NotFound),
+        }
+    }
+
+    async fn import(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotAllowed);
+        }
+
+        // keep the metadata of the source, as far as it's present
+        let metadata = &mut thing.metadata;
+        metadata
+            .uid
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+        metadata.creation_timestamp.get_or_insert_with(clock::now);
+        metadata.generation.get_or_insert(1);
+        metadata
+            .resource_version
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+
+        let key = Self::key(&thing.metadata.application, &thing.metadata.name);
+        match self.things.write().await.entry(key) {
+            Entry::Occupied(_) => Err(storage::Error::AlreadyExists),
+            Entry::Vacant(entry) => Ok(entry.insert(thing).clone()),
+        }
+    }
+
+    async fn delete_with(
+        &self,
+        application: &str,
+        name: &str,
+        opts: Preconditions<'_>,
+    ) -> Result<bool> {
+        if !self.ensure_app(application) {
+            return Ok(false);
+        }
+
+        match self
+            .things
+            .write()
+            .await
+            .entry(Self::key(application, name))
+        {
+            Entry::Occupied(entry) if opts.matches(entry.get()) => {
+                entry.remove();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::Storage as _;
+
+    #[tokio::test]
+    async fn test_versions() {
+        let storage = Storage::new();
+
+        let thing = storage
+            .create(Thing::new("default", "thing1"))
+            .await
+            .unwrap();
+        assert_eq!(thing.metadata.generation, Some(1));
+        assert!(matches!(
+            storage.create(Thing::new("default", "thing1")).await,
+            Err(storage::Error::AlreadyExists)
+        ));
+
+        let updated = storage.update(thing.clone()).await.unwrap();
+        assert_eq!(updated.metadata.generation, Some(2));
+        assert_eq!(updated.metadata.uid, thing.metadata.uid);
+        assert_ne!(
+            updated.metadata.resource_version,
+            thing.metadata.resource_version
+        );
+
+        // the resource version changed
+        assert!(matches!(
+            storage.update(thing.clone()).await,
+            Err(storage::Error::PreconditionFailed)
+        ));
+        assert!(!storage
+            .delete_with("default", "thing1", Preconditions::from(&thing))
+            .await
+            .unwrap());
+
+        assert_eq!(
+            storage.get("default", "thing1").await.unwrap(),
+            Some(updated.clone())
+        );
+        assert!(storage
+            .delete_with("default", "thing1", Preconditions::from(&updated))
+            .await
+            .unwrap());
+        assert_eq!(storage.get("default", "thing1").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let storage = Storage::new();
+
+        for (application, name) in [
+            ("default", "b"),
+            ("default", "a"),
+            ("default", "c"),
+            ("other", "a"),
+        ] {
+            let mut thing = Thing::new(application, name);
+            thing.metadata.labels.insert("name".into(), name.into());
+            storage.create(thing).await.unwrap();
+        }
+
+        let names = |things: Vec<Thing<Internal>>| {
+            things
+                .into_iter()
+                .map(|thing| thing.metadata.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(storage.list("default").await.unwrap()),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(
+            names(
+                storage
+                    .list_with(
+                        "default",
+                        &ListOptions::default().with_after("a").with_limit(1)
+                    )
+                    .await
+                    .unwrap()
+            ),
+            vec!["b"]
+        );
+        assert_eq!(
+            names(
+                storage
+                    .list_with(
+                        "default",
+                        &ListOptions::default().with_selector("name!=b".parse().unwrap())
+                    )
+                    .await
+                    .unwrap()
+            ),
+            vec!["a", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        storage::testkit::run(&Storage::new(), "default").await;
+    }
+
+    #[test]
+    fn test_shared() {
+        let config = Config {
+            name: Uuid::new_v4().to_string(),
+            applications: Default::default(),
+        };
+
+        let first = Storage::from_config(&config).unwrap();
+        let second = Storage::from_config(&config).unwrap();
+        let other = Storage::from_config(&Config::default()).unwrap();
+
+        assert!(Arc::ptr_eq(&first.things, &second.things));
+        assert!(!Arc::ptr_eq(&first.things, &other.things));
+    }
+}
@@ -1,12 +1,22 @@
+#[cfg(feature = "memory")]
+pub mod memory;
+#[cfg(feature = "mongo")]
+pub mod mongodb;
 pub mod postgres;
+pub mod query;
+pub mod selector;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
 
 use crate::model::Internal;
 use crate::{
-    model::{Metadata, Thing},
+    model::{DesiredFeatureReconciliation, Metadata, Thing},
+    processor::Event,
+    storage::{query::Query, selector::LabelSelector},
     Preconditions,
 };
 use async_trait::async_trait;
-use std::{fmt::Debug, future::Future};
+use std::{collections::BTreeMap, fmt::Debug, future::Future};
 use tracing::instrument;
 
 #[derive(Debug, thiserror::Error)]
@@ -45,6 +55,117 @@ where
     Mutator(#[source] UE),
 }
 
+/// Options for listing things.
+///
+/// Things are listed ordered by name. Paging is done using the name of the last thing of the
+/// previous page as cursor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListOptions {
+    /// Only list things with a name after this one.
+    pub after: Option<String>,
+    /// The maximum number of things to list.
+    pub limit: Option<u32>,
+    /// Only list things with matching labels.
+    pub selector: LabelSelector,
+}
+
+impl ListOptions {
+    pub fn with_after(mut self, after: impl Into<String>) -> Self {
+        self.after = Some(after.into());
+        self
+    }
+
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_selector(mut self, selector: LabelSelector) -> Self {
+        self.selector = selector;
+        self
+    }
+}
+
+/// Aggregated statistics of the things of an application.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    /// The number of things.
+    pub things: u64,
+    /// The number of things, by label name and label value.
+    pub labels: BTreeMap<String, BTreeMap<String, u64>>,
+    /// The number of things with at least one failed desired state.
+    pub failed_desired_states: u64,
+    /// The number of things with pending outbox events.
+    pub pending_outbox: u64,
+}
+
+impl Stats {
+    /// Add a thing to the statistics.
+    pub fn add(&mut self, thing: &Thing<Internal>) {
+        self.things += 1;
+
+        for (name, value) in &thing.metadata.labels {
+            *self
+                .labels
+                .entry(name.clone())
+                .or_default()
+                .entry(value.clone())
+                .or_default() += 1;
+        }
+
+        if thing.desired_state.values().any(|desired| {
+            matches!(
+                desired.reconciliation,
+                DesiredFeatureReconciliation::Failed { .. }
+            )
+        }) {
+            self.failed_desired_states += 1;
+        }
+
+        if thing
+            .internal
+            .as_ref()
+            .map_or(false, |internal| !internal.outbox.is_empty())
+        {
+            self.pending_outbox += 1;
+        }
+    }
+}
+
+/// Aggregate the statistics of an application, by listing all of its things.
+pub async fn stats_listed<S: Storage>(
+    storage: &S,
+    application: &str,
+) -> Result<Stats, Error<S::Error>> {
+    let mut stats = Stats::default();
+    for thing in storage.list(application).await? {
+        stats.add(&thing);
+    }
+    Ok(stats)
+}
+
+/// Query things, by listing all things matching the options, and filtering them in memory.
+pub async fn query_listed<S: Storage>(
+    storage: &S,
+    application: &str,
+    query: &Query,
+    opts: &ListOptions,
+) -> Result<Vec<Thing<Internal>>, Error<S::Error>> {
+    let list = ListOptions {
+        limit: None,
+        ..opts.clone()
+    };
+
+    Ok(storage
+        .list_with(application, &list)
+        .await?
+        .into_iter()
+        .filter(|thing| query.matches(thing))
+        .take(opts.limit.map_or(usize::MAX, |limit| limit as usize))
+        .collect())
+}
+
 #[async_trait]
 pub trait Storage: Sized + Send + Sync + 'static {
     type Config: Clone + Debug + Send + Sync + serde::de::DeserializeOwned + 'static;
@@ -57,9 +178,99 @@ pub trait Storage: Sized + Send + Sync + 'static {
         application: &str,
         name: &str,
     ) -> Result<Option<Thing<Internal>>, Error<Self::Error>>;
+    /// List all things of an application.
+    async fn list(&self, application: &str) -> Result<Vec<Thing<Internal>>, Error<Self::Error>> {
+        self.list_with(application, &Default::default()).await
+    }
+    /// List things of an application, ordered by name.
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Error<Self::Error>>;
+    /// Query things of an application by their state, ordered by name.
+    ///
+    /// The limit of the options applies to the matching things. By default, things get listed and
+    /// filtered in memory, see [`query_listed`].
+    async fn query(
+        &self,
+        application: &str,
+        query: &Query,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Error<Self::Error>> {
+        query_listed(self, application, query, opts).await
+    }
+    /// Aggregate the statistics of the things of an application.
+    ///
+    /// By default, all things get listed and aggregated in memory, see [`stats_listed`].
+    async fn stats(&self, application: &str) -> Result<Stats, Error<Self::Error>> {
+        stats_listed(self, application).await
+    }
+    /// Get the events of a thing, waiting in an outbox which is managed by the storage, ordered by
+    /// their creation.
+    ///
+    /// Those events are not part of the outbox of the thing, see
+    /// [`postgres::Config::outbox`]. By default, storages don't manage an outbox.
+    async fn pending_events(
+        &self,
+        _application: &str,
+        _name: &str,
+    ) -> Result<Vec<Event>, Error<Self::Error>> {
+        Ok(vec![])
+    }
     async fn create(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Error<Self::Error>>;
     async fn update(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Error<Self::Error>>;
 
+    /// Update a thing, knowing its current state.
+    ///
+    /// This allows implementations to only write what changed. By default, the thing gets
+    /// updated as a whole.
+    async fn update_from(
+        &self,
+        _current: &Thing<Internal>,
+        thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>, Error<Self::Error>> {
+        self.update(thing).await
+    }
+
+    /// Create multiple things, returning the outcome for each thing, in the same order.
+    ///
+    /// The outer result fails, if the batch could not be processed at all. By default, things are
+    /// created one by one.
+    async fn create_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<Vec<Result<Thing<Internal>, Error<Self::Error>>>, Error<Self::Error>> {
+        let mut result = Vec::with_capacity(things.len());
+        for thing in things {
+            result.push(self.create(thing).await);
+        }
+        Ok(result)
+    }
+
+    /// Update multiple things, returning the outcome for each thing, in the same order.
+    ///
+    /// The outer result fails, if the batch could not be processed at all. By default, things are
+    /// updated one by one.
+    async fn update_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<Vec<Result<Thing<Internal>, Error<Self::Error>>>, Error<Self::Error>> {
+        let mut result = Vec::with_capacity(things.len());
+        for thing in things {
+            result.push(self.update(thing).await);
+        }
+        Ok(result)
+    }
+
+    /// Import a thing, e.g. when transferring things between storages.
+    ///
+    /// Implementations should preserve the metadata managed by the storage (UID, generation,
+    /// resource version, timestamps), if possible. By default, the thing gets created as new.
+    async fn import(&self, thing: Thing<Internal>) -> Result<Thing<Internal>, Error<Self::Error>> {
+        self.create(thing).await
+    }
+
     #[instrument(skip(self, f), err, ret)]
     async fn patch<F, Fut, E>(
         &self,
@@ -127,4 +338,23 @@ pub trait Storage: Sized + Send + Sync + 'static {
         name: &str,
         opts: Preconditions<'_>,
     ) -> Result<bool, Error<Self::Error>>;
+
+    /// Get a specific generation of a thing, `None` if it isn't known (anymore).
+    ///
+    /// By default, only the current generation is available.
+    async fn get_revision(
+        &self,
+        application: &str,
+        name: &str,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>, Error<Self::Error>> {
+        Ok(self
+            .get(application, name)
+            .await
+            .or_else(|err| match err {
+                Error::NotFound => Ok(None),
+                _ => Err(err),
+            })?
+            .filter(|thing| thing.metadata.generation == Some(generation)))
+    }
 }
@@ -0,0 +1,395 @@
+//! Storing things in MongoDB.
+//!
+//! Each thing is stored as a document, identified by its application and name. The thing itself
+//! is stored as serialized JSON, as feature names may contain characters which are not allowed in
+//! field names of MongoDB. The resource version is kept as a separate field, used for optimistic
+//! locking.
+//!
+//! The time a thing needs to be woken up is stored too, however the waker currently requires the
+//! Postgres storage.
+
+use crate::{
+    applications::Applications,
+    clock,
+    config::check::{Check, Checker},
+    model::{Internal, InternalThingExt, Thing},
+    storage::{self, ListOptions},
+    Preconditions,
+};
+use ::mongodb::{
+    bson::{self, doc, Document},
+    error::{ErrorKind, WriteFailure},
+    options::FindOptions,
+    Client, Collection,
+};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use tokio::sync::OnceCell;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// The error code of a duplicate key.
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Config {
+    /// The connection URL, e.g. `mongodb://localhost:27017`.
+    pub url: String,
+    pub database: String,
+    #[serde(default = "default::collection")]
+    pub collection: String,
+    /// Limit access to things of these applications, see [`Applications`].
+    #[serde(default, alias = "application")]
+    pub applications: Applications,
+}
+
+mod default {
+    pub fn collection() -> String {
+        "things".to_string()
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .not_empty("url", &self.url)
+            .not_empty("database", &self.database)
+            .not_empty("collection", &self.collection);
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("MongoDB error: {0}")]
+    Mongo(#[from] ::mongodb::error::Error),
+}
+
+impl From<Error> for storage::Error<Error> {
+    fn from(err: Error) -> Self {
+        storage::Error::Internal(err)
+    }
+}
+
+impl From<::mongodb::error::Error> for storage::Error<Error> {
+    fn from(err: ::mongodb::error::Error) -> Self {
+        storage::Error::Internal(Error::Mongo(err))
+    }
+}
+
+type Result<T> = std::result::Result<T, storage::Error<Error>>;
+
+/// A thing, as stored in a document.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entity {
+    #[serde(rename = "_id")]
+    id: EntityId,
+    uid: String,
+    resource_version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    waker: Option<bson::DateTime>,
+    /// The thing, serialized as JSON.
+    data: String,
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+struct EntityId {
+    application: String,
+    name: String,
+}
+
+impl Entity {
+    fn new(thing: &Thing<Internal>) -> Result<Self> {
+        Ok(Self {
+            id: EntityId {
+                application: thing.metadata.application.clone(),
+                name: thing.metadata.name.clone(),
+            },
+            uid: thing.metadata.uid.clone().unwrap_or_default(),
+            resource_version: thing.metadata.resource_version.clone().unwrap_or_default(),
+            waker: thing
+                .internal
+                .as_ref()
+                .and_then(|internal| internal.waker.when)
+                .map(|when| bson::DateTime::from_millis(when.timestamp_millis())),
+            data: serde_json::to_string(&thing.to_value()?)?,
+        })
+    }
+
+    fn into_thing(self) -> Result<Thing<Internal>> {
+        Ok(serde_json::from_str(&self.data)?)
+    }
+}
+
+fn id(application: &str, name: &str) -> Document {
+    doc! {
+        "_id": {
+            "application": application,
+            "name": name,
+        }
+    }
+}
+
+pub struct Storage {
+    config: Config,
+    collection: OnceCell<Collection<Entity>>,
+}
+
+impl Storage {
+    /// The collection, connecting on first use.
+    async fn collection(&self) -> Result<&Collection<Entity>> {
+        Ok(self
+            .collection
+            .get_or_try_init(|| async {
+                let client = Client::with_uri_str(&self.config.url).await?;
+                Ok::<_, ::mongodb::error::Error>(
+                    client
+                        .database(&self.config.database)
+                        .collection(&self.config.collection),
+                )
+            })
+            .await?)
+    }
+
+    fn ensure_app(&self, application: &str) -> bool {
+        self.config.applications.is_allowed(application)
+    }
+}
+
+#[async_trait]
+impl super::Storage for Storage {
+    type Config = Config;
+    type Error = Error;
+
+    fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            collection: OnceCell::new(),
+        })
+    }
+
+    #[instrument(skip(self), err)]
+    async fn get(&self, application: &str, name: &str) -> Result<Option<Thing<Internal>>> {
+        if !self.ensure_app(application) {
+            return Ok(None);
+        }
+
+        self.collection()
+            .await?
+            .find_one(id(application, name), None)
+            .await?
+            .map(Entity::into_thing)
+            .transpose()
+    }
+
+    #[instrument(skip(self), err)]
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>> {
+        if !self.ensure_app(application) {
+            return Ok(vec![]);
+        }
+
+        let mut filter = doc! { "_id.application": application };
+        if let Some(after) = &opts.after {
+            filter.insert("_id.name", doc! { "$gt": after });
+        }
+
+        // labels are only known after deserializing the thing
+        let limit = match opts.selector.is_empty() {
+            true => opts.limit.map(i64::from),
+            false => None,
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "_id.name": 1 })
+            .limit(limit)
+            .build();
+
+        let mut cursor = self.collection().await?.find(filter, options).await?;
+
+        let mut result = Vec::new();
+        while let Some(entity) = cursor.try_next().await? {
+            let thing = entity.into_thing()?;
+            if !opts.selector.matches(&thing.metadata.labels) {
+                continue;
+            }
+            result.push(thing);
+            if opts
+                .limit
+                .map_or(false, |limit| result.len() >= limit as usize)
+            {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn create(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotAllowed);
+        }
+
+        thing.metadata.uid = Some(Uuid::new_v4().to_string());
+        thing.metadata.creation_timestamp = Some(clock::now());
+        thing.metadata.generation = Some(1);
+        thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+
+        match self
+            .collection()
+            .await?
+            .insert_one(Entity::new(&thing)?, None)
+            .await
+        {
+            Ok(_) => Ok(thing),
+            Err(err) if is_duplicate(&err) => Err(storage::Error::AlreadyExists),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn update(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotFound);
+        }
+
+        let collection = self.collection().await?;
+        let filter = id(&thing.metadata.application, &thing.metadata.name);
+
+        let current = match collection.find_one(filter.clone(), None).await? {
+            Some(current) => current.into_thing()?,
+            None => return Err(storage::Error::NotFound),
+        };
+
+        if !Preconditions::from(&thing).matches(&current) {
+            return Err(storage::Error::PreconditionFailed);
+        }
+
+        // managed by the storage
+        thing.metadata.uid = current.metadata.uid.clone();
+        thing.metadata.creation_timestamp = current.metadata.creation_timestamp;
+        thing.metadata.generation = Some(current.metadata.generation.unwrap_or_default() + 1);
+        thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+
+        // only replace the version we read, the thing might have changed in the meantime
+        let mut filter = filter;
+        filter.insert(
+            "resourceVersion",
+            current.metadata.resource_version.unwrap_or_default(),
+        );
+
+        let result = collection
+            .replace_one(filter, Entity::new(&thing)?, None)
+            .await?;
+
+        match result.matched_count {
+            0 => Err(storage::Error::PreconditionFailed),
+            _ => Ok(thing),
+        }
+    }
+
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn import(&self, thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        if !self.ensure_app(&thing.metadata.application) {
+            return Err(storage::Error::NotAllowed);
+        }
+
+        // keep the metadata of the source, as far as it's present
+        let mut thing = thing;
+        let metadata = &mut thing.metadata;
+        metadata
+            .uid
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+        metadata.creation_timestamp.get_or_insert_with(clock::now);
+        metadata.generation.get_or_insert(1);
+        metadata
+            .resource_version
+            .get_or_insert_with(|| Uuid::new_v4().to_string());
+
+        match self
+            .collection()
+            .await?
+            .insert_one(Entity::new(&thing)?, None)
+            .await
+        {
+            Ok(_) => Ok(thing),
+            Err(err) if is_duplicate(&err) => Err(storage::Error::AlreadyExists),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[instrument(skip(self), err, ret)]
+    async fn delete_with(
+        &self,
+        application: &str,
+        name: &str,
+        opts: Preconditions<'_>,
+    ) -> Result<bool> {
+        if !self.ensure_app(application) {
+            return Ok(false);
+        }
+
+        let mut filter = id(application, name);
+        if let Some(resource_version) = opts.resource_version {
+            filter.insert("resourceVersion", resource_version);
+        }
+        if let Some(uid) = opts.uid {
+            filter.insert("uid", uid);
+        }
+
+        let result = self.collection().await?.delete_one(filter, None).await?;
+
+        Ok(result.deleted_count > 0)
+    }
+}
+
+fn is_duplicate(err: &::mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(err)) if err.code == DUPLICATE_KEY
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entity() {
+        let mut thing = Thing::new("default", "device/channel");
+        thing.metadata.uid = Some("uid".to_string());
+        thing.metadata.resource_version = Some("rv".to_string());
+        thing.reported_state.insert(
+            "$children".into(),
+            crate::model::ReportedFeature::now(1.into()),
+        );
+
+        let entity = Entity::new(&thing).unwrap();
+        assert_eq!(entity.id.application, "default");
+        assert_eq!(entity.id.name, "device/channel");
+        assert_eq!(entity.uid, "uid");
+        assert_eq!(entity.resource_version, "rv");
+        assert_eq!(entity.waker, None);
+
+        let document = bson::to_document(&entity).unwrap();
+        assert_eq!(
+            document.get_document("_id").unwrap(),
+            &doc! { "application": "default", "name": "device/channel" }
+        );
+        assert_eq!(document.get_str("resourceVersion").unwrap(), "rv");
+
+        assert_eq!(entity.into_thing().unwrap(), thing);
+    }
+}
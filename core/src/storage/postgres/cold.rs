@@ -0,0 +1,323 @@
+//! Cold storage of things, which are rarely updated.
+//!
+//! Offloaded things are removed from the `things` table, and stored as JSON objects in an object
+//! store instead. The `cold_things` table keeps track of the offloaded things, and the object
+//! they are stored in. Accessing an offloaded thing rehydrates it into the `things` table.
+//!
+//! Each offload creates a new object, so that removing the object of a rehydrated thing never
+//! affects a later offload of the same thing.
+
+use crate::{
+    config::check::{Check, Checker},
+    model::{Internal, InternalThingExt, Thing},
+};
+use reqwest::StatusCode;
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use std::{io::ErrorKind, path::PathBuf, time::Duration};
+use url::Url;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Config {
+    /// Store objects in a local directory, e.g. a mounted volume.
+    Filesystem(FilesystemConfig),
+    /// Store objects using `GET`, `PUT`, and `DELETE` requests, relative to a base URL.
+    Http(HttpConfig),
+    /// Store objects in an S3 compatible bucket.
+    S3(S3Config),
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct FilesystemConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct HttpConfig {
+    pub url: String,
+    /// A bearer token, sent with every request.
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(with = "humantime_serde", default = "default::timeout")]
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct S3Config {
+    pub bucket: String,
+    #[serde(default = "default::region")]
+    pub region: String,
+    /// The endpoint of an S3 compatible service, defaults to AWS, based on the region.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// The access key, defaults to the AWS environment variables and profiles.
+    #[serde(default)]
+    pub access_key: Option<String>,
+    #[serde(default)]
+    pub secret_key: Option<String>,
+    /// Address the bucket as part of the path, instead of the host name.
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+mod default {
+    use std::time::Duration;
+
+    pub const fn timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    pub fn region() -> String {
+        "us-east-1".to_string()
+    }
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        match self {
+            Self::Filesystem(config) => {
+                checker.not_empty("filesystem.path", &config.path.to_string_lossy());
+            }
+            Self::Http(config) => {
+                if let Err(err) = Url::parse(&config.url) {
+                    checker.issue("http.url", err);
+                }
+            }
+            Self::S3(config) => {
+                checker.not_empty("s3.bucket", &config.bucket);
+                if let Some(Err(err)) = config.endpoint.as_deref().map(Url::parse) {
+                    checker.issue("s3.endpoint", err);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("S3 error: {0}")]
+    S3(#[from] S3Error),
+    #[error("S3 request failed: {0}")]
+    S3Status(u16),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Access to the objects of offloaded things.
+#[derive(Clone, Debug)]
+pub enum ColdStore {
+    Filesystem(PathBuf),
+    Http {
+        client: reqwest::Client,
+        url: Url,
+        token: Option<String>,
+    },
+    S3(Bucket),
+}
+
+/// The key of the object storing an offloaded thing.
+///
+/// Application and name get encoded, including dots, so that they always form a single,
+/// regular, segment of a path or URL.
+pub fn key(application: &str, name: &str, object: &Uuid) -> String {
+    let encode = |value: &str| -> String {
+        url::form_urlencoded::byte_serialize(value.as_bytes())
+            .collect::<String>()
+            .replace('.', "%2E")
+    };
+    format!("{}/{}/{object}.json", encode(application), encode(name))
+}
+
+impl ColdStore {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        Ok(match config {
+            Config::Filesystem(config) => Self::Filesystem(config.path.clone()),
+            Config::Http(config) => {
+                let mut url = Url::parse(&config.url)?;
+                // keys are relative to the base URL, which requires a trailing slash
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                Self::Http {
+                    client: reqwest::Client::builder().timeout(config.timeout).build()?,
+                    url,
+                    token: config.token.clone(),
+                }
+            }
+            Config::S3(config) => {
+                let region = match &config.endpoint {
+                    Some(endpoint) => Region::Custom {
+                        region: config.region.clone(),
+                        endpoint: endpoint.clone(),
+                    },
+                    None => config.region.parse()?,
+                };
+                let credentials = Credentials::new(
+                    config.access_key.as_deref(),
+                    config.secret_key.as_deref(),
+                    None,
+                    None,
+                    None,
+                )?;
+                let mut bucket = Bucket::new(&config.bucket, region, credentials)?;
+                if config.path_style {
+                    bucket.set_path_style();
+                }
+                Self::S3(bucket)
+            }
+        })
+    }
+
+    /// Get an offloaded thing, `None` if the object doesn't exist.
+    pub async fn get(&self, key: &str) -> Result<Option<Thing<Internal>>, Error> {
+        let data = match self {
+            Self::Filesystem(path) => match tokio::fs::read(path.join(key)).await {
+                Ok(data) => data,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            },
+            Self::Http { client, url, token } => {
+                let response = Self::request(client.get(Self::url(url, key)), token)
+                    .send()
+                    .await?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                response.error_for_status()?.bytes().await?.to_vec()
+            }
+            Self::S3(bucket) => {
+                let response = bucket.get_object(key).await?;
+                match response.status_code() {
+                    404 => return Ok(None),
+                    200..=299 => response.bytes().to_vec(),
+                    status => return Err(Error::S3Status(status)),
+                }
+            }
+        };
+
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Store an offloaded thing.
+    pub async fn put(&self, key: &str, thing: &Thing<Internal>) -> Result<(), Error> {
+        let data = serde_json::to_vec(&thing.to_value()?)?;
+
+        match self {
+            Self::Filesystem(path) => {
+                let path = path.join(key);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                // write to a temporary file first, so that readers never see a partial object
+                let temp = path.with_extension("tmp");
+                tokio::fs::write(&temp, data).await?;
+                tokio::fs::rename(temp, path).await?;
+            }
+            Self::Http { client, url, token } => {
+                Self::request(client.put(Self::url(url, key)), token)
+                    .header("content-type", "application/json")
+                    .body(data)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::S3(bucket) => {
+                let response = bucket
+                    .put_object_with_content_type(key, &data, "application/json")
+                    .await?;
+                Self::check_s3(response.status_code(), false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delete the object of an offloaded thing, ignoring objects which don't exist.
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        match self {
+            Self::Filesystem(path) => match tokio::fs::remove_file(path.join(key)).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            },
+            Self::Http { client, url, token } => {
+                let response = Self::request(client.delete(Self::url(url, key)), token)
+                    .send()
+                    .await?;
+                if response.status() != StatusCode::NOT_FOUND {
+                    response.error_for_status()?;
+                }
+            }
+            Self::S3(bucket) => {
+                let response = bucket.delete_object(key).await?;
+                Self::check_s3(response.status_code(), true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_s3(status: u16, allow_missing: bool) -> Result<(), Error> {
+        match status {
+            200..=299 => Ok(()),
+            404 if allow_missing => Ok(()),
+            status => Err(Error::S3Status(status)),
+        }
+    }
+
+    fn url(url: &Url, key: &str) -> Url {
+        // the key is already encoded, so joining can't fail
+        url.join(key).unwrap_or_else(|_| url.clone())
+    }
+
+    fn request(
+        request: reqwest::RequestBuilder,
+        token: &Option<String>,
+    ) -> reqwest::RequestBuilder {
+        match token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let object = Uuid::nil();
+        assert_eq!(
+            key("default", "device/channel", &object),
+            "default/device%2Fchannel/00000000-0000-0000-0000-000000000000.json"
+        );
+        assert_eq!(
+            key("default", "../foo", &object),
+            "default/%2E%2E%2Ffoo/00000000-0000-0000-0000-000000000000.json"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_filesystem() {
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let store = ColdStore::Filesystem(path.clone());
+
+        let thing = Thing::new("default", "device/channel");
+        let key = key("default", "device/channel", &Uuid::new_v4());
+
+        assert_eq!(store.get(&key).await.unwrap(), None);
+        store.put(&key, &thing).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), Some(thing));
+        store.delete(&key).await.unwrap();
+        assert_eq!(store.get(&key).await.unwrap(), None);
+        // deleting again is fine
+        store.delete(&key).await.unwrap();
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,114 @@
+//! Migrating the database schema.
+//!
+//! Migrations are versioned, and embedded into the binary. Each migration must be backward
+//! compatible with the previous version of the application, so that the schema can be migrated
+//! while older instances are still running (e.g. during a rolling update). Removing columns or
+//! tables must be done in a later release, once no instance uses them anymore.
+//!
+//! Running the migrations is protected by an advisory lock, so that multiple instances starting
+//! at the same time don't race each other.
+
+use anyhow::{anyhow, Context};
+use diesel::{sql_types::BigInt, Connection, PgConnection, RunQueryDsl};
+use diesel_migrations::{
+    embed_migrations, EmbeddedMigrations, HarnessWithOutput, MigrationHarness,
+};
+use drogue_bazaar::db::postgres;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("../database-migration/migrations");
+
+/// The key of the advisory lock, held while migrating.
+const LOCK_KEY: i64 = 0x646f_7070_656c;
+
+/// Run all pending migrations.
+pub async fn run(config: &postgres::Config) -> anyhow::Result<()> {
+    let target = Target::from(config);
+
+    tokio::task::spawn_blocking(move || {
+        let mut connection = target.connect()?;
+
+        println!("Migrating database schema...");
+
+        // wait for other instances to finish migrating
+        lock(&mut connection, true)?;
+
+        let result = HarnessWithOutput::new(&mut connection, std::io::stdout())
+            .run_pending_migrations(MIGRATIONS)
+            .map(|_| ())
+            .map_err(|err| anyhow!("Failed to migrate database schema: {err}"));
+
+        lock(&mut connection, false)?;
+        result?;
+
+        println!("Migrating database schema... done!");
+
+        Ok(())
+    })
+    .await?
+}
+
+/// List the names of the pending migrations.
+pub async fn pending(config: &postgres::Config) -> anyhow::Result<Vec<String>> {
+    let target = Target::from(config);
+
+    tokio::task::spawn_blocking(move || {
+        let mut connection = target.connect()?;
+
+        Ok(connection
+            .pending_migrations(MIGRATIONS)
+            .map_err(|err| anyhow!("Failed to evaluate pending migrations: {err}"))?
+            .iter()
+            .map(|migration| migration.name().to_string())
+            .collect())
+    })
+    .await?
+}
+
+/// Acquire or release the advisory lock.
+fn lock(connection: &mut PgConnection, acquire: bool) -> anyhow::Result<()> {
+    let query = match acquire {
+        true => "SELECT pg_advisory_lock($1)",
+        false => "SELECT pg_advisory_unlock($1)",
+    };
+
+    diesel::sql_query(query)
+        .bind::<BigInt, _>(LOCK_KEY)
+        .execute(connection)
+        .context("Failed to acquire/release migration lock")?;
+
+    Ok(())
+}
+
+/// The database to migrate.
+struct Target {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    dbname: String,
+}
+
+impl From<&postgres::Config> for Target {
+    fn from(config: &postgres::Config) -> Self {
+        Self {
+            host: config.db.host.clone().unwrap_or_default(),
+            port: config.db.port.unwrap_or_default(),
+            user: config.db.user.clone().unwrap_or_default(),
+            password: config.db.password.clone().unwrap_or_default(),
+            dbname: config.db.dbname.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl Target {
+    fn connect(&self) -> anyhow::Result<PgConnection> {
+        let url = format!(
+            "postgres://{}:{}@{}:{}/{}",
+            self.user, self.password, self.host, self.port, self.dbname
+        );
+
+        // don't leak the password in the error message
+        PgConnection::establish(&url)
+            .with_context(|| format!("Error connecting to {}:{}", self.host, self.port))
+    }
+}
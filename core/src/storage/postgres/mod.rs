@@ -1,11 +1,23 @@
+pub mod cold;
+pub mod migration;
+mod scripts;
 mod utils;
 
 use crate::{
+    applications::Applications,
+    config::check::{Check, Checker},
     model::{
-        DesiredFeature, Internal, Metadata, Reconciliation, ReportedFeature, Schema,
-        SyntheticFeature, Thing,
+        AliasOf, Condition, DesiredFeature, Internal, Metadata, Reconciliation, ReportedFeature,
+        Schema, SyntheticFeature, Thing, WakerExt, WakerReason,
+    },
+    processor::Event,
+    storage::{
+        self,
+        postgres::cold::ColdStore,
+        query::{Query, Section},
+        selector::{LabelSelector, Requirement},
+        ListOptions, Stats,
     },
-    storage::{self},
     Preconditions,
 };
 use async_trait::async_trait;
@@ -13,7 +25,8 @@ use chrono::{DateTime, Utc};
 use deadpool_postgres::{Object, PoolError};
 use drogue_bazaar::db::postgres;
 use postgres_types::Type;
-use std::collections::BTreeMap;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use tokio_postgres::{
     error::SqlState,
     types::{Json, ToSql},
@@ -24,9 +37,31 @@ use uuid::Uuid;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Config {
-    pub application: Option<String>,
+    /// Limit access to things of these applications, see [`Applications`].
+    #[serde(default, alias = "application")]
+    pub applications: Applications,
     #[serde(flatten)]
     pub postgres: postgres::Config,
+    /// Offload rarely updated things to a cold storage, see [`cold`].
+    #[serde(default)]
+    pub cold: Option<cold::Config>,
+    /// A read replica, serving read-only operations.
+    #[serde(default)]
+    pub replica: Option<postgres::Config>,
+    /// Write outbox events to the `outbox` table, in the same statement as the thing, instead of
+    /// keeping them in the thing. The events get published by the relay, see
+    /// [`crate::outbox`].
+    #[serde(default)]
+    pub outbox: bool,
+}
+
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        self.postgres.check(checker);
+        checker
+            .field("cold", &self.cold)
+            .field("replica", &self.replica);
+    }
 }
 
 pub struct ThingEntity {
@@ -39,7 +74,8 @@ pub struct ThingEntity {
     pub labels: BTreeMap<String, String>,
     pub annotations: BTreeMap<String, String>,
 
-    pub data: Data,
+    /// The persisted data, with unresolved script references
+    pub data: Value,
 
     pub waker: Option<DateTime<Utc>>,
 }
@@ -49,6 +85,8 @@ pub struct ThingEntity {
 pub struct Data {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub schema: Option<Schema>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<AliasOf>,
 
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub reported_state: BTreeMap<String, ReportedFeature>,
@@ -60,6 +98,9 @@ pub struct Data {
     #[serde(default, skip_serializing_if = "Reconciliation::is_empty")]
     pub reconciliation: Reconciliation,
 
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conditions: BTreeMap<String, Condition>,
+
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub internal: Option<Internal>,
 }
@@ -68,10 +109,12 @@ impl From<&Thing<Internal>> for Data {
     fn from(value: &Thing<Internal>) -> Self {
         Self {
             schema: value.schema.clone(),
+            alias_of: value.alias_of.clone(),
             reported_state: value.reported_state.clone(),
             desired_state: value.desired_state.clone(),
             synthetic_state: value.synthetic_state.clone(),
             reconciliation: value.reconciliation.clone(),
+            conditions: value.conditions.clone(),
             internal: value.internal.clone(),
         }
     }
@@ -98,8 +141,12 @@ impl TryFrom<Row> for ThingEntity {
 }
 
 pub struct Storage {
-    application: Option<String>,
+    applications: Applications,
     pool: deadpool_postgres::Pool,
+    /// The pool of the read replica, if configured.
+    replica: Option<deadpool_postgres::Pool>,
+    cold: Option<ColdStore>,
+    outbox: bool,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -110,6 +157,8 @@ pub enum Error {
     Pool(#[from] PoolError),
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
+    #[error("Cold storage error: {0}")]
+    Cold(#[from] cold::Error),
     #[error("{0}")]
     Generic(String),
 }
@@ -129,8 +178,24 @@ impl super::Storage for Storage {
 
     fn from_config(config: &Self::Config) -> anyhow::Result<Self> {
         let pool = config.postgres.create_pool()?;
-        let application = config.application.clone();
-        Ok(Self { application, pool })
+        let applications = config.applications.clone();
+        let cold = config
+            .cold
+            .as_ref()
+            .map(ColdStore::from_config)
+            .transpose()?;
+        let replica = config
+            .replica
+            .as_ref()
+            .map(|replica| replica.create_pool())
+            .transpose()?;
+        Ok(Self {
+            applications,
+            pool,
+            replica,
+            cold,
+            outbox: config.outbox,
+        })
     }
 
     #[instrument(skip(self), err)]
@@ -141,67 +206,53 @@ impl super::Storage for Storage {
             return Ok(None);
         }
 
+        if self.replica.is_some() {
+            let con = self.read_connection().await?;
+            if let Some(thing) = self.fetch(&con, application, name).await? {
+                return Ok(Some(thing));
+            }
+            // fall back to the primary, the thing might not be replicated yet
+        }
+
         let con = self.connection().await?;
+        if let Some(thing) = self.fetch(&con, application, name).await? {
+            return Ok(Some(thing));
+        }
 
-        let stmt = con
-            .prepare_typed_cached(
-                r#"
-SELECT
-    UID,
-    CREATION_TIMESTAMP,
-    DELETION_TIMESTAMP,
-    GENERATION,
-    RESOURCE_VERSION,
-    ANNOTATIONS,
-    LABELS,
-    DATA,
-    WAKER
-FROM
-    THINGS
-WHERE
-        NAME = $1
-    AND
-        APPLICATION = $2 
-"#,
-                &[
-                    Type::VARCHAR, // name
-                    Type::VARCHAR, // application
-                ],
-            )
-            .await
-            .map_err(Error::Postgres)?;
+        // not found, try the cold storage
+        if self.rehydrate(application, name).await? {
+            if let Some(thing) = self.fetch(&con, application, name).await? {
+                return Ok(Some(thing));
+            }
+        }
 
-        tracing::info!("Prepared statement");
+        Err(storage::Error::NotFound)
+    }
 
-        match con
-            .query_opt(&stmt, &[&name, &application])
-            .await
-            .map_err(Error::Postgres)?
-        {
-            Some(row) => {
-                let entity: ThingEntity = row.try_into()?;
-                Ok(Some(Thing {
-                    metadata: Metadata {
-                        name: name.to_string(),
-                        application: application.to_string(),
-                        uid: Some(entity.uid.to_string()),
-                        creation_timestamp: Some(entity.creation_timestamp),
-                        deletion_timestamp: entity.deletion_timestamp,
-                        resource_version: Some(entity.resource_version.to_string()),
-
-                        generation: Some(entity.generation),
-                        annotations: entity.annotations,
-                        labels: entity.labels,
-                    },
-                    schema: entity.data.schema,
-                    reported_state: entity.data.reported_state,
-                    desired_state: entity.data.desired_state,
-                    synthetic_state: entity.data.synthetic_state,
-                    reconciliation: entity.data.reconciliation,
-                    internal: entity.data.internal,
-                }))
-            }
-            None => Err(storage::Error::NotFound),
+    #[instrument(skip(self), err)]
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>> {
+        self.list_matching(application, opts, None).await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn query(
+        &self,
+        application: &str,
+        query: &Query,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>> {
+        if query.is_empty() {
+            return self.list_with(application, opts).await;
+        }
+
+        // offloaded things can only be filtered after loading them
+        match (&self.cold, json_path(query)) {
+            (None, Some(path)) => self.list_matching(application, opts, Some(path)).await,
+            _ => storage::query_listed(self, application, query, opts).await,
         }
     }
 
@@ -209,34 +260,157 @@ WHERE
         name = thing.metadata.name,
         application = thing.metadata.application
     ), err)]
-    async fn create(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
-        self.ensure_app(&thing.metadata.application, || storage::Error::NotAllowed)?;
+    async fn create(&self, thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        self.insert(thing, false).await
+    }
+
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn import(&self, thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        self.insert(thing, true).await
+    }
+
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn update(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
+        self.ensure_app(&thing.metadata.application, || storage::Error::NotFound)?;
 
+        let outbox = self.take_outbox(&mut thing);
         let con = self.connection().await?;
+        let data = self.persist_data(&con, &thing).await?;
 
-        // Init metadata. We need to set this on the thing too, as we return it.
-        let uid = Uuid::new_v4();
-        let resource_version = Uuid::new_v4();
-        let generation = 1i64;
-        let creation_timestamp = Utc::now();
-        thing.metadata.uid = Some(uid.to_string());
-        thing.metadata.creation_timestamp = Some(creation_timestamp);
-        thing.metadata.generation = Some(generation as u32);
-        thing.metadata.resource_version = Some(resource_version.to_string());
+        self.write_update(&con, thing, DataChange::Replace(Json(data)), outbox)
+            .await
+    }
 
-        let waker = waker_data(&thing);
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err)]
+    async fn update_from(
+        &self,
+        current: &Thing<Internal>,
+        mut thing: Thing<Internal>,
+    ) -> Result<Thing<Internal>> {
+        self.ensure_app(&thing.metadata.application, || storage::Error::NotFound)?;
 
-        log::debug!(
-            "Creating new thing: {} / {}",
-            thing.metadata.application,
-            thing.metadata.name
-        );
+        let outbox = self.take_outbox(&mut thing);
+        let con = self.connection().await?;
+        let data = self.persist_data(&con, &thing).await?;
+
+        // only patch what we know is stored, which is ensured by the resource version
+        let change = match (
+            &current.metadata.resource_version,
+            &thing.metadata.resource_version,
+        ) {
+            (Some(current_version), Some(version)) if current_version == version => {
+                let mut current = serde_json::to_value(Data::from(current)).map_err(Error::from)?;
+                scripts::extract(&mut current);
+                DataChange::diff(current, data)
+            }
+            _ => DataChange::Replace(Json(data)),
+        };
 
-        let data: Data = (&thing).into();
+        self.write_update(&con, thing, change, outbox).await
+    }
 
-        let stmt = con
-            .prepare_typed_cached(
-                r#"
+    #[instrument(skip_all, fields(things = things.len()), err)]
+    async fn create_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<Vec<Result<Thing<Internal>>>> {
+        if self.cold.is_some() || self.outbox {
+            // offloaded things must be checked one by one, outbox events written with each thing
+            let mut result = Vec::with_capacity(things.len());
+            for thing in things {
+                result.push(self.insert(thing, false).await);
+            }
+            return Ok(result);
+        }
+
+        let con = self.connection().await?;
+
+        let mut result = Vec::with_capacity(things.len());
+        let mut data = Vec::with_capacity(things.len());
+        let mut seen = HashSet::new();
+
+        for mut thing in things {
+            if let Err(err) =
+                self.ensure_app(&thing.metadata.application, || storage::Error::NotAllowed)
+            {
+                result.push(Err(err));
+                data.push(None);
+                continue;
+            }
+            if !seen.insert((
+                thing.metadata.application.clone(),
+                thing.metadata.name.clone(),
+            )) {
+                result.push(Err(storage::Error::AlreadyExists));
+                data.push(None);
+                continue;
+            }
+
+            thing.metadata.uid = Some(Uuid::new_v4().to_string());
+            thing.metadata.creation_timestamp = Some(Utc::now());
+            thing.metadata.deletion_timestamp = None;
+            thing.metadata.generation = Some(1);
+            thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+
+            data.push(Some(self.persist_data(&con, &thing).await?));
+            result.push(Ok(thing));
+        }
+
+        log::debug!("Creating {} new things", seen.len());
+
+        let created = {
+            let mut names = Vec::new();
+            let mut applications = Vec::new();
+            let mut uids = Vec::new();
+            let mut creation_timestamps = Vec::new();
+            let mut resource_versions = Vec::new();
+            let mut annotations = Vec::new();
+            let mut labels = Vec::new();
+            let mut datas = Vec::new();
+            let mut wakers = Vec::new();
+
+            for (thing, data) in result.iter().zip(&data) {
+                if let (Ok(thing), Some(data)) = (thing, data) {
+                    names.push(thing.metadata.name.as_str());
+                    applications.push(thing.metadata.application.as_str());
+                    uids.push(
+                        thing
+                            .metadata
+                            .uid
+                            .as_deref()
+                            .and_then(|uid| Uuid::parse_str(uid).ok()),
+                    );
+                    creation_timestamps.push(thing.metadata.creation_timestamp);
+                    resource_versions.push(
+                        thing
+                            .metadata
+                            .resource_version
+                            .as_deref()
+                            .and_then(|resource_version| Uuid::parse_str(resource_version).ok()),
+                    );
+                    annotations.push(Json(&thing.metadata.annotations));
+                    labels.push(Json(&thing.metadata.labels));
+                    datas.push(Json(data));
+                    wakers.push(waker_data(thing));
+                }
+            }
+
+            if names.is_empty() {
+                return Ok(result);
+            }
+
+            let stmt = con
+                .prepare_typed_cached(
+                    r#"
 INSERT INTO things (
     NAME,
     APPLICATION,
@@ -248,165 +422,254 @@ INSERT INTO things (
     LABELS,
     DATA,
     WAKER
-) VALUES (
-    $1,
-    $2,
-    $3,
-    $4,
-    $5,
-    $6,
-    $7,
-    $8,
-    $9,
-    $10
 )
+SELECT
+    NAME, APPLICATION, UID, CREATION_TIMESTAMP, 1, RESOURCE_VERSION, ANNOTATIONS, LABELS, DATA, WAKER
+FROM
+    UNNEST($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    AS T(NAME, APPLICATION, UID, CREATION_TIMESTAMP, RESOURCE_VERSION, ANNOTATIONS, LABELS, DATA, WAKER)
+ON CONFLICT DO NOTHING
+RETURNING
+    APPLICATION, NAME
 "#,
+                    &[
+                        Type::VARCHAR_ARRAY,     // names
+                        Type::VARCHAR_ARRAY,     // applications
+                        Type::UUID_ARRAY,        // uids
+                        Type::TIMESTAMPTZ_ARRAY, // creation timestamps
+                        Type::UUID_ARRAY,        // resource versions
+                        Type::JSON_ARRAY,        // annotations
+                        Type::JSONB_ARRAY,       // labels
+                        Type::JSON_ARRAY,        // data
+                        Type::TIMESTAMPTZ_ARRAY, // wakers
+                    ],
+                )
+                .await
+                .map_err(Error::Postgres)?;
+
+            con.query(
+                &stmt,
                 &[
-                    Type::VARCHAR,     // name
-                    Type::VARCHAR,     // application
-                    Type::UUID,        // uid
-                    Type::TIMESTAMPTZ, // creation timestamp
-                    Type::INT8,        // generation
-                    Type::UUID,        // resource version
-                    Type::JSON,        // annotations
-                    Type::JSONB,       // labels
-                    Type::JSON,        // data
-                    Type::TIMESTAMPTZ, // waker
+                    &names,
+                    &applications,
+                    &uids,
+                    &creation_timestamps,
+                    &resource_versions,
+                    &annotations,
+                    &labels,
+                    &datas,
+                    &wakers,
                 ],
             )
             .await
-            .map_err(Error::Postgres)?;
-
-        tracing::info!("Prepared statement");
-
-        con.execute(
-            &stmt,
-            &[
-                &thing.metadata.name,
-                &thing.metadata.application,
-                &uid,
-                &Utc::now(),
-                &generation,
-                &resource_version,
-                &Json(&thing.metadata.annotations),
-                &Json(&thing.metadata.labels),
-                &Json(data),
-                &waker,
-            ],
-        )
-        .await
-        .map_err(|err| match err.code() {
-            Some(&SqlState::UNIQUE_VIOLATION) => storage::Error::AlreadyExists,
-            _ => Error::Postgres(err).into(),
-        })?;
+            .map_err(Error::Postgres)?
+            .into_iter()
+            .map(|row| Ok((row.try_get("APPLICATION")?, row.try_get("NAME")?)))
+            .collect::<std::result::Result<HashSet<(String, String)>, tokio_postgres::Error>>()
+            .map_err(Error::Postgres)?
+        };
+
+        tracing::info!(created = created.len(), "Statement executed");
+
+        // things which were not inserted, already existed
+        for entry in &mut result {
+            let exists = match entry {
+                Ok(thing) => !created.contains(&(
+                    thing.metadata.application.clone(),
+                    thing.metadata.name.clone(),
+                )),
+                Err(_) => false,
+            };
+            if exists {
+                *entry = Err(storage::Error::AlreadyExists);
+            }
+        }
 
-        Ok(thing.clone())
+        Ok(result)
     }
 
-    #[instrument(skip_all, fields(
-        name = thing.metadata.name,
-        application = thing.metadata.application
-    ), err)]
-    async fn update(&self, mut thing: Thing<Internal>) -> Result<Thing<Internal>> {
-        self.ensure_app(&thing.metadata.application, || storage::Error::NotFound)?;
+    #[instrument(skip_all, fields(things = things.len()), err)]
+    async fn update_many(
+        &self,
+        things: Vec<Thing<Internal>>,
+    ) -> Result<Vec<Result<Thing<Internal>>>> {
+        if self.outbox {
+            // outbox events must be written with each thing
+            let mut result = Vec::with_capacity(things.len());
+            for thing in things {
+                result.push(self.update(thing).await);
+            }
+            return Ok(result);
+        }
 
         let con = self.connection().await?;
 
-        let name = &thing.metadata.name;
-        let application = &thing.metadata.application;
+        let mut result = Vec::with_capacity(things.len());
+        let mut data = Vec::with_capacity(things.len());
+        let mut seen = HashSet::new();
+
+        for mut thing in things {
+            if let Err(err) =
+                self.ensure_app(&thing.metadata.application, || storage::Error::NotFound)
+            {
+                result.push(Err(err));
+                data.push(None);
+                continue;
+            }
+            if !seen.insert((
+                thing.metadata.application.clone(),
+                thing.metadata.name.clone(),
+            )) {
+                // the outcome of updating the same thing twice, in a single statement, is undefined
+                result.push(Err(storage::Error::Generic(format!(
+                    "Duplicate thing in batch: {} / {}",
+                    thing.metadata.application, thing.metadata.name
+                ))));
+                data.push(None);
+                continue;
+            }
 
-        let waker = waker_data(&thing);
+            let expected = (
+                thing.metadata.resource_version.take(),
+                thing.metadata.uid.take(),
+            );
+            data.push(Some((self.persist_data(&con, &thing).await?, expected)));
+            thing.metadata.resource_version = Some(Uuid::new_v4().to_string());
+            result.push(Ok(thing));
+        }
 
-        log::debug!("Updating existing thing: {application} / {name}");
+        log::debug!("Updating {} existing things", seen.len());
+
+        let updated = {
+            let mut names = Vec::new();
+            let mut applications = Vec::new();
+            let mut resource_versions = Vec::new();
+            let mut annotations = Vec::new();
+            let mut labels = Vec::new();
+            let mut datas = Vec::new();
+            let mut wakers = Vec::new();
+            let mut expected_resource_versions = Vec::new();
+            let mut expected_uids = Vec::new();
+
+            for (thing, data) in result.iter().zip(&data) {
+                if let (Ok(thing), Some((data, (resource_version, uid)))) = (thing, data) {
+                    names.push(thing.metadata.name.as_str());
+                    applications.push(thing.metadata.application.as_str());
+                    resource_versions.push(
+                        thing
+                            .metadata
+                            .resource_version
+                            .as_deref()
+                            .and_then(|resource_version| Uuid::parse_str(resource_version).ok()),
+                    );
+                    annotations.push(Json(&thing.metadata.annotations));
+                    labels.push(Json(&thing.metadata.labels));
+                    datas.push(Json(data));
+                    wakers.push(waker_data(thing));
+                    expected_resource_versions.push(resource_version.as_deref());
+                    expected_uids.push(uid.as_deref());
+                }
+            }
 
-        let mut stmt = r#"
-UPDATE things
+            if names.is_empty() {
+                return Ok(result);
+            }
+
+            let stmt = con
+                .prepare_typed_cached(
+                    r#"
+UPDATE things AS T
 SET
-    GENERATION = GENERATION + 1,
-    RESOURCE_VERSION = $3,
-    ANNOTATIONS = $4,
-    LABELS = $5,
-    DATA = $6,
-    WAKER = $7
+    GENERATION = T.GENERATION + 1,
+    RESOURCE_VERSION = U.RESOURCE_VERSION,
+    ANNOTATIONS = U.ANNOTATIONS,
+    LABELS = U.LABELS,
+    DATA = U.DATA,
+    WAKER = U.WAKER
+FROM
+    UNNEST($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    AS U(NAME, APPLICATION, RESOURCE_VERSION, ANNOTATIONS, LABELS, DATA, WAKER, EXPECTED_RESOURCE_VERSION, EXPECTED_UID)
 WHERE
-        NAME = $1
+        T.NAME = U.NAME
     AND
-        APPLICATION = $2
-"#
-        .to_string();
-
-        let resource_version = Uuid::new_v4();
-        let data = Json(Data::from(&thing));
-        let annotations = Json(&thing.metadata.annotations);
-        let labels = Json(&thing.metadata.labels);
-
-        let mut types = Vec::new();
-        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
-        types.push(Type::VARCHAR);
-        params.push(name);
-        types.push(Type::VARCHAR);
-        params.push(application);
-        types.push(Type::UUID);
-        params.push(&resource_version);
-        types.push(Type::JSON);
-        params.push(&annotations);
-        types.push(Type::JSONB);
-        params.push(&labels);
-        types.push(Type::JSON);
-        params.push(&data);
-        types.push(Type::TIMESTAMPTZ);
-        params.push(&waker);
-
-        if let Some(resource_version) = &thing.metadata.resource_version {
-            stmt.push_str(&format!(
-                "    AND RESOURCE_VERSION::text=${}",
-                params.len() + 1
-            ));
-            types.push(Type::TEXT);
-            params.push(resource_version);
-        }
-        if let Some(uid) = &thing.metadata.uid {
-            stmt.push_str(&format!("    AND UID::text=${}", params.len() + 1));
-            types.push(Type::TEXT);
-            params.push(uid);
-        }
-
-        stmt.push_str(
-            r#"
+        T.APPLICATION = U.APPLICATION
+    AND
+        (U.EXPECTED_RESOURCE_VERSION IS NULL OR T.RESOURCE_VERSION::text = U.EXPECTED_RESOURCE_VERSION)
+    AND
+        (U.EXPECTED_UID IS NULL OR T.UID::text = U.EXPECTED_UID)
 RETURNING
-    CREATION_TIMESTAMP, GENERATION, UID::text
+    T.APPLICATION, T.NAME, T.CREATION_TIMESTAMP, T.GENERATION, T.UID::text
 "#,
-        );
-
-        let stmt = con
-            .prepare_typed_cached(&stmt, &types)
-            .await
-            .map_err(Error::Postgres)?;
-
-        tracing::info!("Prepared statement");
-
-        match con.query_opt(&stmt, &params).await {
-            Ok(None) => {
-                tracing::info!("Precondition failed");
-                Err(storage::Error::PreconditionFailed)
-            }
-            Ok(Some(row)) => {
-                tracing::info!("Row updated");
-                // update metadata, with new values
-
-                thing.metadata.uid = row.try_get("UID").map_err(Error::Postgres)?;
-                thing.metadata.creation_timestamp =
-                    row.try_get("CREATION_TIMESTAMP").map_err(Error::Postgres)?;
-                thing.metadata.generation = Some(
-                    row.try_get::<_, i64>("GENERATION")
-                        .map_err(Error::Postgres)? as u32,
+                    &[
+                        Type::VARCHAR_ARRAY,     // names
+                        Type::VARCHAR_ARRAY,     // applications
+                        Type::UUID_ARRAY,        // resource versions
+                        Type::JSON_ARRAY,        // annotations
+                        Type::JSONB_ARRAY,       // labels
+                        Type::JSON_ARRAY,        // data
+                        Type::TIMESTAMPTZ_ARRAY, // wakers
+                        Type::TEXT_ARRAY,        // expected resource versions
+                        Type::TEXT_ARRAY,        // expected uids
+                    ],
+                )
+                .await
+                .map_err(Error::Postgres)?;
+
+            let mut updated = HashMap::new();
+            for row in con
+                .query(
+                    &stmt,
+                    &[
+                        &names,
+                        &applications,
+                        &resource_versions,
+                        &annotations,
+                        &labels,
+                        &datas,
+                        &wakers,
+                        &expected_resource_versions,
+                        &expected_uids,
+                    ],
+                )
+                .await
+                .map_err(Error::Postgres)?
+            {
+                let key: (String, String) = (
+                    row.try_get("APPLICATION").map_err(Error::Postgres)?,
+                    row.try_get("NAME").map_err(Error::Postgres)?,
                 );
-                thing.metadata.resource_version = Some(resource_version.to_string());
-
-                Ok(thing)
+                let value: (DateTime<Utc>, i64, String) = (
+                    row.try_get("CREATION_TIMESTAMP").map_err(Error::Postgres)?,
+                    row.try_get("GENERATION").map_err(Error::Postgres)?,
+                    row.try_get("UID").map_err(Error::Postgres)?,
+                );
+                updated.insert(key, value);
+            }
+            updated
+        };
+
+        tracing::info!(updated = updated.len(), "Statement executed");
+
+        // update metadata, with new values
+        for entry in &mut result {
+            let thing = match entry {
+                Ok(thing) => thing,
+                Err(_) => continue,
+            };
+            match updated.get(&(
+                thing.metadata.application.clone(),
+                thing.metadata.name.clone(),
+            )) {
+                Some((creation_timestamp, generation, uid)) => {
+                    thing.metadata.creation_timestamp = Some(*creation_timestamp);
+                    thing.metadata.generation = Some(*generation as u32);
+                    thing.metadata.uid = Some(uid.clone());
+                }
+                None => *entry = Err(storage::Error::PreconditionFailed),
             }
-            Err(err) => Err(Error::Postgres(err).into()),
         }
+
+        Ok(result)
     }
 
     #[instrument(skip(self), err, ret)]
@@ -422,6 +685,9 @@ RETURNING
             return Ok(false);
         }
 
+        // bring back offloaded things, so that the preconditions can be checked
+        self.rehydrate(application, name).await?;
+
         let con = self.connection().await?;
 
         log::debug!("Deleting thing: {application} / {name}");
@@ -469,28 +735,1364 @@ WHERE
 
         Ok(rows > 0)
     }
-}
 
-impl Storage {
-    fn ensure_app<F, E>(&self, application: &str, f: F) -> Result<()>
-    where
-        F: FnOnce() -> E,
-        E: Into<storage::Error<Error>>,
-    {
-        if let Some(expected_application) = &self.application {
-            if expected_application != application {
-                return Err(f().into());
+    #[instrument(skip(self), err)]
+    async fn get_revision(
+        &self,
+        application: &str,
+        name: &str,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>> {
+        let current = match self.get(application, name).await {
+            Ok(current) => current,
+            Err(storage::Error::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        if let Some(current) = current {
+            if current.metadata.generation == Some(generation) {
+                return Ok(Some(current));
             }
         }
-        Ok(())
-    }
 
-    #[instrument(skip_all, err)]
-    async fn connection(&self) -> std::result::Result<Object, Error> {
-        self.pool.get().await.map_err(Error::Pool)
+        self.fetch_revision(application, name, generation).await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn stats(&self, application: &str) -> Result<Stats> {
+        if let Err(storage::Error::NotFound) =
+            self.ensure_app(application, || storage::Error::NotFound)
+        {
+            return Ok(Stats::default());
+        }
+
+        // offloaded things are not part of the things table
+        if self.cold.is_some() {
+            return self.stats_listed(application).await;
+        }
+
+        let con = self.read_connection().await?;
+
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    COUNT(*) AS THINGS,
+    COUNT(*) FILTER (
+        WHERE JSONB_PATH_EXISTS(DATA::JSONB, '$.desired_state.*.reconciliation ? (@.state == "failed")')
+    ) AS FAILED,
+    COUNT(*) FILTER (
+        WHERE JSONB_ARRAY_LENGTH(COALESCE(DATA::JSONB -> 'internal' -> 'outbox', '[]'::JSONB)) > 0
+        OR EXISTS (
+            SELECT 1 FROM OUTBOX O
+            WHERE O.APPLICATION = THINGS.APPLICATION AND O.NAME = THINGS.NAME
+        )
+    ) AS PENDING
+FROM
+    THINGS
+WHERE
+    APPLICATION = $1
+"#,
+                &[Type::VARCHAR],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        let row = con
+            .query_one(&stmt, &[&application])
+            .await
+            .map_err(Error::Postgres)?;
+
+        let count = |name: &str| -> Result<u64> {
+            let count: i64 = row.try_get(name).map_err(Error::Postgres)?;
+            Ok(count as u64)
+        };
+
+        let mut stats = Stats {
+            things: count("THINGS")?,
+            failed_desired_states: count("FAILED")?,
+            pending_outbox: count("PENDING")?,
+            ..Default::default()
+        };
+
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    L.KEY,
+    L.VALUE,
+    COUNT(*) AS COUNT
+FROM
+    THINGS,
+    JSONB_EACH_TEXT(LABELS) AS L
+WHERE
+    APPLICATION = $1
+GROUP BY
+    L.KEY, L.VALUE
+"#,
+                &[Type::VARCHAR],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        for row in con
+            .query(&stmt, &[&application])
+            .await
+            .map_err(Error::Postgres)?
+        {
+            let key: String = row.try_get("KEY").map_err(Error::Postgres)?;
+            let value: String = row.try_get("VALUE").map_err(Error::Postgres)?;
+            let count: i64 = row.try_get("COUNT").map_err(Error::Postgres)?;
+            stats
+                .labels
+                .entry(key)
+                .or_default()
+                .insert(value, count as u64);
+        }
+
+        Ok(stats)
+    }
+
+    #[instrument(skip(self), err)]
+    async fn pending_events(&self, application: &str, name: &str) -> Result<Vec<Event>> {
+        if !self.outbox {
+            return Ok(vec![]);
+        }
+
+        let con = self.read_connection().await?;
+
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    EVENT
+FROM
+    OUTBOX
+WHERE
+        APPLICATION = $1
+    AND
+        NAME = $2
+ORDER BY
+    ID
+"#,
+                &[Type::VARCHAR, Type::VARCHAR],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        con.query(&stmt, &[&application, &name])
+            .await
+            .map_err(Error::Postgres)?
+            .into_iter()
+            .map(|row| {
+                let Json(event): Json<Event> = row.try_get("EVENT").map_err(Error::Postgres)?;
+                Ok(event)
+            })
+            .collect()
+    }
+}
+
+impl Storage {
+    /// Aggregate the statistics by listing all things, including those with events in the
+    /// `outbox` table as pending.
+    async fn stats_listed(&self, application: &str) -> Result<Stats> {
+        let pending = match self.outbox {
+            true => self.outbox_names(application).await?,
+            false => Default::default(),
+        };
+
+        let mut stats = Stats::default();
+        for thing in super::Storage::list(self, application).await? {
+            stats.add(&thing);
+            let counted = thing
+                .internal
+                .as_ref()
+                .map_or(false, |internal| !internal.outbox.is_empty());
+            if !counted && pending.contains(&thing.metadata.name) {
+                stats.pending_outbox += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// The names of the things of an application, with events in the `outbox` table.
+    async fn outbox_names(&self, application: &str) -> Result<BTreeSet<String>> {
+        let con = self.read_connection().await?;
+
+        let stmt = con
+            .prepare_typed_cached(
+                "SELECT DISTINCT NAME FROM OUTBOX WHERE APPLICATION = $1",
+                &[Type::VARCHAR],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        con.query(&stmt, &[&application])
+            .await
+            .map_err(Error::Postgres)?
+            .into_iter()
+            .map(|row| {
+                row.try_get("NAME")
+                    .map_err(|err| Error::Postgres(err).into())
+            })
+            .collect()
+    }
+
+    /// List things, optionally only those matching an SQL/JSON path predicate on their data.
+    async fn list_matching(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+        json_path: Option<String>,
+    ) -> Result<Vec<Thing<Internal>>> {
+        if let Err(storage::Error::NotFound) =
+            self.ensure_app(application, || storage::Error::NotFound)
+        {
+            return Ok(vec![]);
+        }
+
+        let con = self.read_connection().await?;
+
+        let mut types = vec![
+            Type::VARCHAR, // application
+            Type::VARCHAR, // after
+            Type::INT8,    // limit
+        ];
+        let (and_selector, mut selector_params) = selector_clause(&opts.selector, types.len() + 1);
+        let and_query = match json_path {
+            Some(path) => {
+                selector_params.push(SelectorParam::Text(path));
+                format!(
+                    "    AND\n        (DATA::JSONB) @@ ${}::JSONPATH\n",
+                    types.len() + selector_params.len()
+                )
+            }
+            None => String::new(),
+        };
+        types.extend(selector_params.iter().map(SelectorParam::r#type));
+
+        let stmt = con
+            .prepare_typed_cached(
+                &format!(
+                    r#"
+SELECT
+    NAME,
+    UID,
+    CREATION_TIMESTAMP,
+    DELETION_TIMESTAMP,
+    GENERATION,
+    RESOURCE_VERSION,
+    ANNOTATIONS,
+    LABELS,
+    DATA,
+    WAKER
+FROM
+    THINGS
+WHERE
+        APPLICATION = $1
+    AND
+        ($2::VARCHAR IS NULL OR NAME > $2)
+{and_selector}{and_query}
+ORDER BY
+    NAME ASC
+LIMIT $3
+"#
+                ),
+                &types,
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        let limit = opts.limit.map(i64::from);
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&application, &opts.after, &limit];
+        params.extend(selector_params.iter().map(SelectorParam::value));
+
+        let rows = con.query(&stmt, &params).await.map_err(Error::Postgres)?;
+
+        let mut entities = Vec::with_capacity(rows.len());
+        for row in rows {
+            let name: String = row.try_get("NAME").map_err(Error::Postgres)?;
+            let entity: ThingEntity = row.try_into()?;
+            entities.push((name, entity));
+        }
+
+        // resolve the scripts of the whole page at once
+        scripts::load_all(
+            &con,
+            entities.iter_mut().map(|(_, entity)| &mut entity.data),
+        )
+        .await?;
+        let mut result = entities
+            .into_iter()
+            .map(|(name, entity)| Self::to_thing(application, &name, entity))
+            .collect::<Result<Vec<_>>>()?;
+
+        if self.cold.is_some() {
+            let offloaded = self
+                .list_offloaded(&con, application, opts, &result)
+                .await?;
+            result.extend(offloaded);
+            result.sort_unstable_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+            if let Some(limit) = opts.limit {
+                result.truncate(limit as usize);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Fetch a thing from the `things` table.
+    async fn fetch(
+        &self,
+        con: &Object,
+        application: &str,
+        name: &str,
+    ) -> Result<Option<Thing<Internal>>> {
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    UID,
+    CREATION_TIMESTAMP,
+    DELETION_TIMESTAMP,
+    GENERATION,
+    RESOURCE_VERSION,
+    ANNOTATIONS,
+    LABELS,
+    DATA,
+    WAKER
+FROM
+    THINGS
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2 
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        tracing::info!("Prepared statement");
+
+        match con
+            .query_opt(&stmt, &[&name, &application])
+            .await
+            .map_err(Error::Postgres)?
+        {
+            Some(row) => {
+                let entity: ThingEntity = row.try_into()?;
+                Ok(Some(Self::load(con, application, name, entity).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Update a thing in the `things` table, applying the change of its data.
+    async fn write_update(
+        &self,
+        con: &Object,
+        mut thing: Thing<Internal>,
+        change: DataChange,
+        outbox: Option<Json<Vec<Event>>>,
+    ) -> Result<Thing<Internal>> {
+        let name = &thing.metadata.name;
+        let application = &thing.metadata.application;
+
+        let waker = waker_data(&thing);
+
+        log::debug!("Updating existing thing: {application} / {name}");
+
+        let resource_version = Uuid::new_v4();
+        let annotations = Json(&thing.metadata.annotations);
+        let labels = Json(&thing.metadata.labels);
+
+        let mut types = Vec::new();
+        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        types.push(Type::VARCHAR);
+        params.push(name);
+        types.push(Type::VARCHAR);
+        params.push(application);
+        types.push(Type::UUID);
+        params.push(&resource_version);
+        types.push(Type::JSON);
+        params.push(&annotations);
+        types.push(Type::JSONB);
+        params.push(&labels);
+        types.push(Type::TIMESTAMPTZ);
+        params.push(&waker);
+
+        let data_assignment = match &change {
+            DataChange::Replace(data) => {
+                types.push(Type::JSON);
+                params.push(data);
+                format!(",\n    DATA = ${}", params.len())
+            }
+            DataChange::Patch { changed, removed }
+                if changed.0.is_empty() && removed.is_empty() =>
+            {
+                // keep the stored data, unchanged
+                String::new()
+            }
+            DataChange::Patch { changed, removed } => {
+                types.push(Type::TEXT_ARRAY);
+                params.push(removed);
+                types.push(Type::JSONB);
+                params.push(changed);
+                format!(
+                    ",\n    DATA = ((DATA::jsonb - ${}) || ${})::json",
+                    params.len() - 1,
+                    params.len()
+                )
+            }
+        };
+
+        let mut stmt = format!(
+            r#"
+UPDATE things
+SET
+    GENERATION = GENERATION + 1,
+    RESOURCE_VERSION = $3,
+    ANNOTATIONS = $4,
+    LABELS = $5,
+    WAKER = $6{data_assignment}
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2
+"#
+        );
+
+        if let Some(resource_version) = &thing.metadata.resource_version {
+            stmt.push_str(&format!(
+                "    AND RESOURCE_VERSION::text=${}",
+                params.len() + 1
+            ));
+            types.push(Type::TEXT);
+            params.push(resource_version);
+        }
+        if let Some(uid) = &thing.metadata.uid {
+            stmt.push_str(&format!("    AND UID::text=${}", params.len() + 1));
+            types.push(Type::TEXT);
+            params.push(uid);
+        }
+
+        stmt.push_str(
+            r#"
+RETURNING
+    CREATION_TIMESTAMP, GENERATION, UID::text, APPLICATION, NAME
+"#,
+        );
+
+        if let Some(outbox) = &outbox {
+            types.push(Type::JSONB);
+            params.push(outbox);
+            stmt = format!(
+                r#"
+WITH updated AS ({stmt}), outbox AS ({})
+SELECT CREATION_TIMESTAMP, GENERATION, UID FROM updated
+"#,
+                insert_outbox("updated", params.len())
+            );
+        }
+
+        let stmt = con
+            .prepare_typed_cached(&stmt, &types)
+            .await
+            .map_err(Error::Postgres)?;
+
+        tracing::info!("Prepared statement");
+
+        match con.query_opt(&stmt, &params).await {
+            Ok(None) => {
+                tracing::info!("Precondition failed");
+                Err(storage::Error::PreconditionFailed)
+            }
+            Ok(Some(row)) => {
+                tracing::info!("Row updated");
+                // update metadata, with new values
+
+                thing.metadata.uid = row.try_get("UID").map_err(Error::Postgres)?;
+                thing.metadata.creation_timestamp =
+                    row.try_get("CREATION_TIMESTAMP").map_err(Error::Postgres)?;
+                thing.metadata.generation = Some(
+                    row.try_get::<_, i64>("GENERATION")
+                        .map_err(Error::Postgres)? as u32,
+                );
+                thing.metadata.resource_version = Some(resource_version.to_string());
+
+                Ok(thing)
+            }
+            Err(err) => Err(Error::Postgres(err).into()),
+        }
+    }
+
+    /// Fetch a previous generation of a thing from the `things_history` table.
+    ///
+    /// If the thing was deleted and re-created, the most recently archived one is returned.
+    async fn fetch_revision(
+        &self,
+        application: &str,
+        name: &str,
+        generation: u32,
+    ) -> Result<Option<Thing<Internal>>> {
+        let con = self.read_connection().await?;
+
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    UID,
+    CREATION_TIMESTAMP,
+    DELETION_TIMESTAMP,
+    GENERATION,
+    RESOURCE_VERSION,
+    ANNOTATIONS,
+    LABELS,
+    DATA,
+    NULL::TIMESTAMPTZ AS WAKER
+FROM
+    THINGS_HISTORY
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2
+    AND
+        GENERATION = $3
+ORDER BY
+    ARCHIVE_TIMESTAMP DESC
+LIMIT 1
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                    Type::INT8,    // generation
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        match con
+            .query_opt(&stmt, &[&name, &application, &(generation as i64)])
+            .await
+            .map_err(Error::Postgres)?
+        {
+            Some(row) => {
+                let entity: ThingEntity = row.try_into()?;
+                Ok(Some(Self::load(&con, application, name, entity).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Insert a new thing, optionally preserving the metadata managed by the storage.
+    async fn insert(&self, mut thing: Thing<Internal>, preserve: bool) -> Result<Thing<Internal>> {
+        self.ensure_app(&thing.metadata.application, || storage::Error::NotAllowed)?;
+
+        let outbox = self.take_outbox(&mut thing);
+        let con = self.connection().await?;
+
+        if self.cold.is_some()
+            && self
+                .offloaded_object(&con, &thing.metadata.application, &thing.metadata.name)
+                .await?
+                .is_some()
+        {
+            return Err(storage::Error::AlreadyExists);
+        }
+
+        // Init metadata. We need to set this on the thing too, as we return it.
+        let existing = |value: &Option<String>| {
+            value
+                .as_deref()
+                .filter(|_| preserve)
+                .and_then(|value| Uuid::parse_str(value).ok())
+        };
+        let uid = existing(&thing.metadata.uid).unwrap_or_else(Uuid::new_v4);
+        let resource_version =
+            existing(&thing.metadata.resource_version).unwrap_or_else(Uuid::new_v4);
+        let generation = thing.metadata.generation.filter(|_| preserve).unwrap_or(1);
+        let creation_timestamp = thing
+            .metadata
+            .creation_timestamp
+            .filter(|_| preserve)
+            .unwrap_or_else(Utc::now);
+        let deletion_timestamp = thing.metadata.deletion_timestamp.filter(|_| preserve);
+        thing.metadata.uid = Some(uid.to_string());
+        thing.metadata.creation_timestamp = Some(creation_timestamp);
+        thing.metadata.deletion_timestamp = deletion_timestamp;
+        thing.metadata.generation = Some(generation);
+        thing.metadata.resource_version = Some(resource_version.to_string());
+
+        log::debug!(
+            "Creating new thing: {} / {}",
+            thing.metadata.application,
+            thing.metadata.name
+        );
+
+        let data = self.persist_data(&con, &thing).await?;
+        let row = NewRow::new(&thing, uid, resource_version, data);
+
+        let mut stmt = INSERT_THING.to_string();
+        let mut types = INSERT_THING_TYPES.to_vec();
+        let mut params = row.params().to_vec();
+        if let Some(outbox) = &outbox {
+            types.push(Type::JSONB);
+            params.push(outbox);
+            stmt = format!(
+                r#"
+WITH inserted AS ({INSERT_THING}RETURNING APPLICATION, NAME, UID)
+{}
+"#,
+                insert_outbox("inserted", params.len())
+            );
+        }
+
+        let stmt = con
+            .prepare_typed_cached(&stmt, &types)
+            .await
+            .map_err(Error::Postgres)?;
+
+        tracing::info!("Prepared statement");
+
+        con.execute(&stmt, &params)
+            .await
+            .map_err(|err| match err.code() {
+                Some(&SqlState::UNIQUE_VIOLATION) => storage::Error::AlreadyExists,
+                _ => Error::Postgres(err).into(),
+            })?;
+
+        Ok(thing)
+    }
+
+    /// Check if the storage offloads things to a cold storage.
+    pub fn is_tiered(&self) -> bool {
+        self.cold.is_some()
+    }
+
+    /// Get the object of an offloaded thing, `None` if the thing isn't offloaded.
+    async fn offloaded_object(
+        &self,
+        con: &Object,
+        application: &str,
+        name: &str,
+    ) -> Result<Option<Uuid>> {
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    OBJECT
+FROM
+    cold_things
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        Ok(con
+            .query_opt(&stmt, &[&name, &application])
+            .await
+            .map_err(Error::Postgres)?
+            .map(|row| row.try_get("OBJECT"))
+            .transpose()
+            .map_err(Error::Postgres)?)
+    }
+
+    /// List the offloaded things of an application, skipping the ones which are already listed.
+    async fn list_offloaded(
+        &self,
+        con: &Object,
+        application: &str,
+        opts: &ListOptions,
+        listed: &[Thing<Internal>],
+    ) -> Result<Vec<Thing<Internal>>> {
+        let cold = match &self.cold {
+            Some(cold) => cold,
+            None => return Ok(vec![]),
+        };
+
+        let stmt = con
+            .prepare_typed_cached(
+                r#"
+SELECT
+    NAME,
+    OBJECT
+FROM
+    cold_things
+WHERE
+        APPLICATION = $1
+    AND
+        ($2::VARCHAR IS NULL OR NAME > $2)
+ORDER BY
+    NAME ASC
+LIMIT $3
+"#,
+                &[
+                    Type::VARCHAR, // application
+                    Type::VARCHAR, // after
+                    Type::INT8,    // limit
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        // labels of offloaded things are only known after loading them
+        let limit = match opts.selector.is_empty() {
+            true => opts.limit.map(i64::from),
+            false => None,
+        };
+
+        let listed: BTreeSet<_> = listed.iter().map(|thing| &thing.metadata.name).collect();
+
+        let mut result = Vec::new();
+        for row in con
+            .query(&stmt, &[&application, &opts.after, &limit])
+            .await
+            .map_err(Error::Postgres)?
+        {
+            let name: String = row.try_get("NAME").map_err(Error::Postgres)?;
+            if listed.contains(&name) {
+                continue;
+            }
+
+            let object: Uuid = row.try_get("OBJECT").map_err(Error::Postgres)?;
+            let key = cold::key(application, &name, &object);
+            match cold.get(&key).await.map_err(Error::from)? {
+                Some(thing) if opts.selector.matches(&thing.metadata.labels) => result.push(thing),
+                Some(_) => {}
+                None => log::warn!("Missing object of offloaded thing: {key}"),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Rehydrate an offloaded thing into the `things` table.
+    ///
+    /// Returns `true` if the thing was offloaded, and is now available in the `things` table.
+    #[instrument(skip(self), err, ret)]
+    async fn rehydrate(&self, application: &str, name: &str) -> Result<bool> {
+        let cold = match &self.cold {
+            Some(cold) => cold,
+            None => return Ok(false),
+        };
+
+        let mut con = self.connection().await?;
+
+        let object = match self.offloaded_object(&con, application, name).await? {
+            Some(object) => object,
+            None => return Ok(false),
+        };
+
+        let key = cold::key(application, name, &object);
+        let thing =
+            cold.get(&key).await.map_err(Error::from)?.ok_or_else(|| {
+                Error::Generic(format!("Missing object of offloaded thing: {key}"))
+            })?;
+
+        let parse = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| Uuid::parse_str(value).ok())
+                .ok_or_else(|| {
+                    Error::Generic(format!("Invalid metadata of offloaded thing: {key}"))
+                })
+        };
+        let uid = parse(&thing.metadata.uid)?;
+        let resource_version = parse(&thing.metadata.resource_version)?;
+        let data = self.persist_data(&con, &thing).await?;
+        let row = NewRow::new(&thing, uid, resource_version, data);
+
+        let tx = con.transaction().await.map_err(Error::Postgres)?;
+
+        // claim the offloaded thing, concurrent rehydrations wait until we are done
+        let stmt = tx
+            .prepare_typed_cached(
+                r#"
+DELETE FROM cold_things
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2
+    AND
+        OBJECT = $3
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                    Type::UUID,    // object
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        if tx
+            .execute(&stmt, &[&name, &application, &object])
+            .await
+            .map_err(Error::Postgres)?
+            == 0
+        {
+            // got rehydrated concurrently
+            return Ok(true);
+        }
+
+        let stmt = tx
+            .prepare_typed_cached(INSERT_THING, &INSERT_THING_TYPES)
+            .await
+            .map_err(Error::Postgres)?;
+        tx.execute(&stmt, &row.params())
+            .await
+            .map_err(Error::Postgres)?;
+
+        tx.commit().await.map_err(Error::Postgres)?;
+
+        log::info!("Rehydrated thing: {application} / {name}");
+
+        // the object isn't referenced anymore, failing to delete it only leaves garbage
+        if let Err(err) = cold.delete(&key).await {
+            log::warn!("Failed to delete object of rehydrated thing {key}: {err}");
+        }
+
+        Ok(true)
+    }
+
+    /// Offload a thing into the cold storage.
+    ///
+    /// The thing only gets offloaded if it wasn't modified since it was read, and doesn't have
+    /// a waker. Returns `true` if the thing was offloaded.
+    #[instrument(skip_all, fields(
+        name = thing.metadata.name,
+        application = thing.metadata.application
+    ), err, ret)]
+    pub async fn offload(&self, thing: &Thing<Internal>) -> Result<bool> {
+        let cold = self
+            .cold
+            .as_ref()
+            .ok_or_else(|| Error::Generic("No cold storage configured".to_string()))?;
+        let resource_version = thing
+            .metadata
+            .resource_version
+            .as_ref()
+            .ok_or(storage::Error::PreconditionFailed)?;
+
+        let name = &thing.metadata.name;
+        let application = &thing.metadata.application;
+
+        // store the object first, so that it's available once the thing is marked as offloaded
+        let object = Uuid::new_v4();
+        let key = cold::key(application, name, &object);
+        cold.put(&key, thing).await.map_err(Error::from)?;
+
+        let mut con = self.connection().await?;
+        let tx = con.transaction().await.map_err(Error::Postgres)?;
+
+        let stmt = tx
+            .prepare_typed_cached(
+                r#"
+DELETE FROM things
+WHERE
+        NAME = $1
+    AND
+        APPLICATION = $2
+    AND
+        RESOURCE_VERSION::text = $3
+    AND
+        WAKER IS NULL
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                    Type::TEXT,    // resource version
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        if tx
+            .execute(&stmt, &[name, application, resource_version])
+            .await
+            .map_err(Error::Postgres)?
+            == 0
+        {
+            tx.rollback().await.map_err(Error::Postgres)?;
+            if let Err(err) = cold.delete(&key).await {
+                log::warn!("Failed to delete object of skipped thing {key}: {err}");
+            }
+            return Ok(false);
+        }
+
+        let stmt = tx
+            .prepare_typed_cached(
+                r#"
+INSERT INTO cold_things (
+    NAME,
+    APPLICATION,
+    OBJECT,
+    OFFLOAD_TIMESTAMP
+) VALUES (
+    $1,
+    $2,
+    $3,
+    NOW()
+)
+"#,
+                &[
+                    Type::VARCHAR, // name
+                    Type::VARCHAR, // application
+                    Type::UUID,    // object
+                ],
+            )
+            .await
+            .map_err(Error::Postgres)?;
+
+        tx.execute(&stmt, &[name, application, &object])
+            .await
+            .map_err(Error::Postgres)?;
+
+        tx.commit().await.map_err(Error::Postgres)?;
+
+        Ok(true)
+    }
+
+    /// Convert the thing into its persisted data, storing the scripts separately.
+    async fn persist_data(&self, con: &Object, thing: &Thing<Internal>) -> Result<Value> {
+        let mut data = serde_json::to_value(Data::from(thing)).map_err(Error::from)?;
+        scripts::store(con, scripts::extract(&mut data)).await?;
+        Ok(data)
+    }
+
+    fn ensure_app<F, E>(&self, application: &str, f: F) -> Result<()>
+    where
+        F: FnOnce() -> E,
+        E: Into<storage::Error<Error>>,
+    {
+        if !self.applications.is_allowed(application) {
+            return Err(f().into());
+        }
+        Ok(())
+    }
+
+    /// Convert a persisted entity into a thing, resolving all script references.
+    async fn load(
+        con: &Object,
+        application: &str,
+        name: &str,
+        mut entity: ThingEntity,
+    ) -> Result<Thing<Internal>> {
+        scripts::load(con, &mut entity.data).await?;
+        Self::to_thing(application, name, entity)
+    }
+
+    /// Convert a persisted entity into a thing, its script references must already be resolved.
+    fn to_thing(application: &str, name: &str, entity: ThingEntity) -> Result<Thing<Internal>> {
+        let data: Data = serde_json::from_value(entity.data).map_err(Error::from)?;
+        Ok(Thing {
+            metadata: Metadata {
+                name: name.to_string(),
+                application: application.to_string(),
+                uid: Some(entity.uid.to_string()),
+                creation_timestamp: Some(entity.creation_timestamp),
+                deletion_timestamp: entity.deletion_timestamp,
+                resource_version: Some(entity.resource_version.to_string()),
+
+                generation: Some(entity.generation),
+                annotations: entity.annotations,
+                labels: entity.labels,
+            },
+            schema: data.schema,
+            alias_of: data.alias_of,
+            reported_state: data.reported_state,
+            desired_state: data.desired_state,
+            synthetic_state: data.synthetic_state,
+            reconciliation: data.reconciliation,
+            conditions: data.conditions,
+            internal: data.internal,
+        })
+    }
+
+    #[instrument(skip_all, err)]
+    async fn connection(&self) -> std::result::Result<Object, Error> {
+        self.pool.get().await.map_err(Error::Pool)
+    }
+
+    /// Take the outbox events of a thing, when using the transactional outbox.
+    ///
+    /// The events are written to the `outbox` table instead, and relayed from there. So the thing
+    /// neither tracks their delivery, nor requires a waker for sending them.
+    fn take_outbox(&self, thing: &mut Thing<Internal>) -> Option<Json<Vec<Event>>> {
+        if !self.outbox {
+            return None;
+        }
+
+        let internal = thing.internal.as_mut()?;
+        internal.clear_wakeup(WakerReason::Outbox);
+        if internal.outbox.is_empty() {
+            return None;
+        }
+
+        let events = std::mem::take(&mut internal.outbox);
+        internal
+            .deliveries
+            .retain(|delivery| !events.iter().any(|event| event.id == delivery.id));
+
+        Some(Json(events))
+    }
+
+    /// A connection for read-only operations, using the read replica, if configured.
+    async fn read_connection(&self) -> std::result::Result<Object, Error> {
+        self.replica
+            .as_ref()
+            .unwrap_or(&self.pool)
+            .get()
+            .await
+            .map_err(Error::Pool)
     }
 }
 
 fn waker_data(thing: &Thing<Internal>) -> Option<DateTime<Utc>> {
     thing.internal.as_ref().and_then(|i| i.waker.when)
 }
+
+/// Insert outbox events, for the rows returned by a previous statement, in their original order.
+fn insert_outbox(source: &str, events: usize) -> String {
+    format!(
+        r#"
+INSERT INTO outbox (
+    APPLICATION,
+    NAME,
+    UID,
+    EVENT
+)
+SELECT
+    S.APPLICATION, S.NAME, S.UID::uuid, E.VALUE
+FROM
+    {source} AS S,
+    JSONB_ARRAY_ELEMENTS(${events}) WITH ORDINALITY AS E(VALUE, N)
+ORDER BY
+    E.N
+"#
+    )
+}
+
+const INSERT_THING: &str = r#"
+INSERT INTO things (
+    NAME,
+    APPLICATION,
+    UID,
+    CREATION_TIMESTAMP,
+    GENERATION,
+    RESOURCE_VERSION,
+    ANNOTATIONS,
+    LABELS,
+    DATA,
+    WAKER,
+    DELETION_TIMESTAMP
+) VALUES (
+    $1,
+    $2,
+    $3,
+    $4,
+    $5,
+    $6,
+    $7,
+    $8,
+    $9,
+    $10,
+    $11
+)
+"#;
+
+const INSERT_THING_TYPES: [Type; 11] = [
+    Type::VARCHAR,     // name
+    Type::VARCHAR,     // application
+    Type::UUID,        // uid
+    Type::TIMESTAMPTZ, // creation timestamp
+    Type::INT8,        // generation
+    Type::UUID,        // resource version
+    Type::JSON,        // annotations
+    Type::JSONB,       // labels
+    Type::JSON,        // data
+    Type::TIMESTAMPTZ, // waker
+    Type::TIMESTAMPTZ, // deletion timestamp
+];
+
+/// The change of the persisted data of a thing.
+#[derive(Clone, Debug, PartialEq)]
+enum DataChange {
+    /// Replace the data.
+    Replace(Json<Value>),
+    /// Merge the changed top-level sections, and remove the missing ones.
+    Patch {
+        changed: Json<Map<String, Value>>,
+        removed: Vec<String>,
+    },
+}
+
+impl DataChange {
+    /// Compute the change of the top-level sections, between the current and the new data.
+    fn diff(current: Value, new: Value) -> Self {
+        match (current, new) {
+            (Value::Object(current), Value::Object(new)) => {
+                let removed = current
+                    .keys()
+                    .filter(|key| !new.contains_key(*key))
+                    .cloned()
+                    .collect();
+                let changed = new
+                    .into_iter()
+                    .filter(|(key, value)| current.get(key) != Some(value))
+                    .collect();
+                Self::Patch {
+                    changed: Json(changed),
+                    removed,
+                }
+            }
+            (_, new) => Self::Replace(Json(new)),
+        }
+    }
+}
+
+/// A thing with initialized metadata, to be inserted into the `things` table.
+struct NewRow<'a> {
+    thing: &'a Thing<Internal>,
+    uid: Uuid,
+    resource_version: Uuid,
+    creation_timestamp: DateTime<Utc>,
+    generation: i64,
+    annotations: Json<&'a BTreeMap<String, String>>,
+    labels: Json<&'a BTreeMap<String, String>>,
+    data: Json<Value>,
+    waker: Option<DateTime<Utc>>,
+}
+
+impl<'a> NewRow<'a> {
+    fn new(thing: &'a Thing<Internal>, uid: Uuid, resource_version: Uuid, data: Value) -> Self {
+        Self {
+            thing,
+            uid,
+            resource_version,
+            creation_timestamp: thing.metadata.creation_timestamp.unwrap_or_else(Utc::now),
+            generation: thing.metadata.generation.unwrap_or(1) as i64,
+            annotations: Json(&thing.metadata.annotations),
+            labels: Json(&thing.metadata.labels),
+            data: Json(data),
+            waker: waker_data(thing),
+        }
+    }
+
+    fn params(&self) -> [&(dyn ToSql + Sync); 11] {
+        [
+            &self.thing.metadata.name,
+            &self.thing.metadata.application,
+            &self.uid,
+            &self.creation_timestamp,
+            &self.generation,
+            &self.resource_version,
+            &self.annotations,
+            &self.labels,
+            &self.data,
+            &self.waker,
+            &self.thing.metadata.deletion_timestamp,
+        ]
+    }
+}
+
+/// A parameter of a label selector clause.
+enum SelectorParam {
+    Text(String),
+    Array(Vec<String>),
+}
+
+impl SelectorParam {
+    fn r#type(&self) -> Type {
+        match self {
+            Self::Text(_) => Type::VARCHAR,
+            Self::Array(_) => Type::TEXT_ARRAY,
+        }
+    }
+
+    fn value(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            Self::Text(value) => value,
+            Self::Array(values) => values,
+        }
+    }
+}
+
+/// Translate a label selector into a clause on the `LABELS` column, numbering the parameters
+/// starting with `first`.
+fn selector_clause(selector: &LabelSelector, first: usize) -> (String, Vec<SelectorParam>) {
+    let mut clause = String::new();
+    let mut params = Vec::new();
+
+    for requirement in &selector.0 {
+        let key = first + params.len();
+        params.push(SelectorParam::Text(requirement.key().to_string()));
+        let value = key + 1;
+
+        let condition = match requirement {
+            Requirement::Exists(_) => format!("COALESCE(LABELS ? ${key}, FALSE)"),
+            Requirement::NotExists(_) => format!("NOT COALESCE(LABELS ? ${key}, FALSE)"),
+            Requirement::Equals(_, expected) => {
+                params.push(SelectorParam::Text(expected.clone()));
+                format!("LABELS ->> ${key} = ${value}")
+            }
+            Requirement::NotEquals(_, expected) => {
+                params.push(SelectorParam::Text(expected.clone()));
+                format!("(LABELS ->> ${key}) IS DISTINCT FROM ${value}")
+            }
+            Requirement::In(_, values) => {
+                params.push(SelectorParam::Array(values.iter().cloned().collect()));
+                format!("LABELS ->> ${key} = ANY(${value})")
+            }
+            Requirement::NotIn(_, values) => {
+                params.push(SelectorParam::Array(values.iter().cloned().collect()));
+                format!("NOT COALESCE(LABELS ->> ${key} = ANY(${value}), FALSE)")
+            }
+        };
+
+        clause.push_str(&format!("    AND\n        {condition}\n"));
+    }
+
+    (clause, params)
+}
+
+/// Translate a query into an SQL/JSON path predicate on the `DATA` column.
+///
+/// Returns `None` if the query can't be expressed as predicate, e.g. when comparing to objects.
+fn json_path(query: &Query) -> Option<String> {
+    let mut predicates = Vec::with_capacity(query.0.len());
+
+    for condition in &query.0 {
+        if condition.value.is_object() || condition.value.is_array() {
+            return None;
+        }
+
+        // the sections are persisted by their field name, see [`Data`]
+        let section = match condition.section {
+            Section::Reported => "reported_state",
+            Section::Synthetic => "synthetic_state",
+        };
+
+        let value = format!(
+            "$.{}.{}.\"value\"",
+            serde_json::to_string(section).ok()?,
+            serde_json::to_string(&condition.name).ok()?,
+        );
+
+        // comparing a missing feature, or values of different types, doesn't match
+        predicates.push(format!(
+            "{value} {} {}",
+            condition.operator.symbol(),
+            condition.value
+        ));
+    }
+
+    Some(predicates.join(" && "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_data_change() {
+        let current = json!({
+            "reported_state": {"temperature": {"value": 21}},
+            "desired_state": {"mode": {"value": "auto"}},
+            "internal": {"outbox": []},
+        });
+
+        let new = json!({
+            "reported_state": {"temperature": {"value": 22}},
+            "desired_state": {"mode": {"value": "auto"}},
+            "conditions": {"Ready": {"status": "True"}},
+        });
+
+        assert_eq!(
+            DataChange::diff(current.clone(), new),
+            DataChange::Patch {
+                changed: Json(
+                    json!({
+                        "reported_state": {"temperature": {"value": 22}},
+                        "conditions": {"Ready": {"status": "True"}},
+                    })
+                    .as_object()
+                    .cloned()
+                    .unwrap()
+                ),
+                removed: vec!["internal".to_string()],
+            }
+        );
+
+        assert_eq!(
+            DataChange::diff(current.clone(), current),
+            DataChange::Patch {
+                changed: Json(Map::new()),
+                removed: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_selector_clause() {
+        let selector: LabelSelector = "environment=prod,region in (eu,us),!legacy"
+            .parse()
+            .unwrap();
+        let (clause, params) = selector_clause(&selector, 4);
+
+        assert_eq!(
+            clause,
+            r#"    AND
+        LABELS ->> $4 = $5
+    AND
+        LABELS ->> $6 = ANY($7)
+    AND
+        NOT COALESCE(LABELS ? $8, FALSE)
+"#
+        );
+        assert_eq!(
+            params.iter().map(SelectorParam::r#type).collect::<Vec<_>>(),
+            vec![
+                Type::VARCHAR,
+                Type::VARCHAR,
+                Type::VARCHAR,
+                Type::TEXT_ARRAY,
+                Type::VARCHAR
+            ]
+        );
+
+        assert_eq!(selector_clause(&LabelSelector::default(), 4).0, "");
+    }
+
+    #[test]
+    fn test_json_path() {
+        let query: Query = r#"reportedState.temperature > 30,syntheticState.mode != "a\"b""#
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            json_path(&query).as_deref(),
+            Some(
+                r#"$."reported_state"."temperature"."value" > 30 && $."synthetic_state"."mode"."value" != "a\"b""#
+            )
+        );
+
+        assert_eq!(
+            json_path(&"reportedState.position = {\"lat\": 1}".parse().unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Content-addressable storage of scripts.
+//!
+//! Scripts are stored once, in the `scripts` table, using the SHA-256 hash of their content as
+//! key. The persisted data of a thing only contains a reference (`javaScriptRef`) to the script.
+//!
+//! Only the positions which hold code are considered: the reconciliation functions, and the
+//! synthetic features. Values provided by users, like the reported state, are left as they are.
+
+use super::Error;
+use deadpool_postgres::Object;
+use postgres_types::Type;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const JAVASCRIPT: &str = "javaScript";
+const JAVASCRIPT_REF: &str = "javaScriptRef";
+
+/// The sections of the reconciliation holding code, by name.
+const RECONCILIATION: [&str; 3] = ["changed", "timers", "deleting"];
+
+/// The content hash of a script.
+pub fn hash(script: &str) -> String {
+    format!("{:x}", Sha256::digest(script.as_bytes()))
+}
+
+/// Replace all inline scripts with references, returning the extracted scripts by hash.
+pub fn extract(value: &mut Value) -> BTreeMap<String, String> {
+    let mut scripts = BTreeMap::new();
+    for_each_mut(value, &mut |map| {
+        if let Some(Value::String(script)) = map.remove(JAVASCRIPT) {
+            let hash = hash(&script);
+            map.insert(JAVASCRIPT_REF.to_string(), Value::String(hash.clone()));
+            scripts.insert(hash, script);
+        }
+    });
+    scripts
+}
+
+/// Collect all script references.
+pub fn references(value: &Value) -> BTreeSet<String> {
+    let mut refs = BTreeSet::new();
+    for_each(value, &mut |map| {
+        if let Some(Value::String(hash)) = map.get(JAVASCRIPT_REF) {
+            refs.insert(hash.clone());
+        }
+    });
+    refs
+}
+
+/// Replace all script references with the actual scripts.
+pub fn resolve(value: &mut Value, scripts: &HashMap<String, String>) -> Result<(), Error> {
+    let mut missing = None;
+    for_each_mut(value, &mut |map| {
+        if let Some(Value::String(hash)) = map.remove(JAVASCRIPT_REF) {
+            match scripts.get(&hash) {
+                Some(script) => {
+                    map.insert(JAVASCRIPT.to_string(), Value::String(script.clone()));
+                }
+                None => {
+                    missing = Some(hash);
+                }
+            }
+        }
+    });
+
+    match missing {
+        Some(hash) => Err(Error::Generic(format!("Missing script: {hash}"))),
+        None => Ok(()),
+    }
+}
+
+/// Store scripts, skipping the ones which already exist.
+pub async fn store(con: &Object, scripts: BTreeMap<String, String>) -> Result<(), Error> {
+    if scripts.is_empty() {
+        return Ok(());
+    }
+
+    let stmt = con
+        .prepare_typed_cached(
+            r#"
+INSERT INTO scripts (
+    HASH,
+    CODE
+) VALUES (
+    $1,
+    $2
+)
+ON CONFLICT DO NOTHING
+"#,
+            &[
+                Type::VARCHAR, // hash
+                Type::TEXT,    // code
+            ],
+        )
+        .await?;
+
+    for (hash, code) in scripts {
+        con.execute(&stmt, &[&hash, &code]).await?;
+    }
+
+    Ok(())
+}
+
+/// Load the scripts referenced by a value, and resolve the references.
+pub async fn load(con: &Object, value: &mut Value) -> Result<(), Error> {
+    let scripts = fetch(con, references(value)).await?;
+    resolve(value, &scripts)
+}
+
+/// Load the scripts referenced by a set of values, and resolve the references.
+///
+/// All scripts are fetched using a single query.
+pub async fn load_all<'v, I>(con: &Object, values: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = &'v mut Value>,
+{
+    let mut values = values.into_iter().collect::<Vec<_>>();
+    let refs = values.iter().flat_map(|value| references(value)).collect();
+    let scripts = fetch(con, refs).await?;
+    for value in &mut values {
+        resolve(value, &scripts)?;
+    }
+    Ok(())
+}
+
+/// Fetch scripts by their hashes.
+async fn fetch(con: &Object, refs: BTreeSet<String>) -> Result<HashMap<String, String>, Error> {
+    if refs.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let stmt = con
+        .prepare_typed_cached(
+            r#"
+SELECT
+    HASH,
+    CODE
+FROM
+    scripts
+WHERE
+    HASH = ANY($1)
+"#,
+            &[Type::VARCHAR_ARRAY],
+        )
+        .await?;
+
+    let refs = refs.into_iter().collect::<Vec<_>>();
+    Ok(con
+        .query(&stmt, &[&refs])
+        .await?
+        .into_iter()
+        .map(|row| Ok((row.try_get("HASH")?, row.try_get("CODE")?)))
+        .collect::<Result<HashMap<String, String>, tokio_postgres::Error>>()?)
+}
+
+/// Call the function for each object of the persisted data, which may hold code.
+fn for_each<F>(value: &Value, f: &mut F)
+where
+    F: FnMut(&Map<String, Value>),
+{
+    let sections = RECONCILIATION
+        .iter()
+        .filter_map(|section| value.get("reconciliation")?.get(section))
+        .chain(value.get("synthetic_state"));
+
+    for section in sections {
+        if let Value::Object(entries) = section {
+            for entry in entries.values() {
+                if let Value::Object(map) = entry {
+                    f(map);
+                }
+            }
+        }
+    }
+}
+
+/// Call the function for each object of the persisted data, which may hold code.
+fn for_each_mut<F>(value: &mut Value, f: &mut F)
+where
+    F: FnMut(&mut Map<String, Value>),
+{
+    let Value::Object(data) = value else {
+        return;
+    };
+
+    if let Some(Value::Object(reconciliation)) = data.get_mut("reconciliation") {
+        for (name, section) in reconciliation.iter_mut() {
+            if RECONCILIATION.contains(&name.as_str()) {
+                for_each_entry_mut(section, f);
+            }
+        }
+    }
+
+    if let Some(section) = data.get_mut("synthetic_state") {
+        for_each_entry_mut(section, f);
+    }
+}
+
+fn for_each_entry_mut<F>(section: &mut Value, f: &mut F)
+where
+    F: FnMut(&mut Map<String, Value>),
+{
+    if let Value::Object(entries) = section {
+        for entry in entries.values_mut() {
+            if let Value::Object(map) = entry {
+                f(map);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_roundtrip() {
+        let original = json!({
+            "reconciliation": {
+                "changed": {
+                    "a": { "javaScript": "foo();", "lastLog": ["bar"] },
+                    "b": { "javaScript": "foo();" },
+                },
+                "timers": {
+                    "c": { "javaScript": "bar();", "period": "1m" },
+                },
+            },
+        });
+
+        let mut value = original.clone();
+        let scripts = extract(&mut value);
+
+        assert_eq!(scripts.len(), 2);
+        assert_eq!(
+            value["reconciliation"]["changed"]["a"],
+            json!({"javaScriptRef": hash("foo();"), "lastLog": ["bar"]})
+        );
+        assert_eq!(references(&value).len(), 2);
+
+        resolve(&mut value, &scripts.into_iter().collect()).unwrap();
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn test_synthetic() {
+        let mut value = json!({
+            "synthetic_state": {
+                "a": { "javaScript": "foo();", "value": 1 },
+            },
+        });
+
+        let scripts = extract(&mut value);
+
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(
+            value["synthetic_state"]["a"],
+            json!({"javaScriptRef": hash("foo();"), "value": 1})
+        );
+    }
+
+    #[test]
+    fn test_missing() {
+        let mut value = json!({
+            "reconciliation": {
+                "changed": {
+                    "a": { "javaScriptRef": "0000" },
+                },
+            },
+        });
+        assert!(resolve(&mut value, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_user_values() {
+        let original = json!({
+            "reported_state": {
+                "a": { "value": { "javaScript": "foo();" } },
+                "b": { "value": { "javaScriptRef": "0000" } },
+            },
+            "desired_state": {
+                "c": { "value": { "javaScript": "bar();" } },
+            },
+            "synthetic_state": {
+                "d": { "alias": "a", "value": { "javaScriptRef": "0000" } },
+            },
+        });
+
+        let mut value = original.clone();
+
+        assert!(extract(&mut value).is_empty());
+        assert!(references(&value).is_empty());
+        resolve(&mut value, &HashMap::new()).unwrap();
+        assert_eq!(value, original);
+    }
+}
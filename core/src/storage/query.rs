@@ -0,0 +1,366 @@
+//! Queries on the state of things.
+//!
+//! A query is a comma separated list of conditions, which all must match. A condition compares
+//! the value of a reported or synthetic feature, e.g. `reportedState.temperature > 30`. Supported
+//! operators are `==` (or `=`), `!=`, `<`, `<=`, `>`, and `>=`. Values are parsed as JSON, and
+//! fall back to a plain string, so `reportedState.mode = auto` and
+//! `reportedState.mode = "auto"` are equivalent.
+//!
+//! Conditions only match if the feature is present, and its value has the same type as the value
+//! of the condition. Ordering operators only match numbers or strings.
+
+use crate::model::{Internal, Thing};
+use serde_json::Value;
+use std::{
+    cmp::Ordering,
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum QueryError {
+    #[error("Invalid condition '{0}'")]
+    InvalidCondition(String),
+    #[error("Unknown section '{0}', expected 'reportedState' or 'syntheticState'")]
+    UnknownSection(String),
+}
+
+/// The section of a thing, a condition applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Reported,
+    Synthetic,
+}
+
+impl Section {
+    /// The name of the section, as serialized.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Reported => "reportedState",
+            Self::Synthetic => "syntheticState",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Equals,
+    NotEquals,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    /// The operators, longer ones first, so that they are matched first.
+    const ALL: [(&'static str, Self); 7] = [
+        ("==", Self::Equals),
+        ("!=", Self::NotEquals),
+        ("<=", Self::LessOrEqual),
+        (">=", Self::GreaterOrEqual),
+        ("=", Self::Equals),
+        ("<", Self::Less),
+        (">", Self::Greater),
+    ];
+
+    /// The operator, as used in a query, and in SQL/JSON path expressions.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Equals => "==",
+            Self::NotEquals => "!=",
+            Self::Less => "<",
+            Self::LessOrEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+/// A condition on the value of a feature.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub section: Section,
+    pub name: String,
+    pub operator: Operator,
+    pub value: Value,
+}
+
+/// A query on the state of things. An empty query matches everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query(pub Vec<Condition>);
+
+impl Query {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if the thing matches all conditions.
+    pub fn matches(&self, thing: &Thing<Internal>) -> bool {
+        self.0.iter().all(|condition| condition.matches(thing))
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, thing: &Thing<Internal>) -> bool {
+        let value = match self.section {
+            Section::Reported => thing.reported_state.get(&self.name).map(|f| &f.value),
+            Section::Synthetic => thing.synthetic_state.get(&self.name).map(|f| &f.value),
+        };
+
+        let Some(value) = value else {
+            return false;
+        };
+
+        match self.operator {
+            Operator::Equals => value == &self.value,
+            Operator::NotEquals => same_type(value, &self.value) && value != &self.value,
+            Operator::Less => compare(value, &self.value) == Some(Ordering::Less),
+            Operator::LessOrEqual => matches!(
+                compare(value, &self.value),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Operator::Greater => compare(value, &self.value) == Some(Ordering::Greater),
+            Operator::GreaterOrEqual => matches!(
+                compare(value, &self.value),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+        }
+    }
+}
+
+fn same_type(a: &Value, b: &Value) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+}
+
+/// Compare two values of the same type, numbers or strings.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut conditions = vec![];
+
+        // split on commas, outside of quoted strings, objects, and arrays
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut depth = 0usize;
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                _ if quoted => {}
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth = depth.saturating_sub(1),
+                ',' if depth == 0 => {
+                    conditions.push(s[start..i].parse()?);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        conditions.push(s[start..].parse()?);
+
+        Ok(Self(conditions))
+    }
+}
+
+impl FromStr for Condition {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || QueryError::InvalidCondition(s.to_string());
+
+        let pos = s.find(['=', '!', '<', '>']).ok_or_else(invalid)?;
+        let (path, rest) = s.split_at(pos);
+        let (symbol, operator) = Operator::ALL
+            .into_iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .ok_or_else(invalid)?;
+
+        let (section, name) = path.trim().split_once('.').ok_or_else(invalid)?;
+        let section = match section {
+            "reportedState" => Section::Reported,
+            "syntheticState" => Section::Synthetic,
+            _ => return Err(QueryError::UnknownSection(section.to_string())),
+        };
+        if name.is_empty() {
+            return Err(invalid());
+        }
+
+        let value = rest[symbol.len()..].trim();
+        if value.is_empty() {
+            return Err(invalid());
+        }
+        let value =
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+
+        Ok(Self {
+            section,
+            name: name.to_string(),
+            operator,
+            value,
+        })
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{} {} {}",
+            self.section.name(),
+            self.name,
+            self.operator.symbol(),
+            self.value
+        )
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let conditions = self.0.iter().map(ToString::to_string).collect::<Vec<_>>();
+        write!(f, "{}", conditions.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::{ReportedFeature, SyntheticFeature, SyntheticType};
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn condition(section: Section, name: &str, operator: Operator, value: Value) -> Condition {
+        Condition {
+            section,
+            name: name.to_string(),
+            operator,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let query: Query =
+            r#"reportedState.temperature > 30, syntheticState.mode=auto,reportedState.label != "a,b""#
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            query,
+            Query(vec![
+                condition(
+                    Section::Reported,
+                    "temperature",
+                    Operator::Greater,
+                    json!(30)
+                ),
+                condition(Section::Synthetic, "mode", Operator::Equals, json!("auto")),
+                condition(
+                    Section::Reported,
+                    "label",
+                    Operator::NotEquals,
+                    json!("a,b")
+                ),
+            ])
+        );
+
+        assert_eq!(
+            query.to_string(),
+            r#"reportedState.temperature > 30,syntheticState.mode == "auto",reportedState.label != "a,b""#
+        );
+
+        assert_eq!("".parse(), Ok(Query::default()));
+        assert_eq!(
+            "reportedState.a.b<=1.5".parse(),
+            Ok(Query(vec![condition(
+                Section::Reported,
+                "a.b",
+                Operator::LessOrEqual,
+                json!(1.5)
+            )]))
+        );
+        assert_eq!(
+            r#"reportedState.position = {"lat": 1, "lon": 2},reportedState.a=1"#.parse(),
+            Ok(Query(vec![
+                condition(
+                    Section::Reported,
+                    "position",
+                    Operator::Equals,
+                    json!({"lat": 1, "lon": 2})
+                ),
+                condition(Section::Reported, "a", Operator::Equals, json!(1)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(
+            "reportedState.temperature".parse::<Query>(),
+            Err(QueryError::InvalidCondition(
+                "reportedState.temperature".into()
+            ))
+        );
+        assert_eq!(
+            "desiredState.mode = auto".parse::<Query>(),
+            Err(QueryError::UnknownSection("desiredState".into()))
+        );
+        assert_eq!(
+            "reportedState.temperature >".parse::<Query>(),
+            Err(QueryError::InvalidCondition(
+                "reportedState.temperature >".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut thing = Thing::new("default", "thing1");
+        thing
+            .reported_state
+            .insert("temperature".into(), ReportedFeature::now(json!(42)));
+        thing
+            .reported_state
+            .insert("mode".into(), ReportedFeature::now(json!("auto")));
+        thing.synthetic_state.insert(
+            "alarm".into(),
+            SyntheticFeature {
+                r#type: SyntheticType::JavaScript("true".into()),
+                last_update: Utc::now(),
+                value: json!(true),
+            },
+        );
+
+        let matches = |query: &str| query.parse::<Query>().unwrap().matches(&thing);
+
+        assert!(matches("reportedState.temperature > 30"));
+        assert!(matches("reportedState.temperature >= 42"));
+        assert!(!matches("reportedState.temperature < 42"));
+        assert!(matches(
+            "reportedState.mode = auto, syntheticState.alarm = true"
+        ));
+        assert!(!matches("reportedState.mode > 1"));
+        // conditions only match present features
+        assert!(!matches("reportedState.humidity != 1"));
+        assert!(!matches("reportedState.mode != 1"));
+        assert!(matches("reportedState.mode != manual"));
+
+        assert!(Query::default().matches(&thing));
+    }
+}
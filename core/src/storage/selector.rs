@@ -0,0 +1,285 @@
+//! Label selectors, for filtering things by their labels.
+//!
+//! The syntax follows the Kubernetes label selectors: a comma separated list of requirements,
+//! which all must match. Supported requirements are `key`, `!key`, `key=value`, `key==value`,
+//! `key!=value`, `key in (a,b)`, and `key notin (a,b)`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SelectorError {
+    #[error("Invalid requirement '{0}'")]
+    InvalidRequirement(String),
+    #[error("Unbalanced parentheses in '{0}'")]
+    Unbalanced(String),
+}
+
+/// A requirement on the labels of a thing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Requirement {
+    /// The label must be present.
+    Exists(String),
+    /// The label must not be present.
+    NotExists(String),
+    /// The label must be present, and have the value.
+    Equals(String, String),
+    /// The label must not be present, or have a different value.
+    NotEquals(String, String),
+    /// The label must be present, and have one of the values.
+    In(String, BTreeSet<String>),
+    /// The label must not be present, or have none of the values.
+    NotIn(String, BTreeSet<String>),
+}
+
+/// A label selector. An empty selector matches everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LabelSelector(pub Vec<Requirement>);
+
+impl LabelSelector {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if the labels match all requirements.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|requirement| requirement.matches(labels))
+    }
+}
+
+impl Requirement {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Exists(key)
+            | Self::NotExists(key)
+            | Self::Equals(key, _)
+            | Self::NotEquals(key, _)
+            | Self::In(key, _)
+            | Self::NotIn(key, _) => key,
+        }
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        let value = labels.get(self.key());
+        match self {
+            Self::Exists(_) => value.is_some(),
+            Self::NotExists(_) => value.is_none(),
+            Self::Equals(_, expected) => value == Some(expected),
+            Self::NotEquals(_, expected) => value != Some(expected),
+            Self::In(_, values) => value.map_or(false, |value| values.contains(value)),
+            Self::NotIn(_, values) => value.map_or(true, |value| !values.contains(value)),
+        }
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requirements = vec![];
+
+        let mut depth = 0usize;
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth = depth
+                        .checked_sub(1)
+                        .ok_or_else(|| SelectorError::Unbalanced(s.to_string()))?
+                }
+                ',' if depth == 0 => {
+                    requirements.push(&s[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        if depth != 0 {
+            return Err(SelectorError::Unbalanced(s.to_string()));
+        }
+        requirements.push(&s[start..]);
+
+        // an empty selector is valid, empty requirements are not
+        if requirements.len() == 1 && requirements[0].trim().is_empty() {
+            return Ok(Self::default());
+        }
+
+        Ok(Self(
+            requirements
+                .into_iter()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SelectorError::InvalidRequirement(s.trim().to_string());
+        let s = s.trim();
+
+        let key = |key: &str| -> Result<String, SelectorError> {
+            let key = key.trim();
+            match !key.is_empty() && !key.contains(char::is_whitespace) {
+                true => Ok(key.to_string()),
+                false => Err(invalid()),
+            }
+        };
+        let set = |values: &str| -> Result<BTreeSet<String>, SelectorError> {
+            let values = values
+                .trim()
+                .strip_prefix('(')
+                .and_then(|values| values.strip_suffix(')'))
+                .ok_or_else(invalid)?;
+            Ok(values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .collect())
+        };
+
+        if let Some((k, v)) = s.split_once("!=") {
+            return Ok(Self::NotEquals(key(k)?, v.trim().to_string()));
+        }
+        if let Some((k, v)) = s.split_once("==").or_else(|| s.split_once('=')) {
+            return Ok(Self::Equals(key(k)?, v.trim().to_string()));
+        }
+        if let Some((k, v)) = s.split_once(" notin ") {
+            return Ok(Self::NotIn(key(k)?, set(v)?));
+        }
+        if let Some((k, v)) = s.split_once(" in ") {
+            return Ok(Self::In(key(k)?, set(v)?));
+        }
+        if let Some(k) = s.strip_prefix('!') {
+            return Ok(Self::NotExists(key(k)?));
+        }
+
+        Ok(Self::Exists(key(s)?))
+    }
+}
+
+impl Display for Requirement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let join = |values: &BTreeSet<String>| {
+            values
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        match self {
+            Self::Exists(key) => write!(f, "{key}"),
+            Self::NotExists(key) => write!(f, "!{key}"),
+            Self::Equals(key, value) => write!(f, "{key}={value}"),
+            Self::NotEquals(key, value) => write!(f, "{key}!={value}"),
+            Self::In(key, values) => write!(f, "{key} in ({})", join(values)),
+            Self::NotIn(key, values) => write!(f, "{key} notin ({})", join(values)),
+        }
+    }
+}
+
+impl Display for LabelSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let requirements = self.0.iter().map(ToString::to_string).collect::<Vec<_>>();
+        write!(f, "{}", requirements.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> BTreeMap<String, String> {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn set(values: &[&str]) -> BTreeSet<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let selector: LabelSelector =
+            "environment=prod, region in (eu, us),tier notin (edge),!legacy,gpu,zone!=b"
+                .parse()
+                .unwrap();
+
+        assert_eq!(
+            selector,
+            LabelSelector(vec![
+                Requirement::Equals("environment".into(), "prod".into()),
+                Requirement::In("region".into(), set(&["eu", "us"])),
+                Requirement::NotIn("tier".into(), set(&["edge"])),
+                Requirement::NotExists("legacy".into()),
+                Requirement::Exists("gpu".into()),
+                Requirement::NotEquals("zone".into(), "b".into()),
+            ])
+        );
+
+        assert_eq!(
+            selector.to_string(),
+            "environment=prod,region in (eu,us),tier notin (edge),!legacy,gpu,zone!=b"
+        );
+
+        assert_eq!("".parse(), Ok(LabelSelector::default()));
+        assert_eq!(
+            "a==b".parse(),
+            Ok(LabelSelector(vec![Requirement::Equals(
+                "a".into(),
+                "b".into()
+            )]))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(
+            "a=b,".parse::<LabelSelector>(),
+            Err(SelectorError::InvalidRequirement("".into()))
+        );
+        assert_eq!(
+            "region in (eu".parse::<LabelSelector>(),
+            Err(SelectorError::Unbalanced("region in (eu".into()))
+        );
+        assert_eq!(
+            "region in eu".parse::<LabelSelector>(),
+            Err(SelectorError::InvalidRequirement("region in eu".into()))
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let selector: LabelSelector = "environment=prod,region in (eu,us),!legacy,zone!=b"
+            .parse()
+            .unwrap();
+
+        assert!(selector.matches(&labels(&[("environment", "prod"), ("region", "eu")])));
+        assert!(selector.matches(&labels(&[
+            ("environment", "prod"),
+            ("region", "us"),
+            ("zone", "a")
+        ])));
+        assert!(!selector.matches(&labels(&[("environment", "prod"), ("region", "ap")])));
+        assert!(!selector.matches(&labels(&[("environment", "dev"), ("region", "eu")])));
+        assert!(!selector.matches(&labels(&[
+            ("environment", "prod"),
+            ("region", "eu"),
+            ("legacy", "")
+        ])));
+        assert!(!selector.matches(&labels(&[
+            ("environment", "prod"),
+            ("region", "eu"),
+            ("zone", "b")
+        ])));
+
+        assert!(LabelSelector::default().matches(&labels(&[])));
+    }
+}
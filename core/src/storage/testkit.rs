@@ -0,0 +1,271 @@
+//! A conformance test suite for [`Storage`] implementations.
+//!
+//! The tests exercise the semantics the service relies on: metadata managed by the storage,
+//! optimistic locking using the resource version, preconditions, and listing. Each test panics if
+//! the storage doesn't conform.
+//!
+//! The tests expect the application to be empty, and don't clean up. Use a fresh application
+//! (e.g. a random name) for each run, when running against a shared storage:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn test_conformance() {
+//!     let storage = MyStorage::new();
+//!     testkit::run(&storage, "default").await;
+//! }
+//! ```
+
+use super::{Error, ListOptions, Storage};
+use crate::{
+    model::{Internal, ReportedFeature, Thing},
+    Preconditions,
+};
+use serde_json::json;
+
+/// Run all tests of the suite.
+pub async fn run<S: Storage>(storage: &S, application: &str) {
+    create(storage, application).await;
+    update(storage, application).await;
+    optimistic_locking(storage, application).await;
+    delete(storage, application).await;
+    delete_preconditions(storage, application).await;
+    list(storage, application).await;
+}
+
+fn assert_not_found<T: std::fmt::Debug, E: std::error::Error + Send + Sync>(
+    result: Result<Option<T>, Error<E>>,
+) {
+    match result {
+        Ok(None) | Err(Error::NotFound) => {}
+        result => panic!("Expected the thing to be missing, got: {result:?}"),
+    }
+}
+
+/// Creating a thing assigns the metadata managed by the storage.
+pub async fn create<S: Storage>(storage: &S, application: &str) {
+    assert_not_found(storage.get(application, "create").await);
+
+    let mut thing = Thing::<Internal>::new(application, "create");
+    thing.metadata.labels.insert("foo".into(), "bar".into());
+    thing
+        .metadata
+        .annotations
+        .insert("foo".into(), "bar".into());
+
+    let created = storage.create(thing.clone()).await.unwrap();
+    assert!(created.metadata.uid.is_some(), "Must assign a UID");
+    assert!(
+        created.metadata.creation_timestamp.is_some(),
+        "Must assign a creation timestamp"
+    );
+    assert!(
+        created.metadata.resource_version.is_some(),
+        "Must assign a resource version"
+    );
+    assert_eq!(created.metadata.generation, Some(1));
+    assert_eq!(created.metadata.labels, thing.metadata.labels);
+    assert_eq!(created.metadata.annotations, thing.metadata.annotations);
+
+    assert_eq!(
+        storage.get(application, "create").await.unwrap(),
+        Some(created)
+    );
+
+    assert!(
+        matches!(storage.create(thing).await, Err(Error::AlreadyExists)),
+        "Creating an existing thing must fail"
+    );
+}
+
+/// Updating a thing increments the generation, and keeps the metadata managed by the storage.
+///
+/// Updating a missing thing must fail, either as not found, or as failed precondition.
+pub async fn update<S: Storage>(storage: &S, application: &str) {
+    let created = storage
+        .create(Thing::new(application, "update"))
+        .await
+        .unwrap();
+
+    let mut thing = created.clone();
+    thing
+        .reported_state
+        .insert("temperature".into(), ReportedFeature::now(json!(42)));
+    let updated = storage.update(thing.clone()).await.unwrap();
+
+    assert_eq!(updated.metadata.uid, created.metadata.uid);
+    assert_eq!(
+        updated.metadata.creation_timestamp,
+        created.metadata.creation_timestamp
+    );
+    assert_eq!(updated.metadata.generation, Some(2));
+    assert_ne!(
+        updated.metadata.resource_version, created.metadata.resource_version,
+        "Must change the resource version"
+    );
+    assert_eq!(updated.reported_state, thing.reported_state);
+
+    assert_eq!(
+        storage.get(application, "update").await.unwrap(),
+        Some(updated)
+    );
+
+    // storages may not be able to tell a missing thing from a failed precondition
+    assert!(
+        matches!(
+            storage
+                .update(Thing::new(application, "update-missing"))
+                .await,
+            Err(Error::NotFound | Error::PreconditionFailed)
+        ),
+        "Updating a missing thing must fail"
+    );
+}
+
+/// Updates using an outdated resource version or a different UID fail.
+pub async fn optimistic_locking<S: Storage>(storage: &S, application: &str) {
+    let created = storage
+        .create(Thing::new(application, "oplock"))
+        .await
+        .unwrap();
+    let updated = storage.update(created.clone()).await.unwrap();
+
+    assert!(
+        matches!(
+            storage.update(created).await,
+            Err(Error::PreconditionFailed)
+        ),
+        "Updating an outdated thing must fail"
+    );
+
+    let mut other = updated.clone();
+    other.metadata.uid = Some(uuid::Uuid::new_v4().to_string());
+    assert!(
+        matches!(storage.update(other).await, Err(Error::PreconditionFailed)),
+        "Updating a thing with a different UID must fail"
+    );
+
+    // nothing changed
+    assert_eq!(
+        storage.get(application, "oplock").await.unwrap(),
+        Some(updated)
+    );
+}
+
+/// Deleting a thing reports if it existed.
+pub async fn delete<S: Storage>(storage: &S, application: &str) {
+    storage
+        .create(Thing::new(application, "delete"))
+        .await
+        .unwrap();
+
+    assert!(storage.delete(application, "delete").await.unwrap());
+    assert_not_found(storage.get(application, "delete").await);
+    assert!(
+        !storage.delete(application, "delete").await.unwrap(),
+        "Deleting a missing thing must return false"
+    );
+
+    // the name can be re-used, as a new thing
+    let created = storage
+        .create(Thing::new(application, "delete"))
+        .await
+        .unwrap();
+    assert_eq!(created.metadata.generation, Some(1));
+    assert!(storage.delete(application, "delete").await.unwrap());
+}
+
+/// Deleting a thing only succeeds if the preconditions match.
+pub async fn delete_preconditions<S: Storage>(storage: &S, application: &str) {
+    let created = storage
+        .create(Thing::new(application, "delete-preconditions"))
+        .await
+        .unwrap();
+    let updated = storage.update(created.clone()).await.unwrap();
+
+    assert!(!storage
+        .delete_with(
+            application,
+            "delete-preconditions",
+            Preconditions::from(&created)
+        )
+        .await
+        .unwrap());
+    assert!(!storage
+        .delete_with(
+            application,
+            "delete-preconditions",
+            Preconditions {
+                resource_version: None,
+                uid: Some("00000000-0000-0000-0000-000000000000"),
+                unmodified_since: None,
+            }
+        )
+        .await
+        .unwrap());
+    assert_eq!(
+        storage
+            .get(application, "delete-preconditions")
+            .await
+            .unwrap(),
+        Some(updated.clone())
+    );
+
+    assert!(storage
+        .delete_with(
+            application,
+            "delete-preconditions",
+            Preconditions::from(&updated)
+        )
+        .await
+        .unwrap());
+    assert_not_found(storage.get(application, "delete-preconditions").await);
+}
+
+/// Listing things is ordered by name, and supports paging and selectors.
+pub async fn list<S: Storage>(storage: &S, application: &str) {
+    let prefix = "list-";
+    for name in ["b", "a", "c"] {
+        let mut thing = Thing::new(application, format!("{prefix}{name}"));
+        thing.metadata.labels.insert("name".into(), name.into());
+        storage.create(thing).await.unwrap();
+    }
+
+    let names = |things: Vec<Thing<Internal>>| {
+        things
+            .into_iter()
+            .map(|thing| thing.metadata.name)
+            .filter_map(|name| name.strip_prefix(prefix).map(ToString::to_string))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        names(storage.list(application).await.unwrap()),
+        vec!["a", "b", "c"]
+    );
+    assert_eq!(
+        names(
+            storage
+                .list_with(
+                    application,
+                    &ListOptions::default()
+                        .with_after(format!("{prefix}a"))
+                        .with_limit(1)
+                )
+                .await
+                .unwrap()
+        ),
+        vec!["b"]
+    );
+    assert_eq!(
+        names(
+            storage
+                .list_with(
+                    application,
+                    &ListOptions::default().with_selector("name!=b".parse().unwrap())
+                )
+                .await
+                .unwrap()
+        ),
+        vec!["a", "c"]
+    );
+}
@@ -1,6 +1,7 @@
 pub mod postgres;
 
 use crate::{
+    config::check::{Check, Checker},
     model::WakerReason,
     processor::{sink::Sink, Event, Message},
     service::Id,
@@ -38,6 +39,18 @@ pub struct Config<W: Waker, S: Sink> {
     pub sink: S::Config,
 }
 
+impl<W: Waker, S: Sink> Check for Config<W, S>
+where
+    W::Config: Check,
+    S::Config: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("waker", &self.waker)
+            .field("sink", &self.sink);
+    }
+}
+
 /// Process wakeups
 pub struct Processor<W: Waker, S: Sink> {
     waker: W,
@@ -1,12 +1,15 @@
-use crate::model::WakerReason;
+use crate::applications::Applications;
+use crate::config::check::{Check, Checker};
+use crate::model::{Internal, WakerReason};
 use crate::service::Id;
-use crate::storage::postgres::Data;
 use crate::waker::TargetId;
 use anyhow::bail;
 use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
 use deadpool_postgres::{Client, Transaction};
 use drogue_bazaar::db::postgres;
 use postgres_types::{Json, Type};
+use serde_json::Value;
 use std::future::Future;
 use std::time::Duration;
 use tokio::time::MissedTickBehavior;
@@ -16,13 +19,21 @@ use uuid::Uuid;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Config {
-    pub application: Option<String>,
+    /// Only wake things of these applications, see [`Applications`].
+    #[serde(default, alias = "application")]
+    pub applications: Applications,
     #[serde(with = "humantime_serde")]
     #[serde(default = "default::check_duration")]
     pub check_period: Duration,
     pub postgres: postgres::Config,
 }
 
+impl Check for Config {
+    fn check(&self, checker: &mut Checker) {
+        checker.field("postgres", &self.postgres);
+    }
+}
+
 pub mod default {
     use super::*;
 
@@ -32,7 +43,7 @@ pub mod default {
 }
 
 pub struct Waker {
-    application: Option<String>,
+    applications: Applications,
     check_period: Duration,
     pool: deadpool_postgres::Pool,
 }
@@ -47,7 +58,7 @@ impl super::Waker for Waker {
         Ok(Self {
             pool,
             check_period: config.check_period,
-            application: config.application,
+            applications: config.applications,
         })
     }
 
@@ -71,7 +82,10 @@ impl super::Waker for Waker {
                     log::warn!("Failed to prepare for tick: {err}");
                 }
                 Ok(con) => {
-                    if let Err(err) = WakerRun::new(con, &stmt, &self.application, &f).run().await {
+                    if let Err(err) = WakerRun::new(con, &stmt, &self.applications, &f)
+                        .run()
+                        .await
+                    {
                         // FIXME: map to liveness status
                         log::warn!("Failed to tick: {err}");
                     }
@@ -83,22 +97,29 @@ impl super::Waker for Waker {
 
 impl Waker {
     fn build_statement(&self) -> (String, Vec<Type>) {
-        let mut types = vec![];
-
-        let and_application = match self.application.is_some() {
-            true => {
-                types.push(Type::VARCHAR);
-                r#"
-            AND
-                APPLICATION = $1
-"#
+        // the key of the last processed thing
+        let mut types = vec![Type::TIMESTAMPTZ, Type::VARCHAR, Type::VARCHAR];
+
+        let and_application = match self.applications.is_empty() {
+            false => {
+                types.extend([Type::VARCHAR_ARRAY, Type::VARCHAR_ARRAY]);
+                format!(
+                    r#"
+    AND
+        {}
+"#,
+                    self.applications.sql_condition("APPLICATION", 4)
+                )
             }
-            false => "",
+            true => String::new(),
         };
 
         // We retrieve the next thing, and lock it for an update. We only fetch one, and skip
         // all locked rows. So we can scale up processing to some degree. Once we successfully
         // scheduled the wakeup (e.g. sending that to Kafka) we can update the record and commit.
+        //
+        // Within a run, we continue after the last processed thing (keyset pagination), which
+        // can be served from the waker index, without scanning the rows processed before.
 
         let stmt = format!(
             r#"
@@ -107,6 +128,7 @@ SELECT
     NAME,
     UID,
     RESOURCE_VERSION,
+    WAKER,
     DATA
 
 FROM
@@ -114,10 +136,12 @@ FROM
 
 WHERE
         WAKER <= NOW()
+    AND
+        (WAKER, APPLICATION, NAME) > ($1, $2, $3)
 {and_application}
 
 ORDER BY
-    WAKER ASC 
+    WAKER, APPLICATION, NAME
 
 LIMIT 1
 FOR UPDATE SKIP LOCKED
@@ -135,7 +159,9 @@ where
 {
     con: Client,
     stmt: &'r (String, Vec<Type>),
-    application: &'r Option<String>,
+    applications: &'r Applications,
+    /// The key of the last processed thing.
+    last: (DateTime<Utc>, String, String),
 
     f: &'r F,
 }
@@ -148,18 +174,19 @@ where
     fn new(
         con: Client,
         stmt: &'r (String, Vec<Type>),
-        application: &'r Option<String>,
+        applications: &'r Applications,
         f: &'r F,
     ) -> Self {
         Self {
             con,
             stmt,
-            application,
+            applications,
+            last: (Utc.timestamp(0, 0), String::new(), String::new()),
             f,
         }
     }
 
-    #[instrument(level = "debug", skip_all, fields(application=self.application), err)]
+    #[instrument(level = "debug", skip_all, fields(applications=%self.applications), err)]
     async fn run(mut self) -> anyhow::Result<()> {
         let stmt = self
             .con
@@ -179,9 +206,14 @@ where
     async fn tick_next(&mut self, stmt: &Statement) -> anyhow::Result<bool> {
         let tx = self.con.build_transaction().start().await?;
 
-        let row = match &self.application {
-            Some(application) => tx.query_opt(stmt, &[application]).await,
-            None => tx.query_opt(stmt, &[]).await,
+        let (waker, application, thing) = &self.last;
+        let row = match self.applications.is_empty() {
+            true => tx.query_opt(stmt, &[waker, application, thing]).await,
+            false => {
+                let (allow, deny) = self.applications.sql_params();
+                tx.query_opt(stmt, &[waker, application, thing, &allow, &deny])
+                    .await
+            }
         }?;
 
         Ok(match row {
@@ -190,10 +222,18 @@ where
                 let thing: String = row.try_get("NAME")?;
                 let uid: Uuid = row.try_get("UID")?;
                 let resource_version: Uuid = row.try_get("RESOURCE_VERSION")?;
-                let data = row.try_get::<_, Json<Data>>("DATA")?.0;
-
-                let reasons = data
-                    .internal
+                let waker: DateTime<Utc> = row.try_get("WAKER")?;
+                // We only process the internal section, and keep the rest of the data as-is. This
+                // also keeps script references intact.
+                let data = row.try_get::<_, Json<Value>>("DATA")?.0;
+                let internal = data
+                    .get("internal")
+                    .filter(|internal| !internal.is_null())
+                    .cloned()
+                    .map(serde_json::from_value::<Internal>)
+                    .transpose()?;
+
+                let reasons = internal
                     .as_ref()
                     .filter(|i| i.waker.when.is_some())
                     .map(|i| &i.waker.why)
@@ -216,8 +256,9 @@ where
                 )
                 .await?;
 
-                // clear waker
+                // clear waker, and continue after this thing
 
+                self.last = (waker, application.clone(), thing.clone());
                 Self::clear_waker(tx, application, thing, uid, resource_version, data).await?;
 
                 // done with this entry
@@ -234,13 +275,13 @@ where
         thing: String,
         uid: Uuid,
         resource_version: Uuid,
-        mut data: Data,
+        mut data: Value,
     ) -> anyhow::Result<()> {
         // we clear the waker and commit the transaction. The oplock should hold, as we have locked
         // the record "for update".
 
-        if let Some(internal) = &mut data.internal {
-            internal.waker = Default::default();
+        if let Some(Value::Object(internal)) = data.get_mut("internal") {
+            internal.remove("waker");
         }
 
         let stmt = tx
@@ -189,6 +189,8 @@ switch (context.action) {
             &AnnotationsUpdater::new("io.drogue/group", "foo/bar/baz"),
             &UpdateOptions {
                 ignore_unclean_inbox: false,
+                resource_version: None,
+                unmodified_since: None,
             },
         )
         .await?;
@@ -1,4 +1,5 @@
 mod hierarchy;
 mod processor;
+mod registry;
 mod service;
 mod waker;
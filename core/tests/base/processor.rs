@@ -1,4 +1,5 @@
-use crate::common::mock::{setup, RunningContext};
+use crate::common::mock::{setup, Builder, RunningContext};
+use chrono::{Duration, TimeZone, Utc};
 use drogue_doppelgaenger_core::{
     processor::{Event, Message},
     service::{Id, Service},
@@ -63,3 +64,35 @@ async fn test_process() {
     // shutdown runner
     runner.shutdown().await.unwrap();
 }
+
+#[tokio::test]
+async fn test_process_event_time() {
+    let RunningContext {
+        service, runner, ..
+    } = Builder::new().event_time(true).setup().run(false);
+
+    let id = Id::new("default", "thing1");
+    service.create(id.make_thing()).await.unwrap();
+
+    // replay a recorded log of events
+
+    let start = Utc.ymd(2022, 1, 1).and_hms(12, 0, 0);
+    for (offset, value) in [(0, 1), (5, 2), (10, 3)] {
+        let mut event = Event::new(
+            "default",
+            "thing1",
+            Message::report_state(true).state("foo", value),
+        );
+        event.timestamp = start + Duration::seconds(offset);
+        runner.send_wait(event).await.unwrap();
+    }
+
+    // the outcome carries the original timestamps
+
+    let thing = service.get(&id).await.unwrap().expect("Thing to be found");
+    let foo = thing.reported_state.get("foo").unwrap();
+    assert_eq!(foo.value, json!(3));
+    assert_eq!(foo.last_update, start + Duration::seconds(10));
+
+    runner.shutdown().await.unwrap();
+}
@@ -0,0 +1,98 @@
+use crate::common::mock::{setup, Context};
+use drogue_doppelgaenger_core::{
+    registry::{Device, Synchronizer, ANNOTATION_REGISTRY},
+    service::Service,
+};
+use drogue_doppelgaenger_model::Thing;
+
+fn device(name: &str) -> Device {
+    Device {
+        name: name.to_string(),
+        labels: [("foo".to_string(), "bar".to_string())].into(),
+    }
+}
+
+#[tokio::test]
+async fn missing_application_keeps_things() {
+    let Context { service, .. } = setup();
+    let synchronizer = Synchronizer::new("registry", "default", service);
+    let service = synchronizer.service();
+
+    synchronizer
+        .sync(Some(vec![device("device1"), device("device2")]))
+        .await
+        .unwrap();
+    assert_eq!(
+        service
+            .list_with("default", &Default::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // a missing application must not delete all things
+    assert!(synchronizer.sync(None).await.is_err());
+    assert_eq!(
+        service
+            .list_with("default", &Default::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+
+    // a device removed from the registry is deleted
+    synchronizer
+        .sync(Some(vec![device("device1")]))
+        .await
+        .unwrap();
+    let things = service
+        .list_with("default", &Default::default())
+        .await
+        .unwrap();
+    assert_eq!(things.len(), 1);
+    assert_eq!(things[0].metadata.name, "device1");
+    assert_eq!(
+        things[0].metadata.annotations.get(ANNOTATION_REGISTRY),
+        Some(&"registry".to_string())
+    );
+}
+
+#[tokio::test]
+async fn unmanaged_things_are_kept() {
+    let Context { service, .. } = setup();
+    let synchronizer = Synchronizer::new("registry", "default", service);
+    let service = synchronizer.service();
+
+    service
+        .create(Thing::new("default", "device1"))
+        .await
+        .unwrap();
+
+    synchronizer
+        .sync(Some(vec![device("device1")]))
+        .await
+        .unwrap();
+
+    // the existing thing is neither taken over, nor modified
+    let thing = service
+        .get(&("default", "device1").into())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(thing.metadata.labels.is_empty());
+    assert!(thing
+        .metadata
+        .annotations
+        .get(ANNOTATION_REGISTRY)
+        .is_none());
+
+    // and not deleted, when the device is gone
+    synchronizer.sync(Some(vec![])).await.unwrap();
+    assert!(service
+        .get(&("default", "device1").into())
+        .await
+        .unwrap()
+        .is_some());
+}
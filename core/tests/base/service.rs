@@ -1,10 +1,19 @@
 use crate::common::mock::{setup, Context};
-use drogue_doppelgaenger_core::service::{Service, UpdateOptions};
-use drogue_doppelgaenger_model::{Metadata, Thing};
-use std::collections::BTreeMap;
+use chrono::Duration;
+use drogue_doppelgaenger_core::{
+    model::Internal,
+    service::{BatchOperation, Error, Service, UpdateOptions},
+    storage::{self, ListOptions},
+};
+use drogue_doppelgaenger_model::{JsonSchema, Metadata, ReportedFeature, Schema, Thing};
+use futures::TryStreamExt;
+use serde_json::json;
+use std::{collections::BTreeMap, sync::Arc};
 
 const OPTS: UpdateOptions = UpdateOptions {
     ignore_unclean_inbox: true,
+    resource_version: None,
+    unmodified_since: None,
 };
 
 #[tokio::test]
@@ -31,6 +40,222 @@ async fn basic() {
     assert_eq!(notifier.drain().await, vec![thing]);
 }
 
+#[tokio::test]
+async fn list_paging() {
+    let Context { service, .. } = setup();
+
+    for name in ["thing3", "thing1", "thing2"] {
+        service.create(Thing::new("default", name)).await.unwrap();
+    }
+
+    let names = |things: Vec<Thing<_>>| {
+        things
+            .into_iter()
+            .map(|thing| thing.metadata.name)
+            .collect::<Vec<_>>()
+    };
+
+    let page = service
+        .list_with("default", &ListOptions::default().with_limit(2))
+        .await
+        .unwrap();
+    assert_eq!(names(page), vec!["thing1", "thing2"]);
+
+    let page = service
+        .list_with(
+            "default",
+            &ListOptions::default().with_after("thing2").with_limit(2),
+        )
+        .await
+        .unwrap();
+    assert_eq!(names(page), vec!["thing3"]);
+
+    assert_eq!(service.list("default").await.unwrap().len(), 3);
+    assert!(service.list("other").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn get_revision() {
+    let Context { service, .. } = setup();
+
+    let thing = service
+        .create(Thing::new("default", "thing1"))
+        .await
+        .unwrap();
+    let id = ("default", "thing1").into();
+
+    // by default, only the current generation is available
+    assert_eq!(service.get_revision(&id, 1).await.unwrap(), Some(thing));
+    assert_eq!(service.get_revision(&id, 2).await.unwrap(), None);
+    assert_eq!(
+        service
+            .get_revision(&("default", "thing2").into(), 1)
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn query() {
+    let Context { service, .. } = setup();
+
+    for (name, temperature) in [("thing1", 20), ("thing2", 35), ("thing3", 40)] {
+        let mut thing = Thing::new("default", name);
+        thing.reported_state.insert(
+            "temperature".into(),
+            ReportedFeature::now(temperature.into()),
+        );
+        service.create(thing).await.unwrap();
+    }
+
+    let names = |things: Vec<Thing<Internal>>| {
+        things
+            .into_iter()
+            .map(|thing| thing.metadata.name)
+            .collect::<Vec<_>>()
+    };
+
+    let query = "reportedState.temperature > 30".parse().unwrap();
+    assert_eq!(
+        names(
+            service
+                .query("default", &query, &Default::default())
+                .await
+                .unwrap()
+        ),
+        vec!["thing2", "thing3"]
+    );
+    // the limit applies to the matching things
+    assert_eq!(
+        names(
+            service
+                .query("default", &query, &ListOptions::default().with_limit(1))
+                .await
+                .unwrap()
+        ),
+        vec!["thing2"]
+    );
+}
+
+#[tokio::test]
+async fn stats() {
+    let Context { service, .. } = setup();
+
+    for (name, region) in [("thing1", "eu"), ("thing2", "eu"), ("thing3", "us")] {
+        let mut thing = Thing::new("default", name);
+        thing.metadata.labels.insert("region".into(), region.into());
+        service.create(thing).await.unwrap();
+    }
+    service
+        .create(Thing::new("default", "thing4"))
+        .await
+        .unwrap();
+
+    let stats = service.stats("default").await.unwrap();
+
+    assert_eq!(stats.things, 4);
+    assert_eq!(
+        stats.labels,
+        BTreeMap::from([(
+            "region".to_string(),
+            BTreeMap::from([("eu".to_string(), 2), ("us".to_string(), 1)])
+        )])
+    );
+    assert_eq!(stats.failed_desired_states, 0);
+    assert_eq!(stats.pending_outbox, 0);
+
+    assert_eq!(service.stats("other").await.unwrap(), Default::default());
+}
+
+#[tokio::test]
+async fn export_import() {
+    let Context {
+        service,
+        mut notifier,
+        ..
+    } = setup();
+
+    // more than a single page
+    for i in 0..150 {
+        service
+            .create(Thing::new("default", format!("thing{i:03}")))
+            .await
+            .unwrap();
+    }
+    notifier.drain().await;
+
+    let service = Arc::new(service);
+    let things = service
+        .clone()
+        .export("default".to_string())
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+
+    assert_eq!(things.len(), 150);
+    assert_eq!(things[0].metadata.name, "thing000");
+    assert_eq!(things[149].metadata.name, "thing149");
+
+    assert!(service
+        .delete(&("default", "thing000").into(), None)
+        .await
+        .unwrap());
+    notifier.drain().await;
+
+    let imported = service.import(things[0].clone()).await.unwrap();
+    assert_eq!(imported.metadata.name, "thing000");
+    assert_eq!(notifier.drain().await, vec![imported]);
+
+    // existing things are not replaced
+    assert!(service.import(things[1].clone()).await.is_err());
+}
+
+#[tokio::test]
+async fn batch() {
+    let Context {
+        service,
+        mut notifier,
+        ..
+    } = setup();
+
+    service
+        .create(Thing::new("default", "thing1"))
+        .await
+        .unwrap();
+    notifier.drain().await;
+
+    let mut update = Thing::new("default", "thing1");
+    update.metadata.labels.insert("foo".into(), "bar".into());
+
+    let results = service
+        .apply_batch(
+            vec![
+                BatchOperation::Create(Thing::new("default", "thing2")),
+                BatchOperation::Update(update),
+                BatchOperation::Create(Thing::new("default", "thing1")),
+                BatchOperation::Update(Thing::new("default", "thing3")),
+            ],
+            &OPTS,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].as_ref().unwrap().metadata.name, "thing2");
+    let updated = results[1].as_ref().unwrap();
+    assert_eq!(updated.metadata.generation, Some(2));
+    assert_eq!(
+        updated.metadata.labels.get("foo").map(String::as_str),
+        Some("bar")
+    );
+    assert!(results[2].is_err());
+    assert!(results[3].is_err());
+
+    assert_eq!(service.list("default").await.unwrap().len(), 2);
+    assert_eq!(notifier.drain().await.len(), 2);
+}
+
 #[tokio::test]
 async fn delete() {
     let Context {
@@ -173,3 +398,132 @@ async fn update_no_change() {
 
     assert_eq!(thing_1, thing);
 }
+
+#[tokio::test]
+async fn update_preconditions() {
+    let Context { service, .. } = setup();
+
+    service
+        .create(Thing::new("default", "thing1"))
+        .await
+        .unwrap();
+
+    let id = ("default", "thing1").into();
+    let thing = service.get(&id).await.unwrap().unwrap();
+    let modified = thing.internal.as_ref().and_then(|i| i.modified);
+    assert!(modified.is_some());
+
+    let mut update = thing.clone();
+    update.metadata.labels.insert("foo".into(), "bar".into());
+
+    // outdated resource version
+    let result = service
+        .update(
+            &id,
+            &update,
+            &UpdateOptions {
+                resource_version: Some("outdated".to_string()),
+                ..OPTS
+            },
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::Storage(storage::Error::PreconditionFailed))
+    ));
+
+    // modified afterwards
+    let result = service
+        .update(
+            &id,
+            &update,
+            &UpdateOptions {
+                unmodified_since: modified.map(|modified| modified - Duration::seconds(10)),
+                ..OPTS
+            },
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(Error::Storage(storage::Error::PreconditionFailed))
+    ));
+
+    // current resource version
+    let updated = service
+        .update(
+            &id,
+            &update,
+            &UpdateOptions {
+                resource_version: thing.metadata.resource_version.clone(),
+                unmodified_since: modified,
+                ..OPTS
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(updated.metadata.generation, Some(2));
+    assert!(updated.internal.and_then(|i| i.modified) >= modified);
+}
+
+#[tokio::test]
+async fn dry_run() {
+    let Context {
+        service,
+        mut notifier,
+        ..
+    } = setup();
+
+    let mut thing = Thing::new("default", "thing1");
+    thing.schema = Some(Schema::Json(JsonSchema::Draft7(json!({
+        "type": "object",
+        "properties": {
+            "reportedState": {
+                "type": "object",
+                "properties": {
+                    "temperature": { "type": "number" },
+                },
+            },
+        },
+    }))));
+    let thing = service.create(thing).await.unwrap();
+    notifier.drain().await;
+
+    // valid change
+
+    let mut new_thing = thing.clone().strip_internal();
+    new_thing
+        .reported_state
+        .insert("temperature".into(), ReportedFeature::now(json!(21.5)));
+    let outcome = service.dry_run(new_thing.clone()).await.unwrap();
+    assert_eq!(
+        outcome.new_thing.reported_state["temperature"].value,
+        json!(21.5)
+    );
+
+    // invalid change
+
+    new_thing
+        .reported_state
+        .insert("temperature".into(), ReportedFeature::now(json!("warm")));
+    let result = service.dry_run(new_thing).await;
+    assert!(matches!(result, Err(Error::Machine(_))));
+
+    // new thing
+
+    let outcome = service
+        .dry_run(Thing::new("default", "thing2"))
+        .await
+        .unwrap();
+    assert_eq!(outcome.new_thing.metadata.name, "thing2");
+
+    // nothing got stored, or sent
+
+    let id = ("default", "thing1").into();
+    assert_eq!(service.get(&id).await.unwrap(), Some(thing));
+    assert!(service
+        .get(&("default", "thing2").into())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(notifier.drain().await.is_empty());
+}
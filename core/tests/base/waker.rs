@@ -4,7 +4,7 @@ use drogue_doppelgaenger_core::{
     model::WakerReason,
     service::{Id, Service},
 };
-use drogue_doppelgaenger_model::{Code, Reconciliation, Thing, Timer};
+use drogue_doppelgaenger_model::{Code, Reconciliation, Thing, Timer, ANNOTATION_EXPIRES_AFTER};
 use indexmap::IndexMap;
 use serde_json::json;
 use std::{collections::BTreeSet, time::Duration};
@@ -193,3 +193,30 @@ context.newState.reportedState["timer"] = { value, lastUpdate };
     // shutdown runner
     runner.shutdown().await.unwrap();
 }
+
+#[tokio::test]
+async fn test_expiry() {
+    let RunningContext {
+        service, runner, ..
+    } = setup().run(true);
+
+    let id = Id::new("default", "test_expiry");
+    let mut thing = id.make_thing();
+    thing
+        .metadata
+        .annotations
+        .insert(ANNOTATION_EXPIRES_AFTER.to_string(), "2s".to_string());
+    let thing = service.create(thing).await.unwrap();
+
+    let wakeup = thing.internal.unwrap().waker;
+    assert_eq!(wakeup.why, BTreeSet::from([WakerReason::Expiry]));
+
+    // wait until the thing expired
+
+    tokio::time::sleep_until(tokio::time::Instant::now() + Duration::from_secs(2 + 2)).await;
+
+    assert_eq!(service.get(&id).await.unwrap(), None);
+
+    // shutdown runner
+    runner.shutdown().await.unwrap();
+}
@@ -13,7 +13,7 @@ use drogue_doppelgaenger_core::{
     notifier::Notifier,
     processor::{sink::Sink, source::Source, Event, Processor},
     service::{DefaultService, Id},
-    storage::{Error, Storage},
+    storage::{Error, ListOptions, Storage},
     waker::{self, TargetId, Waker},
     Preconditions,
 };
@@ -70,6 +70,31 @@ impl Storage for MockStorage {
         return Ok(self.things.read().await.get(name).cloned());
     }
 
+    async fn list_with(
+        &self,
+        application: &str,
+        opts: &ListOptions,
+    ) -> Result<Vec<Thing<Internal>>, Error<Self::Error>> {
+        if application != self.application {
+            return Ok(vec![]);
+        }
+
+        Ok(self
+            .things
+            .read()
+            .await
+            .values()
+            .filter(|thing| {
+                opts.after
+                    .as_ref()
+                    .map_or(true, |after| &thing.metadata.name > after)
+            })
+            .filter(|thing| opts.selector.matches(&thing.metadata.labels))
+            .take(opts.limit.map_or(usize::MAX, |limit| limit as usize))
+            .cloned()
+            .collect())
+    }
+
     async fn create(
         &self,
         mut thing: Thing<Internal>,
@@ -566,12 +591,14 @@ impl Waker for MockWaker {
 
 pub struct Builder {
     sink_failure: Failure<(), anyhow::Error>,
+    event_time: bool,
 }
 
 impl Builder {
     pub fn new() -> Self {
         Self {
             sink_failure: Default::default(),
+            event_time: false,
         }
     }
 
@@ -580,6 +607,11 @@ impl Builder {
         self
     }
 
+    pub fn event_time(mut self, event_time: bool) -> Self {
+        self.event_time = event_time;
+        self
+    }
+
     pub fn setup(self) -> Context {
         let _ = env_logger::builder().is_test(true).try_init();
 
@@ -600,7 +632,8 @@ impl Builder {
                 command_sink.clone(),
             ),
             source.clone(),
-        );
+        )
+        .with_event_time(self.event_time);
 
         let waker = waker::Processor::new(waker, sink.clone());
 
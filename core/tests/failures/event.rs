@@ -167,6 +167,8 @@ async fn test_resend_event() {
         failures([false, true]),
         UpdateOptions {
             ignore_unclean_inbox: false,
+            resource_version: None,
+            unmodified_since: None,
         },
         Ok((1, vec![1])),
         {
@@ -190,6 +192,8 @@ async fn test_resend_event_2() {
         failures([false, true, true, false]),
         UpdateOptions {
             ignore_unclean_inbox: false,
+            resource_version: None,
+            unmodified_since: None,
         },
         Ok((1, vec![1])),
         {
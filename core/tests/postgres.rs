@@ -0,0 +1,95 @@
+//! Tests running against a PostgreSQL database.
+//!
+//! The tests are ignored by default, as they require a database. The connection is configured
+//! using `TEST_POSTGRES__*`, e.g.:
+//!
+//! ```shell
+//! TEST_POSTGRES__DB__HOST=localhost TEST_POSTGRES__DB__USER=admin TEST_POSTGRES__DB__PASSWORD=admin123456 \
+//! cargo test -p drogue-doppelgaenger-core --test tests -- --ignored postgres
+//! ```
+
+use drogue_bazaar::{core::config::ConfigFromEnv, db::postgres as db};
+use drogue_doppelgaenger_core::{
+    model::Internal,
+    storage::{postgres, ListOptions, Storage},
+};
+use drogue_doppelgaenger_model::{ReportedFeature, Thing};
+use serde_json::json;
+
+async fn storage() -> postgres::Storage {
+    let config = db::Config::from_env_prefix("TEST_POSTGRES").unwrap();
+    postgres::migration::run(&config).await.unwrap();
+
+    postgres::Storage::from_config(&postgres::Config {
+        applications: Default::default(),
+        postgres: config,
+        cold: None,
+        replica: None,
+        outbox: false,
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+#[ignore = "requires a PostgreSQL database"]
+async fn query() {
+    let storage = storage().await;
+    // use a fresh application, as the database is shared
+    let application = uuid::Uuid::new_v4().to_string();
+
+    for (name, temperature) in [("thing1", 21), ("thing2", 35), ("thing3", 42)] {
+        let mut thing = Thing::<Internal>::new(&application, name);
+        thing.reported_state.insert(
+            "temperature".into(),
+            ReportedFeature::now(json!(temperature)),
+        );
+        storage.create(thing).await.unwrap();
+    }
+    storage
+        .create(Thing::new(&application, "thing4"))
+        .await
+        .unwrap();
+
+    let names = |things: Vec<Thing<Internal>>| {
+        things
+            .into_iter()
+            .map(|thing| thing.metadata.name)
+            .collect::<Vec<_>>()
+    };
+
+    let query = "reportedState.temperature > 30".parse().unwrap();
+    assert_eq!(
+        names(
+            storage
+                .query(&application, &query, &ListOptions::default())
+                .await
+                .unwrap()
+        ),
+        vec!["thing2", "thing3"]
+    );
+
+    let query = "reportedState.temperature > 30,reportedState.temperature < 40"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        names(
+            storage
+                .query(&application, &query, &ListOptions::default())
+                .await
+                .unwrap()
+        ),
+        vec!["thing2"]
+    );
+
+    // a missing feature doesn't match
+    let query = "syntheticState.temperature > 30".parse().unwrap();
+    assert_eq!(
+        names(
+            storage
+                .query(&application, &query, &ListOptions::default())
+                .await
+                .unwrap()
+        ),
+        Vec::<String>::new()
+    );
+}
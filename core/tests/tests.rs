@@ -3,3 +3,4 @@ extern crate core;
 mod base;
 mod common;
 mod failures;
+mod postgres;
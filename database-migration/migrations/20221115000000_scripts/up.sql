@@ -0,0 +1,10 @@
+CREATE TABLE scripts (
+    -- the SHA-256 hash of the code, hex encoded
+    HASH VARCHAR(64) NOT NULL,
+    CODE TEXT NOT NULL,
+
+    CREATION_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    -- constraints
+    PRIMARY KEY (HASH)
+);
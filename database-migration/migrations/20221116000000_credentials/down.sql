@@ -0,0 +1,2 @@
+DROP TABLE users;
+DROP TABLE api_keys;
\ No newline at end of file
@@ -0,0 +1,23 @@
+CREATE TABLE api_keys (
+    -- the SHA-256 hash of the API key, hex encoded
+    HASH VARCHAR(64) NOT NULL,
+    USER_ID VARCHAR(256) NOT NULL,
+    ROLES VARCHAR(64)[] NOT NULL DEFAULT '{}',
+
+    CREATION_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    -- constraints
+    PRIMARY KEY (HASH)
+);
+
+CREATE TABLE users (
+    USERNAME VARCHAR(256) NOT NULL,
+    -- the password hash, in the PHC string format (e.g. argon2)
+    PASSWORD_HASH VARCHAR(256) NOT NULL,
+    ROLES VARCHAR(64)[] NOT NULL DEFAULT '{}',
+
+    CREATION_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    -- constraints
+    PRIMARY KEY (USERNAME)
+);
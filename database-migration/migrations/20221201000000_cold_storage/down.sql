@@ -0,0 +1 @@
+DROP TABLE cold_things;
@@ -0,0 +1,11 @@
+CREATE TABLE cold_things (
+    NAME VARCHAR(256) NOT NULL,
+    APPLICATION VARCHAR(64) NOT NULL,
+
+    -- the object in the cold storage, holding the thing
+    OBJECT uuid NOT NULL,
+    OFFLOAD_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL,
+
+    -- constraints
+    PRIMARY KEY (NAME, APPLICATION)
+);
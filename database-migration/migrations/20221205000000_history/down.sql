@@ -0,0 +1,4 @@
+DROP TRIGGER things_archive_delete ON things;
+DROP TRIGGER things_archive_update ON things;
+DROP FUNCTION archive_thing;
+DROP TABLE things_history;
@@ -0,0 +1,50 @@
+CREATE TABLE things_history (
+    NAME VARCHAR(256) NOT NULL,
+    APPLICATION VARCHAR(64) NOT NULL,
+    UID uuid NOT NULL,
+    CREATION_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL,
+    DELETION_TIMESTAMP TIMESTAMP WITH TIME ZONE NULL,
+
+    RESOURCE_VERSION uuid NOT NULL,
+    GENERATION BIGINT NOT NULL,
+
+    ANNOTATIONS JSON,
+    LABELS JSONB,
+
+    -- data, with unresolved script references
+    DATA JSON,
+
+    -- the time the generation got replaced, or deleted
+    ARCHIVE_TIMESTAMP TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+
+    -- constraints
+    PRIMARY KEY (NAME, APPLICATION, UID, GENERATION)
+);
+
+-- archive the previous generation of a thing, when it gets updated or deleted
+CREATE FUNCTION archive_thing() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO things_history (
+        NAME, APPLICATION, UID, CREATION_TIMESTAMP, DELETION_TIMESTAMP,
+        RESOURCE_VERSION, GENERATION, ANNOTATIONS, LABELS, DATA
+    ) VALUES (
+        OLD.NAME, OLD.APPLICATION, OLD.UID, OLD.CREATION_TIMESTAMP, OLD.DELETION_TIMESTAMP,
+        OLD.RESOURCE_VERSION, OLD.GENERATION, OLD.ANNOTATIONS, OLD.LABELS, OLD.DATA
+    )
+    -- offloading and rehydrating the same generation
+    ON CONFLICT DO NOTHING;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+-- updates which don't change the generation (e.g. of the waker) are not archived
+CREATE TRIGGER things_archive_update
+    AFTER UPDATE ON things
+    FOR EACH ROW
+    WHEN (OLD.GENERATION IS DISTINCT FROM NEW.GENERATION)
+    EXECUTE FUNCTION archive_thing();
+
+CREATE TRIGGER things_archive_delete
+    AFTER DELETE ON things
+    FOR EACH ROW
+    EXECUTE FUNCTION archive_thing();
@@ -0,0 +1 @@
+DROP INDEX THINGS_DATA_IDX;
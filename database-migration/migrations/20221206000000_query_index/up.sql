@@ -0,0 +1,2 @@
+-- index the data of things, for querying things by their state
+CREATE INDEX THINGS_DATA_IDX ON things USING GIN ((DATA::JSONB) jsonb_path_ops);
@@ -0,0 +1,2 @@
+DROP INDEX THINGS_ANNOTATIONS_IDX;
+DROP INDEX THINGS_WAKER_IDX;
@@ -0,0 +1,4 @@
+-- index scheduled wakers, in the order the waker processes them
+CREATE INDEX THINGS_WAKER_IDX ON things (WAKER, APPLICATION, NAME) WHERE WAKER IS NOT NULL;
+-- index annotations, for looking up things by their annotations
+CREATE INDEX THINGS_ANNOTATIONS_IDX ON things USING GIN ((ANNOTATIONS::JSONB));
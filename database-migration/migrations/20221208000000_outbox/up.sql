@@ -0,0 +1,11 @@
+-- events of things, written in the same statement as the thing, and relayed to the event sink
+CREATE TABLE outbox (
+    ID BIGSERIAL NOT NULL,
+    APPLICATION VARCHAR(64) NOT NULL,
+    NAME VARCHAR(256) NOT NULL,
+    UID uuid NOT NULL,
+    CREATED TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    EVENT JSONB NOT NULL,
+
+    PRIMARY KEY (ID)
+);
@@ -0,0 +1 @@
+DROP INDEX OUTBOX_THING_IDX;
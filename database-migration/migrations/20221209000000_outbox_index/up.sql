@@ -0,0 +1,2 @@
+-- index outbox events by thing, for looking up the pending events of a thing
+CREATE INDEX OUTBOX_THING_IDX ON outbox (APPLICATION, NAME);
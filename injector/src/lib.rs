@@ -1,9 +1,22 @@
 use drogue_bazaar::app::{Startup, StartupExt};
 use drogue_doppelgaenger_core::{
+    config::check::{self, Check, Checker},
     injector,
-    processor::sink::{kafka, Sink},
+    processor::sink::{self, Sink},
 };
 
+#[cfg(not(any(feature = "nats", feature = "amqp", feature = "pubsub")))]
+type EventSink = sink::kafka::Sink;
+
+#[cfg(feature = "nats")]
+type EventSink = sink::nats::Sink;
+
+#[cfg(all(feature = "amqp", not(feature = "nats")))]
+type EventSink = sink::amqp::Sink;
+
+#[cfg(all(feature = "pubsub", not(any(feature = "nats", feature = "amqp"))))]
+type EventSink = sink::pubsub::Sink;
+
 #[derive(Debug, serde::Deserialize)]
 pub struct Config<Si>
 where
@@ -13,9 +26,25 @@ where
     pub sink: Si::Config,
 }
 
-pub async fn run(config: Config<kafka::Sink>, startup: &mut dyn Startup) -> anyhow::Result<()> {
-    let sink = kafka::Sink::from_config(config.sink)?;
+impl<Si: Sink> Check for Config<Si>
+where
+    Si::Config: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("injector", &self.injector)
+            .field("sink", &self.sink);
+    }
+}
+
+pub async fn run(config: Config<EventSink>, startup: &mut dyn Startup) -> anyhow::Result<()> {
+    let sink = EventSink::from_config(config.sink)?;
     startup.spawn(config.injector.run(sink));
 
     Ok(())
 }
+
+/// Check the configuration, without running the injector.
+pub async fn check(mode: check::Mode) -> anyhow::Result<()> {
+    check::run::<Config<EventSink>>(mode).await
+}
@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+
+/// A condition of a thing, indicating an abnormal state.
+///
+/// A condition is present as long as the state persists, and gets removed once it is resolved.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    /// The time the condition was first raised.
+    pub last_transition_time: DateTime<Utc>,
+    /// A machine readable reason.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// A human readable message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+pub trait ConditionsExt {
+    /// Set a condition, keeping the transition time if the condition was already present.
+    fn set_condition<R, M>(&mut self, r#type: &str, reason: R, message: M)
+    where
+        R: Into<Option<String>>,
+        M: Into<Option<String>>,
+    {
+        self.set_condition_at(r#type, reason, message, Utc::now())
+    }
+
+    /// Set a condition, using the provided time as transition time, if the condition is new.
+    fn set_condition_at<R, M>(&mut self, r#type: &str, reason: R, message: M, now: DateTime<Utc>)
+    where
+        R: Into<Option<String>>,
+        M: Into<Option<String>>;
+
+    /// Clear a condition, returns `true` if the condition was present.
+    fn clear_condition(&mut self, r#type: &str) -> bool;
+}
+
+impl ConditionsExt for BTreeMap<String, Condition> {
+    fn set_condition_at<R, M>(&mut self, r#type: &str, reason: R, message: M, now: DateTime<Utc>)
+    where
+        R: Into<Option<String>>,
+        M: Into<Option<String>>,
+    {
+        let reason = reason.into();
+        let message = message.into();
+
+        match self.get_mut(r#type) {
+            Some(condition) => {
+                condition.reason = reason;
+                condition.message = message;
+            }
+            None => {
+                self.insert(
+                    r#type.to_string(),
+                    Condition {
+                        last_transition_time: now,
+                        reason,
+                        message,
+                    },
+                );
+            }
+        }
+    }
+
+    fn clear_condition(&mut self, r#type: &str) -> bool {
+        self.remove(r#type).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_keeps_transition_time() {
+        let mut conditions = BTreeMap::new();
+        conditions.set_condition("Foo", "Bar".to_string(), None);
+        let first = conditions["Foo"].last_transition_time;
+
+        conditions.set_condition("Foo", "Baz".to_string(), "Message".to_string());
+        assert_eq!(conditions["Foo"].last_transition_time, first);
+        assert_eq!(conditions["Foo"].reason.as_deref(), Some("Baz"));
+        assert_eq!(conditions["Foo"].message.as_deref(), Some("Message"));
+
+        assert!(conditions.clear_condition("Foo"));
+        assert!(!conditions.clear_condition("Foo"));
+    }
+}
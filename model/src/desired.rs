@@ -26,6 +26,24 @@ pub struct DesiredFeature {
     /// The method of reconciliation.
     #[serde(default)]
     pub method: DesiredFeatureMethod,
+    /// The result of the last command, as acknowledged by the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_result: Option<CommandResult>,
+}
+
+/// The result of a command, as acknowledged by the device.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResult {
+    /// The timestamp the result was received.
+    pub when: DateTime<Utc>,
+    /// If the device successfully applied the command.
+    pub success: bool,
+    /// The reason of a failure, as reported by the device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
 /// The mode of the desired feature.
@@ -113,6 +131,14 @@ pub struct Command {
     /// The encoding of the command
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub encoding: Option<CommandEncoding>,
+
+    /// If the acknowledgement of the command by the device completes the reconciliation.
+    ///
+    /// A successful command completes the reconciliation of desired values which are only
+    /// applied once, without waiting for the reported state. A failed command fails the
+    /// reconciliation.
+    #[serde(default, skip_serializing_if = "crate::is_default")]
+    pub acknowledge: bool,
 }
 
 #[derive(
@@ -130,12 +156,26 @@ pub enum CommandMode {
 )]
 #[serde(rename_all = "camelCase")]
 pub enum CommandEncoding {
-    Remap { device: String, channel: String },
+    Remap {
+        device: String,
+        channel: String,
+    },
     // Send the desired value as part of a map, using the feature name as key. Combine with other
     // value sent to the same channel.
     Channel(String),
     // Send the desired value as payload, using the feature name as channel.
     Raw,
+    // Send the desired value as CBOR encoded payload, using the feature name as channel.
+    Cbor,
+    // Send the rendered template as payload, using the channel, or the feature name as channel.
+    //
+    // The placeholder `${value}` gets replaced with the JSON encoded desired value, `${feature}`
+    // with the name of the feature.
+    Template {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        template: String,
+    },
 }
 
 #[cfg(test)]
@@ -158,6 +198,7 @@ mod test {
                 },
                 method: DesiredFeatureMethod::Manual,
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         thing.desired_state.insert(
@@ -171,6 +212,7 @@ mod test {
                 },
                 method: DesiredFeatureMethod::Manual,
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         thing.desired_state.insert(
@@ -185,6 +227,7 @@ mod test {
                 },
                 method: DesiredFeatureMethod::Manual,
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         thing.desired_state.insert(
@@ -198,6 +241,7 @@ mod test {
                 },
                 method: DesiredFeatureMethod::Code(Code::JavaScript("true".to_string())),
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         thing.desired_state.insert(
@@ -213,8 +257,10 @@ mod test {
                     encoding: Some(CommandEncoding::Channel("set-features".to_string())),
                     period: std::time::Duration::from_secs(30),
                     mode: CommandMode::Passive,
+                    acknowledge: false,
                 }),
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         thing.desired_state.insert(
@@ -228,6 +274,7 @@ mod test {
                 },
                 method: DesiredFeatureMethod::External,
                 mode: DesiredMode::Sync,
+                last_result: None,
             },
         );
         assert_eq!(
@@ -1,7 +1,9 @@
+mod condition;
 mod desired;
 mod recon;
 pub mod types;
 
+pub use condition::*;
 pub use desired::*;
 pub use recon::*;
 
@@ -36,6 +38,9 @@ where
     pub metadata: Metadata,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub schema: Option<Schema>,
+    /// Mirror the state of another thing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<AliasOf>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub reported_state: BTreeMap<String, ReportedFeature>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
@@ -46,6 +51,9 @@ where
     #[serde(default, skip_serializing_if = "Reconciliation::is_empty")]
     pub reconciliation: Reconciliation,
 
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conditions: BTreeMap<String, Condition>,
+
     #[serde(
         default = "default_internal",
         skip_serializing_if = "InternalState::is_none_or_empty"
@@ -78,10 +86,12 @@ where
                 labels: Default::default(),
             },
             schema: None,
+            alias_of: None,
             reported_state: Default::default(),
             desired_state: Default::default(),
             synthetic_state: Default::default(),
             reconciliation: Default::default(),
+            conditions: Default::default(),
             internal: None,
         }
     }
@@ -103,20 +113,24 @@ where
         let Thing {
             metadata,
             schema,
+            alias_of,
             reported_state,
             desired_state,
             synthetic_state,
             reconciliation,
+            conditions,
             internal,
         } = self;
         let internal = internal.and_then(f);
         Thing {
             metadata,
             schema,
+            alias_of,
             reported_state,
             desired_state,
             synthetic_state,
             reconciliation,
+            conditions,
             internal,
         }
     }
@@ -217,6 +231,43 @@ pub struct Metadata {
     pub labels: BTreeMap<String, String>,
 }
 
+/// Annotation which suspends the reconciliation of a thing, when set to `true`.
+pub const ANNOTATION_SUSPEND: &str = "drogue.io/suspend";
+
+/// Annotation which deletes a thing, once it didn't receive any reported state for the duration
+/// of its value, e.g. `30d`.
+pub const ANNOTATION_EXPIRES_AFTER: &str = "drogue.io/expires-after";
+
+impl Metadata {
+    /// Check if the reconciliation of the thing is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.annotations
+            .get(ANNOTATION_SUSPEND)
+            .map(|value| value == "true")
+            .unwrap_or_default()
+    }
+
+    /// The duration after which the thing expires, if set and valid.
+    pub fn expires_after(&self) -> Option<std::time::Duration> {
+        self.annotations
+            .get(ANNOTATION_EXPIRES_AFTER)
+            .and_then(|value| humantime::parse_duration(value).ok())
+    }
+}
+
+/// A reference to the thing an alias mirrors.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct AliasOf {
+    /// The application of the mirrored thing, defaults to the application of the alias.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+    /// The name of the mirrored thing.
+    pub thing: String,
+}
+
 #[derive(
     Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
 )]
@@ -251,6 +302,11 @@ impl ReportedFeature {
             last_update: Utc::now(),
         }
     }
+
+    /// Create a new reported feature with the provided value and timestamp.
+    pub fn new(value: Value, last_update: DateTime<Utc>) -> Self {
+        Self { value, last_update }
+    }
 }
 
 #[derive(
@@ -271,6 +327,88 @@ pub struct SyntheticFeature {
 pub enum SyntheticType {
     JavaScript(String),
     Alias(String),
+    /// Rolling statistics of a reported feature.
+    Statistics(Statistics),
+    /// Exponential moving average of a reported feature.
+    Ema(Ema),
+    /// Difference of a reported feature to its previous value.
+    Delta(Delta),
+    /// Breach of a threshold by a reported feature.
+    Threshold(Threshold),
+    /// A feature of another thing, possibly of a different application.
+    Reference(Reference),
+}
+
+/// Maintain statistics of a numeric reported feature, over tumbling windows.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Statistics {
+    /// The name of the reported feature.
+    pub feature: String,
+    /// The duration of a window.
+    #[serde(with = "humantime_serde")]
+    #[schemars(schema_with = "crate::types::humantime")]
+    pub window: std::time::Duration,
+}
+
+/// Maintain the exponential moving average of a numeric reported feature.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Ema {
+    /// The name of the reported feature.
+    pub feature: String,
+    /// The smoothing factor, between 0 and 1. Higher values discount older values faster.
+    pub alpha: serde_json::Number,
+}
+
+/// Track the difference of a numeric reported feature to its previous value.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Delta {
+    /// The name of the reported feature.
+    pub feature: String,
+}
+
+/// Evaluate if a numeric reported feature breaches a threshold, with hysteresis.
+///
+/// The value becomes `true` once the feature is above the upper bound, and `false` again once
+/// it is below the lower bound.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Threshold {
+    /// The name of the reported feature.
+    pub feature: String,
+    /// The value above which the threshold is breached.
+    pub upper: serde_json::Number,
+    /// The value below which the breach is cleared, defaults to the upper bound.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lower: Option<serde_json::Number>,
+}
+
+/// Reference a feature of another thing.
+///
+/// The value is the synthetic, or reported, value of the referenced feature. References to
+/// things of other applications must be allowed by the configuration.
+#[derive(
+    Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Reference {
+    /// The application of the referenced thing, defaults to the application of the thing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+    /// The name of the referenced thing.
+    pub thing: String,
+    /// The name of the referenced feature.
+    pub feature: String,
 }
 
 base64_serde_type!(Base64Standard, STANDARD);
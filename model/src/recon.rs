@@ -29,6 +29,10 @@ impl Reconciliation {
 pub struct Changed {
     #[serde(flatten)]
     pub code: Code,
+    /// Only run the code if the value of one of these features changed. If empty, the code runs
+    /// on every change.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub last_log: Vec<String>,
 }
@@ -37,6 +41,7 @@ impl From<Code> for Changed {
     fn from(code: Code) -> Self {
         Self {
             code,
+            features: Default::default(),
             last_log: Default::default(),
         }
     }
@@ -1,6 +1,8 @@
 use drogue_bazaar::app::{Startup, StartupExt};
 use drogue_doppelgaenger_core::{
-    command::{mqtt, CommandSink},
+    alias,
+    command::{self, CommandSink},
+    config::check::{self, Check, Checker},
     notifier::{self, Notifier},
     processor::{
         self,
@@ -16,21 +18,108 @@ pub struct Config<St: Storage, No: Notifier, Si: Sink, So: Source, Cmd: CommandS
     // serde(bound) required as S isn't serializable: https://github.com/serde-rs/serde/issues/1296
     #[serde(bound = "")]
     pub processor: processor::Config<St, No, Si, So, Cmd>,
+    /// Mirror the state of things into their aliases
+    #[serde(default)]
+    pub alias: Option<alias::Config>,
+}
+
+impl<St: Storage, No: Notifier, Si: Sink, So: Source, Cmd: CommandSink> Check
+    for Config<St, No, Si, So, Cmd>
+where
+    processor::Config<St, No, Si, So, Cmd>: Check,
+{
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("processor", &self.processor)
+            .field("alias", &self.alias);
+    }
+}
+
+#[cfg(not(any(feature = "nats", feature = "amqp", feature = "pubsub")))]
+mod transport {
+    use super::*;
+    pub type Sink = sink::kafka::Sink;
+    pub type Source = source::kafka::Source;
+}
+
+#[cfg(feature = "nats")]
+mod transport {
+    use super::*;
+    pub type Sink = sink::nats::Sink;
+    pub type Source = source::nats::Source;
+}
+
+#[cfg(all(feature = "amqp", not(feature = "nats")))]
+mod transport {
+    use super::*;
+    pub type Sink = sink::amqp::Sink;
+    pub type Source = source::amqp::Source;
+}
+
+#[cfg(all(feature = "pubsub", not(any(feature = "nats", feature = "amqp"))))]
+mod transport {
+    use super::*;
+    pub type Sink = sink::pubsub::Sink;
+    pub type Source = source::pubsub::Source;
+}
+
+#[cfg(not(any(
+    feature = "routed-commands",
+    feature = "kafka-commands",
+    feature = "http-commands"
+)))]
+type Commands = command::mqtt::CommandSink;
+
+#[cfg(feature = "routed-commands")]
+type Commands = command::router::CommandSink;
+
+#[cfg(all(feature = "kafka-commands", not(feature = "routed-commands")))]
+type Commands = command::kafka::CommandSink;
+
+#[cfg(all(
+    feature = "http-commands",
+    not(any(feature = "routed-commands", feature = "kafka-commands"))
+))]
+type Commands = command::http::CommandSink;
+
+#[cfg(not(feature = "chaos"))]
+mod types {
+    use super::*;
+    pub type Storage = postgres::Storage;
+    pub type Notifier = notifier::kafka::Notifier;
+    pub type Sink = super::transport::Sink;
+}
+
+#[cfg(feature = "chaos")]
+mod types {
+    use super::*;
+    use drogue_doppelgaenger_core::chaos::Chaos;
+    pub type Storage = Chaos<postgres::Storage>;
+    pub type Notifier = Chaos<notifier::kafka::Notifier>;
+    pub type Sink = Chaos<super::transport::Sink>;
 }
 
 pub async fn run(
-    config: Config<
-        postgres::Storage,
-        notifier::kafka::Notifier,
-        sink::kafka::Sink,
-        source::kafka::Source,
-        mqtt::CommandSink,
-    >,
+    config: Config<types::Storage, types::Notifier, types::Sink, transport::Source, Commands>,
     startup: &mut dyn Startup,
 ) -> anyhow::Result<()> {
+    if let Some(alias) = config.alias.filter(|config| !config.disabled) {
+        let sink = types::Sink::from_config(config.processor.service.sink.clone())?;
+        log::info!("Running alias sync: {alias:?}");
+        startup.spawn(alias.run(sink));
+    }
+
     let processor = Processor::from_config(startup, config.processor)?;
 
     startup.spawn(processor.run());
 
     Ok(())
 }
+
+/// Check the configuration, without running the processor.
+pub async fn check(mode: check::Mode) -> anyhow::Result<()> {
+    check::run::<Config<types::Storage, types::Notifier, types::Sink, transport::Source, Commands>>(
+        mode,
+    )
+    .await
+}
@@ -1,8 +1,12 @@
 use drogue_bazaar::runtime;
-use drogue_doppelgaenger_core::PROJECT;
-use drogue_doppelgaenger_processor::run;
+use drogue_doppelgaenger_core::{config::check::Mode, PROJECT};
+use drogue_doppelgaenger_processor::{check, run};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    if let Some(mode) = Mode::from_args() {
+        return check(mode).await;
+    }
+
     runtime!(PROJECT).exec_fn(run).await
 }
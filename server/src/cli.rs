@@ -0,0 +1,257 @@
+use chrono::{DateTime, Utc};
+use drogue_bazaar::core::config::ConfigFromEnv;
+use drogue_doppelgaenger_core::{
+    admin::{backfill, check, offload, replay, transfer},
+    config,
+    storage::postgres,
+};
+
+#[derive(Debug, clap::Parser)]
+#[command(about, version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Only check the configuration of the server, without running it
+    #[arg(long)]
+    pub check_config: bool,
+    /// Check the configuration, including the connectivity to the configured services
+    #[arg(long)]
+    pub check_connectivity: bool,
+}
+
+impl Cli {
+    /// The mode of the configuration check, if requested.
+    pub fn check_mode(&self) -> Option<config::check::Mode> {
+        (self.check_config || self.check_connectivity).then_some(config::check::Mode {
+            connect: self.check_connectivity,
+        })
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Run,
+    /// Replay the internal event topic, configured using the `REPLAY__*` environment variables
+    Replay(ReplayArgs),
+    /// Check things for inconsistencies, configured using the `CHECK__*` environment variables
+    Check(CheckArgs),
+    /// Transfer things between storages, configured using the `TRANSFER__*` environment variables
+    Transfer(TransferArgs),
+    /// Migrate the database schema, configured using the `STORAGE__*` environment variables
+    Migrate(MigrateArgs),
+    /// Recompute the waker column of things, configured using the `BACKFILL__*` environment variables
+    BackfillWakers(BackfillArgs),
+    /// Offload idle things to the cold storage, configured using the `OFFLOAD__*` environment variables
+    Offload(OffloadArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ReplayArgs {
+    /// Start replaying at this offset, for every partition
+    #[arg(long, conflicts_with = "from_timestamp")]
+    pub from_offset: Option<i64>,
+    /// Start replaying with the first event at, or after, this timestamp (RFC 3339)
+    #[arg(long)]
+    pub from_timestamp: Option<DateTime<Utc>>,
+    /// Map an application to another one (`<from>=<to>`), can be repeated
+    #[arg(long = "map-application", value_parser = parse_mapping)]
+    pub map_applications: Vec<(String, String)>,
+    /// Only replay events of mapped applications
+    #[arg(long)]
+    pub only_mapped: bool,
+    /// Map a thing to another one (`<from>=<to>`), can be repeated
+    #[arg(long = "map-thing", value_parser = parse_mapping)]
+    pub map_things: Vec<(String, String)>,
+    /// Map a prefix of thing names to another one (`<from>=<to>`), can be repeated
+    #[arg(long = "map-thing-prefix", value_parser = parse_mapping)]
+    pub map_thing_prefixes: Vec<(String, String)>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CheckArgs {
+    /// Repair inconsistent things, instead of only reporting them
+    #[arg(long)]
+    pub repair: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct TransferArgs {
+    /// Transfer the things of this application, can be repeated
+    #[arg(long = "application", required = true)]
+    pub applications: Vec<String>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct MigrateArgs {
+    /// Only list the pending migrations, instead of running them
+    #[arg(long)]
+    pub pending: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BackfillArgs {
+    /// Only report things with an out of sync waker, instead of updating them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct OffloadArgs {
+    /// Only report idle things, instead of offloading them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!("Invalid mapping '{value}', expected '<from>=<to>'")),
+    }
+}
+
+pub async fn replay(args: ReplayArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let mut config = replay::Config::from_env_prefix("REPLAY")?;
+
+    if let Some(offset) = args.from_offset {
+        config.start = replay::Start::Offset(offset);
+    }
+    if let Some(timestamp) = args.from_timestamp {
+        config.start = replay::Start::Timestamp(timestamp);
+    }
+    config.mapping.applications.extend(args.map_applications);
+    config.mapping.only_mapped |= args.only_mapped;
+    config.mapping.things.extend(args.map_things);
+    config
+        .mapping
+        .thing_prefixes
+        .extend(args.map_thing_prefixes);
+
+    log::info!("Replaying: {config:?}");
+
+    let stats = config.run().await?;
+
+    println!(
+        "Replayed: {}, skipped: {}, failed: {}",
+        stats.replayed, stats.skipped, stats.failed
+    );
+
+    Ok(())
+}
+
+pub async fn check(args: CheckArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let config = check::Config::from_env_prefix("CHECK")?;
+
+    log::info!("Checking: {config:?}");
+
+    let report = config.run(args.repair).await?;
+
+    for (id, issues) in &report.issues {
+        for issue in issues {
+            println!("{id}: {issue}");
+        }
+    }
+
+    println!(
+        "Inconsistent: {}, repaired: {}, failed: {}",
+        report.issues.len(),
+        report.repaired,
+        report.failed
+    );
+
+    Ok(())
+}
+
+pub async fn transfer(args: TransferArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let mut config =
+        transfer::Config::<postgres::Storage, postgres::Storage>::from_env_prefix("TRANSFER")?;
+    config.applications.extend(args.applications);
+
+    log::info!("Transferring: {:?}", config.applications);
+
+    let reports = config.run().await?;
+
+    let mut unverified = 0;
+    for report in &reports {
+        println!(
+            "{}: source: {}, imported: {}, existing: {}, failed: {}, target: {}, preserved: {}",
+            report.application,
+            report.source,
+            report.imported,
+            report.existing,
+            report.failed,
+            report.target,
+            report.preserved
+        );
+        println!(
+            "{}: digest - source: {}, target: {}",
+            report.application, report.source_digest, report.target_digest
+        );
+        if !report.is_verified() {
+            unverified += 1;
+        }
+    }
+
+    if unverified > 0 {
+        anyhow::bail!("Failed to verify {unverified} application(s)");
+    }
+
+    Ok(())
+}
+
+pub async fn migrate(args: MigrateArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let config = drogue_bazaar::db::postgres::Config::from_env_prefix("STORAGE")?;
+
+    if args.pending {
+        for migration in postgres::migration::pending(&config).await? {
+            println!("{migration}");
+        }
+        return Ok(());
+    }
+
+    postgres::migration::run(&config).await
+}
+
+pub async fn backfill_wakers(args: BackfillArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let config = backfill::Config::from_env_prefix("BACKFILL")?;
+
+    log::info!("Backfilling wakers: {config:?}");
+
+    let report = config.run(args.dry_run).await?;
+
+    println!(
+        "Scanned: {}, updated: {}, skipped: {}, failed: {}",
+        report.scanned, report.updated, report.skipped, report.failed
+    );
+
+    Ok(())
+}
+
+pub async fn offload(args: OffloadArgs) -> anyhow::Result<()> {
+    env_logger::init();
+
+    let config = offload::Config::from_env_prefix("OFFLOAD")?;
+
+    log::info!("Offloading: {config:?}");
+
+    let report = config.run(args.dry_run).await?;
+
+    println!(
+        "Scanned: {}, offloaded: {}, failed: {}",
+        report.scanned, report.offloaded, report.failed
+    );
+
+    Ok(())
+}
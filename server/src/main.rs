@@ -1,7 +1,11 @@
-mod db;
+mod cli;
 mod keycloak;
 
-use crate::keycloak::SERVICE_CLIENT_SECRET;
+use crate::{
+    cli::{Cli, Command},
+    keycloak::SERVICE_CLIENT_SECRET,
+};
+use clap::Parser;
 use drogue_bazaar::{
     actix::http::{CorsConfig, HttpBuilder, HttpConfig},
     app::Startup,
@@ -10,15 +14,23 @@ use drogue_bazaar::{
     runtime,
 };
 use drogue_doppelgaenger_core::{
+    alias,
     api::az,
+    applications::Applications,
     command::{self, CommandSink},
-    config::kafka::KafkaProperties,
-    injector, notifier,
+    config::{
+        check::{self, Check, Checker},
+        kafka::KafkaProperties,
+    },
+    injector, machine, notifier, outbox,
     processor::{
+        self,
+        dead_letter::DeadLetters,
         sink::{self, Sink},
         source::{self, Source},
         Processor,
     },
+    reference, registry,
     service::{self, DefaultService},
     storage::postgres,
     waker::{self},
@@ -32,9 +44,16 @@ use std::{collections::HashMap, time::Duration};
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Server {
-    #[serde(default)]
-    application: Option<String>,
+    /// limit the server to these applications, e.g. `site-*,!site-test`
+    #[serde(default, alias = "application")]
+    applications: Applications,
     storage: drogue_bazaar::db::postgres::Config,
+    /// optional cold storage, for offloading rarely updated things
+    #[serde(default)]
+    cold_storage: Option<postgres::cold::Config>,
+    /// optional read replica of the storage, serving read-only operations
+    #[serde(default)]
+    replica_storage: Option<drogue_bazaar::db::postgres::Config>,
 
     /// sink for change events
     notifier_sink: notifier::kafka::Config,
@@ -57,6 +76,70 @@ pub struct Server {
     #[serde(default)]
     azure: Option<az::Config>,
 
+    /// optional sync with the Drogue Cloud device registry
+    #[serde(default)]
+    registry: Option<registry::Config>,
+
+    /// mirror the state of things into their aliases
+    #[serde(default)]
+    aliases: bool,
+
+    /// resolve synthetic features referencing other things
+    #[serde(default)]
+    references: bool,
+
+    /// references allowed between applications, e.g. `site-a=weather`
+    #[serde(default)]
+    allow_references: reference::Policy,
+
+    /// rate limiting of state reports
+    #[serde(default)]
+    rate_limit: processor::limit::Config,
+
+    /// process events using their original timestamp as the current time
+    #[serde(default)]
+    event_time: bool,
+
+    /// publish events which permanently failed processing to a dead letter topic
+    #[serde(default)]
+    dead_letter: Option<sink::kafka::Config>,
+
+    /// the maximum number of events processed at the same time
+    #[serde(default = "processor::default::concurrency")]
+    concurrency: usize,
+
+    /// limits of a single reconciliation run
+    #[serde(default)]
+    limits: machine::Limits,
+
+    /// thresholds of delays, raising conditions
+    #[serde(default)]
+    alerts: machine::Alerts,
+
+    /// calling external controllers
+    #[serde(default)]
+    controller: machine::controller::Config,
+
+    /// coerce reported values to the types declared by the schema
+    #[serde(default)]
+    coerce: bool,
+
+    /// archive the last state of deleted things
+    #[serde(default)]
+    archive: Option<service::archive::Config>,
+
+    /// retrying failed change notifications
+    #[serde(default)]
+    notifications: service::notifications::Config,
+
+    /// write events to the transactional outbox table, and relay them from there
+    #[serde(default)]
+    transactional_outbox: bool,
+
+    /// relaying events from the transactional outbox, defaults to relaying from the storage
+    #[serde(default)]
+    outbox: Option<outbox::Config>,
+
     #[serde(with = "humantime_serde")]
     #[serde(default = "waker::postgres::default::check_duration")]
     check_duration: Duration,
@@ -64,13 +147,59 @@ pub struct Server {
     #[serde(default)]
     http: HttpConfig,
 
+    #[serde(default)]
+    payload_limits: drogue_doppelgaenger_backend::PayloadLimits,
+
     #[serde(default)]
     oauth: Option<AuthenticatorConfig>,
 
+    /// local authentication, using credentials stored in the database
+    #[serde(default)]
+    local_auth: drogue_doppelgaenger_backend::LocalAuthMethods,
+
     #[serde(default)]
     keycloak: keycloak::Keycloak,
 }
 
+impl Check for Server {
+    fn check(&self, checker: &mut Checker) {
+        checker
+            .field("storage", &self.storage)
+            .field("cold_storage", &self.cold_storage)
+            .field("replica_storage", &self.replica_storage)
+            .field("archive", &self.archive)
+            .field("notifier_sink", &self.notifier_sink)
+            .field("notifier_source", &self.notifier_source)
+            .field("event_sink", &self.event_sink)
+            .field("event_source", &self.event_source)
+            .field("dead_letter", &self.dead_letter)
+            .field("command_sink", &self.command_sink)
+            .field("injector", &self.injector)
+            .field("outbox", &self.outbox);
+
+        // sinks and sources must match, as the server runs all components
+
+        if self.notifier_sink.topic != self.notifier_source.topic {
+            checker.issue(
+                "notifier_source.topic",
+                format!(
+                    "must match the notifier sink topic ({})",
+                    self.notifier_sink.topic
+                ),
+            );
+        }
+        if self.event_sink.topic != self.event_source.topic {
+            checker.issue(
+                "event_source.topic",
+                format!(
+                    "must match the event sink topic ({})",
+                    self.event_sink.topic
+                ),
+            );
+        }
+    }
+}
+
 mod default {
     #[allow(unused)]
     pub fn application() -> String {
@@ -94,11 +223,25 @@ async fn create_topic(config: KafkaProperties, topic: String) -> anyhow::Result<
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    runtime!(drogue_doppelgaenger_core::PROJECT).exec(run).await
+    let cli = Cli::parse();
+
+    if let Some(mode) = cli.check_mode() {
+        return check::run::<Server>(mode).await;
+    }
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => runtime!(drogue_doppelgaenger_core::PROJECT).exec(run).await,
+        Command::Replay(args) => cli::replay(args).await,
+        Command::Check(args) => cli::check(args).await,
+        Command::Transfer(args) => cli::transfer(args).await,
+        Command::Migrate(args) => cli::migrate(args).await,
+        Command::BackfillWakers(args) => cli::backfill_wakers(args).await,
+        Command::Offload(args) => cli::offload(args).await,
+    }
 }
 
 async fn run(server: Server, startup: &mut dyn Startup) -> anyhow::Result<()> {
-    db::run_migrations(&server.storage).await.unwrap();
+    postgres::migration::run(&server.storage).await.unwrap();
     create_topic(
         KafkaProperties(server.notifier_sink.properties.clone()),
         server.notifier_sink.topic.clone(),
@@ -111,6 +254,14 @@ async fn run(server: Server, startup: &mut dyn Startup) -> anyhow::Result<()> {
     )
     .await
     .unwrap();
+    if let Some(dead_letter) = &server.dead_letter {
+        create_topic(
+            KafkaProperties(dead_letter.properties.clone()),
+            dead_letter.topic.clone(),
+        )
+        .await
+        .unwrap();
+    }
 
     let oauth = if !server.keycloak.disabled {
         keycloak::configure_keycloak(&server.keycloak)
@@ -175,12 +326,21 @@ async fn run(server: Server, startup: &mut dyn Startup) -> anyhow::Result<()> {
 
     let service = service::Config {
         storage: postgres::Config {
-            application: server.application.clone(),
+            applications: server.applications.clone(),
             postgres: server.storage.clone(),
+            cold: server.cold_storage.clone(),
+            replica: server.replica_storage.clone(),
+            outbox: server.transactional_outbox,
         },
         notifier: server.notifier_sink,
         sink: server.event_sink.clone(),
         command_sink: server.command_sink.clone(),
+        limits: server.limits.clone(),
+        alerts: server.alerts.clone(),
+        controller: server.controller.clone(),
+        coerce: server.coerce,
+        archive: server.archive.clone(),
+        notifications: server.notifications.clone(),
     };
     let backend = drogue_doppelgaenger_backend::Config::<
         postgres::Storage,
@@ -188,12 +348,20 @@ async fn run(server: Server, startup: &mut dyn Startup) -> anyhow::Result<()> {
         sink::kafka::Sink,
         command::mqtt::CommandSink,
     > {
-        application: server.application.clone(),
+        applications: server.applications.clone(),
         service: service.clone(),
-        listener: server.notifier_source,
+        listener: server.notifier_source.clone().into(),
         oauth,
+        local_auth: server.local_auth.is_enabled().then(|| {
+            drogue_doppelgaenger_backend::LocalAuthConfig {
+                methods: server.local_auth.clone(),
+                postgres: server.storage.clone(),
+            }
+        }),
         user_auth: None,
         openapi_oauth_client: None,
+        payload_limits: server.payload_limits.clone(),
+        grpc: None,
     };
 
     let configurator = drogue_doppelgaenger_backend::configure(startup, backend).await?;
@@ -228,15 +396,84 @@ async fn run(server: Server, startup: &mut dyn Startup) -> anyhow::Result<()> {
         startup.spawn(az.run(sink, command, service).boxed_local());
     }
 
+    if let Some(registry) = server.registry.filter(|config| !config.disabled) {
+        let service = DefaultService::from_config(startup, service.clone())?;
+        log::info!("Running registry sync: {registry:?}");
+        startup.spawn(registry.run(service).boxed_local());
+    }
+
+    if server.aliases {
+        let alias = alias::Config {
+            disabled: false,
+            storage: postgres::Config {
+                applications: server.applications.clone(),
+                postgres: server.storage.clone(),
+                cold: server.cold_storage.clone(),
+                replica: server.replica_storage.clone(),
+                outbox: server.transactional_outbox,
+            },
+            properties: server.notifier_source.properties.clone(),
+            topic: server.notifier_source.topic.clone(),
+            group_id: "alias-sync".to_string(),
+        };
+        let sink = sink::kafka::Sink::from_config(server.event_sink.clone())?;
+        log::info!("Running alias sync: {alias:?}");
+        startup.spawn(alias.run(sink).boxed_local());
+    }
+
+    if server.references {
+        let reference = reference::Config {
+            disabled: false,
+            storage: postgres::Config {
+                applications: server.applications.clone(),
+                postgres: server.storage.clone(),
+                cold: server.cold_storage.clone(),
+                replica: server.replica_storage.clone(),
+                outbox: server.transactional_outbox,
+            },
+            properties: server.notifier_source.properties.clone(),
+            topic: server.notifier_source.topic.clone(),
+            group_id: "reference-sync".to_string(),
+            allow: server.allow_references,
+        };
+        let sink = sink::kafka::Sink::from_config(server.event_sink.clone())?;
+        log::info!("Running reference sync: {reference:?}");
+        startup.spawn(reference.run(sink).boxed_local());
+    }
+
+    if server.transactional_outbox {
+        let outbox = server.outbox.clone().unwrap_or_else(|| outbox::Config {
+            disabled: false,
+            postgres: server.storage.clone(),
+            batch_size: outbox::default::batch_size(),
+            check_period: outbox::default::check_period(),
+        });
+        if !outbox.disabled {
+            let sink = sink::kafka::Sink::from_config(server.event_sink.clone())?;
+            log::info!("Running outbox relay: {outbox:?}");
+            startup.spawn(outbox.run(sink).boxed_local());
+        }
+    }
+
     let service = DefaultService::from_config(startup, service)?;
-    let processor = Processor::new(service, source).run().boxed();
+    let dead_letters = server
+        .dead_letter
+        .map(DeadLetters::from_config)
+        .transpose()?;
+    let processor = Processor::new(service, source)
+        .with_rate_limit(server.rate_limit)
+        .with_event_time(server.event_time)
+        .with_dead_letters(dead_letters)
+        .with_concurrency(server.concurrency)
+        .run()
+        .boxed();
 
     let waker = waker::Processor::from_config(waker::Config::<
         waker::postgres::Waker,
         sink::kafka::Sink,
     > {
         waker: waker::postgres::Config {
-            application: server.application,
+            applications: server.applications,
             postgres: server.storage,
             check_period: server.check_duration,
         },
@@ -1,11 +1,24 @@
 use drogue_bazaar::app::{Startup, StartupExt};
 use drogue_doppelgaenger_core::{
+    config::check,
     processor::sink::{self},
     waker::{self, Config},
 };
 
+#[cfg(not(any(feature = "nats", feature = "amqp", feature = "pubsub")))]
+type Sink = sink::kafka::Sink;
+
+#[cfg(feature = "nats")]
+type Sink = sink::nats::Sink;
+
+#[cfg(all(feature = "amqp", not(feature = "nats")))]
+type Sink = sink::amqp::Sink;
+
+#[cfg(all(feature = "pubsub", not(any(feature = "nats", feature = "amqp"))))]
+type Sink = sink::pubsub::Sink;
+
 pub async fn run(
-    config: Config<waker::postgres::Waker, sink::kafka::Sink>,
+    config: Config<waker::postgres::Waker, Sink>,
     startup: &mut dyn Startup,
 ) -> anyhow::Result<()> {
     let waker = waker::Processor::from_config(config)?.run();
@@ -14,3 +27,8 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Check the configuration, without running the waker.
+pub async fn check(mode: check::Mode) -> anyhow::Result<()> {
+    check::run::<Config<waker::postgres::Waker, Sink>>(mode).await
+}